@@ -8,6 +8,7 @@ use bitcoin::util::bip32::ExtendedPubKey;
 use directories::BaseDirs;
 use emulator_connect::connections::federated::FederatedEmulatorConnection;
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::transport::{NoiseClientConfig, NoiseKey};
 use emulator_connect::CTVEmulator;
 use serde::*;
 use std::collections::HashMap;
@@ -29,6 +30,10 @@ pub struct EmulatorConfig {
     pub emulators: Vec<(ExtendedPubKey, String)>,
     /// threshold could be larger than u8, but that seems very unlikely/an error.
     pub threshold: u8,
+    /// pinned Noise static keys of the emulators, by EPK. Emulators with a
+    /// pinned key are only spoken to over Noise.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub noise_keys: HashMap<ExtendedPubKey, NoiseKey>,
 }
 
 impl EmulatorConfig {
@@ -54,6 +59,10 @@ impl EmulatorConfig {
                         reconnect: host.to_socket_addrs()?.next().unwrap(),
                         root: *epk,
                         secp: secp.clone(),
+                        noise: self.noise_keys.get(epk).map(|k| NoiseClientConfig {
+                            oracle: *k,
+                            local: None,
+                        }),
                     })
                 });
         Ok(if self.emulators.len() == 1 {
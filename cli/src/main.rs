@@ -20,9 +20,12 @@ use emulator_connect::servers::hd::HDOracleEmulator;
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
 use miniscript::psbt::PsbtExt;
+use sapio::contract::abi::watch_only::ImportTimestamp;
 use sapio::contract::context::MapEffectDB;
 use sapio::contract::object::LinkedPSBT;
+use sapio::contract::object::PsbtVersion;
 use sapio::contract::object::SapioStudioObject;
+use sapio::contract::object::TxModifiable;
 use sapio::contract::Compiled;
 use sapio::contract::Context;
 use sapio::template::output::OutputMeta;
@@ -107,6 +110,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@subcommand bind =>
        (about: "Bind Contract to a specific UTXO")
        (@arg base64_psbt: --base64_psbt "Output as a base64 PSBT")
+       (@arg psbt_v2: --psbt_v2 "Emit version 2 (BIP 370) PSBTs")
        (@group from  =>
             (@arg outpoint: --outpoint +takes_value "Use this specific outpoint")
             (@arg txn: --txn +takes_value "Use this specific transaction ")
@@ -150,6 +154,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@subcommand list =>
        (about: "list available contracts")
       )
+      (@subcommand watch_only =>
+       (about: "Generate a watch-only wallet import for every address in a compiled contract")
+       (@arg importmulti: --importmulti "Output importmulti requests (legacy wallets) instead of importdescriptors")
+       (@arg by_path: --by_path "Output a map from contract path to output descriptor instead of importdescriptors")
+       (@arg rescan_from: --rescan_from +takes_value "Unix timestamp to rescan from, otherwise only new transactions are watched")
+       (@arg json: "Compiled contract JSON, otherwise read from stdin")
+      )
       )
       );
     let matches = app.get_matches();
@@ -326,6 +337,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("{} -- {}", plugin.get_name()?, plugin.id().to_string());
                 }
             }
+            Some(("watch_only", args)) => {
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let timestamp = match args.value_of("rescan_from") {
+                    Some(t) => ImportTimestamp::Time(t.parse()?),
+                    None => ImportTimestamp::Now,
+                };
+                let requests = if args.is_present("importmulti") {
+                    serde_json::to_value(j.watch_only_importmulti(timestamp))?
+                } else if args.is_present("by_path") {
+                    serde_json::to_value(j.all_descriptors())?
+                } else {
+                    serde_json::to_value(j.watch_only_descriptors(timestamp))?
+                };
+                println!("{}", serde_json::to_string_pretty(&requests)?);
+            }
             Some(("bind", args)) => {
                 fn create_mock_output() -> bitcoin::OutPoint {
                     bitcoin::OutPoint {
@@ -339,6 +371,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 let use_mock = args.is_present("mock");
                 let use_base64 = args.is_present("base64_psbt");
+                let psbt_version = if args.is_present("psbt_v2") {
+                    PsbtVersion::V2
+                } else {
+                    PsbtVersion::V0
+                };
                 let outpoint: Option<bitcoin::OutPoint> = args
                     .value_of("outpoint")
                     .map(serde_json::from_str)
@@ -426,6 +463,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     HashMap::new(),
                     logger,
                     emulator.as_ref(),
+                    psbt_version,
                 )?;
 
                 if outpoint.is_none() {
@@ -444,8 +482,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     simp: Default::default(),
                                 },
                                 output_metadata,
+                                broadcast_after: None,
+                                cpfp_children: vec![],
                             }
-                            .into()],
+                            .into_studio_format(psbt_version, TxModifiable::default())],
+                            witness_templates: vec![],
                         },
                     );
                 }
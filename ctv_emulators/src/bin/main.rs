@@ -6,7 +6,10 @@
 
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::bip32::*;
+use emulator_connect::quorum::QuorumConfig;
 use emulator_connect::servers::hd::*;
+use emulator_connect::servers::policy::AuditLog;
+use emulator_connect::transport::NoiseServerConfig;
 
 use tokio;
 use tokio::io::AsyncReadExt;
@@ -23,13 +26,53 @@ async fn main() -> Result<(), std::io::Error> {
         ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Regtest, &contents[..])
             .unwrap();
     let pk_root = ExtendedPubKey::from_private(&Secp256k1::new(), &root);
+    // optionally, the federation this oracle serves in
+    if let Some(quorum) = std::env::args().nth(3) {
+        let config: QuorumConfig = serde_json::from_slice(&tokio::fs::read(quorum).await?)?;
+        config.validate()?;
+        let idx = config.member_index(&pk_root).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "This oracle is not a member of the quorum",
+            )
+        })?;
+        println!(
+            "Serving as member {} of a {}-of-{} federation",
+            idx,
+            config.threshold,
+            config.members.len()
+        );
+    }
     let oracle = HDOracleEmulator::new(root, true);
-    let server = oracle.bind(
-        std::env::args()
-            .nth(2)
-            .expect("No Interface given (e.g., 127.0.0.1:8080"),
+    let noise = oracle.noise_keypair();
+    println!(
+        "Accepting Noise connections with static key: {}",
+        noise.public
     );
+    let oracle = oracle.with_noise(NoiseServerConfig {
+        keypair: noise,
+        authorized: None,
+        required: false,
+    });
+    // optionally, where to record everything the oracle is asked to sign
+    let oracle = match std::env::var_os("EMULATOR_AUDIT_LOG") {
+        Some(path) => oracle.with_audit_log(AuditLog::open(path)?),
+        None => oracle,
+    };
+    let interface = std::env::args()
+        .nth(2)
+        .expect("No Interface given (e.g., 127.0.0.1:8080");
     println!("Running Oracle With Key: {}", pk_root);
-    server.await?;
+    #[cfg(feature = "http")]
+    if let Some(addr) = interface.strip_prefix("http://") {
+        let addr = addr
+            .parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Bad Interface"))?;
+        return oracle
+            .bind_http(addr)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+    }
+    oracle.bind(interface).await?;
     Ok(())
 }
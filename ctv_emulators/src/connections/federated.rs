@@ -5,8 +5,17 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use bitcoin::util::sighash::{Prevouts, SigHashCache};
+use bitcoin::{SchnorrSig, TxOut, XOnlyPublicKey};
+use std::collections::{BTreeMap, BTreeSet};
 /// Creates a multi-condition emulator with a certain threshold.
 /// It implements CTVEmulator so that it itself can be used as a trait object.
+///
+/// Signing asks every emulator for its signature, so that the threshold can
+/// be met even if some of them are unreachable or misbehave. A member's
+/// signatures are only kept if they verify and are by the key that member
+/// signs the template with, and the threshold counts the distinct such keys,
+/// so a member answering with junk or with other keys does not count extra.
 pub struct FederatedEmulatorConnection {
     emulators: Vec<Arc<dyn CTVEmulator>>,
     threshold: u8,
@@ -19,6 +28,14 @@ impl FederatedEmulatorConnection {
             threshold,
         }
     }
+    /// the number of signatures needed
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+    /// the members of the federation
+    pub fn emulators(&self) -> &[Arc<dyn CTVEmulator>] {
+        &self.emulators
+    }
 }
 
 impl CTVEmulator for FederatedEmulatorConnection {
@@ -30,13 +47,200 @@ impl CTVEmulator for FederatedEmulatorConnection {
             .collect::<Result<Vec<Clause>, EmulatorError>>()?;
         Ok(Clause::Threshold(self.threshold as usize, v))
     }
+    fn sign_batch(&self, hs: &[Sha256]) -> Result<Vec<Clause>, EmulatorError> {
+        // one batch per member, rather than one request per member per hash
+        let per_member = self
+            .emulators
+            .iter()
+            .map(|e| e.sign_batch(hs))
+            .collect::<Result<Vec<Vec<Clause>>, EmulatorError>>()?;
+        if per_member.iter().any(|m| m.len() != hs.len()) {
+            return Err(input_err("Member returned the wrong number of clauses").into());
+        }
+        Ok((0..hs.len())
+            .map(|i| {
+                Clause::Threshold(
+                    self.threshold as usize,
+                    per_member.iter().map(|m| m[i].clone()).collect(),
+                )
+            })
+            .collect())
+    }
     fn sign(
         &self,
         mut b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let mut last_err = None;
+        let original = b.clone();
+        let h = original.clone().extract_tx().get_ctv_hash(0);
+        let mut verified = BTreeMap::new();
         for emulator in self.emulators.iter() {
-            b = emulator.sign(b)?;
+            // each emulator signs the original, so that a faulty one cannot
+            // interfere with the others' signatures
+            let sigs = emulator.get_signer_for(h).and_then(|signer| match signer {
+                Clause::Key(key) => {
+                    let signed = emulator.sign(original.clone())?;
+                    Ok(new_valid_script_sigs(&original, &signed, key)?)
+                }
+                _ => Err(input_err("Member's signer is not a key").into()),
+            });
+            match sigs {
+                Ok(sigs) if !sigs.is_empty() => verified.extend(sigs),
+                Ok(_) => last_err = Some(input_err("Fault Signed PSBT").into()),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let signers: BTreeSet<_> = verified.keys().map(|(pk, _)| *pk).collect();
+        if signers.len() < self.threshold as usize {
+            return Err(last_err.unwrap_or_else(|| {
+                EmulatorError::NetworkIssue(input_err("Too few emulators in federation"))
+            }));
+        }
+        if let Some(input_zero) = b.inputs.get_mut(0) {
+            input_zero.tap_script_sigs.extend(verified);
         }
         Ok(b)
     }
 }
+
+/// The script path signatures by `key` on input 0 of `signed` which are not
+/// on `original` and which verify against `original`'s transaction
+fn new_valid_script_sigs(
+    original: &PartiallySignedTransaction,
+    signed: &PartiallySignedTransaction,
+    key: XOnlyPublicKey,
+) -> Result<
+    BTreeMap<(XOnlyPublicKey, bitcoin::util::taproot::TapLeafHash), SchnorrSig>,
+    std::io::Error,
+> {
+    let (before, after) = match (original.inputs.get(0), signed.inputs.get(0)) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return input_error("PSBT has no inputs"),
+    };
+    let tx = original.clone().extract_tx();
+    let utxos: Vec<TxOut> = original
+        .inputs
+        .iter()
+        .map(|o| o.witness_utxo.clone())
+        .collect::<Option<Vec<TxOut>>>()
+        .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
+    let mut sighash = SigHashCache::new(&tx);
+    let mut valid = BTreeMap::new();
+    for ((pk, tlh), sig) in after.tap_script_sigs.iter() {
+        if *pk != key || before.tap_script_sigs.contains_key(&(*pk, *tlh)) {
+            continue;
+        }
+        let hash = match sighash.taproot_signature_hash(
+            0,
+            &Prevouts::All(&utxos),
+            None,
+            Some((*tlh, 0xffffffff)),
+            sig.hash_ty,
+        ) {
+            Ok(hash) => hash,
+            Err(_) => continue,
+        };
+        let msg =
+            bitcoin::secp256k1::Message::from_slice(&hash[..]).expect("Size must be correct.");
+        if SECP.with(|secp| secp.verify_schnorr(&sig.sig, &msg, pk).is_ok()) {
+            valid.insert((*pk, *tlh), *sig);
+        }
+    }
+    Ok(valid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::util::psbt::Input;
+    use bitcoin::util::taproot::{ControlBlock, LeafVersion};
+    use bitcoin::{OutPoint, Script, Transaction, TxIn};
+    use sapio_ctv_emulator_trait::dev::InsecureDevEmulator;
+
+    fn dev(seed: u8) -> InsecureDevEmulator {
+        InsecureDevEmulator::from_seed(&[seed; 32], bitcoin::Network::Regtest).unwrap()
+    }
+
+    fn member(seed: u8) -> Arc<dyn CTVEmulator> {
+        Arc::new(dev(seed))
+    }
+
+    /// claims its own key for the template, but signs with other members' keys
+    struct Forger(InsecureDevEmulator, Vec<InsecureDevEmulator>);
+    impl CTVEmulator for Forger {
+        fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+            self.0.get_signer_for(h)
+        }
+        fn sign(
+            &self,
+            mut b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            for other in self.1.iter() {
+                let signed = other.sign(b.clone())?;
+                let sigs = signed.inputs[0].tap_script_sigs.clone();
+                b.inputs[0].tap_script_sigs.extend(sigs);
+            }
+            Ok(b)
+        }
+    }
+
+    /// spends an output with one leaf, for the members to sign
+    fn psbt() -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut control = vec![0xc0];
+        control.extend_from_slice(
+            &Vec::<u8>::from_hex(
+                "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+        );
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0] = Input {
+            witness_utxo: Some(TxOut {
+                value: 2000,
+                script_pubkey: Script::new(),
+            }),
+            tap_scripts: vec![(
+                ControlBlock::from_slice(&control).unwrap(),
+                (Script::new(), LeafVersion::TapScript),
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        psbt
+    }
+
+    #[test]
+    fn threshold_counts_distinct_signers() {
+        let signed = FederatedEmulatorConnection::new(vec![member(1), member(2)], 2)
+            .sign(psbt())
+            .unwrap();
+        assert_eq!(signed.inputs[0].tap_script_sigs.len(), 2);
+        // the same key twice is one signer
+        assert!(
+            FederatedEmulatorConnection::new(vec![member(1), member(1)], 2)
+                .sign(psbt())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn foreign_key_signatures_do_not_count() {
+        let forger: Arc<dyn CTVEmulator> = Arc::new(Forger(dev(3), vec![dev(4), dev(5)]));
+        let federation = FederatedEmulatorConnection::new(vec![forger, member(1)], 2);
+        assert!(federation.sign(psbt()).is_err());
+    }
+}
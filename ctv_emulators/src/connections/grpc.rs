@@ -0,0 +1,117 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! gRPC transport for talking to an oracle server.
+use super::*;
+use crate::proto::ctv_emulator_client::CtvEmulatorClient;
+use crate::proto::*;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::psbt::Map;
+use std::str::FromStr;
+use tonic::transport::{Channel, Endpoint};
+
+/// GrpcEmulatorConnection wraps a tokio runtime and a gRPC channel with a key
+/// to be able to talk to an Oracle server serving the `CtvEmulator` service.
+///
+/// Like HDOracleEmulatorConnection, it uses block_in_place/block_on internally
+/// because the CTVEmulator trait is not async.
+pub struct GrpcEmulatorConnection {
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    pub client: CtvEmulatorClient<Channel>,
+    pub root: ExtendedPubKey,
+    pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+}
+
+fn status_err(s: tonic::Status) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, s.to_string())
+}
+
+impl GrpcEmulatorConnection {
+    /// Helper function to derive an EPK
+    fn derive(&self, h: Sha256) -> Result<ExtendedPubKey, Error> {
+        let c = hash_to_child_vec(h);
+        self.root.derive_pub(&self.secp, &c)
+    }
+
+    /// Creates a new instance of a GrpcEmulatorConnection to `endpoint` (e.g.
+    /// `http://127.0.0.1:8080`).
+    ///
+    /// As with HDOracleEmulatorConnection, a connection is not opened to the
+    /// server until it is actually needed.
+    pub async fn new(
+        endpoint: String,
+        root: ExtendedPubKey,
+        runtime: Arc<tokio::runtime::Runtime>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Result<Self, std::io::Error> {
+        let channel = Endpoint::from_shared(endpoint)
+            .map_err(|e| input_err(&e.to_string()))?
+            .connect_lazy()
+            .map_err(|e| input_err(&e.to_string()))?;
+        Ok(GrpcEmulatorConnection {
+            runtime,
+            client: CtvEmulatorClient::new(channel),
+            root,
+            secp,
+        })
+    }
+
+    /// Ask the oracle for its root key, e.g. to check it matches `self.root`.
+    pub async fn get_xpub(&self) -> Result<ExtendedPubKey, std::io::Error> {
+        let xpub = self
+            .client
+            .clone()
+            .get_xpub(GetXpubRequest {})
+            .await
+            .map_err(status_err)?
+            .into_inner()
+            .xpub;
+        ExtendedPubKey::from_str(&xpub).map_err(|e| input_err(&e.to_string()))
+    }
+
+    /// Check that the oracle is able to serve requests.
+    pub async fn health(&self) -> Result<bool, std::io::Error> {
+        Ok(self
+            .client
+            .clone()
+            .health(HealthRequest {})
+            .await
+            .map_err(status_err)?
+            .into_inner()
+            .serving)
+    }
+}
+
+impl CTVEmulator for GrpcEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.derive(h)?.to_x_only_pub()))
+    }
+    fn sign(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let inp: Result<PartiallySignedTransaction, std::io::Error> =
+            tokio::task::block_in_place(|| {
+                self.runtime.block_on(async {
+                    let psbt = self
+                        .client
+                        .clone()
+                        .sign_template(SignTemplateRequest {
+                            psbt: serialize(&b),
+                        })
+                        .await
+                        .map_err(status_err)?
+                        .into_inner()
+                        .psbt;
+                    deserialize(&psbt[..]).map_err(|e| input_err(&e.to_string()))
+                })
+            });
+
+        b.merge(inp?)
+            .or_else(|_e| input_error("Fault Signed PSBT"))?;
+        Ok(b)
+    }
+}
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use crate::transport::{NoiseClientConfig, Transport};
 /// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
 /// with a key to be able to talk to an Oracle server.
 ///
@@ -15,10 +16,12 @@ use super::*;
 /// traits.
 pub struct HDOracleEmulatorConnection {
     pub runtime: Arc<tokio::runtime::Runtime>,
-    pub connection: Mutex<Option<TcpStream>>,
+    pub connection: Mutex<Option<Transport>>,
     pub reconnect: SocketAddr,
     pub root: ExtendedPubKey,
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    /// if set, connect over Noise, authenticating the oracle by its pinned key
+    pub noise: Option<NoiseClientConfig>,
 }
 
 impl HDOracleEmulatorConnection {
@@ -57,27 +60,24 @@ impl HDOracleEmulatorConnection {
             runtime,
             root,
             secp,
+            noise: None,
         })
     }
 
-    /// make a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
+    /// Connect over Noise, so that only the oracle holding the static key
+    /// `config.oracle` can answer requests.
+    pub fn with_noise(mut self, config: NoiseClientConfig) -> Self {
+        self.noise = Some(config);
+        self
     }
-    /// receive a response via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        let t: T = serde_json::from_slice::<T>(&v[..])?;
-        Ok(t)
+
+    /// make a request via the transport.
+    async fn request(t: &mut Transport, r: &msgs::Request) -> Result<(), std::io::Error> {
+        t.send(&serde_json::to_vec(r)?).await
+    }
+    /// receive a response via the transport, which limits its length.
+    async fn response<T: DeserializeOwned + Clone>(t: &mut Transport) -> Result<T, std::io::Error> {
+        Ok(serde_json::from_slice::<T>(&t.recv().await?)?)
     }
 }
 
@@ -99,10 +99,11 @@ impl CTVEmulator for HDOracleEmulatorConnection {
                         if let Some(conn) = &mut *mconn {
                             Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
                                 .await?;
-                            conn.flush().await?;
                             return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
                         } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
+                            *mconn = Some(
+                                Transport::connect(&self.reconnect, self.noise.as_ref()).await?,
+                            );
                         }
                     }
                 })
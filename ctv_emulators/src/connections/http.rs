@@ -0,0 +1,120 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! HTTP/JSON transport for talking to an oracle server.
+use super::*;
+use crate::servers::http::{HealthBody, KeyBody, SignBody};
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::util::psbt::Map;
+
+/// HttpEmulatorConnection talks to an Oracle server serving the HTTP/JSON
+/// endpoints (see [`crate::servers::http`]), e.g. behind a reverse proxy.
+///
+/// Like HDOracleEmulatorConnection, it uses block_in_place/block_on internally
+/// because the CTVEmulator trait is not async.
+pub struct HttpEmulatorConnection {
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    pub client: reqwest::Client,
+    /// the server's base url, e.g. `https://oracle.example.com`
+    pub url: String,
+    pub root: ExtendedPubKey,
+    pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+}
+
+fn http_err(e: reqwest::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+}
+
+impl HttpEmulatorConnection {
+    /// Helper function to derive an EPK
+    fn derive(&self, h: Sha256) -> Result<ExtendedPubKey, Error> {
+        let c = hash_to_child_vec(h);
+        self.root.derive_pub(&self.secp, &c)
+    }
+
+    /// Creates a new instance of a HttpEmulatorConnection to `url`. No
+    /// request is made until one is needed.
+    pub fn new(
+        url: String,
+        root: ExtendedPubKey,
+        runtime: Arc<tokio::runtime::Runtime>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Self {
+        HttpEmulatorConnection {
+            runtime,
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').into(),
+            root,
+            secp,
+        }
+    }
+
+    /// Ask the oracle for its root key, e.g. to check it matches `self.root`.
+    pub async fn get_xpub(&self) -> Result<ExtendedPubKey, std::io::Error> {
+        Ok(self
+            .client
+            .get(format!("{}/get_key", self.url))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(http_err)?
+            .json::<KeyBody>()
+            .await
+            .map_err(http_err)?
+            .xpub)
+    }
+
+    /// Check that the oracle is able to serve requests.
+    pub async fn health(&self) -> Result<bool, std::io::Error> {
+        Ok(self
+            .client
+            .get(format!("{}/health", self.url))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(http_err)?
+            .json::<HealthBody>()
+            .await
+            .map_err(http_err)?
+            .serving)
+    }
+}
+
+impl CTVEmulator for HttpEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        Ok(Clause::Key(self.derive(h)?.to_x_only_pub()))
+    }
+    fn sign(
+        &self,
+        mut b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        let inp: Result<PartiallySignedTransaction, std::io::Error> =
+            tokio::task::block_in_place(|| {
+                self.runtime.block_on(async {
+                    let signed = self
+                        .client
+                        .post(format!("{}/sign", self.url))
+                        .json(&SignBody {
+                            psbt: base64::encode(serialize(&b)),
+                        })
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status())
+                        .map_err(http_err)?
+                        .json::<SignBody>()
+                        .await
+                        .map_err(http_err)?;
+                    let bytes =
+                        base64::decode(&signed.psbt).map_err(|e| input_err(&e.to_string()))?;
+                    deserialize(&bytes[..]).map_err(|e| input_err(&e.to_string()))
+                })
+            });
+
+        b.merge(inp?)
+            .or_else(|_e| input_error("Fault Signed PSBT"))?;
+        Ok(b)
+    }
+}
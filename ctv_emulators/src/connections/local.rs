@@ -0,0 +1,52 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::*;
+use super::hd::HDOracleEmulatorConnection;
+use crate::servers::hd::HDOracleEmulator;
+/// LocalFallbackEmulatorConnection signs in-process when the oracle's private
+/// key is held locally (e.g., in dev/test or single operator deployments),
+/// and only falls back to the network oracle when it is not.
+///
+/// Keys are always derived from the remote connection's root, so the Clauses
+/// produced are identical whichever way signing happens.
+pub struct LocalFallbackEmulatorConnection {
+    local: Option<HDOracleEmulator>,
+    remote: HDOracleEmulatorConnection,
+}
+
+impl LocalFallbackEmulatorConnection {
+    /// Creates a new LocalFallbackEmulatorConnection.
+    ///
+    /// `keys` are the private keys available locally; if one of them is the
+    /// private key for `remote`'s root, signing never touches the network.
+    pub fn new(remote: HDOracleEmulatorConnection, keys: &[ExtendedPrivKey]) -> Self {
+        let local = keys
+            .iter()
+            .map(|k| HDOracleEmulator::new(*k, false))
+            .find(|e| e.xpub() == remote.root);
+        LocalFallbackEmulatorConnection { local, remote }
+    }
+    /// true if signing happens in-process
+    pub fn is_local(&self) -> bool {
+        self.local.is_some()
+    }
+}
+
+impl CTVEmulator for LocalFallbackEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        self.remote.get_signer_for(h)
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        match &self.local {
+            Some(local) => Ok(SECP.with(|secp| local.sign(b, secp))?),
+            None => self.remote.sign(b),
+        }
+    }
+}
@@ -6,4 +6,9 @@
 
 use super::*;
 pub mod federated;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod hd;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod local;
@@ -0,0 +1,115 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Configuration of a k-of-n federation of oracles, shared by the oracle
+//! servers (to check they are members) and by clients (to connect to all of
+//! them).
+use super::*;
+use crate::connections::federated::FederatedEmulatorConnection;
+use crate::connections::hd::HDOracleEmulatorConnection;
+use crate::transport::{NoiseClientConfig, NoiseKey};
+use serde_derive::{Deserialize, Serialize};
+
+/// One oracle in a federation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuorumMember {
+    /// the oracle's root key
+    pub xpub: ExtendedPubKey,
+    /// the interface the oracle serves on, e.g. `oracle1.example.com:8080`
+    pub address: String,
+    /// the oracle's Noise static key, if it should be spoken to over Noise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise: Option<NoiseKey>,
+}
+
+/// A k-of-n federation of oracles
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuorumConfig {
+    /// how many members must sign
+    pub threshold: u8,
+    /// all members
+    pub members: Vec<QuorumMember>,
+}
+
+impl QuorumConfig {
+    /// Check that the threshold can be met (and is not trivially met) and
+    /// that no oracle is listed twice.
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        if self.threshold == 0 || self.threshold as usize > self.members.len() {
+            return input_error("Threshold must be between 1 and the number of members");
+        }
+        for (i, m) in self.members.iter().enumerate() {
+            if self.members[..i].iter().any(|o| o.xpub == m.xpub) {
+                return input_error(&format!("Oracle {} is listed twice", m.xpub));
+            }
+        }
+        Ok(())
+    }
+
+    /// The index of the member with root key `xpub`, if it is one. Oracle
+    /// servers use this to refuse to run with a config they are not part of.
+    pub fn member_index(&self, xpub: &ExtendedPubKey) -> Option<usize> {
+        self.members.iter().position(|m| m.xpub == *xpub)
+    }
+
+    /// Connect to every member. Connections are opened lazily, see
+    /// [`HDOracleEmulatorConnection::new`].
+    pub async fn connect(
+        &self,
+        runtime: Arc<tokio::runtime::Runtime>,
+        secp: Arc<Secp256k1<All>>,
+    ) -> Result<FederatedEmulatorConnection, std::io::Error> {
+        self.validate()?;
+        let mut emulators: Vec<Arc<dyn CTVEmulator>> = vec![];
+        for m in &self.members {
+            let conn = HDOracleEmulatorConnection::new(
+                m.address.clone(),
+                m.xpub,
+                runtime.clone(),
+                secp.clone(),
+            )
+            .await?;
+            emulators.push(Arc::new(match m.noise {
+                Some(oracle) => conn.with_noise(NoiseClientConfig {
+                    oracle,
+                    local: None,
+                }),
+                None => conn,
+            }));
+        }
+        Ok(FederatedEmulatorConnection::new(emulators, self.threshold))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn validates_threshold_and_members() {
+        let secp = Secp256k1::new();
+        let key = |seed: u8| {
+            let sk = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[seed; 32]).unwrap();
+            ExtendedPubKey::from_priv(&secp, &sk)
+        };
+        let member = |seed: u8| QuorumMember {
+            xpub: key(seed),
+            address: "127.0.0.1:8080".into(),
+            noise: None,
+        };
+        let mut config = QuorumConfig {
+            threshold: 2,
+            members: vec![member(1), member(2), member(3)],
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.member_index(&key(2)), Some(1));
+        assert_eq!(config.member_index(&key(4)), None);
+        config.threshold = 4;
+        assert!(config.validate().is_err());
+        config.threshold = 2;
+        config.members.push(member(1));
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! gRPC transport for the HD oracle emulator.
+use super::hd::HDOracleEmulator;
+use super::*;
+use crate::proto::ctv_emulator_server::{CtvEmulator, CtvEmulatorServer};
+use crate::proto::*;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use tonic::{Request, Response, Status};
+
+#[tonic::async_trait]
+impl CtvEmulator for HDOracleEmulator {
+    async fn sign_template(
+        &self,
+        request: Request<SignTemplateRequest>,
+    ) -> Result<Response<SignTemplateResponse>, Status> {
+        let psbt = request.into_inner().psbt;
+        if psbt.len() > MAX_MSG {
+            return Err(Status::invalid_argument("PSBT Too Large"));
+        }
+        let unsigned: PartiallySignedTransaction =
+            deserialize(&psbt[..]).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let psbt = SECP
+            .with(|secp| self.sign(unsigned, secp))
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(Response::new(SignTemplateResponse {
+            psbt: serialize(&psbt),
+        }))
+    }
+    async fn get_xpub(
+        &self,
+        _request: Request<GetXpubRequest>,
+    ) -> Result<Response<GetXpubResponse>, Status> {
+        Ok(Response::new(GetXpubResponse {
+            xpub: self.xpub().to_string(),
+        }))
+    }
+    async fn health(
+        &self,
+        _request: Request<HealthRequest>,
+    ) -> Result<Response<HealthResponse>, Status> {
+        Ok(Response::new(HealthResponse { serving: true }))
+    }
+}
+
+impl HDOracleEmulator {
+    /// binds a HDOracleEmulator to a socket address and serves the gRPC
+    /// `CtvEmulator` service.
+    ///
+    /// This only returns if the server fails.
+    pub async fn bind_grpc(self, a: SocketAddr) -> Result<(), tonic::transport::Error> {
+        tonic::transport::Server::builder()
+            .add_service(CtvEmulatorServer::new(self))
+            .serve(a)
+            .await
+    }
+}
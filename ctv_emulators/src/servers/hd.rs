@@ -4,18 +4,17 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use super::policy::{AuditLog, AuditRecord, SigningPolicy};
 use super::*;
-use bitcoin::util::sighash::Prevouts;
-use bitcoin::util::taproot::TapLeafHash;
-use bitcoin::util::taproot::TapSighashHash;
-use bitcoin::SchnorrSig;
-use bitcoin::Script;
-use bitcoin::TxOut;
-use bitcoin::XOnlyPublicKey;
+use crate::transport::{NoiseKeypair, NoiseServerConfig, Transport};
+use sapio_ctv_emulator_trait::hd::sign_with_root;
 #[derive(Clone)]
 pub struct HDOracleEmulator {
     root: ExtendedPrivKey,
     debug: bool,
+    noise: Option<NoiseServerConfig>,
+    policy: Option<Arc<SigningPolicy>>,
+    audit: Option<Arc<AuditLog>>,
 }
 
 impl HDOracleEmulator {
@@ -23,7 +22,38 @@ impl HDOracleEmulator {
     ///
     /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
     pub fn new(root: ExtendedPrivKey, debug: bool) -> Self {
-        HDOracleEmulator { root, debug }
+        HDOracleEmulator {
+            root,
+            debug,
+            noise: None,
+            policy: None,
+            audit: None,
+        }
+    }
+    /// refuse to sign templates `policy` does not allow
+    pub fn with_policy(mut self, policy: SigningPolicy) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+    /// record every signing request in `log`
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(Arc::new(log));
+        self
+    }
+    /// accept Noise connections (see [`crate::transport`]) with `config`
+    pub fn with_noise(mut self, config: NoiseServerConfig) -> Self {
+        self.noise = Some(config);
+        self
+    }
+    /// a Noise keypair derived from the oracle's root key, so that its
+    /// static key can be pinned alongside its xpub
+    pub fn noise_keypair(&self) -> NoiseKeypair {
+        let tagged = [
+            &b"sapio/emulator/noise"[..],
+            &self.root.private_key.secret_bytes()[..],
+        ]
+        .concat();
+        NoiseKeypair::from_private(Sha256::hash(&tagged).into_inner())
     }
     /// binds a HDOracleEmulator to a socket interface and runs the server
     ///
@@ -33,14 +63,14 @@ impl HDOracleEmulator {
     pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
         let listener = TcpListener::bind(a).await?;
         loop {
-            let (mut socket, _) = listener.accept().await?;
+            let (socket, _) = listener.accept().await?;
             {
                 let this = self.clone();
                 let j: tokio::task::JoinHandle<Result<(), std::io::Error>> =
                     tokio::spawn(async move {
+                        let mut t = Transport::accept(socket, this.noise.as_ref()).await?;
                         loop {
-                            socket.readable().await?;
-                            this.handle(&mut socket).await?;
+                            this.handle(&mut t).await?;
                         }
                     });
                 if self.debug {
@@ -49,75 +79,41 @@ impl HDOracleEmulator {
             }
         }
     }
-    /// helper to get an EPK for the oracle.
-    fn derive(&self, h: Sha256, secp: &Secp256k1<All>) -> Result<ExtendedPrivKey, Error> {
-        let c = hash_to_child_vec(h);
-        self.root.derive_priv(secp, &c)
+    /// the root public key that clients derive the oracle's keys from
+    pub fn xpub(&self) -> ExtendedPubKey {
+        SECP.with(|secp| ExtendedPubKey::from_priv(secp, &self.root))
     }
-
     /// Signs a PSBT with the correct derived key.
     ///
     /// Always signs for spending index 0.
     ///
-    /// May fail to sign if the PSBT is not properly formatted
-    fn sign(
+    /// May fail to sign if the PSBT is not properly formatted, or if the
+    /// signing policy refuses it. Every request is first recorded in the
+    /// audit log, if there is one.
+    pub(crate) fn sign(
         &self,
-        mut b: PartiallySignedTransaction,
+        b: PartiallySignedTransaction,
         secp: &Secp256k1<All>,
     ) -> Result<PartiallySignedTransaction, std::io::Error> {
         let tx = b.clone().extract_tx();
         let h = tx.get_ctv_hash(0);
-        let utxos: Vec<TxOut> = b
-            .inputs
-            .iter()
-            .map(|o| o.witness_utxo.clone())
-            .collect::<Option<Vec<TxOut>>>()
-            .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
-        let key = self
-            .derive(h, secp)
-            .map_err(|_| input_err("Could Not Derive Key"))?;
-        let untweaked = key.to_keypair(secp);
-        let pk = XOnlyPublicKey::from_keypair(&untweaked);
-        let mut sighash = bitcoin::util::sighash::SigHashCache::new(&tx);
-        let input_zero = &mut b.inputs[0];
-        use bitcoin::schnorr::TapTweak;
-        let tweaked = untweaked
-            .tap_tweak(secp, input_zero.tap_merkle_root)
-            .into_inner();
-        let tweaked_pk = tweaked.public_key();
-        let hash_ty = bitcoin::util::sighash::SchnorrSigHashType::All;
-        let prevouts = &Prevouts::All(&utxos);
-        let mut get_sig = |path, kp| {
-            let annex = None;
-            let sighash: TapSighashHash = sighash
-                .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
-                .expect("Signature hash cannot fail...");
-            let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..])
-                .expect("Size must be correct.");
-            let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
-            SchnorrSig { sig, hash_ty }
-        };
-        if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
-            v.script_pubkey == Script::new_v1_p2tr_tweaked(tweaked_pk.dangerous_assume_tweaked())
-        }) {
-            let sig = get_sig(None, &tweaked);
-            input_zero.tap_key_sig = Some(sig);
+        let refused = self
+            .policy
+            .as_ref()
+            .and_then(|p| p.check(self.root.network, &tx, &b).err());
+        if let Some(log) = &self.audit {
+            log.append(&AuditRecord::new(h, &tx, refused.clone()))?;
         }
-        for tlh in input_zero
-            .tap_scripts
-            .values()
-            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
-        {
-            let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
-            input_zero.tap_script_sigs.insert((pk.clone(), tlh), sig);
+        if let Some(reason) = refused {
+            return input_error(&format!("Refused to sign: {}", reason));
         }
-        Ok(b)
+        sign_with_root(&self.root, b, secp)
     }
 
     /// the main server business logic.
     ///
     /// - on receiving Request::SignPSBT, signs the PSBT.
-    async fn handle(&self, t: &mut TcpStream) -> Result<(), std::io::Error> {
+    async fn handle(&self, t: &mut Transport) -> Result<(), std::io::Error> {
         let request = Self::requested(t).await?;
         match request {
             msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
@@ -127,23 +123,13 @@ impl HDOracleEmulator {
         }
     }
 
-    /// receive a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
-    async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        Ok(serde_json::from_slice(&v[..])?)
+    /// receive a request via the transport, which limits its length.
+    async fn requested(t: &mut Transport) -> Result<msgs::Request, std::io::Error> {
+        Ok(serde_json::from_slice(&t.recv().await?)?)
     }
 
-    /// respond via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await?;
-        t.flush().await
+    /// respond via the transport.
+    async fn respond<T: Serialize>(t: &mut Transport, r: &T) -> Result<(), std::io::Error> {
+        t.send(&serde_json::to_vec(r)?).await
     }
 }
@@ -0,0 +1,116 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! HTTP/JSON transport for the HD oracle emulator, for deployment behind
+//! ordinary load balancers and reverse proxies.
+//!
+//! - `GET /get_key` returns `{"xpub": ...}`
+//! - `POST /sign` takes and returns `{"psbt": <base64>}`
+//! - `GET /health` returns `{"serving": true}`
+use super::hd::HDOracleEmulator;
+use super::*;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::Infallible;
+
+/// The body of `POST /sign` and its response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignBody {
+    /// base64 encoded PSBT
+    pub psbt: String,
+}
+
+/// The response of `GET /get_key`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyBody {
+    /// the root key the oracle's keys are derived from
+    pub xpub: ExtendedPubKey,
+}
+
+/// The response of `GET /health`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthBody {
+    /// whether the oracle is able to serve requests
+    pub serving: bool,
+}
+
+fn json<T: Serialize>(status: StatusCode, t: &T) -> Response<Body> {
+    let mut r = Response::new(Body::from(
+        serde_json::to_vec(t).expect("serializing our own types cannot fail"),
+    ));
+    *r.status_mut() = status;
+    r.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    r
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": msg }))
+}
+
+/// read a body of at most `max` bytes
+async fn read_limited(mut body: Body, max: usize) -> Result<Vec<u8>, Response<Body>> {
+    let mut v = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        if v.len() + chunk.len() > max {
+            return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "PSBT Too Large"));
+        }
+        v.extend_from_slice(&chunk);
+    }
+    Ok(v)
+}
+
+impl HDOracleEmulator {
+    async fn sign_http(&self, body: Body) -> Result<Response<Body>, Response<Body>> {
+        // base64 plus JSON framing of a MAX_MSG PSBT
+        let bytes = read_limited(body, MAX_MSG * 4 / 3 + 1024).await?;
+        let req: SignBody = serde_json::from_slice(&bytes)
+            .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        let psbt = base64::decode(&req.psbt)
+            .map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        let unsigned: PartiallySignedTransaction =
+            deserialize(&psbt[..]).map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        let psbt = SECP
+            .with(|secp| self.sign(unsigned, secp))
+            .map_err(|e| error(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()))?;
+        Ok(json(
+            StatusCode::OK,
+            &SignBody {
+                psbt: base64::encode(serialize(&psbt)),
+            },
+        ))
+    }
+
+    async fn route(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let (method, path) = (req.method().clone(), req.uri().path().to_owned());
+        Ok(match (&method, path.as_str()) {
+            (&Method::GET, "/get_key") => json(StatusCode::OK, &KeyBody { xpub: self.xpub() }),
+            (&Method::GET, "/health") => json(StatusCode::OK, &HealthBody { serving: true }),
+            (&Method::POST, "/sign") => match self.sign_http(req.into_body()).await {
+                Ok(r) | Err(r) => r,
+            },
+            _ => error(StatusCode::NOT_FOUND, "Unknown Endpoint"),
+        })
+    }
+
+    /// binds a HDOracleEmulator to a socket address and serves the HTTP/JSON
+    /// endpoints.
+    ///
+    /// This only returns if the server fails.
+    pub async fn bind_http(self, a: SocketAddr) -> Result<(), hyper::Error> {
+        let make = make_service_fn(move |_conn| {
+            let this = self.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| this.clone().route(req))) }
+        });
+        hyper::Server::bind(&a).serve(make).await
+    }
+}
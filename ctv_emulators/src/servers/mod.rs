@@ -5,4 +5,9 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 pub mod hd;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod policy;
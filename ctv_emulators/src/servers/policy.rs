@@ -0,0 +1,201 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limits on what an oracle will sign, and an append-only record of what it
+//! signed (or refused to).
+use super::*;
+use bitcoin::{Amount, Network, Transaction};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A check on a template before it is signed, returning why it is refused
+pub type TemplateCheck =
+    dyn Fn(&Transaction, &PartiallySignedTransaction) -> Result<(), String> + Send + Sync;
+
+/// What an oracle is willing to sign. Everything is allowed by default;
+/// limits are added with the `with_*` methods.
+#[derive(Default)]
+pub struct SigningPolicy {
+    /// the most a template may send to its outputs
+    pub max_amount: Option<Amount>,
+    /// the networks the oracle's keys may be used on
+    pub networks: Option<Vec<Network>>,
+    /// the most templates signed per minute
+    pub max_per_minute: Option<usize>,
+    /// further checks, e.g. to inspect the outputs of templates
+    pub checks: Vec<Arc<TemplateCheck>>,
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl SigningPolicy {
+    /// Refuse templates sending more than `max`
+    pub fn with_max_amount(mut self, max: Amount) -> Self {
+        self.max_amount = Some(max);
+        self
+    }
+    /// Refuse to sign unless the oracle's keys are for one of `networks`
+    pub fn with_networks(mut self, networks: Vec<Network>) -> Self {
+        self.networks = Some(networks);
+        self
+    }
+    /// Refuse more than `max` templates in any minute
+    pub fn with_max_per_minute(mut self, max: usize) -> Self {
+        self.max_per_minute = Some(max);
+        self
+    }
+    /// Add a check run on every template
+    pub fn with_check<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Transaction, &PartiallySignedTransaction) -> Result<(), String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.checks.push(Arc::new(f));
+        self
+    }
+
+    /// Check whether `tx` may be signed by an oracle with keys on `network`,
+    /// counting it against the rate limit if so.
+    pub fn check(
+        &self,
+        network: Network,
+        tx: &Transaction,
+        psbt: &PartiallySignedTransaction,
+    ) -> Result<(), String> {
+        if let Some(networks) = &self.networks {
+            if !networks.contains(&network) {
+                return Err(format!("Network {} is not allowed", network));
+            }
+        }
+        if let Some(max) = self.max_amount {
+            let amount = Amount::from_sat(tx.output.iter().map(|o| o.value).sum());
+            if amount > max {
+                return Err(format!("Template sends {} > {}", amount, max));
+            }
+        }
+        for check in &self.checks {
+            check(tx, psbt)?;
+        }
+        if let Some(max) = self.max_per_minute {
+            let mut recent = self
+                .recent
+                .lock()
+                .map_err(|_| String::from("Rate limiter poisoned"))?;
+            let now = Instant::now();
+            while recent
+                .front()
+                .map_or(false, |t| now.duration_since(*t) > Duration::from_secs(60))
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= max {
+                return Err(String::from("Rate limit exceeded"));
+            }
+            recent.push_back(now);
+        }
+        Ok(())
+    }
+}
+
+/// # Audit Record
+/// One signing request an oracle received.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// seconds since the unix epoch
+    pub time: u64,
+    /// the template hash signed for
+    pub template: Sha256,
+    /// the txid of the transaction presented
+    pub txid: bitcoin::Txid,
+    /// the total sent to its outputs, in sats
+    pub amount: u64,
+    /// why it was refused, if it was
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub refused: Option<String>,
+}
+
+impl AuditRecord {
+    /// A record of the request to sign `tx` for `template`
+    pub fn new(template: Sha256, tx: &Transaction, refused: Option<String>) -> Self {
+        AuditRecord {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            template,
+            txid: tx.txid(),
+            amount: tx.output.iter().map(|o| o.value).sum(),
+            refused,
+        }
+    }
+}
+
+/// An append-only log of [`AuditRecord`]s, one JSON object per line
+pub struct AuditLog {
+    file: Mutex<std::fs::File>,
+}
+
+impl AuditLog {
+    /// Open (creating if needed) the log at `path`, appending to it
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, std::io::Error> {
+        Ok(AuditLog {
+            file: Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+        })
+    }
+    /// Append `r`, syncing it to disk before returning so nothing is signed
+    /// without a record
+    pub fn append(&self, r: &AuditRecord) -> Result<(), std::io::Error> {
+        let mut line = serde_json::to_vec(r)?;
+        line.push(b'\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| input_err("Audit log poisoned"))?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{Script, TxOut};
+    #[test]
+    fn enforces_limits() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        let policy = SigningPolicy::default()
+            .with_max_amount(Amount::from_sat(10_000))
+            .with_networks(vec![Network::Regtest])
+            .with_max_per_minute(2);
+        assert!(policy.check(Network::Bitcoin, &tx, &psbt).is_err());
+        assert!(policy.check(Network::Regtest, &tx, &psbt).is_ok());
+        assert!(policy.check(Network::Regtest, &tx, &psbt).is_ok());
+        assert!(policy.check(Network::Regtest, &tx, &psbt).is_err());
+        let policy = SigningPolicy::default().with_check(|tx, _| match tx.output.len() {
+            1 => Err("single output templates are not allowed".into()),
+            _ => Ok(()),
+        });
+        assert!(policy.check(Network::Regtest, &tx, &psbt).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An in-process emulator for tests and CI. Its keys are derived from a
+//! public seed, so anyone can sign for its templates: never use it for
+//! real funds.
+use crate::hd::{hash_to_child_vec, sign_with_root};
+use crate::{CTVEmulator, Clause, EmulatorError};
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::Network;
+
+/// InsecureDevEmulator signs synchronously with keys derived from a seed,
+/// exactly as an HD oracle server with the same root would, so no server
+/// needs to be running.
+pub struct InsecureDevEmulator {
+    root: ExtendedPrivKey,
+    secp: Secp256k1<All>,
+}
+
+impl InsecureDevEmulator {
+    /// Creates an emulator whose root key is derived deterministically from
+    /// `seed`.
+    pub fn from_seed(seed: &[u8], network: Network) -> Result<Self, EmulatorError> {
+        Ok(Self::new(ExtendedPrivKey::new_master(network, seed)?))
+    }
+    /// Creates an emulator signing with `root`.
+    pub fn new(root: ExtendedPrivKey) -> Self {
+        InsecureDevEmulator {
+            root,
+            secp: Secp256k1::new(),
+        }
+    }
+    /// the root public key, e.g. to configure a client of an HD oracle
+    /// server run with the same seed
+    pub fn xpub(&self) -> ExtendedPubKey {
+        ExtendedPubKey::from_priv(&self.secp, &self.root)
+    }
+}
+
+impl Default for InsecureDevEmulator {
+    /// an emulator with an all-zero seed on regtest
+    fn default() -> Self {
+        Self::from_seed(&[0u8; 32], Network::Regtest).expect("A 32 byte seed is always valid")
+    }
+}
+
+impl CTVEmulator for InsecureDevEmulator {
+    fn get_signer_for(&self, h: sha256::Hash) -> Result<Clause, EmulatorError> {
+        let key = self.root.derive_priv(&self.secp, &hash_to_child_vec(h))?;
+        Ok(Clause::Key(
+            ExtendedPubKey::from_priv(&self.secp, &key).to_x_only_pub(),
+        ))
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(sign_with_root(&self.root, b, &self.secp)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::schnorr::TapTweak;
+    use bitcoin::util::psbt::Input;
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+    use sapio_base::CTVHash;
+    #[test]
+    fn deterministic_keys_sign_their_template() {
+        let a = InsecureDevEmulator::from_seed(&[1u8; 32], Network::Regtest).unwrap();
+        let b = InsecureDevEmulator::from_seed(&[1u8; 32], Network::Regtest).unwrap();
+        let h = sha256::Hash::hash(b"template");
+        assert_eq!(a.get_signer_for(h).unwrap(), b.get_signer_for(h).unwrap());
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let key = match a.get_signer_for(tx.get_ctv_hash(0)).unwrap() {
+            Clause::Key(k) => k,
+            _ => panic!("expected a key"),
+        };
+        let (tweaked, _) = key.tap_tweak(&a.secp, None);
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0] = Input {
+            witness_utxo: Some(TxOut {
+                value: 2000,
+                script_pubkey: Script::new_v1_p2tr_tweaked(tweaked),
+            }),
+            ..Default::default()
+        };
+        let signed = a.sign(psbt).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+    }
+}
@@ -44,6 +44,12 @@ pub trait CTVEmulator: Sync + Send {
     /// For a given transaction hash, gets the corresponding Clause that the
     /// Emulator would satisfy.
     fn get_signer_for(&self, h: sha256::Hash) -> Result<Clause, EmulatorError>;
+    /// Gets the Clauses for many transaction hashes at once, in order.
+    /// Emulators which need a round trip per hash should override this to
+    /// make just one.
+    fn sign_batch(&self, hs: &[sha256::Hash]) -> Result<Vec<Clause>, EmulatorError> {
+        hs.iter().map(|h| self.get_signer_for(*h)).collect()
+    }
     /// Adds the Emulators signature to the PSBT, if any.
     fn sign(
         &self,
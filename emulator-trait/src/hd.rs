@@ -0,0 +1,112 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Key derivation and signing shared by every emulator deriving its keys from
+//! an HD root, whether in-process or behind a server.
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::schnorr::TapTweak;
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::util::sighash::Prevouts;
+use bitcoin::util::taproot::{TapLeafHash, TapSighashHash};
+use bitcoin::{SchnorrSig, Script, TxOut, XOnlyPublicKey};
+use sapio_base::CTVHash;
+
+fn input_err(s: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, s)
+}
+
+/// Compute a derivation path from a sha256 hash.
+///
+/// Format is a bit peculiar, it's 9 u32's with the top bit as 0 (for unhardened
+/// derivation). We take each u32 in the hash (big endian) and mask off the top bit.
+/// Then we go over the 8 u32s and make a 8 bit u32 from the top bits.
+///
+/// This is because the ChildNumber is a enum u31 where the top bit is used to
+/// indicate hardened or not, so we can't just do the simple thing.
+pub fn hash_to_child_vec(h: sha256::Hash) -> Vec<ChildNumber> {
+    let a: [u8; 32] = h.into_inner();
+    let b: [[u8; 4]; 8] = unsafe { std::mem::transmute(a) };
+    let mut c: Vec<ChildNumber> = b
+        .iter()
+        // Note: We mask off the top bit. This removes 8 bits of entropy from the hash,
+        // but we add it back in later.
+        .map(|x| (u32::from_be_bytes(*x) << 1) >> 1)
+        .map(ChildNumber::from)
+        .collect();
+    // Add a unique 9th path for the MSB's
+    c.push(
+        b.iter()
+            .enumerate()
+            .map(|(i, x)| (u32::from_be_bytes(*x) >> 31) << i)
+            .sum::<u32>()
+            .into(),
+    );
+    c
+}
+
+/// Signs input 0 of a PSBT with the key `root` derives for its CTV hash,
+/// both on the key path (if it is the output's internal key) and on every
+/// leaf present.
+///
+/// May fail to sign if the PSBT is not properly formatted.
+pub fn sign_with_root(
+    root: &ExtendedPrivKey,
+    mut b: PartiallySignedTransaction,
+    secp: &Secp256k1<All>,
+) -> Result<PartiallySignedTransaction, std::io::Error> {
+    let tx = b.clone().extract_tx();
+    let h = tx.get_ctv_hash(0);
+    let utxos: Vec<TxOut> = b
+        .inputs
+        .iter()
+        .map(|o| o.witness_utxo.clone())
+        .collect::<Option<Vec<TxOut>>>()
+        .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
+    let key = root
+        .derive_priv(secp, &hash_to_child_vec(h))
+        .map_err(|_| input_err("Could Not Derive Key"))?;
+    let untweaked = key.to_keypair(secp);
+    let pk = XOnlyPublicKey::from_keypair(&untweaked);
+    let mut sighash = bitcoin::util::sighash::SigHashCache::new(&tx);
+    let input_zero = b
+        .inputs
+        .get_mut(0)
+        .ok_or_else(|| input_err("PSBT has no inputs"))?;
+    let tweaked = untweaked
+        .tap_tweak(secp, input_zero.tap_merkle_root)
+        .into_inner();
+    let tweaked_pk = tweaked.public_key();
+    let hash_ty = bitcoin::util::sighash::SchnorrSigHashType::All;
+    let prevouts = &Prevouts::All(&utxos);
+    let mut get_sig = |path, kp| {
+        let annex = None;
+        let sighash: TapSighashHash = sighash
+            .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
+            .expect("Signature hash cannot fail...");
+        let msg =
+            bitcoin::secp256k1::Message::from_slice(&sighash[..]).expect("Size must be correct.");
+        let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+        SchnorrSig { sig, hash_ty }
+    };
+    if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
+        v.script_pubkey == Script::new_v1_p2tr_tweaked(tweaked_pk.dangerous_assume_tweaked())
+    }) {
+        let sig = get_sig(None, &tweaked);
+        input_zero.tap_key_sig = Some(sig);
+    }
+    for tlh in input_zero
+        .tap_scripts
+        .values()
+        .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+    {
+        let sig = get_sig(Some((tlh, 0xffffffff)), &untweaked);
+        input_zero.tap_script_sigs.insert((pk.clone(), tlh), sig);
+    }
+    Ok(b)
+}
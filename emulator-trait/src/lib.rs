@@ -4,6 +4,10 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+#[deny(missing_docs)]
+pub mod dev;
 #[deny(missing_docs)]
 pub mod emulator;
+#[deny(missing_docs)]
+pub mod hd;
 pub use emulator::*;
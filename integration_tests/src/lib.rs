@@ -1,7 +1,60 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! End-to-end testing of compiled contracts on regtest: start (or attach
+//! to) a bitcoind with [`node::RegtestNode`], serve a CTV emulator with
+//! [`oracle::Oracle`], and fund and mine a contract's template tree with
+//! [`runner::run_contract`], checking who owns the coins at the end.
+use bitcoin::Txid;
+use bitcoincore_rpc_async as rpc;
+use sapio_tools::core_wallet::CoreWalletError;
+
+pub mod node;
+pub mod oracle;
+pub mod runner;
+
+/// Errors from running a contract on regtest
+#[derive(Debug)]
+pub enum RegtestError {
+    /// bitcoind could not be started, or the oracle served
+    Io(std::io::Error),
+    /// The node returned an error
+    Rpc(rpc::Error),
+    /// Funding or binding the contract failed
+    Wallet(CoreWalletError),
+    /// The node is not on regtest, but the named chain
+    NotRegtest(String),
+    /// A transaction was still not accepted after waiting the maximum
+    /// number of blocks
+    NeverMatured(Txid),
+    /// A transaction did not confirm in the block mined for it
+    Unconfirmed(Txid),
+    /// Something else unexpected
+    Malformed(String),
+}
+impl std::fmt::Display for RegtestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for RegtestError {}
+impl From<std::io::Error> for RegtestError {
+    fn from(e: std::io::Error) -> Self {
+        RegtestError::Io(e)
+    }
+}
+impl From<rpc::Error> for RegtestError {
+    fn from(e: rpc::Error) -> Self {
+        RegtestError::Rpc(e)
+    }
+}
+impl From<CoreWalletError> for RegtestError {
+    fn from(e: CoreWalletError) -> Self {
+        RegtestError::Wallet(e)
     }
 }
+
+type Result<T> = std::result::Result<T, RegtestError>;
@@ -0,0 +1,185 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A regtest bitcoind to run contracts against, either started for the test
+//! or already running.
+use super::{RegtestError, Result};
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use sapio_tools::core_wallet::CoreWallet;
+use serde_json::json;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Environment variable naming the bitcoind binary to start
+pub const BITCOIND_ENV: &str = "BITCOIND";
+/// Environment variable with the RPC url of a running regtest node to use
+/// instead of starting one
+pub const REGTEST_URL_ENV: &str = "SAPIO_REGTEST_URL";
+/// Environment variable with the cookie file for [`REGTEST_URL_ENV`]
+pub const REGTEST_COOKIE_ENV: &str = "SAPIO_REGTEST_COOKIE";
+/// Seconds of (mock) time each mined block advances the clock
+pub const BLOCK_INTERVAL_SECS: u64 = 600;
+const WALLET: &str = "sapio-regtest";
+
+/// A regtest node with a funded wallet. Blocks are mined with mock time
+/// advancing [`BLOCK_INTERVAL_SECS`] per block, so that relative and
+/// absolute time locks can mature without waiting.
+pub struct RegtestNode {
+    /// RPC Client, for the node's wallet
+    pub client: rpc::Client,
+    url: String,
+    auth: rpc::Auth,
+    mining_address: String,
+    mock_time: u64,
+    process: Option<Child>,
+    datadir: Option<PathBuf>,
+}
+
+fn free_port() -> Result<u16> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port())
+}
+
+impl RegtestNode {
+    /// Use the node in [`REGTEST_URL_ENV`] if set, otherwise start one
+    pub async fn from_env() -> Result<Self> {
+        match std::env::var(REGTEST_URL_ENV) {
+            Ok(url) => {
+                let cookie = std::env::var(REGTEST_COOKIE_ENV).map_err(|_| {
+                    RegtestError::Malformed(format!("{} unset", REGTEST_COOKIE_ENV))
+                })?;
+                Self::attach(url, rpc::Auth::CookieFile(cookie.into())).await
+            }
+            Err(_) => Self::start().await,
+        }
+    }
+
+    /// Start a bitcoind ([`BITCOIND_ENV`], or `bitcoind` on the PATH) in a
+    /// fresh data directory. It is stopped and the directory removed when
+    /// this is dropped.
+    pub async fn start() -> Result<Self> {
+        let bin = std::env::var(BITCOIND_ENV).unwrap_or_else(|_| "bitcoind".into());
+        let rpc_port = free_port()?;
+        let datadir = std::env::temp_dir().join(format!("sapio-regtest-{}", rpc_port));
+        std::fs::create_dir_all(&datadir)?;
+        let process = Command::new(bin)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg("-rpcuser=sapio")
+            .arg("-rpcpassword=sapio")
+            .arg("-listen=0")
+            .arg("-server=1")
+            .arg("-txindex=1")
+            .arg("-fallbackfee=0.0002")
+            .stdout(Stdio::null())
+            .spawn()?;
+        let url = format!("http://127.0.0.1:{}", rpc_port);
+        let auth = rpc::Auth::UserPass("sapio".into(), "sapio".into());
+        let mut node = Self::connect(url, auth, Some(process), Some(datadir)).await?;
+        node.client
+            .call::<serde_json::Value>("createwallet", &[json!(WALLET)])
+            .await?;
+        node.reconnect_to_wallet().await?;
+        node.mining_address = node.new_address().await?;
+        // coinbases need 100 confirmations to be spent
+        node.mine(101).await?;
+        Ok(node)
+    }
+
+    /// Use the running regtest node at `url`, which must have a loaded
+    /// wallet with funds to spend
+    pub async fn attach(url: String, auth: rpc::Auth) -> Result<Self> {
+        let mut node = Self::connect(url, auth, None, None).await?;
+        node.mining_address = node.new_address().await?;
+        Ok(node)
+    }
+
+    async fn connect(
+        url: String,
+        auth: rpc::Auth,
+        process: Option<Child>,
+        datadir: Option<PathBuf>,
+    ) -> Result<Self> {
+        let mut node = RegtestNode {
+            client: rpc::Client::new(url.clone(), auth.clone()).await?,
+            url,
+            auth,
+            mining_address: String::new(),
+            mock_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|e| RegtestError::Malformed(e.to_string()))?
+                .as_secs(),
+            process,
+            datadir,
+        };
+        // wait for the node to finish starting up
+        let mut tries = 0;
+        loop {
+            match node.client.get_blockchain_info().await {
+                Ok(info) if info.chain == "regtest" => break,
+                Ok(info) => return Err(RegtestError::NotRegtest(info.chain)),
+                Err(e) if tries > 60 => return Err(e.into()),
+                Err(_) => tries += 1,
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        node.client
+            .call::<serde_json::Value>("setmocktime", &[json!(node.mock_time)])
+            .await?;
+        Ok(node)
+    }
+
+    async fn reconnect_to_wallet(&mut self) -> Result<()> {
+        self.url = format!("{}/wallet/{}", self.url, WALLET);
+        self.client = rpc::Client::new(self.url.clone(), self.auth.clone()).await?;
+        Ok(())
+    }
+
+    /// A new address of the node's wallet
+    pub async fn new_address(&self) -> Result<String> {
+        Ok(self.client.call("getnewaddress", &[]).await?)
+    }
+
+    /// A [`CoreWallet`] for the node's wallet
+    pub async fn wallet(&self) -> Result<CoreWallet> {
+        Ok(CoreWallet::new(
+            rpc::Client::new(self.url.clone(), self.auth.clone()).await?,
+        ))
+    }
+
+    /// Mine `n` blocks, advancing mock time by [`BLOCK_INTERVAL_SECS`] each
+    pub async fn mine(&mut self, n: u32) -> Result<()> {
+        for _ in 0..n {
+            self.mock_time += BLOCK_INTERVAL_SECS;
+            self.client
+                .call::<serde_json::Value>("setmocktime", &[json!(self.mock_time)])
+                .await?;
+            self.client
+                .call::<serde_json::Value>(
+                    "generatetoaddress",
+                    &[json!(1), json!(self.mining_address)],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RegtestNode {
+    fn drop(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+        if let Some(datadir) = self.datadir.take() {
+            let _ = std::fs::remove_dir_all(datadir);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A CTV emulator oracle served locally for the duration of a test.
+use super::Result;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::servers::hd::HDOracleEmulator;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+/// An [`HDOracleEmulator`] listening on a local port, and a connection to
+/// it. The server is shut down when this is dropped.
+pub struct Oracle {
+    /// the connection to the oracle, to compile and bind contracts with
+    pub connection: Arc<dyn CTVEmulator>,
+    shutdown: Option<oneshot::Sender<()>>,
+    // kept so the connection can make requests
+    _runtime: Arc<Runtime>,
+}
+
+impl Oracle {
+    /// Serve an oracle with the key `root` on a free local port. Must not
+    /// be called from within an async context.
+    pub fn start(root: ExtendedPrivKey) -> Result<Self> {
+        let secp = Arc::new(Secp256k1::new());
+        let pk_root = ExtendedPubKey::from_private(&secp, &root);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        drop(listener);
+        let (shutdown, quit) = oneshot::channel();
+        let (ready, bound) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let oracle = HDOracleEmulator::new(root, true);
+            match Runtime::new() {
+                Ok(rt) => rt.block_on(async {
+                    let server = tokio::spawn(oracle.bind(address));
+                    let _ = ready.send(());
+                    let _ = quit.await;
+                    server.abort();
+                }),
+                Err(_) => drop(ready),
+            }
+        });
+        bound.recv().map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::Other, "oracle failed to start")
+        })?;
+        let runtime = Arc::new(Runtime::new()?);
+        let connection = runtime.block_on(HDOracleEmulatorConnection::new(
+            address,
+            pk_root,
+            runtime.clone(),
+            secp,
+        ))?;
+        Ok(Oracle {
+            connection: Arc::new(connection),
+            shutdown: Some(shutdown),
+            _runtime: runtime,
+        })
+    }
+}
+
+impl Drop for Oracle {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fund a compiled contract on regtest and mine its template tree.
+use super::node::RegtestNode;
+use super::{RegtestError, Result};
+use bitcoin::util::amount::Amount;
+use bitcoin::{OutPoint, Script, Transaction, TxOut};
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use sapio::contract::abi::broadcast::BroadcastAfter;
+use sapio::contract::Compiled;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use sapio_tools::core_wallet::{CoreWallet, Executable, Funding};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// What happened when a contract was run
+#[derive(Debug, Clone)]
+pub struct Execution {
+    /// the funding transaction
+    pub funding: Funding,
+    /// the contract's transactions confirmed, in order, with the path of
+    /// the contract each spent
+    pub txs: Vec<(SArc<EffectPath>, Transaction)>,
+    /// the outputs left unspent at the end, as confirmed by the node
+    pub utxos: Vec<(OutPoint, TxOut)>,
+}
+
+impl Execution {
+    /// The total of the unspent outputs paying `script`
+    pub fn owned_by(&self, script: &Script) -> Amount {
+        Amount::from_sat(
+            self.utxos
+                .iter()
+                .filter(|(_, o)| o.script_pubkey == *script)
+                .map(|(_, o)| o.value)
+                .sum(),
+        )
+    }
+
+    /// Panic unless each script in `expected` is paid exactly its amount
+    pub fn assert_owned(&self, expected: &[(Script, Amount)]) {
+        for (script, amount) in expected {
+            assert_eq!(
+                self.owned_by(script),
+                *amount,
+                "wrong amount paid to {} in {:?}",
+                script,
+                self.utxos
+            );
+        }
+    }
+}
+
+/// Fund `compiled` with `amount` from `node`'s wallet, then walk its
+/// template tree: for each output created, `choose` picks which of the
+/// transactions spending it to broadcast (or None to leave it unspent).
+/// Blocks are mined until each chosen transaction's timelocks mature and
+/// then to confirm it. Fails if a transaction is still not accepted after
+/// `max_wait` blocks.
+///
+/// Only transactions which can be finalized with `emulator`'s signatures
+/// alone are run, see [`CoreWallet::executable_txs`].
+pub async fn run_contract<F>(
+    node: &mut RegtestNode,
+    compiled: &Compiled,
+    amount: Amount,
+    emulator: &dyn CTVEmulator,
+    choose: F,
+    max_wait: u32,
+) -> Result<Execution>
+where
+    F: Fn(&[&Executable]) -> Option<usize>,
+{
+    let wallet = node.wallet().await?;
+    let funding = wallet.fund(compiled, amount).await?;
+    node.mine(1).await?;
+    let txs = CoreWallet::executable_txs(compiled, &funding, emulator)?;
+    let height = confirmed(&wallet, &funding.outpoint.txid).await?;
+    let mut outputs: HashMap<OutPoint, TxOut> = HashMap::new();
+    outputs.insert(
+        funding.outpoint,
+        funding.tx.output[funding.outpoint.vout as usize].clone(),
+    );
+    let mut frontier = vec![(funding.outpoint, height)];
+    let mut confirmed_txs = vec![];
+    let mut unspent = vec![];
+    while let Some((out, height)) = frontier.pop() {
+        let candidates: Vec<&Executable> = txs
+            .iter()
+            .filter(|e| e.tx.input[0].previous_output == out)
+            .collect();
+        let chosen = match choose(&candidates) {
+            Some(i) if i < candidates.len() => candidates[i],
+            _ => {
+                unspent.push(out);
+                continue;
+            }
+        };
+        let txid = broadcast_when_mature(node, &wallet, &chosen.tx, height, max_wait).await?;
+        node.mine(1).await?;
+        let height = confirmed(&wallet, &txid).await?;
+        for (vout, o) in chosen.tx.output.iter().enumerate() {
+            let out = OutPoint::new(txid, vout as u32);
+            outputs.insert(out, o.clone());
+            frontier.push((out, height));
+        }
+        confirmed_txs.push((chosen.path.clone(), chosen.tx.clone()));
+    }
+    let mut utxos = vec![];
+    for out in unspent {
+        let found: serde_json::Value = node
+            .client
+            .call("gettxout", &[json!(out.txid.to_string()), json!(out.vout)])
+            .await?;
+        if !found.is_null() {
+            utxos.push((out, outputs[&out].clone()));
+        }
+    }
+    Ok(Execution {
+        funding,
+        txs: confirmed_txs,
+        utxos,
+    })
+}
+
+async fn confirmed(wallet: &CoreWallet, txid: &bitcoin::Txid) -> Result<u32> {
+    wallet
+        .confirmation_height(txid)
+        .await?
+        .ok_or(RegtestError::Unconfirmed(*txid))
+}
+
+/// Mine blocks until `tx`, spending an output confirmed at
+/// `funding_height`, is accepted by the node
+async fn broadcast_when_mature(
+    node: &mut RegtestNode,
+    wallet: &CoreWallet,
+    tx: &Transaction,
+    funding_height: u32,
+    max_wait: u32,
+) -> Result<bitcoin::Txid> {
+    for _ in 0..=max_wait {
+        let tip = wallet.chain_tip().await?;
+        let after = BroadcastAfter::compute(tx, Some(funding_height), Some(tip));
+        if after.ready != Some(false) {
+            match wallet.client.send_raw_transaction(tx).await {
+                Ok(txid) => return Ok(txid),
+                // relative time locks can't be checked ahead of time, so
+                // keep mining for those
+                Err(e) if after.ready == Some(true) => return Err(e.into()),
+                Err(_) => (),
+            }
+        }
+        node.mine(1).await?;
+    }
+    Err(RegtestError::NeverMatured(tx.txid()))
+}
@@ -122,6 +122,7 @@ fn test_connect() {
         HashMap::new(),
         txindex,
         rc_conn.as_ref(),
+        sapio::contract::object::PsbtVersion::V0,
     );
     use bitcoin::psbt::PartiallySignedTransaction;
     use sapio::contract::abi::studio::SapioStudioFormat;
@@ -133,7 +134,6 @@ fn test_connect() {
                     let mut psbt = PartiallySignedTransaction::from_str(&psbt).unwrap();
                     miniscript::psbt::finalize(&mut psbt, &secp).unwrap();
                     println!("{}", psbt.to_string());
-
                 }
             }
         }
@@ -141,3 +141,54 @@ fn test_connect() {
     shutdown.send(()).unwrap();
     // TODO: Test PSBT result
 }
+
+/// Mines a two-step relative timelocked chain of templates on regtest and
+/// checks the wallet ends up with the coins. Needs bitcoind, see
+/// [`sapio_integration_tests::node::RegtestNode::from_env`].
+#[test]
+#[ignore]
+fn test_regtest_execution() {
+    use sapio_integration_tests::node::RegtestNode;
+    use sapio_integration_tests::oracle::Oracle;
+    use sapio_integration_tests::runner::run_contract;
+    let root =
+        ExtendedPrivKey::new_master(bitcoin::network::constants::Network::Regtest, &[45u8; 32])
+            .unwrap();
+    let oracle = Oracle::start(root).unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut node = RegtestNode::from_env().await.unwrap();
+        let to = bitcoin::Address::from_str(&node.new_address().await.unwrap()).unwrap();
+        let paid = Amount::from_btc(0.9998).unwrap();
+        let contract = TestEmulation {
+            to_contract: TestEmulation {
+                to_contract: Compiled::from_address(to.clone(), None),
+                amount: paid,
+                timeout: 6,
+            },
+            amount: Amount::from_btc(0.9999).unwrap(),
+            timeout: 4,
+        };
+        let compiled = contract
+            .compile(Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_btc(1.0).unwrap(),
+                oracle.connection.clone(),
+                EffectPath::try_from("regtest_execution").unwrap(),
+                Arc::new(Default::default()),
+            ))
+            .unwrap();
+        let execution = run_contract(
+            &mut node,
+            &compiled,
+            Amount::from_btc(1.0).unwrap(),
+            oracle.connection.as_ref(),
+            |candidates| if candidates.is_empty() { None } else { Some(0) },
+            20,
+        )
+        .await
+        .unwrap();
+        assert_eq!(execution.txs.len(), 2);
+        execution.assert_owned(&[(to.script_pubkey(), paid)]);
+    });
+}
@@ -101,4 +101,4 @@ impl TryFrom<PoolTypes> for CoinPool {
         }
     }
 }
-REGISTER![[CoinPool, PoolTypes], "logo.png"];
+REGISTER![[CoinPool, PoolTypes], "logo.png"; uses [CtvEmulator]];
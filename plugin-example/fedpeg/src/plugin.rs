@@ -24,4 +24,4 @@ impl From<Wrap> for FederatedPeg {
     }
 }
 
-REGISTER![[FederatedPeg, Wrap], "logo.png"];
+REGISTER![[FederatedPeg, Wrap], "logo.png"; uses [CtvEmulator]];
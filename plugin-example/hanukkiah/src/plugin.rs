@@ -21,4 +21,4 @@ impl From<Wrap> for Hanukkiah2 {
     }
 }
 
-REGISTER![[Hanukkiah2, Wrap], "logo.png"];
+REGISTER![[Hanukkiah2, Wrap], "logo.png"; uses [CtvEmulator]];
@@ -63,4 +63,4 @@ impl Contract for TrustlessEscrow {
     declare! {non updatable}
 }
 
-REGISTER![TrustlessEscrow, "logo.png"];
+REGISTER![TrustlessEscrow, "logo.png"; uses [CtvEmulator]];
@@ -265,4 +265,4 @@ impl<S: State + 'static> Contract for Vault<S> {
     declare! {then, Self::backup, Self::begin_redeem}
 }
 type JamesVault = Vault<Secure>;
-REGISTER![JamesVault, "logo.png"];
+REGISTER![JamesVault, "logo.png"; uses [CtvEmulator]];
@@ -119,7 +119,7 @@ impl TryFrom<Versions> for NFTDutchAuction {
     }
 }
 
-REGISTER![[NFTDutchAuction, Versions], "logo.png"];
+REGISTER![[NFTDutchAuction, Versions], "logo.png"; uses [CtvEmulator, LookupModule, GetApi, CreateContract]];
 
 impl NFTDutchAuction {
     /// # signed
@@ -43,7 +43,7 @@ impl From<Versions> for SimpleNFTSale {
     }
 }
 
-REGISTER![[SimpleNFTSale, Versions], "logo.png"];
+REGISTER![[SimpleNFTSale, Versions], "logo.png"; uses [CtvEmulator, LookupModule, GetApi, CreateContract]];
 
 impl SimpleNFTSale {
     /// # transfer
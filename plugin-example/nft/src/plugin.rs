@@ -147,4 +147,4 @@ impl TryFrom<Versions> for SimpleNFT {
         }
     }
 }
-REGISTER![[SimpleNFT, Versions], "logo.png"];
+REGISTER![[SimpleNFT, Versions], "logo.png"; uses [CtvEmulator, LookupModule, GetApi, CreateContract]];
@@ -21,4 +21,4 @@ impl From<Wrapped> for ChainReturn {
     }
 }
 
-REGISTER![[ChainReturn, Wrapped], "logo.png"];
+REGISTER![[ChainReturn, Wrapped], "logo.png"; uses [CtvEmulator]];
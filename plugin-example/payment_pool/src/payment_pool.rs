@@ -225,10 +225,7 @@ impl PaymentPool {
             tmpl = tmpl.add_output(
                 spent,
                 // TODO: Fix this treepay
-                &TreePay {
-                    participants: all_payments,
-                    radix: 4,
-                },
+                &TreePay::new(all_payments, 4),
                 None,
             )?;
         } else {
@@ -243,4 +240,4 @@ impl PaymentPool {
         tmpl.into()
     }
 }
-REGISTER![PaymentPool, "logo.png"];
+REGISTER![PaymentPool, "logo.png"; uses [CtvEmulator]];
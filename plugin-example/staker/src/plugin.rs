@@ -22,4 +22,4 @@ impl From<Wrapper> for BondedStaker {
         v.0
     }
 }
-REGISTER![[BondedStaker, Wrapper], "logo.png"];
+REGISTER![[BondedStaker, Wrapper], "logo.png"; uses [CtvEmulator]];
@@ -20,8 +20,10 @@ use std::sync::Arc;
 #[derive(JsonSchema, Serialize, Deserialize)]
 pub struct TrampolinePay {
     /// # Which Plugin to Use
-    /// Specify which contract plugin to call out to.
-    handle: SapioHostAPI<BatchingTraitVersion0_1_1>,
+    /// Specify which contract plugin to call out to. If none is given, any
+    /// plugin the host knows of which implements the batching trait is used.
+    #[serde(default)]
+    handle: Option<SapioHostAPI<BatchingTraitVersion0_1_1>>,
     /// # Data for the Contract
     // Just do this to get the data... not always necessary (could be computed any way)
     data: BatchingTraitVersion0_1_1,
@@ -34,11 +36,24 @@ enum Versions {
     BatchingTraitVersion0_1_1(BatchingTraitVersion0_1_1),
 }
 impl TrampolinePay {
+    /// the key of the plugin to call out to
+    fn plugin(&self) -> Result<[u8; 32], CompilationError> {
+        if let Some(handle) = &self.handle {
+            return Ok(handle.key);
+        }
+        let this = lookup_this_module_name();
+        lookup_plugins_implementing::<BatchingTraitVersion0_1_1>()?
+            .into_iter()
+            .map(|handle| handle.key)
+            .find(|key| Some(*key) != this)
+            .ok_or(CompilationError::UnknownModule)
+    }
+
     #[then]
     fn expand(self, mut ctx: Context) {
         let contract = create_contract_by_key(
             ctx.derive_str(Arc::new("plugin_trampoline".into()))?,
-            &self.handle.key,
+            &self.plugin()?,
             CreateArgs {
                 context: ContextualArguments {
                     amount: ctx.funds(),
@@ -58,4 +73,4 @@ impl Contract for TrampolinePay {
     declare! {non updatable}
 }
 
-REGISTER![TrampolinePay, "logo.png"];
+REGISTER![TrampolinePay, "logo.png"; uses [CtvEmulator, LookupModule, GetApi, CreateContract]];
@@ -151,4 +151,4 @@ impl From<Versions> for TreePay {
         }
     }
 }
-REGISTER![[TreePay, Versions], "logo.png"];
+REGISTER![[TreePay, Versions], "logo.png"; uses [CtvEmulator]];
@@ -29,4 +29,4 @@ impl TryFrom<Versions> for Vault {
         }
     }
 }
-REGISTER![[Vault, Versions], "logo.png"];
+REGISTER![[Vault, Versions], "logo.png"; uses [CtvEmulator]];
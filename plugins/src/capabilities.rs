@@ -0,0 +1,49 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The host functions a plugin declares it needs.
+//!
+//! Plugins are embedded with a [`PluginManifest`] (see the `REGISTER!`
+//! macro) listing the [`Capability`]s they use, and the host refuses any
+//! call to a host function which the plugin did not declare. Logging, and
+//! looking up the plugin's own key, are always allowed.
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// # Capability
+/// A group of host functions a plugin may be granted
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Get the conditions and signatures of the host's CTV emulator, which
+    /// any contract with CTV templates needs when compiled under an emulator
+    CtvEmulator,
+    /// Look up the key of another plugin by its name
+    LookupModule,
+    /// Get the API of another plugin
+    GetApi,
+    /// Create contracts with another plugin
+    CreateContract,
+}
+
+/// # Plugin Manifest
+/// What a plugin declares about itself to the host
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PluginManifest {
+    /// # Capabilities
+    /// The host functions the plugin may call
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl PluginManifest {
+    /// Whether the plugin declared `c`
+    pub fn allows(&self, c: Capability) -> bool {
+        self.capabilities.contains(&c)
+    }
+}
@@ -8,6 +8,7 @@ use super::*;
 use core::convert::TryFrom;
 ///! Wraps the external API with friendly methods
 use sapio::contract::CompilationError;
+use sapio::template::ContractVersion;
 use sapio_trait::SapioJSONTrait;
 use std::marker::PhantomData;
 /// Print a &str to the parent's console.
@@ -39,9 +40,9 @@ pub fn create_contract_by_key<S: Serialize>(
         );
         if p != 0 {
             let cs = CString::from_raw(p as *mut c_char);
-            let res: Result<Compiled, String> = serde_json::from_slice(cs.as_bytes())
+            let res: Result<Compiled, WasmCompilationError> = serde_json::from_slice(cs.as_bytes())
                 .map_err(CompilationError::DeserializationError)?;
-            res.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+            res.map_err(CompilationError::from)
         } else {
             Err(CompilationError::InternalModuleError("Unknown".into()))
         }
@@ -86,6 +87,15 @@ pub fn lookup_this_module_name() -> Option<[u8; 32]> {
     }
 }
 
+/// Get the [`ContractVersion`] of the current executing module, for use with
+/// `Builder::commit_contract_version`.
+pub fn this_contract_version(schema_version: u32) -> Option<ContractVersion> {
+    lookup_this_module_name().map(|module| ContractVersion {
+        module,
+        schema_version,
+    })
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
 /// # Lookup Parameters
 /// - either using a hash key (exact); or
@@ -194,6 +204,85 @@ pub fn create_contract<S: Serialize>(
     create_contract_by_key(context, &key, args)
 }
 
+/// Find every plugin known to the host which implements the trait `T`, so
+/// that a contract can pick an implementation at runtime rather than
+/// depending on a particular module's hash. Plugins are returned in the
+/// order of their keys.
+pub fn lookup_plugins_implementing<T: SapioJSONTrait>(
+) -> Result<Vec<SapioHostAPI<T>>, CompilationError> {
+    let apis: Vec<(String, serde_json::Value)> = unsafe {
+        let p = sapio_v1_wasm_plugin_list_plugin_apis();
+        if p == 0 {
+            return Err(CompilationError::InternalModuleError(
+                "Plugin APIs Not Available".into(),
+            ));
+        }
+        let cs = CString::from_raw(p as *mut c_char);
+        serde_json::from_slice(cs.as_bytes()).map_err(CompilationError::DeserializationError)?
+    };
+    Ok(apis
+        .into_iter()
+        .filter(|(_, api)| T::check_trait_implemented_inner(api).is_ok())
+        .filter_map(|(hash, api)| {
+            let which_plugin = LookupFrom::HashKey(hash);
+            Some(SapioHostAPI {
+                key: which_plugin.to_key()?,
+                which_plugin,
+                api,
+                _pd: Default::default(),
+            })
+        })
+        .collect())
+}
+
+/// Read the `Result<T, String>` the host's emulator returned at `p`
+unsafe fn emulator_result<T: for<'a> Deserialize<'a>>(
+    p: i32,
+) -> Result<T, sapio_ctv_emulator_trait::EmulatorError> {
+    let host_err = |s: String| -> sapio_ctv_emulator_trait::EmulatorError {
+        std::io::Error::new(std::io::ErrorKind::Other, s).into()
+    };
+    if p == 0 {
+        return Err(host_err("Emulator Not Available".into()));
+    }
+    let cs = CString::from_raw(p as *mut c_char);
+    let res: Result<T, String> =
+        serde_json::from_slice(cs.as_bytes()).map_err(|e| host_err(e.to_string()))?;
+    res.map_err(host_err)
+}
+
+/// Get the clause the host's CTV emulator will satisfy for the template
+/// hash `h`. Requires the `CtvEmulator` capability.
+pub fn ctv_emulator_signer_for(
+    h: bitcoin::hashes::sha256::Hash,
+) -> Result<
+    miniscript::policy::concrete::Policy<bitcoin::XOnlyPublicKey>,
+    sapio_ctv_emulator_trait::EmulatorError,
+> {
+    let mut inner = h.into_inner();
+    unsafe {
+        emulator_result(sapio_v1_wasm_plugin_ctv_emulator_signer_for(
+            &mut inner[0] as *mut u8 as i32,
+        ))
+    }
+}
+
+/// Get the host's CTV emulator to sign `psbt`. Requires the `CtvEmulator`
+/// capability.
+pub fn ctv_emulator_sign(
+    psbt: bitcoin::util::psbt::PartiallySignedTransaction,
+) -> Result<bitcoin::util::psbt::PartiallySignedTransaction, sapio_ctv_emulator_trait::EmulatorError>
+{
+    let s = serde_json::to_string_pretty(&psbt)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    unsafe {
+        emulator_result(sapio_v1_wasm_plugin_ctv_emulator_sign(
+            s.as_ptr() as i32,
+            s.len() as u32,
+        ))
+    }
+}
+
 /// A empty type tag to bind the dynamically linked host emulator functionality
 pub struct WasmHostEmulator;
 impl CTVEmulator for WasmHostEmulator {
@@ -204,12 +293,7 @@ impl CTVEmulator for WasmHostEmulator {
         miniscript::policy::concrete::Policy<bitcoin::XOnlyPublicKey>,
         sapio_ctv_emulator_trait::EmulatorError,
     > {
-        let mut inner = h.into_inner();
-        let signer = unsafe {
-            sapio_v1_wasm_plugin_ctv_emulator_signer_for(&mut inner[0] as *mut u8 as i32)
-        };
-        let signer = unsafe { CString::from_raw(signer as *mut c_char) };
-        Ok(serde_json::from_slice(signer.to_bytes()).unwrap())
+        ctv_emulator_signer_for(h)
     }
     fn sign(
         &self,
@@ -218,15 +302,6 @@ impl CTVEmulator for WasmHostEmulator {
         bitcoin::util::psbt::PartiallySignedTransaction,
         sapio_ctv_emulator_trait::EmulatorError,
     > {
-        let s = serde_json::to_string_pretty(&psbt).unwrap();
-        let len = s.len();
-        let ret = unsafe {
-            CString::from_raw(
-                sapio_v1_wasm_plugin_ctv_emulator_sign(s.as_ptr() as i32, len as u32)
-                    as *mut c_char,
-            )
-        };
-        let j = serde_json::from_slice(ret.as_bytes()).unwrap();
-        Ok(j)
+        ctv_emulator_sign(psbt)
     }
 }
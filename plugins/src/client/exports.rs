@@ -83,3 +83,16 @@ unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_logo() -> *mut c_char {
         .unwrap()
         .into_raw()
 }
+
+pub(crate) static mut SAPIO_PLUGIN_CAPABILITIES: &'static [Capability] = &[];
+/// Gets the plugin's [`PluginManifest`], as JSON.
+/// host must drop the returned pointer.
+#[no_mangle]
+unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_manifest() -> *mut c_char {
+    let manifest = PluginManifest {
+        capabilities: SAPIO_PLUGIN_CAPABILITIES.iter().cloned().collect(),
+    };
+    CString::new(serde_json::to_string(&manifest).unwrap())
+        .unwrap()
+        .into_raw()
+}
@@ -7,9 +7,11 @@
 //! External symbols that must be provided by the WASM host
 
 extern "C" {
-    /// get the oracle to sign the psbt passed in
+    /// get the oracle to sign the psbt passed in, returning a
+    /// `Result<PartiallySignedTransaction, String>`
     pub fn sapio_v1_wasm_plugin_ctv_emulator_sign(psbt: i32, len: u32) -> i32;
-    /// for the provided hash value, get the clause the oracle will satisfy
+    /// for the provided hash value, get the clause the oracle will satisfy,
+    /// returning a `Result<Clause, String>`
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(hash: i32) -> i32;
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(a: i32, len: i32);
@@ -25,6 +27,9 @@ extern "C" {
     /// Get contract API by "trampolining" through the host to use another
     /// plugin identified by key.
     pub fn sapio_v1_wasm_plugin_get_api(key: i32) -> i32;
+    /// Get the key and API of every plugin the host knows of, as a JSON list
+    /// of (hex key, API) pairs.
+    pub fn sapio_v1_wasm_plugin_list_plugin_apis() -> i32;
     /// lookup a plugin key from a human reable name.
     /// if ok == 1, result is valid.
     /// out is written and must be 32 bytes of writable memory.
@@ -28,7 +28,7 @@ where
 
     /// creates an instance of the plugin from a json pointer and outputs a result pointer
     unsafe fn create(p: *mut c_char, c: *mut c_char) -> *mut c_char {
-        let res = Self::create_result(p, c).map_err(|e| e.to_string());
+        let res = Self::create_result(p, c).map_err(|e| WasmCompilationError::from(&e));
         encode_json(&res)
     }
 
@@ -79,10 +79,17 @@ where
         converted.compile(ctx)
     }
     /// binds this type to the wasm interface, must be called before the plugin can be used.
-    unsafe fn register(name: &'static str, logo: Option<&'static [u8]>) {
+    /// The host only permits the plugin to call the host functions grouped
+    /// under `capabilities`.
+    unsafe fn register(
+        name: &'static str,
+        logo: Option<&'static [u8]>,
+        capabilities: &'static [Capability],
+    ) {
         SAPIO_V1_WASM_PLUGIN_CLIENT_GET_CREATE_ARGUMENTS_PTR = Self::get_api_inner;
         SAPIO_V1_WASM_PLUGIN_CLIENT_CREATE_PTR = Self::create;
         SAPIO_PLUGIN_NAME = name;
+        SAPIO_PLUGIN_CAPABILITIES = capabilities;
         if let Some(logo) = logo {
             SAPIO_PLUGIN_LOGO = logo;
         }
@@ -101,19 +108,30 @@ fn encode_json<S: Serialize>(s: &S) -> *mut c_char {
 /// A helper macro to implement the plugin interface for a plugin-type
 /// and register it to the plugin entry point.
 ///
+/// The host functions the plugin needs are declared after `uses`, by
+/// [`Capability`] name, and any others are denied to it:
+///
+/// ```ignore
+/// REGISTER![[MyContract, Versions], "logo.png"; uses [CtvEmulator, CreateContract]];
+/// ```
+///
 /// U.B. to call REGISTER more than once because of the internal #[no_mangle]
 #[macro_export]
 macro_rules! REGISTER {
-    [$plugin:ident$(, $logo:expr)?] => {
-        REGISTER![[$plugin, $plugin]$(, $logo)*];
+    [$plugin:ident$(, $logo:expr)?$(; uses [$($cap:ident),*])?] => {
+        REGISTER![[$plugin, $plugin]$(, $logo)*; uses [$($($cap),*)?]];
     };
-    [[$to:ident,$plugin:ident]$(, $logo:expr)?] => {
+    [[$to:ident,$plugin:ident]$(, $logo:expr)?$(; uses [$($cap:ident),*])?] => {
         impl Plugin for $plugin {
             type ToType = $to;
         }
         #[no_mangle]
         unsafe fn sapio_v1_wasm_plugin_entry_point() {
-            $plugin::register(stringify!($to), optional_logo!($($logo)*));
+            $plugin::register(
+                stringify!($to),
+                optional_logo!($($logo)*),
+                &[$($($crate::Capability::$cap),*)?],
+            );
         }
     };
 }
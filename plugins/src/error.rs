@@ -0,0 +1,304 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Errors which cross the WASM boundary between a plugin and its host.
+//!
+//! A [`CompilationError`] may hold arbitrary boxed errors, so it can not be
+//! sent across the boundary as is. Instead it is converted to a
+//! [`WasmCompilationError`], which keeps the variant and its data where they
+//! can be serialized, and only falls back to the error's message otherwise.
+//!
+//! A contract created by another plugin which fails is reported as
+//! [`WasmCompilationError::Nested`], with the path it was created at, so
+//! that the failing branch can be found however deep the plugins nest.
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use sapio::contract::CompilationError;
+use sapio_base::effects::EffectPath;
+use sapio_base::plugin_args::CreateArgs;
+use sapio_base::Clause;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
+
+/// # WASM Compilation Error
+/// A [`CompilationError`] in a form which can be sent between a plugin and
+/// its host. See the variants of [`CompilationError`] of the same name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum WasmCompilationError {
+    /// Guards were added to a template where the compiler forbids them
+    AdditionalGuardsNotAllowedHere,
+    /// Compilation was stopped without a reason
+    TerminateCompilation,
+    /// Compilation was stopped, with a message
+    TerminateWith(String),
+    /// Metadata was set twice, by its key
+    OverwriteMetadata(String),
+    /// A fee specification was invalid
+    MinFeerateError,
+    /// No fee estimator is installed on the Context
+    NoFeeEstimator,
+    /// A context path was derived twice
+    ContexPathAlreadyDerived,
+    /// A context path was derived with an invalid name
+    InvalidPathName,
+    /// A `ThenFunc` returned no templates
+    MissingTemplates,
+    /// A policy was empty
+    EmptyPolicy,
+    /// A contract did not have the funds available it asked for
+    OutOfFunds,
+    /// A contract is funded with less than a mandatory branch requires
+    InsufficientFunding {
+        /// the amount the branch requires
+        required_sats: u64,
+        /// the amount available in the context
+        available_sats: u64,
+        /// the path of the branch requiring `required_sats`
+        path: EffectPath,
+    },
+    /// A template's nSequence or nLockTime cannot satisfy its branch's timelocks
+    TimelockNotSatisfiable {
+        /// the template's CTV hash
+        template: sha256::Hash,
+        /// the path of the branch
+        path: EffectPath,
+    },
+    /// A branch requires height and time based locks of the same kind
+    MixedTimelockUnits {
+        /// a pair of the conflicting locks
+        locks: (Clause, Clause),
+        /// the path of the branch
+        path: EffectPath,
+    },
+    /// Two branches of one kind have the same name, so the same path
+    DuplicateBranchName {
+        /// the name
+        name: String,
+        /// the path both branches would have
+        path: EffectPath,
+    },
+    /// A nondeterministic input was used under strict determinism
+    Nondeterministic {
+        /// what the input was
+        source: String,
+        /// the path being compiled
+        path: EffectPath,
+    },
+    /// An output would be below the dust threshold for its script type
+    DustOutput {
+        /// the path of the output
+        path: EffectPath,
+        /// the output's amount
+        amount_sats: u64,
+    },
+    /// The change computed for a template would be dust
+    ChangeIsDust {
+        /// the change's amount
+        amount_sats: u64,
+    },
+    /// A relative timelock is incompatible with the sequence already set
+    IncompatibleSequence,
+    /// An absolute timelock is incompatible with the locktime already set
+    IncompatibleLockTime,
+    /// A sequence was set for an input which does not exist
+    NoSuchSequence,
+    /// Conditional compilation failed, with its reasons
+    ConditionalCompilationFailed(Vec<String>),
+    /// A module could not be found
+    UnknownModule,
+    /// A module could not be queried
+    InvalidModule,
+    /// A module failed internally, with its message
+    InternalModuleError(String),
+    /// A module has no function of this name
+    ModuleCouldNotFindFunction(String),
+    /// A module did not satisfy its API's examples
+    ModuleFailedAPICheck(String),
+    /// A plugin failed to create the contract at `path` from `args`
+    Nested {
+        path: EffectPath,
+        args: CreateArgs<serde_json::Value>,
+        error: Box<WasmCompilationError>,
+    },
+    /// Any other error, by its message
+    Other(String),
+}
+
+impl std::fmt::Display for WasmCompilationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for WasmCompilationError {}
+
+impl WasmCompilationError {
+    /// The error `e` wraps, where it is nested inside another error
+    fn from_boxed(e: &(dyn Error + 'static)) -> Self {
+        if let Some(e) = e.downcast_ref::<CompilationError>() {
+            e.into()
+        } else if let Some(e) = e.downcast_ref::<WasmCompilationError>() {
+            e.clone()
+        } else {
+            WasmCompilationError::Other(e.to_string())
+        }
+    }
+
+    /// The paths of the contracts created by other plugins that this error
+    /// passed through, outermost first, and the error they failed with
+    pub fn nested_paths(&self) -> (Vec<&EffectPath>, &WasmCompilationError) {
+        let mut paths = vec![];
+        let mut e = self;
+        while let WasmCompilationError::Nested { path, error, .. } = e {
+            paths.push(path);
+            e = error;
+        }
+        (paths, e)
+    }
+}
+
+impl From<&CompilationError> for WasmCompilationError {
+    fn from(e: &CompilationError) -> Self {
+        use CompilationError as C;
+        use WasmCompilationError as W;
+        match e {
+            C::AdditionalGuardsNotAllowedHere => W::AdditionalGuardsNotAllowedHere,
+            C::TerminateCompilation => W::TerminateCompilation,
+            C::TerminateWith(s) => W::TerminateWith(s.clone()),
+            C::OverwriteMetadata(s) => W::OverwriteMetadata(s.clone()),
+            C::MinFeerateError => W::MinFeerateError,
+            C::NoFeeEstimator => W::NoFeeEstimator,
+            C::ContexPathAlreadyDerived => W::ContexPathAlreadyDerived,
+            C::InvalidPathName => W::InvalidPathName,
+            C::MissingTemplates => W::MissingTemplates,
+            C::EmptyPolicy => W::EmptyPolicy,
+            C::OutOfFunds => W::OutOfFunds,
+            C::InsufficientFunding {
+                required,
+                available,
+                path,
+            } => W::InsufficientFunding {
+                required_sats: required.as_sat(),
+                available_sats: available.as_sat(),
+                path: path.as_ref().clone(),
+            },
+            C::TimelockNotSatisfiable { template, path } => W::TimelockNotSatisfiable {
+                template: *template,
+                path: path.as_ref().clone(),
+            },
+            C::MixedTimelockUnits { locks, path } => W::MixedTimelockUnits {
+                locks: locks.clone(),
+                path: path.as_ref().clone(),
+            },
+            C::DuplicateBranchName { name, path } => W::DuplicateBranchName {
+                name: name.clone(),
+                path: path.as_ref().clone(),
+            },
+            C::Nondeterministic { source, path } => W::Nondeterministic {
+                source: source.clone(),
+                path: path.as_ref().clone(),
+            },
+            C::DustOutput(path, amount) => W::DustOutput {
+                path: path.as_ref().clone(),
+                amount_sats: amount.as_sat(),
+            },
+            C::ChangeIsDust(amount) => W::ChangeIsDust {
+                amount_sats: amount.as_sat(),
+            },
+            C::IncompatibleSequence => W::IncompatibleSequence,
+            C::IncompatibleLockTime => W::IncompatibleLockTime,
+            C::NoSuchSequence => W::NoSuchSequence,
+            C::ConditionalCompilationFailed(l) => {
+                W::ConditionalCompilationFailed(l.iter().cloned().collect())
+            }
+            C::UnknownModule => W::UnknownModule,
+            C::InvalidModule => W::InvalidModule,
+            C::InternalModuleError(s) => W::InternalModuleError(s.clone()),
+            C::ModuleCouldNotFindFunction(s) => W::ModuleCouldNotFindFunction(s.clone()),
+            C::ModuleFailedAPICheck(s) => W::ModuleFailedAPICheck(s.clone()),
+            C::ModuleCouldNotCreateContract(path, args, e) => W::Nested {
+                path: path.clone(),
+                args: args.clone(),
+                error: Box::new(WasmCompilationError::from_boxed(e.as_ref())),
+            },
+            // errors which already crossed the boundary keep their type
+            C::ModuleRuntimeError(e) | C::Custom(e) => {
+                match e.downcast_ref::<WasmCompilationError>() {
+                    Some(w) => w.clone(),
+                    None => W::Other(e.to_string()),
+                }
+            }
+            C::ModuleCompilationErrorUnsendable(s) => W::Other(s.clone()),
+            e => W::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<WasmCompilationError> for CompilationError {
+    fn from(e: WasmCompilationError) -> Self {
+        use CompilationError as C;
+        use WasmCompilationError as W;
+        match e {
+            W::AdditionalGuardsNotAllowedHere => C::AdditionalGuardsNotAllowedHere,
+            W::TerminateCompilation => C::TerminateCompilation,
+            W::TerminateWith(s) => C::TerminateWith(s),
+            W::OverwriteMetadata(s) => C::OverwriteMetadata(s),
+            W::MinFeerateError => C::MinFeerateError,
+            W::NoFeeEstimator => C::NoFeeEstimator,
+            W::ContexPathAlreadyDerived => C::ContexPathAlreadyDerived,
+            W::InvalidPathName => C::InvalidPathName,
+            W::MissingTemplates => C::MissingTemplates,
+            W::EmptyPolicy => C::EmptyPolicy,
+            W::OutOfFunds => C::OutOfFunds,
+            W::InsufficientFunding {
+                required_sats,
+                available_sats,
+                path,
+            } => C::InsufficientFunding {
+                required: Amount::from_sat(required_sats),
+                available: Amount::from_sat(available_sats),
+                path: Arc::new(path),
+            },
+            W::TimelockNotSatisfiable { template, path } => C::TimelockNotSatisfiable {
+                template,
+                path: Arc::new(path),
+            },
+            W::MixedTimelockUnits { locks, path } => C::MixedTimelockUnits {
+                locks,
+                path: Arc::new(path),
+            },
+            W::DuplicateBranchName { name, path } => C::DuplicateBranchName {
+                name,
+                path: Arc::new(path),
+            },
+            W::Nondeterministic { source, path } => C::Nondeterministic {
+                source,
+                path: Arc::new(path),
+            },
+            W::DustOutput { path, amount_sats } => {
+                C::DustOutput(Arc::new(path), Amount::from_sat(amount_sats))
+            }
+            W::ChangeIsDust { amount_sats } => C::ChangeIsDust(Amount::from_sat(amount_sats)),
+            W::IncompatibleSequence => C::IncompatibleSequence,
+            W::IncompatibleLockTime => C::IncompatibleLockTime,
+            W::NoSuchSequence => C::NoSuchSequence,
+            W::ConditionalCompilationFailed(l) => {
+                C::ConditionalCompilationFailed(l.into_iter().collect())
+            }
+            W::UnknownModule => C::UnknownModule,
+            W::InvalidModule => C::InvalidModule,
+            W::InternalModuleError(s) => C::InternalModuleError(s),
+            W::ModuleCouldNotFindFunction(s) => C::ModuleCouldNotFindFunction(s),
+            W::ModuleFailedAPICheck(s) => C::ModuleFailedAPICheck(s),
+            W::Nested { path, args, error } => {
+                C::ModuleCouldNotCreateContract(path, args, Box::new(C::from(*error)))
+            }
+            W::Other(s) => C::ModuleCompilationErrorUnsendable(s),
+        }
+    }
+}
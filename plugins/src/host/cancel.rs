@@ -0,0 +1,69 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Cancelling plugin calls from another thread.
+//!
+//! A [`CancellationToken`] governs every call into a plugin made while it is
+//! [run](CancellationToken::run), including the calls plugins make into
+//! other plugins. Once cancelled, the call running is interrupted (much as
+//! if it timed out) and fails with [`PluginError::Cancelled`], as does
+//! every later call.
+use super::error::PluginError;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+thread_local! {
+    /// the token governing plugin calls made by this thread
+    static CURRENT: RefCell<Option<CancellationToken>> = RefCell::new(None);
+}
+
+/// A handle to cancel plugin calls, which may be cloned and sent to other
+/// threads.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// A token which has not been cancelled
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Cancel every call governed by this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+    /// Whether this token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Run `f`, with every call into a plugin it makes governed by this
+    /// token.
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<CancellationToken>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let previous = self.0.take();
+                CURRENT.with(|c| *c.borrow_mut() = previous);
+            }
+        }
+        let _restore = Restore(CURRENT.with(|c| c.borrow_mut().replace(self.clone())));
+        f()
+    }
+
+    /// The token governing plugin calls made by this thread, if any
+    pub(crate) fn current() -> Option<CancellationToken> {
+        CURRENT.with(|c| c.borrow().clone())
+    }
+
+    /// Fail if the plugin calls made by this thread have been cancelled
+    pub(crate) fn check() -> Result<(), PluginError> {
+        match CancellationToken::current() {
+            Some(t) if t.is_cancelled() => Err(PluginError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Selection of the WASM engine that plugins are run with.
+//!
+//! Each engine is enabled by its own feature (`wasmer-engine` or
+//! `wasmtime-engine`), and caches compiled modules by the same key.
+use super::{PluginHandle, PluginRegistry, ResourceLimits};
+use sapio_ctv_emulator_trait::CTVEmulator;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A WASM engine capable of loading and running plugins.
+pub trait WasmEngine {
+    /// The handle to a plugin loaded with this engine
+    type Handle: PluginHandle + 'static;
+    /// Load a plugin from either a cache key or the module bytes, every call
+    /// into which is held to `limits`. Only one of key or file should be
+    /// set, and one should be set.
+    fn load(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+        limits: ResourceLimits,
+    ) -> Result<Self::Handle, Box<dyn Error>> {
+        let registry = PluginRegistry::new(plugin_map.unwrap_or_else(HashMap::new));
+        Self::load_with_registry(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file,
+            net,
+            Arc::new(registry),
+            limits,
+        )
+    }
+    /// Like [`WasmEngine::load`], but sharing `registry` with other plugins
+    fn load_with_registry(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
+    ) -> Result<Self::Handle, Box<dyn Error>>;
+    /// Get the keys of every module this engine has cached.
+    fn cached_keys(typ: &str, org: &str, proj: &str) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+/// The wasmer engine, see [`super::WasmPluginHandle`]
+#[cfg(feature = "wasmer-engine")]
+pub struct Wasmer;
+
+#[cfg(feature = "wasmer-engine")]
+impl WasmEngine for Wasmer {
+    type Handle = super::WasmPluginHandle;
+    fn load_with_registry(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
+    ) -> Result<Self::Handle, Box<dyn Error>> {
+        super::WasmPluginHandle::new_with_registry(
+            typ, org, proj, emulator, key, file, net, registry, limits,
+        )
+    }
+    fn cached_keys(typ: &str, org: &str, proj: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        super::wasm_cache::get_all_keys_from_fs(typ, org, proj)
+    }
+}
+
+/// The wasmtime engine, see [`super::WasmtimePluginHandle`]
+#[cfg(feature = "wasmtime-engine")]
+pub struct Wasmtime;
+
+#[cfg(feature = "wasmtime-engine")]
+impl WasmEngine for Wasmtime {
+    type Handle = super::WasmtimePluginHandle;
+    fn load_with_registry(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
+    ) -> Result<Self::Handle, Box<dyn Error>> {
+        super::WasmtimePluginHandle::new_with_registry(
+            typ, org, proj, emulator, key, file, net, registry, limits,
+        )
+    }
+    fn cached_keys(typ: &str, org: &str, proj: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        super::wasm_cache::precompiled::get_all_keys_from_fs(typ, org, proj)
+    }
+}
+
+/// # Engine Kind
+/// Which of the compiled-in engines a host should run plugins with.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EngineKind {
+    /// Use wasmer
+    #[cfg(feature = "wasmer-engine")]
+    Wasmer,
+    /// Use wasmtime
+    #[cfg(feature = "wasmtime-engine")]
+    Wasmtime,
+}
+
+impl Default for EngineKind {
+    #[cfg(feature = "wasmer-engine")]
+    fn default() -> Self {
+        EngineKind::Wasmer
+    }
+    #[cfg(not(feature = "wasmer-engine"))]
+    fn default() -> Self {
+        EngineKind::Wasmtime
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "wasmer-engine")]
+            "wasmer" => Ok(EngineKind::Wasmer),
+            #[cfg(feature = "wasmtime-engine")]
+            "wasmtime" => Ok(EngineKind::Wasmtime),
+            _ => Err(format!("Unknown or Disabled WASM Engine: {}", s)),
+        }
+    }
+}
+
+impl EngineKind {
+    /// Load a plugin with the selected engine, every call into which is held
+    /// to `limits`. Only one of key or file should be set, and one should be
+    /// set.
+    pub fn load(
+        &self,
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+        limits: ResourceLimits,
+    ) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        Ok(match self {
+            #[cfg(feature = "wasmer-engine")]
+            EngineKind::Wasmer => Box::new(Wasmer::load(
+                typ, org, proj, emulator, key, file, net, plugin_map, limits,
+            )?),
+            #[cfg(feature = "wasmtime-engine")]
+            EngineKind::Wasmtime => Box::new(Wasmtime::load(
+                typ, org, proj, emulator, key, file, net, plugin_map, limits,
+            )?),
+        })
+    }
+
+    /// Like [`EngineKind::load`], but sharing `registry` with other plugins
+    pub fn load_with_registry(
+        &self,
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
+    ) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        Ok(match self {
+            #[cfg(feature = "wasmer-engine")]
+            EngineKind::Wasmer => Box::new(Wasmer::load_with_registry(
+                typ, org, proj, emulator, key, file, net, registry, limits,
+            )?),
+            #[cfg(feature = "wasmtime-engine")]
+            EngineKind::Wasmtime => Box::new(Wasmtime::load_with_registry(
+                typ, org, proj, emulator, key, file, net, registry, limits,
+            )?),
+        })
+    }
+
+    /// Get the keys of every module the selected engine has cached.
+    pub fn cached_keys(
+        &self,
+        typ: &str,
+        org: &str,
+        proj: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        match self {
+            #[cfg(feature = "wasmer-engine")]
+            EngineKind::Wasmer => Wasmer::cached_keys(typ, org, proj),
+            #[cfg(feature = "wasmtime-engine")]
+            EngineKind::Wasmtime => Wasmtime::cached_keys(typ, org, proj),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Errors from the host running a plugin.
+use super::limits::Resource;
+use crate::capabilities::Capability;
+use sapio::contract::CompilationError;
+use std::error::Error;
+
+/// Errors from running a plugin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginError {
+    /// The plugin was stopped for exceeding one of its
+    /// [`ResourceLimits`](super::ResourceLimits)
+    ResourceExhausted(Resource),
+    /// The plugin was stopped for calling a host function it did not
+    /// declare in its manifest
+    CapabilityDenied(Capability),
+    /// The call was cancelled with a
+    /// [`CancellationToken`](super::CancellationToken)
+    Cancelled,
+}
+
+impl PluginError {
+    /// Find the [`PluginError`] that caused `e`, if any
+    pub fn find(e: &CompilationError) -> Option<&PluginError> {
+        match e {
+            CompilationError::ModuleRuntimeError(e)
+            | CompilationError::ModuleCouldNotCreateContract(_, _, e)
+            | CompilationError::ModuleCouldNotGetAPI(e)
+            | CompilationError::ModuleCouldNotGetName(e)
+            | CompilationError::ModuleCouldNotGetLogo(e)
+            | CompilationError::ModuleCouldNotAllocateError(_, e)
+            | CompilationError::ModuleCouldNotDeallocate(_, e) => e.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether a plugin calling the plugin which failed with this must be
+    /// stopped too, as it shares the limits and cancellation of the call
+    pub(crate) fn stops_caller(&self) -> bool {
+        match self {
+            PluginError::ResourceExhausted(_) | PluginError::Cancelled => true,
+            PluginError::CapabilityDenied(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for PluginError {}
+
+impl From<Resource> for PluginError {
+    fn from(r: Resource) -> Self {
+        PluginError::ResourceExhausted(r)
+    }
+}
+
+impl From<PluginError> for CompilationError {
+    fn from(e: PluginError) -> Self {
+        CompilationError::ModuleRuntimeError(Box::new(e))
+    }
+}
+
+/// Map the error of a failed call into a plugin with `on_err`, unless the
+/// host stopped the call with a [`PluginError`].
+pub(crate) fn or_plugin_error<F>(on_err: F) -> impl FnOnce(Box<dyn Error>) -> CompilationError
+where
+    F: FnOnce(Box<dyn Error>) -> CompilationError,
+{
+    move |e| match e.downcast::<PluginError>() {
+        Ok(p) => (*p).into(),
+        Err(e) => on_err(e),
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limits on the resources a plugin may use while the host runs it.
+//!
+//! Plugins are untrusted code, so every call into a plugin is metered: it
+//! may execute at most a fixed amount of fuel (roughly, WASM instructions),
+//! grow its memory to at most a fixed size, and run for at most a fixed
+//! wall-clock time. A plugin that creates contracts through other plugins
+//! (see `create_contract_by_key` in the client) shares its remaining fuel
+//! and time with them, and may only nest so deep.
+//!
+//! A call which exceeds a limit is stopped and fails with
+//! [`PluginError::ResourceExhausted`](super::PluginError::ResourceExhausted).
+use super::cancel::CancellationToken;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// # Resource Limits
+/// The resources a plugin may use for a single call into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// fuel a call may consume, None for unmetered
+    pub fuel: Option<u64>,
+    /// bytes a plugin's memory may grow to, None for the engine's maximum
+    pub max_memory: Option<u64>,
+    /// how many plugins deep a call may create contracts through other
+    /// plugins. 0 forbids a plugin from calling other plugins.
+    pub max_call_depth: u32,
+    /// wall-clock time a call may take, None to wait forever
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            fuel: None,
+            max_memory: Some(512 * 1024 * 1024),
+            max_call_depth: 16,
+            timeout: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// No limits at all, for plugins which are trusted
+    pub fn unlimited() -> Self {
+        ResourceLimits {
+            fuel: None,
+            max_memory: None,
+            max_call_depth: u32::MAX,
+            timeout: None,
+        }
+    }
+    /// set the fuel a call may consume
+    pub fn with_fuel(mut self, fuel: Option<u64>) -> Self {
+        self.fuel = fuel;
+        self
+    }
+    /// set the bytes a plugin's memory may grow to
+    pub fn with_max_memory(mut self, max_memory: Option<u64>) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+    /// set how deep plugins may call other plugins
+    pub fn with_max_call_depth(mut self, max_call_depth: u32) -> Self {
+        self.max_call_depth = max_call_depth;
+        self
+    }
+    /// set the wall-clock time a call may take
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The limits for a plugin called by a plugin running under these
+    /// limits, which has `fuel` left and must finish by `deadline`.
+    pub(crate) fn nested(
+        &self,
+        fuel: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> Result<ResourceLimits, Resource> {
+        if self.max_call_depth == 0 {
+            return Err(Resource::CallDepth);
+        }
+        let timeout = match deadline {
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::from_secs(0) => Some(left),
+                _ => return Err(Resource::Timeout),
+            },
+            None => None,
+        };
+        if fuel == Some(0) {
+            return Err(Resource::Fuel);
+        }
+        Ok(ResourceLimits {
+            fuel: self.fuel.and(fuel),
+            max_memory: self.max_memory,
+            max_call_depth: self.max_call_depth - 1,
+            timeout,
+        })
+    }
+
+    /// when a call starting now must finish by
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|t| Instant::now() + t)
+    }
+}
+
+/// A resource a plugin may run out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// the call used all of its fuel
+    Fuel,
+    /// the plugin tried to grow its memory past the limit
+    Memory,
+    /// the plugin called through too many other plugins
+    CallDepth,
+    /// the call took too long
+    Timeout,
+}
+
+/// How often a [`Watchdog`] checks whether its call was cancelled
+const POLL: Duration = Duration::from_millis(10);
+
+/// Interrupts a call into a plugin once its deadline passes, or the thread's
+/// [`CancellationToken`] is cancelled, until the call returns and this is
+/// dropped.
+pub(crate) struct Watchdog {
+    done: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Call `interrupt` once `deadline` passes or the call is cancelled,
+    /// and then periodically in case the plugin did not notice. Does nothing
+    /// without a deadline or a [`CancellationToken`].
+    pub(crate) fn start<F>(deadline: Option<Instant>, interrupt: F) -> Self
+    where
+        F: Fn() + Send + 'static,
+    {
+        let cancel = CancellationToken::current();
+        if deadline.is_none() && cancel.is_none() {
+            return Watchdog {
+                done: None,
+                thread: None,
+            };
+        }
+        // how long until the call must be interrupted, checking in on the
+        // token every so often
+        let next = move || {
+            if cancel.as_ref().map_or(false, |c| c.is_cancelled()) {
+                return Duration::from_secs(0);
+            }
+            let left = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            match (&cancel, left) {
+                (Some(_), Some(left)) => std::cmp::min(left, POLL),
+                (Some(_), None) => POLL,
+                (None, Some(left)) => left,
+                (None, None) => unreachable!(),
+            }
+        };
+        let (done, stop) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let mut wait = next();
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(wait) {
+                wait = next();
+                if wait == Duration::from_secs(0) {
+                    interrupt();
+                    wait = POLL;
+                }
+            }
+        });
+        Watchdog {
+            done: Some(done),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.done.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -4,34 +4,64 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use bitcoin::hashes::sha256;
-use bitcoin::hashes::Hash;
-use bitcoin::util::psbt::PartiallySignedTransaction;
+#[cfg(feature = "wasmer-engine")]
+use crate::capabilities::PluginManifest;
+pub use cancel::CancellationToken;
+pub use engine::{EngineKind, WasmEngine};
+pub use error::PluginError;
+pub use limits::{Resource, ResourceLimits};
+pub use output_cache::OutputCache;
 pub use plugin_handle::PluginHandle;
+#[cfg(feature = "wasmer-engine")]
 pub use plugin_handle::WasmPluginHandle;
-use sapio::contract::CompilationError;
+#[cfg(feature = "wasmtime-engine")]
+pub use plugin_handle::WasmtimePluginHandle;
+pub use registry::PluginRegistry;
+#[cfg(feature = "wasmer-engine")]
 use sapio_ctv_emulator_trait::CTVEmulator;
-use std::cell::Cell;
-use std::collections::HashMap;
-use std::io::Write;
+#[cfg(feature = "wasmer-engine")]
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "wasmer-engine")]
+use std::time::Instant;
+#[cfg(feature = "wasmer-engine")]
 use wasmer::*;
+pub use watcher::{PluginEvent, PluginPin, PluginWatcher};
+pub use workers::{PluginSource, PluginTask, PluginWorkers};
 
+pub mod cancel;
+pub mod engine;
+pub mod error;
+pub mod limits;
+pub mod output_cache;
 pub mod plugin_handle;
+pub mod registry;
 pub mod wasm_cache;
+pub mod watcher;
+pub mod workers;
 
 /// The state that host-side functions need to be able to use
 /// Also handles the imports of plugin-side functions
+#[cfg(feature = "wasmer-engine")]
 #[derive(WasmerEnv, Clone)]
 pub struct HostEnvironmentInner {
     pub typ: String,
     pub org: String,
     pub proj: String,
     pub this: [u8; 32],
-    pub module_map: HashMap<Vec<u8>, [u8; 32]>,
+    pub registry: Arc<PluginRegistry>,
     pub store: Arc<Mutex<Store>>,
     pub net: bitcoin::Network,
     pub emulator: Arc<dyn CTVEmulator>,
+    pub limits: ResourceLimits,
+    /// what the plugin declared, nothing until it has been loaded
+    pub manifest: PluginManifest,
+    /// when the current call must finish by
+    pub deadline: Option<Instant>,
+    /// the fuel consumed by every call into the plugin so far
+    pub fuel_consumed: u64,
+    /// the fuel left for the current call, added by metering the module
+    #[wasmer(export(name = "remaining_points"))]
+    pub remaining_points: LazyInit<Global>,
     #[wasmer(export)]
     pub memory: LazyInit<Memory>,
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_allocate_bytes"))]
@@ -42,6 +72,9 @@ pub struct HostEnvironmentInner {
     pub get_name: LazyInit<NativeFunc<(), i32>>,
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_logo"))]
     pub get_logo: LazyInit<NativeFunc<(), i32>>,
+    /// plugins built before manifests existed don't export this
+    #[wasmer(export(optional = true, name = "sapio_v1_wasm_plugin_client_get_manifest"))]
+    pub get_manifest: LazyInit<NativeFunc<(), i32>>,
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_drop_allocation"))]
     pub forget: LazyInit<NativeFunc<i32, ()>>,
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_create"))]
@@ -54,13 +87,34 @@ pub struct HostEnvironmentInner {
 /// We must be careful to ensure we don't see deadlocks.
 ///
 /// TODO: Figure out how to *just* make this Arc and not Mutex.
+#[cfg(feature = "wasmer-engine")]
 pub type HostEnvironment = Arc<Mutex<HostEnvironmentInner>>;
 
+#[cfg(feature = "wasmer-engine")]
 mod exports {
     //! the exports that the client will be able to use.
     //! They must be manually bound when instantiating the client.
     use super::*;
+    use crate::capabilities::Capability;
+    use crate::host::error::PluginError;
+    use crate::{CreateArgs, WasmCompilationError};
+    use bitcoin::hashes::sha256;
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use sapio::contract::CompilationError;
     use sapio_base::effects::EffectPath;
+    use std::cell::Cell;
+    use std::io::Write;
+
+    /// deny the plugin the call unless it declared `c`
+    fn require(env: &HostEnvironmentInner, c: Capability) -> Result<(), PluginError> {
+        if env.manifest.allows(c) {
+            Ok(())
+        } else {
+            Err(PluginError::CapabilityDenied(c))
+        }
+    }
+
     /// lookup a plugin key from a human reable name.
     /// if ok == 1, result is valid.
     /// out is written and must be 32 bytes of writable memory.
@@ -71,22 +125,14 @@ mod exports {
         len: i32,
         out: i32,
         ok: i32,
-    ) {
+    ) -> Result<(), PluginError> {
         let env = env.lock().unwrap();
         let m_hash = {
             if key == 0 && len == 0 {
-                Some(&env.this)
+                Some(env.this)
             } else {
-                let mut buf = vec![0u8; len as usize];
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [key as usize..(key + len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(buf.iter_mut())
-                {
-                    *dst = src;
-                }
-                env.module_map.get(&buf)
+                require(&env, Capability::LookupModule)?;
+                env.registry.key_for(&read_bytes(&env, key, len))
             }
         };
         let is_ok = if let Some(b) = m_hash {
@@ -102,11 +148,15 @@ mod exports {
             0
         };
         env.memory_ref().unwrap().view::<u8>()[ok as usize].set(is_ok);
+        Ok(())
     }
 
     /// Create an instance of a contract by "trampolining" through the host to use another
     /// plugin identified by key.
-    pub fn sapio_v1_wasm_plugin_get_api(env: &HostEnvironment, key: i32) -> i32 {
+    pub fn sapio_v1_wasm_plugin_get_api(
+        env: &HostEnvironment,
+        key: i32,
+    ) -> Result<i32, PluginError> {
         wasm_plugin_action(env, key, Action::GetAPI)
     }
     /// Create an instance of a contract by "trampolining" through the host to use another
@@ -118,7 +168,7 @@ mod exports {
         key: i32,
         json: i32,
         json_len: i32,
-    ) -> i32 {
+    ) -> Result<i32, PluginError> {
         wasm_plugin_action(
             env,
             key,
@@ -140,116 +190,184 @@ mod exports {
         GetAPI,
     }
 
-    fn wasm_plugin_action(env: &HostEnvironment, key: i32, action: Action) -> i32 {
-        let env = env.lock().unwrap();
-        const KEY_LEN: usize = 32;
-        let key = key as usize;
-        let h = wasmer_cache::Hash::new({
-            let mut buf = [0u8; KEY_LEN];
-            for (src, dst) in env.memory_ref().unwrap().view()[key..key + KEY_LEN]
-                .iter()
-                .map(Cell::get)
-                .zip(buf.iter_mut())
-            {
-                *dst = src;
+    /// the fuel left for the current call into the plugin
+    pub(crate) fn remaining_points(env: &HostEnvironmentInner) -> u64 {
+        env.remaining_points_ref()
+            .and_then(|g| g.get().i64())
+            .map_or(0, |p| p as u64)
+    }
+
+    /// copy `s` into a new allocation in the plugin's memory
+    fn pass_string(env: &HostEnvironmentInner, s: &str) -> Result<i32, CompilationError> {
+        let bytes: i32 = env
+            .allocate_wasm_bytes_ref()
+            .ok_or_else(|| {
+                CompilationError::ModuleCouldNotFindFunction("allocate_wasm_bytes".into())
+            })?
+            .call(s.len() as i32)
+            .map_err(|e| CompilationError::ModuleCouldNotAllocateError(s.len() as i32, e.into()))?;
+        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
+            .iter()
+            .zip(s.as_bytes())
+        {
+            byte.set(*c);
+        }
+        Ok(bytes)
+    }
+
+    /// read `len` bytes at `at` out of the plugin's memory
+    fn read_bytes(env: &HostEnvironmentInner, at: i32, len: i32) -> Vec<u8> {
+        let mut buf = vec![0u8; len as usize];
+        for (src, dst) in env.memory_ref().unwrap().view()[at as usize..(at + len) as usize]
+            .iter()
+            .map(Cell::get)
+            .zip(buf.iter_mut())
+        {
+            *dst = src;
+        }
+        buf
+    }
+
+    /// Run `f` with the plugin `key`, loaded to be called by the calling
+    /// plugin. None if the plugin could not be loaded.
+    fn with_nested<T>(
+        env: &HostEnvironmentInner,
+        key: &str,
+        f: impl FnOnce(&WasmPluginHandle) -> Result<T, CompilationError>,
+    ) -> Result<Option<Result<T, CompilationError>>, PluginError> {
+        let fuel = env.limits.fuel.map(|_| remaining_points(env));
+        let nested = env.limits.nested(fuel, env.deadline)?;
+        let sph = match WasmPluginHandle::new_with_registry(
+            env.typ.clone(),
+            env.org.clone(),
+            env.proj.clone(),
+            &env.emulator,
+            Some(key),
+            None,
+            env.net,
+            env.registry.clone(),
+            nested,
+        ) {
+            Ok(sph) => sph,
+            Err(e) => {
+                return match e.downcast::<PluginError>() {
+                    Ok(e) if e.stops_caller() => Err(*e),
+                    _ => Ok(None),
+                }
             }
-            buf
-        })
-        .to_string();
-        let action_to_take = match action {
-            Action::GetAPI => None,
+        };
+        let value = f(&sph);
+        // the plugin called shares its caller's limits, so running out
+        // stops the caller too
+        if let Some(e) = value.as_ref().err().and_then(PluginError::find) {
+            if e.stops_caller() {
+                return Err(e.clone());
+            }
+        }
+        if let Some(left) = fuel {
+            let used = sph.fuel_consumed();
+            if used >= left {
+                return Err(Resource::Fuel.into());
+            }
+            if let Some(g) = env.remaining_points_ref() {
+                let _ = g.set(Value::I64((left - used) as i64));
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// The API of the plugin `key`, fetched once and then remembered by the
+    /// registry. None if the plugin could not be loaded or queried.
+    fn plugin_api(
+        env: &HostEnvironmentInner,
+        key: [u8; 32],
+    ) -> Result<Option<serde_json::Value>, PluginError> {
+        if let Some(api) = env.registry.api(&key) {
+            return Ok(Some(api));
+        }
+        Ok(
+            match with_nested(env, &hex::encode(key), |sph| sph.get_api())? {
+                Some(Ok(api)) => {
+                    env.registry.insert_api(key, api.clone());
+                    Some(api)
+                }
+                _ => None,
+            },
+        )
+    }
+
+    fn wasm_plugin_action(
+        env: &HostEnvironment,
+        key: i32,
+        action: Action,
+    ) -> Result<i32, PluginError> {
+        let env = env.lock().unwrap();
+        require(
+            &env,
+            match action {
+                Action::GetAPI => Capability::GetApi,
+                Action::Create { .. } => Capability::CreateContract,
+            },
+        )?;
+        let mut h = [0u8; 32];
+        h.copy_from_slice(&read_bytes(&env, key, 32));
+        let value = match action {
+            Action::GetAPI => plugin_api(&env, h)?.map(Ok),
             Action::Create {
                 path,
                 path_len,
                 json,
                 json_len,
-                ..
             } => {
-                // use this buffer twice, so make it the max size
-                let mut v = vec![0u8; std::cmp::max(json_len, path_len) as usize];
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [json as usize..(json + json_len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(v.iter_mut())
-                {
-                    *dst = src;
-                }
-                let create_args = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..json_len as usize])
-                        .to_owned()
-                        .to_string(),
-                )
-                .map_err(CompilationError::DeserializationError);
-
-                for (src, dst) in env.memory_ref().unwrap().view()
-                    [path as usize..(path + path_len) as usize]
-                    .iter()
-                    .map(Cell::get)
-                    .zip(v.iter_mut())
-                {
-                    *dst = src;
-                }
-                let effectpath: Result<EffectPath, _> = serde_json::from_str(
-                    &String::from_utf8_lossy(&v[..path_len as usize])
-                        .to_owned()
-                        .to_string(),
-                )
-                .map_err(CompilationError::DeserializationError);
-
-                Some((create_args, effectpath))
+                let create_args: Result<CreateArgs<serde_json::Value>, _> =
+                    serde_json::from_slice(&read_bytes(&env, json, json_len))
+                        .map_err(CompilationError::DeserializationError);
+                let effectpath: Result<EffectPath, _> =
+                    serde_json::from_slice(&read_bytes(&env, path, path_len))
+                        .map_err(CompilationError::DeserializationError);
+                let created = match (effectpath, create_args) {
+                    (Ok(path), Ok(args)) => {
+                        env.registry.outputs().get_or_create(h, &path, &args, || {
+                            with_nested(&env, &hex::encode(h), |sph| sph.create(&path, &args))
+                        })?
+                    }
+                    (Err(e), _) | (_, Err(e)) => Some(Err(e)),
+                };
+                created.map(|c| {
+                    c.and_then(|c| {
+                        serde_json::to_value(c).map_err(CompilationError::SerializationError)
+                    })
+                })
             }
         };
-        let emulator = env.emulator.clone();
-        let mmap = env.module_map.clone();
-        let typ = env.typ.clone();
-        let org = env.org.clone();
-        let proj = env.proj.clone();
-        let net = env.net;
+        Ok(match value {
+            Some(value) => {
+                serde_json::to_string(&value.map_err(|e| WasmCompilationError::from(&e)))
+                    .map_err(CompilationError::SerializationError)
+                    .and_then(|s| pass_string(&env, &s))
+                    .unwrap_or(0)
+            }
+            None => 0,
+        })
+    }
 
-        match WasmPluginHandle::new(typ, org, proj, &emulator, Some(&h), None, net, Some(mmap)) {
-            Ok(sph) => {
-                let comp_s = (move || -> Result<serde_json::Value, CompilationError> {
-                    let value = match action_to_take {
-                        None => Ok(sph.get_api()),
-                        Some((create_args, path)) => {
-                            sph.create(&path?, &create_args?).map(|comp| {
-                                serde_json::to_value(comp)
-                                    .map_err(CompilationError::DeserializationError)
-                            })
-                        }
-                    };
-                    serde_json::to_value(&value??).map_err(CompilationError::SerializationError)
-                })();
-                return (move || -> Result<i32, CompilationError> {
-                    let comp_s = serde_json::to_string(&comp_s.map_err(|s| s.to_string()))
-                        .map_err(CompilationError::SerializationError)?;
-                    let bytes: i32 = env
-                        .allocate_wasm_bytes_ref()
-                        .ok_or_else(|| {
-                            CompilationError::ModuleCouldNotFindFunction(
-                                "allocate_wasm_bytes".into(),
-                            )
-                        })?
-                        .call(comp_s.len() as i32)
-                        .map_err(|e| {
-                            CompilationError::ModuleCouldNotAllocateError(
-                                comp_s.len() as i32,
-                                e.into(),
-                            )
-                        })?;
-                    for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-                        .iter()
-                        .zip(comp_s.as_bytes())
-                    {
-                        byte.set(*c);
-                    }
-                    Ok(bytes)
-                })()
-                .unwrap_or(0);
+    /// The key and API of every plugin in the registry which can be loaded,
+    /// so that a plugin can find the plugins implementing a trait.
+    pub fn sapio_v1_wasm_plugin_list_plugin_apis(
+        env: &HostEnvironment,
+    ) -> Result<i32, PluginError> {
+        let env = env.lock().unwrap();
+        require(&env, Capability::GetApi)?;
+        let mut apis = vec![];
+        for key in env.registry.keys() {
+            if let Some(api) = plugin_api(&env, key)? {
+                apis.push((hex::encode(key), api));
             }
-            _ => 0,
         }
+        Ok(serde_json::to_string(&apis)
+            .map_err(CompilationError::SerializationError)
+            .and_then(|s| pass_string(&env, &s))
+            .unwrap_or(0))
     }
 
     /// use the hosts stdout to log a string. The host may make this a no-op.
@@ -265,68 +383,45 @@ mod exports {
         w.write("\n".as_bytes()).unwrap();
     }
 
-    /// for the provided hash value, get the clause the oracle will satisfy
-    pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(env: &HostEnvironment, hash: i32) -> i32 {
+    /// for the provided hash value, get the clause the oracle will satisfy.
+    /// The clause is returned as a `Result<Clause, String>`, so that the
+    /// emulator failing is reported to the plugin.
+    pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(
+        env: &HostEnvironment,
+        hash: i32,
+    ) -> Result<i32, PluginError> {
         let env = env.lock().unwrap();
-        let hash = hash as usize;
-        let h = sha256::Hash::from_inner({
-            let mut buf = [0u8; 32];
-            for (src, dst) in env.memory_ref().unwrap().view()[hash..hash + 32]
-                .iter()
-                .map(Cell::get)
-                .zip(buf.iter_mut())
-            {
-                *dst = src;
-            }
-            buf
-        });
-        let clause = env.emulator.get_signer_for(h).unwrap();
-        let s = serde_json::to_string_pretty(&clause).unwrap();
-        let bytes = env
-            .allocate_wasm_bytes_ref()
-            .unwrap()
-            .call(s.len() as i32)
-            .unwrap();
-        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-            .iter()
-            .zip(s.as_bytes())
-        {
-            byte.set(*c);
-        }
-        bytes
+        require(&env, Capability::CtvEmulator)?;
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(&read_bytes(&env, hash, 32));
+        let clause = env
+            .emulator
+            .get_signer_for(sha256::Hash::from_inner(buf))
+            .map_err(|e| e.to_string());
+        Ok(serde_json::to_string_pretty(&clause)
+            .map_err(CompilationError::SerializationError)
+            .and_then(|s| pass_string(&env, &s))
+            .unwrap_or(0))
     }
 
-    /// get the oracle to sign the psbt passed in
+    /// get the oracle to sign the psbt passed in. The psbt is returned as a
+    /// `Result<PartiallySignedTransaction, String>`, so that the emulator
+    /// failing is reported to the plugin.
     pub fn sapio_v1_wasm_plugin_ctv_emulator_sign(
         env: &HostEnvironment,
         psbt: i32,
         len: u32,
-    ) -> i32 {
+    ) -> Result<i32, PluginError> {
         let env = env.lock().unwrap();
-        let mut buf = vec![0u8; len as usize];
-        let psbt = psbt as usize;
-        for (src, dst) in env.memory_ref().unwrap().view()[psbt..]
-            .iter()
-            .map(Cell::get)
-            .zip(buf.iter_mut())
-        {
-            *dst = src;
-        }
-        let psbt: PartiallySignedTransaction = serde_json::from_slice(&buf[..]).unwrap();
-        let psbt = env.emulator.sign(psbt).unwrap();
-        buf.clear();
-        let s = serde_json::to_string_pretty(&psbt).unwrap();
-        let bytes = env
-            .allocate_wasm_bytes_ref()
-            .unwrap()
-            .call(s.len() as i32)
-            .unwrap();
-        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
-            .iter()
-            .zip(s.as_bytes())
-        {
-            byte.set(*c);
-        }
-        bytes
+        require(&env, Capability::CtvEmulator)?;
+        let psbt = serde_json::from_slice::<PartiallySignedTransaction>(&read_bytes(
+            &env, psbt, len as i32,
+        ))
+        .map_err(|e| e.to_string())
+        .and_then(|psbt| env.emulator.sign(psbt).map_err(|e| e.to_string()));
+        Ok(serde_json::to_string_pretty(&psbt)
+            .map_err(CompilationError::SerializationError)
+            .and_then(|s| pass_string(&env, &s))
+            .unwrap_or(0))
     }
 }
@@ -0,0 +1,181 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Remembers the contracts plugins create for one another.
+//!
+//! A plugin is keyed by the hash of its module and compiling a contract is
+//! deterministic, so the contract a plugin creates is fixed by its key, the
+//! effect path it is created at and its canonically encoded arguments
+//! (which carry the amount, network and effects it is created with).
+//! Contracts which fan out often call the same plugin with the same
+//! arguments many times; only the first call loads and runs the plugin.
+//!
+//! The cache holds at most a fixed number of contracts, evicting the least
+//! recently used. Only contracts which compiled are cached, and since a
+//! plugin's key changes with its module there is nothing to invalidate when
+//! a plugin is rebuilt, but a host may still drop a plugin's outputs with
+//! [`OutputCache::invalidate`].
+use crate::CreateArgs;
+use bitcoin::hashes::sha256;
+use sapio::contract::{CompilationError, Compiled};
+use sapio_base::canonical::{canonical_hash, CanonicalError};
+use sapio_base::effects::EffectPath;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// How many contracts an [`OutputCache`] holds by default
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The contracts created by plugins, by [`OutputCache::key`].
+#[derive(Debug)]
+pub struct OutputCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// incremented on every use, to order entries by when they were used
+    clock: u64,
+    by_key: HashMap<sha256::Hash, Entry>,
+    /// the key of every entry, by when it was last used
+    by_use: BTreeMap<u64, sha256::Hash>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    last_used: u64,
+    plugin: [u8; 32],
+    compiled: Compiled,
+}
+
+impl Default for OutputCache {
+    fn default() -> Self {
+        OutputCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl OutputCache {
+    /// A cache of at most `capacity` contracts. 0 disables caching.
+    pub fn new(capacity: usize) -> Self {
+        OutputCache {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// The key the contract created by `plugin` at `path` with `args` is
+    /// cached under
+    pub fn key(
+        plugin: &[u8; 32],
+        path: &EffectPath,
+        args: &CreateArgs<serde_json::Value>,
+    ) -> Result<sha256::Hash, CanonicalError> {
+        canonical_hash(b"sapio/plugin_output", &(hex::encode(plugin), path, args))
+    }
+
+    /// The contract cached under `key`
+    pub fn get(&self, key: &sha256::Hash) -> Option<Compiled> {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            clock,
+            by_key,
+            by_use,
+        } = &mut *entries;
+        let entry = by_key.get_mut(key)?;
+        *clock += 1;
+        by_use.remove(&entry.last_used);
+        by_use.insert(*clock, *key);
+        entry.last_used = *clock;
+        Some(entry.compiled.clone())
+    }
+
+    /// Cache the contract `plugin` created under `key`, evicting the least
+    /// recently used contract if the cache is full
+    pub fn insert(&self, key: sha256::Hash, plugin: [u8; 32], compiled: Compiled) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            clock,
+            by_key,
+            by_use,
+        } = &mut *entries;
+        *clock += 1;
+        let entry = Entry {
+            last_used: *clock,
+            plugin,
+            compiled,
+        };
+        if let Some(old) = by_key.insert(key, entry) {
+            by_use.remove(&old.last_used);
+        }
+        by_use.insert(*clock, key);
+        while by_key.len() > self.capacity {
+            let oldest = match by_use.keys().next() {
+                Some(t) => *t,
+                None => break,
+            };
+            if let Some(k) = by_use.remove(&oldest) {
+                by_key.remove(&k);
+            }
+        }
+    }
+
+    /// The contract `plugin` creates at `path` with `args`: from the cache,
+    /// or else made by `create` (which returns None if the plugin could not
+    /// be loaded) and cached if it compiled
+    pub(crate) fn get_or_create<E>(
+        &self,
+        plugin: [u8; 32],
+        path: &EffectPath,
+        args: &CreateArgs<serde_json::Value>,
+        create: impl FnOnce() -> Result<Option<Result<Compiled, CompilationError>>, E>,
+    ) -> Result<Option<Result<Compiled, CompilationError>>, E> {
+        // arguments which cannot be canonically encoded are just not cached
+        let key = OutputCache::key(&plugin, path, args).ok();
+        if let Some(compiled) = key.and_then(|k| self.get(&k)) {
+            return Ok(Some(Ok(compiled)));
+        }
+        let created = create()?;
+        if let (Some(key), Some(Ok(compiled))) = (key, &created) {
+            self.insert(key, plugin, compiled.clone());
+        }
+        Ok(created)
+    }
+
+    /// Drop every contract created by `plugin`
+    pub fn invalidate(&self, plugin: &[u8; 32]) {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries { by_key, by_use, .. } = &mut *entries;
+        by_key.retain(|_, e| {
+            if &e.plugin == plugin {
+                by_use.remove(&e.last_used);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Drop every contract
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.by_key.clear();
+        entries.by_use.clear();
+    }
+
+    /// How many contracts are cached
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().by_key.len()
+    }
+
+    /// Whether no contracts are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -4,19 +4,52 @@
 //  License, v. 2.0. If a copy of the MPL was not distributed with this
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "wasmer-engine")]
 use super::wasm_cache;
 use crate::CreateArgs;
 pub use plugin_handle::*;
 use sapio::contract::Compiled;
 use sapio_ctv_emulator_trait::NullEmulator;
+#[cfg(feature = "wasmer-engine")]
 use std::cell::Cell;
 use std::collections::HashMap;
 use std::ffi::OsStr;
+#[cfg(feature = "wasmer-engine")]
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "wasmer-engine")]
 pub use wasm::*;
+#[cfg(feature = "wasmer-engine")]
 use wasmer::{imports, Function, ImportObject, Instance, LazyInit, MemoryView, Module, Store};
+#[cfg(feature = "wasmer-engine")]
 use wasmer_cache::Hash as WASMCacheID;
+#[cfg(feature = "wasmtime-engine")]
+pub use wasmtime_handle::*;
 
 mod plugin_handle;
+#[cfg(feature = "wasmer-engine")]
 mod wasm;
+#[cfg(feature = "wasmer-engine")]
+mod wasm_limits;
+#[cfg(feature = "wasmtime-engine")]
+mod wasmtime_handle;
+
+/// A plugin exporting everything the host needs except a manifest, as
+/// plugins built before manifests existed do
+#[cfg(all(test, any(feature = "wasmer-engine", feature = "wasmtime-engine")))]
+const NO_MANIFEST_PLUGIN: &str = r#"
+(module
+  (memory (export "memory") 1)
+  (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32)
+    i32.const 0)
+  (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32)
+    i32.const 0)
+  (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32)
+    i32.const 0)
+  (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32)
+    i32.const 0)
+  (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+  (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32)
+    i32.const 0)
+  (func (export "sapio_v1_wasm_plugin_entry_point")))
+"#;
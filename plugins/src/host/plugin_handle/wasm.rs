@@ -5,15 +5,27 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //!  a plugin handle for a wasm plugin.
+use super::wasm_limits::{limited_store, UNMETERED_FUEL};
 use super::*;
+use crate::host::cancel::CancellationToken;
+use crate::host::error::{or_plugin_error, PluginError};
 use crate::host::exports::*;
+use crate::host::limits::{Resource, ResourceLimits, Watchdog};
+use crate::host::registry::PluginRegistry;
 use crate::host::wasm_cache::get_all_keys_from_fs;
 use crate::host::{HostEnvironment, HostEnvironmentInner};
+use crate::WasmCompilationError;
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::error::Error;
-use wasmer::Memory;
+use std::time::Instant;
+use wasmer::{Memory, NativeFunc, Pages, RuntimeError, Value};
+
+/// Metering traps when the fuel left is less than the cost of the next
+/// block, so a trap with less fuel than this left is taken to be from
+/// running out.
+const EXHAUSTED_BELOW: u64 = 1024;
 pub struct WasmPluginHandle {
     store: Store,
     env: HostEnvironment,
@@ -81,8 +93,8 @@ impl WasmPluginHandle {
             plugin_map,
         )
     }
-    /// Create an plugin handle. Only one of key or file should be set, and one
-    /// should be set.
+    /// Create an plugin handle with the default [`ResourceLimits`]. Only one
+    /// of key or file should be set, and one should be set.
     /// TODO: Revert to async?
     pub fn new(
         typ: String,
@@ -93,18 +105,74 @@ impl WasmPluginHandle {
         file: Option<&Vec<u8>>,
         net: bitcoin::Network,
         plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::new_limited(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file,
+            net,
+            plugin_map,
+            ResourceLimits::default(),
+        )
+    }
+
+    /// Create an plugin handle, every call into which (including loading
+    /// it) is held to `limits`. See [`WasmPluginHandle::new`].
+    ///
+    /// wasmer cannot interrupt a running plugin directly, so the timeout is
+    /// enforced by taking away the call's remaining fuel.
+    pub fn new_limited(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+        limits: ResourceLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let registry = PluginRegistry::new(plugin_map.unwrap_or_else(HashMap::new));
+        Self::new_with_registry(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file,
+            net,
+            Arc::new(registry),
+            limits,
+        )
+    }
+
+    /// Create an plugin handle sharing `registry` (and so the APIs and
+    /// contracts it caches) with other handles, see
+    /// [`WasmPluginHandle::new_limited`].
+    pub fn new_with_registry(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
     ) -> Result<Self, Box<dyn Error>> {
         // ensures that either key or file is passed
         key.xor(file.and(Some("")))
             .ok_or("Passed Both Key and File or Neither")?;
-        let store = Store::default();
+        let store = limited_store(&limits);
 
         let (module, key) = match (file, key) {
             (Some(wasm_bytes), _) => {
                 match wasm_cache::load_module(&typ, &org, &proj, &store, &wasm_bytes) {
                     Ok(module) => module,
                     Err(_) => {
-                        let store = Store::default();
                         let module = Module::new(&store, &wasm_bytes)?;
                         let key =
                             wasm_cache::store_module(&typ, &org, &proj, &module, &wasm_bytes)?;
@@ -136,14 +204,20 @@ impl WasmPluginHandle {
             org,
             proj,
             this,
-            module_map: plugin_map.unwrap_or_else(HashMap::new).into(),
+            registry,
             store: Arc::new(Mutex::new(store.clone())),
             net,
             emulator: emulator.clone(),
+            limits,
+            manifest: Default::default(),
+            deadline: None,
+            fuel_consumed: 0,
+            remaining_points: LazyInit::new(),
             memory: LazyInit::new(),
             get_api: LazyInit::new(),
             get_name: LazyInit::new(),
             get_logo: LazyInit::new(),
+            get_manifest: LazyInit::new(),
             forget: LazyInit::new(),
             create: LazyInit::new(),
             init: LazyInit::new(),
@@ -157,6 +231,7 @@ impl WasmPluginHandle {
             sapio_v1_wasm_plugin_debug_log_string,
             sapio_v1_wasm_plugin_create_contract,
             sapio_v1_wasm_plugin_get_api,
+            sapio_v1_wasm_plugin_list_plugin_apis,
             sapio_v1_wasm_plugin_lookup_module_name
         );
 
@@ -164,14 +239,8 @@ impl WasmPluginHandle {
         use wasmer::WasmerEnv;
         wasm_ctv_emulator.init_with_instance(&instance)?;
 
-        wasm_ctv_emulator
-            .lock()
-            .unwrap()
-            .init_ref()
-            .ok_or("No Init Function Specified")?
-            .call()?;
-
-        Ok(WasmPluginHandle {
+        let init = wasm_ctv_emulator.lock().unwrap().init.clone();
+        let handle = WasmPluginHandle {
             store,
             env: wasm_ctv_emulator,
             net,
@@ -179,32 +248,118 @@ impl WasmPluginHandle {
             module,
             instance,
             key,
+        };
+        handle.call(|| {
+            init.get_ref()
+                .ok_or_else(|| RuntimeError::new("No Init Function Specified"))?
+                .call()
+        })?;
+        // without a manifest, the plugin keeps the default of no capabilities
+        let has_manifest = handle.env.lock().unwrap().get_manifest.get_ref().is_some();
+        if has_manifest {
+            let manifest = handle.call_for_string(
+                |env| &env.get_manifest,
+                "get_manifest",
+                CompilationError::ModuleRuntimeError,
+            )?;
+            handle.env.lock().unwrap().manifest = serde_json::from_slice(&manifest)?;
+        }
+        Ok(handle)
+    }
+
+    /// the fuel consumed by every call into this plugin so far
+    pub fn fuel_consumed(&self) -> u64 {
+        self.env.lock().unwrap().fuel_consumed
+    }
+
+    /// run `f`, which calls into the plugin, held to its [`ResourceLimits`].
+    /// `f` must not hold the environment's lock while the plugin runs, as
+    /// host functions take it.
+    fn call<R>(&self, f: impl FnOnce() -> Result<R, RuntimeError>) -> Result<R, Box<dyn Error>> {
+        CancellationToken::check()?;
+        let (points, fuel, limits, deadline) = {
+            let mut env = self.env.lock().unwrap();
+            let fuel = env.limits.fuel.unwrap_or(UNMETERED_FUEL);
+            env.deadline = env.limits.deadline();
+            let points = env.remaining_points.clone();
+            points
+                .get_ref()
+                .ok_or("No Metering Available")?
+                .set(Value::I64(fuel as i64))?;
+            (points, fuel, env.limits, env.deadline)
+        };
+        let res = {
+            let points = points.clone();
+            let _watchdog = Watchdog::start(deadline, move || {
+                if let Some(g) = points.get_ref() {
+                    let _ = g.set(Value::I64(0));
+                }
+            });
+            f()
+        };
+        let left = {
+            let mut env = self.env.lock().unwrap();
+            let left = remaining_points(&env);
+            env.fuel_consumed += fuel.saturating_sub(left);
+            left
+        };
+        res.map_err(|e| -> Box<dyn Error> {
+            match e.downcast::<PluginError>() {
+                Ok(e) => Box::new(e),
+                Err(e) => match CancellationToken::check() {
+                    Err(c) => Box::new(c),
+                    Ok(()) => match self.exhausted(&limits, deadline, left) {
+                        Some(r) => Box::new(PluginError::from(r)),
+                        None => Box::new(e),
+                    },
+                },
+            }
         })
     }
 
+    /// which limit, if any, stopped the call that just failed
+    fn exhausted(
+        &self,
+        limits: &ResourceLimits,
+        deadline: Option<Instant>,
+        fuel_left: u64,
+    ) -> Option<Resource> {
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            return Some(Resource::Timeout);
+        }
+        if limits.fuel.is_some() && fuel_left < EXHAUSTED_BELOW {
+            return Some(Resource::Fuel);
+        }
+        let max = limits.max_memory?;
+        let size = self.get_memory().ok()?.size();
+        if size.bytes().0 as u64 + Pages(1).bytes().0 as u64 > max {
+            Some(Resource::Memory)
+        } else {
+            None
+        }
+    }
+
     /// forget an allocated pointer
     pub fn forget(&self, p: i32) -> Result<(), CompilationError> {
-        Ok(self
-            .env
-            .lock()
-            .unwrap()
-            .forget_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("forget".into()))?
-            .call(p)
-            .map_err(|e| CompilationError::ModuleCouldNotDeallocate(p, e.into()))?)
+        let forget = self.env.lock().unwrap().forget.clone();
+        let forget = forget
+            .get_ref()
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("forget".into()))?;
+        self.call(|| forget.call(p)).map_err(or_plugin_error(|e| {
+            CompilationError::ModuleCouldNotDeallocate(p, e)
+        }))
     }
 
     /// create an allocation
     pub fn allocate(&self, len: i32) -> Result<i32, CompilationError> {
-        self.env
-            .lock()
-            .unwrap()
-            .allocate_wasm_bytes_ref()
-            .ok_or_else(|| {
-                CompilationError::ModuleCouldNotFindFunction("allocate_wasm_bytes".into())
-            })?
-            .call(len)
-            .map_err(|e| CompilationError::ModuleCouldNotAllocateError(len, e.into()))
+        let allocate = self.env.lock().unwrap().allocate_wasm_bytes.clone();
+        let allocate = allocate.get_ref().ok_or_else(|| {
+            CompilationError::ModuleCouldNotFindFunction("allocate_wasm_bytes".into())
+        })?;
+        self.call(|| allocate.call(len))
+            .map_err(or_plugin_error(|e| {
+                CompilationError::ModuleCouldNotAllocateError(len, e)
+            }))
     }
 
     /// pass a string to the WASM plugin
@@ -235,6 +390,24 @@ impl WasmPluginHandle {
             .get_memory("memory")
             .map_err(|e| CompilationError::ModuleFailedToGetMemory(e.into()))
     }
+    /// call a plugin function which returns an allocated string, read it,
+    /// and free it.
+    fn call_for_string(
+        &self,
+        f: fn(&HostEnvironmentInner) -> &LazyInit<NativeFunc<(), i32>>,
+        name: &str,
+        on_err: fn(Box<dyn Error>) -> CompilationError,
+    ) -> Result<Vec<u8>, CompilationError> {
+        let f = f(&self.env.lock().unwrap()).clone();
+        let f = f
+            .get_ref()
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction(name.into()))?;
+        let p = self.call(|| f.call()).map_err(or_plugin_error(on_err))?;
+        let v = self.read_to_vec(p)?;
+        self.forget(p)?;
+        Ok(v)
+    }
+
     /// read something from wasm memory, null terminated
     fn read_to_vec(&self, p: i32) -> Result<Vec<u8>, CompilationError> {
         let memory = self.get_memory()?;
@@ -261,57 +434,77 @@ impl PluginHandle for WasmPluginHandle {
             let env = self.env.lock().unwrap();
             env.create.clone()
         };
-        let result_ptr = create_func
+        let create_func = create_func
             .get_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?
-            .call(path_ptr, args_ptr)
-            .map_err(|e| {
-                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
-            })?;
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?;
+        let result_ptr =
+            self.call(|| create_func.call(path_ptr, args_ptr))
+                .map_err(or_plugin_error(|e| {
+                    CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e)
+                }))?;
         let buf = self.read_to_vec(result_ptr)?;
         self.forget(result_ptr)?;
-        let v: Result<Compiled, String> =
+        let v: Result<Compiled, WasmCompilationError> =
             serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
-        v.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+        // keep the path so a failure can be traced through nested plugins
+        v.map_err(|e| {
+            CompilationError::ModuleCouldNotCreateContract(
+                path.clone(),
+                c.clone(),
+                Box::new(CompilationError::from(e)),
+            )
+        })
     }
     fn get_api(&self) -> Result<serde_json::value::Value, CompilationError> {
-        let p = self
-            .env
-            .lock()
-            .unwrap()
-            .get_api_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_api".into()))?
-            .call()
-            .map_err(|e| CompilationError::ModuleCouldNotGetAPI(e.into()))?;
-        let v = self.read_to_vec(p)?;
-        self.forget(p)?;
+        let v = self.call_for_string(
+            |env| &env.get_api,
+            "get_api",
+            CompilationError::ModuleCouldNotGetAPI,
+        )?;
         serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)
     }
     fn get_name(&self) -> Result<String, CompilationError> {
-        let p = self
-            .env
-            .lock()
-            .unwrap()
-            .get_name_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_name".into()))?
-            .call()
-            .map_err(|e| CompilationError::ModuleCouldNotGetName(e.into()))?;
-        let v = self.read_to_vec(p)?;
-        self.forget(p)?;
+        let v = self.call_for_string(
+            |env| &env.get_name,
+            "get_name",
+            CompilationError::ModuleCouldNotGetName,
+        )?;
         Ok(String::from_utf8_lossy(&v).to_string())
     }
 
     fn get_logo(&self) -> Result<String, CompilationError> {
-        let p = self
-            .env
-            .lock()
-            .unwrap()
-            .get_logo_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("get_logo".into()))?
-            .call()
-            .map_err(|e| CompilationError::ModuleCouldNotGetLogo(e.into()))?;
-        let v = self.read_to_vec(p)?;
-        self.forget(p)?;
+        let v = self.call_for_string(
+            |env| &env.get_logo,
+            "get_logo",
+            CompilationError::ModuleCouldNotGetLogo,
+        )?;
         Ok(String::from_utf8_lossy(&v).to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::capabilities::PluginManifest;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+
+    #[test]
+    fn missing_manifest_grants_nothing() -> Result<(), Box<dyn Error>> {
+        let emulator: NullEmulator = Arc::new(CTVAvailable);
+        let handle = WasmPluginHandle::new(
+            "org".into(),
+            "judica".into(),
+            "sapio-test".into(),
+            &emulator,
+            None,
+            Some(&NO_MANIFEST_PLUGIN.as_bytes().to_vec()),
+            bitcoin::Network::Regtest,
+            None,
+        )?;
+        assert_eq!(
+            handle.env.lock().unwrap().manifest,
+            PluginManifest::default()
+        );
+        Ok(())
+    }
+}
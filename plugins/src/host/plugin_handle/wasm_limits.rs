@@ -0,0 +1,111 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Enforcing [`ResourceLimits`] with wasmer: modules are compiled with
+//! metering, and memories are created with a maximum size.
+use crate::host::limits::ResourceLimits;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use wasmer::vm::{
+    self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition,
+};
+use wasmer::wasmparser::Operator;
+use wasmer::{
+    BaseTunables, Cranelift, MemoryType, Pages, Store, TableType, Target, Tunables, JIT,
+    WASM_MAX_PAGES,
+};
+use wasmer_middlewares::Metering;
+
+/// The fuel a call is given when it is not metered. Modules are always
+/// metered, so that cached modules work under any limits.
+pub(crate) const UNMETERED_FUEL: u64 = i64::MAX as u64;
+
+/// A store which compiles modules with metering, one point of fuel per
+/// operator, and limits memories to [`ResourceLimits::max_memory`].
+pub(crate) fn limited_store(limits: &ResourceLimits) -> Store {
+    let mut compiler = Cranelift::default();
+    compiler.push_middleware(Arc::new(Metering::new(UNMETERED_FUEL, |_: &Operator| 1)));
+    let engine = JIT::new(compiler).engine();
+    let limit = limits.max_memory.map(|bytes| {
+        Pages(std::cmp::min(bytes / Pages(1).bytes().0 as u64, WASM_MAX_PAGES as u64) as u32)
+    });
+    let tunables = LimitingTunables {
+        base: BaseTunables::for_target(&Target::default()),
+        limit,
+    };
+    Store::new_with_tunables(&engine, tunables)
+}
+
+/// Tunables which cap the maximum size of every memory created
+struct LimitingTunables {
+    base: BaseTunables,
+    limit: Option<Pages>,
+}
+
+impl LimitingTunables {
+    /// cap the maximum of `requested` to the limit
+    fn adjust_memory(&self, requested: &MemoryType) -> Result<MemoryType, MemoryError> {
+        let mut adjusted = requested.clone();
+        if let Some(limit) = self.limit {
+            if requested.minimum > limit {
+                return Err(MemoryError::Generic(
+                    "Minimum exceeds the allowed memory limit".into(),
+                ));
+            }
+            adjusted.maximum = Some(requested.maximum.map_or(limit, |m| std::cmp::min(m, limit)));
+        }
+        Ok(adjusted)
+    }
+}
+
+impl Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        match self.adjust_memory(memory) {
+            Ok(adjusted) => self.base.memory_style(&adjusted),
+            Err(_) => self.base.memory_style(memory),
+        }
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        self.base
+            .create_host_memory(&self.adjust_memory(ty)?, style)
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        self.base
+            .create_vm_memory(&self.adjust_memory(ty)?, style, vm_definition_location)
+    }
+
+    fn create_host_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
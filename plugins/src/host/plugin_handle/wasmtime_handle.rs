@@ -0,0 +1,854 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//!  a plugin handle for a wasm plugin run with wasmtime.
+use super::*;
+use crate::capabilities::{Capability, PluginManifest};
+use crate::host::cancel::CancellationToken;
+use crate::host::error::{or_plugin_error, PluginError};
+use crate::host::limits::{Resource, ResourceLimits, Watchdog};
+use crate::host::registry::PluginRegistry;
+use crate::host::wasm_cache::precompiled;
+use crate::WasmCompilationError;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use sapio::contract::CompilationError;
+use sapio_base::effects::EffectPath;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::error::Error;
+use std::io::Write;
+use std::time::Instant;
+use wasmtime::{
+    Caller, Config, Engine, Instance, Linker, Memory, Module, ResourceLimiter, Store, Trap,
+    TypedFunc, WasmParams, WasmResults,
+};
+
+/// The fuel a call is given when it is not metered. Fuel is always
+/// consumed, so that precompiled artifacts work under any limits.
+const UNMETERED_FUEL: u64 = i64::MAX as u64;
+
+/// The state that host-side functions need to be able to use when a plugin
+/// is run with wasmtime.
+pub struct WasmtimeHostState {
+    typ: String,
+    org: String,
+    proj: String,
+    this: [u8; 32],
+    registry: Arc<PluginRegistry>,
+    net: bitcoin::Network,
+    emulator: Arc<dyn CTVEmulator>,
+    exports: Option<ClientExports>,
+    limits: ResourceLimits,
+    /// when the current call must finish by
+    deadline: Option<Instant>,
+    memory: MemoryLimiter,
+    /// what the plugin declared, nothing until it has been loaded
+    manifest: PluginManifest,
+    /// set by host functions which stop the plugin
+    stopped: Option<PluginError>,
+}
+
+/// Denies a plugin growing its memory past [`ResourceLimits::max_memory`],
+/// and remembers that it did.
+struct MemoryLimiter {
+    max: Option<u64>,
+    denied: bool,
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        match self.max {
+            Some(max) if desired as u64 > max => {
+                self.denied = true;
+                false
+            }
+            _ => true,
+        }
+    }
+    fn table_growing(&mut self, _current: u32, _desired: u32, _maximum: Option<u32>) -> bool {
+        true
+    }
+}
+
+/// refill the fuel and reset the deadline for a call into the plugin,
+/// returning the deadline
+fn begin_call(store: &mut Store<WasmtimeHostState>) -> Result<Option<Instant>, Box<dyn Error>> {
+    CancellationToken::check()?;
+    let limits = store.data().limits;
+    let fuel = limits.fuel.unwrap_or(UNMETERED_FUEL);
+    let left = store.consume_fuel(0)?;
+    if left < fuel {
+        store.add_fuel(fuel - left)?;
+    } else {
+        store.consume_fuel(left - fuel)?;
+    }
+    store.set_epoch_deadline(1);
+    let deadline = limits.deadline();
+    let state = store.data_mut();
+    state.deadline = deadline;
+    state.stopped = None;
+    state.memory.denied = false;
+    Ok(deadline)
+}
+
+/// why the host stopped the call that just failed, if it did
+fn stopped(store: &mut Store<WasmtimeHostState>) -> Option<PluginError> {
+    let out_of_fuel = store.consume_fuel(0).map_or(false, |left| left == 0);
+    let state = store.data_mut();
+    let exhausted = if out_of_fuel {
+        Some(Resource::Fuel)
+    } else if state.deadline.map_or(false, |d| Instant::now() >= d) {
+        Some(Resource::Timeout)
+    } else if state.memory.denied {
+        Some(Resource::Memory)
+    } else {
+        None
+    };
+    state
+        .stopped
+        .take()
+        .or_else(|| CancellationToken::check().err())
+        .or_else(|| exhausted.map(Into::into))
+}
+
+/// run `f` against the store as a single metered call into the plugin
+fn metered<R>(
+    store: &mut Store<WasmtimeHostState>,
+    f: impl FnOnce(&mut Store<WasmtimeHostState>) -> Result<R, Box<dyn Error>>,
+) -> Result<R, Box<dyn Error>> {
+    let deadline = begin_call(store)?;
+    let engine = store.engine().clone();
+    let _watchdog = Watchdog::start(deadline, move || engine.increment_epoch());
+    f(&mut *store).map_err(|e| match stopped(store) {
+        Some(p) => Box::new(p) as Box<dyn Error>,
+        None => e,
+    })
+}
+
+/// The plugin-side functions, bound once the plugin is instantiated.
+#[derive(Clone)]
+struct ClientExports {
+    memory: Memory,
+    allocate_wasm_bytes: TypedFunc<i32, i32>,
+    get_api: TypedFunc<(), i32>,
+    get_name: TypedFunc<(), i32>,
+    get_logo: TypedFunc<(), i32>,
+    /// plugins built before manifests existed don't export this
+    get_manifest: Option<TypedFunc<(), i32>>,
+    forget: TypedFunc<i32, ()>,
+    create: TypedFunc<(i32, i32), i32>,
+}
+
+impl ClientExports {
+    fn new(
+        store: &mut Store<WasmtimeHostState>,
+        instance: &Instance,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(ClientExports {
+            memory: instance
+                .get_memory(&mut *store, "memory")
+                .ok_or("No Memory Exported")?,
+            allocate_wasm_bytes: instance.get_typed_func::<i32, i32, _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_allocate_bytes",
+            )?,
+            get_api: instance.get_typed_func::<(), i32, _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_get_create_arguments",
+            )?,
+            get_name: instance.get_typed_func::<(), i32, _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_get_name",
+            )?,
+            get_logo: instance.get_typed_func::<(), i32, _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_get_logo",
+            )?,
+            get_manifest: instance
+                .get_typed_func::<(), i32, _>(
+                    &mut *store,
+                    "sapio_v1_wasm_plugin_client_get_manifest",
+                )
+                .ok(),
+            forget: instance.get_typed_func::<i32, (), _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_drop_allocation",
+            )?,
+            create: instance.get_typed_func::<(i32, i32), i32, _>(
+                &mut *store,
+                "sapio_v1_wasm_plugin_client_create",
+            )?,
+        })
+    }
+}
+
+fn trap<E: std::fmt::Debug>(e: E) -> Trap {
+    Trap::new(format!("{:?}", e))
+}
+
+/// stop the calling plugin
+fn stop<E: Into<PluginError>>(caller: &mut Caller<'_, WasmtimeHostState>, e: E) -> Trap {
+    let e = e.into();
+    let t = trap(&e);
+    caller.data_mut().stopped = Some(e);
+    t
+}
+
+/// stop the calling plugin unless it declared `c`
+fn require(caller: &mut Caller<'_, WasmtimeHostState>, c: Capability) -> Result<(), Trap> {
+    if caller.data().manifest.allows(c) {
+        Ok(())
+    } else {
+        Err(stop(caller, PluginError::CapabilityDenied(c)))
+    }
+}
+
+fn client_exports(caller: &Caller<'_, WasmtimeHostState>) -> Result<ClientExports, Trap> {
+    caller
+        .data()
+        .exports
+        .clone()
+        .ok_or_else(|| Trap::new("Plugin Not Initialized"))
+}
+
+/// read `len` bytes of plugin memory starting at `at`
+fn read_bytes(
+    caller: &Caller<'_, WasmtimeHostState>,
+    exports: &ClientExports,
+    at: i32,
+    len: usize,
+) -> Result<Vec<u8>, Trap> {
+    let mut buf = vec![0u8; len];
+    exports
+        .memory
+        .read(caller, at as usize, &mut buf)
+        .map_err(trap)?;
+    Ok(buf)
+}
+
+/// allocate a buffer in the plugin and copy `s` into it
+fn pass_string(
+    caller: &mut Caller<'_, WasmtimeHostState>,
+    exports: &ClientExports,
+    s: &str,
+) -> Result<i32, Trap> {
+    let bytes = exports
+        .allocate_wasm_bytes
+        .call(&mut *caller, s.len() as i32)?;
+    exports
+        .memory
+        .write(&mut *caller, bytes as usize, s.as_bytes())
+        .map_err(trap)?;
+    Ok(bytes)
+}
+
+/// Run `f` with the plugin `key`, loaded to be called by the calling
+/// plugin. The plugin called shares its caller's limits, so running out
+/// stops the caller too. None if the plugin could not be loaded.
+fn with_nested<T>(
+    caller: &mut Caller<'_, WasmtimeHostState>,
+    key: &str,
+    f: impl FnOnce(&WasmtimePluginHandle) -> Result<T, CompilationError>,
+) -> Result<Option<Result<T, CompilationError>>, Trap> {
+    let limits = caller.data().limits;
+    let fuel = match limits.fuel {
+        Some(_) => Some(caller.consume_fuel(0).map_err(trap)?),
+        None => None,
+    };
+    let nested = match limits.nested(fuel, caller.data().deadline) {
+        Ok(nested) => nested,
+        Err(r) => return Err(stop(caller, r)),
+    };
+    let sph = {
+        let env = caller.data();
+        WasmtimePluginHandle::new_with_registry(
+            env.typ.clone(),
+            env.org.clone(),
+            env.proj.clone(),
+            &env.emulator,
+            Some(key),
+            None,
+            env.net,
+            env.registry.clone(),
+            nested,
+        )
+    };
+    let sph = match sph {
+        Ok(sph) => sph,
+        Err(e) => {
+            return match e.downcast_ref::<PluginError>() {
+                Some(e) if e.stops_caller() => Err(stop(caller, e.clone())),
+                _ => Ok(None),
+            }
+        }
+    };
+    let value = f(&sph);
+    if let Some(e) = value.as_ref().err().and_then(PluginError::find) {
+        if e.stops_caller() {
+            return Err(stop(caller, e.clone()));
+        }
+    }
+    if nested.fuel.is_some() && caller.consume_fuel(sph.fuel_consumed()).is_err() {
+        return Err(stop(caller, Resource::Fuel));
+    }
+    Ok(Some(value))
+}
+
+/// The API of the plugin `key`, fetched once and then remembered by the
+/// registry. None if the plugin could not be loaded or queried.
+fn plugin_api(
+    caller: &mut Caller<'_, WasmtimeHostState>,
+    key: [u8; 32],
+) -> Result<Option<serde_json::Value>, Trap> {
+    let registry = caller.data().registry.clone();
+    if let Some(api) = registry.api(&key) {
+        return Ok(Some(api));
+    }
+    Ok(
+        match with_nested(caller, &hex::encode(key), |sph| sph.get_api())? {
+            Some(Ok(api)) => {
+                registry.insert_api(key, api.clone());
+                Some(api)
+            }
+            _ => None,
+        },
+    )
+}
+
+/// Create an instance of a contract or get the API of another plugin by
+/// "trampolining" through the host. The other plugin is also run with
+/// wasmtime.
+fn wasm_plugin_action(
+    caller: &mut Caller<'_, WasmtimeHostState>,
+    key: i32,
+    action: Option<(i32, i32, i32, i32)>,
+) -> Result<i32, Trap> {
+    let exports = client_exports(caller)?;
+    let mut h = [0u8; 32];
+    h.copy_from_slice(&read_bytes(caller, &exports, key, 32)?);
+    let value = match action {
+        None => plugin_api(caller, h)?.map(Ok),
+        Some((path, path_len, json, json_len)) => {
+            let create_args: Result<CreateArgs<serde_json::Value>, _> =
+                serde_json::from_slice(&read_bytes(caller, &exports, json, json_len as usize)?)
+                    .map_err(CompilationError::DeserializationError);
+            let effectpath: Result<EffectPath, _> =
+                serde_json::from_slice(&read_bytes(caller, &exports, path, path_len as usize)?)
+                    .map_err(CompilationError::DeserializationError);
+            let registry = caller.data().registry.clone();
+            let created = match (effectpath, create_args) {
+                (Ok(path), Ok(args)) => {
+                    registry.outputs().get_or_create(h, &path, &args, || {
+                        with_nested(caller, &hex::encode(h), |sph| sph.create(&path, &args))
+                    })?
+                }
+                (Err(e), _) | (_, Err(e)) => Some(Err(e)),
+            };
+            created.map(|c| {
+                c.and_then(|c| {
+                    serde_json::to_value(c).map_err(CompilationError::SerializationError)
+                })
+            })
+        }
+    };
+    match value {
+        Some(value) => {
+            let comp_s = serde_json::to_string(&value.map_err(|e| WasmCompilationError::from(&e)))
+                .map_err(trap)?;
+            pass_string(caller, &exports, &comp_s)
+        }
+        None => Ok(0),
+    }
+}
+
+/// The key and API of every plugin in the registry which can be loaded
+fn list_plugin_apis(caller: &mut Caller<'_, WasmtimeHostState>) -> Result<i32, Trap> {
+    let exports = client_exports(caller)?;
+    let mut apis = vec![];
+    for key in caller.data().registry.keys() {
+        if let Some(api) = plugin_api(caller, key)? {
+            apis.push((hex::encode(key), api));
+        }
+    }
+    let s = serde_json::to_string(&apis).map_err(trap)?;
+    pass_string(caller, &exports, &s)
+}
+
+/// bind all of the host functions a plugin may import
+fn link_host_functions(linker: &mut Linker<WasmtimeHostState>) -> Result<(), Box<dyn Error>> {
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_lookup_module_name",
+        |mut caller: Caller<'_, WasmtimeHostState>,
+         key: i32,
+         len: i32,
+         out: i32,
+         ok: i32|
+         -> Result<(), Trap> {
+            let exports = client_exports(&caller)?;
+            let m_hash = if key == 0 && len == 0 {
+                Some(caller.data().this)
+            } else {
+                require(&mut caller, Capability::LookupModule)?;
+                let buf = read_bytes(&caller, &exports, key, len as usize)?;
+                caller.data().registry.key_for(&buf)
+            };
+            let is_ok = if let Some(b) = m_hash {
+                exports
+                    .memory
+                    .write(&mut caller, out as usize, &b)
+                    .map_err(trap)?;
+                1
+            } else {
+                0
+            };
+            exports
+                .memory
+                .write(&mut caller, ok as usize, &[is_ok])
+                .map_err(trap)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_get_api",
+        |mut caller: Caller<'_, WasmtimeHostState>, key: i32| -> Result<i32, Trap> {
+            require(&mut caller, Capability::GetApi)?;
+            wasm_plugin_action(&mut caller, key, None)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_create_contract",
+        |mut caller: Caller<'_, WasmtimeHostState>,
+         path: i32,
+         path_len: i32,
+         key: i32,
+         json: i32,
+         json_len: i32|
+         -> Result<i32, Trap> {
+            require(&mut caller, Capability::CreateContract)?;
+            wasm_plugin_action(&mut caller, key, Some((path, path_len, json, json_len)))
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_list_plugin_apis",
+        |mut caller: Caller<'_, WasmtimeHostState>| -> Result<i32, Trap> {
+            require(&mut caller, Capability::GetApi)?;
+            list_plugin_apis(&mut caller)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_debug_log_string",
+        |caller: Caller<'_, WasmtimeHostState>, a: i32, len: i32| -> Result<(), Trap> {
+            let exports = client_exports(&caller)?;
+            let buf = read_bytes(&caller, &exports, a, len as usize)?;
+            let stdout = std::io::stdout();
+            let mut w = std::io::BufWriter::new(stdout.lock());
+            w.write_all(&buf).map_err(trap)?;
+            w.write_all("\n".as_bytes()).map_err(trap)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_ctv_emulator_signer_for",
+        |mut caller: Caller<'_, WasmtimeHostState>, hash: i32| -> Result<i32, Trap> {
+            require(&mut caller, Capability::CtvEmulator)?;
+            let exports = client_exports(&caller)?;
+            let mut buf = [0u8; 32];
+            buf.copy_from_slice(&read_bytes(&caller, &exports, hash, 32)?);
+            let h = sha256::Hash::from_inner(buf);
+            // emulator errors are returned to the plugin
+            let clause = caller
+                .data()
+                .emulator
+                .get_signer_for(h)
+                .map_err(|e| e.to_string());
+            let s = serde_json::to_string_pretty(&clause).map_err(trap)?;
+            pass_string(&mut caller, &exports, &s)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "sapio_v1_wasm_plugin_ctv_emulator_sign",
+        |mut caller: Caller<'_, WasmtimeHostState>, psbt: i32, len: u32| -> Result<i32, Trap> {
+            require(&mut caller, Capability::CtvEmulator)?;
+            let exports = client_exports(&caller)?;
+            let buf = read_bytes(&caller, &exports, psbt, len as usize)?;
+            let psbt = serde_json::from_slice::<PartiallySignedTransaction>(&buf)
+                .map_err(|e| e.to_string())
+                .and_then(|psbt| caller.data().emulator.sign(psbt).map_err(|e| e.to_string()));
+            let s = serde_json::to_string_pretty(&psbt).map_err(trap)?;
+            pass_string(&mut caller, &exports, &s)
+        },
+    )?;
+    Ok(())
+}
+
+/// A plugin handle for a WASM plugin run with wasmtime.
+pub struct WasmtimePluginHandle {
+    store: Mutex<Store<WasmtimeHostState>>,
+    exports: ClientExports,
+    key: String,
+}
+
+impl WasmtimePluginHandle {
+    /// the cache ID for this plugin
+    pub fn id(&self) -> String {
+        self.key.clone()
+    }
+
+    /// load all the precompiled keys as plugins upfront.
+    pub fn load_all_keys(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: NullEmulator,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut r = vec![];
+        for key in precompiled::get_all_keys_from_fs(&typ, &org, &proj)? {
+            let wph = Self::new(
+                typ.clone(),
+                org.clone(),
+                proj.clone(),
+                &emulator,
+                Some(&key),
+                None,
+                net,
+                plugin_map.clone(),
+            )?;
+            r.push(wph)
+        }
+        Ok(r)
+    }
+
+    pub async fn new_async(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&OsStr>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let file = if let Some(f) = file {
+            Some(tokio::fs::read(f).await?)
+        } else {
+            None
+        };
+        Self::new(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file.as_ref(),
+            net,
+            plugin_map,
+        )
+    }
+
+    /// Create an plugin handle with the default [`ResourceLimits`]. Only one
+    /// of key or file should be set, and one should be set.
+    ///
+    /// When a file is passed, the precompiled artifact is used if one exists,
+    /// otherwise the module is compiled and the artifact is stored for next time.
+    pub fn new(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::new_limited(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file,
+            net,
+            plugin_map,
+            ResourceLimits::default(),
+        )
+    }
+
+    /// Create an plugin handle, every call into which (including loading
+    /// it) is held to `limits`. See [`WasmtimePluginHandle::new`].
+    pub fn new_limited(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+        limits: ResourceLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        let registry = PluginRegistry::new(plugin_map.unwrap_or_else(HashMap::new));
+        Self::new_with_registry(
+            typ,
+            org,
+            proj,
+            emulator,
+            key,
+            file,
+            net,
+            Arc::new(registry),
+            limits,
+        )
+    }
+
+    /// Create an plugin handle sharing `registry` (and so the APIs and
+    /// contracts it caches) with other handles, see
+    /// [`WasmtimePluginHandle::new_limited`].
+    pub fn new_with_registry(
+        typ: String,
+        org: String,
+        proj: String,
+        emulator: &Arc<dyn CTVEmulator>,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+        net: bitcoin::Network,
+        registry: Arc<PluginRegistry>,
+        limits: ResourceLimits,
+    ) -> Result<Self, Box<dyn Error>> {
+        // ensures that either key or file is passed
+        key.xor(file.and(Some("")))
+            .ok_or("Passed Both Key and File or Neither")?;
+        let mut config = Config::new();
+        config.consume_fuel(true).epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let (module, key) = match (file, key) {
+            (Some(wasm_bytes), _) => {
+                match precompiled::load_module(&typ, &org, &proj, &engine, wasm_bytes) {
+                    Ok(module) => module,
+                    Err(_) => {
+                        let module = Module::new(&engine, wasm_bytes)?;
+                        let key =
+                            precompiled::store_module(&typ, &org, &proj, &module, wasm_bytes)?;
+                        (module, key)
+                    }
+                }
+            }
+            (_, Some(key)) => precompiled::load_module_key(&typ, &org, &proj, &engine, key)?,
+            _ => unreachable!(),
+        };
+        let mut this = [0; 32];
+        this.clone_from_slice(&hex::decode(&key)?);
+        let mut store = Store::new(
+            &engine,
+            WasmtimeHostState {
+                typ,
+                org,
+                proj,
+                this,
+                registry,
+                net,
+                emulator: emulator.clone(),
+                exports: None,
+                limits,
+                deadline: None,
+                memory: MemoryLimiter {
+                    max: limits.max_memory,
+                    denied: false,
+                },
+                manifest: PluginManifest::default(),
+                stopped: None,
+            },
+        );
+        store.limiter(|state| &mut state.memory);
+        let mut linker = Linker::new(&engine);
+        link_host_functions(&mut linker)?;
+        let exports = metered(&mut store, |store| {
+            let instance = linker.instantiate(&mut *store, &module)?;
+            let exports = ClientExports::new(store, &instance)?;
+            store.data_mut().exports = Some(exports.clone());
+            instance
+                .get_typed_func::<(), (), _>(&mut *store, "sapio_v1_wasm_plugin_entry_point")
+                .map_err(|_| "No Init Function Specified")?
+                .call(&mut *store, ())?;
+            Ok(exports)
+        })?;
+        let handle = WasmtimePluginHandle {
+            store: Mutex::new(store),
+            exports,
+            key,
+        };
+        // without a manifest, the plugin keeps the default of no capabilities
+        if let Some(get_manifest) = &handle.exports.get_manifest {
+            let manifest =
+                handle.call_for_string(get_manifest, CompilationError::ModuleRuntimeError)?;
+            handle.store.lock().unwrap().data_mut().manifest = serde_json::from_slice(&manifest)?;
+        }
+        Ok(handle)
+    }
+
+    /// the fuel consumed by every call into this plugin so far
+    pub fn fuel_consumed(&self) -> u64 {
+        self.store.lock().unwrap().fuel_consumed().unwrap_or(0)
+    }
+
+    /// call a function of the plugin, held to its [`ResourceLimits`]
+    fn call<P: WasmParams, R: WasmResults>(
+        &self,
+        f: &TypedFunc<P, R>,
+        params: P,
+    ) -> Result<R, Box<dyn Error>> {
+        let mut store = self.store.lock().unwrap();
+        metered(&mut store, |store| Ok(f.call(store, params)?))
+    }
+
+    /// forget an allocated pointer
+    pub fn forget(&self, p: i32) -> Result<(), CompilationError> {
+        self.call(&self.exports.forget, p)
+            .map_err(or_plugin_error(|e| {
+                CompilationError::ModuleCouldNotDeallocate(p, e)
+            }))
+    }
+
+    /// create an allocation
+    pub fn allocate(&self, len: i32) -> Result<i32, CompilationError> {
+        self.call(&self.exports.allocate_wasm_bytes, len)
+            .map_err(or_plugin_error(|e| {
+                CompilationError::ModuleCouldNotAllocateError(len, e)
+            }))
+    }
+
+    /// pass a string to the WASM plugin
+    pub fn pass_string(&self, s: &str) -> Result<i32, CompilationError> {
+        let offset = self.allocate(s.len() as i32)?;
+        let res = {
+            let mut store = self.store.lock().unwrap();
+            self.exports
+                .memory
+                .write(&mut *store, offset as usize, s.as_bytes())
+        };
+        match res {
+            Ok(_) => Ok(offset),
+            Err(e) => {
+                self.forget(offset)?;
+                Err(CompilationError::ModuleFailedToGetMemory(e.into()))
+            }
+        }
+    }
+
+    /// read something from wasm memory, null terminated
+    fn read_to_vec(&self, p: i32) -> Result<Vec<u8>, CompilationError> {
+        let store = self.store.lock().unwrap();
+        Ok(self
+            .exports
+            .memory
+            .data(&*store)
+            .get(p as usize..)
+            .unwrap_or_default()
+            .iter()
+            .take_while(|i| **i != 0)
+            .cloned()
+            .collect())
+    }
+
+    /// call a plugin function which returns an allocated string, read it,
+    /// and free it.
+    fn call_for_string(
+        &self,
+        f: &TypedFunc<(), i32>,
+        on_err: fn(Box<dyn Error>) -> CompilationError,
+    ) -> Result<Vec<u8>, CompilationError> {
+        let p = self.call(f, ()).map_err(or_plugin_error(on_err))?;
+        let v = self.read_to_vec(p)?;
+        self.forget(p)?;
+        Ok(v)
+    }
+}
+
+impl PluginHandle for WasmtimePluginHandle {
+    fn create(
+        &self,
+        path: &EffectPath,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Result<Compiled, CompilationError> {
+        let arg_str = serde_json::to_string(c).map_err(CompilationError::SerializationError)?;
+        let args_ptr = self.pass_string(&arg_str)?;
+        let path_str = serde_json::to_string(path).map_err(CompilationError::SerializationError)?;
+        let path_ptr = self.pass_string(&path_str)?;
+        let result_ptr = self
+            .call(&self.exports.create, (path_ptr, args_ptr))
+            .map_err(or_plugin_error(|e| {
+                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e)
+            }))?;
+        let buf = self.read_to_vec(result_ptr)?;
+        self.forget(result_ptr)?;
+        let v: Result<Compiled, WasmCompilationError> =
+            serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
+        // keep the path so a failure can be traced through nested plugins
+        v.map_err(|e| {
+            CompilationError::ModuleCouldNotCreateContract(
+                path.clone(),
+                c.clone(),
+                Box::new(CompilationError::from(e)),
+            )
+        })
+    }
+    fn get_api(&self) -> Result<serde_json::value::Value, CompilationError> {
+        let v = self.call_for_string(
+            &self.exports.get_api,
+            CompilationError::ModuleCouldNotGetAPI,
+        )?;
+        serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)
+    }
+    fn get_name(&self) -> Result<String, CompilationError> {
+        let v = self.call_for_string(
+            &self.exports.get_name,
+            CompilationError::ModuleCouldNotGetName,
+        )?;
+        Ok(String::from_utf8_lossy(&v).to_string())
+    }
+    fn get_logo(&self) -> Result<String, CompilationError> {
+        let v = self.call_for_string(
+            &self.exports.get_logo,
+            CompilationError::ModuleCouldNotGetLogo,
+        )?;
+        Ok(String::from_utf8_lossy(&v).to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+
+    #[test]
+    fn missing_manifest_grants_nothing() -> Result<(), Box<dyn Error>> {
+        let emulator: Arc<dyn CTVEmulator> = Arc::new(CTVAvailable);
+        let handle = WasmtimePluginHandle::new_limited(
+            "org".into(),
+            "judica".into(),
+            "sapio-test".into(),
+            &emulator,
+            None,
+            Some(&NO_MANIFEST_PLUGIN.as_bytes().to_vec()),
+            bitcoin::Network::Regtest,
+            None,
+            ResourceLimits::default(),
+        )?;
+        assert!(handle.exports.get_manifest.is_none());
+        assert_eq!(
+            handle.store.lock().unwrap().data().manifest,
+            PluginManifest::default()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The plugins a host knows of, by name, and the interfaces they advertise.
+//!
+//! A plugin's API (the JSON schema of its arguments) is what it advertises:
+//! a plugin implements a trait if an example of the trait's arguments
+//! validates against its API (see `sapio_trait::SapioJSONTrait`). Since
+//! plugins are keyed by the hash of their module, a plugin's API never
+//! changes, so it is only ever fetched once.
+//!
+//! The registry also holds the [`OutputCache`] of the contracts its plugins
+//! have created for one another.
+use super::output_cache::OutputCache;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, RwLock};
+
+/// The plugins a host, and every plugin it runs, may look up and discover.
+///
+/// A registry is shared by a plugin and the plugins it calls. The plugin a
+/// name refers to may change while it is shared, e.g. when a
+/// [`PluginWatcher`](super::PluginWatcher) reloads a plugin.
+#[derive(Debug, Default)]
+pub struct PluginRegistry {
+    names: RwLock<HashMap<Vec<u8>, [u8; 32]>>,
+    apis: Mutex<BTreeMap<[u8; 32], serde_json::Value>>,
+    outputs: OutputCache,
+}
+
+impl PluginRegistry {
+    /// A registry of the plugins in `names`
+    pub fn new(names: HashMap<Vec<u8>, [u8; 32]>) -> Self {
+        PluginRegistry {
+            names: RwLock::new(names),
+            apis: Default::default(),
+            outputs: Default::default(),
+        }
+    }
+
+    /// use `outputs` to cache the contracts plugins create
+    pub fn with_output_cache(mut self, outputs: OutputCache) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    /// The contracts plugins have created for one another
+    pub fn outputs(&self) -> &OutputCache {
+        &self.outputs
+    }
+
+    /// The key of the plugin called `name`
+    pub fn key_for(&self, name: &[u8]) -> Option<[u8; 32]> {
+        self.names.read().unwrap().get(name).cloned()
+    }
+
+    /// Make `name` refer to the plugin `key`, returning the key it referred
+    /// to before
+    pub fn insert_name(&self, name: Vec<u8>, key: [u8; 32]) -> Option<[u8; 32]> {
+        self.names.write().unwrap().insert(name, key)
+    }
+
+    /// Forget the name `name`, returning the key it referred to
+    pub fn remove_name(&self, name: &[u8]) -> Option<[u8; 32]> {
+        self.names.write().unwrap().remove(name)
+    }
+
+    /// The key of every plugin in the registry, in order
+    pub fn keys(&self) -> Vec<[u8; 32]> {
+        let mut keys: Vec<_> = self.names.read().unwrap().values().cloned().collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    /// The API of the plugin `key`, if it has been fetched
+    pub fn api(&self, key: &[u8; 32]) -> Option<serde_json::Value> {
+        self.apis.lock().unwrap().get(key).cloned()
+    }
+
+    /// Remember the API of the plugin `key`
+    pub fn insert_api(&self, key: [u8; 32], api: serde_json::Value) {
+        self.apis.lock().unwrap().insert(key, api);
+    }
+}
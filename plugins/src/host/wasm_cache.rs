@@ -6,15 +6,23 @@
 
 //! tools for caching compilations of wasm plugins to disk
 use std::path::PathBuf;
+#[cfg(feature = "wasmer-engine")]
 use wasmer::{DeserializeError, Module, SerializeError, Store};
+#[cfg(feature = "wasmer-engine")]
 use wasmer_cache::{Cache, FileSystemCache, Hash};
 
-/// get the path for the compiled modules
+/// get the path for the compiled modules. Modules are compiled with
+/// metering, so those cached before metering was added are not reused.
 fn get_path(typ: &str, org: &str, proj: &str) -> impl Into<PathBuf> {
+    get_path_in(typ, org, proj, "modules_metered")
+}
+
+/// get the path for compiled modules stored in the data directory `dir`
+fn get_path_in(typ: &str, org: &str, proj: &str, dir: &str) -> PathBuf {
     let proj =
         directories::ProjectDirs::from(typ, org, proj).expect("Failed to find config directory");
     let mut path: PathBuf = proj.data_dir().clone().into();
-    path.push("modules");
+    path.push(dir);
     path
 }
 
@@ -24,7 +32,12 @@ pub fn get_all_keys_from_fs(
     org: &str,
     proj: &str,
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    std::fs::read_dir(get_path(typ, org, proj).into())?
+    get_all_keys_in(get_path(typ, org, proj).into())
+}
+
+/// get all of the keys (as Strings) for plugins stored in `path`
+fn get_all_keys_in(path: PathBuf) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    std::fs::read_dir(path)?
         .map(|entry| {
             match entry.map(|x| {
                 x.path()
@@ -40,7 +53,21 @@ pub fn get_all_keys_from_fs(
         .collect()
 }
 
+/// compute the key a module is cached under from its bytes, which is the
+/// same under either engine
+pub fn module_key(bytes: &[u8]) -> String {
+    #[cfg(feature = "wasmtime-engine")]
+    {
+        precompiled::key_for(bytes)
+    }
+    #[cfg(not(feature = "wasmtime-engine"))]
+    {
+        Hash::generate(bytes).to_string()
+    }
+}
+
 /// load a module given the bytes of the module, may consult cache if available
+#[cfg(feature = "wasmer-engine")]
 pub fn load_module(
     typ: &str,
     org: &str,
@@ -55,6 +82,7 @@ pub fn load_module(
 }
 
 /// load a module from the cache
+#[cfg(feature = "wasmer-engine")]
 pub fn load_module_key(
     typ: &str,
     org: &str,
@@ -68,6 +96,7 @@ pub fn load_module_key(
 }
 
 /// store a module into the cache
+#[cfg(feature = "wasmer-engine")]
 pub fn store_module(
     typ: &str,
     org: &str,
@@ -86,3 +115,88 @@ pub fn store_module(
     cache.store(key, module)?;
     Ok(key)
 }
+
+/// Precompiled module artifacts for the wasmtime engine.
+///
+/// Artifacts are keyed by the same blake3 hash of the module bytes that the
+/// wasmer cache uses, so a plugin has the same key under either engine.
+#[cfg(feature = "wasmtime-engine")]
+pub mod precompiled {
+    use super::*;
+    use std::error::Error;
+    use wasmtime::{Engine, Module};
+
+    /// the data directory precompiled wasmtime artifacts are kept in
+    const WASMTIME_MODULES: &str = "modules_wasmtime";
+    /// the file extension for precompiled wasmtime artifacts
+    const EXTENSION: &str = "cwasm";
+
+    /// compute the key for a module from its bytes
+    pub fn key_for(bytes: &[u8]) -> String {
+        blake3::hash(bytes).to_hex().to_string()
+    }
+
+    fn artifact_path(typ: &str, org: &str, proj: &str, key: &str) -> PathBuf {
+        let mut path = get_path_in(typ, org, proj, WASMTIME_MODULES);
+        path.push(key);
+        path.set_extension(EXTENSION);
+        path
+    }
+
+    /// look at the cache and get all of the keys (as Strings) for precompiled plugins
+    pub fn get_all_keys_from_fs(
+        typ: &str,
+        org: &str,
+        proj: &str,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        get_all_keys_in(get_path_in(typ, org, proj, WASMTIME_MODULES))
+    }
+
+    /// load a precompiled module from the cache.
+    ///
+    /// Fails if the artifact was produced by an incompatible engine
+    /// configuration or wasmtime version.
+    pub fn load_module_key(
+        typ: &str,
+        org: &str,
+        proj: &str,
+        engine: &Engine,
+        key: &str,
+    ) -> Result<(Module, String), Box<dyn Error>> {
+        let path = artifact_path(typ, org, proj, key);
+        // Safety: artifacts are only ever written by `store_module` from a
+        // module compiled by wasmtime, and deserialization checks the
+        // artifact is compatible with `engine`.
+        let module = unsafe { Module::deserialize_file(engine, path) }?;
+        Ok((module, key.into()))
+    }
+
+    /// load a module given the bytes of the module, using the precompiled
+    /// artifact if one is available
+    pub fn load_module(
+        typ: &str,
+        org: &str,
+        proj: &str,
+        engine: &Engine,
+        bytes: &[u8],
+    ) -> Result<(Module, String), Box<dyn Error>> {
+        load_module_key(typ, org, proj, engine, &key_for(bytes))
+    }
+
+    /// precompile a module and store the artifact into the cache
+    pub fn store_module(
+        typ: &str,
+        org: &str,
+        proj: &str,
+        module: &Module,
+        bytes: &[u8],
+    ) -> Result<String, Box<dyn Error>> {
+        let key = key_for(bytes);
+        let path = artifact_path(typ, org, proj, &key);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, module.serialize()?)?;
+        Ok(key)
+    }
+}
@@ -0,0 +1,294 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watching a directory of plugins and reloading them as they change.
+//!
+//! For iterative development, a [`PluginWatcher`] loads every `.wasm`
+//! module in a directory under the name of its file (`treepay.wasm` is
+//! `treepay`), and reloads a module whenever its file changes. The
+//! watcher's [`PluginRegistry`] always maps a name to the module loaded
+//! last, so plugins looking up a name see reloads too.
+//!
+//! Callers choose per call whether to follow reloads, with
+//! [`PluginPin::Latest`], or to run exactly one module, with
+//! [`PluginPin::Exact`]. Modules are cached by hash, so an exact pin keeps
+//! working after its module was replaced or unloaded.
+use super::wasm_cache::module_key;
+use super::{PluginHandle, PluginRegistry, PluginSource};
+use sapio::contract::CompilationError;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// How often a [`PluginWatcher`] checks its directory by default
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// # Plugin Pin
+/// Which module a call should run.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginPin {
+    /// # Latest
+    /// The module most recently loaded under a name
+    Latest(String),
+    /// # Exact
+    /// The module with this hex encoded hash
+    Exact(String),
+}
+
+/// # Plugin Event
+/// A change to the plugins a [`PluginWatcher`] has loaded.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginEvent {
+    /// A new name was loaded
+    Loaded {
+        /// the plugin's name
+        name: String,
+        /// the module's key
+        key: String,
+    },
+    /// A name now refers to a different module
+    Replaced {
+        /// the plugin's name
+        name: String,
+        /// the key of the module replaced
+        old: String,
+        /// the key of the module loaded
+        new: String,
+    },
+    /// A name's file was removed
+    Unloaded {
+        /// the plugin's name
+        name: String,
+        /// the key of the module the name referred to
+        key: String,
+    },
+    /// A module failed to load. A module already loaded under the name is
+    /// kept.
+    Failed {
+        /// the plugin's name
+        name: String,
+        /// why it failed
+        error: String,
+    },
+}
+
+/// A module file, as of when it was last checked
+#[derive(Clone, PartialEq, Eq)]
+struct Seen {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+struct WatchState {
+    dir: PathBuf,
+    source: PluginSource,
+    /// the files loaded, or which failed to load, by name
+    seen: Mutex<HashMap<String, Seen>>,
+    subscribers: Mutex<Vec<mpsc::Sender<PluginEvent>>>,
+}
+
+/// Loads the plugins in a directory, and reloads them as they change.
+///
+/// Dropping the watcher stops watching, but the plugins it loaded remain
+/// cached.
+pub struct PluginWatcher {
+    state: Arc<WatchState>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PluginWatcher {
+    /// Load every plugin in `dir` with `source`, and check for changes
+    /// every `interval`. The plugins are named in `source`'s registry.
+    pub fn watch(
+        dir: PathBuf,
+        source: PluginSource,
+        interval: Duration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let state = Arc::new(WatchState {
+            dir,
+            source,
+            seen: Default::default(),
+            subscribers: Default::default(),
+        });
+        state.scan()?;
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a directory which can not be read is checked again
+                    // later
+                    let _ = state.scan();
+                }
+            })
+        };
+        Ok(PluginWatcher {
+            state,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// A stream of the changes to the plugins from now on
+    pub fn subscribe(&self) -> mpsc::Receiver<PluginEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.state.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Check the directory for changes now, rather than waiting
+    pub fn rescan(&self) -> Result<(), Box<dyn Error>> {
+        self.state.scan()
+    }
+
+    /// The registry the plugins are named in
+    pub fn registry(&self) -> &Arc<PluginRegistry> {
+        self.state.source.registry()
+    }
+
+    /// The key of the module `pin` refers to
+    pub fn resolve(&self, pin: &PluginPin) -> Result<[u8; 32], CompilationError> {
+        match pin {
+            PluginPin::Latest(name) => self
+                .registry()
+                .key_for(name.as_bytes())
+                .ok_or(CompilationError::UnknownModule),
+            PluginPin::Exact(key) => {
+                let mut r = [0u8; 32];
+                hex::decode_to_slice(key, &mut r).map_err(|_| CompilationError::UnknownModule)?;
+                Ok(r)
+            }
+        }
+    }
+
+    /// Load the module `pin` refers to
+    pub fn load(&self, pin: &PluginPin) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        let key = self.resolve(pin)?;
+        self.state.source.load(&hex::encode(key))
+    }
+}
+
+impl Drop for PluginWatcher {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl WatchState {
+    /// Load the modules which changed since the last scan, and unload the
+    /// ones which were removed
+    fn scan(&self) -> Result<(), Box<dyn Error>> {
+        let mut found = HashMap::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("wasm")) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(OsStr::to_str) {
+                found.insert(name.to_string(), path.clone());
+            }
+        }
+        let mut seen = self.seen.lock().unwrap();
+        let removed: Vec<String> = seen
+            .keys()
+            .filter(|name| !found.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in removed {
+            seen.remove(&name);
+            if let Some(key) = self.source.registry().remove_name(name.as_bytes()) {
+                self.unload(&key);
+                self.emit(PluginEvent::Unloaded {
+                    name,
+                    key: hex::encode(key),
+                });
+            }
+        }
+        for (name, path) in found {
+            let now = match Seen::of(&path) {
+                Ok(now) => now,
+                // e.g., removed while scanning
+                Err(_) => continue,
+            };
+            if seen.get(&name) == Some(&now) {
+                continue;
+            }
+            seen.insert(name.clone(), now);
+            match self.load(&path) {
+                Ok(key) => self.name(name, key),
+                Err(e) => self.emit(PluginEvent::Failed {
+                    name,
+                    error: e.to_string(),
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the module at `path`, returning its key
+    fn load(&self, path: &Path) -> Result<[u8; 32], Box<dyn Error>> {
+        let bytes = std::fs::read(path)?;
+        self.source.load_module(&bytes)?;
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(module_key(&bytes), &mut key)?;
+        Ok(key)
+    }
+
+    /// Point `name` at the module `key`
+    fn name(&self, name: String, key: [u8; 32]) {
+        match self.source.registry().insert_name(name.clone().into(), key) {
+            None => self.emit(PluginEvent::Loaded {
+                name,
+                key: hex::encode(key),
+            }),
+            Some(old) if old != key => {
+                self.unload(&old);
+                self.emit(PluginEvent::Replaced {
+                    name,
+                    old: hex::encode(old),
+                    new: hex::encode(key),
+                })
+            }
+            // the file was touched, but the module is the same
+            Some(_) => {}
+        }
+    }
+
+    /// Drop the contracts `key` created, which only an exact pin can still
+    /// ask for
+    fn unload(&self, key: &[u8; 32]) {
+        self.source.registry().outputs().invalidate(key);
+    }
+
+    fn emit(&self, event: PluginEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+impl Seen {
+    fn of(path: &Path) -> std::io::Result<Self> {
+        let meta = std::fs::metadata(path)?;
+        Ok(Seen {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+        })
+    }
+}
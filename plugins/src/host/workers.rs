@@ -0,0 +1,302 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running plugins off the calling thread.
+//!
+//! A call into a plugin blocks until the plugin returns, so a host which
+//! must stay responsive (e.g., a UI embedding the compiler) hands its calls
+//! to a pool of [`PluginWorkers`] instead. Each job returns a
+//! [`PluginTask`], which is a future of the job's result and can cancel the
+//! plugin calls the job makes (see [`CancellationToken`]).
+use super::cancel::CancellationToken;
+use super::error::{or_plugin_error, PluginError};
+use super::{EngineKind, PluginHandle, PluginRegistry, ResourceLimits};
+use crate::{CreateArgs, WasmCompilationError};
+use sapio::contract::{CompilationError, Compiled};
+use sapio_base::effects::EffectPath;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::JoinHandle;
+
+/// Where the plugins a job runs are loaded from, and how they are run.
+#[derive(Clone)]
+pub struct PluginSource {
+    engine: EngineKind,
+    typ: String,
+    org: String,
+    proj: String,
+    emulator: Arc<dyn CTVEmulator>,
+    net: bitcoin::Network,
+    registry: Arc<PluginRegistry>,
+    limits: ResourceLimits,
+}
+
+impl PluginSource {
+    /// Load plugins cached by the sapio cli with `engine`, resolving names
+    /// with `plugin_map`, and run them under the default [`ResourceLimits`]
+    pub fn new(
+        engine: EngineKind,
+        emulator: Arc<dyn CTVEmulator>,
+        net: bitcoin::Network,
+        plugin_map: Option<HashMap<Vec<u8>, [u8; 32]>>,
+    ) -> Self {
+        PluginSource {
+            engine,
+            typ: "org".into(),
+            org: "judica".into(),
+            proj: "sapio-cli".into(),
+            emulator,
+            net,
+            registry: Arc::new(PluginRegistry::new(plugin_map.unwrap_or_else(HashMap::new))),
+            limits: Default::default(),
+        }
+    }
+    /// load plugins from the cache of the project `typ`, `org`, `proj`
+    pub fn with_project(mut self, typ: String, org: String, proj: String) -> Self {
+        self.typ = typ;
+        self.org = org;
+        self.proj = proj;
+        self
+    }
+    /// share `registry` (and the APIs and contracts it caches)
+    pub fn with_registry(mut self, registry: Arc<PluginRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+    /// run plugins under `limits`
+    pub fn with_limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The registry plugins are loaded with
+    pub fn registry(&self) -> &Arc<PluginRegistry> {
+        &self.registry
+    }
+
+    /// Load the plugin `key`
+    pub fn load(&self, key: &str) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        self.load_from(Some(key), None)
+    }
+
+    /// Load the plugin in the module `bytes`, caching it to be loaded by key
+    pub fn load_module(&self, bytes: &Vec<u8>) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        self.load_from(None, Some(bytes))
+    }
+
+    fn load_from(
+        &self,
+        key: Option<&str>,
+        file: Option<&Vec<u8>>,
+    ) -> Result<Box<dyn PluginHandle>, Box<dyn Error>> {
+        self.engine.load_with_registry(
+            self.typ.clone(),
+            self.org.clone(),
+            self.proj.clone(),
+            &self.emulator,
+            key,
+            file,
+            self.net,
+            self.registry.clone(),
+            self.limits,
+        )
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads which run plugins.
+///
+/// Dropping the pool waits for the jobs already given to it to finish.
+pub struct PluginWorkers {
+    jobs: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PluginWorkers {
+    /// A pool of `threads` threads (at least one)
+    pub fn new(threads: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let threads = (0..std::cmp::max(threads, 1))
+            .map(|_| {
+                let rx = rx.clone();
+                std::thread::spawn(move || loop {
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    job()
+                })
+            })
+            .collect();
+        PluginWorkers {
+            jobs: Some(jobs),
+            threads,
+        }
+    }
+
+    /// Run `f` on the pool, with every plugin call it makes governed by the
+    /// returned task's [`CancellationToken`].
+    pub fn spawn<T, F>(&self, f: F) -> PluginTask<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, CompilationError> + Send + 'static,
+    {
+        let task = PluginTask {
+            shared: Arc::new((Mutex::new(Shared::default()), Condvar::new())),
+            cancel: CancellationToken::new(),
+        };
+        let shared = task.shared.clone();
+        let cancel = task.cancel.clone();
+        let job = Box::new(move || {
+            let result = if cancel.is_cancelled() {
+                Err(Failure::Plugin(PluginError::Cancelled))
+            } else {
+                match catch_unwind(AssertUnwindSafe(|| cancel.run(f))) {
+                    Ok(r) => r.map_err(Failure::from),
+                    Err(_) => Err(Failure::Other(WasmCompilationError::Other(
+                        "Plugin Job Panicked".into(),
+                    ))),
+                }
+            };
+            let (state, done) = &*shared;
+            let mut state = state.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            done.notify_all();
+        });
+        if let Some(jobs) = &self.jobs {
+            // the workers only hang up once the pool is dropped
+            let _ = jobs.send(job);
+        }
+        task
+    }
+
+    /// Create a contract with the plugin `key` on the pool, the way a plugin
+    /// would with `create_contract_by_key`.
+    pub fn create_contract_by_key(
+        &self,
+        source: PluginSource,
+        key: [u8; 32],
+        path: EffectPath,
+        args: CreateArgs<serde_json::Value>,
+    ) -> PluginTask<Compiled> {
+        self.spawn(move || {
+            let created = source
+                .registry
+                .outputs()
+                .get_or_create(key, &path, &args, || {
+                    let sph = source
+                        .load(&hex::encode(key))
+                        .map_err(or_plugin_error(CompilationError::ModuleRuntimeError))?;
+                    Ok(Some(sph.create(&path, &args)))
+                })?;
+            created.unwrap_or(Err(CompilationError::UnknownModule))
+        })
+    }
+}
+
+impl Drop for PluginWorkers {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Why a job failed, as errors can not be sent between threads
+enum Failure {
+    Plugin(PluginError),
+    Other(WasmCompilationError),
+}
+
+impl From<CompilationError> for Failure {
+    fn from(e: CompilationError) -> Self {
+        match PluginError::find(&e) {
+            Some(p) => Failure::Plugin(p.clone()),
+            None => Failure::Other(WasmCompilationError::from(&e)),
+        }
+    }
+}
+
+impl From<Failure> for CompilationError {
+    fn from(f: Failure) -> Self {
+        match f {
+            Failure::Plugin(p) => p.into(),
+            Failure::Other(e) => e.into(),
+        }
+    }
+}
+
+struct Shared<T> {
+    result: Option<Result<T, Failure>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Default for Shared<T> {
+    fn default() -> Self {
+        Shared {
+            result: None,
+            waker: None,
+        }
+    }
+}
+
+/// A job running on [`PluginWorkers`], which can be awaited or waited on
+/// for its result.
+///
+/// Dropping a task does not cancel it.
+pub struct PluginTask<T> {
+    shared: Arc<(Mutex<Shared<T>>, Condvar)>,
+    cancel: CancellationToken,
+}
+
+impl<T> PluginTask<T> {
+    /// Cancel the job. It fails with [`PluginError::Cancelled`] unless it
+    /// finishes first.
+    pub fn cancel(&self) {
+        self.cancel.cancel()
+    }
+    /// The token which cancels the job, to cancel it from elsewhere
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+    /// Block until the job finishes
+    pub fn wait(self) -> Result<T, CompilationError> {
+        let (state, done) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result.map_err(Into::into);
+            }
+            state = done.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T> Future for PluginTask<T> {
+    type Output = Result<T, CompilationError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.0.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result.map_err(Into::into)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -21,7 +21,13 @@ where
     serde_json::from_str(&s).map_err(serde::de::Error::custom)
 }
 
-#[cfg(feature = "host")]
+pub mod capabilities;
+pub use capabilities::{Capability, PluginManifest};
+
+pub mod error;
+pub use error::WasmCompilationError;
+
+#[cfg(any(feature = "wasmer-engine", feature = "wasmtime-engine"))]
 pub mod host;
 
 #[cfg(feature = "client")]
@@ -0,0 +1,458 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A canonical binary encoding for values exchanged between parties who must
+//! agree on them byte for byte (e.g., to hash them), without JSON's pitfalls
+//! of map ordering and of many texts for the same number.
+//!
+//! Values are encoded from their JSON form as deterministic CBOR (RFC 8949
+//! section 4.2.1):
+//! - integers take the shortest form, and floats with an integral value are
+//!   encoded as that integer (so `1.0` and `1` agree);
+//! - other floats are always 64 bit;
+//! - map keys are sorted by their encoding, bytewise;
+//! - lengths are always definite, and tags are never used.
+//!
+//! Decoding rejects anything not in this form, so every value has exactly one
+//! encoding. Decoding then re-encoding always gives back the same bytes, and
+//! a value decodes to one equal to what was encoded, except that integral
+//! floats come back as integers.
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+use std::convert::TryFrom;
+
+/// Errors from canonically encoding or decoding a value
+#[derive(Debug)]
+pub enum CanonicalError {
+    /// The value could not be converted to or from JSON
+    Json(serde_json::Error),
+    /// The encoding ended early
+    Truncated,
+    /// There were this many bytes left after the encoded value
+    TrailingBytes(usize),
+    /// The encoding is CBOR, but not in canonical form
+    NotCanonical(&'static str),
+    /// The encoding uses a CBOR feature with no JSON equivalent, starting
+    /// with this byte
+    Unsupported(u8),
+    /// A string was not UTF-8
+    InvalidUtf8,
+    /// Arrays or maps were nested more than [`MAX_DEPTH`] deep
+    TooDeep,
+}
+impl std::error::Error for CanonicalError {}
+impl std::fmt::Display for CanonicalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<serde_json::Error> for CanonicalError {
+    fn from(e: serde_json::Error) -> Self {
+        CanonicalError::Json(e)
+    }
+}
+
+/// The deepest arrays and maps may be nested when decoding, as for JSON
+pub const MAX_DEPTH: usize = 128;
+
+const UINT: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const FLOAT64: u8 = 0xfb;
+
+/// A type exchanged in its canonical encoding
+pub trait CanonicalEncoding: Serialize + DeserializeOwned {
+    /// Tags [`CanonicalEncoding::canonical_hash`]es of this type, so that
+    /// they never collide with hashes of other types
+    const HASH_TAG: &'static [u8];
+    /// The canonical encoding of this value
+    fn to_canonical_bytes(&self) -> Result<Vec<u8>, CanonicalError> {
+        to_canonical_bytes(self)
+    }
+    /// Decode a value, which must be canonically encoded
+    fn from_canonical_bytes(b: &[u8]) -> Result<Self, CanonicalError> {
+        from_canonical_bytes(b)
+    }
+    /// A stable hash of this value's canonical encoding
+    fn canonical_hash(&self) -> Result<sha256::Hash, CanonicalError> {
+        canonical_hash(Self::HASH_TAG, self)
+    }
+}
+
+/// The canonical encoding of `v`
+pub fn to_canonical_bytes<T: Serialize + ?Sized>(v: &T) -> Result<Vec<u8>, CanonicalError> {
+    Ok(value_to_canonical_bytes(&serde_json::to_value(v)?))
+}
+
+/// Decode a `T`, which must be canonically encoded
+pub fn from_canonical_bytes<T: DeserializeOwned>(b: &[u8]) -> Result<T, CanonicalError> {
+    Ok(serde_json::from_value(canonical_bytes_to_value(b)?)?)
+}
+
+/// A hash of `v`'s canonical encoding, tagged (as in BIP-340) with `tag`
+pub fn canonical_hash<T: Serialize + ?Sized>(
+    tag: &[u8],
+    v: &T,
+) -> Result<sha256::Hash, CanonicalError> {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine.input(&to_canonical_bytes(v)?);
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+/// The canonical encoding of a JSON value
+pub fn value_to_canonical_bytes(v: &Value) -> Vec<u8> {
+    let mut out = vec![];
+    encode(v, &mut out);
+    out
+}
+
+/// Decode a JSON value, which must be canonically encoded
+pub fn canonical_bytes_to_value(b: &[u8]) -> Result<Value, CanonicalError> {
+    let mut d = Decoder { b, pos: 0 };
+    let v = d.value(0)?;
+    match b.len() - d.pos {
+        0 => Ok(v),
+        n => Err(CanonicalError::TrailingBytes(n)),
+    }
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let m = major << 5;
+    if n < 24 {
+        out.push(m | n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.push(m | 24);
+        out.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+        out.push(m | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(m | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(m | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// `f` as an integer, if it is one which is encoded as an integer
+fn integral(f: f64) -> Option<Result<u64, i64>> {
+    if f.fract() != 0.0 {
+        None
+    } else if (0.0..18_446_744_073_709_551_616.0).contains(&f) {
+        Some(Ok(f as u64))
+    } else if (-9_223_372_036_854_775_808.0..0.0).contains(&f) {
+        Some(Err(f as i64))
+    } else {
+        None
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, n: Result<u64, i64>) {
+    match n {
+        Ok(u) => write_head(out, UINT, u),
+        // n < 0, so -1 - n can't overflow
+        Err(i) => write_head(out, NEGATIVE, (-1 - i) as u64),
+    }
+}
+
+fn encode(v: &Value, out: &mut Vec<u8>) {
+    match v {
+        Value::Null => out.push(NULL),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                write_int(out, Ok(u))
+            } else if let Some(i) = n.as_i64() {
+                write_int(out, Err(i))
+            } else {
+                let f = n.as_f64().expect("Numbers are u64, i64 or f64");
+                match integral(f) {
+                    Some(n) => write_int(out, n),
+                    None => {
+                        out.push(FLOAT64);
+                        out.extend_from_slice(&f.to_bits().to_be_bytes());
+                    }
+                }
+            }
+        }
+        Value::String(s) => {
+            write_head(out, TEXT, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(a) => {
+            write_head(out, ARRAY, a.len() as u64);
+            a.iter().for_each(|v| encode(v, out));
+        }
+        Value::Object(m) => {
+            let mut entries: Vec<(Vec<u8>, &Value)> = m
+                .iter()
+                .map(|(k, v)| {
+                    let mut key = vec![];
+                    encode(&Value::String(k.clone()), &mut key);
+                    (key, v)
+                })
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            write_head(out, MAP, entries.len() as u64);
+            for (k, v) in entries {
+                out.extend_from_slice(&k);
+                encode(v, out);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    b: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CanonicalError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|e| *e <= self.b.len())
+            .ok_or(CanonicalError::Truncated)?;
+        let s = &self.b[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, CanonicalError> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// the major type and argument of the next item, checking the argument
+    /// is in its shortest form
+    fn head(&mut self) -> Result<(u8, u64), CanonicalError> {
+        let initial = self.take(1)?[0];
+        let (n, min) = match initial & 31 {
+            i @ 0..=23 => (i as u64, 0),
+            24 => (self.uint(1)?, 24),
+            25 => (self.uint(2)?, 1 << 8),
+            26 => (self.uint(4)?, 1 << 16),
+            27 => (self.uint(8)?, 1 << 32),
+            _ => return Err(CanonicalError::Unsupported(initial)),
+        };
+        if n < min {
+            return Err(CanonicalError::NotCanonical("integer not in shortest form"));
+        }
+        Ok((initial >> 5, n))
+    }
+
+    fn text(&mut self, len: u64) -> Result<String, CanonicalError> {
+        let len = usize::try_from(len).map_err(|_| CanonicalError::Truncated)?;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| CanonicalError::InvalidUtf8)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, CanonicalError> {
+        if depth > MAX_DEPTH {
+            return Err(CanonicalError::TooDeep);
+        }
+        let initial = *self.b.get(self.pos).ok_or(CanonicalError::Truncated)?;
+        match initial {
+            FALSE | TRUE | NULL => {
+                self.pos += 1;
+                return Ok(match initial {
+                    FALSE => Value::Bool(false),
+                    TRUE => Value::Bool(true),
+                    _ => Value::Null,
+                });
+            }
+            FLOAT64 => {
+                self.pos += 1;
+                let f = f64::from_bits(self.uint(8)?);
+                if integral(f).is_some() {
+                    return Err(CanonicalError::NotCanonical("integral float"));
+                }
+                return Number::from_f64(f)
+                    .map(Value::Number)
+                    .ok_or(CanonicalError::NotCanonical("non-finite float"));
+            }
+            _ => {}
+        }
+        let (major, n) = self.head()?;
+        match major {
+            UINT => Ok(Value::Number(n.into())),
+            NEGATIVE if n <= i64::MAX as u64 => Ok(Value::Number((-1 - n as i64).into())),
+            TEXT => Ok(Value::String(self.text(n)?)),
+            ARRAY => {
+                // every item takes at least a byte, so don't trust larger
+                // lengths for the allocation
+                let mut a = Vec::with_capacity((n as usize).min(self.b.len() - self.pos));
+                for _ in 0..n {
+                    a.push(self.value(depth + 1)?);
+                }
+                Ok(Value::Array(a))
+            }
+            MAP => {
+                let mut m = Map::new();
+                let b = self.b;
+                let mut last: Option<&[u8]> = None;
+                for _ in 0..n {
+                    let start = self.pos;
+                    let (major, len) = self.head()?;
+                    if major != TEXT {
+                        return Err(CanonicalError::Unsupported(b[start]));
+                    }
+                    let k = self.text(len)?;
+                    let key = &b[start..self.pos];
+                    if last.map_or(false, |l| l >= key) {
+                        return Err(CanonicalError::NotCanonical("map keys not sorted"));
+                    }
+                    last = Some(key);
+                    let v = self.value(depth + 1)?;
+                    m.insert(k, v);
+                }
+                Ok(Value::Object(m))
+            }
+            _ => Err(CanonicalError::Unsupported(initial)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Rng;
+    use serde_json::json;
+
+    fn value(rng: &mut Rng, depth: usize) -> Value {
+        match rng.below(if depth == 0 { 6 } else { 8 }) {
+            0 => Value::Null,
+            1 => Value::Bool(rng.below(2) == 1),
+            2 => {
+                let bits = rng.below(64);
+                json!(rng.next() >> bits)
+            }
+            3 => json!(-((rng.next() >> (1 + rng.below(63))) as i64) - 1),
+            4 => json!((rng.next() >> 11) as f64 / [1.0, 3.0, 1e9][rng.below(3)]),
+            5 => Value::String((0..rng.below(30)).map(|i| ['a', 'é', '0'][i % 3]).collect()),
+            6 => Value::Array((0..rng.below(5)).map(|_| value(rng, depth - 1)).collect()),
+            _ => Value::Object(
+                (0..rng.below(5))
+                    .map(|_| (format!("k{}", rng.below(300)), value(rng, depth - 1)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// equal, but for integral floats being decoded as integers
+    fn same(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Number(x), Value::Number(y)) if x.is_f64() || y.is_f64() => {
+                x.as_f64() == y.as_f64()
+            }
+            (Value::Array(x), Value::Array(y)) => {
+                x.len() == y.len() && x.iter().zip(y).all(|(x, y)| same(x, y))
+            }
+            (Value::Object(x), Value::Object(y)) => {
+                x.len() == y.len()
+                    && x.iter()
+                        .all(|(k, v)| y.get(k).map_or(false, |w| same(v, w)))
+            }
+            (x, y) => x == y,
+        }
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(value_to_canonical_bytes(&json!(0)), vec![0x00]);
+        assert_eq!(
+            value_to_canonical_bytes(&json!(500)),
+            vec![0x19, 0x01, 0xf4]
+        );
+        assert_eq!(value_to_canonical_bytes(&json!(-1)), vec![0x20]);
+        assert_eq!(value_to_canonical_bytes(&json!(2.0)), vec![0x02]);
+        assert_eq!(
+            value_to_canonical_bytes(&json!(1.5)),
+            vec![0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]
+        );
+        // "b" sorts before "aa", as it is shorter
+        assert_eq!(
+            value_to_canonical_bytes(&json!({"aa": null, "b": [true]})),
+            vec![0xa2, 0x61, b'b', 0x81, 0xf5, 0x62, b'a', b'a', 0xf6]
+        );
+    }
+
+    #[test]
+    fn rejects_non_canonical() {
+        for b in [
+            &[0x18, 0x05][..],                               // 5, not in shortest form
+            &[0xfb, 0x40, 0, 0, 0, 0, 0, 0, 0][..],          // 2.0
+            &[0xa2, 0x61, b'b', 0xf6, 0x61, b'a', 0xf6][..], // keys out of order
+            &[0xa2, 0x61, b'a', 0xf6, 0x61, b'a', 0xf6][..], // duplicate keys
+            &[0x9f, 0xff][..],                               // indefinite length
+            &[0xc0, 0x00][..],                               // a tag
+            &[0x42, 0x00, 0x01][..],                         // a byte string
+            &[0x82, 0x00][..],                               // truncated
+            &[0x00, 0x00][..],                               // trailing bytes
+        ] {
+            assert!(canonical_bytes_to_value(b).is_err(), "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn property_round_trips() {
+        let mut rng = Rng(0x5eed_cb02_0bad_f00d);
+        for _ in 0..1000 {
+            let v = value(&mut rng, 4);
+            let b = value_to_canonical_bytes(&v);
+            let decoded = canonical_bytes_to_value(&b).unwrap();
+            assert_eq!(value_to_canonical_bytes(&decoded), b);
+            // every JSON text of the value encodes the same
+            let reparsed: Value = serde_json::from_str(&v.to_string()).unwrap();
+            assert_eq!(value_to_canonical_bytes(&reparsed), b);
+            assert!(same(&v, &decoded), "{} decoded as {}", v, decoded);
+        }
+    }
+
+    #[test]
+    fn create_args_round_trip() {
+        use crate::effects::MapEffectDB;
+        use crate::plugin_args::{ContextualArguments, CreateArgs};
+        use bitcoin::util::amount::Amount;
+        let args = CreateArgs {
+            arguments: json!({"b": [1, 2.5], "a": "x"}),
+            context: ContextualArguments {
+                network: bitcoin::Network::Signet,
+                amount: Amount::from_sat(10_000_001),
+                effects: MapEffectDB::default(),
+            },
+        };
+        let b = args.to_canonical_bytes().unwrap();
+        let decoded = CreateArgs::<Value>::from_canonical_bytes(&b).unwrap();
+        assert_eq!(decoded.context.amount, args.context.amount);
+        assert_eq!(decoded.arguments, args.arguments);
+        assert_eq!(
+            decoded.canonical_hash().unwrap(),
+            args.canonical_hash().unwrap()
+        );
+        // the same arguments, from differently ordered JSON
+        let reordered: CreateArgs<Value> = serde_json::from_str(
+            r#"{"context": {"amount": 0.10000001, "network": "Signet"},
+                "arguments": {"a": "x", "b": [1.0, 2.5]}}"#,
+        )
+        .unwrap();
+        assert_eq!(reordered.to_canonical_bytes().unwrap(), b);
+    }
+}
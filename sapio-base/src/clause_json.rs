@@ -0,0 +1,314 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A stable, documented JSON encoding for [`Clause`].
+//!
+//! [`Clause`]'s own serde representation is whatever miniscript provides, so
+//! it may change between versions. [`ClauseJson`] is the format external
+//! tools should produce and consume instead. Fields holding a [`Clause`] can
+//! opt into it with `#[serde(with = "sapio_base::clause_json")]`.
+//!
+//! Every clause is an object tagged by `"type"`:
+//! - keys are hex encoded x-only public keys;
+//! - hashes are hex encoded, in the same byte order as miniscript policy
+//!   strings;
+//! - `after` is a raw nLockTime value, `older` a raw nSequence value.
+use super::Clause;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+/// # Clause
+/// A spending condition.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClauseJson {
+    /// # Unsatisfiable
+    /// can never be satisfied
+    Unsatisfiable,
+    /// # Trivial
+    /// is always satisfied
+    Trivial,
+    /// # Key
+    /// a signature from the key
+    Key {
+        /// hex encoded x-only public key
+        key: String,
+    },
+    /// # After
+    /// an absolute timelock (nLockTime)
+    After {
+        /// the nLockTime value that must be reached
+        locktime: u32,
+    },
+    /// # Older
+    /// a relative timelock (nSequence)
+    Older {
+        /// the nSequence value that must be reached
+        sequence: u32,
+    },
+    /// # SHA256 Preimage
+    Sha256 {
+        /// hex encoded hash
+        hash: String,
+    },
+    /// # Double SHA256 Preimage
+    Hash256 {
+        /// hex encoded hash
+        hash: String,
+    },
+    /// # RIPEMD160 Preimage
+    Ripemd160 {
+        /// hex encoded hash
+        hash: String,
+    },
+    /// # HASH160 Preimage
+    Hash160 {
+        /// hex encoded hash
+        hash: String,
+    },
+    /// # Transaction Template
+    /// the spending transaction must match a BIP-119 template hash
+    TxTemplate {
+        /// hex encoded template hash
+        hash: String,
+    },
+    /// # And
+    /// every clause must be satisfied
+    And {
+        /// the clauses
+        clauses: Vec<ClauseJson>,
+    },
+    /// # Or
+    /// any one branch must be satisfied
+    Or {
+        /// the branches, with relative likelihood weights
+        branches: Vec<WeightedClauseJson>,
+    },
+    /// # Threshold
+    /// at least `threshold` of the clauses must be satisfied
+    Threshold {
+        /// how many must be satisfied
+        threshold: usize,
+        /// the clauses
+        clauses: Vec<ClauseJson>,
+    },
+}
+
+/// # Weighted Clause
+/// A branch of an `or`, weighted by how likely it is to be used.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct WeightedClauseJson {
+    /// relative likelihood this branch is used
+    pub weight: usize,
+    /// the branch's clause
+    pub clause: ClauseJson,
+}
+
+/// Errors converting a [`ClauseJson`] into a [`Clause`]
+#[derive(Debug)]
+pub enum ClauseJsonError {
+    /// A key could not be parsed
+    BadKey(String),
+    /// A hash could not be parsed
+    BadHash(String),
+}
+impl std::fmt::Display for ClauseJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for ClauseJsonError {}
+
+impl From<&Clause> for ClauseJson {
+    fn from(c: &Clause) -> Self {
+        match c {
+            Clause::Unsatisfiable => ClauseJson::Unsatisfiable,
+            Clause::Trivial => ClauseJson::Trivial,
+            Clause::Key(k) => ClauseJson::Key { key: k.to_string() },
+            Clause::After(t) => ClauseJson::After { locktime: *t },
+            Clause::Older(t) => ClauseJson::Older { sequence: *t },
+            Clause::Sha256(h) => ClauseJson::Sha256 {
+                hash: h.to_string(),
+            },
+            Clause::Hash256(h) => ClauseJson::Hash256 {
+                hash: h.to_string(),
+            },
+            Clause::Ripemd160(h) => ClauseJson::Ripemd160 {
+                hash: h.to_string(),
+            },
+            Clause::Hash160(h) => ClauseJson::Hash160 {
+                hash: h.to_string(),
+            },
+            Clause::TxTemplate(h) => ClauseJson::TxTemplate {
+                hash: h.to_string(),
+            },
+            Clause::And(cs) => ClauseJson::And {
+                clauses: cs.iter().map(ClauseJson::from).collect(),
+            },
+            Clause::Or(cs) => ClauseJson::Or {
+                branches: cs
+                    .iter()
+                    .map(|(weight, c)| WeightedClauseJson {
+                        weight: *weight,
+                        clause: c.into(),
+                    })
+                    .collect(),
+            },
+            Clause::Threshold(threshold, cs) => ClauseJson::Threshold {
+                threshold: *threshold,
+                clauses: cs.iter().map(ClauseJson::from).collect(),
+            },
+        }
+    }
+}
+
+fn parse_hash<H: FromStr>(hash: &str) -> Result<H, ClauseJsonError> {
+    H::from_str(hash).map_err(|_| ClauseJsonError::BadHash(hash.into()))
+}
+
+impl TryFrom<&ClauseJson> for Clause {
+    type Error = ClauseJsonError;
+    fn try_from(c: &ClauseJson) -> Result<Self, Self::Error> {
+        Ok(match c {
+            ClauseJson::Unsatisfiable => Clause::Unsatisfiable,
+            ClauseJson::Trivial => Clause::Trivial,
+            ClauseJson::Key { key } => Clause::Key(
+                XOnlyPublicKey::from_str(key).map_err(|_| ClauseJsonError::BadKey(key.clone()))?,
+            ),
+            ClauseJson::After { locktime } => Clause::After(*locktime),
+            ClauseJson::Older { sequence } => Clause::Older(*sequence),
+            ClauseJson::Sha256 { hash } => Clause::Sha256(parse_hash::<sha256::Hash>(hash)?),
+            ClauseJson::Hash256 { hash } => Clause::Hash256(parse_hash::<sha256d::Hash>(hash)?),
+            ClauseJson::Ripemd160 { hash } => {
+                Clause::Ripemd160(parse_hash::<ripemd160::Hash>(hash)?)
+            }
+            ClauseJson::Hash160 { hash } => Clause::Hash160(parse_hash::<hash160::Hash>(hash)?),
+            ClauseJson::TxTemplate { hash } => {
+                Clause::TxTemplate(parse_hash::<sha256::Hash>(hash)?)
+            }
+            ClauseJson::And { clauses } => Clause::And(
+                clauses
+                    .iter()
+                    .map(Clause::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            ClauseJson::Or { branches } => Clause::Or(
+                branches
+                    .iter()
+                    .map(|b| Ok((b.weight, Clause::try_from(&b.clause)?)))
+                    .collect::<Result<_, ClauseJsonError>>()?,
+            ),
+            ClauseJson::Threshold { threshold, clauses } => Clause::Threshold(
+                *threshold,
+                clauses
+                    .iter()
+                    .map(Clause::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+impl TryFrom<ClauseJson> for Clause {
+    type Error = ClauseJsonError;
+    fn try_from(c: ClauseJson) -> Result<Self, Self::Error> {
+        Clause::try_from(&c)
+    }
+}
+
+/// Serialize a [`Clause`] as a [`ClauseJson`], for use with `#[serde(with)]`
+pub fn serialize<S: Serializer>(c: &Clause, s: S) -> Result<S::Ok, S::Error> {
+    ClauseJson::from(c).serialize(s)
+}
+
+/// Deserialize a [`Clause`] from a [`ClauseJson`], for use with `#[serde(with)]`
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Clause, D::Error> {
+    Clause::try_from(ClauseJson::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn key() -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_stable_encoding() {
+        let c = Clause::Or(vec![
+            (1, Clause::And(vec![Clause::Key(key()), Clause::After(100)])),
+            (
+                3,
+                Clause::Threshold(
+                    1,
+                    vec![Clause::Older(6), Clause::Sha256(sha256::Hash::hash(&[]))],
+                ),
+            ),
+        ]);
+        let expected = serde_json::json!({
+            "type": "or",
+            "branches": [
+                {
+                    "weight": 1,
+                    "clause": {
+                        "type": "and",
+                        "clauses": [
+                            {"type": "key", "key": "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"},
+                            {"type": "after", "locktime": 100}
+                        ]
+                    }
+                },
+                {
+                    "weight": 3,
+                    "clause": {
+                        "type": "threshold",
+                        "threshold": 1,
+                        "clauses": [
+                            {"type": "older", "sequence": 6},
+                            {"type": "sha256", "hash": "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"}
+                        ]
+                    }
+                }
+            ]
+        });
+        let json = serde_json::to_value(ClauseJson::from(&c)).unwrap();
+        assert_eq!(json, expected);
+        let back: ClauseJson = serde_json::from_value(json).unwrap();
+        assert_eq!(Clause::try_from(back).unwrap(), c);
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schemars::schema_for!(ClauseJson)).unwrap();
+        let s = schema.to_string();
+        for tag in [
+            "unsatisfiable",
+            "trivial",
+            "key",
+            "after",
+            "older",
+            "sha256",
+            "hash256",
+            "ripemd160",
+            "hash160",
+            "tx_template",
+            "and",
+            "or",
+            "threshold",
+        ]
+        .iter()
+        {
+            assert!(s.contains(&format!("\"{}\"", tag)), "{}", tag);
+        }
+    }
+}
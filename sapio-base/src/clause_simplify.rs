@@ -0,0 +1,317 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Rewrites [`Clause`]s built up compositionally into smaller equivalent ones
+//! before they are compiled.
+//!
+//! [`simplify`] applies, bottom up:
+//! - constant folding: `Trivial` and `Unsatisfiable` are absorbed into (or
+//!   absorb) their parents;
+//! - flattening: an `And` in an `And`, or an `Or` in an `Or`, is merged into
+//!   its parent, keeping the relative weights of every branch;
+//! - threshold merging: a `Threshold` of all of its children becomes an
+//!   `And` and one of only one becomes an `Or`, to be flattened in turn;
+//! - deduplication: repeated conjuncts are dropped, repeated branches are
+//!   merged (summing their weights), and a branch requiring everything some
+//!   other branch does is absorbed by it;
+//! - timelock merging: of the timelocks of one kind (absolute or relative,
+//!   height or time) required together only the longest is kept.
+use super::satisfaction::{LOCKTIME_THRESHOLD, SEQUENCE_MASK, SEQUENCE_TYPE_FLAG};
+use super::Clause;
+
+/// Simplify `c` into an equivalent, usually smaller, clause. The result is a
+/// fixed point: simplifying it again does not change it.
+pub fn simplify(c: &Clause) -> Clause {
+    match c {
+        Clause::And(cs) => simplify_and(cs.iter().map(simplify).collect()),
+        Clause::Or(cs) => simplify_or(cs.iter().map(|(w, c)| (*w, simplify(c))).collect()),
+        Clause::Threshold(k, cs) => simplify_threshold(*k, cs.iter().map(simplify).collect()),
+        c => c.clone(),
+    }
+}
+
+/// the kind of a timelock, if timelocks of its kind may be merged
+fn timelock_kind(c: &Clause) -> Option<(bool, bool)> {
+    match c {
+        Clause::After(n) => Some((true, *n >= LOCKTIME_THRESHOLD)),
+        // only plain relative locks, with no other (e.g., disable) bits set
+        Clause::Older(n) if *n & !(SEQUENCE_TYPE_FLAG | SEQUENCE_MASK) == 0 => {
+            Some((false, *n & SEQUENCE_TYPE_FLAG != 0))
+        }
+        _ => None,
+    }
+}
+
+/// add `c` to the conjuncts `out`, unless it is already required
+fn push_conjunct(out: &mut Vec<Clause>, c: Clause) {
+    if let Some(kind) = timelock_kind(&c) {
+        if let Some(prev) = out.iter_mut().find(|p| timelock_kind(p) == Some(kind)) {
+            if *prev < c {
+                *prev = c;
+            }
+            return;
+        }
+    }
+    if !out.contains(&c) {
+        out.push(c)
+    }
+}
+
+/// `cs` must all be simplified
+fn simplify_and(cs: Vec<Clause>) -> Clause {
+    let mut out: Vec<Clause> = vec![];
+    for c in cs {
+        match c {
+            Clause::Trivial => {}
+            Clause::Unsatisfiable => return Clause::Unsatisfiable,
+            Clause::And(inner) => inner.into_iter().for_each(|c| push_conjunct(&mut out, c)),
+            c => push_conjunct(&mut out, c),
+        }
+    }
+    match out.len() {
+        0 => Clause::Trivial,
+        1 => out.pop().expect("Has one element"),
+        _ => Clause::And(out),
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// the total weight of `cs`, treating all zero weights as all equal
+fn total_weight(cs: &mut [(usize, Clause)]) -> usize {
+    let total = cs.iter().fold(0usize, |t, (w, _)| t.saturating_add(*w));
+    if total == 0 {
+        cs.iter_mut().for_each(|(w, _)| *w = 1);
+        cs.len()
+    } else {
+        total
+    }
+}
+
+/// Merge the branches of nested `Or`s into `cs`, scaling weights to keep
+/// every branch's share of the total. None if the weights would overflow.
+fn flatten_or(cs: &mut Vec<(usize, Clause)>) -> Option<Vec<(usize, Clause)>> {
+    let mut totals = vec![];
+    for (_, c) in cs.iter_mut() {
+        if let Clause::Or(inner) = c {
+            totals.push(total_weight(inner));
+        }
+    }
+    let scale = totals.iter().try_fold(1usize, |s, t| s.checked_mul(*t))?;
+    let mut totals = totals.into_iter();
+    let mut out = vec![];
+    for (w, c) in cs.iter() {
+        match c {
+            Clause::Or(inner) => {
+                let share = scale / totals.next().expect("One per nested Or");
+                for (w2, c2) in inner.iter() {
+                    out.push((w.checked_mul(*w2)?.checked_mul(share)?, c2.clone()));
+                }
+            }
+            c => out.push((w.checked_mul(scale)?, c.clone())),
+        }
+    }
+    Some(out)
+}
+
+/// the clauses which must all hold for `c` to hold
+fn conjuncts(c: &Clause) -> &[Clause] {
+    match c {
+        Clause::And(cs) => &cs[..],
+        c => std::slice::from_ref(c),
+    }
+}
+
+/// `cs` must all be simplified
+fn simplify_or(cs: Vec<(usize, Clause)>) -> Clause {
+    let mut cs: Vec<(usize, Clause)> = cs
+        .into_iter()
+        .filter(|(_, c)| *c != Clause::Unsatisfiable)
+        .collect();
+    if cs.iter().any(|(_, c)| *c == Clause::Trivial) {
+        return Clause::Trivial;
+    }
+    if let Some(flat) = flatten_or(&mut cs) {
+        cs = flat;
+    }
+    let mut out: Vec<(usize, Clause)> = vec![];
+    for (w, c) in cs {
+        match out.iter_mut().find(|(_, p)| *p == c) {
+            Some(prev) => prev.0 = prev.0.saturating_add(w),
+            None => out.push((w, c)),
+        }
+    }
+    // a branch needing everything another branch needs (and more) is never
+    // needed, so it is absorbed into the other
+    let mut i = 0;
+    while i < out.len() {
+        let needs = conjuncts(&out[i].1);
+        let by = (0..out.len())
+            .find(|j| *j != i && conjuncts(&out[*j].1).iter().all(|c| needs.contains(c)));
+        match by {
+            Some(j) => {
+                let (w, _) = out.remove(i);
+                let j = if j > i { j - 1 } else { j };
+                out[j].0 = out[j].0.saturating_add(w);
+            }
+            None => i += 1,
+        }
+    }
+    let g = out.iter().fold(0, |g, (w, _)| gcd(g, *w));
+    if g > 1 {
+        out.iter_mut().for_each(|(w, _)| *w /= g);
+    }
+    match out.len() {
+        0 => Clause::Unsatisfiable,
+        1 => out.pop().expect("Has one element").1,
+        _ => Clause::Or(out),
+    }
+}
+
+/// `cs` must all be simplified
+fn simplify_threshold(mut k: usize, cs: Vec<Clause>) -> Clause {
+    let mut out = vec![];
+    for c in cs {
+        match c {
+            Clause::Trivial => k = k.saturating_sub(1),
+            Clause::Unsatisfiable => {}
+            c => out.push(c),
+        }
+    }
+    if k == 0 {
+        Clause::Trivial
+    } else if k > out.len() {
+        Clause::Unsatisfiable
+    } else if k == out.len() {
+        simplify_and(out)
+    } else if k == 1 {
+        simplify_or(out.into_iter().map(|c| (1, c)).collect())
+    } else {
+        Clause::Threshold(k, out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::satisfaction::SpendingState;
+    use crate::test_util::Rng;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    use bitcoin::XOnlyPublicKey;
+
+    fn keys() -> Vec<XOnlyPublicKey> {
+        let secp = Secp256k1::new();
+        (1..=3u8)
+            .map(|b| {
+                XOnlyPublicKey::from_keypair(&KeyPair::from_seckey_slice(&secp, &[b; 32]).unwrap())
+            })
+            .collect()
+    }
+
+    fn clause(rng: &mut Rng, keys: &[XOnlyPublicKey], depth: usize) -> Clause {
+        match if depth == 0 {
+            rng.below(5)
+        } else {
+            rng.below(9)
+        } {
+            0 => Clause::Key(keys[rng.below(3)]),
+            1 => Clause::After([10, 20][rng.below(2)]),
+            2 => Clause::Older([5, 15][rng.below(2)]),
+            3 => Clause::Trivial,
+            4 => Clause::Unsatisfiable,
+            5 | 6 => {
+                let n = 1 + rng.below(3);
+                Clause::And((0..n).map(|_| clause(rng, keys, depth - 1)).collect())
+            }
+            7 => {
+                let n = 1 + rng.below(3);
+                Clause::Or(
+                    (0..n)
+                        .map(|_| (rng.below(4), clause(rng, keys, depth - 1)))
+                        .collect(),
+                )
+            }
+            _ => {
+                let n = 1 + rng.below(4);
+                Clause::Threshold(
+                    rng.below(n + 1),
+                    (0..n).map(|_| clause(rng, keys, depth - 1)).collect(),
+                )
+            }
+        }
+    }
+
+    /// every combination of keys held and chain heights either side of the
+    /// generated timelocks, for an output confirmed at height 1
+    fn states(keys: &[XOnlyPublicKey]) -> Vec<SpendingState> {
+        let mut res = vec![];
+        for held in 0..(1 << keys.len()) {
+            for height in [0, 4, 5, 9, 10, 15, 19, 20] {
+                let mut s = SpendingState::default()
+                    .with_height(height)
+                    .with_confirmation(1, 0);
+                for (i, k) in keys.iter().enumerate() {
+                    if held & (1 << i) != 0 {
+                        s = s.with_key(*k);
+                    }
+                }
+                res.push(s);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn simplifies_redundancy() {
+        let k = keys();
+        let (a, b) = (Clause::Key(k[0]), Clause::Key(k[1]));
+        let c = Clause::And(vec![
+            Clause::Trivial,
+            Clause::And(vec![a.clone(), Clause::After(10)]),
+            Clause::Threshold(1, vec![a.clone(), Clause::Unsatisfiable]),
+            Clause::After(20),
+        ]);
+        assert_eq!(
+            simplify(&c),
+            Clause::And(vec![a.clone(), Clause::After(20)])
+        );
+        let c = Clause::Or(vec![
+            (1, a.clone()),
+            (3, Clause::Or(vec![(1, b.clone()), (1, a.clone())])),
+            (1, Clause::And(vec![b.clone(), Clause::Older(5)])),
+        ]);
+        // b absorbs b and older(5); a is 1/5 + 3/10 of the weight, b 3/10 + 1/5
+        assert_eq!(simplify(&c), Clause::Or(vec![(1, a), (1, b)]));
+    }
+
+    #[test]
+    fn property_simplify_is_equivalent() {
+        let keys = keys();
+        let states = states(&keys);
+        let mut rng = Rng(0x51a9_1f1e_d00d_cafe);
+        for _ in 0..500 {
+            let c = clause(&mut rng, &keys, 3);
+            let s = simplify(&c);
+            assert_eq!(s, simplify(&s), "not a fixed point: {:?}", c);
+            for state in states.iter() {
+                assert_eq!(
+                    state.evaluate(&c).is_satisfied(),
+                    state.evaluate(&s).is_satisfied(),
+                    "{:?} simplified to {:?} in {:?}",
+                    c,
+                    s,
+                    state
+                );
+            }
+        }
+    }
+}
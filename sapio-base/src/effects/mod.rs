@@ -6,17 +6,25 @@
 
 //! general non-parameter compilation state required by all contracts
 
+use crate::canonical::CanonicalEncoding;
 use crate::reverse_path::ReversePath;
 use crate::serialization_helpers::SArc;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use std::sync::Arc;
 pub mod path_fragment;
 pub use path_fragment::*;
 pub mod reverse_path;
 pub use reverse_path::*;
+pub mod pattern;
+pub use pattern::*;
+pub mod signed;
+pub use signed::*;
+pub mod versioned;
+pub use versioned::*;
 
 pub type EffectPath = ReversePath<PathFragment>;
 
@@ -25,6 +33,91 @@ pub type EffectPath = ReversePath<PathFragment>;
 pub enum EffectDBError {
     /// Error was from Deserialization
     SerializationError(serde_json::Error),
+    /// The effect is past its valid-until height or time
+    Expired(SArc<EffectPath>, SArc<String>),
+    /// The effect requires a newer version than is being compiled
+    VersionTooOld(SArc<EffectPath>, SArc<String>),
+    /// The effect has conditions but the `EffectEnvironment` does not have the
+    /// information required to check them
+    UnverifiableConditions(SArc<EffectPath>, SArc<String>),
+    /// A signed effect's signature did not verify
+    BadSignature(SArc<EffectPath>, SArc<String>),
+    /// The effect's arguments do not match the schema of the continuation it
+    /// is applied to, with each violation found
+    SchemaViolation(SArc<EffectPath>, SArc<String>, Vec<String>),
+    /// Two effect sets being merged set the same effect differently
+    MergeConflict(SArc<EffectPath>, SArc<String>),
+}
+
+/// # Effect Conditions
+/// Optional restrictions on when an effect may be applied during compilation.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
+pub struct EffectConditions {
+    /// # Valid Until Height
+    /// The effect may not be applied at or after this block height
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub valid_until_height: Option<u32>,
+    /// # Valid Until Time
+    /// The effect may not be applied at or after this unix timestamp
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub valid_until_time: Option<u32>,
+    /// # Minimum Version
+    /// The effect may only be applied when compiling at least this version
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub min_version: Option<u64>,
+}
+
+/// # Effect Environment
+/// The chain state and version that effect conditions are checked against.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
+pub struct EffectEnvironment {
+    /// # Current Block Height
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub height: Option<u32>,
+    /// # Current Time (unix timestamp)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub time: Option<u32>,
+    /// # Version being compiled
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<u64>,
+}
+
+impl EffectEnvironment {
+    /// helps determine if an EffectEnvironment has anything worth serializing or not
+    pub fn is_empty(&self) -> bool {
+        *self == Default::default()
+    }
+}
+
+impl EffectConditions {
+    /// Checks that the conditions are met in the environment `env`.
+    ///
+    /// If a condition is set but the corresponding environment field is not,
+    /// the effect can't be verified and is rejected.
+    pub fn check(
+        &self,
+        env: &EffectEnvironment,
+        path: &SArc<EffectPath>,
+        name: &SArc<String>,
+    ) -> Result<(), EffectDBError> {
+        let unverifiable = || EffectDBError::UnverifiableConditions(path.clone(), name.clone());
+        if let Some(until) = self.valid_until_height {
+            if env.height.ok_or_else(unverifiable)? >= until {
+                return Err(EffectDBError::Expired(path.clone(), name.clone()));
+            }
+        }
+        if let Some(until) = self.valid_until_time {
+            if env.time.ok_or_else(unverifiable)? >= until {
+                return Err(EffectDBError::Expired(path.clone(), name.clone()));
+            }
+        }
+        if let Some(min) = self.min_version {
+            if env.version.ok_or_else(unverifiable)? < min {
+                return Err(EffectDBError::VersionTooOld(path.clone(), name.clone()));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl From<serde_json::Error> for EffectDBError {
@@ -39,6 +132,15 @@ pub trait EffectDB {
         &'a self,
         at: &Arc<EffectPath>,
     ) -> Box<dyn Iterator<Item = (&'a Arc<String>, &'a serde_json::Value)> + 'a>;
+    /// check that the effect `name` at path `at` may be applied. Should be
+    /// called before applying any value returned by `get_value`.
+    fn check_applicable(
+        &self,
+        _at: &Arc<EffectPath>,
+        _name: &Arc<String>,
+    ) -> Result<(), EffectDBError> {
+        Ok(())
+    }
 }
 /// #  Effects
 /// Map of all effects to process during compilation.  Each Key represents a
@@ -49,13 +151,135 @@ pub struct MapEffectDB {
     /// List of effects to include while compiling.
     #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     effects: HashMap<SArc<EffectPath>, HashMap<SArc<String>, serde_json::Value>>,
+    /// # Conditions on effects
+    /// Restrictions on when an effect in the set of all effects may apply.
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    conditions: HashMap<SArc<EffectPath>, HashMap<SArc<String>, EffectConditions>>,
+    /// # Environment
+    /// The state effect conditions are checked against.
+    #[serde(skip_serializing_if = "EffectEnvironment::is_empty", default)]
+    environment: EffectEnvironment,
+    /// # Effects by pattern
+    /// Effects applied at every path matching a pattern, unless an effect
+    /// of the same name is set at the exact path. Where several patterns
+    /// match, the most specific applies (the first listed, if tied).
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pattern_effects: Vec<PatternEffect>,
     #[serde(skip, default)]
     empty: HashMap<SArc<String>, serde_json::Value>,
 }
 impl MapEffectDB {
     pub fn skip_serializing(&self) -> bool {
         self.effects.is_empty()
+            && self.conditions.is_empty()
+            && self.environment.is_empty()
+            && self.pattern_effects.is_empty()
+    }
+    /// set the effect `name` at `at` to `value`, returning the value it
+    /// replaced
+    pub fn insert(
+        &mut self,
+        at: SArc<EffectPath>,
+        name: SArc<String>,
+        value: serde_json::Value,
+    ) -> Option<serde_json::Value> {
+        self.effects.entry(at).or_default().insert(name, value)
+    }
+    /// restrict when the effect `name` at `at` may apply, returning the
+    /// conditions it replaced
+    pub fn set_conditions(
+        &mut self,
+        at: SArc<EffectPath>,
+        name: SArc<String>,
+        conditions: EffectConditions,
+    ) -> Option<EffectConditions> {
+        self.conditions
+            .entry(at)
+            .or_default()
+            .insert(name, conditions)
+    }
+    /// remove every effect (and its conditions) at `at`, returning true if
+    /// there were any
+    pub fn remove_path(&mut self, at: &SArc<EffectPath>) -> bool {
+        self.conditions.remove(at);
+        self.effects.remove(at).is_some()
     }
+    /// move every effect (and its conditions) at `from` to `to`, replacing
+    /// any effect of the same name already there
+    pub fn move_path(&mut self, from: &SArc<EffectPath>, to: SArc<EffectPath>) {
+        if let Some(effects) = self.effects.remove(from) {
+            self.effects.entry(to.clone()).or_default().extend(effects);
+        }
+        if let Some(conditions) = self.conditions.remove(from) {
+            self.conditions.entry(to).or_default().extend(conditions);
+        }
+    }
+    /// add an effect applying at every path matching its pattern
+    pub fn insert_pattern(&mut self, effect: PatternEffect) {
+        self.pattern_effects.push(effect)
+    }
+    /// every effect set at an exact path matching `pattern`
+    pub fn query<'a>(
+        &'a self,
+        pattern: &'a EffectPathPattern,
+    ) -> impl Iterator<Item = (&'a Arc<EffectPath>, &'a Arc<String>, &'a serde_json::Value)> + 'a
+    {
+        self.effects
+            .iter()
+            .filter(move |(p, _)| pattern.matches(&p.0))
+            .flat_map(|(p, m)| m.iter().map(move |(k, v)| (&p.0, &k.0, v)))
+    }
+    /// set the environment that conditions on effects are checked against
+    pub fn set_environment(&mut self, environment: EffectEnvironment) {
+        self.environment = environment;
+    }
+    /// A digest of every effect (and its conditions) at or below `at`, plus
+    /// the environment. Two databases with the same digest compile the
+    /// subtree at `at` identically.
+    pub fn digest_under(&self, at: &EffectPath) -> sha256::Hash {
+        let prefix = at.to_string();
+        let under = |p: &SArc<EffectPath>| {
+            let p = p.0.to_string();
+            p == prefix || p.starts_with(&format!("{}/", prefix))
+        };
+        let effects: BTreeMap<String, BTreeMap<&String, &serde_json::Value>> = self
+            .effects
+            .iter()
+            .filter(|(p, _)| under(p))
+            .map(|(p, v)| {
+                (
+                    p.0.to_string(),
+                    v.iter().map(|(k, v)| (k.0.as_ref(), v)).collect(),
+                )
+            })
+            .collect();
+        let conditions: BTreeMap<String, BTreeMap<&String, &EffectConditions>> = self
+            .conditions
+            .iter()
+            .filter(|(p, _)| under(p))
+            .map(|(p, v)| {
+                (
+                    p.0.to_string(),
+                    v.iter().map(|(k, v)| (k.0.as_ref(), v)).collect(),
+                )
+            })
+            .collect();
+        let mut engine = sha256::Hash::engine();
+        for part in [
+            serde_json::to_vec(&effects),
+            serde_json::to_vec(&conditions),
+            serde_json::to_vec(&self.environment),
+            // patterns may match anywhere, so all are included
+            serde_json::to_vec(&self.pattern_effects),
+        ] {
+            engine.input(&part.unwrap_or_default());
+        }
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+impl CanonicalEncoding for MapEffectDB {
+    const HASH_TAG: &'static [u8] = b"sapio/effects";
 }
 
 impl EffectDB for MapEffectDB {
@@ -64,7 +288,37 @@ impl EffectDB for MapEffectDB {
         at: &Arc<EffectPath>,
     ) -> Box<dyn Iterator<Item = (&'a Arc<String>, &'a serde_json::Value)> + 'a> {
         let r: &HashMap<_, _> = self.effects.get(&SArc(at.clone())).unwrap_or(&self.empty);
-        Box::new(r.iter().map(|(a, b)| (&a.0, b)))
+        let mut by_pattern: HashMap<&SArc<String>, (Specificity, &serde_json::Value)> =
+            HashMap::new();
+        for e in self.pattern_effects.iter() {
+            if r.contains_key(&e.name) || !e.pattern.matches(at) {
+                continue;
+            }
+            let s = e.pattern.specificity();
+            match by_pattern.get(&e.name) {
+                Some((best, _)) if *best >= s => {}
+                _ => {
+                    by_pattern.insert(&e.name, (s, &e.value));
+                }
+            }
+        }
+        Box::new(
+            r.iter()
+                .map(|(a, b)| (&a.0, b))
+                .chain(by_pattern.into_iter().map(|(a, (_, b))| (&a.0, b))),
+        )
+    }
+    fn check_applicable(
+        &self,
+        at: &Arc<EffectPath>,
+        name: &Arc<String>,
+    ) -> Result<(), EffectDBError> {
+        let path = SArc(at.clone());
+        let name = SArc(name.clone());
+        match self.conditions.get(&path).and_then(|c| c.get(&name)) {
+            Some(conditions) => conditions.check(&self.environment, &path, &name),
+            None => Ok(()),
+        }
     }
 }
 
@@ -100,4 +354,65 @@ mod test {
             serde_json::from_str("\"hello/#100/@finish_fn\"").map_err(|_| ())
         );
     }
+    #[test]
+    fn test_pattern_precedence() {
+        let at = Arc::new(EffectPath::try_from("root/#1/@finish_or_fn/pay").unwrap());
+        let name = Arc::new(String::from("update"));
+        let pattern = |p: &str, v: u64| {
+            PatternEffect::new(p.try_into().unwrap(), name.clone(), serde_json::json!(v))
+        };
+        let value = |db: &MapEffectDB| {
+            let v: Vec<_> = db.get_value(&at).map(|(_, v)| v.clone()).collect();
+            assert!(v.len() <= 1);
+            v.into_iter().next()
+        };
+        let mut db = MapEffectDB::default();
+        db.insert_pattern(pattern("**", 1));
+        db.insert_pattern(pattern("root/**", 2));
+        db.insert_pattern(pattern("root/**", 3));
+        db.insert_pattern(pattern("**/other", 4));
+        // the first of the most specific
+        assert_eq!(value(&db), Some(serde_json::json!(2)));
+        db.insert_pattern(pattern("root/#*/**", 5));
+        assert_eq!(value(&db), Some(serde_json::json!(5)));
+        // an exact path beats any pattern
+        db.insert(SArc(at.clone()), SArc(name.clone()), serde_json::json!(6));
+        assert_eq!(value(&db), Some(serde_json::json!(6)));
+        let q = "root/**".try_into().unwrap();
+        assert_eq!(db.query(&q).count(), 1);
+        let q = "**/other".try_into().unwrap();
+        assert_eq!(db.query(&q).count(), 0);
+    }
+    #[test]
+    fn test_conditions() {
+        let path = SArc(Arc::new(EffectPath::try_from("hello").unwrap()));
+        let name = SArc(Arc::new("update".to_string()));
+        let conditions = EffectConditions {
+            valid_until_height: Some(100),
+            valid_until_time: None,
+            min_version: Some(2),
+        };
+        let mut env = EffectEnvironment {
+            height: Some(99),
+            time: None,
+            version: Some(2),
+        };
+        assert!(conditions.check(&env, &path, &name).is_ok());
+        env.height = Some(100);
+        assert!(matches!(
+            conditions.check(&env, &path, &name),
+            Err(EffectDBError::Expired(_, _))
+        ));
+        env.height = Some(99);
+        env.version = Some(1);
+        assert!(matches!(
+            conditions.check(&env, &path, &name),
+            Err(EffectDBError::VersionTooOld(_, _))
+        ));
+        env.version = None;
+        assert!(matches!(
+            conditions.check(&env, &path, &name),
+            Err(EffectDBError::UnverifiableConditions(_, _))
+        ));
+    }
 }
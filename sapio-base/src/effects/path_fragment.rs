@@ -5,6 +5,17 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Path  Fragments
+//!
+//! An EffectPath is written as its fragments from the root, separated by
+//! `/`, e.g. `@root/vault/@finish_or_fn/@suggested/#0`:
+//! - fragments used by the compiler are `@` followed by their name
+//! - `PathFragment::Branch(n)` is `#n`
+//! - `PathFragment::Named` is the name, with every byte of its UTF-8 encoding
+//!   other than ascii alphanumerics and `_` written as `%XX` (uppercase hex)
+//!
+//! Every path has exactly one such string, which `FromStr` (and
+//! `TryFrom<&str>`) parse back to the same path, and which is the path's
+//! serde form.
 use crate::reverse_path::ReversePath;
 use crate::serialization_helpers::SArc;
 use schemars::JsonSchema;
@@ -53,11 +64,78 @@ impl From<&PathFragment> for String {
             PathFragment::DefaultEffect => "@default_effect".into(),
             PathFragment::Effects => "@effects".into(),
             PathFragment::Branch(u) => format!("#{}", u),
-            PathFragment::Named(SArc(a)) => a.as_ref().clone(),
+            PathFragment::Named(SArc(a)) => escape_name(a),
         }
     }
 }
 
+impl std::fmt::Display for PathFragment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from(self))
+    }
+}
+
+impl FromStr for PathFragment {
+    type Err = ValidFragmentError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+/// Returns true if `c` may appear unescaped in the canonical encoding of a
+/// `PathFragment::Named`.
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Canonically encodes a name so that it can't be confused with a reserved
+/// fragment or the `/` separator. Every byte of the UTF-8 encoding that is not
+/// ascii alphanumeric or `_` is written as `%XX` (uppercase hex).
+fn escape_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for b in name.bytes() {
+        if is_unreserved(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Decodes a name written by `escape_name`. Only the canonical encoding is
+/// accepted, so that every name has exactly one string form.
+fn unescape_name(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)?;
+                if !hex
+                    .iter()
+                    .all(|h| h.is_ascii_digit() || (b'A'..=b'F').contains(h))
+                {
+                    return None;
+                }
+                let b = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+                if is_unreserved(b) {
+                    return None;
+                }
+                out.push(b);
+                i += 3;
+            }
+            b if is_unreserved(b) => {
+                out.push(b);
+                i += 1;
+            }
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
 #[derive(Serialize, Deserialize, Debug, Hash, Eq, PartialEq, JsonSchema, Clone)]
 pub enum ValidFragmentError {
     BranchParseError,
@@ -100,10 +178,10 @@ impl TryFrom<&str> for PathFragment {
             "@default_effect" => PathFragment::DefaultEffect,
             "@effects" => PathFragment::Effects,
             n if n.starts_with('#') => PathFragment::Branch(FromStr::from_str(&n[1..])?),
-            n if n.chars().all(|x| x.is_ascii_alphanumeric() || x == '_') => {
-                PathFragment::Named(SArc(Arc::new(s.into())))
-            }
-            _ => return Err(ValidFragmentError::BadName(SArc(Arc::new(s.into())))),
+            n => match unescape_name(n) {
+                Some(name) => PathFragment::Named(SArc(Arc::new(name))),
+                None => return Err(ValidFragmentError::BadName(SArc(Arc::new(s.into())))),
+            },
         })
     }
 }
@@ -116,6 +194,27 @@ impl From<ReversePath<PathFragment>> for String {
     }
 }
 
+impl std::fmt::Display for ReversePath<PathFragment> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut v: Vec<&PathFragment> = self.iter().collect();
+        v.reverse();
+        for (i, frag) in v.into_iter().enumerate() {
+            if i != 0 {
+                f.write_str("/")?;
+            }
+            std::fmt::Display::fmt(frag, f)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ReversePath<PathFragment> {
+    type Err = ValidFragmentError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
 impl TryFrom<&str> for ReversePath<PathFragment> {
     type Error = ValidFragmentError;
     fn try_from(r: &str) -> Result<ReversePath<PathFragment>, Self::Error> {
@@ -133,3 +232,72 @@ impl TryFrom<String> for ReversePath<PathFragment> {
         Self::try_from(r.as_ref())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::Rng;
+
+    fn name(rng: &mut Rng) -> String {
+        const ALPHABET: &[char] = &['a', 'Z', '0', '_', '/', '#', '@', '%', ' ', 'é', '∀'];
+        let len = rng.below(8);
+        (0..len)
+            .map(|_| ALPHABET[rng.below(ALPHABET.len())])
+            .collect()
+    }
+    fn fragment(rng: &mut Rng) -> PathFragment {
+        const RESERVED: &[PathFragment] = &[
+            PathFragment::Root,
+            PathFragment::Cloned,
+            PathFragment::ThenFn,
+            PathFragment::FinishOrFn,
+            PathFragment::FinishFn,
+            PathFragment::CondCompIf,
+            PathFragment::Guard,
+            PathFragment::Next,
+            PathFragment::Suggested,
+            PathFragment::DefaultEffect,
+            PathFragment::Effects,
+        ];
+        match rng.below(4) {
+            0 => RESERVED[rng.below(RESERVED.len())].clone(),
+            1 => PathFragment::Branch(rng.next()),
+            _ => PathFragment::Named(SArc(Arc::new(name(rng)))),
+        }
+    }
+
+    #[test]
+    fn test_escaping() {
+        let frag = PathFragment::Named(SArc(Arc::new("a/#b@%".into())));
+        assert_eq!(frag.to_string(), "a%2F%23b%40%25");
+        assert_eq!(Ok(frag), "a%2F%23b%40%25".parse());
+        assert!(PathFragment::try_from("a%2f").is_err());
+        assert!(PathFragment::try_from("%61").is_err());
+        assert!(PathFragment::try_from("a%2").is_err());
+        assert!(PathFragment::try_from("%FF").is_err());
+        assert!(PathFragment::try_from("a b").is_err());
+    }
+
+    #[test]
+    fn test_fuzz_fragment_round_trip() {
+        let mut rng = Rng(0x5a91_0000_dead_beef);
+        for _ in 0..10_000 {
+            let frag = fragment(&mut rng);
+            let s = frag.to_string();
+            assert_eq!(Ok(frag.clone()), s.parse::<PathFragment>(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_fuzz_path_round_trip() {
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        for _ in 0..1_000 {
+            let len = 1 + rng.below(6);
+            let frags: Vec<PathFragment> = (0..len).map(|_| fragment(&mut rng)).collect();
+            let path = ReversePath::try_from(frags).unwrap();
+            let s = path.to_string();
+            assert_eq!(s, String::from(path.clone()));
+            assert_eq!(Ok(path), s.parse::<ReversePath<PathFragment>>(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Patterns over [`EffectPath`]s, so effects can be found or declared without
+//! spelling out every fragment of a path.
+//!
+//! A pattern is written like a path, with `/` between fragments. Each
+//! fragment is one of:
+//! - `**`, matching any number (including none) of fragments
+//! - `*`, matching exactly one fragment
+//! - a fragment containing `*`, e.g. `#*` or `pay_*`, matching one fragment
+//!   whose string form matches with `*` standing for any characters
+//! - anything else, matching exactly that [`PathFragment`]
+//!
+//! `*` never appears in the string form of a fragment (it is escaped in
+//! names), so patterns are unambiguous.
+use super::{EffectPath, PathFragment, ValidFragmentError};
+use crate::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// One fragment of an [`EffectPathPattern`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PatternFragment {
+    /// matches exactly this fragment
+    Exact(PathFragment),
+    /// matches a fragment whose string form matches the glob
+    Glob(String),
+    /// `*`: matches any one fragment
+    Any,
+    /// `**`: matches any number of fragments
+    AnyMany,
+}
+
+/// # Effect Path Pattern
+/// A glob over effect paths, e.g. `**/@finish_or_fn/@suggested/*`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(into = "String", try_from = "String")]
+#[schemars(transparent)]
+pub struct EffectPathPattern {
+    #[schemars(with = "String")]
+    frags: Vec<PatternFragment>,
+}
+
+/// How specific a pattern is. When several patterns match a path, the one
+/// with the greatest specificity applies: the most exact fragments, then the
+/// most partial globs, then the most `*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+    exact: usize,
+    glob: usize,
+    any: usize,
+}
+
+/// matches `s` against `glob`, where `*` stands for any (possibly empty)
+/// sequence of characters
+fn glob_matches(glob: &str, s: &str) -> bool {
+    let mut parts = glob.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match s.strip_prefix(first) {
+        Some(r) => r,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i + 1 == parts.len() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    // no `*` at all
+    rest.is_empty()
+}
+
+impl PatternFragment {
+    fn matches(&self, frag: &PathFragment) -> bool {
+        match self {
+            PatternFragment::Exact(f) => f == frag,
+            PatternFragment::Glob(g) => glob_matches(g, &String::from(frag)),
+            PatternFragment::Any | PatternFragment::AnyMany => true,
+        }
+    }
+}
+
+impl EffectPathPattern {
+    /// A pattern matching exactly `path`
+    pub fn exact(path: &EffectPath) -> Self {
+        EffectPathPattern {
+            frags: Vec::from(path.clone())
+                .into_iter()
+                .map(PatternFragment::Exact)
+                .collect(),
+        }
+    }
+    /// The pattern's fragments, from the root
+    pub fn fragments(&self) -> &[PatternFragment] {
+        &self.frags
+    }
+    /// Does `path` match this pattern?
+    pub fn matches(&self, path: &EffectPath) -> bool {
+        let path: Vec<&PathFragment> = {
+            let mut v: Vec<_> = path.iter().collect();
+            v.reverse();
+            v
+        };
+        // can[j]: the first i fragments of the pattern match the first j of
+        // the path
+        let mut can = vec![false; path.len() + 1];
+        can[0] = true;
+        for p in self.frags.iter() {
+            let mut next = vec![false; path.len() + 1];
+            for j in 0..=path.len() {
+                next[j] = match p {
+                    PatternFragment::AnyMany => can[j] || (j > 0 && next[j - 1]),
+                    p => j > 0 && can[j - 1] && p.matches(path[j - 1]),
+                };
+            }
+            can = next;
+        }
+        can[path.len()]
+    }
+    /// How specific this pattern is, for choosing between several which match
+    pub fn specificity(&self) -> Specificity {
+        let mut s = Specificity {
+            exact: 0,
+            glob: 0,
+            any: 0,
+        };
+        for f in self.frags.iter() {
+            match f {
+                PatternFragment::Exact(_) => s.exact += 1,
+                PatternFragment::Glob(_) => s.glob += 1,
+                PatternFragment::Any => s.any += 1,
+                PatternFragment::AnyMany => {}
+            }
+        }
+        s
+    }
+}
+
+impl std::fmt::Display for EffectPathPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, frag) in self.frags.iter().enumerate() {
+            if i != 0 {
+                f.write_str("/")?;
+            }
+            match frag {
+                PatternFragment::Exact(p) => std::fmt::Display::fmt(p, f)?,
+                PatternFragment::Glob(g) => f.write_str(g)?,
+                PatternFragment::Any => f.write_str("*")?,
+                PatternFragment::AnyMany => f.write_str("**")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<EffectPathPattern> for String {
+    fn from(p: EffectPathPattern) -> String {
+        p.to_string()
+    }
+}
+
+impl TryFrom<&str> for EffectPathPattern {
+    type Error = ValidFragmentError;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let frags = s
+            .split('/')
+            .map(|f| {
+                Ok(match f {
+                    "**" => PatternFragment::AnyMany,
+                    "*" => PatternFragment::Any,
+                    g if g.contains('*') => PatternFragment::Glob(g.into()),
+                    f => PatternFragment::Exact(PathFragment::try_from(f)?),
+                })
+            })
+            .collect::<Result<Vec<_>, ValidFragmentError>>()?;
+        if frags.is_empty() {
+            return Err(ValidFragmentError::InvalidReversePath(
+                "Pattern must have at least one element.",
+            ));
+        }
+        Ok(EffectPathPattern { frags })
+    }
+}
+
+impl TryFrom<String> for EffectPathPattern {
+    type Error = ValidFragmentError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::try_from(s.as_str())
+    }
+}
+
+impl FromStr for EffectPathPattern {
+    type Err = ValidFragmentError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+/// # Pattern Effect
+/// An effect to apply at every path matching a pattern.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PatternEffect {
+    /// # Pattern
+    /// The paths the effect applies at
+    pub pattern: EffectPathPattern,
+    /// # Name
+    /// The name of the effect
+    pub name: SArc<String>,
+    /// # Arguments
+    /// The arguments to the continuation
+    pub value: serde_json::Value,
+}
+
+impl PatternEffect {
+    /// An effect named `name` applying `value` at every path matching `pattern`
+    pub fn new(pattern: EffectPathPattern, name: Arc<String>, value: serde_json::Value) -> Self {
+        PatternEffect {
+            pattern,
+            name: SArc(name),
+            value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn path(s: &str) -> EffectPath {
+        EffectPath::try_from(s).unwrap()
+    }
+    fn pat(s: &str) -> EffectPathPattern {
+        EffectPathPattern::try_from(s).unwrap()
+    }
+    #[test]
+    fn test_pattern_roundtrip() {
+        for s in ["**", "a/*/#*", "**/@finish_or_fn/pay_*", "a%20b/**/c"] {
+            assert_eq!(pat(s).to_string(), s);
+            let json = serde_json::to_string(&pat(s)).unwrap();
+            assert_eq!(
+                serde_json::from_str::<EffectPathPattern>(&json).unwrap(),
+                pat(s)
+            );
+        }
+        assert!(EffectPathPattern::try_from("a/@bad").is_err());
+    }
+    #[test]
+    fn test_matches() {
+        let p = path("root/#1/@finish_or_fn/pay_alice");
+        assert!(pat("**").matches(&p));
+        assert!(pat("root/**").matches(&p));
+        assert!(pat("root/#1/@finish_or_fn/pay_alice/**").matches(&p));
+        assert!(pat("**/pay_*").matches(&p));
+        assert!(pat("root/#*/*/pay_alice").matches(&p));
+        assert!(EffectPathPattern::exact(&p).matches(&p));
+        assert!(!pat("root/*").matches(&p));
+        assert!(!pat("**/pay_bob").matches(&p));
+        assert!(!pat("root/#2/**").matches(&p));
+        assert!(!pat("*/*/*/*/*").matches(&p));
+    }
+    #[test]
+    fn test_specificity() {
+        let p = path("root/#1/@finish_or_fn/pay_alice");
+        let mut matching = vec![
+            pat("**"),
+            pat("root/**/pay_alice"),
+            pat("root/#*/**"),
+            pat("root/*/**"),
+            pat("**/pay_*"),
+        ];
+        assert!(matching.iter().all(|m| m.matches(&p)));
+        matching.sort_by_key(|m| std::cmp::Reverse(m.specificity()));
+        let order: Vec<String> = matching.iter().map(|m| m.to_string()).collect();
+        assert_eq!(
+            order,
+            vec![
+                "root/**/pay_alice",
+                "root/#*/**",
+                "root/*/**",
+                "**/pay_*",
+                "**"
+            ]
+        );
+        assert!(
+            EffectPathPattern::exact(&p).specificity() > pat("root/**/pay_alice").specificity()
+        );
+    }
+}
@@ -7,10 +7,18 @@
 //! general non-parameter compilation state required by all contracts
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
 /// Used to Build a Shared Path for all children of a given context.
-#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Hash)]
+///
+/// Each node caches its length and a hash of the whole path, so hashing is
+/// O(1) and most unequal paths compare in O(1). Paths made through a
+/// [`PathInterner`] share their common prefixes, and equal interned paths
+/// compare by pointer.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(try_from = "Y")]
 #[serde(into = "Y")]
 #[serde(
@@ -19,6 +27,10 @@ use std::sync::Arc;
 pub struct ReversePath<T, Y = String> {
     past: Option<Arc<ReversePath<T, Y>>>,
     this: T,
+    #[serde(skip)]
+    len: usize,
+    #[serde(skip)]
+    digest: u64,
     _pd: PhantomData<Y>,
 }
 
@@ -27,11 +39,37 @@ where
     T: PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
-        self.iter().eq(other.iter())
+        if std::ptr::eq(self, other) {
+            return true;
+        }
+        if self.len != other.len || self.digest != other.digest {
+            return false;
+        }
+        let (mut a, mut b) = (self, other);
+        loop {
+            if a.this != b.this {
+                return false;
+            }
+            match (&a.past, &b.past) {
+                (Some(x), Some(y)) if Arc::ptr_eq(x, y) => return true,
+                (Some(x), Some(y)) => {
+                    a = x;
+                    b = y;
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
     }
 }
 impl<T, Y> Eq for ReversePath<T, Y> where T: Eq {}
 
+impl<T: Hash, Y> Hash for ReversePath<T, Y> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.digest)
+    }
+}
+
 /// RPI = ReversePathIterator
 /// This simplifies iterating over a reversepath.
 pub struct RPI<'a, T, Y> {
@@ -58,7 +96,7 @@ impl<'a, T, Y> Iterator for RPI<'a, T, Y> {
 }
 
 use std::convert::TryFrom;
-impl<T, Y> TryFrom<Vec<T>> for ReversePath<T, Y> {
+impl<T: Hash, Y> TryFrom<Vec<T>> for ReversePath<T, Y> {
     type Error = &'static str;
     fn try_from(v: Vec<T>) -> Result<Self, Self::Error> {
         match v
@@ -78,13 +116,9 @@ impl<T: Clone, Y> From<ReversePath<T, Y>> for Vec<T> {
         v
     }
 }
-impl<T: Clone, Y> From<T> for ReversePath<T, Y> {
+impl<T: Clone + Hash, Y> From<T> for ReversePath<T, Y> {
     fn from(this: T) -> Self {
-        ReversePath {
-            past: None,
-            this,
-            _pd: Default::default(),
-        }
+        Self::push_owned(None, this)
     }
 }
 /// Helper for making a ReversePath.
@@ -99,7 +133,7 @@ impl<T, Y> MkReversePath<T, Y> {
         }
     }
 }
-impl<T, Y> From<Vec<T>> for MkReversePath<T, Y> {
+impl<T: Hash, Y> From<Vec<T>> for MkReversePath<T, Y> {
     fn from(v: Vec<T>) -> Self {
         let mut rp: Option<Arc<ReversePath<T, Y>>> = None;
         for val in v {
@@ -109,23 +143,94 @@ impl<T, Y> From<Vec<T>> for MkReversePath<T, Y> {
         MkReversePath(rp)
     }
 }
-impl<T, Y> ReversePath<T, Y> {
+impl<T: Hash, Y> ReversePath<T, Y> {
     /// Add an element to a ReversePath
     pub fn push(v: Option<Arc<ReversePath<T, Y>>>, s: T) -> Arc<ReversePath<T, Y>> {
         Arc::new(Self::push_owned(v, s))
     }
     /// Add an element to a ReversePath and do not wrap in Arc
     pub fn push_owned(v: Option<Arc<ReversePath<T, Y>>>, s: T) -> ReversePath<T, Y> {
+        let mut h = DefaultHasher::new();
+        v.as_ref().map(|p| p.digest).hash(&mut h);
+        s.hash(&mut h);
         ReversePath::<T, Y> {
+            len: v.as_ref().map_or(0, |p| p.len) + 1,
+            digest: h.finish(),
             past: v,
             this: s,
             _pd: Default::default(),
         }
     }
+}
+impl<T, Y> ReversePath<T, Y> {
     /// iterate over a reversepath
     pub fn iter(&self) -> RPI<'_, T, Y> {
         RPI { inner: Some(self) }
     }
+    /// the number of elements in the path
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// always false, a path has at least one element
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+struct InternerState<T, Y> {
+    nodes: HashMap<(usize, T), Weak<ReversePath<T, Y>>>,
+    prune_at: usize,
+}
+
+/// Hash-conses ReversePaths, so that equal paths pushed through the same
+/// interner are the same allocation.
+///
+/// Nodes are held weakly, so paths no longer in use are still freed.
+pub struct PathInterner<T, Y = String> {
+    state: Mutex<InternerState<T, Y>>,
+}
+
+impl<T, Y> Default for PathInterner<T, Y> {
+    fn default() -> Self {
+        PathInterner {
+            state: Mutex::new(InternerState {
+                nodes: HashMap::new(),
+                prune_at: 1024,
+            }),
+        }
+    }
+}
+
+impl<T: Hash + Eq + Clone, Y> PathInterner<T, Y> {
+    /// Add an element to a ReversePath, returning the existing node if this
+    /// path was already interned. `v` should itself come from this interner,
+    /// otherwise the result is correct but shares less.
+    pub fn push(&self, v: Option<Arc<ReversePath<T, Y>>>, s: T) -> Arc<ReversePath<T, Y>> {
+        let key = (v.as_ref().map_or(0, |p| Arc::as_ptr(p) as usize), s);
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            // interning is only an optimization
+            Err(_) => return ReversePath::push(v, key.1),
+        };
+        if let Some(node) = state.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+        if state.nodes.len() >= state.prune_at {
+            state.nodes.retain(|_, w| w.strong_count() > 0);
+            state.prune_at = std::cmp::max(1024, 2 * state.nodes.len());
+        }
+        let node = ReversePath::push(v, key.1.clone());
+        state.nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+    /// Intern every node of `path`
+    pub fn intern(&self, path: &ReversePath<T, Y>) -> Arc<ReversePath<T, Y>> {
+        let mut v: Vec<&T> = path.iter().collect();
+        v.reverse();
+        v.into_iter()
+            .fold(None, |past, s| Some(self.push(past, s.clone())))
+            .expect("a path has at least one element")
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +307,24 @@ mod test {
             .unwrap();
         assert_ne!(a, b);
     }
+    #[test]
+    fn test_interner() {
+        let interner = PathInterner::<i64, Vec<i64>>::default();
+        let a = (0..100)
+            .fold(None, |x, y| Some(interner.push(x, y)))
+            .unwrap();
+        let b = (0..100)
+            .fold(None, |x, y| Some(interner.push(x, y)))
+            .unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(a.len(), 100);
+        let c = (0..100)
+            .fold(None, |x, y| Some(ReversePath::<i64, Vec<i64>>::push(x, y)))
+            .unwrap();
+        assert_eq!(a, c);
+        assert!(Arc::ptr_eq(&interner.intern(&c), &a));
+        let mut set = std::collections::HashSet::new();
+        set.insert(a.clone());
+        assert!(set.contains(&c));
+    }
 }
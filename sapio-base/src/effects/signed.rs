@@ -0,0 +1,155 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Effects signed by the party proposing them, so they can be passed around
+//! through untrusted channels.
+use super::{EffectConditions, EffectDBError, EffectPath, MapEffectDB};
+use crate::serialization_helpers::SArc;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{schnorr::Signature, Message, Secp256k1, Signing, Verification};
+use bitcoin::secp256k1::{KeyPair, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// BIP-340 style tag for the digest signed in a [`SignedEffect`]
+const SIGNED_EFFECT_TAG: &[u8] = b"sapio/signed_effect";
+
+/// # Effect Proposal
+/// A single effect to apply at `path`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct EffectProposal {
+    /// # Path
+    /// The path the effect is to be applied at
+    pub path: SArc<EffectPath>,
+    /// # Name
+    /// The name of the effect (i.e., the continuation to call)
+    pub name: SArc<String>,
+    /// # Arguments
+    /// The arguments to the continuation
+    pub value: serde_json::Value,
+    /// # Conditions
+    /// Restrictions on when the effect may be applied
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub conditions: Option<EffectConditions>,
+}
+
+impl EffectProposal {
+    /// The digest that a signer commits to. `serde_json` emits map keys in
+    /// sorted order, so the digest does not depend on the order fields in
+    /// `value` were constructed in.
+    pub fn digest(&self) -> Result<sha256::Hash, EffectDBError> {
+        let tag = sha256::Hash::hash(SIGNED_EFFECT_TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine.input(&serde_json::to_vec(self)?);
+        Ok(sha256::Hash::from_engine(engine))
+    }
+
+    /// Sign the proposal with `key`
+    pub fn sign<C: Signing>(
+        self,
+        secp: &Secp256k1<C>,
+        key: &KeyPair,
+    ) -> Result<SignedEffect, EffectDBError> {
+        let msg = Message::from_slice(&self.digest()?[..]).expect("32 bytes is a valid message");
+        Ok(SignedEffect {
+            signature: secp.sign_schnorr_no_aux_rand(&msg, key),
+            signer: XOnlyPublicKey::from_keypair(key),
+            proposal: self,
+        })
+    }
+}
+
+/// # Signed Effect
+/// An [`EffectProposal`] with a BIP-340 signature from the proposer.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct SignedEffect {
+    /// # Proposal
+    pub proposal: EffectProposal,
+    /// # Signer
+    /// The x-only key of the proposer
+    #[schemars(with = "String")]
+    pub signer: XOnlyPublicKey,
+    /// # Signature
+    /// Signature over the proposal's digest
+    #[schemars(with = "String")]
+    pub signature: Signature,
+}
+
+impl SignedEffect {
+    /// Check the signature is valid for the proposal and signer
+    pub fn verify<C: Verification>(&self, secp: &Secp256k1<C>) -> Result<(), EffectDBError> {
+        let msg =
+            Message::from_slice(&self.proposal.digest()?[..]).expect("32 bytes is a valid message");
+        secp.verify_schnorr(&self.signature, &msg, &self.signer)
+            .map_err(|_| {
+                EffectDBError::BadSignature(self.proposal.path.clone(), self.proposal.name.clone())
+            })
+    }
+}
+
+impl MapEffectDB {
+    /// Add a signed effect, after checking its signature. Any conditions on
+    /// the effect are added as well.
+    pub fn insert_signed<C: Verification>(
+        &mut self,
+        secp: &Secp256k1<C>,
+        effect: SignedEffect,
+    ) -> Result<(), EffectDBError> {
+        effect.verify(secp)?;
+        let EffectProposal {
+            path,
+            name,
+            value,
+            conditions,
+        } = effect.proposal;
+        if let Some(c) = conditions {
+            self.conditions
+                .entry(path.clone())
+                .or_default()
+                .insert(name.clone(), c);
+        }
+        self.effects.entry(path).or_default().insert(name, value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::effects::EffectDB;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    #[test]
+    fn test_sign_verify() {
+        let secp = Secp256k1::new();
+        let key = KeyPair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let proposal = EffectProposal {
+            path: SArc(Arc::new(EffectPath::try_from("hello/@finish_fn").unwrap())),
+            name: SArc(Arc::new("update".into())),
+            value: serde_json::json!({"b": 1, "a": 2}),
+            conditions: None,
+        };
+        let signed = proposal.clone().sign(&secp, &key).unwrap();
+        signed.verify(&secp).unwrap();
+        let json = serde_json::to_string(&signed).unwrap();
+        let back: SignedEffect = serde_json::from_str(&json).unwrap();
+        back.verify(&secp).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.proposal.value = serde_json::json!({"a": 3});
+        assert!(matches!(
+            tampered.verify(&secp),
+            Err(EffectDBError::BadSignature(_, _))
+        ));
+
+        let mut db = MapEffectDB::default();
+        assert!(db.insert_signed(&secp, tampered).is_err());
+        db.insert_signed(&secp, signed).unwrap();
+        assert_eq!(db.get_value(&proposal.path.0).count(), 1);
+    }
+}
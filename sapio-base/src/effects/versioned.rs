@@ -0,0 +1,287 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A mutable effect database which records every change made to it, so that
+//! decisions can be accumulated across sessions and merged between parties.
+use super::{EffectDB, EffectDBError, EffectPath, EffectProposal, MapEffectDB};
+use crate::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// # Effect Operation
+/// One change to a [`VersionedEffectDB`].
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub enum EffectOp {
+    /// # Apply
+    /// Set an effect (and its conditions), replacing any with the same path
+    /// and name
+    Apply(EffectProposal),
+    /// # Revert
+    /// Remove the effect with the name at the path
+    Revert {
+        /// # Path
+        path: SArc<EffectPath>,
+        /// # Name
+        name: SArc<String>,
+    },
+}
+
+/// # Effect Log
+/// Every operation made on a [`VersionedEffectDB`], oldest first. Replaying
+/// it rebuilds the database.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+pub struct EffectLog {
+    /// # Operations
+    pub ops: Vec<EffectOp>,
+}
+
+/// A [`MapEffectDB`] which may be modified, keeping a log of each change.
+///
+/// The version is the number of operations applied. Operations made inside
+/// [`VersionedEffectDB::transaction`] are all kept or all discarded.
+#[derive(Clone, Debug, Default)]
+pub struct VersionedEffectDB {
+    db: MapEffectDB,
+    log: EffectLog,
+}
+
+impl VersionedEffectDB {
+    /// Rebuild a database by replaying `log`
+    pub fn from_log(log: EffectLog) -> Self {
+        let mut db = VersionedEffectDB::default();
+        for op in log.ops {
+            db.push(op);
+        }
+        db
+    }
+    /// The log of every operation made, which may be serialized and passed
+    /// to [`VersionedEffectDB::from_log`]
+    pub fn log(&self) -> &EffectLog {
+        &self.log
+    }
+    /// The number of operations made
+    pub fn version(&self) -> u64 {
+        self.log.ops.len() as u64
+    }
+    /// The current effects, e.g. to pass to a `Context` for compilation
+    pub fn snapshot(&self) -> MapEffectDB {
+        self.db.clone()
+    }
+
+    /// Set the effect `name` at `path` to `value`
+    pub fn apply(&mut self, path: SArc<EffectPath>, name: SArc<String>, value: serde_json::Value) {
+        self.push(EffectOp::Apply(EffectProposal {
+            path,
+            name,
+            value,
+            conditions: None,
+        }))
+    }
+    /// Set an effect with any conditions it has
+    pub fn apply_proposal(&mut self, proposal: EffectProposal) {
+        self.push(EffectOp::Apply(proposal))
+    }
+    /// Remove the effect `name` at `path`, returning its value if it was set
+    pub fn revert(
+        &mut self,
+        path: SArc<EffectPath>,
+        name: SArc<String>,
+    ) -> Option<serde_json::Value> {
+        let old = self.get(&path, &name).cloned();
+        if old.is_some() {
+            self.push(EffectOp::Revert { path, name });
+        }
+        old
+    }
+    /// The value of the effect `name` at `path`
+    pub fn get(&self, path: &SArc<EffectPath>, name: &SArc<String>) -> Option<&serde_json::Value> {
+        self.db.effects.get(path).and_then(|m| m.get(name))
+    }
+
+    /// Run `f`, discarding every operation it made if it fails
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+    {
+        let checkpoint = (self.db.clone(), self.log.ops.len());
+        let r = f(self);
+        if r.is_err() {
+            self.db = checkpoint.0;
+            self.log.ops.truncate(checkpoint.1);
+        }
+        r
+    }
+
+    /// The operations which would turn the effects of `self` into those of
+    /// `other`, in a deterministic order.
+    pub fn diff(&self, other: &VersionedEffectDB) -> Vec<EffectOp> {
+        let ours = self.proposals();
+        let theirs = other.proposals();
+        let removed = ours
+            .keys()
+            .filter(|k| !theirs.contains_key(*k))
+            .map(|k| EffectOp::Revert {
+                path: ours[k].path.clone(),
+                name: ours[k].name.clone(),
+            });
+        let set = theirs
+            .iter()
+            .filter(|(k, p)| ours.get(*k) != Some(*p))
+            .map(|(_, p)| EffectOp::Apply(p.clone()));
+        removed.chain(set).collect()
+    }
+
+    /// Add every effect of `other`, failing without changes if any effect
+    /// is set differently in both.
+    pub fn merge(&mut self, other: &VersionedEffectDB) -> Result<(), EffectDBError> {
+        let ours = self.proposals();
+        for (k, p) in other.proposals() {
+            if matches!(ours.get(&k), Some(mine) if *mine != p) {
+                return Err(EffectDBError::MergeConflict(p.path, p.name));
+            }
+        }
+        for op in self.diff(other) {
+            if let EffectOp::Apply(p) = op {
+                self.push(EffectOp::Apply(p));
+            }
+        }
+        Ok(())
+    }
+
+    /// every effect, keyed by path and name in sorted order
+    fn proposals(&self) -> BTreeMap<(String, String), EffectProposal> {
+        self.db
+            .effects
+            .iter()
+            .flat_map(|(path, m)| {
+                m.iter().map(move |(name, value)| {
+                    (
+                        (path.0.to_string(), name.0.to_string()),
+                        EffectProposal {
+                            path: path.clone(),
+                            name: name.clone(),
+                            value: value.clone(),
+                            conditions: self
+                                .db
+                                .conditions
+                                .get(path)
+                                .and_then(|c| c.get(name))
+                                .cloned(),
+                        },
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn push(&mut self, op: EffectOp) {
+        match &op {
+            EffectOp::Apply(EffectProposal {
+                path,
+                name,
+                value,
+                conditions,
+            }) => {
+                let c = self.db.conditions.entry(path.clone()).or_default();
+                match conditions {
+                    Some(conditions) => {
+                        c.insert(name.clone(), conditions.clone());
+                    }
+                    None => {
+                        c.remove(name);
+                    }
+                }
+                if c.is_empty() {
+                    self.db.conditions.remove(path);
+                }
+                self.db
+                    .effects
+                    .entry(path.clone())
+                    .or_default()
+                    .insert(name.clone(), value.clone());
+            }
+            EffectOp::Revert { path, name } => {
+                if let Some(e) = self.db.effects.get_mut(path) {
+                    e.remove(name);
+                    if e.is_empty() {
+                        self.db.effects.remove(path);
+                    }
+                }
+                if let Some(c) = self.db.conditions.get_mut(path) {
+                    c.remove(name);
+                    if c.is_empty() {
+                        self.db.conditions.remove(path);
+                    }
+                }
+            }
+        }
+        self.log.ops.push(op);
+    }
+}
+
+impl EffectDB for VersionedEffectDB {
+    fn get_value<'a>(
+        &'a self,
+        at: &Arc<EffectPath>,
+    ) -> Box<dyn Iterator<Item = (&'a Arc<String>, &'a serde_json::Value)> + 'a> {
+        self.db.get_value(at)
+    }
+    fn check_applicable(
+        &self,
+        at: &Arc<EffectPath>,
+        name: &Arc<String>,
+    ) -> Result<(), EffectDBError> {
+        self.db.check_applicable(at, name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    fn key(p: &str, n: &str) -> (SArc<EffectPath>, SArc<String>) {
+        (
+            SArc(Arc::new(EffectPath::try_from(p).unwrap())),
+            SArc(Arc::new(n.into())),
+        )
+    }
+    #[test]
+    fn test_log_diff_merge() {
+        let (p, a) = key("hello/@finish_fn", "a");
+        let (_, b) = key("hello/@finish_fn", "b");
+        let mut db = VersionedEffectDB::default();
+        db.apply(p.clone(), a.clone(), serde_json::json!(1));
+        db.apply(p.clone(), b.clone(), serde_json::json!(2));
+        assert_eq!(db.revert(p.clone(), b.clone()), Some(serde_json::json!(2)));
+        assert_eq!(db.version(), 3);
+        assert_eq!(db.get_value(&p.0).count(), 1);
+
+        let json = serde_json::to_string(db.log()).unwrap();
+        let replayed = VersionedEffectDB::from_log(serde_json::from_str(&json).unwrap());
+        assert!(db.diff(&replayed).is_empty());
+
+        let r: Result<(), ()> = db.transaction(|db| {
+            db.apply(p.clone(), b.clone(), serde_json::json!(3));
+            Err(())
+        });
+        assert!(r.is_err());
+        assert_eq!(db.version(), 3);
+        assert!(db.get(&p, &b).is_none());
+
+        let mut other = VersionedEffectDB::default();
+        other.apply(p.clone(), b.clone(), serde_json::json!(4));
+        assert_eq!(db.diff(&other).len(), 2);
+        db.merge(&other).unwrap();
+        assert_eq!(db.get(&p, &b), Some(&serde_json::json!(4)));
+        other.apply(p.clone(), a.clone(), serde_json::json!(5));
+        assert!(matches!(
+            db.merge(&other),
+            Err(EffectDBError::MergeConflict(_, _))
+        ));
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fee estimation, so that contracts can budget fees at compile time.
+use bitcoin::util::amount::Amount;
+
+/// Errors from estimating fees
+#[derive(Debug)]
+pub enum FeeEstimatorError {
+    /// The estimator has no estimate for the target
+    NoEstimate(u16),
+    /// The estimate could not be fetched
+    RpcError(Box<dyn std::error::Error>),
+}
+impl std::error::Error for FeeEstimatorError {}
+
+impl std::fmt::Display for FeeEstimatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A source of feerate estimates
+pub trait FeeEstimator: Send + Sync {
+    /// The feerate, in sats per vbyte, expected to confirm within
+    /// `target_blocks` blocks
+    fn estimate_feerate(&self, target_blocks: u16) -> Result<Amount, FeeEstimatorError>;
+    /// true if every estimate is fixed ahead of time, rather than e.g.
+    /// fetched from the network, so compiling with it is reproducible
+    fn is_deterministic(&self) -> bool {
+        false
+    }
+}
+
+/// Always estimates the same feerate, e.g. for tests or reproducible builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticFeeEstimator {
+    /// sats per vbyte
+    pub feerate: Amount,
+}
+
+impl FeeEstimator for StaticFeeEstimator {
+    fn estimate_feerate(&self, _target_blocks: u16) -> Result<Amount, FeeEstimatorError> {
+        Ok(self.feerate)
+    }
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+}
@@ -17,10 +17,21 @@ pub mod timelocks;
 /// Trait & Structs for accessing Chain Data
 pub mod txindex;
 
+pub mod fees;
+
 pub mod effects;
 pub use effects::reverse_path;
 pub mod serialization_helpers;
 
+pub mod canonical;
+pub mod clause_json;
+pub mod clause_simplify;
+
+pub mod satisfaction;
+
+#[cfg(test)]
+pub(crate) mod test_util;
+
 /// Concrete Instantiation of Miniscript Policy. Because we need to be able to generate exact
 /// transactions, we only work with `bitcoin::PublicKey` types.
 pub type Clause = miniscript::policy::concrete::Policy<XOnlyPublicKey>;
@@ -1,5 +1,7 @@
+use crate::canonical::CanonicalEncoding;
 use crate::effects::MapEffectDB;
 use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(remote = "bitcoin::Network")]
@@ -25,6 +27,12 @@ pub struct CreateArgs<S> {
     pub context: ContextualArguments,
 }
 
+/// Parties exchanging arguments to compile a contract with should compare
+/// their canonical hashes, as the JSON text of equal arguments may differ.
+impl<S: Serialize + DeserializeOwned> CanonicalEncoding for CreateArgs<S> {
+    const HASH_TAG: &'static [u8] = b"sapio/create_args";
+}
+
 /// # Contextual Arguments For Creating this Contract
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct ContextualArguments {
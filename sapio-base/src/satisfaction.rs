@@ -0,0 +1,337 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! "Who can spend when": whether a [`Clause`] can be satisfied by a party
+//! holding some keys and preimages at some point in the chain, what they
+//! are missing if not, and when any timelocks they wait on expire.
+use super::Clause;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// nLockTime values at or above this are unix times, below are heights
+pub(crate) const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// nSequence flag disabling the relative lock
+pub(crate) const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// nSequence flag making the relative lock time based
+pub(crate) const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+/// nSequence bits holding the relative lock
+pub(crate) const SEQUENCE_MASK: u32 = 0xffff;
+/// the granularity of time based relative locks, in seconds
+pub(crate) const SEQUENCE_GRANULARITY: u32 = 512;
+
+/// What a party has available to spend with, and the state of the chain and
+/// the output being spent.
+#[derive(Clone, Debug, Default)]
+pub struct SpendingState {
+    /// keys the party can sign with
+    pub keys: BTreeSet<XOnlyPublicKey>,
+    /// preimages the party knows, of any hash type
+    pub preimages: Vec<Vec<u8>>,
+    /// the height of the chain tip
+    pub height: u32,
+    /// the median time past of the chain tip
+    pub time: u32,
+    /// the height of the block the output was confirmed in, if it was
+    pub confirmed_height: Option<u32>,
+    /// the median time past of the block before the one the output was
+    /// confirmed in, if it was
+    pub confirmed_time: Option<u32>,
+}
+
+/// # Missing Requirement
+/// One thing standing between a party and satisfying a clause.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Missing {
+    /// a signature from this key
+    Key(XOnlyPublicKey),
+    /// a preimage of this hash
+    Sha256(sha256::Hash),
+    /// a preimage of this hash
+    Hash256(sha256d::Hash),
+    /// a preimage of this hash
+    Ripemd160(ripemd160::Hash),
+    /// a preimage of this hash
+    Hash160(hash160::Hash),
+    /// the chain tip must reach this height
+    Height(u32),
+    /// the chain tip's median time past must reach this time
+    Time(u32),
+    /// the output must be confirmed before its relative locks start
+    Confirmation,
+    /// the clause can never be satisfied
+    Unsatisfiable,
+}
+
+impl Missing {
+    /// true if this is only a matter of time
+    pub fn is_waiting(&self) -> bool {
+        matches!(
+            self,
+            Missing::Height(_) | Missing::Time(_) | Missing::Confirmation
+        )
+    }
+}
+
+/// # Satisfiability
+/// Whether a clause can be satisfied, and if not what is missing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Satisfiability {
+    /// everything missing on the cheapest way to satisfy the clause, empty
+    /// if it can be satisfied now
+    pub missing: BTreeSet<Missing>,
+    /// the tip height at which the pending height locks expire, if any are
+    /// pending
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub unlocks_at_height: Option<u32>,
+    /// the tip median time past at which the pending time locks expire, if
+    /// any are pending
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub unlocks_at_time: Option<u32>,
+}
+
+impl Satisfiability {
+    fn satisfied() -> Self {
+        Satisfiability {
+            missing: BTreeSet::new(),
+            unlocks_at_height: None,
+            unlocks_at_time: None,
+        }
+    }
+    fn missing(m: Missing) -> Self {
+        let mut s = Satisfiability::satisfied();
+        match m {
+            Missing::Height(h) => s.unlocks_at_height = Some(h),
+            Missing::Time(t) => s.unlocks_at_time = Some(t),
+            _ => {}
+        }
+        s.missing.insert(m);
+        s
+    }
+    /// true if the clause can be satisfied now
+    pub fn is_satisfied(&self) -> bool {
+        self.missing.is_empty()
+    }
+    /// true if the clause could ever be satisfied, given what is missing
+    pub fn is_possible(&self) -> bool {
+        !self.missing.contains(&Missing::Unsatisfiable)
+    }
+    /// true if nothing but waiting (for timelocks, or for the output to
+    /// confirm) is needed to satisfy the clause
+    pub fn only_waiting(&self) -> bool {
+        self.missing.iter().all(Missing::is_waiting)
+    }
+    /// both `self` and `other` must be satisfied
+    fn and(mut self, other: Self) -> Self {
+        self.missing.extend(other.missing);
+        self.unlocks_at_height = self.unlocks_at_height.max(other.unlocks_at_height);
+        self.unlocks_at_time = self.unlocks_at_time.max(other.unlocks_at_time);
+        self
+    }
+    /// orders alternatives: possible before impossible, then fewest missing
+    /// items which are not just waiting, then fewest missing at all, then
+    /// soonest to unlock
+    fn cost(&self) -> (bool, usize, usize, u32, u32) {
+        let waiting = self.missing.iter().filter(|m| m.is_waiting()).count();
+        (
+            !self.is_possible(),
+            self.missing.len() - waiting,
+            self.missing.len(),
+            self.unlocks_at_height.unwrap_or(0),
+            self.unlocks_at_time.unwrap_or(0),
+        )
+    }
+}
+
+impl SpendingState {
+    /// Add a key the party can sign with
+    pub fn with_key(mut self, key: XOnlyPublicKey) -> Self {
+        self.keys.insert(key);
+        self
+    }
+    /// Add a preimage the party knows
+    pub fn with_preimage(mut self, preimage: Vec<u8>) -> Self {
+        self.preimages.push(preimage);
+        self
+    }
+    /// Set the height of the chain tip
+    pub fn with_height(mut self, height: u32) -> Self {
+        self.height = height;
+        self
+    }
+    /// Set the median time past of the chain tip
+    pub fn with_time(mut self, time: u32) -> Self {
+        self.time = time;
+        self
+    }
+    /// Set the block the output was confirmed in, by its height and the
+    /// median time past of the block before it
+    pub fn with_confirmation(mut self, height: u32, time: u32) -> Self {
+        self.confirmed_height = Some(height);
+        self.confirmed_time = Some(time);
+        self
+    }
+
+    fn has_preimage<H: Hash>(&self, h: &H) -> bool {
+        self.preimages.iter().any(|p| H::hash(&p[..]) == *h)
+    }
+
+    fn after(&self, n: u32) -> Satisfiability {
+        // a transaction locked to `n` may be mined once the tip reaches `n`
+        // (height), or the tip's median time past passes `n` (time)
+        if n < LOCKTIME_THRESHOLD {
+            if self.height >= n {
+                Satisfiability::satisfied()
+            } else {
+                Satisfiability::missing(Missing::Height(n))
+            }
+        } else if self.time > n {
+            Satisfiability::satisfied()
+        } else {
+            Satisfiability::missing(Missing::Time(n + 1))
+        }
+    }
+
+    fn older(&self, n: u32) -> Satisfiability {
+        if n & SEQUENCE_DISABLE_FLAG != 0 {
+            return Satisfiability::missing(Missing::Unsatisfiable);
+        }
+        let value = n & SEQUENCE_MASK;
+        if n & SEQUENCE_TYPE_FLAG == 0 {
+            // the spend is mined at the tip's height + 1
+            match self.confirmed_height {
+                Some(c) => {
+                    let unlock = (c + value).saturating_sub(1);
+                    if self.height >= unlock {
+                        Satisfiability::satisfied()
+                    } else {
+                        Satisfiability::missing(Missing::Height(unlock))
+                    }
+                }
+                None => Satisfiability::missing(Missing::Confirmation),
+            }
+        } else {
+            match self.confirmed_time {
+                Some(c) => {
+                    let unlock = c + value * SEQUENCE_GRANULARITY;
+                    if self.time >= unlock {
+                        Satisfiability::satisfied()
+                    } else {
+                        Satisfiability::missing(Missing::Time(unlock))
+                    }
+                }
+                None => Satisfiability::missing(Missing::Confirmation),
+            }
+        }
+    }
+
+    /// Whether `clause` can be satisfied in this state. Of alternatives (`Or`
+    /// branches, or the children of a `Threshold`), the ones missing the
+    /// least are chosen. A CTV template is taken to be satisfied, as any
+    /// party may make the transaction it commits to.
+    pub fn evaluate(&self, clause: &Clause) -> Satisfiability {
+        match clause {
+            Clause::Unsatisfiable => Satisfiability::missing(Missing::Unsatisfiable),
+            Clause::Trivial | Clause::TxTemplate(_) => Satisfiability::satisfied(),
+            Clause::Key(k) if self.keys.contains(k) => Satisfiability::satisfied(),
+            Clause::Key(k) => Satisfiability::missing(Missing::Key(*k)),
+            Clause::After(n) => self.after(*n),
+            Clause::Older(n) => self.older(*n),
+            Clause::Sha256(h) if self.has_preimage(h) => Satisfiability::satisfied(),
+            Clause::Sha256(h) => Satisfiability::missing(Missing::Sha256(*h)),
+            Clause::Hash256(h) if self.has_preimage(h) => Satisfiability::satisfied(),
+            Clause::Hash256(h) => Satisfiability::missing(Missing::Hash256(*h)),
+            Clause::Ripemd160(h) if self.has_preimage(h) => Satisfiability::satisfied(),
+            Clause::Ripemd160(h) => Satisfiability::missing(Missing::Ripemd160(*h)),
+            Clause::Hash160(h) if self.has_preimage(h) => Satisfiability::satisfied(),
+            Clause::Hash160(h) => Satisfiability::missing(Missing::Hash160(*h)),
+            Clause::And(cs) => cs
+                .iter()
+                .map(|c| self.evaluate(c))
+                .fold(Satisfiability::satisfied(), Satisfiability::and),
+            Clause::Or(cs) => cs
+                .iter()
+                .map(|(_, c)| self.evaluate(c))
+                .min_by_key(Satisfiability::cost)
+                .unwrap_or_else(|| Satisfiability::missing(Missing::Unsatisfiable)),
+            Clause::Threshold(k, cs) => {
+                if *k > cs.len() {
+                    return Satisfiability::missing(Missing::Unsatisfiable);
+                }
+                let mut all: Vec<_> = cs.iter().map(|c| self.evaluate(c)).collect();
+                // stable, so ties keep the order they were written in
+                all.sort_by_key(Satisfiability::cost);
+                all.into_iter()
+                    .take(*k)
+                    .fold(Satisfiability::satisfied(), Satisfiability::and)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    fn key(b: u8) -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        XOnlyPublicKey::from_keypair(&KeyPair::from_seckey_slice(&secp, &[b; 32]).unwrap())
+    }
+    #[test]
+    fn vault_paths() {
+        let (hot, cold) = (key(1), key(2));
+        // the cold key spends at once, the hot key after 144 blocks
+        let vault = Clause::Or(vec![
+            (1, Clause::Key(cold)),
+            (1, Clause::And(vec![Clause::Key(hot), Clause::Older(144)])),
+        ]);
+        let state = SpendingState::default().with_key(hot).with_height(150);
+        let s = state.evaluate(&vault);
+        assert!(s.is_possible() && !s.is_satisfied());
+        assert_eq!(s.missing, vec![Missing::Confirmation].into_iter().collect());
+
+        let state = state.with_confirmation(100, 0);
+        let s = state.evaluate(&vault);
+        assert!(s.only_waiting());
+        assert_eq!(s.unlocks_at_height, Some(243));
+        assert!(state.with_height(243).evaluate(&vault).is_satisfied());
+
+        let s = SpendingState::default().evaluate(&vault);
+        assert_eq!(s.missing, vec![Missing::Key(cold)].into_iter().collect());
+        // waiting to confirm must not be preferred to a satisfied branch
+        let either = Clause::Or(vec![(1, Clause::Older(1)), (1, Clause::Key(hot))]);
+        assert!(SpendingState::default()
+            .with_key(hot)
+            .evaluate(&either)
+            .is_satisfied());
+    }
+    #[test]
+    fn preimages_thresholds_and_time() {
+        let h = sha256::Hash::hash(b"secret");
+        let c = Clause::Threshold(
+            2,
+            vec![
+                Clause::Sha256(h),
+                Clause::Key(key(3)),
+                Clause::After(1_600_000_000),
+            ],
+        );
+        let state = SpendingState::default().with_time(1_500_000_000);
+        let s = state.evaluate(&c);
+        assert_eq!(s.missing.len(), 2);
+        assert_eq!(s.unlocks_at_time, Some(1_600_000_001));
+        let s = state.with_preimage(b"secret".to_vec()).evaluate(&c);
+        assert!(s.only_waiting());
+        assert!(!SpendingState::default()
+            .evaluate(&Clause::Threshold(2, vec![Clause::Trivial]))
+            .is_possible());
+        assert!(SpendingState::default()
+            .evaluate(&Clause::TxTemplate(h))
+            .is_satisfied());
+    }
+}
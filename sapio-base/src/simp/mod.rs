@@ -8,6 +8,8 @@
 use schemars::JsonSchema;
 use serde::Deserialize;
 use serde::Serialize;
+pub mod op_return;
+pub use op_return::{OnChainSIMP, SIMPOpReturn, SIMPOpReturnError};
 
 /// Errors that may come up when working with SIMPs
 #[derive(Debug)]
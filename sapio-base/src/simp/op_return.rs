@@ -0,0 +1,193 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A standard envelope for committing SIMP metadata on-chain in an OP_RETURN,
+//! so indexers can recognize and decode Sapio generated transactions.
+//!
+//! Wire format (version 0):
+//! `"simp" || version:u8 || protocol:zigzag-LEB128 || payload`
+use super::SIMP;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::{Script, Transaction};
+
+/// Prefix of every SIMP OP_RETURN
+pub const SIMP_OP_RETURN_MAGIC: [u8; 4] = *b"simp";
+/// The envelope version produced by this library
+pub const SIMP_OP_RETURN_VERSION: u8 = 0;
+/// Maximum length of the data pushed, matching the limit Sapio enforces on
+/// OP_RETURN outputs
+pub const MAX_SIMP_OP_RETURN_LEN: usize = 40;
+
+/// Errors encoding or decoding a SIMP OP_RETURN
+#[derive(Debug)]
+pub enum SIMPOpReturnError {
+    /// The encoded envelope would be this many bytes, over
+    /// [`MAX_SIMP_OP_RETURN_LEN`]
+    TooLarge(usize),
+    /// The script is not a single push OP_RETURN
+    NotOpReturn,
+    /// The data does not start with [`SIMP_OP_RETURN_MAGIC`]
+    BadMagic,
+    /// The envelope version is not understood
+    UnknownVersion(u8),
+    /// The protocol number is truncated or overlong
+    BadProtocol,
+    /// The envelope is for a different SIMP than the one requested
+    WrongProtocol(i64),
+    /// The payload could not be decoded by the SIMP
+    BadPayload(String),
+}
+impl std::fmt::Display for SIMPOpReturnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for SIMPOpReturnError {}
+
+/// A decoded SIMP OP_RETURN envelope
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SIMPOpReturn {
+    /// the SIMP protocol number
+    pub protocol: i64,
+    /// the SIMP defined payload
+    pub payload: Vec<u8>,
+}
+
+fn write_protocol(v: &mut Vec<u8>, protocol: i64) {
+    let mut z = ((protocol << 1) ^ (protocol >> 63)) as u64;
+    loop {
+        let b = (z & 0x7f) as u8;
+        z >>= 7;
+        if z == 0 {
+            v.push(b);
+            return;
+        }
+        v.push(b | 0x80);
+    }
+}
+
+fn read_protocol(b: &[u8]) -> Result<(i64, usize), SIMPOpReturnError> {
+    let mut z: u64 = 0;
+    for (i, byte) in b.iter().enumerate().take(10) {
+        z |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((((z >> 1) as i64) ^ -((z & 1) as i64), i + 1));
+        }
+    }
+    Err(SIMPOpReturnError::BadProtocol)
+}
+
+impl SIMPOpReturn {
+    /// Create an envelope, checking that it fits in an OP_RETURN
+    pub fn new(protocol: i64, payload: Vec<u8>) -> Result<Self, SIMPOpReturnError> {
+        let s = SIMPOpReturn { protocol, payload };
+        let len = s.to_bytes().len();
+        if len > MAX_SIMP_OP_RETURN_LEN {
+            return Err(SIMPOpReturnError::TooLarge(len));
+        }
+        Ok(s)
+    }
+    /// The data to push in the OP_RETURN
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = SIMP_OP_RETURN_MAGIC.to_vec();
+        v.push(SIMP_OP_RETURN_VERSION);
+        write_protocol(&mut v, self.protocol);
+        v.extend_from_slice(&self.payload);
+        v
+    }
+    /// Parse data created by [`SIMPOpReturn::to_bytes`]
+    pub fn from_bytes(b: &[u8]) -> Result<Self, SIMPOpReturnError> {
+        if b.len() > MAX_SIMP_OP_RETURN_LEN {
+            return Err(SIMPOpReturnError::TooLarge(b.len()));
+        }
+        if b.len() < 5 || b[..4] != SIMP_OP_RETURN_MAGIC {
+            return Err(SIMPOpReturnError::BadMagic);
+        }
+        if b[4] != SIMP_OP_RETURN_VERSION {
+            return Err(SIMPOpReturnError::UnknownVersion(b[4]));
+        }
+        let (protocol, used) = read_protocol(&b[5..])?;
+        Ok(SIMPOpReturn {
+            protocol,
+            payload: b[5 + used..].to_vec(),
+        })
+    }
+    /// The OP_RETURN script committing to this envelope
+    pub fn to_script(&self) -> Script {
+        Script::new_op_return(&self.to_bytes())
+    }
+    /// Parse an OP_RETURN script created by [`SIMPOpReturn::to_script`]
+    pub fn from_script(s: &Script) -> Result<Self, SIMPOpReturnError> {
+        let mut instructions = s.instructions();
+        match instructions.next() {
+            Some(Ok(Instruction::Op(opcodes::all::OP_RETURN))) => (),
+            _ => return Err(SIMPOpReturnError::NotOpReturn),
+        }
+        match (instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::PushBytes(b))), None) => Self::from_bytes(b),
+            _ => Err(SIMPOpReturnError::NotOpReturn),
+        }
+    }
+    /// Every SIMP envelope found in a transaction's outputs
+    pub fn from_tx(tx: &Transaction) -> Vec<Self> {
+        tx.output
+            .iter()
+            .filter_map(|o| Self::from_script(&o.script_pubkey).ok())
+            .collect()
+    }
+}
+
+/// A SIMP which defines a compact binary encoding so that it may be committed
+/// to on-chain with a [`SIMPOpReturn`].
+pub trait OnChainSIMP: SIMP + Sized {
+    /// Encode the SIMP's on-chain payload
+    fn to_payload(&self) -> Vec<u8>;
+    /// Decode a payload created by [`OnChainSIMP::to_payload`]
+    fn from_payload(b: &[u8]) -> Result<Self, SIMPOpReturnError>;
+
+    /// Wrap this SIMP in an envelope, checking it fits
+    fn to_op_return(&self) -> Result<SIMPOpReturn, SIMPOpReturnError> {
+        SIMPOpReturn::new(Self::get_protocol_number(), self.to_payload())
+    }
+    /// Decode this SIMP from an envelope
+    fn from_op_return(s: &SIMPOpReturn) -> Result<Self, SIMPOpReturnError> {
+        if s.protocol != Self::get_protocol_number() {
+            return Err(SIMPOpReturnError::WrongProtocol(s.protocol));
+        }
+        Self::from_payload(&s.payload)
+    }
+    /// Find and decode this SIMP in a transaction's outputs, if present
+    fn from_tx(tx: &Transaction) -> Option<Result<Self, SIMPOpReturnError>> {
+        SIMPOpReturn::from_tx(tx)
+            .iter()
+            .find(|s| s.protocol == Self::get_protocol_number())
+            .map(Self::from_op_return)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn round_trip() {
+        for protocol in [0, 1, -1, -12345, i64::MAX, i64::MIN] {
+            let s = SIMPOpReturn {
+                protocol,
+                payload: vec![1, 2, 3],
+            };
+            assert_eq!(SIMPOpReturn::from_script(&s.to_script()).unwrap(), s);
+        }
+    }
+    #[test]
+    fn size_limit() {
+        assert!(SIMPOpReturn::new(-12345, vec![0; 32]).is_ok());
+        assert!(matches!(
+            SIMPOpReturn::new(-12345, vec![0; 33]),
+            Err(SIMPOpReturnError::TooLarge(41))
+        ));
+    }
+}
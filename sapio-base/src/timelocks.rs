@@ -7,6 +7,8 @@
 use super::Clause;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::default::Default;
@@ -19,6 +21,9 @@ pub enum LockTimeError {
     TimeTooFarInPast(Duration),
     HeightTooHigh(u32),
     UnknownSeqType(u32),
+    /// Height and time based locks of the same kind (absolute or relative)
+    /// can't both be satisfied by one transaction
+    MixedUnits(Clause, Clause),
 }
 
 /// Type Tags used for creating lock time variants. The module lets us keep them
@@ -279,3 +284,217 @@ mod trait_impls {
         }
     }
 }
+
+/// # Time Lock Kind
+/// Whether a lock is absolute or relative, and counts blocks or time. One
+/// transaction can only satisfy locks of one unit of each of absolute and
+/// relative.
+#[derive(
+    JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum TimeLockKind {
+    /// nLockTime as a height
+    AbsHeight,
+    /// nLockTime as a median time past
+    AbsTime,
+    /// nSequence as a number of blocks
+    RelHeight,
+    /// nSequence as a number of 512 second intervals
+    RelTime,
+}
+
+impl TimeLockKind {
+    /// The kind of `c`, if it is a timelock. A relative lock with the
+    /// disable flag set is not a lock.
+    pub fn of(c: &Clause) -> Option<Self> {
+        match c {
+            Clause::After(n) if *n < START_OF_TIME.get() => Some(TimeLockKind::AbsHeight),
+            Clause::After(_) => Some(TimeLockKind::AbsTime),
+            Clause::Older(n) if *n & (1 << 31) != 0 => None,
+            Clause::Older(n) if *n & (1 << 22) != 0 => Some(TimeLockKind::RelTime),
+            Clause::Older(_) => Some(TimeLockKind::RelHeight),
+            _ => None,
+        }
+    }
+    /// true if no transaction can satisfy locks of both kinds
+    pub fn conflicts_with(&self, other: &Self) -> bool {
+        use TimeLockKind::*;
+        matches!(
+            (self, other),
+            (AbsHeight, AbsTime)
+                | (AbsTime, AbsHeight)
+                | (RelHeight, RelTime)
+                | (RelTime, RelHeight)
+        )
+    }
+}
+
+impl AnyAbsTimeLock {
+    /// true if counted in blocks
+    pub fn is_height(&self) -> bool {
+        matches!(self, AnyAbsTimeLock::AH(_))
+    }
+    /// Compare locks of the same unit; None if they differ
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (AnyAbsTimeLock::AH(a), AnyAbsTimeLock::AH(b)) => Some(a.cmp(b)),
+            (AnyAbsTimeLock::AT(a), AnyAbsTimeLock::AT(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+    /// The lock which satisfies both `self` and `other`, the later of them
+    pub fn merge(self, other: Self) -> Result<Self, LockTimeError> {
+        match self.compare(&other) {
+            Some(Ordering::Less) => Ok(other),
+            Some(_) => Ok(self),
+            None => Err(LockTimeError::MixedUnits(self.into(), other.into())),
+        }
+    }
+    /// true if a transaction locked to this may be mined in the block after
+    /// a tip at `height` whose median time past is `mtp` (BIP-113)
+    pub fn is_satisfied_at(&self, height: u32, mtp: u32) -> bool {
+        match self {
+            AnyAbsTimeLock::AH(h) => height >= h.get(),
+            AnyAbsTimeLock::AT(t) => mtp > t.get(),
+        }
+    }
+}
+
+impl AnyRelTimeLock {
+    /// true if counted in blocks
+    pub fn is_height(&self) -> bool {
+        matches!(self, AnyRelTimeLock::RH(_))
+    }
+    /// Compare locks of the same unit; None if they differ
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (AnyRelTimeLock::RH(a), AnyRelTimeLock::RH(b)) => Some(a.cmp(b)),
+            (AnyRelTimeLock::RT(a), AnyRelTimeLock::RT(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+    /// The lock which satisfies both `self` and `other`, the longer of them
+    pub fn merge(self, other: Self) -> Result<Self, LockTimeError> {
+        match self.compare(&other) {
+            Some(Ordering::Less) => Ok(other),
+            Some(_) => Ok(self),
+            None => Err(LockTimeError::MixedUnits(self.into(), other.into())),
+        }
+    }
+    /// The time a time based lock waits for, measured in median time past
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            AnyRelTimeLock::RH(_) => None,
+            AnyRelTimeLock::RT(t) => Some(Duration::from_secs(512 * (t.get() & 0xffff) as u64)),
+        }
+    }
+}
+
+/// the locks every satisfaction of each of `cs` requires
+fn all_required<'a, I: Iterator<Item = &'a Clause>>(
+    cs: I,
+) -> Result<Vec<BTreeMap<TimeLockKind, Clause>>, LockTimeError> {
+    cs.map(required_locks).collect()
+}
+
+/// the first lock of each kind that every satisfaction of a clause requires
+fn required_locks(c: &Clause) -> Result<BTreeMap<TimeLockKind, Clause>, LockTimeError> {
+    let required = match c {
+        Clause::After(_) | Clause::Older(_) => TimeLockKind::of(c)
+            .map(|k| (k, c.clone()))
+            .into_iter()
+            .collect(),
+        Clause::And(cs) => {
+            let mut required = BTreeMap::new();
+            for r in all_required(cs.iter())? {
+                for (k, l) in r {
+                    required.entry(k).or_insert(l);
+                }
+            }
+            required
+        }
+        Clause::Or(cs) => at_least(all_required(cs.iter().map(|(_, c)| c))?, 1),
+        Clause::Threshold(k, cs) => at_least(all_required(cs.iter())?, *k),
+        _ => BTreeMap::new(),
+    };
+    let kinds: Vec<_> = required.iter().collect();
+    for (i, (k, a)) in kinds.iter().enumerate() {
+        if let Some((_, b)) = kinds[i + 1..].iter().find(|(k2, _)| k.conflicts_with(k2)) {
+            return Err(LockTimeError::MixedUnits((*a).clone(), (*b).clone()));
+        }
+    }
+    Ok(required)
+}
+
+/// the locks required whichever `k` of the children with `required` locks
+/// are satisfied: those which fewer than `k` children don't require
+fn at_least(
+    required: Vec<BTreeMap<TimeLockKind, Clause>>,
+    k: usize,
+) -> BTreeMap<TimeLockKind, Clause> {
+    let mut res = BTreeMap::new();
+    for r in required.iter() {
+        for (kind, l) in r {
+            let without = required.iter().filter(|r| !r.contains_key(kind)).count();
+            if without < k {
+                res.entry(*kind).or_insert_with(|| l.clone());
+            }
+        }
+    }
+    res
+}
+
+/// Check that no part of `c` requires locks of mixing units, e.g. an
+/// `And` of a height and a time based `After`, which could never be
+/// satisfied. Returns the first such pair found.
+pub fn check_timelock_units(c: &Clause) -> Result<(), LockTimeError> {
+    required_locks(c).map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn merge_and_compare() {
+        let h = |n: u32| AnyAbsTimeLock::from(AbsHeight::try_from(n).unwrap());
+        let t = |n: u32| AnyAbsTimeLock::from(AbsTime::try_from(n).unwrap());
+        assert_eq!(h(10).compare(&h(20)), Some(Ordering::Less));
+        assert_eq!(h(10).compare(&t(1_600_000_000)), None);
+        assert_eq!(h(10).merge(h(20)).unwrap().get(), 20);
+        assert!(h(10).merge(t(1_600_000_000)).is_err());
+        assert!(t(1_600_000_000).is_satisfied_at(0, 1_600_000_001));
+        assert!(!t(1_600_000_000).is_satisfied_at(0, 1_600_000_000));
+        let rt = AnyRelTimeLock::from(RelTime::from(2));
+        assert_eq!(rt.duration(), Some(Duration::from_secs(1024)));
+        assert!(rt.merge(RelHeight::from(2).into()).is_err());
+    }
+    #[test]
+    fn detects_mixed_units() {
+        let height = Clause::After(100);
+        let time = Clause::After(1_600_000_000);
+        let older = Clause::Older(10);
+        // either lock alone, or different kinds together, are fine
+        assert!(
+            check_timelock_units(&Clause::Or(vec![(1, height.clone()), (1, time.clone())])).is_ok()
+        );
+        assert!(check_timelock_units(&Clause::And(vec![height.clone(), older.clone()])).is_ok());
+        assert!(check_timelock_units(&Clause::Threshold(
+            2,
+            vec![height.clone(), time.clone(), Clause::Trivial]
+        ))
+        .is_ok());
+        // a branch needing both is found however deep it is
+        let dead = Clause::And(vec![
+            older.clone(),
+            Clause::Or(vec![
+                (1, height.clone()),
+                (1, Clause::And(vec![height.clone(), older])),
+            ]),
+            time.clone(),
+        ]);
+        match check_timelock_units(&Clause::Or(vec![(1, Clause::Trivial), (1, dead)])) {
+            Err(LockTimeError::MixedUnits(a, b)) => assert_eq!((a, b), (height, time)),
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -24,6 +24,37 @@ impl std::fmt::Display for TxIndexError {
     }
 }
 type Result<T> = std::result::Result<T, TxIndexError>;
+
+/// The most recent block an index knows about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    /// the height of the tip
+    pub height: u32,
+    /// the median time past of the tip
+    pub median_time_past: u32,
+}
+
+/// An unconfirmed transaction spending an outpoint
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MempoolSpend {
+    /// the spending transaction
+    pub txid: Txid,
+    /// the fee it pays, if known
+    pub fee: Option<bitcoin::Amount>,
+    /// its virtual size, if known
+    pub vsize: Option<u64>,
+}
+
+impl MempoolSpend {
+    /// The feerate in sats per vbyte, if the fee and size are known
+    pub fn feerate(&self) -> Option<f64> {
+        match (self.fee, self.vsize) {
+            (Some(fee), Some(vsize)) if vsize > 0 => Some(fee.as_sat() as f64 / vsize as f64),
+            _ => None,
+        }
+    }
+}
+
 pub trait TxIndex {
     fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>>;
     fn lookup_output(&self, b: &bitcoin::OutPoint) -> Result<bitcoin::TxOut> {
@@ -34,6 +65,36 @@ pub trait TxIndex {
             .ok_or(TxIndexError::IndexTooHigh(b.vout))
     }
     fn add_tx(&self, tx: Arc<bitcoin::Transaction>) -> Result<Txid>;
+    /// The height a transaction was confirmed at, or None if it is not known
+    /// to be confirmed.
+    fn lookup_confirmation_height(&self, _b: &Txid) -> Result<Option<u32>> {
+        Ok(None)
+    }
+    /// The current chain tip, or None if the index does not track the chain.
+    fn chain_tip(&self) -> Result<Option<ChainTip>> {
+        Ok(None)
+    }
+    /// The unconfirmed transaction spending an outpoint, or None if no
+    /// mempool transaction spends it (or the index does not track a mempool).
+    fn lookup_mempool_spend(&self, _o: &bitcoin::OutPoint) -> Result<Option<MempoolSpend>> {
+        Ok(None)
+    }
+    /// The transaction spending an outpoint, confirmed or not, or None if it
+    /// is unspent (or the index cannot tell). By default only mempool spends
+    /// are found.
+    fn lookup_spend(&self, o: &bitcoin::OutPoint) -> Result<Option<Txid>> {
+        Ok(self.lookup_mempool_spend(o)?.map(|s| s.txid))
+    }
+    /// Detect an unconfirmed spend of `o` by anything other than `expected`.
+    fn find_conflicting_spend(
+        &self,
+        o: &bitcoin::OutPoint,
+        expected: &Txid,
+    ) -> Result<Option<MempoolSpend>> {
+        Ok(self
+            .lookup_mempool_spend(o)?
+            .filter(|spend| spend.txid != *expected))
+    }
 }
 pub struct TxIndexLogger {
     map: Mutex<HashMap<Txid, Arc<bitcoin::Transaction>>>,
@@ -89,4 +150,16 @@ where
             self.cache.add_tx(tx)
         }
     }
+    fn lookup_confirmation_height(&self, b: &Txid) -> Result<Option<u32>> {
+        self.primary.lookup_confirmation_height(b)
+    }
+    fn chain_tip(&self) -> Result<Option<ChainTip>> {
+        self.primary.chain_tip()
+    }
+    fn lookup_mempool_spend(&self, o: &bitcoin::OutPoint) -> Result<Option<MempoolSpend>> {
+        self.primary.lookup_mempool_spend(o)
+    }
+    fn lookup_spend(&self, o: &bitcoin::OutPoint) -> Result<Option<Txid>> {
+        self.primary.lookup_spend(o)
+    }
 }
@@ -0,0 +1,245 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Stream a balance to a beneficiary, a fixed payment at a time
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::util::amountrange::dust_threshold;
+use sapio::*;
+use sapio_base::timelocks::RelHeight;
+use sapio_base::Clause;
+use sapio_macros::{compile_if, guard};
+use schemars::*;
+use serde::*;
+use std::convert::TryInto;
+
+/// # Annuity
+/// Pays `payment` to `beneficiary` every `interval` blocks, re-creating
+/// itself with what is left. Once too little is left for another payment,
+/// the rest is swept to `beneficiary`. The payer may cancel at any time,
+/// sweeping the balance back to `payer_address`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Annuity {
+    /// # Payer Key
+    /// The key authorized to cancel
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub payer: bitcoin::XOnlyPublicKey,
+    /// # Payer Address
+    /// Where the balance goes on cancellation
+    pub payer_address: bitcoin::Address,
+    /// # Beneficiary
+    pub beneficiary: bitcoin::Address,
+    /// # Payment
+    /// The amount paid every interval
+    pub payment: CoinAmount,
+    /// # Blocks between Payments
+    pub interval: RelHeight,
+    /// # Fees per Transaction
+    pub fees: CoinAmount,
+    /// # Balance
+    /// What is left to pay
+    pub balance: CoinAmount,
+}
+
+impl Annuity {
+    fn amounts(&self) -> Result<(Amount, Amount, Amount), CompilationError> {
+        Ok((
+            self.balance.try_into()?,
+            self.payment.try_into()?,
+            self.fees.try_into()?,
+        ))
+    }
+
+    /// The smallest amount the beneficiary may be paid
+    fn dust(&self) -> Amount {
+        dust_threshold(&self.beneficiary.script_pubkey())
+    }
+
+    /// The balance less one transaction's fees
+    fn less_fees(&self) -> Result<Amount, CompilationError> {
+        let (balance, _, fees) = self.amounts()?;
+        balance
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)
+    }
+
+    #[compile_if]
+    fn can_pay(self, _ctx: Context) {
+        match self.amounts() {
+            Ok((balance, payment, fees)) if balance >= payment + fees => {
+                ConditionalCompileType::Required
+            }
+            _ => ConditionalCompileType::Never,
+        }
+    }
+
+    #[compile_if]
+    fn must_sweep(self, _ctx: Context) {
+        match self.amounts() {
+            Ok((balance, payment, fees)) if balance >= payment + fees => {
+                ConditionalCompileType::Never
+            }
+            _ => ConditionalCompileType::Required,
+        }
+    }
+
+    /// Pay the next payment once `interval` blocks have passed. A remainder
+    /// too small to sweep later is paid along with it.
+    #[then(compile_if = "[Self::can_pay]")]
+    fn pay(self, ctx: sapio::Context) {
+        let (balance, payment, fees) = self.amounts()?;
+        let rest = balance - payment - fees;
+        let builder = ctx.template().set_sequence(0, self.interval.into())?;
+        if rest >= self.dust() + fees {
+            let next = Annuity {
+                balance: rest.into(),
+                ..self.clone()
+            };
+            builder
+                .add_output(
+                    payment,
+                    &Compiled::from_address(self.beneficiary.clone(), None),
+                    None,
+                )?
+                .add_output(rest, &next, None)?
+        } else {
+            builder.add_output(
+                payment + rest,
+                &Compiled::from_address(self.beneficiary.clone(), None),
+                None,
+            )?
+        }
+        .add_fees(fees)?
+        .into()
+    }
+
+    /// Sweep what is left, less than a payment, once `interval` blocks have
+    /// passed
+    #[then(compile_if = "[Self::must_sweep]")]
+    fn sweep(self, ctx: sapio::Context) {
+        let (_, _, fees) = self.amounts()?;
+        ctx.template()
+            .set_sequence(0, self.interval.into())?
+            .add_output(
+                self.less_fees()?,
+                &Compiled::from_address(self.beneficiary.clone(), None),
+                None,
+            )?
+            .add_fees(fees)?
+            .into()
+    }
+
+    /// The payer must sign
+    #[guard]
+    fn payer_signed(self, _ctx: Context) {
+        Clause::Key(self.payer)
+    }
+
+    /// Cancel, sweeping the balance back to the payer
+    #[then(guarded_by = "[Self::payer_signed]")]
+    fn cancel(self, ctx: sapio::Context) {
+        let (_, _, fees) = self.amounts()?;
+        ctx.template()
+            .add_output(
+                self.less_fees()?,
+                &Compiled::from_address(self.payer_address.clone(), None),
+                None,
+            )?
+            .add_fees(fees)?
+            .into()
+    }
+}
+
+impl Contract for Annuity {
+    declare! {then, Self::pay, Self::sweep, Self::cancel}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        let (_, payment, _) = self.amounts()?;
+        if payment < self.dust() {
+            return Err(CompilationError::TerminateWith(
+                "Payment Must Not be Dust".into(),
+            ));
+        }
+        if self.less_fees()? < self.dust() {
+            return Err(CompilationError::TerminateWith(
+                "Balance Too Small to Pay Out".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use bitcoin::Script;
+    use std::str::FromStr;
+
+    const FEES: u64 = 1_000;
+
+    fn annuity(balance: u64) -> Result<Annuity, Box<dyn std::error::Error>> {
+        Ok(Annuity {
+            payer: test_util::key(1),
+            payer_address: test_util::address(1),
+            beneficiary: bitcoin::Address::from_str(
+                "bcrt1qumrrqgt7e3a7damzm8x97m6sjs20u8hjw2hcjj",
+            )?,
+            payment: Amount::from_sat(10_000).into(),
+            interval: RelHeight::from(144),
+            fees: Amount::from_sat(FEES).into(),
+            balance: Amount::from_sat(balance).into(),
+        })
+    }
+
+    /// Compile `a`, then follow its payments to the end, returning the amount
+    /// each pays the beneficiary
+    fn payments(a: &Annuity) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+        let ctx = test_util::ctx(a.balance.try_into()?);
+        let beneficiary = a.beneficiary.script_pubkey();
+        let to_beneficiary =
+            |o: &template::Output| Script::from(o.contract.address.clone()) == beneficiary;
+        let mut o = a.compile(ctx)?;
+        let mut paid = vec![];
+        loop {
+            // cancelling pays the payer, so this is the payment or sweep
+            let t = o
+                .ctv_to_tx
+                .values()
+                .find(|t| t.outputs.iter().any(to_beneficiary))
+                .expect("every annuity pays the beneficiary")
+                .clone();
+            let (to, rest): (Vec<_>, Vec<_>) = t.outputs.into_iter().partition(to_beneficiary);
+            paid.push(to.iter().map(|o| o.amount.as_sat()).sum());
+            match rest.into_iter().next() {
+                Some(next) => o = next.contract,
+                None => return Ok(paid),
+            }
+        }
+    }
+
+    #[test]
+    fn pays_then_sweeps() -> Result<(), Box<dyn std::error::Error>> {
+        // 2_000 is left after three payments: too little to pay, enough to
+        // sweep
+        let paid = payments(&annuity(35_000)?)?;
+        assert_eq!(paid, vec![10_000, 10_000, 10_000, 1_000]);
+        // everything funded is paid out, less each transaction's fees
+        assert_eq!(paid.iter().sum::<u64>() + FEES * paid.len() as u64, 35_000);
+        Ok(())
+    }
+
+    #[test]
+    fn dust_remainder_paid_with_last_payment() -> Result<(), Box<dyn std::error::Error>> {
+        // 500 would be left after two payments, too little to sweep later
+        let paid = payments(&annuity(22_500)?)?;
+        assert_eq!(paid, vec![10_000, 10_500]);
+        assert_eq!(paid.iter().sum::<u64>() + FEES * paid.len() as u64, 22_500);
+        Ok(())
+    }
+}
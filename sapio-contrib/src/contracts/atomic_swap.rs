@@ -0,0 +1,262 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Both halves of a cross-chain atomic swap, built from [`Htlc`]s
+//!
+//! The initiator, who knows the preimage, locks the offer on one chain to
+//! the participant, and the participant locks the redeem on the other chain
+//! to the initiator under the same hash. The initiator claims the redeem,
+//! revealing the preimage, which lets the participant claim the offer.
+//!
+//! The participant must have time to claim the offer once the preimage is
+//! revealed, so the redeem must time out at least a safety margin before the
+//! offer does. Heights on different chains do not advance together, so
+//! timeouts in unix time are the safer choice.
+use super::htlc::{HashLock, Htlc};
+use bitcoin::util::amount::CoinAmount;
+use sapio::contract::CompilationError;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use schemars::*;
+use serde::*;
+use std::convert::TryFrom;
+
+/// # Swap Offer
+/// The initiator's side of the swap, fixed before a participant is found
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct SwapOffer {
+    /// # Initiator Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub initiator: bitcoin::XOnlyPublicKey,
+    /// # Hash Lock
+    /// The hash of the initiator's secret, shared by both halves
+    pub lock: HashLock,
+    /// # Amount Offered
+    /// What the initiator locks in the offer
+    pub offered: CoinAmount,
+    /// # Amount Asked
+    /// What the participant must lock in the redeem
+    pub asked: CoinAmount,
+    /// # Offer Timeout
+    /// When the initiator may take back the offer
+    pub offer_timeout: AnyAbsTimeLock,
+    /// # Redeem Timeout
+    /// When the participant may take back the redeem
+    pub redeem_timeout: AnyAbsTimeLock,
+    /// # Safety Margin
+    /// How long, in the timeouts' units, the offer must outlast the redeem
+    pub safety_margin: u32,
+    /// # Initiator Claim Address
+    /// Where the initiator's claim of the redeem pays, if bound
+    #[serde(default)]
+    pub claim_to: Option<bitcoin::Address>,
+    /// # Initiator Refund Address
+    /// Where the initiator's refund of the offer pays, if bound
+    #[serde(default)]
+    pub refund_to: Option<bitcoin::Address>,
+}
+
+/// # Counterparty Parameters
+/// What a participant must supply to take a [`SwapOffer`]. See
+/// [`counterparty_schema`].
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct CounterpartyParams {
+    /// # Participant Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub participant: bitcoin::XOnlyPublicKey,
+    /// # Participant Claim Address
+    /// Where the participant's claim of the offer pays, if bound
+    #[serde(default)]
+    pub claim_to: Option<bitcoin::Address>,
+    /// # Participant Refund Address
+    /// Where the participant's refund of the redeem pays, if bound
+    #[serde(default)]
+    pub refund_to: Option<bitcoin::Address>,
+}
+
+/// The JSON schema of the [`CounterpartyParams`] a participant must supply
+pub fn counterparty_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(CounterpartyParams)
+}
+
+/// # Atomic Swap
+/// An offer and the participant who took it
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct AtomicSwap {
+    /// # Offer
+    pub offer: SwapOffer,
+    /// # Counterparty
+    pub counterparty: CounterpartyParams,
+}
+
+/// # Swap Half
+/// Which half of a swap to create
+#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SwapHalf {
+    /// # Offer
+    /// The initiator's funds, claimable by the participant
+    Offer,
+    /// # Redeem
+    /// The participant's funds, claimable by the initiator
+    Redeem,
+}
+
+/// # Atomic Swap Half
+/// Selects one half of `swap`, to create on its chain
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct AtomicSwapHalf {
+    /// # Swap
+    pub swap: AtomicSwap,
+    /// # Half
+    pub half: SwapHalf,
+}
+
+impl TryFrom<AtomicSwapHalf> for Htlc {
+    type Error = CompilationError;
+    fn try_from(h: AtomicSwapHalf) -> Result<Self, CompilationError> {
+        match h.half {
+            SwapHalf::Offer => h.swap.offer(),
+            SwapHalf::Redeem => h.swap.redeem(),
+        }
+    }
+}
+
+impl AtomicSwap {
+    /// Check that the redeem times out at least the safety margin before the
+    /// offer, in the same units
+    pub fn check_timeouts(&self) -> Result<(), CompilationError> {
+        let SwapOffer {
+            offer_timeout,
+            redeem_timeout,
+            safety_margin,
+            ..
+        } = self.offer;
+        if offer_timeout.compare(&redeem_timeout).is_none() {
+            return Err(CompilationError::TerminateWith(
+                "Offer and Redeem Timeouts Must Use the Same Units".into(),
+            ));
+        }
+        match redeem_timeout.get().checked_add(safety_margin) {
+            Some(earliest) if offer_timeout.get() >= earliest => Ok(()),
+            _ => Err(CompilationError::TerminateWith(format!(
+                "Offer Timeout {} Must be at Least {} After Redeem Timeout {}",
+                offer_timeout.get(),
+                safety_margin,
+                redeem_timeout.get()
+            ))),
+        }
+    }
+
+    /// The initiator's half, paying the participant
+    pub fn offer(&self) -> Result<Htlc, CompilationError> {
+        self.check_timeouts()?;
+        Ok(Htlc {
+            receiver: self.counterparty.participant,
+            sender: self.offer.initiator,
+            lock: self.offer.lock,
+            timeout: self.offer.offer_timeout,
+            amount: self.offer.offered,
+            fees: None,
+            claim_to: self.counterparty.claim_to.clone(),
+            refund_to: self.offer.refund_to.clone(),
+            preimage: None,
+        })
+    }
+
+    /// The participant's half, paying the initiator
+    pub fn redeem(&self) -> Result<Htlc, CompilationError> {
+        self.check_timeouts()?;
+        Ok(Htlc {
+            receiver: self.offer.initiator,
+            sender: self.counterparty.participant,
+            lock: self.offer.lock,
+            timeout: self.offer.redeem_timeout,
+            amount: self.offer.asked,
+            fees: None,
+            claim_to: self.offer.claim_to.clone(),
+            refund_to: self.counterparty.refund_to.clone(),
+            preimage: None,
+        })
+    }
+
+    /// Both halves of the swap, offer first
+    pub fn pair(&self) -> Result<(Htlc, Htlc), CompilationError> {
+        Ok((self.offer()?, self.redeem()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contracts::htlc::HashType;
+    use crate::test_util::{address, ctx, fees, key, paid_to};
+    use bitcoin::util::amount::Amount;
+    use sapio::contract::Compilable;
+    use sapio_base::timelocks::AbsTime;
+
+    fn time(t: u32) -> AnyAbsTimeLock {
+        AnyAbsTimeLock::AT(AbsTime::try_from(t).unwrap())
+    }
+
+    fn swap() -> AtomicSwap {
+        AtomicSwap {
+            offer: SwapOffer {
+                initiator: key(1),
+                lock: HashLock::of(HashType::Sha256, b"swap secret"),
+                offered: Amount::from_sat(100_000).into(),
+                asked: Amount::from_sat(200_000).into(),
+                offer_timeout: time(1_700_086_400),
+                redeem_timeout: time(1_700_000_000),
+                safety_margin: 86_400,
+                claim_to: Some(address(1)),
+                refund_to: Some(address(2)),
+            },
+            counterparty: CounterpartyParams {
+                participant: key(3),
+                claim_to: Some(address(3)),
+                refund_to: Some(address(4)),
+            },
+        }
+    }
+
+    #[test]
+    fn timeout_margin() -> Result<(), Box<dyn std::error::Error>> {
+        let mut swap = swap();
+        let (offer, redeem) = swap.pair()?;
+        assert_eq!(offer.lock, redeem.lock);
+        assert_eq!(offer.receiver, redeem.sender);
+        swap.offer.safety_margin += 1;
+        assert!(swap.pair().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn halves_pay_each_side() -> Result<(), Box<dyn std::error::Error>> {
+        let (offer, redeem) = swap().pair()?;
+        // each half is claimed by the other side, or refunded to its sender
+        for (half, amount, claim, refund) in [
+            (offer, 100_000, address(3), address(2)),
+            (redeem, 200_000, address(1), address(4)),
+        ]
+        .iter()
+        {
+            let compiled = half.compile(ctx(Amount::from_sat(*amount)))?;
+            let mut paid: Vec<_> = compiled
+                .ctv_to_tx
+                .values()
+                .map(|t| {
+                    assert_eq!(fees(t), Amount::from_sat(0));
+                    (paid_to(t, claim).as_sat(), paid_to(t, refund).as_sat())
+                })
+                .collect();
+            paid.sort();
+            assert_eq!(paid, vec![(0, *amount), (*amount, 0)]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A knowledge bounty, paid to whoever reveals the solution to a puzzle
+//!
+//! The solution is the preimages of every hash lock in the puzzle. Note
+//! that an unbound claim reveals the preimages in the mempool without
+//! committing to where the bounty goes, so anyone who sees it may replace
+//! it with a claim of their own. Binding the bounty to a claimer's key
+//! means only that claimer can use the solution.
+use super::htlc::HashLock;
+use bitcoin::util::amount::CoinAmount;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+
+/// # Knowledge Bounty
+/// Pays whoever reveals the preimage of every lock in `puzzle`, or, if
+/// `claimer` is set, only that key with the preimages. Once
+/// `reclaim_after` passes the sponsor may take the bounty back, so a
+/// solution must be claimed before then.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Bounty {
+    /// # Puzzle
+    /// The hash locks whose preimages together solve the puzzle
+    pub puzzle: Vec<HashLock>,
+    /// # Claimer Key
+    /// The only key which may claim, to keep the solution from being
+    /// sniped in the mempool
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "Option<bitcoin::hashes::sha256::Hash>")]
+    #[serde(default)]
+    pub claimer: Option<bitcoin::XOnlyPublicKey>,
+    /// # Sponsor Key
+    /// The key which may reclaim the bounty
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub sponsor: bitcoin::XOnlyPublicKey,
+    /// # Reclaim Timeout
+    pub reclaim_after: AnyAbsTimeLock,
+    /// # Amount
+    pub amount: CoinAmount,
+}
+
+impl Bounty {
+    /// Whether `preimages`, in the order of the puzzle's locks, solve it
+    pub fn is_solved_by(&self, preimages: &[&[u8]]) -> bool {
+        preimages.len() == self.puzzle.len()
+            && self
+                .puzzle
+                .iter()
+                .zip(preimages.iter())
+                .all(|(lock, preimage)| lock.is_opened_by(preimage))
+    }
+
+    /// Reveal every preimage, and sign with the claimer's key if bound
+    #[guard]
+    fn solved(self, _ctx: Context) {
+        Clause::And(
+            self.puzzle
+                .iter()
+                .map(HashLock::clause)
+                .chain(self.claimer.map(Clause::Key))
+                .collect(),
+        )
+    }
+
+    /// The sponsor may reclaim after the timeout
+    #[guard]
+    fn reclaim(self, _ctx: Context) {
+        Clause::And(vec![Clause::Key(self.sponsor), self.reclaim_after.into()])
+    }
+}
+
+impl Contract for Bounty {
+    declare! {finish, Self::solved, Self::reclaim}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.puzzle.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Puzzle Must Have at Least One Hash Lock".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::htlc::HashType;
+    use super::*;
+    use crate::test_util;
+    use sapio_base::timelocks::AbsHeight;
+    use std::convert::TryFrom;
+    #[test]
+    fn puzzle() -> Result<(), Box<dyn std::error::Error>> {
+        let mut bounty = Bounty {
+            puzzle: vec![
+                HashLock::of(HashType::Sha256, b"first"),
+                HashLock::of(HashType::Hash160, b"second"),
+            ],
+            claimer: None,
+            sponsor: test_util::key(1),
+            reclaim_after: AbsHeight::try_from(800_000u32).unwrap().into(),
+            amount: bitcoin::Amount::from_sat(100_000).into(),
+        };
+        assert!(bounty.is_solved_by(&[&b"first"[..], &b"second"[..]]));
+        assert!(!bounty.is_solved_by(&[&b"second"[..], &b"first"[..]]));
+        assert!(!bounty.is_solved_by(&[&b"first"[..]]));
+        // the bounty is claimed or reclaimed by signature, with no templates
+        let ctx = || test_util::ctx(bitcoin::Amount::from_sat(100_000));
+        assert!(bounty.compile(ctx())?.ctv_to_tx.is_empty());
+        let locks: Vec<Clause> = bounty.puzzle.iter().map(HashLock::clause).collect();
+        assert_eq!(bounty.guard_solved(ctx()), Clause::And(locks.clone()));
+        bounty.claimer = Some(test_util::key(2));
+        let mut bound = locks;
+        bound.push(Clause::Key(test_util::key(2)));
+        assert_eq!(bounty.guard_solved(ctx()), Clause::And(bound));
+        assert!(bounty.compile(ctx())?.ctv_to_tx.is_empty());
+        Ok(())
+    }
+}
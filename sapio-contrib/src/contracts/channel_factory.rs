@@ -0,0 +1,338 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A channel factory, which opens many two-party channels from one UTXO
+//!
+//! The factory's UTXO fans out, by CTV, into a 2-of-2 funding output for
+//! each channel, which the pair then run as a payment channel off chain.
+//! While everyone is online, all the participants together may close the
+//! factory however they like, or move the capacity between channels by
+//! rebalancing into a new factory. Any single channel may also be expanded
+//! on its own, leaving the others in a new factory; as with a payment pool,
+//! every order of expansions is precompiled, so factories should be small.
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::Clause;
+use sapio_macros::{actions, finish, guard, Contract};
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+
+/// # Channel
+/// A channel between `a` and `b`, and what each of them starts with
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct ChannelSpec {
+    /// # First Party
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub a: bitcoin::XOnlyPublicKey,
+    /// # Second Party
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub b: bitcoin::XOnlyPublicKey,
+    /// # First Party's Balance
+    pub a_balance: CoinAmount,
+    /// # Second Party's Balance
+    pub b_balance: CoinAmount,
+}
+
+impl ChannelSpec {
+    /// The channel's capacity
+    pub fn capacity(&self) -> Result<Amount, CompilationError> {
+        Amount::try_from(self.a_balance)?
+            .checked_add(self.b_balance.try_into()?)
+            .ok_or(CompilationError::TerminateCompilation)
+    }
+}
+
+/// # Channel Funding
+/// A channel's funding output, which both parties must sign to spend
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Contract)]
+pub struct ChannelFunding {
+    /// # Channel
+    pub channel: ChannelSpec,
+}
+
+#[actions]
+impl ChannelFunding {
+    #[finish]
+    fn both_signed(self, _ctx: Context) {
+        Clause::And(vec![
+            Clause::Key(self.channel.a),
+            Clause::Key(self.channel.b),
+        ])
+    }
+}
+
+/// # Channel Factory
+/// Opens every channel in `channels`. The factory holds the channels'
+/// capacity and `fees` for each channel, so that expanding the channels one
+/// at a time is always paid for.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct ChannelFactory {
+    /// # Channels
+    pub channels: Vec<ChannelSpec>,
+    /// # Fees per Transaction
+    pub fees: CoinAmount,
+    /// # Sequence
+    /// How many expansions or rebalances came before, so that each factory
+    /// is distinct from the one before
+    #[serde(default)]
+    pub sequence: u64,
+}
+
+impl ChannelFactory {
+    /// Every participant's key, each once
+    pub fn participants(&self) -> Vec<bitcoin::XOnlyPublicKey> {
+        let mut keys: Vec<bitcoin::XOnlyPublicKey> = vec![];
+        for c in self.channels.iter() {
+            for k in [c.a, c.b].iter() {
+                if !keys.contains(k) {
+                    keys.push(*k);
+                }
+            }
+        }
+        keys
+    }
+
+    /// The capacity of every channel together
+    pub fn capacity(&self) -> Result<Amount, CompilationError> {
+        let mut total = Amount::from_sat(0);
+        for c in self.channels.iter() {
+            total = total
+                .checked_add(c.capacity()?)
+                .ok_or(CompilationError::TerminateCompilation)?;
+        }
+        Ok(total)
+    }
+
+    /// The fees held for expanding every channel
+    pub fn reserve(&self) -> Result<Amount, CompilationError> {
+        Amount::try_from(self.fees)?
+            .checked_mul(self.channels.len() as u64)
+            .ok_or(CompilationError::TerminateCompilation)
+    }
+
+    /// What the factory holds: every channel's capacity and the reserve
+    pub fn total(&self) -> Result<Amount, CompilationError> {
+        self.capacity()?
+            .checked_add(self.reserve()?)
+            .ok_or(CompilationError::TerminateCompilation)
+    }
+
+    /// The factory left once channel `i` is expanded
+    pub fn without(&self, i: usize) -> ChannelFactory {
+        let mut channels = self.channels.clone();
+        channels.remove(i);
+        ChannelFactory {
+            channels,
+            fees: self.fees,
+            sequence: self.sequence + 1,
+        }
+    }
+
+    /// The transaction expanding channel `i` alone
+    fn expansion(&self, ctx: Context, i: usize) -> Result<Template, CompilationError> {
+        let channel = ChannelFunding {
+            channel: self.channels[i].clone(),
+        };
+        let mut builder = ctx
+            .template()
+            .add_output(channel.channel.capacity()?, &channel, None)?;
+        if self.channels.len() > 1 {
+            let rest = self.without(i);
+            builder = builder.add_output_cached(rest.total()?, &rest, None)?;
+        }
+        Ok(builder.add_fees(self.fees.try_into()?)?.into())
+    }
+
+    /// Every participant must sign
+    #[guard]
+    fn all_signed(self, _ctx: Context) {
+        Clause::And(self.participants().into_iter().map(Clause::Key).collect())
+    }
+
+    /// Open every channel at once, paying the whole reserve as fees
+    #[then]
+    fn expand_all(self, ctx: sapio::Context) {
+        let mut builder = ctx.template();
+        for c in self.channels.iter() {
+            let channel = ChannelFunding { channel: c.clone() };
+            builder = builder.add_output(c.capacity()?, &channel, None)?;
+        }
+        builder.add_fees(self.reserve()?)?.into()
+    }
+
+    /// Open any one channel, one transaction per channel
+    #[then]
+    fn expand_one(self, ctx: sapio::Context) {
+        let mut ctx = ctx;
+        let tmpls = (0..self.channels.len())
+            .map(|i| {
+                let c = ctx.derive_num(i as u64)?;
+                self.expansion(c, i)
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(tmpls.into_iter()))
+    }
+
+    /// Move capacity between channels, or change who has channels, into a
+    /// new factory
+    #[continuation(
+        guarded_by = "[Self::all_signed]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn rebalance(self, ctx: sapio::Context, o: Rebalance) {
+        if let Rebalance::Rebalance { channels } = o {
+            let next = ChannelFactory {
+                channels,
+                fees: self.fees,
+                sequence: self.sequence + 1,
+            };
+            next.validate(&ctx)?;
+            // The new factory's capacity and reserve come out of this one's,
+            // less the fees for this transaction
+            let fees: Amount = self.fees.try_into()?;
+            let available = self
+                .total()?
+                .checked_sub(fees)
+                .ok_or(CompilationError::OutOfFunds)?;
+            if next.total()? != available {
+                return Err(CompilationError::TerminateWith(
+                    "Rebalance Must Keep Every Sat Less One Transaction's Fees".into(),
+                ));
+            }
+            ctx.template()
+                .add_output(next.total()?, &next, None)?
+                .add_fees(fees)?
+                .into()
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <ChannelFactory as Contract>::StatefulArguments,
+) -> Result<Rebalance, CompilationError> {
+    Ok(k)
+}
+
+/// Updates to a ChannelFactory
+#[derive(Deserialize, JsonSchema)]
+pub enum Rebalance {
+    /// # Rebalance
+    Rebalance {
+        /// # New Channels
+        /// Every channel of the new factory, with its balances
+        channels: Vec<ChannelSpec>,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for Rebalance {
+    fn default() -> Self {
+        Rebalance::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for Rebalance {}
+
+impl Contract for ChannelFactory {
+    declare! {then, Self::expand_all, Self::expand_one}
+    declare! {finish, Self::all_signed}
+    declare! {updatable<Rebalance>, Self::rebalance}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.channels.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Factory Must Have at Least One Channel".into(),
+            ));
+        }
+        for (i, c) in self.channels.iter().enumerate() {
+            if c.a == c.b {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Channel {} Has the Same Key Twice",
+                    i
+                )));
+            }
+            if c.capacity()?.as_sat() == 0 {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Channel {} Has No Capacity",
+                    i
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    /// the amount of each of `t`'s outputs, and its fees
+    fn amounts(t: &Template) -> (Vec<u64>, u64) {
+        (
+            t.outputs.iter().map(|o| o.amount.as_sat()).collect(),
+            test_util::fees(t).as_sat(),
+        )
+    }
+
+    #[test]
+    fn expansions() -> Result<(), Box<dyn std::error::Error>> {
+        let channel = |a: u8, b: u8| ChannelSpec {
+            a: test_util::key(a),
+            b: test_util::key(b),
+            a_balance: Amount::from_sat(30_000).into(),
+            b_balance: Amount::from_sat(10_000).into(),
+        };
+        let factory = ChannelFactory {
+            channels: vec![channel(1, 2), channel(2, 3), channel(3, 1)],
+            fees: Amount::from_sat(500).into(),
+            sequence: 0,
+        };
+        assert_eq!(factory.participants().len(), 3);
+        assert_eq!(factory.total()?, Amount::from_sat(121_500));
+        let rest = factory.without(0);
+        assert_eq!(rest.total()?, Amount::from_sat(81_000));
+        factory.compile(test_util::ctx(factory.total()?))?;
+        let all = factory
+            .then_expand_all(test_util::ctx(factory.total()?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            all.iter().map(amounts).collect::<Vec<_>>(),
+            vec![(vec![40_000, 40_000, 40_000], 1_500)]
+        );
+        // each channel alone, with the others left in a smaller factory
+        let one = factory
+            .then_expand_one(test_util::ctx(factory.total()?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            one.iter().map(amounts).collect::<Vec<_>>(),
+            vec![(vec![40_000, 81_000], 500); 3]
+        );
+        // rebalancing keeps everything but one transaction's fees
+        let total = factory.total()?;
+        let rebalance = |channels| {
+            factory.continue_rebalance(test_util::ctx(total), Rebalance::Rebalance { channels })
+        };
+        let mut smaller = channel(1, 2);
+        smaller.b_balance = Amount::from_sat(9_500).into();
+        let next = rebalance(vec![smaller, channel(2, 3), channel(3, 1)])?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            next.iter().map(amounts).collect::<Vec<_>>(),
+            vec![(vec![121_000], 500)]
+        );
+        assert!(rebalance(factory.channels.clone()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A loan secured by on-chain collateral, with a price-oracle margin call
+use super::derivatives::{Oracle, Symbol};
+use bitcoin::util::amount::Amount;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use sapio_macros::guard;
+
+/// To set up a loan, pick the collateral, the maturity, and the price at
+/// which the oracle's attestation triggers a margin call.
+pub struct CollateralizedLoanArguments<'a> {
+    /// the borrower's key
+    pub borrower: bitcoin::XOnlyPublicKey,
+    /// the lender's key
+    pub lender: bitcoin::XOnlyPublicKey,
+    /// the collateral locked by the borrower
+    pub collateral: Amount,
+    /// after maturity, the lender may take the collateral
+    pub maturity: AnyAbsTimeLock,
+    /// the price oracle
+    pub oracle: &'a dyn Oracle,
+    /// the symbol the collateral is priced by
+    pub symbol: Symbol,
+    /// a price attested below this is under-collateralized
+    pub liquidation_price: i64,
+    /// where the collateral goes if the loan is liquidated
+    pub lender_payout: Compiled,
+    /// fees for the margin call, paid out of the collateral
+    pub fees: Amount,
+}
+
+impl<'a> From<CollateralizedLoanArguments<'a>> for CollateralizedLoan {
+    fn from(v: CollateralizedLoanArguments<'a>) -> Self {
+        let (under_collateralized, _) = v.oracle.get_key_lt_gte(&v.symbol, v.liquidation_price);
+        CollateralizedLoan {
+            borrower: v.borrower,
+            lender: v.lender,
+            collateral: v.collateral,
+            maturity: v.maturity,
+            under_collateralized,
+            lender_payout: v.lender_payout,
+            fees: v.fees,
+        }
+    }
+}
+
+/// A CollateralizedLoan holds the borrower's collateral until:
+/// - the loan is repaid, and both parties cooperatively close;
+/// - maturity passes without repayment, and the lender takes the collateral; or
+/// - the oracle attests the collateral's price has dropped below the
+///   liquidation price, and the collateral is sent to the lender's payout.
+pub struct CollateralizedLoan {
+    borrower: bitcoin::XOnlyPublicKey,
+    lender: bitcoin::XOnlyPublicKey,
+    collateral: Amount,
+    maturity: AnyAbsTimeLock,
+    under_collateralized: Clause,
+    lender_payout: Compiled,
+    fees: Amount,
+}
+
+impl CollateralizedLoan {
+    /// Both parties sign to release the collateral once repaid
+    #[guard]
+    fn cooperate(self, _ctx: Context) {
+        Clause::And(vec![Clause::Key(self.borrower), Clause::Key(self.lender)])
+    }
+    /// The lender may take the collateral after maturity
+    #[guard]
+    fn liquidate(self, _ctx: Context) {
+        Clause::And(vec![Clause::Key(self.lender), self.maturity.into()])
+    }
+    /// The oracle's attestation of under-collateralization
+    #[guard]
+    fn price_below_liquidation(self, _ctx: Context) {
+        self.under_collateralized.clone()
+    }
+    /// Send the collateral, less fees, to the lender on a margin call
+    #[then(guarded_by = "[Self::price_below_liquidation]")]
+    fn margin_call(self, ctx: sapio::Context) {
+        let payout = self
+            .collateral
+            .checked_sub(self.fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        ctx.template()
+            .add_output(payout, &self.lender_payout, None)?
+            .add_fees(self.fees)?
+            .into()
+    }
+}
+
+impl Contract for CollateralizedLoan {
+    declare! {then, Self::margin_call}
+    declare! {finish, Self::cooperate, Self::liquidate}
+    declare! {non updatable}
+
+    fn validate(&self, ctx: &Context) -> Result<(), CompilationError> {
+        if ctx.funds() < self.collateral {
+            return Err(CompilationError::OutOfFunds);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    use bitcoin::{Script, XOnlyPublicKey};
+    use sapio::contract::object::SupportedDescriptors;
+    use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::AbsHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn key(b: u8) -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        XOnlyPublicKey::from_keypair(&KeyPair::from_seckey_slice(&secp, &[b; 32]).unwrap())
+    }
+
+    fn ctx(amount: Amount) -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("loan").unwrap(),
+            Arc::new(Default::default()),
+        )
+    }
+
+    /// attests prices below the query with key 3, and above with key 4
+    struct TestOracle;
+    impl Oracle for TestOracle {
+        fn get_key_lt_gte(&self, _t: &Symbol, _price: i64) -> (Clause, Clause) {
+            (Clause::Key(key(3)), Clause::Key(key(4)))
+        }
+    }
+
+    #[test]
+    fn repay_default_and_margin_call() -> Result<(), Box<dyn std::error::Error>> {
+        let payout = bitcoin::Address::p2wsh(&Script::new(), bitcoin::Network::Regtest);
+        let collateral = Amount::from_sat(100_000);
+        let loan: CollateralizedLoan = CollateralizedLoanArguments {
+            borrower: key(1),
+            lender: key(2),
+            collateral,
+            maturity: AbsHeight::try_from(800_000).unwrap().into(),
+            oracle: &TestOracle,
+            symbol: "BTCUSD".into(),
+            liquidation_price: 20_000,
+            lender_payout: Compiled::from_address(payout.clone(), None),
+            fees: Amount::from_sat(1_000),
+        }
+        .into();
+        // repaying and defaulting are signed for, rather than templated
+        assert_eq!(
+            loan.guard_cooperate(ctx(collateral)),
+            Clause::And(vec![Clause::Key(key(1)), Clause::Key(key(2))])
+        );
+        assert_eq!(
+            loan.guard_liquidate(ctx(collateral)),
+            Clause::And(vec![
+                Clause::Key(key(2)),
+                AnyAbsTimeLock::from(AbsHeight::try_from(800_000).unwrap()).into()
+            ])
+        );
+        let compiled = loan.compile(ctx(collateral))?;
+        match &compiled.descriptor {
+            Some(SupportedDescriptors::XOnly(d)) => {
+                assert!(d.to_string().contains("after(800000)"))
+            }
+            _ => panic!("expected a taproot descriptor"),
+        }
+
+        // the margin call is the only template, paying the lender less fees
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        let margin_call = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(margin_call.outputs.len(), 1);
+        assert_eq!(margin_call.outputs[0].amount, Amount::from_sat(99_000));
+        assert_eq!(
+            Script::from(margin_call.outputs[0].contract.address.clone()),
+            payout.script_pubkey()
+        );
+        assert_eq!(margin_call.max, collateral);
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An assurance contract, which only pays out if it raises enough
+//!
+//! Pledges accumulate one at a time: each pledge spends the campaign into a
+//! new campaign holding one more pledge. The organizer signs each pledge
+//! transaction with `SIGHASH_ALL | SIGHASH_ANYONECANPAY`, so the pledger
+//! can add their own input to it (which also pays its fees). Once the goal
+//! is reached, the campaign pays the beneficiary. If it is not reached by
+//! the deadline, a [`TreePay`] refunds every pledge.
+use super::treepay::{Payment, TreePay};
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::util::amountrange::AmountF64;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use sapio_macros::{compile_if, guard};
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+
+/// # Crowdfund
+/// Raises `goal` for `beneficiary` by `deadline`, refunding every pledge
+/// if it falls short.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Crowdfund {
+    /// # Organizer Key
+    /// The key which signs pledge transactions
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub organizer: bitcoin::XOnlyPublicKey,
+    /// # Beneficiary
+    pub beneficiary: bitcoin::Address,
+    /// # Goal
+    pub goal: CoinAmount,
+    /// # Deadline
+    /// When pledges may be refunded if the goal was not reached
+    pub deadline: AnyAbsTimeLock,
+    /// # Pledges
+    /// Every pledge so far, with where to refund it
+    pub pledges: Vec<Payment>,
+    /// # Fee Reserve
+    /// Held beside the pledges to pay for the payout or the refund
+    pub fee_reserve: CoinAmount,
+    /// # Refund Tree Radix
+    pub radix: usize,
+}
+
+impl Crowdfund {
+    /// The total pledged so far
+    pub fn pledged(&self) -> Result<Amount, CompilationError> {
+        let mut total = Amount::from_sat(0);
+        for p in self.pledges.iter() {
+            total = total
+                .checked_add(p.amount.try_into()?)
+                .ok_or(CompilationError::TerminateCompilation)?;
+        }
+        Ok(total)
+    }
+
+    /// The campaign's value: the pledges and the fee reserve
+    pub fn total(&self) -> Result<Amount, CompilationError> {
+        self.pledged()?
+            .checked_add(self.fee_reserve.try_into()?)
+            .ok_or(CompilationError::TerminateCompilation)
+    }
+
+    fn reached(&self) -> bool {
+        match (self.pledged(), Amount::try_from(self.goal)) {
+            (Ok(pledged), Ok(goal)) => pledged >= goal,
+            _ => false,
+        }
+    }
+
+    #[compile_if]
+    fn goal_reached(self, _ctx: Context) {
+        if self.reached() {
+            ConditionalCompileType::Required
+        } else {
+            ConditionalCompileType::Never
+        }
+    }
+
+    #[compile_if]
+    fn goal_not_reached(self, _ctx: Context) {
+        if self.reached() {
+            ConditionalCompileType::Never
+        } else {
+            ConditionalCompileType::Required
+        }
+    }
+
+    #[guard]
+    fn organizer_signed(self, _ctx: Context) {
+        Clause::Key(self.organizer)
+    }
+
+    /// Pay everything pledged to the beneficiary
+    #[then(compile_if = "[Self::goal_reached]")]
+    fn payout(self, ctx: sapio::Context) {
+        ctx.template()
+            .add_output(
+                self.pledged()?,
+                &Compiled::from_address(self.beneficiary.clone(), None),
+                None,
+            )?
+            .add_fees(self.fee_reserve.try_into()?)?
+            .into()
+    }
+
+    /// Refund every pledge once the deadline has passed
+    #[then(compile_if = "[Self::goal_not_reached]")]
+    fn refund(self, ctx: sapio::Context) {
+        let refunds = TreePay::new(self.pledges.clone(), self.radix);
+        ctx.template()
+            .add_output(self.pledged()?, &refunds, None)?
+            .add_fees(self.fee_reserve.try_into()?)?
+            .set_lock_time(self.deadline)?
+            .into()
+    }
+
+    /// Add a pledge, which the pledger funds with an input of their own
+    #[continuation(
+        guarded_by = "[Self::organizer_signed]",
+        coerce_args = "default_coerce",
+        compile_if = "[Self::goal_not_reached]",
+        web_api,
+        sighash = "AllPlusAnyoneCanPay"
+    )]
+    fn add_pledge(self, ctx: sapio::Context, o: NewPledge) {
+        if let NewPledge::Pledge { amount, refund_to } = o {
+            let amount: Amount = amount.into();
+            let mut next = self.clone();
+            next.pledges.push(Payment {
+                amount: amount.into(),
+                address: refund_to,
+            });
+            ctx.template()
+                .add_sequence()
+                .add_amount(amount)
+                .add_output(next.total()?, &next, None)?
+                .into()
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <Crowdfund as Contract>::StatefulArguments,
+) -> Result<NewPledge, CompilationError> {
+    Ok(k)
+}
+
+/// A pledge to a Crowdfund
+#[derive(Deserialize, JsonSchema)]
+pub enum NewPledge {
+    /// # Pledge
+    Pledge {
+        /// # Amount
+        amount: AmountF64,
+        /// # Refund Address
+        /// Where the pledge is refunded if the goal is not reached
+        refund_to: bitcoin::Address,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for NewPledge {
+    fn default() -> Self {
+        NewPledge::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for NewPledge {}
+
+impl Contract for Crowdfund {
+    declare! {then, Self::payout, Self::refund}
+    declare! {updatable<NewPledge>, Self::add_pledge}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.pledges.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Crowdfund Must Start with a Pledge".into(),
+            ));
+        }
+        if self.radix < 2 {
+            return Err(CompilationError::TerminateWith(
+                "Radix Must be at Least 2".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use sapio_base::timelocks::AbsHeight;
+    #[test]
+    fn goal_tracking() -> Result<(), Box<dyn std::error::Error>> {
+        let pledge = |sats| Payment {
+            amount: Amount::from_sat(sats).into(),
+            address: test_util::address(2),
+        };
+        let mut c = Crowdfund {
+            organizer: test_util::key(1),
+            beneficiary: test_util::address(1),
+            goal: Amount::from_sat(50_000).into(),
+            deadline: AbsHeight::try_from(800_000u32).unwrap().into(),
+            pledges: vec![pledge(20_000), pledge(20_000)],
+            fee_reserve: Amount::from_sat(1_000).into(),
+            radix: 4,
+        };
+        assert_eq!(c.pledged()?, Amount::from_sat(40_000));
+        assert_eq!(c.total()?, Amount::from_sat(41_000));
+        assert!(!c.reached());
+        c.compile(test_util::ctx(c.total()?))?;
+        // short of the goal, the pledges are refunded after the deadline
+        let refund = c
+            .then_refund(test_util::ctx(c.total()?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(refund.len(), 1);
+        assert_eq!(refund[0].outputs[0].amount, Amount::from_sat(40_000));
+        assert_eq!(refund[0].tx.lock_time, 800_000);
+        assert_eq!(test_util::fees(&refund[0]), Amount::from_sat(1_000));
+        // a pledge is funded by the pledger's input, on top of the campaign
+        let added = c
+            .continue_add_pledge(
+                test_util::ctx(c.total()?),
+                NewPledge::Pledge {
+                    amount: Amount::from_sat(10_000).into(),
+                    refund_to: test_util::address(3),
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].outputs[0].amount, Amount::from_sat(51_000));
+        c.pledges.push(pledge(10_000));
+        assert!(c.reached());
+        c.compile(test_util::ctx(c.total()?))?;
+        let payout = c
+            .then_payout(test_util::ctx(c.total()?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(payout.len(), 1);
+        assert_eq!(
+            test_util::paid_to(&payout[0], &test_util::address(1)),
+            Amount::from_sat(50_000)
+        );
+        assert_eq!(test_util::fees(&payout[0]), Amount::from_sat(1_000));
+        Ok(())
+    }
+}
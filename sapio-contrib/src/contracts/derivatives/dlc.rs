@@ -7,13 +7,18 @@
 //! A collection of modules for creating derivative contracts with Sapio
 use bitcoin;
 
-use bitcoin::util::amount::Amount;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::util::amount::{Amount, CoinAmount};
 
 use bitcoin::XOnlyPublicKey;
 use contract::*;
 use sapio::template::Template;
 use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
 use sapio_base::Clause;
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
 use std::sync::Arc;
 
 struct Event(String);
@@ -204,10 +209,286 @@ impl From<StandardDLC> for DLCContract {
     }
 }
 
+/// # Event Descriptor
+/// The event an oracle will attest to, and its possible outcomes
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct EventDescriptor {
+    /// # Event ID
+    pub event_id: String,
+    /// # Outcomes
+    /// Every outcome the oracle may attest to, in order
+    pub outcomes: Vec<String>,
+}
+
+/// # Oracle Announcement
+/// An oracle's commitment to attest to an event with a given nonce
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct OracleAnnouncement {
+    /// # Oracle Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub oracle: XOnlyPublicKey,
+    /// # Nonce Point
+    /// The R the oracle will sign the outcome with
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub nonce: XOnlyPublicKey,
+    /// # Event
+    pub event: EventDescriptor,
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// The point with even y for `k`
+fn even_point(k: &XOnlyPublicKey) -> Result<bitcoin::secp256k1::PublicKey, CompilationError> {
+    let mut b = [2u8; 33];
+    b[1..].copy_from_slice(&k.serialize());
+    bitcoin::secp256k1::PublicKey::from_slice(&b[..])
+        .map_err(|_| CompilationError::TerminateWith("Invalid Oracle Key or Nonce".into()))
+}
+
+impl OracleAnnouncement {
+    /// The point whose discrete log is the oracle's BIP-340 signature of
+    /// `outcome`, `s*G = R + H(R || X || sha256(outcome))*X`. The signature
+    /// the oracle attests with is the key to this point.
+    pub fn attestation_point(&self, outcome: &str) -> Result<XOnlyPublicKey, CompilationError> {
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let msg = sha256::Hash::hash(outcome.as_bytes());
+        let e = tagged_hash(
+            "BIP0340/challenge",
+            &[&self.nonce.serialize(), &self.oracle.serialize(), &msg[..]],
+        );
+        let mut ex = even_point(&self.oracle)?;
+        ex.mul_assign(&secp, &e)
+            .map_err(|_| CompilationError::TerminateCompilation)?;
+        let s = ex
+            .combine(&even_point(&self.nonce)?)
+            .map_err(|_| CompilationError::TerminateCompilation)?;
+        Ok(XOnlyPublicKey::from(s))
+    }
+}
+
+/// # Curve Point
+/// What the offerer is paid at one outcome
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct CurvePoint {
+    /// # Outcome
+    /// The index of the outcome
+    pub outcome: usize,
+    /// # Paid to Offerer
+    pub to_offerer: CoinAmount,
+}
+
+/// # Payout Curve
+/// What the offerer is paid at each outcome. The accepter is paid the rest.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutCurve {
+    /// # Explicit
+    /// The payout at every outcome, in order
+    Explicit(Vec<CoinAmount>),
+    /// # Piecewise Linear
+    /// Payouts interpolated between points, which must be given in order
+    /// from the first outcome to the last
+    Linear(Vec<CurvePoint>),
+}
+
+impl PayoutCurve {
+    /// What the offerer is paid at each of `n` outcomes
+    pub fn to_offerer(&self, n: usize) -> Result<Vec<Amount>, CompilationError> {
+        let invalid =
+            |s: &str| CompilationError::TerminateWith(format!("Invalid Payout Curve: {}", s));
+        match self {
+            PayoutCurve::Explicit(v) => {
+                if v.len() != n {
+                    return Err(invalid("One Payout Required per Outcome"));
+                }
+                v.iter()
+                    .map(|a| (*a).try_into().map_err(Into::into))
+                    .collect()
+            }
+            PayoutCurve::Linear(points) => {
+                let points = points
+                    .iter()
+                    .map(|p| Ok((p.outcome, Amount::try_from(p.to_offerer)?.as_sat())))
+                    .collect::<Result<Vec<_>, CompilationError>>()?;
+                match (points.first(), points.last()) {
+                    (Some((0, _)), Some((last, _))) if *last + 1 == n => {}
+                    _ => return Err(invalid("Points Must Span Every Outcome")),
+                }
+                let mut payouts = Vec::with_capacity(n);
+                payouts.push(Amount::from_sat(points[0].1));
+                for w in points.windows(2) {
+                    let ((x0, y0), (x1, y1)) = (w[0], w[1]);
+                    if x1 <= x0 {
+                        return Err(invalid("Points Must be in Order"));
+                    }
+                    for x in x0 + 1..=x1 {
+                        let y = y0 as i128
+                            + (y1 as i128 - y0 as i128) * (x - x0) as i128 / (x1 - x0) as i128;
+                        payouts.push(Amount::from_sat(y as u64));
+                    }
+                }
+                Ok(payouts)
+            }
+        }
+    }
+}
+
+/// # Discreet Log Contract
+/// Settles between an offerer and an accepter on the outcome an oracle
+/// attests to. Each outcome has a CET (contract execution transaction)
+/// which either party may broadcast with the oracle's attestation, the way
+/// an adaptor signature is completed by it. If the oracle never attests,
+/// each party is refunded their collateral after `refund_after`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Dlc {
+    /// # Announcement
+    pub announcement: OracleAnnouncement,
+    /// # Payout Curve
+    pub payouts: PayoutCurve,
+    /// # Offerer Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub offerer: XOnlyPublicKey,
+    /// # Accepter Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub accepter: XOnlyPublicKey,
+    /// # Offerer Collateral
+    pub offerer_collateral: CoinAmount,
+    /// # Accepter Collateral
+    pub accepter_collateral: CoinAmount,
+    /// # Fees per Transaction
+    pub fees: CoinAmount,
+    /// # Refund Timeout
+    /// When the collateral may be refunded if the oracle has not attested
+    pub refund_after: AnyAbsTimeLock,
+}
+
+impl Dlc {
+    /// Both parties' collateral, less fees
+    fn payable(&self) -> Result<(Amount, Amount), CompilationError> {
+        let fees: Amount = self.fees.try_into()?;
+        let total = Amount::try_from(self.offerer_collateral)?
+            .checked_add(self.accepter_collateral.try_into()?)
+            .and_then(|t| t.checked_sub(fees))
+            .ok_or(CompilationError::OutOfFunds)?;
+        Ok((total, fees))
+    }
+
+    #[guard]
+    fn cooperate(self, _ctx: Context) {
+        Clause::And(vec![Clause::Key(self.offerer), Clause::Key(self.accepter)])
+    }
+
+    /// One CET per outcome, each guarded by the oracle's attestation of it
+    #[then]
+    fn settle(self, ctx: sapio::Context) {
+        let mut ctx = ctx;
+        let (total, fees) = self.payable()?;
+        let outcomes = &self.announcement.event.outcomes;
+        let to_offerer = self.payouts.to_offerer(outcomes.len())?;
+        let offerer = self
+            .offerer
+            .compile(ctx.derive_str(Arc::new("offerer".into()))?)?;
+        let accepter = self
+            .accepter
+            .compile(ctx.derive_str(Arc::new("accepter".into()))?)?;
+        let mut cets_ctx = ctx.derive_str(Arc::new("outcomes".into()))?;
+        let mut tmpls: Vec<Result<Template, CompilationError>> = vec![];
+        for (i, (outcome, paid)) in outcomes.iter().zip(to_offerer).enumerate() {
+            let rest = total
+                .checked_sub(paid)
+                .ok_or(CompilationError::OutOfFunds)?;
+            let attested = Clause::And(vec![
+                Clause::Key(self.announcement.attestation_point(outcome)?),
+                Clause::Threshold(
+                    1,
+                    vec![Clause::Key(self.offerer), Clause::Key(self.accepter)],
+                ),
+            ]);
+            let mut tmpl = cets_ctx
+                .derive_num(i as u64)?
+                .template()
+                .add_guard(attested)
+                .add_fees(fees)?;
+            if paid > Amount::from_sat(0) {
+                tmpl = tmpl.add_output(paid, &offerer, None)?;
+            }
+            if rest > Amount::from_sat(0) {
+                tmpl = tmpl.add_output(rest, &accepter, None)?;
+            }
+            tmpls.push(Ok(tmpl.into()));
+        }
+        Ok(Box::new(tmpls.into_iter()))
+    }
+
+    /// Refund each party's collateral if the oracle never attests. The
+    /// accepter's share pays the fees.
+    #[then]
+    fn refund(self, ctx: sapio::Context) {
+        let mut ctx = ctx;
+        let fees: Amount = self.fees.try_into()?;
+        let offerer_share: Amount = self.offerer_collateral.try_into()?;
+        let accepter_share = Amount::try_from(self.accepter_collateral)?
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        let offerer = self
+            .offerer
+            .compile(ctx.derive_str(Arc::new("offerer".into()))?)?;
+        let accepter = self
+            .accepter
+            .compile(ctx.derive_str(Arc::new("accepter".into()))?)?;
+        ctx.template()
+            .add_output(offerer_share, &offerer, None)?
+            .add_output(accepter_share, &accepter, None)?
+            .add_fees(fees)?
+            .set_lock_time(self.refund_after)?
+            .into()
+    }
+}
+
+impl Contract for Dlc {
+    declare! {then, Self::settle, Self::refund}
+    declare! {finish, Self::cooperate}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.announcement.event.outcomes.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Event Must Have at Least One Outcome".into(),
+            ));
+        }
+        let (total, _) = self.payable()?;
+        let to_offerer = self
+            .payouts
+            .to_offerer(self.announcement.event.outcomes.len())?;
+        if to_offerer.iter().any(|p| *p > total) {
+            return Err(CompilationError::TerminateWith(
+                "Payout Exceeds Collateral".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util;
+    use bitcoin::secp256k1::Secp256k1;
     use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::AbsHeight;
     use sapio_ctv_emulator_trait::CTVAvailable;
     use std::convert::TryFrom;
 
@@ -255,4 +536,71 @@ mod tests {
         );
         let _r = d.compile(ctx).unwrap();
     }
+
+    #[test]
+    fn create_announced_dlc() -> Result<(), Box<dyn std::error::Error>> {
+        let announcement = OracleAnnouncement {
+            oracle: test_util::key(1),
+            nonce: test_util::key(2),
+            event: EventDescriptor {
+                event_id: "coin flip".into(),
+                outcomes: vec!["heads".into(), "edge".into(), "tails".into()],
+            },
+        };
+        assert_ne!(
+            announcement.attestation_point("heads")?,
+            announcement.attestation_point("tails")?
+        );
+        let d = Dlc {
+            announcement,
+            payouts: PayoutCurve::Linear(vec![
+                CurvePoint {
+                    outcome: 0,
+                    to_offerer: Amount::from_sat(199_000).into(),
+                },
+                CurvePoint {
+                    outcome: 2,
+                    to_offerer: Amount::from_sat(0).into(),
+                },
+            ]),
+            offerer: test_util::key(3),
+            accepter: test_util::key(4),
+            offerer_collateral: Amount::from_sat(100_000).into(),
+            accepter_collateral: Amount::from_sat(100_000).into(),
+            fees: Amount::from_sat(1_000).into(),
+            refund_after: AnyAbsTimeLock::AH(AbsHeight::try_from(800_000u32)?),
+        };
+        assert_eq!(
+            d.payouts.to_offerer(3)?,
+            vec![
+                Amount::from_sat(199_000),
+                Amount::from_sat(99_500),
+                Amount::from_sat(0)
+            ]
+        );
+        d.compile(test_util::ctx(Amount::from_sat(200_000)))?;
+        let (offerer, accepter) = (test_util::key_address(3), test_util::key_address(4));
+        let paid = |t: Template| {
+            assert_eq!(test_util::fees(&t), Amount::from_sat(1_000));
+            (
+                test_util::paid_to(&t, &offerer).as_sat(),
+                test_util::paid_to(&t, &accepter).as_sat(),
+                t.tx.lock_time,
+            )
+        };
+        let cets = d
+            .then_settle(test_util::ctx(Amount::from_sat(200_000)))?
+            .map(|t| t.map(paid))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            cets,
+            vec![(199_000, 0, 0), (99_500, 99_500, 0), (0, 199_000, 0)]
+        );
+        let refund = d
+            .then_refund(test_util::ctx(Amount::from_sat(200_000)))?
+            .map(|t| t.map(paid))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(refund, vec![(100_000, 99_000, 800_000)]);
+        Ok(())
+    }
 }
@@ -0,0 +1,210 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fee and Hashrate Futures
+//!
+//! A future on the average feerate, or the difficulty, at a target height,
+//! settled as a [`Dlc`] on an oracle's attestation of it. The oracle
+//! attests to a bucket (by its lower bound) rather than the exact value, so
+//! there is one CET per bucket.
+use super::dlc::{Dlc, EventDescriptor, OracleAnnouncement, PayoutCurve};
+use bitcoin::util::amount::{Amount, CoinAmount};
+use bitcoin::XOnlyPublicKey;
+use sapio::contract::CompilationError;
+use sapio_base::timelocks::{AbsHeight, AnyAbsTimeLock};
+use schemars::*;
+use serde::*;
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+
+/// # Metric
+/// What the future settles on
+#[derive(JsonSchema, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeMetric {
+    /// # Average Feerate
+    /// In sats per vbyte, over the block at the target height
+    AverageFeerate,
+    /// # Difficulty
+    /// At the target height
+    Difficulty,
+}
+
+impl FeeMetric {
+    fn label(&self) -> &'static str {
+        match self {
+            FeeMetric::AverageFeerate => "feerate",
+            FeeMetric::Difficulty => "difficulty",
+        }
+    }
+}
+
+/// # Fee Future
+/// `long` gains `sats_per_unit` for each unit the metric settles above
+/// `strike`, and `short` for each unit below, up to the other's collateral.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct FeeFuture {
+    /// # Metric
+    pub metric: FeeMetric,
+    /// # Target Height
+    pub target_height: AbsHeight,
+    /// # Buckets
+    /// The lower bound of every bucket the oracle may attest to, ascending
+    pub buckets: Vec<u64>,
+    /// # Strike
+    pub strike: u64,
+    /// # Sats per Unit
+    /// What changes hands for each unit of the metric away from the strike
+    pub sats_per_unit: CoinAmount,
+    /// # Oracle Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub oracle: XOnlyPublicKey,
+    /// # Oracle Nonce
+    /// The R the oracle announced for this metric at this height
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub nonce: XOnlyPublicKey,
+    /// # Long Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub long: XOnlyPublicKey,
+    /// # Short Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub short: XOnlyPublicKey,
+    /// # Long Collateral
+    pub long_collateral: CoinAmount,
+    /// # Short Collateral
+    pub short_collateral: CoinAmount,
+    /// # Fees per Transaction
+    pub fees: CoinAmount,
+    /// # Refund Timeout
+    /// When the collateral may be refunded if the oracle has not attested,
+    /// which must be after the target height
+    pub refund_after: AnyAbsTimeLock,
+}
+
+impl FeeFuture {
+    /// The event the oracle attests to: which bucket the metric fell in
+    pub fn event(&self) -> EventDescriptor {
+        EventDescriptor {
+            event_id: format!("{}@{}", self.metric.label(), self.target_height.get()),
+            outcomes: self.buckets.iter().map(|b| b.to_string()).collect(),
+        }
+    }
+
+    /// What the long side is paid at each bucket
+    pub fn to_long(&self) -> Result<Vec<Amount>, CompilationError> {
+        let fees: Amount = self.fees.try_into()?;
+        let long: Amount = self.long_collateral.try_into()?;
+        let payable = long
+            .checked_add(self.short_collateral.try_into()?)
+            .and_then(|t| t.checked_sub(fees))
+            .ok_or(CompilationError::OutOfFunds)?;
+        let rate = Amount::try_from(self.sats_per_unit)?.as_sat() as i128;
+        Ok(self
+            .buckets
+            .iter()
+            .map(|b| {
+                let gain = (*b as i128 - self.strike as i128) * rate;
+                let paid = (long.as_sat() as i128 + gain).clamp(0, payable.as_sat() as i128);
+                Amount::from_sat(paid as u64)
+            })
+            .collect())
+    }
+}
+
+impl TryFrom<FeeFuture> for Dlc {
+    type Error = CompilationError;
+    fn try_from(f: FeeFuture) -> Result<Self, Self::Error> {
+        if f.buckets.is_empty() || f.buckets.windows(2).any(|w| w[1] <= w[0]) {
+            return Err(CompilationError::TerminateWith(
+                "Buckets Must be Given and Ascending".into(),
+            ));
+        }
+        let target: AnyAbsTimeLock = f.target_height.into();
+        if f.refund_after.compare(&target) == Some(Ordering::Less) {
+            return Err(CompilationError::TerminateWith(
+                "Refund Must Come After the Target Height".into(),
+            ));
+        }
+        let payouts = PayoutCurve::Explicit(f.to_long()?.into_iter().map(Into::into).collect());
+        Ok(Dlc {
+            announcement: OracleAnnouncement {
+                oracle: f.oracle,
+                nonce: f.nonce,
+                event: f.event(),
+            },
+            payouts,
+            offerer: f.long,
+            accepter: f.short,
+            offerer_collateral: f.long_collateral,
+            accepter_collateral: f.short_collateral,
+            fees: f.fees,
+            refund_after: f.refund_after,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use sapio::contract::Compilable;
+    use std::collections::BTreeSet;
+    #[test]
+    fn feerate_payouts() -> Result<(), Box<dyn std::error::Error>> {
+        let future = FeeFuture {
+            metric: FeeMetric::AverageFeerate,
+            target_height: AbsHeight::try_from(800_000u32).unwrap(),
+            buckets: vec![0, 10, 20, 30, 40, 50],
+            strike: 20,
+            sats_per_unit: Amount::from_sat(1_000).into(),
+            oracle: test_util::key(1),
+            nonce: test_util::key(2),
+            long: test_util::key(3),
+            short: test_util::key(4),
+            long_collateral: Amount::from_sat(20_000).into(),
+            short_collateral: Amount::from_sat(20_000).into(),
+            fees: Amount::from_sat(1_000).into(),
+            refund_after: AbsHeight::try_from(801_000u32).unwrap().into(),
+        };
+        let to_long: Vec<u64> = future.to_long()?.iter().map(|a| a.as_sat()).collect();
+        assert_eq!(to_long, vec![0, 10_000, 20_000, 30_000, 39_000, 39_000]);
+        assert_eq!(future.event().event_id, "feerate@800000");
+        let dlc = Dlc::try_from(future)?;
+        assert_eq!(dlc.announcement.event.outcomes.len(), 6);
+        // the CETs (the two capped buckets are the same transaction) and the
+        // refund, as (paid to long, paid to short, lock time)
+        let (long, short) = (test_util::key_address(3), test_util::key_address(4));
+        let paid: BTreeSet<(u64, u64, u32)> = dlc
+            .compile(test_util::ctx(Amount::from_sat(40_000)))?
+            .ctv_to_tx
+            .values()
+            .map(|t| {
+                assert_eq!(test_util::fees(t), Amount::from_sat(1_000));
+                (
+                    test_util::paid_to(t, &long).as_sat(),
+                    test_util::paid_to(t, &short).as_sat(),
+                    t.tx.lock_time,
+                )
+            })
+            .collect();
+        assert_eq!(
+            paid.into_iter().collect::<Vec<_>>(),
+            vec![
+                (0, 39_000, 0),
+                (10_000, 29_000, 0),
+                (20_000, 19_000, 0),
+                (20_000, 19_000, 801_000),
+                (30_000, 9_000, 0),
+                (39_000, 0, 0),
+            ]
+        );
+        Ok(())
+    }
+}
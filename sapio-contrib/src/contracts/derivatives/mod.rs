@@ -23,6 +23,7 @@ pub mod apis;
 pub mod call;
 pub mod dlc;
 pub mod exploding;
+pub mod fee_future;
 pub mod put;
 pub mod risk_reversal;
 
@@ -0,0 +1,314 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sell a UTXO at a price that declines on a schedule
+//!
+//! A sale at each step pays the seller that step's price and is locked
+//! until the step begins. The seller signs every step ahead of time with
+//! `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`, which commits only to the asset
+//! input and the seller's payment, so anyone may complete a sale by adding
+//! inputs for the price and an output taking the asset. Buyers wait for
+//! the price they are willing to pay, and the first to pay it wins.
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::timelocks::{AbsHeight, AnyAbsTimeLock};
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+
+/// # Auction Step
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct AuctionStep {
+    /// # Price
+    pub price: CoinAmount,
+    /// # Start
+    /// When the asset may be bought at this price
+    pub after: AnyAbsTimeLock,
+}
+
+/// # Auction Schedule
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum AuctionSchedule {
+    /// # Explicit
+    /// Every step, with prices decreasing and starts increasing
+    Explicit(Vec<AuctionStep>),
+    /// # Linear
+    /// `steps` prices from `start_price` down to `end_price`, one every
+    /// `interval` blocks from `start`
+    Linear {
+        /// # Starting Price
+        start_price: CoinAmount,
+        /// # Final Price
+        end_price: CoinAmount,
+        /// # Starting Height
+        start: AbsHeight,
+        /// # Blocks between Steps
+        interval: u32,
+        /// # Number of Steps
+        steps: u32,
+    },
+}
+
+impl AuctionSchedule {
+    /// Every step of the schedule, in order
+    pub fn steps(&self) -> Result<Vec<AuctionStep>, CompilationError> {
+        match self {
+            AuctionSchedule::Explicit(steps) => Ok(steps.clone()),
+            AuctionSchedule::Linear {
+                start_price,
+                end_price,
+                start,
+                interval,
+                steps,
+            } => {
+                let hi = Amount::try_from(*start_price)?.as_sat() as u128;
+                let lo = Amount::try_from(*end_price)?.as_sat() as u128;
+                let n = *steps as u128;
+                if n < 2 || lo > hi {
+                    return Err(CompilationError::TerminateWith(
+                        "Linear Schedule Needs 2 Steps and a Falling Price".into(),
+                    ));
+                }
+                (0..n)
+                    .map(|i| {
+                        let price = hi - (hi - lo) * i / (n - 1);
+                        let height = (i as u32)
+                            .checked_mul(*interval)
+                            .and_then(|h| h.checked_add(start.get()))
+                            .ok_or(CompilationError::TerminateCompilation)?;
+                        let after = AbsHeight::try_from(height)
+                            .map_err(|e| CompilationError::TerminateWith(e.to_string()))?;
+                        Ok(AuctionStep {
+                            price: Amount::from_sat(price as u64).into(),
+                            after: after.into(),
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// # Dutch Auction
+/// Sells the `asset` UTXO for a price declining on `schedule`. The seller
+/// may cancel, returning the asset to `seller_address`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct DutchAuction {
+    /// # Seller Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub seller: bitcoin::XOnlyPublicKey,
+    /// # Seller Address
+    /// Where the price, or the asset on cancellation, is paid
+    pub seller_address: bitcoin::Address,
+    /// # Asset Amount
+    /// The value of the UTXO for sale
+    pub asset: CoinAmount,
+    /// # Schedule
+    pub schedule: AuctionSchedule,
+    /// # Cancellation Fees
+    pub cancel_fees: CoinAmount,
+}
+
+impl DutchAuction {
+    /// The sale at `step`, which the buyer completes
+    fn sale(&self, ctx: Context, step: &AuctionStep) -> Result<Template, CompilationError> {
+        let price: Amount = step.price.try_into()?;
+        // The price comes from the buyer's inputs, and the asset's output is
+        // left for the buyer to add
+        ctx.template()
+            .add_amount(price)
+            .add_output(
+                price,
+                &Compiled::from_address(self.seller_address.clone(), None),
+                None,
+            )?
+            .set_lock_time(step.after)
+            .map(Into::into)
+    }
+
+    #[guard]
+    fn seller_signed(self, _ctx: Context) {
+        Clause::Key(self.seller)
+    }
+
+    /// The sales the seller signs: every step by default, or just the one
+    /// given
+    #[continuation(
+        guarded_by = "[Self::seller_signed]",
+        coerce_args = "default_coerce",
+        web_api,
+        sighash = "SinglePlusAnyoneCanPay"
+    )]
+    fn sell(self, ctx: sapio::Context, o: SellStep) {
+        let mut ctx = ctx;
+        let steps = self.schedule.steps()?;
+        let indexes: Vec<usize> = match o {
+            SellStep::Step { index } if index < steps.len() => vec![index],
+            SellStep::Step { index } => {
+                return Err(CompilationError::TerminateWith(format!(
+                    "No Auction Step {}",
+                    index
+                )))
+            }
+            SellStep::AllSteps {} => (0..steps.len()).collect(),
+        };
+        let tmpls = indexes
+            .into_iter()
+            .map(|i| {
+                let c = ctx.derive_num(i as u64)?;
+                self.sale(c, &steps[i])
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(tmpls.into_iter()))
+    }
+
+    /// Cancel the auction, returning the asset to the seller
+    #[then(guarded_by = "[Self::seller_signed]")]
+    fn cancel(self, ctx: sapio::Context) {
+        let fees: Amount = self.cancel_fees.try_into()?;
+        let rest = Amount::try_from(self.asset)?
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        ctx.template()
+            .add_output(
+                rest,
+                &Compiled::from_address(self.seller_address.clone(), None),
+                None,
+            )?
+            .add_fees(fees)?
+            .into()
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <DutchAuction as Contract>::StatefulArguments,
+) -> Result<SellStep, CompilationError> {
+    Ok(k)
+}
+
+/// Which sales of a DutchAuction to sign
+#[derive(Deserialize, JsonSchema)]
+pub enum SellStep {
+    /// # Step
+    Step {
+        /// # Step Index
+        index: usize,
+    },
+    /// # All Steps
+    AllSteps {},
+}
+impl Default for SellStep {
+    fn default() -> Self {
+        SellStep::AllSteps {}
+    }
+}
+impl StatefulArgumentsTrait for SellStep {}
+
+impl Contract for DutchAuction {
+    declare! {then, Self::cancel}
+    declare! {updatable<SellStep>, Self::sell}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        let steps = self.schedule.steps()?;
+        if steps.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Auction Must Have at Least One Step".into(),
+            ));
+        }
+        for w in steps.windows(2) {
+            if Amount::try_from(w[1].price)? > Amount::try_from(w[0].price)?
+                || w[1].after.compare(&w[0].after) != Some(Ordering::Greater)
+            {
+                return Err(CompilationError::TerminateWith(
+                    "Prices Must Not Rise and Steps Must Start in Order".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+
+    fn linear() -> AuctionSchedule {
+        AuctionSchedule::Linear {
+            start_price: Amount::from_sat(10_000).into(),
+            end_price: Amount::from_sat(4_000).into(),
+            start: AbsHeight::try_from(800_000u32).unwrap(),
+            interval: 144,
+            steps: 4,
+        }
+    }
+
+    #[test]
+    fn linear_schedule() -> Result<(), CompilationError> {
+        let steps = linear().steps()?;
+        let prices: Vec<u64> = steps
+            .iter()
+            .map(|s| Amount::try_from(s.price).unwrap().as_sat())
+            .collect();
+        assert_eq!(prices, vec![10_000, 8_000, 6_000, 4_000]);
+        assert_eq!(steps[3].after.get(), 800_432);
+        Ok(())
+    }
+
+    #[test]
+    fn sales_and_cancel_pay_seller() -> Result<(), Box<dyn std::error::Error>> {
+        let auction = DutchAuction {
+            seller: test_util::key(1),
+            seller_address: test_util::address(1),
+            asset: Amount::from_sat(50_000).into(),
+            schedule: linear(),
+            cancel_fees: Amount::from_sat(1_000).into(),
+        };
+        auction.compile(test_util::ctx(Amount::from_sat(50_000)))?;
+        // each sale pays the seller that step's price once the step begins
+        let sales = auction
+            .continue_sell(
+                test_util::ctx(Amount::from_sat(50_000)),
+                SellStep::AllSteps {},
+            )?
+            .map(|t| {
+                t.map(|t| {
+                    (
+                        test_util::paid_to(&t, &test_util::address(1)).as_sat(),
+                        t.tx.lock_time,
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            sales,
+            vec![
+                (10_000, 800_000),
+                (8_000, 800_144),
+                (6_000, 800_288),
+                (4_000, 800_432)
+            ]
+        );
+        let cancel = auction
+            .then_cancel(test_util::ctx(Amount::from_sat(50_000)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(cancel.len(), 1);
+        assert_eq!(
+            test_util::paid_to(&cancel[0], &test_util::address(1)),
+            Amount::from_sat(49_000)
+        );
+        assert_eq!(test_util::fees(&cancel[0]), Amount::from_sat(1_000));
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A Contract holding the reserves backing a federated (e.g., ecash) mint
+use super::treepay::Payment;
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::actions::ConditionalCompileType;
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::{AnyAbsTimeLock, AnyRelTimeLock};
+use sapio_base::Clause;
+use sapio_macros::{compile_if, guard};
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+
+/// # Key Rotation
+/// A scheduled hand-off of the reserve to a new federation key set.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct KeyRotation {
+    /// # Successor Keys
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+    pub keys: Vec<bitcoin::XOnlyPublicKey>,
+    /// # Successor Threshold
+    pub threshold: usize,
+    /// # Rotate At
+    /// The rotation may not happen before this time
+    pub rotate_at: AnyAbsTimeLock,
+}
+
+/// # Federated Mint Reserve
+/// Funds backing a federated mint. The federation spends with a threshold of
+/// its keys during normal operation. The reserve rotates into a successor
+/// federation on a fixed schedule, and if the federation goes dark, after
+/// `emergency_delay` the reserve is paid back to its users, in proportion to
+/// their `claims`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct FederatedMintReserve {
+    /// # Federation Keys
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+    pub keys: Vec<bitcoin::XOnlyPublicKey>,
+    /// # Federation Threshold
+    pub threshold: usize,
+    /// # Scheduled Rotations
+    /// Upcoming key rotations, soonest first
+    #[serde(default)]
+    pub rotations: Vec<KeyRotation>,
+    /// # Emergency Delay
+    /// How long the reserve must sit unspent before the emergency path opens
+    pub emergency_delay: AnyRelTimeLock,
+    /// # User Claims
+    /// What each user is owed if the federation stops operating. The reserve
+    /// is split in proportion to these, so fees paid by rotations come out of
+    /// every claim alike.
+    pub claims: Vec<Payment>,
+    /// # Fees per Transaction
+    /// Reserved for each rotation or emergency payout
+    pub fees: CoinAmount,
+    /// # Amount in Reserve
+    pub amount: CoinAmount,
+}
+
+impl FederatedMintReserve {
+    /// The reserve left once a transaction's fees are paid
+    fn less_fees(&self) -> Result<Amount, CompilationError> {
+        Amount::try_from(self.amount)?
+            .checked_sub(self.fees.try_into()?)
+            .ok_or(CompilationError::OutOfFunds)
+    }
+
+    /// Each claim's share of `funds`, with what rounding leaves over going
+    /// to the first claim
+    fn shares(&self, funds: Amount) -> Result<Vec<Amount>, CompilationError> {
+        let claims = self
+            .claims
+            .iter()
+            .map(|c| Ok(Amount::try_from(c.amount)?.as_sat() as u128))
+            .collect::<Result<Vec<u128>, CompilationError>>()?;
+        let total: u128 = claims.iter().sum();
+        if total == 0 {
+            return Err(CompilationError::TerminateWith(
+                "Claims Must Not Total Zero".into(),
+            ));
+        }
+        let mut shares: Vec<u64> = claims
+            .iter()
+            .map(|c| (c * funds.as_sat() as u128 / total) as u64)
+            .collect();
+        shares[0] += funds.as_sat() - shares.iter().sum::<u64>();
+        Ok(shares.into_iter().map(Amount::from_sat).collect())
+    }
+
+    #[guard]
+    fn federation_signed(self, _ctx: Context) {
+        Clause::Threshold(
+            self.threshold,
+            self.keys.iter().cloned().map(Clause::Key).collect(),
+        )
+    }
+
+    #[compile_if]
+    fn has_rotation(self, _ctx: Context) {
+        if self.rotations.is_empty() {
+            ConditionalCompileType::Never
+        } else {
+            ConditionalCompileType::Required
+        }
+    }
+
+    /// Move the reserve to the next federation once its rotation time has
+    /// passed. Anyone may broadcast this, so a rotation cannot be stalled by
+    /// the outgoing federation.
+    #[then(compile_if = "[Self::has_rotation]")]
+    fn rotate(self, ctx: sapio::Context) {
+        let next = &self.rotations[0];
+        let successor = FederatedMintReserve {
+            keys: next.keys.clone(),
+            threshold: next.threshold,
+            rotations: self.rotations[1..].to_vec(),
+            emergency_delay: self.emergency_delay,
+            claims: self.claims.clone(),
+            fees: self.fees,
+            amount: self.less_fees()?.into(),
+        };
+        ctx.template()
+            .add_output(self.less_fees()?, &successor, None)?
+            .add_fees(self.fees.try_into()?)?
+            .set_lock_time(next.rotate_at)?
+            .into()
+    }
+
+    /// Pay the reserve back to its users once it has been unspent for
+    /// `emergency_delay`.
+    #[then]
+    fn emergency(self, ctx: sapio::Context) {
+        let mut builder = ctx.template();
+        for (claim, share) in self.claims.iter().zip(self.shares(self.less_fees()?)?) {
+            builder = builder.add_output(
+                share,
+                &Compiled::from_address(claim.address.clone(), None),
+                None,
+            )?;
+        }
+        builder
+            .add_fees(self.fees.try_into()?)?
+            .set_sequence(0, self.emergency_delay)?
+            .into()
+    }
+}
+
+impl Contract for FederatedMintReserve {
+    declare! {then, Self::rotate, Self::emergency}
+    declare! {finish, Self::federation_signed}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        let sets = std::iter::once((self.threshold, self.keys.len()))
+            .chain(self.rotations.iter().map(|r| (r.threshold, r.keys.len())));
+        for (threshold, n_keys) in sets {
+            if threshold == 0 || threshold > n_keys {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Threshold {} Invalid for {} Keys",
+                    threshold, n_keys
+                )));
+            }
+        }
+        if self.less_fees()? <= Amount::from_sat(0) {
+            return Err(CompilationError::TerminateWith(
+                "Reserve Too Small to Pay Out".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    use bitcoin::{Script, XOnlyPublicKey};
+    use sapio::template::Template;
+    use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::{AbsHeight, RelHeight};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    const FEES: u64 = 1_000;
+
+    fn key(b: u8) -> XOnlyPublicKey {
+        let secp = Secp256k1::new();
+        XOnlyPublicKey::from_keypair(&KeyPair::from_seckey_slice(&secp, &[b; 32]).unwrap())
+    }
+
+    fn address(b: u8) -> bitcoin::Address {
+        bitcoin::Address::p2wsh(&Script::from(vec![b]), bitcoin::Network::Regtest)
+    }
+
+    fn ctx(amount: Amount) -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("mint").unwrap(),
+            Arc::new(Default::default()),
+        )
+    }
+
+    /// the fees reserved by `t`
+    fn fees(t: &Template) -> u64 {
+        (t.max - t.total_amount()).as_sat()
+    }
+
+    /// what `t` pays each of the claims' addresses, and the fees it reserves
+    fn payouts(t: &Template) -> (Vec<u64>, u64) {
+        let paid = (1..=2)
+            .map(|b| {
+                t.outputs
+                    .iter()
+                    .filter(|o| {
+                        Script::from(o.contract.address.clone()) == address(b).script_pubkey()
+                    })
+                    .map(|o| o.amount.as_sat())
+                    .sum()
+            })
+            .collect();
+        (paid, fees(t))
+    }
+
+    #[test]
+    fn rotation_and_emergency_reserve_fees() -> Result<(), Box<dyn std::error::Error>> {
+        let reserve = FederatedMintReserve {
+            keys: vec![key(1), key(2), key(3)],
+            threshold: 2,
+            rotations: vec![KeyRotation {
+                keys: vec![key(4), key(5)],
+                threshold: 2,
+                rotate_at: AbsHeight::try_from(800_000).unwrap().into(),
+            }],
+            emergency_delay: RelHeight::from(4_320).into(),
+            claims: vec![
+                Payment {
+                    amount: Amount::from_sat(30_000).into(),
+                    address: address(1),
+                },
+                Payment {
+                    amount: Amount::from_sat(10_000).into(),
+                    address: address(2),
+                },
+            ],
+            fees: Amount::from_sat(FEES).into(),
+            amount: Amount::from_sat(41_000).into(),
+        };
+        let compiled = reserve.compile(ctx(Amount::from_sat(41_000)))?;
+        assert_eq!(compiled.ctv_to_tx.len(), 2);
+        let (emergency, rotate): (Vec<&Template>, Vec<&Template>) = compiled
+            .ctv_to_tx
+            .values()
+            .partition(|t| t.outputs.len() == 2);
+        assert_eq!(payouts(emergency[0]), (vec![30_000, 10_000], FEES));
+
+        let rotate = rotate[0];
+        assert_eq!(fees(rotate), FEES);
+        assert_eq!(rotate.outputs.len(), 1);
+        assert_eq!(rotate.outputs[0].amount, Amount::from_sat(40_000));
+        // the successor pays the users its smaller reserve, pro rata
+        let successor = &rotate.outputs[0].contract;
+        assert_eq!(successor.ctv_to_tx.len(), 1);
+        let emergency = successor.ctv_to_tx.values().next().unwrap();
+        assert_eq!(payouts(emergency), (vec![29_250, 9_750], FEES));
+        Ok(())
+    }
+}
@@ -127,6 +127,24 @@ where
     declare! {then, Self::begin_recovery}
     declare! {finish, Self::normal_signed, Self::finish_recovery}
     declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.thresh_normal == 0 || self.thresh_normal > self.keys.len() {
+            return Err(CompilationError::TerminateWith(format!(
+                "Normal Threshold {} Invalid for {} Keys",
+                self.thresh_normal,
+                self.keys.len()
+            )));
+        }
+        if self.thresh_recovery == 0 || self.thresh_recovery > self.keys_recovery.len() {
+            return Err(CompilationError::TerminateWith(format!(
+                "Recovery Threshold {} Invalid for {} Keys",
+                self.thresh_recovery,
+                self.keys_recovery.len()
+            )));
+        }
+        Ok(())
+    }
 }
 
 /// Type Alias for the state to start FederatedPegIn from.
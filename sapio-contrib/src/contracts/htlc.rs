@@ -0,0 +1,297 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hashed Timelock Contracts, for composing into other contracts
+//!
+//! An [`Htlc`] pays the receiver once they reveal the preimage of a hash, or
+//! refunds the sender once a timeout passes. Either party may spend with
+//! their key alone, or the HTLC may bind the claim and refund to addresses.
+//! A bound claim records its preimage so that binding the claim fills in
+//! the PSBT preimage fields, ready for finalization.
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use sapio_macros::{compile_if, guard};
+use schemars::*;
+use serde::*;
+use std::convert::TryInto;
+
+/// # Hash Type
+/// Which hash function a hash lock uses
+#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashType {
+    /// # SHA256
+    Sha256,
+    /// # Double SHA256
+    Hash256,
+    /// # RIPEMD160
+    Ripemd160,
+    /// # RIPEMD160 of SHA256
+    Hash160,
+}
+
+/// # Hash Lock
+/// A hash whose preimage must be revealed
+#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashLock {
+    /// # SHA256
+    Sha256(sha256::Hash),
+    /// # Double SHA256
+    Hash256(sha256d::Hash),
+    /// # RIPEMD160
+    Ripemd160(ripemd160::Hash),
+    /// # RIPEMD160 of SHA256
+    Hash160(hash160::Hash),
+}
+
+impl HashLock {
+    /// The lock `preimage` opens, hashing with `typ`
+    pub fn of(typ: HashType, preimage: &[u8]) -> Self {
+        match typ {
+            HashType::Sha256 => HashLock::Sha256(sha256::Hash::hash(preimage)),
+            HashType::Hash256 => HashLock::Hash256(sha256d::Hash::hash(preimage)),
+            HashType::Ripemd160 => HashLock::Ripemd160(ripemd160::Hash::hash(preimage)),
+            HashType::Hash160 => HashLock::Hash160(hash160::Hash::hash(preimage)),
+        }
+    }
+    /// The hash function this lock uses
+    pub fn hash_type(&self) -> HashType {
+        match self {
+            HashLock::Sha256(_) => HashType::Sha256,
+            HashLock::Hash256(_) => HashType::Hash256,
+            HashLock::Ripemd160(_) => HashType::Ripemd160,
+            HashLock::Hash160(_) => HashType::Hash160,
+        }
+    }
+    /// Whether `preimage` opens this lock
+    pub fn is_opened_by(&self, preimage: &[u8]) -> bool {
+        HashLock::of(self.hash_type(), preimage) == *self
+    }
+    /// The clause requiring the preimage
+    pub fn clause(&self) -> Clause {
+        match *self {
+            HashLock::Sha256(h) => Clause::Sha256(h),
+            HashLock::Hash256(h) => Clause::Hash256(h),
+            HashLock::Ripemd160(h) => Clause::Ripemd160(h),
+            HashLock::Hash160(h) => Clause::Hash160(h),
+        }
+    }
+}
+
+/// # Hashed Timelock Contract
+/// Pays `receiver` if they reveal the preimage of `lock`, or refunds
+/// `sender` once `timeout` has passed.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Htlc {
+    /// # Receiver Key
+    /// The key which may claim with the preimage
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub receiver: bitcoin::XOnlyPublicKey,
+    /// # Sender Key
+    /// The key which may take a refund after the timeout
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub sender: bitcoin::XOnlyPublicKey,
+    /// # Hash Lock
+    pub lock: HashLock,
+    /// # Timeout
+    /// When the sender may take a refund
+    pub timeout: AnyAbsTimeLock,
+    /// # Amount
+    pub amount: CoinAmount,
+    /// # Fees
+    /// The fees the bound claim and refund pay, if any
+    #[serde(default)]
+    pub fees: Option<CoinAmount>,
+    /// # Claim Address
+    /// Bind the receiver's claim to pay this address
+    #[serde(default)]
+    pub claim_to: Option<bitcoin::Address>,
+    /// # Refund Address
+    /// Bind the sender's refund to pay this address
+    #[serde(default)]
+    pub refund_to: Option<bitcoin::Address>,
+    /// # Preimage
+    /// The hex encoded preimage, if known, to put in the bound claim's PSBT
+    #[serde(default)]
+    pub preimage: Option<String>,
+}
+
+impl Htlc {
+    /// The clause the receiver claims with, to use in other contracts' guards
+    pub fn claim_clause(&self) -> Clause {
+        Clause::And(vec![Clause::Key(self.receiver), self.lock.clause()])
+    }
+    /// The clause the sender is refunded with, to use in other contracts'
+    /// guards
+    pub fn refund_clause(&self) -> Clause {
+        Clause::And(vec![Clause::Key(self.sender), self.timeout.into()])
+    }
+
+    fn preimage_bytes(&self) -> Result<Option<Vec<u8>>, CompilationError> {
+        self.preimage
+            .as_ref()
+            .map(|p| {
+                Vec::from_hex(p)
+                    .map_err(|_| CompilationError::TerminateWith("Preimage Must be Hex".into()))
+            })
+            .transpose()
+    }
+
+    /// The amount the bound transactions pay, and their fees
+    fn less_fees(&self) -> Result<(Amount, Amount), CompilationError> {
+        let amount: Amount = self.amount.try_into()?;
+        let fees: Amount = match self.fees {
+            Some(f) => f.try_into()?,
+            None => Amount::from_sat(0),
+        };
+        let rest = amount
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        Ok((rest, fees))
+    }
+
+    #[guard]
+    fn claimable(self, _ctx: Context) {
+        self.claim_clause()
+    }
+
+    #[guard]
+    fn refundable(self, _ctx: Context) {
+        self.refund_clause()
+    }
+
+    #[compile_if]
+    fn binds_claim(self, _ctx: Context) {
+        if self.claim_to.is_some() {
+            ConditionalCompileType::Required
+        } else {
+            ConditionalCompileType::Never
+        }
+    }
+
+    #[compile_if]
+    fn binds_refund(self, _ctx: Context) {
+        if self.refund_to.is_some() {
+            ConditionalCompileType::Required
+        } else {
+            ConditionalCompileType::Never
+        }
+    }
+
+    /// The receiver claims to `claim_to`
+    #[then(compile_if = "[Self::binds_claim]", guarded_by = "[Self::claimable]")]
+    fn claim(self, ctx: sapio::Context) {
+        let (rest, fees) = self.less_fees()?;
+        let to = self
+            .claim_to
+            .clone()
+            .ok_or(CompilationError::MissingTemplates)?;
+        let mut builder = ctx
+            .template()
+            .add_output(rest, &Compiled::from_address(to, None), None)?
+            .add_fees(fees)?;
+        if let Some(preimage) = self.preimage_bytes()? {
+            builder = builder.add_preimage(&preimage)?;
+        }
+        builder.into()
+    }
+
+    /// The sender is refunded to `refund_to` after the timeout
+    #[then(compile_if = "[Self::binds_refund]", guarded_by = "[Self::refundable]")]
+    fn refund(self, ctx: sapio::Context) {
+        let (rest, fees) = self.less_fees()?;
+        let to = self
+            .refund_to
+            .clone()
+            .ok_or(CompilationError::MissingTemplates)?;
+        ctx.template()
+            .add_output(rest, &Compiled::from_address(to, None), None)?
+            .add_fees(fees)?
+            .set_lock_time(self.timeout)?
+            .into()
+    }
+}
+
+impl Contract for Htlc {
+    declare! {then, Self::claim, Self::refund}
+    declare! {finish, Self::claimable, Self::refundable}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if let Some(preimage) = self.preimage_bytes()? {
+            if !self.lock.is_opened_by(&preimage) {
+                return Err(CompilationError::TerminateWith(
+                    "Preimage Does Not Open Hash Lock".into(),
+                ));
+            }
+        }
+        self.less_fees().map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{address, ctx, fees, key, paid_to};
+    use bitcoin::hashes::hex::ToHex;
+    use sapio_base::timelocks::AbsHeight;
+    use std::convert::TryFrom;
+    #[test]
+    fn hash_types() {
+        let secret = b"htlc secret";
+        for typ in [
+            HashType::Sha256,
+            HashType::Hash256,
+            HashType::Ripemd160,
+            HashType::Hash160,
+        ] {
+            let lock = HashLock::of(typ, secret);
+            assert_eq!(lock.hash_type(), typ);
+            assert!(lock.is_opened_by(secret));
+            assert!(!lock.is_opened_by(b"wrong"));
+        }
+    }
+
+    #[test]
+    fn bound_claim_and_refund() -> Result<(), Box<dyn std::error::Error>> {
+        let htlc = Htlc {
+            receiver: key(1),
+            sender: key(2),
+            lock: HashLock::of(HashType::Sha256, b"htlc secret"),
+            timeout: AbsHeight::try_from(800_000).unwrap().into(),
+            amount: Amount::from_sat(10_000).into(),
+            fees: Some(Amount::from_sat(500).into()),
+            claim_to: Some(address(1)),
+            refund_to: Some(address(2)),
+            preimage: Some(b"htlc secret".to_hex()),
+        };
+        let compiled = htlc.compile(ctx(Amount::from_sat(10_000)))?;
+        // (paid to the receiver, paid to the sender, fees, lock time)
+        let mut spends: Vec<_> = compiled
+            .ctv_to_tx
+            .values()
+            .map(|t| {
+                (
+                    paid_to(t, &address(1)).as_sat(),
+                    paid_to(t, &address(2)).as_sat(),
+                    fees(t).as_sat(),
+                    t.tx.lock_time,
+                )
+            })
+            .collect();
+        spends.sort();
+        assert_eq!(spends, vec![(0, 9_500, 500, 800_000), (9_500, 0, 500, 0)]);
+        Ok(())
+    }
+}
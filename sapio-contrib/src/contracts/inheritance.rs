@@ -0,0 +1,229 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Inheritance through a multisig of heirs whose threshold decays over time
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::util::amountrange::AmountF64;
+use sapio::*;
+use sapio_base::timelocks::AnyAbsTimeLock;
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+/// The most milestones a [`DecayingInheritance`] may have, as each is a
+/// separately declared guard (and so a separate leaf)
+pub const MAX_MILESTONES: usize = 5;
+
+/// # Decaying Inheritance
+/// The owner may always spend. After `milestones[0]`, all of the heirs may
+/// spend together, and after each later milestone one fewer heir is needed
+/// (e.g., 3-of-3, then 2-of-3, then 1-of-3). The owner checks in to push
+/// the milestones back.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct DecayingInheritance {
+    /// # Owner Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub owner: bitcoin::XOnlyPublicKey,
+    /// # Heir Keys
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+    pub heirs: Vec<bitcoin::XOnlyPublicKey>,
+    /// # Milestones
+    /// When each lower threshold of heirs may spend, in order
+    pub milestones: Vec<AnyAbsTimeLock>,
+    /// # Amount
+    pub amount: CoinAmount,
+}
+
+impl DecayingInheritance {
+    /// The heirs' clause after milestone `i`, or Unsatisfiable if there is
+    /// no such milestone
+    fn tier(&self, i: usize) -> Clause {
+        match self.milestones.get(i) {
+            Some(lock) if i < self.heirs.len() => Clause::And(vec![
+                Clause::Threshold(
+                    self.heirs.len() - i,
+                    self.heirs.iter().cloned().map(Clause::Key).collect(),
+                ),
+                (*lock).into(),
+            ]),
+            _ => Clause::Unsatisfiable,
+        }
+    }
+
+    #[guard]
+    fn owner_signed(self, _ctx: Context) {
+        Clause::Key(self.owner)
+    }
+    #[guard]
+    fn milestone_0(self, _ctx: Context) {
+        self.tier(0)
+    }
+    #[guard]
+    fn milestone_1(self, _ctx: Context) {
+        self.tier(1)
+    }
+    #[guard]
+    fn milestone_2(self, _ctx: Context) {
+        self.tier(2)
+    }
+    #[guard]
+    fn milestone_3(self, _ctx: Context) {
+        self.tier(3)
+    }
+    #[guard]
+    fn milestone_4(self, _ctx: Context) {
+        self.tier(4)
+    }
+
+    /// Roll the contract forward with later milestones
+    #[continuation(
+        guarded_by = "[Self::owner_signed]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn check_in(self, ctx: sapio::Context, o: CheckIn) {
+        if let CheckIn::CheckIn { milestones, fees } = o {
+            if milestones.len() != self.milestones.len()
+                || milestones
+                    .iter()
+                    .zip(self.milestones.iter())
+                    .any(|(new, old)| new.compare(old) != Some(Ordering::Greater))
+            {
+                return Err(CompilationError::TerminateWith(
+                    "Check In Must Push Back Every Milestone".into(),
+                ));
+            }
+            let fees: Amount = fees.into();
+            let rest = Amount::try_from(self.amount)?
+                .checked_sub(fees)
+                .ok_or(CompilationError::OutOfFunds)?;
+            let next = DecayingInheritance {
+                milestones,
+                amount: rest.into(),
+                ..self.clone()
+            };
+            ctx.template()
+                .add_output(rest, &next, None)?
+                .add_fees(fees)?
+                .into()
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <DecayingInheritance as Contract>::StatefulArguments,
+) -> Result<CheckIn, CompilationError> {
+    Ok(k)
+}
+
+/// Updates to a DecayingInheritance
+#[derive(Deserialize, JsonSchema)]
+pub enum CheckIn {
+    /// # Check In
+    CheckIn {
+        /// # New Milestones
+        /// Each must be later than the one it replaces
+        milestones: Vec<AnyAbsTimeLock>,
+        /// Fees to pay
+        fees: AmountF64,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for CheckIn {
+    fn default() -> Self {
+        CheckIn::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for CheckIn {}
+
+impl Contract for DecayingInheritance {
+    declare! {finish, Self::owner_signed, Self::milestone_0, Self::milestone_1, Self::milestone_2, Self::milestone_3, Self::milestone_4}
+    declare! {updatable<CheckIn>, Self::check_in}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.heirs.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Must Have at Least One Heir".into(),
+            ));
+        }
+        if self.milestones.is_empty()
+            || self.milestones.len() > self.heirs.len()
+            || self.milestones.len() > MAX_MILESTONES
+        {
+            return Err(CompilationError::TerminateWith(format!(
+                "Must Have Between 1 and {} Milestones",
+                std::cmp::min(self.heirs.len(), MAX_MILESTONES)
+            )));
+        }
+        if self
+            .milestones
+            .windows(2)
+            .any(|w| w[1].compare(&w[0]) != Some(Ordering::Greater))
+        {
+            return Err(CompilationError::TerminateWith(
+                "Milestones Must Increase in the Same Units".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    use sapio_base::timelocks::AbsHeight;
+    #[test]
+    fn tiers_decay() -> Result<(), Box<dyn std::error::Error>> {
+        let height = |h: u32| -> Result<AnyAbsTimeLock, Box<dyn std::error::Error>> {
+            Ok(AnyAbsTimeLock::AH(AbsHeight::try_from(h)?))
+        };
+        let c = DecayingInheritance {
+            owner: test_util::key(1),
+            heirs: vec![test_util::key(2), test_util::key(3), test_util::key(4)],
+            milestones: vec![height(800_000)?, height(850_000)?, height(900_000)?],
+            amount: Amount::from_sat(100_000).into(),
+        };
+        for (i, threshold) in [3, 2, 1].iter().enumerate() {
+            match c.tier(i) {
+                Clause::And(v) => {
+                    assert!(matches!(v[0], Clause::Threshold(t, _) if t == *threshold))
+                }
+                _ => panic!("tier {} not a timelocked threshold", i),
+            }
+        }
+        assert!(c.tier(3) == Clause::Unsatisfiable);
+        c.compile(test_util::ctx(Amount::from_sat(100_000)))?;
+        // checking in pays everything but the fees into the next contract
+        let check_in = |milestones| {
+            c.continue_check_in(
+                test_util::ctx(Amount::from_sat(100_000)),
+                CheckIn::CheckIn {
+                    milestones,
+                    fees: Amount::from_sat(1_000).into(),
+                },
+            )
+        };
+        let next = check_in(vec![height(810_000)?, height(860_000)?, height(910_000)?])?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(next.len(), 1);
+        assert_eq!(next[0].outputs.len(), 1);
+        assert_eq!(next[0].outputs[0].amount, Amount::from_sat(99_000));
+        assert_eq!(test_util::fees(&next[0]), Amount::from_sat(1_000));
+        assert!(check_in(vec![height(810_000)?, height(850_000)?, height(910_000)?]).is_err());
+        Ok(())
+    }
+}
@@ -13,19 +13,33 @@ use sapio::*;
 use schemars::*;
 use serde::*;
 use std::convert::TryInto;
+pub mod annuity;
+pub mod atomic_swap;
 pub mod basic_examples;
+pub mod bounty;
 pub mod channel;
+pub mod channel_factory;
 pub mod coin_pool;
+pub mod collateralized_loan;
+pub mod crowdfund;
 pub mod derivatives;
+pub mod dutch_auction;
 pub mod dynamic;
 pub mod eltoo_channel;
+pub mod federated_mint;
 pub mod federated_sidechain;
 pub mod hanukkah;
 pub mod hodl_chicken;
+pub mod htlc;
+pub mod inheritance;
 pub mod op_return_chain;
+pub mod payment_pool;
 pub mod readme_contracts;
 pub mod staked_signer;
+pub mod state_machine;
+pub mod statechain;
 pub mod tic_tac_toe;
+pub mod tranche_release;
 pub mod treepay;
 pub mod undo_send;
 pub mod vault;
@@ -0,0 +1,251 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A non-custodial payment pool, where members share one UTXO
+//!
+//! All members together may spend the pool however they like. Any member
+//! may also leave on their own, through a CTV exit which pays them and moves
+//! everyone else into a new pool. Every order of exits is precompiled, and
+//! pools of the same members are only compiled once, but there are still as
+//! many pools as subsets of members, so pools should be kept small.
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+
+/// # Pool Member
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct PoolMember {
+    /// # Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub key: bitcoin::XOnlyPublicKey,
+    /// # Balance
+    pub balance: CoinAmount,
+}
+
+/// # Payment Pool
+/// `members` share the pool, each with their own balance.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct PaymentPool {
+    /// # Members
+    pub members: Vec<PoolMember>,
+    /// # Sequence
+    /// How many members have exited before, so that each pool a member
+    /// exits into is distinct from the one before
+    #[serde(default)]
+    pub sequence: u64,
+    /// # Fees per Exit
+    /// Paid from the exiting member's balance
+    pub exit_fees: CoinAmount,
+}
+
+impl PaymentPool {
+    /// The total of every member's balance
+    pub fn total(&self) -> Result<Amount, CompilationError> {
+        let mut total = Amount::from_sat(0);
+        for m in self.members.iter() {
+            total = total
+                .checked_add(m.balance.try_into()?)
+                .ok_or(CompilationError::TerminateCompilation)?;
+        }
+        Ok(total)
+    }
+
+    /// The pool left once member `i` exits
+    pub fn without(&self, i: usize) -> PaymentPool {
+        let mut members = self.members.clone();
+        members.remove(i);
+        PaymentPool {
+            members,
+            sequence: self.sequence + 1,
+            exit_fees: self.exit_fees,
+        }
+    }
+
+    /// The transaction in which member `i` exits
+    fn exit_template(&self, ctx: Context, i: usize) -> Result<Template, CompilationError> {
+        let mut ctx = ctx;
+        let member = &self.members[i];
+        let fees: Amount = self.exit_fees.try_into()?;
+        let paid = Amount::try_from(member.balance)?
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        let to = member
+            .key
+            .compile(ctx.derive_str(Arc::new("member".into()))?)?;
+        let mut builder = ctx.template().add_output(paid, &to, None)?;
+        if self.members.len() > 1 {
+            let rest = self.without(i);
+            builder = builder.add_output_cached(rest.total()?, &rest, None)?;
+        }
+        Ok(builder.add_fees(fees)?.into())
+    }
+
+    /// The index of the member with `key`
+    fn member_index(&self, key: &bitcoin::XOnlyPublicKey) -> Result<usize, CompilationError> {
+        self.members
+            .iter()
+            .position(|m| m.key == *key)
+            .ok_or_else(|| CompilationError::TerminateWith("Not a Pool Member".into()))
+    }
+
+    /// Every member must sign
+    #[guard]
+    fn all_signed(self, _ctx: Context) {
+        Clause::And(self.members.iter().map(|m| Clause::Key(m.key)).collect())
+    }
+
+    /// Some member must sign
+    #[guard]
+    fn any_member(self, _ctx: Context) {
+        Clause::Threshold(1, self.members.iter().map(|m| Clause::Key(m.key)).collect())
+    }
+
+    /// Any member may exit, one transaction per member
+    #[then]
+    fn exits(self, ctx: sapio::Context) {
+        let mut ctx = ctx;
+        let tmpls = (0..self.members.len())
+            .map(|i| {
+                let c = ctx.derive_num(i as u64)?;
+                self.exit_template(c, i)
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(tmpls.into_iter()))
+    }
+
+    /// The exit of the member named in the arguments, for that member to
+    /// request
+    #[continuation(
+        guarded_by = "[Self::any_member]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn exit_of(self, ctx: sapio::Context, o: PoolExit) {
+        if let PoolExit::Exit { member } = o {
+            let i = self.member_index(&member)?;
+            let mut ctx = ctx;
+            let c = ctx.derive_num(i as u64)?;
+            Ok(Box::new(std::iter::once(self.exit_template(c, i))))
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <PaymentPool as Contract>::StatefulArguments,
+) -> Result<PoolExit, CompilationError> {
+    Ok(k)
+}
+
+/// Which member of a PaymentPool exits
+#[derive(Deserialize, JsonSchema)]
+pub enum PoolExit {
+    /// # Exit
+    Exit {
+        /// # Member Key
+        /// The key of the member exiting
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        member: bitcoin::XOnlyPublicKey,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for PoolExit {
+    fn default() -> Self {
+        PoolExit::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for PoolExit {}
+
+impl Contract for PaymentPool {
+    declare! {then, Self::exits}
+    declare! {finish, Self::all_signed}
+    declare! {updatable<PoolExit>, Self::exit_of}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.members.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Pool Must Have at Least One Member".into(),
+            ));
+        }
+        let fees: Amount = self.exit_fees.try_into()?;
+        for (i, m) in self.members.iter().enumerate() {
+            if Amount::try_from(m.balance)? <= fees {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Member {} Balance Does Not Cover Exit Fees",
+                    i
+                )));
+            }
+            if self.members[..i].iter().any(|n| n.key == m.key) {
+                return Err(CompilationError::TerminateWith(format!(
+                    "Member {} Appears Twice",
+                    i
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    #[test]
+    fn exits() -> Result<(), Box<dyn std::error::Error>> {
+        let pool = PaymentPool {
+            members: (1..=3)
+                .map(|seed| PoolMember {
+                    key: test_util::key(seed),
+                    balance: Amount::from_sat(10_000).into(),
+                })
+                .collect(),
+            sequence: 0,
+            exit_fees: Amount::from_sat(500).into(),
+        };
+        let after = pool.without(1);
+        assert_eq!(after.members.len(), 2);
+        assert_eq!(after.sequence, 1);
+        assert_eq!(after.total()?, Amount::from_sat(20_000));
+        pool.compile(test_util::ctx(pool.total()?))?;
+        // each exit pays its member less fees, and the rest to the next pool
+        let exits = pool
+            .then_exits(test_util::ctx(pool.total()?))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(exits.len(), 3);
+        for (t, seed) in exits.iter().zip(1..=3) {
+            let paid = test_util::paid_to(t, &test_util::key_address(seed));
+            assert_eq!(paid, Amount::from_sat(9_500));
+            assert_eq!(test_util::fees(t), Amount::from_sat(500));
+            assert_eq!(t.total_amount() - paid, Amount::from_sat(20_000));
+        }
+        let exit = pool
+            .continue_exit_of(
+                test_util::ctx(pool.total()?),
+                PoolExit::Exit {
+                    member: test_util::key(2),
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(exit.len(), 1);
+        assert_eq!(
+            test_util::paid_to(&exit[0], &test_util::key_address(2)),
+            Amount::from_sat(9_500)
+        );
+        Ok(())
+    }
+}
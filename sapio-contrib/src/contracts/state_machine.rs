@@ -0,0 +1,377 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A framework for contracts which are finite state machines
+//!
+//! Rather than writing a contract type per state by hand (as
+//! [`super::federated_sidechain::FederatedPegIn`] does), a protocol
+//! declares its states, the transitions out of each, and what each terminal
+//! state pays out, by implementing [`StateMachine`] (or with the
+//! [`state_machine!`](crate::state_machine) macro). [`Machine`] then is the
+//! contract for any state: every transition is a CTV template into the
+//! `Machine` of the next state, or into the payouts of a terminal one.
+//!
+//! ```ignore
+//! state_machine! {
+//!     /// The states of an escrow
+//!     pub enum EscrowState for Escrow { Funded, Disputed, Released, Refunded }
+//!     transitions(e) {
+//!         Funded => [
+//!             Transition::to(EscrowState::Released).with_guard(Clause::Key(e.buyer)),
+//!             Transition::to(EscrowState::Disputed).with_guard(Clause::Key(e.seller)),
+//!         ],
+//!         Disputed => [
+//!             Transition::to(EscrowState::Refunded).with_guard(Clause::Key(e.arbiter)),
+//!             Transition::to(EscrowState::Released).with_timeout(RelHeight::from(144).into()),
+//!         ],
+//!     }
+//!     payouts(e, funds) {
+//!         Released => vec![Payment { amount: funds.into(), address: e.seller_address.clone() }],
+//!         Refunded => vec![Payment { amount: funds.into(), address: e.buyer_address.clone() }],
+//!     }
+//! }
+//! ```
+use super::treepay::Payment;
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::timelocks::{AnyAbsTimeLock, AnyRelTimeLock};
+use sapio_base::Clause;
+use sapio_macros::{compile_if, guard};
+use schemars::*;
+use serde::de::DeserializeOwned;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Debug;
+
+/// A transition out of a state
+#[derive(Clone, Debug)]
+pub struct Transition<S> {
+    /// The state entered
+    pub to: S,
+    /// Who must sign for the transition, if anyone
+    pub guard: Option<Clause>,
+    /// How long after entering the state the transition may happen
+    pub timeout: Option<AnyRelTimeLock>,
+    /// When the transition may happen
+    pub lock_time: Option<AnyAbsTimeLock>,
+}
+
+impl<S> Transition<S> {
+    /// An unguarded transition to `to`, which anyone may make at any time
+    pub fn to(to: S) -> Self {
+        Transition {
+            to,
+            guard: None,
+            timeout: None,
+            lock_time: None,
+        }
+    }
+    /// Require `guard` to be satisfied, as well as any guard already set
+    pub fn with_guard(mut self, guard: Clause) -> Self {
+        self.guard = Some(match self.guard.take() {
+            Some(g) => Clause::And(vec![g, guard]),
+            None => guard,
+        });
+        self
+    }
+    /// Only allow the transition `timeout` after entering the state
+    pub fn with_timeout(mut self, timeout: AnyRelTimeLock) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Only allow the transition after `lock_time`
+    pub fn with_lock_time(mut self, lock_time: AnyAbsTimeLock) -> Self {
+        self.lock_time = Some(lock_time);
+        self
+    }
+}
+
+/// A protocol's states, transitions and payouts
+pub trait StateMachine: Clone + Serialize + DeserializeOwned + JsonSchema + 'static {
+    /// The states of the protocol
+    type State: Clone + PartialEq + Debug + Serialize + DeserializeOwned + JsonSchema + 'static;
+    /// The transitions out of `state`
+    fn transitions(&self, state: &Self::State) -> Vec<Transition<Self::State>>;
+    /// What entering `state` pays out of `funds`, if it is terminal. A
+    /// terminal state's transitions are never used.
+    fn payouts(&self, _state: &Self::State, _funds: Amount) -> Option<Vec<Payment>> {
+        None
+    }
+    /// Who may spend however they like in `state`, e.g. every party together
+    fn cooperative(&self, _state: &Self::State) -> Clause {
+        Clause::Unsatisfiable
+    }
+}
+
+/// # State Machine
+/// The contract for `machine` in `state`
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Machine<M: StateMachine> {
+    /// # Machine
+    pub machine: M,
+    /// # State
+    pub state: M::State,
+    /// # Amount
+    pub amount: CoinAmount,
+    /// # Fees per Transition
+    pub fees: CoinAmount,
+}
+
+impl<M: StateMachine> Machine<M> {
+    /// The transaction making transition `t`
+    fn enter(&self, ctx: Context, t: &Transition<M::State>) -> Result<Template, CompilationError> {
+        let fees: Amount = self.fees.try_into()?;
+        let funds = Amount::try_from(self.amount)?
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        let mut builder = ctx.template();
+        if let Some(guard) = &t.guard {
+            builder = builder.add_guard(guard.clone());
+        }
+        if let Some(timeout) = t.timeout {
+            builder = builder.set_sequence(0, timeout)?;
+        }
+        if let Some(lock_time) = t.lock_time {
+            builder = builder.set_lock_time(lock_time)?;
+        }
+        builder = match self.machine.payouts(&t.to, funds) {
+            Some(payouts) => {
+                for p in payouts {
+                    builder = builder.add_output(
+                        p.amount.try_into()?,
+                        &Compiled::from_address(p.address, None),
+                        None,
+                    )?;
+                }
+                builder
+            }
+            None => {
+                let next = Machine {
+                    state: t.to.clone(),
+                    amount: funds.into(),
+                    ..self.clone()
+                };
+                builder.add_output(funds, &next, None)?
+            }
+        };
+        Ok(builder.add_fees(fees)?.into())
+    }
+
+    #[compile_if]
+    fn has_transitions(self, _ctx: Context) {
+        if self.machine.transitions(&self.state).is_empty() {
+            ConditionalCompileType::Never
+        } else {
+            ConditionalCompileType::Required
+        }
+    }
+
+    #[guard]
+    fn cooperative(self, _ctx: Context) {
+        self.machine.cooperative(&self.state)
+    }
+
+    /// Every transition out of the current state
+    #[then(compile_if = "[Self::has_transitions]")]
+    fn transition(self, ctx: sapio::Context) {
+        let mut ctx = ctx;
+        let tmpls = self
+            .machine
+            .transitions(&self.state)
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                let c = ctx.derive_num(i as u64)?;
+                self.enter(c, t)
+            })
+            .collect::<Vec<_>>();
+        Ok(Box::new(tmpls.into_iter()))
+    }
+}
+
+impl<M: StateMachine> Contract for Machine<M> {
+    declare! {then, Self::transition}
+    declare! {finish, Self::cooperative}
+    declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self
+            .machine
+            .payouts(&self.state, self.amount.try_into()?)
+            .is_some()
+        {
+            return Err(CompilationError::TerminateWith(format!(
+                "State {:?} is Terminal and Pays Out on Entry",
+                self.state
+            )));
+        }
+        if self.machine.transitions(&self.state).is_empty()
+            && self.machine.cooperative(&self.state) == Clause::Unsatisfiable
+        {
+            return Err(CompilationError::TerminateWith(format!(
+                "State {:?} Has No Way Out",
+                self.state
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Declares a [`StateMachine`]'s states and implements it, with the
+/// transitions out of each state, the payouts of each terminal state, and
+/// optionally a cooperative clause for every state. States without
+/// transitions have none, and states without payouts are not terminal.
+///
+/// ```ignore
+/// state_machine! {
+///     /// docs for the state enum
+///     pub enum States for MyMachine { A, B, C }
+///     transitions(m) {
+///         A => [Transition::to(States::B).with_guard(m.some_clause())],
+///     }
+///     payouts(m, funds) {
+///         B => vec![/* Payments out of funds */],
+///     }
+///     cooperative(m) => m.everyone();
+/// }
+/// ```
+#[macro_export]
+macro_rules! state_machine {
+    {
+        $(#[$meta:meta])*
+        $vis:vis enum $state:ident for $machine:ty {
+            $($(#[$smeta:meta])* $s:ident),* $(,)?
+        }
+        transitions($m:ident) {
+            $($from:ident => [$($t:expr),* $(,)?]),* $(,)?
+        }
+        $(payouts($pm:ident, $funds:ident) {
+            $($terminal:ident => $p:expr),* $(,)?
+        })?
+        $(cooperative($cm:ident) => $coop:expr;)?
+    } => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+        $vis enum $state {
+            $($(#[$smeta])* $s),*
+        }
+        impl $crate::contracts::state_machine::StateMachine for $machine {
+            type State = $state;
+            #[allow(unreachable_patterns, unused_variables)]
+            fn transitions(
+                &self,
+                state: &$state,
+            ) -> Vec<$crate::contracts::state_machine::Transition<$state>> {
+                let $m = self;
+                match state {
+                    $($state::$from => vec![$($t),*],)*
+                    _ => vec![],
+                }
+            }
+            $(
+            #[allow(unreachable_patterns, unused_variables)]
+            fn payouts(
+                &self,
+                state: &$state,
+                $funds: bitcoin::util::amount::Amount,
+            ) -> Option<Vec<$crate::contracts::treepay::Payment>> {
+                let $pm = self;
+                match state {
+                    $($state::$terminal => Some($p),)*
+                    _ => None,
+                }
+            }
+            )?
+            $(
+            fn cooperative(&self, _state: &$state) -> sapio_base::Clause {
+                let $cm = self;
+                $coop
+            }
+            )?
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state_machine;
+    use ::rand::rngs::OsRng;
+    use bitcoin::secp256k1::Secp256k1;
+    use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    #[derive(JsonSchema, Serialize, Deserialize, Clone)]
+    struct Escrow {
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        buyer: bitcoin::XOnlyPublicKey,
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        seller: bitcoin::XOnlyPublicKey,
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        arbiter: bitcoin::XOnlyPublicKey,
+        buyer_address: bitcoin::Address,
+        seller_address: bitcoin::Address,
+    }
+
+    state_machine! {
+        /// The states of an escrow
+        enum EscrowState for Escrow { Funded, Disputed, Released, Refunded }
+        transitions(e) {
+            Funded => [
+                Transition::to(EscrowState::Released).with_guard(Clause::Key(e.buyer)),
+                Transition::to(EscrowState::Disputed).with_guard(Clause::Key(e.seller)),
+            ],
+            Disputed => [
+                Transition::to(EscrowState::Refunded).with_guard(Clause::Key(e.arbiter)),
+                Transition::to(EscrowState::Released).with_timeout(RelHeight::from(144).into()),
+            ],
+        }
+        payouts(e, funds) {
+            Released => vec![Payment { amount: funds.into(), address: e.seller_address.clone() }],
+            Refunded => vec![Payment { amount: funds.into(), address: e.buyer_address.clone() }],
+        }
+        cooperative(e) => Clause::And(vec![Clause::Key(e.buyer), Clause::Key(e.seller)]);
+    }
+
+    #[test]
+    fn escrow() -> Result<(), Box<dyn std::error::Error>> {
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new()?;
+        let mut key = || -> bitcoin::XOnlyPublicKey { secp.generate_keypair(&mut rng).1.into() };
+        let address = bitcoin::Address::from_str("bcrt1qumrrqgt7e3a7damzm8x97m6sjs20u8hjw2hcjj")?;
+        let escrow = Machine {
+            machine: Escrow {
+                buyer: key(),
+                seller: key(),
+                arbiter: key(),
+                buyer_address: address.clone(),
+                seller_address: address,
+            },
+            state: EscrowState::Funded,
+            amount: Amount::from_sat(100_000).into(),
+            fees: Amount::from_sat(1_000).into(),
+        };
+        assert_eq!(escrow.machine.transitions(&EscrowState::Released).len(), 0);
+        assert!(escrow
+            .machine
+            .payouts(&EscrowState::Disputed, Amount::from_sat(1))
+            .is_none());
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("escrow").unwrap(),
+            Arc::new(Default::default()),
+        );
+        escrow.compile(ctx)?;
+        Ok(())
+    }
+}
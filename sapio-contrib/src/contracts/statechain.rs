@@ -0,0 +1,218 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A statechain, which hands a UTXO from owner to owner off chain
+//!
+//! The UTXO is locked to the facilitator's key and a shared owner key. As
+//! in a statechain, the owner key is handed from each owner to the next
+//! off chain, and the facilitator promises to sign only with the newest
+//! owner. Each handover is the facilitator and the owner key signing the
+//! new owner a backup transaction, which pays them unilaterally once its
+//! lock time passes. Every backup is locked `step` blocks earlier than the
+//! one before, so the newest owner can always exit before any earlier one.
+//! Nothing about the holders is in the script, so the address stays the
+//! same as ownership rolls forward.
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::template::Template;
+use sapio::*;
+use sapio_base::timelocks::AbsHeight;
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+
+/// # Statechain
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Statechain {
+    /// # Facilitator Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub facilitator: bitcoin::XOnlyPublicKey,
+    /// # Owner Key
+    /// The key handed from owner to owner
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub owner_key: bitcoin::XOnlyPublicKey,
+    /// # Holder
+    /// The current owner's own key, which their backup pays
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub holder: bitcoin::XOnlyPublicKey,
+    /// # Expiry
+    /// When the first owner's backup may be broadcast
+    pub expiry: AbsHeight,
+    /// # Blocks per Transfer
+    /// How much earlier each owner's backup is than the last
+    pub step: u32,
+    /// # Transfers
+    /// How many times ownership has been handed over
+    #[serde(default)]
+    pub transfers: u32,
+    /// # Amount
+    pub amount: CoinAmount,
+    /// # Fees
+    /// Paid by each backup transaction
+    pub fees: CoinAmount,
+}
+
+impl Statechain {
+    /// When the backup after `transfers` handovers may be broadcast
+    pub fn exit_height(&self, transfers: u32) -> Result<AbsHeight, CompilationError> {
+        let exhausted = || CompilationError::TerminateWith("Statechain Exhausted".into());
+        let h = transfers
+            .checked_mul(self.step)
+            .and_then(|d| self.expiry.get().checked_sub(d))
+            .ok_or_else(exhausted)?;
+        AbsHeight::try_from(h).map_err(|_| exhausted())
+    }
+
+    /// The statechain once it is handed over to `new_holder`
+    pub fn transferred(&self, new_holder: bitcoin::XOnlyPublicKey) -> Statechain {
+        Statechain {
+            holder: new_holder,
+            transfers: self.transfers + 1,
+            ..self.clone()
+        }
+    }
+
+    /// The backup transaction of the current holder
+    fn backup(&self, ctx: Context) -> Result<Template, CompilationError> {
+        let mut ctx = ctx;
+        let fees: Amount = self.fees.try_into()?;
+        let paid = Amount::try_from(self.amount)?
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        let to = self
+            .holder
+            .compile(ctx.derive_str(Arc::new("holder".into()))?)?;
+        Ok(ctx
+            .template()
+            .add_output(paid, &to, None)?
+            .add_fees(fees)?
+            .set_lock_time(self.exit_height(self.transfers)?.into())?
+            .into())
+    }
+
+    /// The facilitator and the owner key must both sign
+    #[guard]
+    fn cooperate(self, _ctx: Context) {
+        Clause::And(vec![
+            Clause::Key(self.facilitator),
+            Clause::Key(self.owner_key),
+        ])
+    }
+
+    /// The backup transaction to sign for a handover, or for the current
+    /// holder
+    #[continuation(
+        guarded_by = "[Self::cooperate]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn handover(self, ctx: sapio::Context, o: Handover) {
+        let owner = match o {
+            Handover::Transfer { new_holder } => self.transferred(new_holder),
+            Handover::Backup {} => self.clone(),
+            Handover::NoUpdate {} => return empty(),
+        };
+        Ok(Box::new(std::iter::once(owner.backup(ctx))))
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <Statechain as Contract>::StatefulArguments,
+) -> Result<Handover, CompilationError> {
+    Ok(k)
+}
+
+/// Handovers of a Statechain
+#[derive(Deserialize, JsonSchema)]
+pub enum Handover {
+    /// # Transfer
+    /// The backup for the next owner
+    Transfer {
+        /// # New Holder
+        /// The next owner's own key, which their backup pays
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        new_holder: bitcoin::XOnlyPublicKey,
+    },
+    /// # Backup
+    /// The backup for the current owner, e.g. before funding
+    Backup {},
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for Handover {
+    fn default() -> Self {
+        Handover::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for Handover {}
+
+impl Contract for Statechain {
+    declare! {finish, Self::cooperate}
+    declare! {updatable<Handover>, Self::handover}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.step == 0 {
+            return Err(CompilationError::TerminateWith(
+                "Each Backup Must be Earlier than the Last".into(),
+            ));
+        }
+        self.exit_height(self.transfers)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util;
+    #[test]
+    fn exits_decrement() -> Result<(), Box<dyn std::error::Error>> {
+        let chain = Statechain {
+            facilitator: test_util::key(1),
+            owner_key: test_util::key(2),
+            holder: test_util::key(3),
+            expiry: AbsHeight::try_from(800_000u32).unwrap(),
+            step: 144,
+            transfers: 0,
+            amount: Amount::from_sat(100_000).into(),
+            fees: Amount::from_sat(1_000).into(),
+        };
+        let next = chain.transferred(test_util::key(4));
+        assert_eq!(next.transfers, 1);
+        assert_eq!(next.exit_height(next.transfers)?.get(), 799_856);
+        assert!(chain.exit_height(10_000).is_err());
+        chain.compile(test_util::ctx(Amount::from_sat(100_000)))?;
+        // each backup pays its holder less fees, earlier than the last
+        let backup = |o| -> Result<(u64, u64, u32), Box<dyn std::error::Error>> {
+            let t = chain
+                .continue_handover(test_util::ctx(Amount::from_sat(100_000)), o)?
+                .next()
+                .expect("one backup")?;
+            assert_eq!(test_util::fees(&t), Amount::from_sat(1_000));
+            Ok((
+                test_util::paid_to(&t, &test_util::key_address(3)).as_sat(),
+                test_util::paid_to(&t, &test_util::key_address(4)).as_sat(),
+                t.tx.lock_time,
+            ))
+        };
+        assert_eq!(backup(Handover::Backup {})?, (99_000, 0, 800_000));
+        assert_eq!(
+            backup(Handover::Transfer {
+                new_holder: test_util::key(4)
+            })?,
+            (0, 99_000, 799_856)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Release a locked balance in equal tranches on a fixed block schedule
+use bitcoin::util::amount::{Amount, CoinAmount};
+use sapio::contract::*;
+use sapio::util::amountrange::AmountF64;
+use sapio::*;
+use sapio_base::timelocks::RelHeight;
+use sapio_base::Clause;
+use sapio_macros::guard;
+use schemars::*;
+use serde::*;
+use std::convert::{TryFrom, TryInto};
+
+/// # Tranche Release
+/// Pays `tranche` to `destination` every `interval` blocks, `n_tranches`
+/// times, through a chain of CTV templates, each paying `fees`. At any point
+/// the owner may cancel and sweep whatever has not yet been released back to
+/// `owner_address`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct TrancheRelease {
+    /// # Owner Key
+    /// The key authorized to cancel the schedule
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub owner: bitcoin::XOnlyPublicKey,
+    /// # Owner Address
+    /// Where the remainder goes on cancellation
+    pub owner_address: bitcoin::Address,
+    /// # Destination
+    /// Where each tranche is paid
+    pub destination: bitcoin::Address,
+    /// # Amount per Tranche
+    pub tranche: CoinAmount,
+    /// # Fees per Release
+    /// Reserved for each release's transaction
+    pub fees: CoinAmount,
+    /// # Tranches Remaining
+    pub n_tranches: u64,
+    /// # Blocks between Tranches
+    pub interval: RelHeight,
+}
+
+impl TrancheRelease {
+    /// The tranches left to release and the fees for releasing them
+    fn remaining(&self) -> Result<Amount, CompilationError> {
+        Amount::try_from(self.tranche)
+            .map_err(|_| CompilationError::TerminateCompilation)?
+            .checked_add(self.fees.try_into()?)
+            .and_then(|per| per.checked_mul(self.n_tranches))
+            .ok_or(CompilationError::TerminateCompilation)
+    }
+
+    /// Release the next tranche once `interval` blocks have passed
+    #[then]
+    fn release(self, ctx: sapio::Context) {
+        let mut builder = ctx
+            .template()
+            .add_output(
+                self.tranche.try_into()?,
+                &Compiled::from_address(self.destination.clone(), None),
+                None,
+            )?
+            .set_sequence(0, self.interval.into())?;
+        if self.n_tranches > 1 {
+            let rest = TrancheRelease {
+                n_tranches: self.n_tranches - 1,
+                ..self.clone()
+            };
+            builder = builder.add_output(rest.remaining()?, &rest, None)?;
+        }
+        builder.add_fees(self.fees.try_into()?)?.into()
+    }
+
+    /// The owner must sign to cancel
+    #[guard]
+    fn owner_signed(self, _ctx: Context) {
+        Clause::Key(self.owner)
+    }
+
+    /// Cancel the schedule, sweeping the unreleased remainder (less fees)
+    /// back to the owner
+    #[continuation(
+        guarded_by = "[Self::owner_signed]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn cancel(self, ctx: sapio::Context, o: CancelTranches) {
+        if let CancelTranches::Cancel { fees } = o {
+            let remaining = self.remaining()?;
+            let fees: Amount = fees.into();
+            let sweep = remaining
+                .checked_sub(fees)
+                .ok_or(CompilationError::OutOfFunds)?;
+            ctx.template()
+                .add_output(
+                    sweep,
+                    &Compiled::from_address(self.owner_address.clone(), None),
+                    None,
+                )?
+                .add_fees(fees)?
+                .into()
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <TrancheRelease as Contract>::StatefulArguments,
+) -> Result<CancelTranches, CompilationError> {
+    Ok(k)
+}
+
+/// Updates to a TrancheRelease
+#[derive(Deserialize, JsonSchema)]
+pub enum CancelTranches {
+    /// # Cancel
+    Cancel {
+        /// Fees to pay
+        fees: AmountF64,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for CancelTranches {
+    fn default() -> Self {
+        CancelTranches::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for CancelTranches {}
+
+impl Contract for TrancheRelease {
+    declare! {then, Self::release}
+    declare! {updatable<CancelTranches>, Self::cancel}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.n_tranches == 0 {
+            return Err(CompilationError::TerminateWith(
+                "Must Release at Least One Tranche".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    use bitcoin::{Script, XOnlyPublicKey};
+    use sapio::template::Template;
+    use sapio_base::effects::EffectPath;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    const FEES: u64 = 1_000;
+
+    fn ctx(amount: Amount) -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("tranches").unwrap(),
+            Arc::new(Default::default()),
+        )
+    }
+
+    fn tranches() -> TrancheRelease {
+        let secp = Secp256k1::new();
+        TrancheRelease {
+            owner: XOnlyPublicKey::from_keypair(
+                &KeyPair::from_seckey_slice(&secp, &[1; 32]).unwrap(),
+            ),
+            owner_address: bitcoin::Address::p2wsh(&Script::new(), bitcoin::Network::Regtest),
+            destination: bitcoin::Address::p2wsh(
+                &Script::from(vec![0x51]),
+                bitcoin::Network::Regtest,
+            ),
+            tranche: Amount::from_sat(10_000).into(),
+            fees: Amount::from_sat(FEES).into(),
+            n_tranches: 3,
+            interval: RelHeight::from(144),
+        }
+    }
+
+    /// the fees reserved by `t`
+    fn fees(t: &Template) -> u64 {
+        (t.max - t.total_amount()).as_sat()
+    }
+
+    #[test]
+    fn releases_and_cancel_reserve_fees() -> Result<(), Box<dyn std::error::Error>> {
+        let t = tranches();
+        let funds = t.remaining()?;
+        assert_eq!(funds, Amount::from_sat(33_000));
+        let compiled = t.compile(ctx(funds))?;
+        let release = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(fees(release), FEES);
+        assert_eq!(release.max, funds);
+        assert_eq!(
+            release
+                .outputs
+                .iter()
+                .map(|o| o.amount.as_sat())
+                .collect::<Vec<_>>(),
+            vec![10_000, 22_000]
+        );
+
+        let cancel = t
+            .continue_cancel(
+                ctx(funds),
+                CancelTranches::Cancel {
+                    fees: Amount::from_sat(500).into(),
+                },
+            )?
+            .collect::<Result<Vec<Template>, _>>()?;
+        assert_eq!(cancel.len(), 1);
+        assert_eq!(fees(&cancel[0]), 500);
+        assert_eq!(cancel[0].total_amount(), Amount::from_sat(32_500));
+        Ok(())
+    }
+}
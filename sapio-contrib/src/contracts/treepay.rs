@@ -5,12 +5,19 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! contracts for paying a large set of recipients fee efficiently
+//!
+//! A [`TreePay`] commits to every payment with a single output, and each
+//! payment is only made on chain once the branch of the tree leading to it
+//! is expanded. Expansion may be slowed down level by level with relative
+//! timelocks to exert backpressure when blockspace is congested.
+use bitcoin::util::amount::Amount;
 use sapio::contract::*;
 use sapio::*;
+use sapio_base::timelocks::AnyRelTimeLock;
 
 use schemars::*;
 use serde::*;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 /// instructions to send an amount of coin to an address
 #[derive(JsonSchema, Serialize, Deserialize, Clone)]
@@ -22,45 +29,149 @@ pub struct Payment {
     pub address: bitcoin::Address,
 }
 /// Create a tree of payments with a given radix
-#[derive(JsonSchema, Serialize, Deserialize)]
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
 pub struct TreePay {
     /// the list of payments to create
     pub participants: Vec<Payment>,
     /// the radix to use (4 or 5 near optimal, depending on if CTV emulation is used this may be inaccurate)
     pub radix: usize,
+    /// # Timelocks per Level
+    /// How long to wait before the transaction at each level of the tree may
+    /// be expanded, starting at the root. Levels past the end of the list
+    /// are not delayed.
+    #[serde(default)]
+    pub level_timelocks: Vec<Option<AnyRelTimeLock>>,
+    /// # Level
+    /// How deep in the tree this subtree is, 0 for the root
+    #[serde(default)]
+    pub level: usize,
+}
+
+/// # Tree Payment Subtree
+/// Selects the subtree of `tree` at `path`, for expanding just that part of
+/// the tree on demand.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct TreePaySubtree {
+    /// # Tree
+    /// The whole tree, as it was created
+    pub tree: TreePay,
+    /// # Path
+    /// The index of the output to follow at each level, starting at the root
+    pub path: Vec<usize>,
+}
+
+impl TryFrom<TreePaySubtree> for TreePay {
+    type Error = CompilationError;
+    fn try_from(s: TreePaySubtree) -> Result<Self, CompilationError> {
+        s.tree.subtree(&s.path)
+    }
 }
 
 impl TreePay {
+    /// Pay `participants` with a tree of the given `radix`
+    pub fn new(participants: Vec<Payment>, radix: usize) -> Self {
+        TreePay {
+            participants,
+            radix,
+            level_timelocks: vec![],
+            level: 0,
+        }
+    }
+    /// delay expanding each level of the tree by `level_timelocks`, starting
+    /// at the root
+    pub fn with_level_timelocks(mut self, level_timelocks: Vec<Option<AnyRelTimeLock>>) -> Self {
+        self.level_timelocks = level_timelocks;
+        self
+    }
+
+    /// The total paid by this (sub)tree
+    pub fn amount(&self) -> Result<Amount, CompilationError> {
+        let mut amt = Amount::from_sat(0);
+        for Payment { amount, .. } in self.participants.iter() {
+            amt = amt
+                .checked_add((*amount).try_into()?)
+                .ok_or(CompilationError::TerminateCompilation)?;
+        }
+        Ok(amt)
+    }
+
+    /// The payments made by each output of this (sub)tree's transaction: one
+    /// payment per output once there are few enough, otherwise at most
+    /// `radix` evenly sized groups
+    fn groups(&self) -> Vec<&[Payment]> {
+        if self.participants.len() <= self.radix {
+            self.participants.chunks(1).collect()
+        } else {
+            let size = (self.participants.len() + self.radix - 1) / self.radix;
+            self.participants.chunks(size).collect()
+        }
+    }
+
+    /// The subtree paying `group`, one level below this one
+    fn child(&self, group: &[Payment]) -> TreePay {
+        TreePay {
+            participants: group.to_vec(),
+            radix: self.radix,
+            level_timelocks: self.level_timelocks.clone(),
+            level: self.level + 1,
+        }
+    }
+
+    /// The subtrees of the outputs of this (sub)tree's transaction, by
+    /// output index. Outputs which pay an address directly have none.
+    pub fn children(&self) -> Vec<Option<TreePay>> {
+        self.groups()
+            .into_iter()
+            .map(|g| {
+                if g.len() > 1 {
+                    Some(self.child(g))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// The subtree reached by following the output at each index of `path`,
+    /// so that it can be compiled (and expanded) without compiling the rest
+    /// of the tree
+    pub fn subtree(&self, path: &[usize]) -> Result<TreePay, CompilationError> {
+        let mut tree = self.clone();
+        for (depth, idx) in path.iter().enumerate() {
+            tree = tree
+                .children()
+                .into_iter()
+                .nth(*idx)
+                .flatten()
+                .ok_or_else(|| {
+                    CompilationError::TerminateWith(format!(
+                        "No Subtree at Output {} of Level {}",
+                        idx,
+                        self.level + depth
+                    ))
+                })?;
+        }
+        Ok(tree)
+    }
+
     #[then]
     fn expand(self, ctx: sapio::Context) {
         let mut builder = ctx.template();
-        if self.participants.len() > self.radix {
-            for c in self
-                .participants
-                .chunks(self.participants.len() / self.radix)
-            {
-                let mut amt = bitcoin::util::amount::Amount::from_sat(0);
-                for Payment { amount, .. } in c {
-                    amt += amount.clone().try_into()?;
-                }
-                builder = builder.add_output(
-                    amt,
-                    &TreePay {
-                        participants: c.to_vec(),
-                        radix: self.radix,
-                    },
-                    None,
-                )?;
-            }
-        } else {
-            for Payment { amount, address } in self.participants.iter() {
+        for group in self.groups() {
+            if let [Payment { amount, address }] = group {
                 builder = builder.add_output(
                     (*amount).try_into()?,
                     &Compiled::from_address(address.clone(), None),
                     None,
                 )?;
+            } else {
+                let child = self.child(group);
+                builder = builder.add_output(child.amount()?, &child, None)?;
             }
         }
+        if let Some(Some(timelock)) = self.level_timelocks.get(self.level) {
+            builder = builder.set_sequence(0, *timelock)?;
+        }
         builder.into()
     }
 }
@@ -68,4 +179,56 @@ impl TreePay {
 impl Contract for TreePay {
     declare! {then, Self::expand}
     declare! {non updatable}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        if self.radix < 2 {
+            return Err(CompilationError::TerminateWith(
+                "Radix Must be at Least 2".into(),
+            ));
+        }
+        if self.participants.is_empty() {
+            return Err(CompilationError::TerminateWith(
+                "Must Pay at Least One Participant".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::effects::EffectPath;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    #[test]
+    fn subtrees() -> Result<(), Box<dyn std::error::Error>> {
+        let address = bitcoin::Address::from_str("bcrt1qumrrqgt7e3a7damzm8x97m6sjs20u8hjw2hcjj")?;
+        let participants = (0..10)
+            .map(|_| Payment {
+                amount: Amount::from_sat(1000).into(),
+                address: address.clone(),
+            })
+            .collect();
+        let tree = TreePay::new(participants, 3)
+            .with_level_timelocks(vec![None, Some(RelHeight::from(10).into())]);
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            tree.amount()?,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("treepay").unwrap(),
+            Arc::new(Default::default()),
+        );
+        tree.clone().compile(ctx)?;
+        // 10 payments split 4, 4 and 2, and each 4 splits 2 and 2
+        assert_eq!(tree.children().len(), 3);
+        let sub = tree.subtree(&[0, 1])?;
+        assert_eq!(sub.level, 2);
+        assert_eq!(sub.participants.len(), 2);
+        assert!(sub.children().iter().all(Option::is_none));
+        assert!(tree.subtree(&[0, 1, 0]).is_err());
+        Ok(())
+    }
 }
@@ -10,6 +10,7 @@ use bitcoin::util::amount::CoinAmount;
 use sapio::contract::*;
 use sapio::*;
 use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_macros::{actions, Contract};
 
 use schemars::*;
 use serde::*;
@@ -18,7 +19,7 @@ use std::convert::TryInto;
 /// # Undoable Sending Contract
 /// UndoSendInternal allows funds to be sent to the to_contract only after a
 /// relative timeout. Otherwise, they can move back to the from_contract.
-#[derive(JsonSchema, Serialize, Deserialize)]
+#[derive(JsonSchema, Serialize, Deserialize, Contract)]
 pub struct UndoSendInternal {
     /// The contract to return funds to before timeout
     pub from_contract: Compiled,
@@ -31,23 +32,19 @@ pub struct UndoSendInternal {
     pub timeout: AnyRelTimeLock,
 }
 
+#[actions]
 impl UndoSendInternal {
     #[then]
-    fn complete(self, ctx: sapio::Context) {
+    fn undo(self, ctx: sapio::Context) {
         ctx.template()
-            .add_output(self.amount.try_into()?, &self.to_contract, None)?
-            .set_sequence(0, self.timeout)?
+            .add_output(self.amount.try_into()?, &self.from_contract, None)?
             .into()
     }
     #[then]
-    fn undo(self, ctx: sapio::Context) {
+    fn complete(self, ctx: sapio::Context) {
         ctx.template()
-            .add_output(self.amount.try_into()?, &self.from_contract, None)?
+            .add_output(self.amount.try_into()?, &self.to_contract, None)?
+            .set_sequence(0, self.timeout)?
             .into()
     }
 }
-
-impl Contract for UndoSendInternal {
-    declare! {then, Self::undo, Self::complete}
-    declare! {non updatable}
-}
@@ -6,10 +6,13 @@
 
 //! Contract for managing movement of funds from cold to hot storage
 use super::undo_send::UndoSendInternal;
-use bitcoin::util::amount::CoinAmount;
+use bitcoin::util::amount::{Amount, CoinAmount};
 use sapio::contract::*;
+use sapio::util::amountrange::AmountF64;
 use sapio::*;
 use sapio_base::timelocks::AnyRelTimeLock;
+use sapio_base::Clause;
+use sapio_macros::guard;
 
 use schemars::*;
 use serde::*;
@@ -172,10 +175,7 @@ impl TryFrom<VaultTree> for Vault {
                             address: cs.clone(),
                         });
                     }
-                    ctx.compile(super::treepay::TreePay {
-                        participants: pmts,
-                        radix: rad,
-                    })
+                    ctx.compile(super::treepay::TreePay::new(pmts, rad))
                 }
             }),
             hot_storage: v.hot_storage,
@@ -187,6 +187,229 @@ impl TryFrom<VaultTree> for Vault {
     }
 }
 
+/// # Clawback Vault
+/// Funds in the vault can only reach the hot key by unvaulting, after which
+/// the hot key must wait `delay` before spending them. Until then, the cold
+/// key may claw the funds back to `deep_cold`. The cold key may also sweep
+/// the vault to `deep_cold` at any time, and the hot key may unvault part of
+/// the funds, re-vaulting the change.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct ClawbackVault {
+    /// # Hot Key
+    /// The key which may spend funds once unvaulted and the delay passed
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub hot: bitcoin::XOnlyPublicKey,
+    /// # Cold Key
+    /// The key which may claw back or sweep funds to deep cold storage
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub cold: bitcoin::XOnlyPublicKey,
+    /// # Deep Cold Storage
+    /// The address of the deep cold storage descriptor
+    pub deep_cold: bitcoin::Address,
+    /// # Unvault Delay
+    /// How long the hot key must wait after unvaulting
+    pub delay: AnyRelTimeLock,
+    /// # Amount
+    /// The amount in the vault
+    pub amount: CoinAmount,
+    /// # Fees per Transaction
+    /// The fees set aside from the amount for each transaction
+    pub fees: CoinAmount,
+}
+
+impl ClawbackVault {
+    /// The amount left once one transaction's fees are paid
+    fn less_fees(&self) -> Result<(Amount, Amount), CompilationError> {
+        let amount: Amount = self.amount.try_into()?;
+        let fees: Amount = self.fees.try_into()?;
+        let rest = amount
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        Ok((rest, fees))
+    }
+
+    /// The unvaulting of `amount` from this vault
+    fn unvaulting(&self, amount: Amount) -> Unvaulting {
+        Unvaulting {
+            hot: self.hot,
+            cold: self.cold,
+            deep_cold: self.deep_cold.clone(),
+            delay: self.delay,
+            amount: amount.into(),
+            fees: self.fees,
+        }
+    }
+
+    /// Unvault everything, starting the delay
+    #[then]
+    fn unvault(self, ctx: sapio::Context) {
+        let (rest, fees) = self.less_fees()?;
+        ctx.template()
+            .add_output(rest, &self.unvaulting(rest), None)?
+            .add_fees(fees)?
+            .into()
+    }
+
+    /// Sweep everything to deep cold storage
+    #[then(guarded_by = "[Self::cold_signed]")]
+    fn sweep(self, ctx: sapio::Context) {
+        let (rest, fees) = self.less_fees()?;
+        ctx.template()
+            .add_output(
+                rest,
+                &Compiled::from_address(self.deep_cold.clone(), None),
+                None,
+            )?
+            .add_fees(fees)?
+            .into()
+    }
+
+    /// The cold key must sign
+    #[guard]
+    fn cold_signed(self, _ctx: Context) {
+        Clause::Key(self.cold)
+    }
+
+    /// The hot key must sign
+    #[guard]
+    fn hot_signed(self, _ctx: Context) {
+        Clause::Key(self.hot)
+    }
+
+    /// Unvault part of the funds, returning the change to a new vault
+    #[continuation(
+        guarded_by = "[Self::hot_signed]",
+        coerce_args = "default_coerce",
+        web_api
+    )]
+    fn withdraw(self, ctx: sapio::Context, o: Withdraw) {
+        if let Withdraw::Partial { amount, fees } = o {
+            let amount: Amount = amount.into();
+            let fees: Amount = fees.into();
+            let change = Amount::try_from(self.amount)?
+                .checked_sub(amount)
+                .and_then(|a| a.checked_sub(fees))
+                .ok_or(CompilationError::OutOfFunds)?;
+            let mut builder = ctx
+                .template()
+                .add_output(amount, &self.unvaulting(amount), None)?;
+            if change > Amount::from_sat(0) {
+                builder = builder.add_output(
+                    change,
+                    &ClawbackVault {
+                        amount: change.into(),
+                        ..self.clone()
+                    },
+                    None,
+                )?;
+            }
+            builder.add_fees(fees)?.into()
+        } else {
+            empty()
+        }
+    }
+}
+
+/// Helper
+fn default_coerce(
+    k: <ClawbackVault as Contract>::StatefulArguments,
+) -> Result<Withdraw, CompilationError> {
+    Ok(k)
+}
+
+/// Updates to a ClawbackVault
+#[derive(Deserialize, JsonSchema)]
+pub enum Withdraw {
+    /// # Partial Withdrawal
+    Partial {
+        /// Amount to unvault
+        amount: AmountF64,
+        /// Fees to pay
+        fees: AmountF64,
+    },
+    /// # Update without Args
+    NoUpdate {},
+}
+impl Default for Withdraw {
+    fn default() -> Self {
+        Withdraw::NoUpdate {}
+    }
+}
+impl StatefulArgumentsTrait for Withdraw {}
+
+impl Contract for ClawbackVault {
+    declare! {then, Self::unvault, Self::sweep}
+    declare! {updatable<Withdraw>, Self::withdraw}
+
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        self.less_fees().map(|_| ())
+    }
+}
+
+/// # Unvaulting
+/// Funds on their way out of a [`ClawbackVault`]. The hot key may spend them
+/// once `delay` has passed, and until then the cold key may claw them back
+/// to `deep_cold`.
+#[derive(JsonSchema, Serialize, Deserialize, Clone)]
+pub struct Unvaulting {
+    /// # Hot Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub hot: bitcoin::XOnlyPublicKey,
+    /// # Cold Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub cold: bitcoin::XOnlyPublicKey,
+    /// # Deep Cold Storage
+    pub deep_cold: bitcoin::Address,
+    /// # Unvault Delay
+    pub delay: AnyRelTimeLock,
+    /// # Amount
+    pub amount: CoinAmount,
+    /// # Fees for the Clawback
+    pub fees: CoinAmount,
+}
+
+impl Unvaulting {
+    /// The hot key may spend once the delay has passed
+    #[guard]
+    fn hot_matured(self, _ctx: Context) {
+        Clause::And(vec![Clause::Key(self.hot), self.delay.into()])
+    }
+
+    /// The cold key must sign
+    #[guard]
+    fn cold_signed(self, _ctx: Context) {
+        Clause::Key(self.cold)
+    }
+
+    /// Claw the funds back to deep cold storage
+    #[then(guarded_by = "[Self::cold_signed]")]
+    fn clawback(self, ctx: sapio::Context) {
+        let amount: Amount = self.amount.try_into()?;
+        let fees: Amount = self.fees.try_into()?;
+        let rest = amount
+            .checked_sub(fees)
+            .ok_or(CompilationError::OutOfFunds)?;
+        ctx.template()
+            .add_output(
+                rest,
+                &Compiled::from_address(self.deep_cold.clone(), None),
+                None,
+            )?
+            .add_fees(fees)?
+            .into()
+    }
+}
+
+impl Contract for Unvaulting {
+    declare! {then, Self::clawback}
+    declare! {finish, Self::hot_matured}
+    declare! {non updatable}
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -221,4 +444,31 @@ mod test {
         Vault::try_from(v.arguments)?.compile(ctx)?;
         Ok(())
     }
+    #[test]
+    fn clawback() -> Result<(), Box<dyn std::error::Error>> {
+        use ::rand::rngs::OsRng;
+        use bitcoin::secp256k1::Secp256k1;
+        use sapio_base::timelocks::RelHeight;
+        use std::str::FromStr;
+        let secp = Secp256k1::new();
+        let mut rng = OsRng::new()?;
+        let mut key = || -> bitcoin::XOnlyPublicKey { secp.generate_keypair(&mut rng).1.into() };
+        let vault = ClawbackVault {
+            hot: key(),
+            cold: key(),
+            deep_cold: bitcoin::Address::from_str("bcrt1qumrrqgt7e3a7damzm8x97m6sjs20u8hjw2hcjj")?,
+            delay: RelHeight::from(144).into(),
+            amount: Amount::from_sat(100_000).into(),
+            fees: Amount::from_sat(1_000).into(),
+        };
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("vault").unwrap(),
+            Arc::new(Default::default()),
+        );
+        vault.compile(ctx)?;
+        Ok(())
+    }
 }
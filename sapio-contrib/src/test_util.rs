@@ -0,0 +1,65 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by this crate's tests
+use bitcoin::schnorr::TweakedPublicKey;
+use bitcoin::secp256k1::{KeyPair, Secp256k1};
+use bitcoin::util::amount::Amount;
+use bitcoin::{Address, Network, Script, XOnlyPublicKey};
+use sapio::contract::Context;
+use sapio::template::Template;
+use sapio_base::effects::EffectPath;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// the key pair with secret key `[seed; 32]`, so that tests are reproducible
+pub(crate) fn keypair(seed: u8) -> KeyPair {
+    KeyPair::from_seckey_slice(&Secp256k1::new(), &[seed; 32]).unwrap()
+}
+
+/// the public key of [`keypair`]
+pub(crate) fn key(seed: u8) -> XOnlyPublicKey {
+    XOnlyPublicKey::from_keypair(&keypair(seed))
+}
+
+/// the address a [`key`] compiles to
+pub(crate) fn key_address(seed: u8) -> Address {
+    Address::p2tr_tweaked(
+        TweakedPublicKey::dangerous_assume_tweaked(key(seed)),
+        Network::Regtest,
+    )
+}
+
+/// an address distinct for each `seed`
+pub(crate) fn address(seed: u8) -> Address {
+    Address::p2wsh(&Script::from(vec![seed]), Network::Regtest)
+}
+
+/// a context to compile a contract holding `amount` in
+pub(crate) fn ctx(amount: Amount) -> Context {
+    Context::new(
+        Network::Regtest,
+        amount,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("test").unwrap(),
+        Arc::new(Default::default()),
+    )
+}
+
+/// what `t` pays to `address`
+pub(crate) fn paid_to(t: &Template, address: &Address) -> Amount {
+    let script = address.script_pubkey();
+    t.outputs
+        .iter()
+        .filter(|o| Script::from(o.contract.address.clone()) == script)
+        .fold(Amount::from_sat(0), |a, o| a + o.amount)
+}
+
+/// the fees `t` reserves
+pub(crate) fn fees(t: &Template) -> Amount {
+    t.max - t.total_amount()
+}
@@ -0,0 +1,11 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! `#[derive(Contract)]` must reject contracts whose actions it cannot see.
+#[test]
+fn derive_contract_compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
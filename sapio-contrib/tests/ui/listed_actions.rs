@@ -0,0 +1,22 @@
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::Clause;
+use sapio_macros::{actions, Contract};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(JsonSchema, Serialize, Deserialize, Contract)]
+#[contract(finish(signed))]
+struct Listed {
+    key: bitcoin::XOnlyPublicKey,
+}
+
+#[actions]
+impl Listed {
+    #[finish]
+    fn signed(self, _ctx: Context) {
+        Clause::Key(self.key)
+    }
+}
+
+fn main() {}
@@ -0,0 +1,20 @@
+use sapio::contract::*;
+use sapio::*;
+use sapio_base::Clause;
+use sapio_macros::Contract;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(JsonSchema, Serialize, Deserialize, Contract)]
+struct Forgotten {
+    key: bitcoin::XOnlyPublicKey,
+}
+
+impl Forgotten {
+    #[finish]
+    fn signed(self, _ctx: Context) {
+        Clause::Key(self.key)
+    }
+}
+
+fn main() {}
@@ -6,6 +6,8 @@
 
 #[deny(missing_docs)]
 pub mod session;
+#[deny(missing_docs)]
+pub mod multiparty;
 #[cfg(test)]
 mod tests {
     #[test]
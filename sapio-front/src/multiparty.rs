@@ -0,0 +1,323 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A protocol for N parties to agree on a contract before funding it.
+//!
+//! Every party contributes its public inputs (keys, amounts, salts, ...) as a
+//! JSON object. Once all inputs are collected, each party compiles the
+//! contract locally in deterministic mode (no emulator, no effects, a fixed
+//! root path) and shares a [`CompilationCommitment`]. Funding should only
+//! proceed once [`MultipartySession::verify`] confirms every party derived
+//! the same root address and descriptor from the same inputs.
+//!
+//! The [`Transcript`] records everything exchanged so the agreement can be
+//! audited (and re-verified) later.
+use crate::session::{Menu, SessionError};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::amount::Amount;
+use sapio::contract::context::MapEffectDB;
+use sapio::contract::object::SupportedDescriptors;
+use sapio::contract::{Compiled, Context};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// Errors that can arise during a multiparty session
+#[derive(Debug)]
+pub enum MultipartyError {
+    /// The party is not part of this session
+    UnknownParty(String),
+    /// The party already submitted inputs
+    DuplicateInput(String),
+    /// A party's inputs were not a JSON object
+    InputsNotObject(String),
+    /// Two parties set the same field
+    ConflictingField(String),
+    /// Not every party has submitted inputs
+    MissingInputs(Vec<String>),
+    /// Not every party has submitted a commitment
+    MissingCommitments(Vec<String>),
+    /// The party's commitment differs from ours
+    Mismatch(String),
+    /// Compilation failed
+    Session(SessionError),
+}
+impl std::error::Error for MultipartyError {}
+impl Display for MultipartyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<SessionError> for MultipartyError {
+    fn from(e: SessionError) -> Self {
+        MultipartyError::Session(e)
+    }
+}
+
+/// # Session Parameters
+/// What every party has agreed to compile, before any inputs are shared.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct SessionParams {
+    /// # Contract
+    /// The name of the contract in the [`Menu`]
+    pub contract: String,
+    /// # Network
+    #[schemars(with = "String")]
+    pub network: bitcoin::Network,
+    /// # Amount (sats)
+    /// The amount the contract will be funded with
+    pub amount: u64,
+    /// # Parties
+    /// The identifiers of every party in the session
+    pub parties: Vec<String>,
+}
+
+/// # Compilation Commitment
+/// What a party derived from compiling the session's inputs.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct CompilationCommitment {
+    /// # Inputs Hash
+    /// Hash of the parameters and inputs that were compiled
+    #[schemars(with = "String")]
+    pub inputs_hash: sha256::Hash,
+    /// # Root Script
+    /// hex encoded scriptPubKey of the root contract
+    pub script_pubkey: String,
+    /// # Root Descriptor
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub descriptor: Option<String>,
+}
+
+impl CompilationCommitment {
+    /// Commit to a compiled contract
+    pub fn new(inputs_hash: sha256::Hash, compiled: &Compiled) -> Self {
+        CompilationCommitment {
+            inputs_hash,
+            script_pubkey: format!("{:x}", bitcoin::Script::from(compiled.address.clone())),
+            descriptor: compiled.descriptor.as_ref().map(|d| match d {
+                SupportedDescriptors::Pk(d) => d.to_string(),
+                SupportedDescriptors::XOnly(d) => d.to_string(),
+            }),
+        }
+    }
+}
+
+/// # Session Transcript
+/// Everything exchanged during a multiparty session.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Transcript {
+    /// # Parameters
+    pub params: SessionParams,
+    /// # Inputs
+    /// Each party's inputs, keyed by party
+    pub inputs: BTreeMap<String, Value>,
+    /// # Commitments
+    /// Each party's compilation commitment, keyed by party
+    pub commitments: BTreeMap<String, CompilationCommitment>,
+}
+
+/// A multiparty compilation session, from the perspective of one party.
+pub struct MultipartySession {
+    transcript: Transcript,
+}
+
+impl MultipartySession {
+    /// Start a session with the agreed parameters
+    pub fn new(params: SessionParams) -> Self {
+        MultipartySession {
+            transcript: Transcript {
+                params,
+                inputs: Default::default(),
+                commitments: Default::default(),
+            },
+        }
+    }
+
+    /// Resume (or audit) a session from a transcript
+    pub fn from_transcript(transcript: Transcript) -> Self {
+        MultipartySession { transcript }
+    }
+
+    /// The session's transcript so far
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    fn check_party(&self, party: &str) -> Result<(), MultipartyError> {
+        if self.transcript.params.parties.iter().any(|p| p == party) {
+            Ok(())
+        } else {
+            Err(MultipartyError::UnknownParty(party.into()))
+        }
+    }
+
+    fn missing<T>(&self, m: &BTreeMap<String, T>) -> Vec<String> {
+        self.transcript
+            .params
+            .parties
+            .iter()
+            .filter(|p| !m.contains_key(*p))
+            .cloned()
+            .collect()
+    }
+
+    /// Record a party's public inputs. Inputs must be a JSON object.
+    pub fn add_inputs(&mut self, party: String, inputs: Value) -> Result<(), MultipartyError> {
+        self.check_party(&party)?;
+        if !inputs.is_object() {
+            return Err(MultipartyError::InputsNotObject(party));
+        }
+        if self.transcript.inputs.contains_key(&party) {
+            return Err(MultipartyError::DuplicateInput(party));
+        }
+        self.transcript.inputs.insert(party, inputs);
+        Ok(())
+    }
+
+    /// The contract arguments: every party's inputs merged into one object.
+    /// No two parties may set the same field.
+    pub fn args(&self) -> Result<Value, MultipartyError> {
+        let missing = self.missing(&self.transcript.inputs);
+        if !missing.is_empty() {
+            return Err(MultipartyError::MissingInputs(missing));
+        }
+        let mut args = serde_json::Map::new();
+        for inputs in self.transcript.inputs.values() {
+            for (k, v) in inputs.as_object().into_iter().flatten() {
+                if args.insert(k.clone(), v.clone()).is_some() {
+                    return Err(MultipartyError::ConflictingField(k.clone()));
+                }
+            }
+        }
+        Ok(Value::Object(args))
+    }
+
+    /// Hash of the parameters and all inputs, which every commitment must
+    /// match.
+    pub fn inputs_hash(&self) -> Result<sha256::Hash, MultipartyError> {
+        let bytes = serde_json::to_vec(&(&self.transcript.params, &self.transcript.inputs))
+            .map_err(SessionError::Json)?;
+        Ok(sha256::Hash::hash(&bytes))
+    }
+
+    /// The context contracts are compiled with. No emulator or effects are
+    /// used, so that every party compiles exactly the same thing.
+    fn deterministic_context(&self) -> Context {
+        Context::new(
+            self.transcript.params.network,
+            Amount::from_sat(self.transcript.params.amount),
+            Arc::new(CTVAvailable),
+            "multiparty_session".try_into().unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+
+    /// Compile the contract locally and record our commitment as `party`.
+    pub fn compile(
+        &mut self,
+        menu: &Menu,
+        party: String,
+    ) -> Result<(Compiled, CompilationCommitment), MultipartyError> {
+        self.check_party(&party)?;
+        let compiled = menu.compile(
+            self.transcript.params.contract.clone(),
+            self.args()?,
+            self.deterministic_context(),
+        )?;
+        let commitment = CompilationCommitment::new(self.inputs_hash()?, &compiled);
+        self.transcript
+            .commitments
+            .insert(party, commitment.clone());
+        Ok((compiled, commitment))
+    }
+
+    /// Record another party's commitment.
+    pub fn add_commitment(
+        &mut self,
+        party: String,
+        commitment: CompilationCommitment,
+    ) -> Result<(), MultipartyError> {
+        self.check_party(&party)?;
+        self.transcript.commitments.insert(party, commitment);
+        Ok(())
+    }
+
+    /// Check that every party committed to the same inputs, address, and
+    /// descriptor. Returns the agreed commitment.
+    pub fn verify(&self) -> Result<CompilationCommitment, MultipartyError> {
+        let missing = self.missing(&self.transcript.commitments);
+        if !missing.is_empty() {
+            return Err(MultipartyError::MissingCommitments(missing));
+        }
+        let inputs_hash = self.inputs_hash()?;
+        let mut agreed: Option<&CompilationCommitment> = None;
+        for (party, c) in self.transcript.commitments.iter() {
+            if c.inputs_hash != inputs_hash || agreed.map_or(false, |a| a != c) {
+                return Err(MultipartyError::Mismatch(party.clone()));
+            }
+            agreed = Some(c);
+        }
+        agreed
+            .cloned()
+            .ok_or_else(|| MultipartyError::MissingCommitments(vec![]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn test_inputs_and_verify() {
+        let mut s = MultipartySession::new(SessionParams {
+            contract: "Example".into(),
+            network: bitcoin::Network::Regtest,
+            amount: 100_000,
+            parties: vec!["alice".into(), "bob".into()],
+        });
+        s.add_inputs("alice".into(), json!({"alice_key": "aa"}))
+            .unwrap();
+        assert!(matches!(
+            s.add_inputs("carol".into(), json!({})),
+            Err(MultipartyError::UnknownParty(_))
+        ));
+        assert!(matches!(s.args(), Err(MultipartyError::MissingInputs(_))));
+        s.add_inputs("bob".into(), json!({"bob_key": "bb", "salt": 7}))
+            .unwrap();
+        assert_eq!(
+            s.args().unwrap(),
+            json!({"alice_key": "aa", "bob_key": "bb", "salt": 7})
+        );
+
+        let commitment = CompilationCommitment {
+            inputs_hash: s.inputs_hash().unwrap(),
+            script_pubkey: "51".into(),
+            descriptor: None,
+        };
+        s.add_commitment("alice".into(), commitment.clone())
+            .unwrap();
+        assert!(matches!(
+            s.verify(),
+            Err(MultipartyError::MissingCommitments(_))
+        ));
+        let mut different = commitment.clone();
+        different.script_pubkey = "52".into();
+        s.add_commitment("bob".into(), different).unwrap();
+        assert!(matches!(s.verify(), Err(MultipartyError::Mismatch(_))));
+        s.add_commitment("bob".into(), commitment.clone()).unwrap();
+        assert_eq!(s.verify().unwrap(), commitment);
+
+        let transcript = serde_json::to_string(s.transcript()).unwrap();
+        let audited =
+            MultipartySession::from_transcript(serde_json::from_str(&transcript).unwrap());
+        assert_eq!(audited.verify().unwrap(), commitment);
+    }
+}
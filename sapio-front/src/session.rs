@@ -11,6 +11,7 @@ use bitcoin::util::amount::Amount;
 use sapio::contract::context::MapEffectDB;
 
 use sapio::contract::object::Program;
+use sapio::contract::object::PsbtVersion;
 use sapio::contract::{Compilable, CompilationError, Compiled, Context};
 use sapio::util::extended_address::ExtendedAddress;
 use sapio_ctv_emulator_trait::CTVAvailable;
@@ -154,6 +155,7 @@ impl Action {
                         HashMap::new(),
                         Rc::new(TxIndexLogger::new()),
                         &CTVAvailable,
+                        PsbtVersion::V0,
                     )
                     .ok()?;
                 println!("{:?}", program);
@@ -0,0 +1,59 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A trait for contracts that open a two party payment channel
+use sapio_base::timelocks::{AnyRelTimeLock, RelHeight};
+use sapio_trait::SapioJSONTrait;
+use schemars::*;
+use serde::*;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// # Channel Open Trait
+/// Open a channel between a local and a remote party with the given initial
+/// balances. A party may close unilaterally after `dispute_timeout`.
+#[derive(Serialize, JsonSchema, Deserialize, Clone)]
+pub struct ChannelOpenTraitVersion0_1_0 {
+    /// # Local Key
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub local_key: bitcoin::XOnlyPublicKey,
+    /// # Remote Key
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub remote_key: bitcoin::XOnlyPublicKey,
+    /// # Local Balance (sats)
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub local_amount: bitcoin::util::amount::Amount,
+    /// # Remote Balance (sats)
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub remote_amount: bitcoin::util::amount::Amount,
+    /// # Dispute Timeout
+    pub dispute_timeout: AnyRelTimeLock,
+}
+
+/// Every version of the Channel Open Trait
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum Versions {
+    /// # Channel Open Trait API
+    ChannelOpenTraitVersion0_1_0(ChannelOpenTraitVersion0_1_0),
+}
+
+impl SapioJSONTrait for ChannelOpenTraitVersion0_1_0 {
+    fn get_example_for_api_checking() -> Value {
+        let key = bitcoin::XOnlyPublicKey::from_str(super::EXAMPLE_KEY).unwrap();
+        serde_json::to_value(Versions::ChannelOpenTraitVersion0_1_0(
+            ChannelOpenTraitVersion0_1_0 {
+                local_key: key,
+                remote_key: key,
+                local_amount: bitcoin::util::amount::Amount::from_sat(0),
+                remote_amount: bitcoin::util::amount::Amount::from_sat(0),
+                dispute_timeout: RelHeight::from(0).into(),
+            },
+        ))
+        .unwrap()
+    }
+}
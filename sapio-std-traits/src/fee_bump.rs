@@ -0,0 +1,49 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A trait for contracts that pay fees on behalf of other transactions
+use sapio_trait::SapioJSONTrait;
+use schemars::*;
+use serde::*;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// # Fee Bump Sponsor Trait
+/// Spend the contract's funds to get `sponsored` transactions mined at (at
+/// least) `feerate_per_vbyte`, returning any leftover funds to `change`.
+#[derive(Serialize, JsonSchema, Deserialize, Clone)]
+pub struct FeeBumpSponsorTraitVersion0_1_0 {
+    /// # Sponsored Transactions
+    /// The txids of the transactions to bump
+    #[schemars(with = "Vec<bitcoin::hashes::sha256d::Hash>")]
+    pub sponsored: Vec<bitcoin::Txid>,
+    /// # Target Feerate (sats per vbyte)
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub feerate_per_vbyte: bitcoin::util::amount::Amount,
+    /// # Change Address
+    pub change: bitcoin::Address,
+}
+
+/// Every version of the Fee Bump Sponsor Trait
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum Versions {
+    /// # Fee Bump Sponsor Trait API
+    FeeBumpSponsorTraitVersion0_1_0(FeeBumpSponsorTraitVersion0_1_0),
+}
+
+impl SapioJSONTrait for FeeBumpSponsorTraitVersion0_1_0 {
+    fn get_example_for_api_checking() -> Value {
+        serde_json::to_value(Versions::FeeBumpSponsorTraitVersion0_1_0(
+            FeeBumpSponsorTraitVersion0_1_0 {
+                sponsored: vec![],
+                feerate_per_vbyte: bitcoin::util::amount::Amount::from_sat(0),
+                change: bitcoin::Address::from_str(super::EXAMPLE_ADDRESS).unwrap(),
+            },
+        ))
+        .unwrap()
+    }
+}
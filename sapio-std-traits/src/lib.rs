@@ -0,0 +1,49 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Standard traits for plugin interoperability.
+//!
+//! Each trait is a versioned argument type implementing
+//! [`sapio_trait::SapioJSONTrait`]. A plugin implements a trait by accepting
+//! the trait's `Versions` enum variant in its own arguments, and a plugin
+//! depending on one (e.g., via `SapioHostAPI<PaymentTraitVersion0_1_0>`) can
+//! then call any such plugin without bespoke glue types.
+//!
+//! Traits are never changed once published: changes are made by adding a new
+//! version alongside the old one.
+#![deny(missing_docs)]
+pub mod channel;
+pub mod fee_bump;
+pub mod payment;
+pub mod vault;
+
+pub use channel::ChannelOpenTraitVersion0_1_0;
+pub use fee_bump::FeeBumpSponsorTraitVersion0_1_0;
+pub use payment::PaymentTraitVersion0_1_0;
+pub use vault::VaultTraitVersion0_1_0;
+
+/// A key used in the examples for API checking
+pub(crate) const EXAMPLE_KEY: &str =
+    "9c7ad3670650f427bedac55f9a3f6779c1e7a26ab7715299aa0eadb1a09c0e62";
+/// An address used in the examples for API checking
+pub(crate) const EXAMPLE_ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+#[cfg(test)]
+mod test {
+    use sapio_base::plugin_args::CreateArgs;
+    use sapio_trait::SapioJSONTrait;
+    fn implements_self<T: SapioJSONTrait, V: schemars::JsonSchema>() {
+        let api = serde_json::to_value(schemars::schema_for!(CreateArgs<V>)).unwrap();
+        T::check_trait_implemented_inner(&api).unwrap();
+    }
+    #[test]
+    fn test_examples_match_schemas() {
+        implements_self::<super::PaymentTraitVersion0_1_0, super::payment::Versions>();
+        implements_self::<super::VaultTraitVersion0_1_0, super::vault::Versions>();
+        implements_self::<super::ChannelOpenTraitVersion0_1_0, super::channel::Versions>();
+        implements_self::<super::FeeBumpSponsorTraitVersion0_1_0, super::fee_bump::Versions>();
+    }
+}
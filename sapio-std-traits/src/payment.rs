@@ -0,0 +1,50 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A trait for contracts that make a single payment
+use sapio_trait::SapioJSONTrait;
+use schemars::*;
+use serde::*;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// # Payment Trait
+/// Pay an amount to an address, in whatever way the implementing contract
+/// sees fit (e.g., immediately, delayed, or batched with others).
+#[derive(Serialize, JsonSchema, Deserialize, Clone)]
+pub struct PaymentTraitVersion0_1_0 {
+    /// # Address
+    /// The Address to send to
+    pub address: bitcoin::Address,
+    /// # Amount (sats)
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub amount: bitcoin::util::amount::Amount,
+    /// # Memo
+    /// An optional note for the recipient
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub memo: Option<String>,
+}
+
+/// Every version of the Payment Trait
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum Versions {
+    /// # Payment Trait API
+    PaymentTraitVersion0_1_0(PaymentTraitVersion0_1_0),
+}
+
+impl SapioJSONTrait for PaymentTraitVersion0_1_0 {
+    fn get_example_for_api_checking() -> Value {
+        serde_json::to_value(Versions::PaymentTraitVersion0_1_0(
+            PaymentTraitVersion0_1_0 {
+                address: bitcoin::Address::from_str(super::EXAMPLE_ADDRESS).unwrap(),
+                amount: bitcoin::util::amount::Amount::from_sat(0),
+                memo: None,
+            },
+        ))
+        .unwrap()
+    }
+}
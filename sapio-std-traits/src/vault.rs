@@ -0,0 +1,57 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A trait for vaults that trickle funds from cold to hot storage
+use sapio_base::timelocks::{AnyRelTimeLock, RelHeight};
+use sapio_trait::SapioJSONTrait;
+use schemars::*;
+use serde::*;
+use serde_json::Value;
+use std::str::FromStr;
+
+/// # Vault Trait
+/// Release `amount_step` to hot storage every `timeout`, `n_steps` times.
+/// Funds in hot storage may be reverted to cold storage until `mature`, and
+/// all remaining funds may be sent to cold storage at any time.
+#[derive(Serialize, JsonSchema, Deserialize, Clone)]
+pub struct VaultTraitVersion0_1_0 {
+    /// # Cold Storage Address
+    pub cold_storage: bitcoin::Address,
+    /// # Hot Storage Address
+    pub hot_storage: bitcoin::Address,
+    /// # Number of Steps
+    pub n_steps: u64,
+    /// # Amount per Step (sats)
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub amount_step: bitcoin::util::amount::Amount,
+    /// # How long between steps
+    pub timeout: AnyRelTimeLock,
+    /// # How long before hot storage is spendable
+    pub mature: AnyRelTimeLock,
+}
+
+/// Every version of the Vault Trait
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub enum Versions {
+    /// # Vault Trait API
+    VaultTraitVersion0_1_0(VaultTraitVersion0_1_0),
+}
+
+impl SapioJSONTrait for VaultTraitVersion0_1_0 {
+    fn get_example_for_api_checking() -> Value {
+        let address = bitcoin::Address::from_str(super::EXAMPLE_ADDRESS).unwrap();
+        serde_json::to_value(Versions::VaultTraitVersion0_1_0(VaultTraitVersion0_1_0 {
+            cold_storage: address.clone(),
+            hot_storage: address,
+            n_steps: 1,
+            amount_step: bitcoin::util::amount::Amount::from_sat(0),
+            timeout: RelHeight::from(0).into(),
+            mature: RelHeight::from(0).into(),
+        }))
+        .unwrap()
+    }
+}
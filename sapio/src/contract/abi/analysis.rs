@@ -0,0 +1,275 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A machine readable report of what it costs to spend each path of a
+//! compiled contract, and what is needed to do so, for auditors.
+use super::object::{Object, ObjectError, SupportedDescriptors};
+use super::witness_template::SpendPath;
+use ::miniscript::miniscript::decode::Terminal;
+use ::miniscript::{Descriptor, DescriptorTrait, Miniscript, Tap};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::XOnlyPublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// the weight of an input, not counting its witness: outpoint, empty
+/// scriptSig and sequence
+const INPUT_BASE_WEIGHT: usize = (32 + 4 + 1 + 4) * 4;
+/// a BIP-340 signature with the default sighash, and its length prefix
+const KEY_PATH_WITNESS: usize = 1 + 1 + 64;
+
+/// # Spend Path Report
+/// The cost of, and requirements for, one way to spend an output.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SpendPathReport {
+    /// the path of the contract whose output is spent
+    pub path: String,
+    /// how the output is spent
+    pub spend_path: SpendPath,
+    /// the size of the leaf (or witness) script, zero for a key path spend
+    pub script_size: usize,
+    /// the size of the control block, zero if not a taproot script path
+    pub control_block_size: usize,
+    /// the largest witness (including the script and control block) a spend
+    /// may need, in weight units
+    pub max_witness_weight: usize,
+    /// the fee for the input's share of a transaction at the report's
+    /// feerate, at the largest witness
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub estimated_fee: Amount,
+    /// hex encoded keys which may need to sign
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub keys: BTreeSet<String>,
+    /// absolute timelocks required (CLTV)
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub after: BTreeSet<u32>,
+    /// relative timelocks required (CSV)
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub older: BTreeSet<u32>,
+    /// hex encoded hashes whose preimages may be needed
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub hashes: BTreeSet<String>,
+    /// CTV template hashes the spend must match one of
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub templates: BTreeSet<sha256::Hash>,
+    /// true if the requirements could not be read from the script, e.g. for
+    /// a covenant leaf which is not miniscript
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub opaque: bool,
+}
+
+/// # Compilation Report
+/// A [`SpendPathReport`] for every spend path of every contract reachable
+/// from a compiled object.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CompilationReport {
+    /// the feerate fees were estimated at, in sats per vbyte
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub feerate: Amount,
+    /// every spend path
+    pub spend_paths: Vec<SpendPathReport>,
+}
+
+impl SpendPathReport {
+    fn new(path: &str, spend_path: SpendPath, feerate: Amount) -> Self {
+        SpendPathReport {
+            path: path.into(),
+            spend_path,
+            script_size: 0,
+            control_block_size: 0,
+            max_witness_weight: 0,
+            estimated_fee: feerate,
+            keys: Default::default(),
+            after: Default::default(),
+            older: Default::default(),
+            hashes: Default::default(),
+            templates: Default::default(),
+            opaque: false,
+        }
+    }
+    /// set the witness weight, and the fee at `feerate` for it
+    fn with_witness(mut self, weight: usize, feerate: Amount) -> Self {
+        self.max_witness_weight = weight;
+        let vbytes = (INPUT_BASE_WEIGHT + weight + 3) / 4;
+        self.estimated_fee = feerate * vbytes as u64;
+        self
+    }
+    /// record everything `ms` requires
+    fn with_requirements(mut self, ms: &Miniscript<XOnlyPublicKey, Tap>) -> Self {
+        for node in ms.iter() {
+            match &node.node {
+                Terminal::PkK(k) => {
+                    self.keys.insert(k.to_hex());
+                }
+                Terminal::MultiA(_, ks) => self.keys.extend(ks.iter().map(|k| k.to_hex())),
+                Terminal::After(n) => {
+                    self.after.insert(*n);
+                }
+                Terminal::Older(n) => {
+                    self.older.insert(*n);
+                }
+                Terminal::Sha256(h) => {
+                    self.hashes.insert(h.to_hex());
+                }
+                Terminal::Hash256(h) => {
+                    self.hashes.insert(h.to_hex());
+                }
+                Terminal::Ripemd160(h) => {
+                    self.hashes.insert(h.to_hex());
+                }
+                Terminal::Hash160(h) => {
+                    self.hashes.insert(h.to_hex());
+                }
+                Terminal::TxTemplate(h) => {
+                    self.templates.insert(*h);
+                }
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
+/// the weight of a script path witness: the satisfaction, then the script
+/// and control block with their length prefixes, and the item count
+fn script_path_weight(satisfaction: usize, script: usize, control_block: usize) -> usize {
+    let prefix = |n: usize| bitcoin::VarInt(n as u64).len();
+    1 + satisfaction + prefix(script) + script + prefix(control_block) + control_block
+}
+
+impl Object {
+    fn spend_path_reports(
+        &self,
+        path: &str,
+        feerate: Amount,
+    ) -> Result<Vec<SpendPathReport>, ObjectError> {
+        let mut res = vec![];
+        match (&self.descriptor, &self.raw_taproot) {
+            (Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))), _) => {
+                res.push(
+                    SpendPathReport::new(path, SpendPath::KeyPath, feerate)
+                        .with_witness(1 + KEY_PATH_WITNESS, feerate),
+                );
+                for (depth, ms) in t.iter_scripts() {
+                    let script = ms.encode();
+                    let cb = 33 + 32 * depth as usize;
+                    let mut r = SpendPathReport::new(
+                        path,
+                        SpendPath::ScriptPath {
+                            leaf_hash: TapLeafHash::from_script(&script, LeafVersion::TapScript)
+                                .to_hex(),
+                            depth,
+                        },
+                        feerate,
+                    )
+                    .with_requirements(ms);
+                    r.script_size = script.len();
+                    r.control_block_size = cb;
+                    let sat = ms.max_satisfaction_size()?;
+                    res.push(r.with_witness(script_path_weight(sat, script.len(), cb), feerate));
+                }
+            }
+            (Some(SupportedDescriptors::Pk(d)), _) => {
+                let script = d.explicit_script()?;
+                let mut r = SpendPathReport::new(path, SpendPath::WitnessScript, feerate);
+                r.script_size = script.len();
+                // the descriptor's weight includes the scriptSig length byte
+                let weight = d.max_satisfaction_weight()?.saturating_sub(4);
+                res.push(r.with_witness(weight, feerate));
+            }
+            (_, Some(raw)) => {
+                let info = raw.spend_info(&Secp256k1::verification_only())?;
+                res.push(
+                    SpendPathReport::new(path, SpendPath::KeyPath, feerate)
+                        .with_witness(1 + KEY_PATH_WITNESS, feerate),
+                );
+                for (_, script) in raw.leaves.iter() {
+                    let cb = info
+                        .control_block(&(script.clone(), LeafVersion::TapScript))
+                        .expect("Must be present")
+                        .size();
+                    let leaf_hash = TapLeafHash::from_script(script, LeafVersion::TapScript);
+                    let depth = ((cb - 33) / 32) as u8;
+                    let r = SpendPathReport::new(
+                        path,
+                        SpendPath::ScriptPath {
+                            leaf_hash: leaf_hash.to_hex(),
+                            depth,
+                        },
+                        feerate,
+                    );
+                    let mut r = match Miniscript::<XOnlyPublicKey, Tap>::parse(script) {
+                        Ok(ms) => {
+                            let sat = ms.max_satisfaction_size()?;
+                            r.with_requirements(&ms)
+                                .with_witness(script_path_weight(sat, script.len(), cb), feerate)
+                        }
+                        Err(_) => {
+                            let mut r =
+                                r.with_witness(script_path_weight(0, script.len(), cb), feerate);
+                            r.opaque = true;
+                            r
+                        }
+                    };
+                    r.script_size = script.len();
+                    r.control_block_size = cb;
+                    res.push(r);
+                }
+            }
+            _ => {}
+        }
+        Ok(res)
+    }
+
+    /// Report the cost of every spend path of every contract reachable from
+    /// this object, with fees estimated at `feerate` sats per vbyte.
+    pub fn compilation_report(&self, feerate: Amount) -> Result<CompilationReport, ObjectError> {
+        let mut spend_paths = vec![];
+        for (path, obj) in self.reachable_objects() {
+            spend_paths.extend(obj.spend_path_reports(&path, feerate)?);
+        }
+        Ok(CompilationReport {
+            feerate,
+            spend_paths,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use std::str::FromStr;
+    const K: &str = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+    #[test]
+    fn reports_each_leaf() {
+        let d = Descriptor::<XOnlyPublicKey>::from_str(&format!(
+            "tr({k},{{pk({k}),and_v(v:pk({k}),older(144))}})",
+            k = K
+        ))
+        .unwrap();
+        let o = Context::compiled_from_descriptor(d, None);
+        let report = o.compilation_report(Amount::from_sat(2)).unwrap();
+        assert_eq!(report.spend_paths.len(), 3);
+        assert_eq!(report.spend_paths[0].spend_path, SpendPath::KeyPath);
+        let timelocked = report
+            .spend_paths
+            .iter()
+            .find(|r| !r.older.is_empty())
+            .unwrap();
+        assert_eq!(timelocked.older.iter().collect::<Vec<_>>(), vec![&144]);
+        assert!(timelocked.keys.contains(K));
+        assert_eq!(timelocked.control_block_size, 65);
+        for r in report.spend_paths.iter() {
+            assert!(r.max_witness_weight > 0);
+            assert!(r.estimated_fee > Amount::from_sat(2 * 41));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks for when a bound transaction's timelocks permit it to be mined
+use bitcoin::Transaction;
+use sapio_base::txindex::ChainTip;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0x0000ffff;
+const SEQUENCE_GRANULARITY: u32 = 9;
+
+/// # Broadcast After
+/// The earliest point a bound transaction may be mined, from its nLockTime and
+/// the nSequence of the input spending the contract.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BroadcastAfter {
+    /// # Minimum Height
+    /// The transaction may only be mined in blocks at or above this height.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub height: Option<u32>,
+    /// # Minimum Time
+    /// The transaction may only be mined once median time past exceeds this.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub time: Option<u32>,
+    /// # Relative Blocks
+    /// Confirmations the spent output needs first. Folded into `height`
+    /// when the spent output's confirmation height is known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub relative_blocks: Option<u32>,
+    /// # Relative Seconds
+    /// Seconds (by median time past) that must pass after the spent output
+    /// confirms.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub relative_seconds: Option<u32>,
+    /// # Ready
+    /// Whether the transaction could be mined in the next block, if that can
+    /// be determined from the chain tip.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub ready: Option<bool>,
+}
+
+impl BroadcastAfter {
+    /// Compute when `tx` may be mined, given the (earliest) height the output
+    /// spent by input 0 confirms at and the current chain tip.
+    pub fn compute(tx: &Transaction, funding_height: Option<u32>, tip: Option<ChainTip>) -> Self {
+        let mut res = BroadcastAfter::default();
+        let locktime_enabled = tx.input.iter().any(|i| i.sequence != u32::MAX);
+        if locktime_enabled && tx.lock_time != 0 {
+            if tx.lock_time < LOCKTIME_THRESHOLD {
+                res.height = Some(tx.lock_time + 1);
+            } else {
+                res.time = Some(tx.lock_time);
+            }
+        }
+        if let Some(seq) = tx.input.get(0).map(|i| i.sequence) {
+            let value = seq & SEQUENCE_MASK;
+            if tx.version >= 2 && seq & SEQUENCE_DISABLE_FLAG == 0 && value != 0 {
+                if seq & SEQUENCE_TYPE_FLAG != 0 {
+                    res.relative_seconds = Some(value << SEQUENCE_GRANULARITY);
+                } else {
+                    match funding_height {
+                        Some(h) => {
+                            res.height = Some(std::cmp::max(res.height.unwrap_or(0), h + value));
+                        }
+                        None => res.relative_blocks = Some(value),
+                    }
+                }
+            }
+        }
+        if let Some(tip) = tip {
+            let next = tip.height + 1;
+            let unknown = res.relative_blocks.is_some()
+                || res.relative_seconds.is_some()
+                || funding_height.is_none();
+            if !unknown {
+                let confirmed = funding_height.map_or(false, |h| h <= tip.height);
+                res.ready = Some(
+                    confirmed
+                        && res.height.map_or(true, |h| next >= h)
+                        && res.time.map_or(true, |t| tip.median_time_past > t),
+                );
+            }
+        }
+        res
+    }
+
+    /// The earliest height the transaction could be confirmed at, given the
+    /// earliest height the output it spends confirms at.
+    pub fn earliest_confirmation(&self, funding_height: Option<u32>) -> Option<u32> {
+        funding_height.map(|f| std::cmp::max(f, self.height.unwrap_or(0)))
+    }
+}
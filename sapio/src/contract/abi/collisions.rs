@@ -0,0 +1,81 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Detects distinct templates in a compiled contract which pay the same
+//! address. Such reuse leaks privacy and, where the templates are otherwise
+//! identical, makes it ambiguous which branch a transaction came from.
+//!
+//! Collisions between compiled child contracts may be avoided by compiling
+//! with [`Context::with_address_salting`](crate::Context::with_address_salting).
+use super::object::Object;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::sha256;
+use bitcoin::Script;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// # Output Use
+/// One output paying a colliding address.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OutputUse {
+    /// the path of the contract whose template creates the output
+    pub path: String,
+    /// the template's CTV hash
+    pub template: sha256::Hash,
+    /// the output's index in the template
+    pub output: u32,
+}
+
+/// # Address Collision
+/// An address paid by more than one distinct template.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AddressCollision {
+    /// the address (or hex script, if it has no address form)
+    pub address: String,
+    /// every output paying it
+    pub uses: Vec<OutputUse>,
+}
+
+impl Object {
+    /// Find every address which is paid by more than one distinct template
+    /// reachable from this object. OP_RETURN outputs are ignored.
+    pub fn address_collisions(&self) -> Vec<AddressCollision> {
+        let mut by_script: BTreeMap<Script, (String, Vec<OutputUse>)> = BTreeMap::new();
+        for (path, obj) in self.reachable_objects() {
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                for (i, out) in tmpl.outputs.iter().enumerate() {
+                    let address = match &out.contract.address {
+                        ExtendedAddress::OpReturn(_) => continue,
+                        ExtendedAddress::Address(a) => a.to_string(),
+                        ExtendedAddress::Unknown(s) => format!("{:x}", s),
+                    };
+                    by_script
+                        .entry(Script::from(out.contract.address.clone()))
+                        .or_insert_with(|| (address, vec![]))
+                        .1
+                        .push(OutputUse {
+                            path: path.clone(),
+                            template: tmpl.hash(),
+                            output: i as u32,
+                        });
+                }
+            }
+        }
+        by_script
+            .into_iter()
+            .filter_map(|(_, (address, mut uses))| {
+                uses.sort();
+                uses.dedup();
+                let first = uses.first()?.template;
+                if uses.iter().all(|u| u.template == first) {
+                    None
+                } else {
+                    Some(AddressCollision { address, uses })
+                }
+            })
+            .collect()
+    }
+}
@@ -6,11 +6,19 @@
 
 //! ABI for contract resumption
 
-use sapio_base::effects::EffectPath;
+use super::object::Object;
+use crate::contract::compiler::schema::check_effect_arg;
+use crate::contract::{Compilable, CompilationError, Context};
+use crate::template::Template;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::SchnorrSighashType;
+use sapio_base::effects::{EffectPath, MapEffectDB};
 use sapio_base::serialization_helpers::SArc;
 use schemars::schema::RootSchema;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 /// Instructions for how to resume a contract compilation at a given point
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
@@ -22,6 +30,16 @@ pub struct ContinuationPoint {
     #[serde(serialize_with = "sapio_base::serialization_helpers::serializer")]
     #[serde(deserialize_with = "sapio_base::serialization_helpers::deserializer")]
     pub path: Arc<EffectPath>,
+    /// The sighash type spends via this continuation must be signed with, if
+    /// not the default
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        serialize_with = "serialize_sighash",
+        deserialize_with = "deserialize_sighash"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub sighash: Option<SchnorrSighashType>,
 }
 impl ContinuationPoint {
     /// Creates a new continuation
@@ -29,25 +47,177 @@ impl ContinuationPoint {
         ContinuationPoint {
             schema: schema.map(SArc),
             path,
+            sighash: None,
         }
     }
+    /// A hash of the schema alone, stable across compilations and versions
+    /// of sapio, or None if there is no schema.
+    pub fn schema_hash(&self) -> Option<sha256::Hash> {
+        self.schema.as_ref().map(|s| {
+            // serde_json::Value sorts object keys, so this is canonical
+            let v = serde_json::to_value(&*s.0).unwrap_or_default();
+            tagged_hash(
+                b"sapio/continuation/schema",
+                &[&serde_json::to_vec(&v).unwrap_or_default()],
+            )
+        })
+    }
+    /// A hash of the path and schema, stable across compilations and versions
+    /// of sapio. Effects saved against a continuation point apply as they
+    /// did before if and only if its stable hash is unchanged.
+    pub fn stable_hash(&self) -> sha256::Hash {
+        let schema = self.schema_hash();
+        tagged_hash(
+            b"sapio/continuation",
+            &[
+                self.path.to_string().as_bytes(),
+                schema.as_ref().map_or(&[][..], |h| &h[..]),
+            ],
+        )
+    }
+    /// Require spends via this continuation to sign with `sighash`
+    pub fn with_sighash(mut self, sighash: Option<SchnorrSighashType>) -> Self {
+        self.sighash = sighash;
+        self
+    }
+}
+
+/// The result of [`Object::continue_with`]
+#[derive(Clone, Debug)]
+pub struct Continued {
+    /// the contract recompiled with the effect
+    pub compiled: Object,
+    /// the effects it was recompiled with, to continue from again later
+    pub effects: MapEffectDB,
+    /// the templates reachable from `compiled` which were not reachable
+    /// before
+    pub new_templates: HashMap<sha256::Hash, Template>,
+}
+
+impl Object {
+    /// Find the continuation point at `path` in this object or any object
+    /// reachable from it
+    pub fn find_continuation(&self, path: &EffectPath) -> Option<&ContinuationPoint> {
+        let key = SArc(Arc::new(path.clone()));
+        self.reachable_objects()
+            .into_iter()
+            .find_map(|(_, o)| o.continue_apis.get(&key))
+    }
+
+    /// Every template reachable from this object
+    fn all_templates(&self) -> HashMap<sha256::Hash, &Template> {
+        self.reachable_objects()
+            .into_iter()
+            .flat_map(|(_, o)| o.ctv_to_tx.iter().chain(o.suggested_txs.iter()))
+            .map(|(h, t)| (*h, t))
+            .collect()
+    }
+
+    /// Recompile `contract` (which this object was compiled from, with
+    /// `effects`) with the effect `name` set to `arg` at the continuation
+    /// point at `path`.
+    ///
+    /// `arg` is checked against the continuation's schema first. `mk_ctx`
+    /// builds the top level Context to compile with from the new effects,
+    /// and must be at the same path this object was compiled at.
+    pub fn continue_with<C, F>(
+        &self,
+        contract: &C,
+        effects: &MapEffectDB,
+        path: &EffectPath,
+        name: &str,
+        arg: serde_json::Value,
+        mk_ctx: F,
+    ) -> Result<Continued, CompilationError>
+    where
+        C: Compilable + ?Sized,
+        F: FnOnce(Arc<MapEffectDB>) -> Context,
+    {
+        let point = self.find_continuation(path).ok_or_else(|| {
+            CompilationError::TerminateWith(format!("No continuation point at {}", path))
+        })?;
+        let name = Arc::new(String::from(name));
+        check_effect_arg(
+            point.schema.as_ref().map(|s| &*s.0),
+            &point.path,
+            &name,
+            &arg,
+        )?;
+        let mut effects = effects.clone();
+        effects.insert(SArc(point.path.clone()), SArc(name), arg);
+        let ctx = mk_ctx(Arc::new(effects.clone()));
+        if **ctx.path() != *self.root_path.0 {
+            return Err(CompilationError::TerminateWith(format!(
+                "Must recompile at {}, not {}",
+                self.root_path.0,
+                ctx.path()
+            )));
+        }
+        let compiled = contract.compile(ctx)?;
+        let old = self.all_templates();
+        let new_templates = compiled
+            .all_templates()
+            .into_iter()
+            .filter(|(h, _)| !old.contains_key(h))
+            .map(|(h, t)| (h, t.clone()))
+            .collect();
+        Ok(Continued {
+            compiled,
+            effects,
+            new_templates,
+        })
+    }
+}
+
+/// BIP-340 style tagged hash of length prefixed `parts`
+fn tagged_hash(tag: &[u8], parts: &[&[u8]]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for p in parts {
+        engine.input(&(p.len() as u64).to_le_bytes());
+        engine.input(p);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+fn serialize_sighash<S>(v: &Option<SchnorrSighashType>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    v.map(|t| t.to_string()).serialize(s)
+}
+
+fn deserialize_sighash<'de, D>(d: D) -> Result<Option<SchnorrSighashType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(d)?
+        .map(|s| SchnorrSighashType::from_str(&s).map_err(serde::de::Error::custom))
+        .transpose()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use sapio_base::effects::PathFragment;
+    use std::convert::TryFrom;
     #[test]
     fn test_continuation_point_ser() -> Result<(), Box<dyn std::error::Error>> {
         let a: ContinuationPoint = ContinuationPoint::at(
             Some(Arc::new(schemars::schema_for!(ContinuationPoint))),
-            EffectPath::push(None, PathFragment::Named(SArc(Arc::new("one".into())))),
+            Arc::new(EffectPath::try_from("one").unwrap()),
         );
         let b: ContinuationPoint = serde_json::from_str(&format!(
             "{{\"schema\":{},\"path\":\"one\"}}",
             serde_json::to_string(&schemars::schema_for!(ContinuationPoint))?
         ))?;
         assert_eq!(a, b);
+        let c = a
+            .clone()
+            .with_sighash(Some(SchnorrSighashType::SinglePlusAnyoneCanPay));
+        let d: ContinuationPoint = serde_json::from_str(&serde_json::to_string(&c)?)?;
+        assert_eq!(c, d);
         Ok(())
     }
 }
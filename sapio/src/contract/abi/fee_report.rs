@@ -0,0 +1,59 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reports the fees each template in a compiled contract pays, so that
+//! underfunded branches can be found without binding the contract.
+use super::object::Object;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use serde::{Deserialize, Serialize};
+
+/// # Template Fees
+/// The fees paid by one template.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TemplateFees {
+    /// the path of the contract the template spends
+    pub path: String,
+    /// the template's CTV hash
+    pub template: sha256::Hash,
+    /// the fees the template pays
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fees: Amount,
+    /// the template's virtual size, not counting witnesses
+    pub vsize: u64,
+    /// the minimum feerate required, in sats per vbyte, if any
+    #[serde(with = "bitcoin::util::amount::serde::as_sat::opt")]
+    pub min_feerate: Option<Amount>,
+}
+
+impl TemplateFees {
+    /// the feerate paid, in sats per vbyte, before witnesses are added
+    pub fn feerate(&self) -> f64 {
+        self.fees.as_sat() as f64 / std::cmp::max(self.vsize, 1) as f64
+    }
+}
+
+impl Object {
+    /// The fees paid by every template reachable from this object
+    pub fn fee_report(&self) -> Vec<TemplateFees> {
+        let mut res = vec![];
+        for (path, obj) in self.reachable_objects() {
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                res.push(TemplateFees {
+                    path: path.clone(),
+                    template: tmpl.hash(),
+                    fees: tmpl
+                        .max
+                        .checked_sub(tmpl.total_amount())
+                        .unwrap_or(Amount::from_sat(0)),
+                    vsize: (tmpl.tx.get_weight() as u64 + 3) / 4,
+                    min_feerate: tmpl.min_feerate_sats_vbyte,
+                });
+            }
+        }
+        res
+    }
+}
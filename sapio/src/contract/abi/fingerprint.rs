@@ -0,0 +1,131 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fingerprints of compiled contracts, which parties to a multi-party
+//! protocol can exchange to confirm they compiled the same contract from
+//! the same arguments before funding it.
+use super::object::Object;
+use crate::contract::CompilationError;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use sapio_base::canonical::{to_canonical_bytes, CanonicalEncoding};
+use serde::Serialize;
+
+fn tagged_hash(tag: &[u8], parts: &[&[u8]]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for p in parts {
+        engine.input(&(p.len() as u64).to_le_bytes());
+        engine.input(p);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+impl CanonicalEncoding for Object {
+    const HASH_TAG: &'static [u8] = b"sapio/compiled";
+}
+
+impl Object {
+    /// A hash committing to everything compiled into this object: its
+    /// paths, address and descriptors, every template (recursively, with
+    /// the contracts they create) and its continuation points. Two
+    /// compilations have the same fingerprint if and only if they agree on
+    /// all of these, regardless of the order maps were filled in. It is
+    /// computed over the object's canonical encoding, see
+    /// [`sapio_base::canonical`].
+    pub fn fingerprint(&self) -> Result<sha256::Hash, CompilationError> {
+        Ok(tagged_hash(
+            b"sapio/fingerprint/object",
+            &[&self.to_canonical_bytes()?],
+        ))
+    }
+
+    /// Like [`Object::fingerprint`], but also committing to the `args` the
+    /// contract was compiled from
+    pub fn fingerprint_with_args<A: Serialize>(
+        &self,
+        args: &A,
+    ) -> Result<sha256::Hash, CompilationError> {
+        Ok(tagged_hash(
+            b"sapio/fingerprint/args",
+            &[&to_canonical_bytes(args)?, &self.fingerprint()?[..]],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use crate::template::{Template, TemplateMetadata};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{Transaction, XOnlyPublicKey};
+    use miniscript::Descriptor;
+    use sapio_base::CTVHash;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    const K: &str = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+
+    fn template(lock_time: u32) -> Template {
+        let tx = Transaction {
+            version: 2,
+            lock_time,
+            input: vec![Default::default()],
+            output: vec![],
+        };
+        Template {
+            guards: vec![],
+            ctv: tx.get_ctv_hash(0),
+            ctv_index: 0,
+            max: Amount::from_sat(0),
+            min_feerate_sats_vbyte: None,
+            metadata_map_s2s: TemplateMetadata::new(),
+            tx,
+            outputs: vec![],
+            sibling_inputs: vec![],
+            external_amount: Amount::from_sat(0),
+        }
+    }
+
+    fn object(lock_times: &[u32]) -> Object {
+        let d = Descriptor::<XOnlyPublicKey>::from_str(&format!("tr({})", K)).unwrap();
+        let mut o = Context::compiled_from_descriptor(d, None);
+        for t in lock_times.iter().map(|l| template(*l)) {
+            o.suggested_txs.insert(t.ctv, t);
+        }
+        o
+    }
+
+    #[test]
+    fn fingerprint_is_canonical() {
+        let a = object(&[1, 2, 3, 4, 5, 6]);
+        let b = object(&[6, 5, 4, 3, 2, 1]);
+        assert_eq!(a.fingerprint().unwrap(), b.fingerprint().unwrap());
+        let c = object(&[1, 2, 3, 4, 5, 7]);
+        assert_ne!(a.fingerprint().unwrap(), c.fingerprint().unwrap());
+
+        let args = |order: &[(&str, u64)]| order.iter().cloned().collect::<HashMap<_, _>>();
+        let x = a
+            .fingerprint_with_args(&args(&[("a", 1), ("b", 2), ("c", 3)]))
+            .unwrap();
+        let y = b
+            .fingerprint_with_args(&args(&[("c", 3), ("b", 2), ("a", 1)]))
+            .unwrap();
+        assert_eq!(x, y);
+        assert_ne!(x, a.fingerprint_with_args(&args(&[("a", 2)])).unwrap());
+    }
+
+    #[test]
+    fn canonical_encoding_round_trips() {
+        let a = object(&[1, 2, 3]);
+        let bytes = a.to_canonical_bytes().unwrap();
+        let b = Object::from_canonical_bytes(&bytes).unwrap();
+        assert_eq!(b.to_canonical_bytes().unwrap(), bytes);
+        assert_eq!(a.fingerprint().unwrap(), b.fingerprint().unwrap());
+        assert_eq!(a.suggested_txs.len(), b.suggested_txs.len());
+    }
+}
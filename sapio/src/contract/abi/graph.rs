@@ -0,0 +1,227 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Renders the tree of a compiled contract as a graph, in Graphviz DOT or
+//! Mermaid syntax, for reviewing its structure without Sapio Studio.
+//!
+//! Contracts are boxes, templates are ellipses (solid edges for CTV
+//! templates, dashed for suggested ones) and continuation points are
+//! hexagons.
+use super::object::Object;
+use crate::template::Template;
+use crate::util::extended_address::ExtendedAddress;
+use std::fmt::Write;
+
+/// the kind of a node, which decides its shape
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    Contract,
+    Template,
+    Continuation,
+}
+
+struct Node {
+    kind: NodeKind,
+    label: String,
+}
+
+struct Edge {
+    from: usize,
+    to: usize,
+    label: String,
+    dashed: bool,
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+/// sequences at or above this have no relative timelock
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+fn template_label(t: &Template, ctv: bool) -> String {
+    let mut label = match &t.metadata_map_s2s.label {
+        Some(l) => l.clone(),
+        None if ctv => "ctv".into(),
+        None => "suggested".into(),
+    };
+    let _ = write!(label, "\n{}", &t.hash().to_string()[..16]);
+    let _ = write!(label, "\n{}", t.total_amount());
+    if t.tx.lock_time != 0 {
+        let _ = match t.tx.lock_time < LOCKTIME_THRESHOLD {
+            true => write!(label, "\nafter height {}", t.tx.lock_time),
+            false => write!(label, "\nafter time {}", t.tx.lock_time),
+        };
+    }
+    for (i, inp) in t.tx.input.iter().enumerate() {
+        if inp.sequence & SEQUENCE_DISABLE_FLAG == 0 && inp.sequence != 0 && t.tx.version >= 2 {
+            let _ = write!(label, "\ninput {} older {:#x}", i, inp.sequence);
+        }
+    }
+    label
+}
+
+fn address_label(a: &ExtendedAddress) -> String {
+    match a {
+        ExtendedAddress::Address(a) => a.to_string(),
+        ExtendedAddress::OpReturn(_) => "OP_RETURN".into(),
+        ExtendedAddress::Unknown(s) => format!("{:x}", s),
+    }
+}
+
+impl Graph {
+    fn add(&mut self, kind: NodeKind, label: String) -> usize {
+        self.nodes.push(Node { kind, label });
+        self.nodes.len() - 1
+    }
+    fn visit(&mut self, obj: &Object) -> usize {
+        let label = format!(
+            "{}\n{}\n{} - {}",
+            obj.root_path.0,
+            address_label(&obj.address),
+            obj.amount_range.min(),
+            obj.amount_range.max()
+        );
+        let id = self.add(NodeKind::Contract, label);
+        let mut continuations: Vec<_> = obj.continue_apis.keys().collect();
+        continuations.sort_by_key(|p| p.0.to_string());
+        for p in continuations {
+            let c = self.add(NodeKind::Continuation, p.0.to_string());
+            self.edges.push(Edge {
+                from: id,
+                to: c,
+                label: "update".into(),
+                dashed: true,
+            });
+        }
+        let mut templates: Vec<(bool, &Template)> = obj
+            .ctv_to_tx
+            .values()
+            .map(|t| (true, t))
+            .chain(obj.suggested_txs.values().map(|t| (false, t)))
+            .collect();
+        templates.sort_by_key(|(ctv, t)| (!*ctv, t.hash()));
+        for (ctv, t) in templates {
+            let tid = self.add(NodeKind::Template, template_label(t, ctv));
+            self.edges.push(Edge {
+                from: id,
+                to: tid,
+                label: String::new(),
+                dashed: !ctv,
+            });
+            for out in t.outputs.iter() {
+                let child = self.visit(&out.contract);
+                self.edges.push(Edge {
+                    from: tid,
+                    to: child,
+                    label: out.amount.to_string(),
+                    dashed: false,
+                });
+            }
+        }
+        id
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;").replace('\n', "<br/>")
+}
+
+impl Object {
+    fn graph(&self) -> Graph {
+        let mut g = Graph::default();
+        g.visit(self);
+        g
+    }
+
+    /// Render the tree of contracts, templates and continuation points
+    /// reachable from this object as a Graphviz DOT digraph
+    pub fn to_dot(&self) -> String {
+        let g = self.graph();
+        let mut s = String::from("digraph sapio {\n");
+        for (i, n) in g.nodes.iter().enumerate() {
+            let shape = match n.kind {
+                NodeKind::Contract => "box",
+                NodeKind::Template => "ellipse",
+                NodeKind::Continuation => "hexagon",
+            };
+            let _ = writeln!(
+                s,
+                "  n{} [shape={}, label=\"{}\"];",
+                i,
+                shape,
+                dot_escape(&n.label)
+            );
+        }
+        for e in g.edges.iter() {
+            let _ = writeln!(
+                s,
+                "  n{} -> n{} [label=\"{}\"{}];",
+                e.from,
+                e.to,
+                dot_escape(&e.label),
+                if e.dashed { ", style=dashed" } else { "" }
+            );
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// Render the tree of contracts, templates and continuation points
+    /// reachable from this object as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        let g = self.graph();
+        let mut s = String::from("flowchart TD\n");
+        for (i, n) in g.nodes.iter().enumerate() {
+            let label = mermaid_escape(&n.label);
+            let _ = match n.kind {
+                NodeKind::Contract => writeln!(s, "  n{}[\"{}\"]", i, label),
+                NodeKind::Template => writeln!(s, "  n{}([\"{}\"])", i, label),
+                NodeKind::Continuation => writeln!(s, "  n{}{{{{\"{}\"}}}}", i, label),
+            };
+        }
+        for e in g.edges.iter() {
+            let arrow = if e.dashed { "-.->" } else { "-->" };
+            let _ = if e.label.is_empty() {
+                writeln!(s, "  n{} {} n{}", e.from, arrow, e.to)
+            } else {
+                writeln!(
+                    s,
+                    "  n{} {}|\"{}\"| n{}",
+                    e.from,
+                    arrow,
+                    mermaid_escape(&e.label),
+                    e.to
+                )
+            };
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn renders_leaf_object() {
+        let o = Object::from_op_return(&b"hello"[..]).unwrap();
+        let dot = o.to_dot();
+        assert!(dot.starts_with("digraph sapio {\n"));
+        assert!(dot.contains("n0 [shape=box"));
+        assert!(dot.ends_with("}\n"));
+        let mermaid = o.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n  n0[\""));
+    }
+}
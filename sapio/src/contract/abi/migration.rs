@@ -0,0 +1,188 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Detecting changes to a contract's ABI between two compilations, so that
+//! effects saved against the old one are not silently mis-applied.
+//!
+//! The ABI of a compiled contract is the set of its continuation points,
+//! each identified by [`ContinuationPoint::stable_hash`].
+use super::continuation::ContinuationPoint;
+use super::object::Object;
+use sapio_base::effects::{EffectPath, MapEffectDB, ValidFragmentError};
+use sapio_base::serialization_helpers::SArc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// # ABI Migration
+/// How the continuation points of a contract changed between two
+/// compilations. Paths are in their canonical string form.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AbiMigration {
+    /// the ABI version of the old compilation
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub from_version: Option<u64>,
+    /// the ABI version of the new compilation
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub to_version: Option<u64>,
+    /// continuation points which moved to a new path with the same schema,
+    /// old path to new
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub moved: BTreeMap<String, String>,
+    /// continuation points at the same path whose schema changed
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub changed: BTreeSet<String>,
+    /// continuation points which are gone
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub removed: BTreeSet<String>,
+    /// continuation points which are new
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    pub added: BTreeSet<String>,
+}
+
+impl AbiMigration {
+    /// true if every effect saved against the old compilation applies to
+    /// the new one unchanged
+    pub fn is_compatible(&self) -> bool {
+        self.moved.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Move effects saved against the old compilation to the new paths of
+    /// their continuation points. Effects at changed or removed points are
+    /// dropped, as they can not be applied safely; they remain in
+    /// `effects` for the caller to review.
+    pub fn migrate_effects(
+        &self,
+        effects: &MapEffectDB,
+    ) -> Result<MapEffectDB, ValidFragmentError> {
+        let mut migrated = effects.clone();
+        for p in self.changed.iter().chain(self.removed.iter()) {
+            migrated.remove_path(&path(p)?);
+        }
+        for (from, to) in self.moved.iter() {
+            migrated.move_path(&path(from)?, path(to)?);
+        }
+        Ok(migrated)
+    }
+}
+
+fn path(s: &str) -> Result<SArc<EffectPath>, ValidFragmentError> {
+    Ok(SArc(std::sync::Arc::new(s.parse()?)))
+}
+
+/// every continuation point reachable from `o`, by path
+fn continuation_points(o: &Object) -> BTreeMap<String, &ContinuationPoint> {
+    o.reachable_objects()
+        .into_iter()
+        .flat_map(|(_, o)| o.continue_apis.iter())
+        .map(|(p, c)| (p.0.to_string(), c))
+        .collect()
+}
+
+impl Object {
+    /// Compare the ABI of this (old) compilation with `new`.
+    ///
+    /// A removed point and an added point with the same schema are taken to
+    /// be a move if neither schema matches any other removed or added point.
+    pub fn abi_migration(&self, new: &Object) -> AbiMigration {
+        let old_points = continuation_points(self);
+        let new_points = continuation_points(new);
+        let mut m = AbiMigration {
+            from_version: self.abi_version,
+            to_version: new.abi_version,
+            ..Default::default()
+        };
+        for (p, c) in old_points.iter() {
+            match new_points.get(p) {
+                Some(n) if n.stable_hash() == c.stable_hash() => {}
+                Some(_) => {
+                    m.changed.insert(p.clone());
+                }
+                None => {
+                    m.removed.insert(p.clone());
+                }
+            }
+        }
+        for p in new_points.keys() {
+            if !old_points.contains_key(p) {
+                m.added.insert(p.clone());
+            }
+        }
+        let by_schema = |paths: &BTreeSet<String>,
+                         points: &BTreeMap<String, &ContinuationPoint>| {
+            let mut r: BTreeMap<_, Vec<String>> = BTreeMap::new();
+            for p in paths {
+                if let Some(h) = points[p].schema_hash() {
+                    r.entry(h).or_default().push(p.clone());
+                }
+            }
+            r
+        };
+        let removed = by_schema(&m.removed, &old_points);
+        let added = by_schema(&m.added, &new_points);
+        for (h, from) in removed {
+            if let ([from], Some([to])) = (&from[..], added.get(&h).map(|v| &v[..])) {
+                m.removed.remove(from);
+                m.added.remove(to);
+                m.moved.insert(from.clone(), to.clone());
+            }
+        }
+        m
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    #[derive(schemars::JsonSchema)]
+    #[allow(dead_code)]
+    struct A {
+        a: u64,
+    }
+    #[derive(schemars::JsonSchema)]
+    #[allow(dead_code)]
+    struct B {
+        b: String,
+    }
+    fn object(points: &[(&str, Option<schemars::schema::RootSchema>)]) -> Object {
+        let mut o = Object::from_op_return(&b""[..]).unwrap();
+        for (p, schema) in points {
+            let path = Arc::new(p.parse::<EffectPath>().unwrap());
+            o.continue_apis.insert(
+                SArc(path.clone()),
+                ContinuationPoint::at(schema.clone().map(Arc::new), path),
+            );
+        }
+        o
+    }
+    #[test]
+    fn detects_moves_and_changes() {
+        let a = || Some(schemars::schema_for!(A));
+        let b = || Some(schemars::schema_for!(B));
+        let old = object(&[("x/same", a()), ("x/old", b()), ("x/changes", a())]);
+        let new = object(&[("x/same", a()), ("x/new", b()), ("x/changes", b())]);
+        assert!(old.abi_migration(&old).is_compatible());
+        let m = old.abi_migration(&new);
+        assert!(!m.is_compatible());
+        assert_eq!(m.moved.get("x/old").map(String::as_str), Some("x/new"));
+        assert!(m.changed.contains("x/changes"));
+        assert!(m.removed.is_empty() && m.added.is_empty());
+
+        let mut effects = MapEffectDB::default();
+        for p in ["x/same", "x/old", "x/changes"] {
+            effects.insert(path(p).unwrap(), SArc(Arc::new("e".into())), 1.into());
+        }
+        let migrated = m.migrate_effects(&effects).unwrap();
+        let names = |p: &str| {
+            use sapio_base::effects::EffectDB;
+            migrated.get_value(&path(p).unwrap().0).count()
+        };
+        assert_eq!(names("x/same"), 1);
+        assert_eq!(names("x/old"), 0);
+        assert_eq!(names("x/new"), 1);
+        assert_eq!(names("x/changes"), 0);
+    }
+}
@@ -6,6 +6,17 @@
 
 //! ABI contains the output formats of Sapio Compilatios
 
+pub mod analysis;
+pub mod broadcast;
+pub mod collisions;
 pub mod continuation;
+pub mod fee_report;
+pub mod fingerprint;
+pub mod graph;
+pub mod migration;
 pub mod object;
+pub mod satisfaction;
+pub mod standardness;
 pub mod studio;
+pub mod watch_only;
+pub mod witness_template;
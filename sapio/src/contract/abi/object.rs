@@ -6,20 +6,30 @@
 
 //! Object is the output of Sapio Compilation & can be linked to a specific coin
 pub use super::studio::*;
+use crate::contract::abi::broadcast::BroadcastAfter;
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::witness_template::taproot_spend_info;
+use crate::contract::covenant::apo;
+use crate::template::preimage::add_preimages;
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
 use crate::util::extended_address::ExtendedAddress;
+use crate::util::internal_key::InternalKeySource;
+use crate::util::musig::KeyAggregation;
+use ::miniscript::descriptor::Tr;
 use ::miniscript::{self, *};
-use bitcoin::hashes::sha256;
+use ::miniscript::{ForEach, ForEachKey};
 use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::{hash160, sha256, Hash};
 use bitcoin::util::amount::Amount;
+use bitcoin::util::bip32::{DerivationPath, Fingerprint, KeySource};
 use bitcoin::util::psbt::PartiallySignedTransaction;
-use bitcoin::util::taproot::TaprootBuilder;
-use bitcoin::util::taproot::TaprootBuilderError;
-use bitcoin::util::taproot::TaprootSpendInfo;
+use bitcoin::util::taproot::{
+    LeafVersion, TapLeafHash, TaprootBuilder, TaprootBuilderError, TaprootSpendInfo,
+};
 use bitcoin::OutPoint;
 use bitcoin::PublicKey;
+use bitcoin::SchnorrSighashType;
 use bitcoin::Script;
 use bitcoin::XOnlyPublicKey;
 use sapio_base::effects::EffectPath;
@@ -31,7 +41,7 @@ use sapio_base::txindex::TxIndexError;
 use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -64,6 +74,40 @@ impl SupportedDescriptors {
     }
 }
 
+/// A taproot output whose leaves are not all miniscript, so which has no
+/// descriptor (see
+/// [`CovenantBackend::AnyPrevout`](crate::contract::covenant::CovenantBackend::AnyPrevout)).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct RawTaproot {
+    /// The internal key
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub internal_key: XOnlyPublicKey,
+    /// Every leaf script, with its weight
+    #[schemars(with = "Vec<(u32, String)>")]
+    pub leaves: Vec<(u32, Script)>,
+    /// True if the leaves are Elements tapscript, in an Elements tree (see
+    /// [`ElementsTaproot`](crate::contract::covenant::elements::ElementsTaproot))
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub elements: bool,
+}
+
+impl RawTaproot {
+    /// The tree of the leaves, Huffman encoded by weight
+    pub fn spend_info<C: bitcoin::secp256k1::Verification>(
+        &self,
+        secp: &bitcoin::secp256k1::Secp256k1<C>,
+    ) -> Result<TaprootSpendInfo, ObjectError> {
+        if self.elements {
+            return Err(ObjectError::Custom(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Elements outputs have no Bitcoin spend info",
+            ))));
+        }
+        Ok(TaprootBuilder::with_huffman_tree(self.leaves.clone())?
+            .finalize(secp, self.internal_key)?)
+    }
+}
+
 /// Error types that can arise when constructing an Object
 #[derive(Debug)]
 pub enum ObjectError {
@@ -157,6 +201,166 @@ pub struct Object {
     pub descriptor: Option<SupportedDescriptors>,
     /// The amount_range safe to send this object
     pub amount_range: AmountRange,
+    /// If the internal key is a MuSig2 aggregate, how it was made
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key_aggregation: Option<KeyAggregation>,
+    /// How the internal key was chosen, if this object was compiled
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub internal_key: Option<InternalKeySource>,
+    /// The BIP-32 origins of the keys in the descriptor, where known
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    #[schemars(with = "BTreeMap<String, (String, String)>")]
+    pub key_origins: BTreeMap<XOnlyPublicKey, KeySource>,
+    /// The Object's taproot tree, if it has leaves which are not miniscript
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub raw_taproot: Option<RawTaproot>,
+    /// The version of the contract's ABI (its continuation points) declared
+    /// by the contract, if any. See [`crate::contract::abi::migration`].
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub abi_version: Option<u64>,
+}
+
+/// What a PSBTv2 constructor may change in a bound transaction. A CTV hash
+/// commits to the input count and outputs, so those are fixed; otherwise
+/// anything not yet covered by a signature may change.
+fn tx_modifiable(
+    psbt: &PartiallySignedTransaction,
+    uses_ctv: bool,
+    sighash: Option<SchnorrSighashType>,
+) -> TxModifiable {
+    if uses_ctv {
+        return TxModifiable::default();
+    }
+    let signed = psbt.inputs.iter().any(|i| {
+        i.tap_key_sig.is_some()
+            || !i.tap_script_sigs.is_empty()
+            || !i.partial_sigs.is_empty()
+            || i.final_script_witness.is_some()
+    });
+    let (anyone_can_pay, sighash_none, sighash_single) = match sighash {
+        Some(SchnorrSighashType::AllPlusAnyoneCanPay) => (true, false, false),
+        Some(SchnorrSighashType::None) => (false, true, false),
+        Some(SchnorrSighashType::NonePlusAnyoneCanPay) => (true, true, false),
+        Some(SchnorrSighashType::Single) => (false, false, true),
+        Some(SchnorrSighashType::SinglePlusAnyoneCanPay) => (true, false, true),
+        _ => (false, false, false),
+    };
+    TxModifiable {
+        inputs: !signed || anyone_can_pay,
+        outputs: !signed || sighash_none,
+        sighash_single,
+    }
+}
+
+/// Add what a signer needs to spend `obj` to a PSBT input
+fn add_spend_info<C: bitcoin::secp256k1::Verification>(
+    inp: &mut bitcoin::util::psbt::Input,
+    obj: &Object,
+    secp: &bitcoin::secp256k1::Secp256k1<C>,
+) -> Result<(), ObjectError> {
+    match &obj.descriptor {
+        Some(SupportedDescriptors::Pk(d)) => {
+            inp.witness_script = Some(d.explicit_script()?);
+            inp.bip32_derivation = bip32_derivation(d, &obj.key_origins);
+        }
+        Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+            let info = taproot_spend_info(t, secp)?;
+            for item in info.as_script_map().keys() {
+                let cb = info.control_block(item).expect("Must be present");
+                inp.tap_scripts.insert(cb.clone(), item.clone());
+            }
+            inp.tap_merkle_root = info.merkle_root();
+            inp.tap_internal_key = Some(info.internal_key());
+            inp.tap_key_origins = tap_key_origins(t, &obj.key_origins);
+        }
+        None => {
+            if let Some(raw) = &obj.raw_taproot {
+                let info = raw.spend_info(secp)?;
+                for item in info.as_script_map().keys() {
+                    let cb = info.control_block(item).expect("Must be present");
+                    inp.tap_scripts.insert(cb.clone(), item.clone());
+                }
+                inp.tap_merkle_root = info.merkle_root();
+                inp.tap_internal_key = Some(info.internal_key());
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Add what a signer needs to recognize `obj` as a PSBT output
+fn add_output_info(out: &mut bitcoin::util::psbt::Output, obj: &Object) {
+    match &obj.descriptor {
+        Some(SupportedDescriptors::Pk(d)) => {
+            out.witness_script = d.explicit_script().ok();
+            out.bip32_derivation = bip32_derivation(d, &obj.key_origins);
+        }
+        Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+            out.tap_internal_key = Some(t.internal_key().clone());
+            out.tap_key_origins = tap_key_origins(t, &obj.key_origins);
+        }
+        _ => (),
+    }
+}
+
+/// The origin of a key without a known origin, which (as Bitcoin Core does)
+/// is the key's own fingerprint with an empty derivation path.
+fn bare_key_source(key: &bitcoin::secp256k1::PublicKey) -> KeySource {
+    let h = hash160::Hash::hash(&key.serialize());
+    (Fingerprint::from(&h[0..4]), DerivationPath::from(vec![]))
+}
+
+/// The recorded origin of `key`, or else its bare origin
+fn key_source(key: &XOnlyPublicKey, known: &BTreeMap<XOnlyPublicKey, KeySource>) -> KeySource {
+    known.get(key).cloned().unwrap_or_else(|| {
+        let mut compressed = [0x02; 33];
+        compressed[1..].copy_from_slice(&key.serialize());
+        let pk = bitcoin::secp256k1::PublicKey::from_slice(&compressed)
+            .expect("an x-only key with even y is a valid key");
+        bare_key_source(&pk)
+    })
+}
+
+/// Every key in an ECDSA descriptor, with its origin
+fn bip32_derivation(
+    d: &Descriptor<PublicKey>,
+    known: &BTreeMap<XOnlyPublicKey, KeySource>,
+) -> BTreeMap<bitcoin::secp256k1::PublicKey, KeySource> {
+    let mut res = BTreeMap::new();
+    d.for_each_key(|k: ForEach<PublicKey>| {
+        let pk = k.as_key().inner;
+        let origin = known
+            .get(&XOnlyPublicKey::from(pk))
+            .cloned()
+            .unwrap_or_else(|| bare_key_source(&pk));
+        res.insert(pk, origin);
+        true
+    });
+    res
+}
+
+/// Every key in a taproot descriptor, with the leaves it appears in and its
+/// origin. The internal key is listed with no leaves, for the key path.
+fn tap_key_origins(
+    t: &Tr<XOnlyPublicKey>,
+    known: &BTreeMap<XOnlyPublicKey, KeySource>,
+) -> BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)> {
+    let mut origins: BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)> = BTreeMap::new();
+    let internal = t.internal_key().clone();
+    origins.insert(internal, (vec![], key_source(&internal, known)));
+    for (_, ms) in t.iter_scripts() {
+        let leaf = TapLeafHash::from_script(&ms.encode(), LeafVersion::TapScript);
+        for pk in ms.iter_pk() {
+            let entry = origins
+                .entry(pk)
+                .or_insert_with(|| (vec![], key_source(&pk, known)));
+            if !entry.0.contains(&leaf) {
+                entry.0.push(leaf);
+            }
+        }
+    }
+    origins
 }
 
 impl Object {
@@ -179,6 +383,11 @@ impl Object {
                 a.update_range(Amount::from_sat(21_000_000 * 100_000_000));
                 a
             }),
+            key_aggregation: None,
+            internal_key: None,
+            key_origins: Default::default(),
+            raw_taproot: None,
+            abi_version: None,
         }
     }
 
@@ -209,6 +418,11 @@ impl Object {
             address: ExtendedAddress::make_op_return(data)?,
             descriptor: None,
             amount_range: AmountRange::new(),
+            key_aggregation: None,
+            internal_key: None,
+            key_origins: Default::default(),
+            raw_taproot: None,
+            abi_version: None,
         })
     }
 
@@ -216,37 +430,63 @@ impl Object {
     /// Vector of PSBTs and transaction metadata.
     ///
     /// `bind_psbt` accepts a CTVEmulator, a txindex, and a map of outputs to be
-    /// bound to specific template hashes.
+    /// bound to specific template hashes. The map supplies the outpoints of
+    /// any sibling inputs (see [`Builder::add_sibling_input`](crate::template::Builder::add_sibling_input)),
+    /// whose PSBT inputs are filled in from their expected contracts.
+    ///
+    /// Taproot inputs carry their internal key, merkle root, leaf scripts with
+    /// control blocks, and key origins, and outputs to contracts their internal
+    /// key and key origins, so that signers can sign and verify them directly.
+    /// Keys given through [`Context::key`](crate::Context::key) carry their
+    /// BIP-32 origin; any other key is listed under its own fingerprint.
+    /// Preimages recorded with
+    /// [`Builder::add_preimage`](crate::template::Builder::add_preimage) fill
+    /// the PSBT preimage fields of the hash locks they open.
+    ///
+    /// PSBTs are emitted as `psbt_version`. As version 2 PSBTs, templates
+    /// enforced by CTV are marked unmodifiable, while suggested transactions
+    /// stay open to added inputs and outputs as far as their signatures allow.
+    ///
+    /// Each PSBT is annotated with when its timelocks permit it to be mined,
+    /// using the funding transaction's confirmation height and the chain tip
+    /// if the txindex knows them.
     pub fn bind_psbt(
         &self,
         out_in: bitcoin::OutPoint,
         output_map: HashMap<Sha256, Vec<Option<bitcoin::OutPoint>>>,
         blockdata: Rc<dyn TxIndex>,
         emulator: &dyn CTVEmulator,
+        psbt_version: PsbtVersion,
     ) -> Result<Program, ObjectError> {
         let mut result = HashMap::<SArc<EffectPath>, SapioStudioObject>::new();
         // Could use a queue instead to do BFS linking, but order doesn't matter and stack is
         // faster.
-        let mut stack = vec![(out_in, self)];
+        let tip = blockdata.chain_tip()?;
+        let funding_height = match blockdata.lookup_confirmation_height(&out_in.txid)? {
+            Some(h) => Some(h),
+            // if unconfirmed, the earliest it can confirm is the next block
+            None => tip.map(|t| t.height + 1),
+        };
+        let mut stack = vec![(out_in, self, funding_height)];
         let mut mock_out = OutPoint::default();
         mock_out.vout = 0;
         let secp = bitcoin::secp256k1::Secp256k1::new();
-        while let Some((
-            out,
-            Object {
+        while let Some((out, obj, funding_height)) = stack.pop() {
+            let Object {
                 root_path,
                 continue_apis,
                 descriptor,
                 ctv_to_tx,
                 suggested_txs,
                 ..
-            },
-        )) = stack.pop()
-        {
+            } = obj;
             result.insert(
                 root_path.clone(),
                 SapioStudioObject {
                     continue_apis: continue_apis.clone(),
+                    witness_templates: descriptor
+                        .as_ref()
+                        .map_or(Ok(vec![]), |d| d.witness_templates())?,
                     txs: ctv_to_tx
                         .iter()
                         .chain(suggested_txs.iter())
@@ -256,6 +496,7 @@ impl Object {
                                 Template {
                                     metadata_map_s2s,
                                     outputs,
+                                    sibling_inputs,
                                     tx,
                                     ..
                                 },
@@ -282,46 +523,56 @@ impl Object {
                                         blockdata.lookup_output(&tx_in.previous_output).ok();
                                 }
                                 // Missing other Witness Info.
-                                match descriptor {
-                                    Some(SupportedDescriptors::Pk(d)) => {
-                                        psbtx.inputs[0].witness_script = Some(d.explicit_script()?);
-                                    }
-                                    Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
-                                        let mut builder = TaprootBuilder::new();
-                                        let mut added = false;
-                                        for (depth, ms) in t.iter_scripts() {
-                                            added = true;
-                                            let script = ms.encode();
-                                            builder = builder.add_leaf(depth, script)?;
-                                        }
-                                        let info = if added {
-                                            builder.finalize(&secp, t.internal_key().clone())?
-                                        } else {
-                                            TaprootSpendInfo::new_key_spend(
-                                                &secp,
-                                                t.internal_key().clone(),
-                                                None,
-                                            )
-                                        };
-                                        let inp = &mut psbtx.inputs[0];
-                                        for item in info.as_script_map().keys() {
-                                            let cb =
-                                                info.control_block(item).expect("Must be present");
-                                            inp.tap_scripts.insert(cb.clone(), item.clone());
-                                        }
-                                        inp.tap_merkle_root = info.merkle_root();
-                                        inp.tap_internal_key = Some(info.internal_key());
+                                add_spend_info(&mut psbtx.inputs[0], obj, &secp)?;
+                                let preimages = metadata_map_s2s.preimages();
+                                add_preimages(&mut psbtx.inputs[0], &preimages);
+                                if let Some(ty) = metadata_map_s2s.sighash_type() {
+                                    psbtx.inputs[0].sighash_type = Some(ty.into());
+                                }
+                                for s in sibling_inputs {
+                                    let inp = &mut psbtx.inputs[s.input as usize];
+                                    if inp.witness_utxo.is_none() {
+                                        inp.witness_utxo = Some(s.expected_txout());
                                     }
-                                    _ => (),
+                                    add_spend_info(inp, &s.contract, &secp)?;
+                                    add_preimages(inp, &preimages);
+                                }
+                                for (psbt_out, o) in psbtx.outputs.iter_mut().zip(outputs.iter()) {
+                                    add_output_info(psbt_out, &o.contract);
                                 }
                                 psbtx = emulator.sign(psbtx)?;
+                                if obj.raw_taproot.is_some() {
+                                    apo::finalize_covenant_input(&mut psbtx, &secp)
+                                        .map_err(|e| ObjectError::Custom(Box::new(e)))?;
+                                }
                                 let final_tx = psbtx.clone().extract_tx();
+                                let broadcast_after =
+                                    BroadcastAfter::compute(&final_tx, funding_height, tip);
+                                let confirms_at =
+                                    broadcast_after.earliest_confirmation(funding_height);
+                                let cpfp_children = metadata_map_s2s
+                                    .anchor_outputs()
+                                    .iter()
+                                    .filter_map(|a| {
+                                        let out = final_tx.output.get(a.vout as usize)?.clone();
+                                        Some(a.child_psbt(final_tx.txid(), out))
+                                    })
+                                    .collect();
                                 let txid = blockdata.add_tx(Arc::new(final_tx))?;
                                 stack.reserve(outputs.len());
                                 for (vout, v) in outputs.iter().enumerate() {
                                     let vout = vout as u32;
-                                    stack.push((bitcoin::OutPoint { txid, vout }, &v.contract));
+                                    stack.push((
+                                        bitcoin::OutPoint { txid, vout },
+                                        &v.contract,
+                                        confirms_at,
+                                    ));
                                 }
+                                let modifiable = tx_modifiable(
+                                    &psbtx,
+                                    ctv_to_tx.contains_key(ctv_hash),
+                                    metadata_map_s2s.sighash_type(),
+                                );
                                 Ok(LinkedPSBT {
                                     psbt: psbtx,
                                     metadata: metadata_map_s2s.clone(),
@@ -330,8 +581,10 @@ impl Object {
                                         .cloned()
                                         .map(|x| x.metadata)
                                         .collect::<Vec<_>>(),
+                                    broadcast_after: Some(broadcast_after),
+                                    cpfp_children,
                                 }
-                                .into())
+                                .into_studio_format(psbt_version, modifiable))
                             },
                         )
                         .collect::<Result<Vec<SapioStudioFormat>, ObjectError>>()?,
@@ -0,0 +1,188 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Which spend paths of a compiled contract a party can use now, what they
+//! are missing for the others, and when timelocked paths open up.
+use super::object::{Object, SupportedDescriptors};
+use super::witness_template::SpendPath;
+use ::miniscript::miniscript::decode::Terminal;
+use ::miniscript::{Descriptor, Miniscript, Tap};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::XOnlyPublicKey;
+use sapio_base::satisfaction::{Satisfiability, SpendingState};
+use sapio_base::Clause;
+use serde::{Deserialize, Serialize};
+
+/// # Spend Path Status
+/// Whether a party can use one spend path of a contract.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SpendPathStatus {
+    /// the path of the contract whose output is spent
+    pub path: String,
+    /// how the output is spent
+    pub spend_path: SpendPath,
+    /// whether it can be used, or None if the requirements could not be
+    /// read from the script
+    pub status: Option<Satisfiability>,
+}
+
+/// The conditions of a tapscript leaf as a [`Clause`], or None if it uses
+/// fragments with no equivalent (e.g. a key hash).
+pub fn leaf_clause(ms: &Miniscript<XOnlyPublicKey, Tap>) -> Option<Clause> {
+    let two = |a: &Miniscript<_, _>, b: &Miniscript<_, _>| -> Option<Vec<Clause>> {
+        Some(vec![leaf_clause(a)?, leaf_clause(b)?])
+    };
+    Some(match &ms.node {
+        Terminal::True => Clause::Trivial,
+        Terminal::False => Clause::Unsatisfiable,
+        Terminal::PkK(k) => Clause::Key(*k),
+        Terminal::After(n) => Clause::After(*n),
+        Terminal::Older(n) => Clause::Older(*n),
+        Terminal::Sha256(h) => Clause::Sha256(*h),
+        Terminal::Hash256(h) => Clause::Hash256(*h),
+        Terminal::Ripemd160(h) => Clause::Ripemd160(*h),
+        Terminal::Hash160(h) => Clause::Hash160(*h),
+        Terminal::TxTemplate(h) => Clause::TxTemplate(*h),
+        Terminal::Alt(s)
+        | Terminal::Swap(s)
+        | Terminal::Check(s)
+        | Terminal::DupIf(s)
+        | Terminal::Verify(s)
+        | Terminal::NonZero(s)
+        | Terminal::ZeroNotEqual(s) => leaf_clause(s)?,
+        Terminal::AndV(a, b) | Terminal::AndB(a, b) => Clause::And(two(a, b)?),
+        Terminal::AndOr(a, b, c) => {
+            Clause::Or(vec![(1, Clause::And(two(a, b)?)), (1, leaf_clause(c)?)])
+        }
+        Terminal::OrB(a, b) | Terminal::OrD(a, b) | Terminal::OrC(a, b) | Terminal::OrI(a, b) => {
+            Clause::Or(two(a, b)?.into_iter().map(|c| (1, c)).collect())
+        }
+        Terminal::Thresh(k, subs) => Clause::Threshold(
+            *k,
+            subs.iter()
+                .map(|s| leaf_clause(s))
+                .collect::<Option<Vec<_>>>()?,
+        ),
+        Terminal::MultiA(k, ks) => {
+            Clause::Threshold(*k, ks.iter().map(|k| Clause::Key(*k)).collect())
+        }
+        _ => return None,
+    })
+}
+
+impl Object {
+    /// Every way to spend this object's output, with its conditions if they
+    /// can be read from the script
+    pub(crate) fn spend_conditions(&self) -> Vec<(SpendPath, Option<Clause>)> {
+        let leaf_hash = |script: &bitcoin::Script| {
+            TapLeafHash::from_script(script, LeafVersion::TapScript).to_hex()
+        };
+        let mut res = vec![];
+        match (&self.descriptor, &self.raw_taproot) {
+            (Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))), _) => {
+                res.push((SpendPath::KeyPath, Some(Clause::Key(*t.internal_key()))));
+                for (depth, ms) in t.iter_scripts() {
+                    let spend_path = SpendPath::ScriptPath {
+                        leaf_hash: leaf_hash(&ms.encode()),
+                        depth,
+                    };
+                    res.push((spend_path, leaf_clause(ms)));
+                }
+            }
+            (Some(SupportedDescriptors::Pk(_)), _) => {
+                res.push((SpendPath::WitnessScript, None));
+            }
+            (_, Some(raw)) if !raw.elements => {
+                res.push((SpendPath::KeyPath, Some(Clause::Key(raw.internal_key))));
+                let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+                let info = match raw.spend_info(&secp) {
+                    Ok(info) => info,
+                    Err(_) => return res,
+                };
+                for (_, script) in raw.leaves.iter() {
+                    let cb = info
+                        .control_block(&(script.clone(), LeafVersion::TapScript))
+                        .expect("Must be present")
+                        .size();
+                    let spend_path = SpendPath::ScriptPath {
+                        leaf_hash: leaf_hash(script),
+                        depth: ((cb - 33) / 32) as u8,
+                    };
+                    let clause = Miniscript::<XOnlyPublicKey, Tap>::parse(script)
+                        .ok()
+                        .and_then(|ms| leaf_clause(&ms));
+                    res.push((spend_path, clause));
+                }
+            }
+            _ => {}
+        }
+        res
+    }
+
+    fn spend_path_statuses(&self, path: &str, state: &SpendingState) -> Vec<SpendPathStatus> {
+        self.spend_conditions()
+            .into_iter()
+            .map(|(spend_path, clause)| SpendPathStatus {
+                path: path.into(),
+                spend_path,
+                status: clause.map(|c| state.evaluate(&c)),
+            })
+            .collect()
+    }
+
+    /// For every spend path of every contract reachable from this object,
+    /// whether it can be used in `state`, what is missing if not, and when
+    /// its timelocks expire.
+    ///
+    /// Relative timelocks are measured from `state`'s confirmation, which is
+    /// only meaningful for the outputs of this object itself; paths of
+    /// contracts further down are reported as waiting for confirmation unless
+    /// one is given.
+    pub fn spend_path_statuses_at(&self, state: &SpendingState) -> Vec<SpendPathStatus> {
+        self.reachable_objects()
+            .into_iter()
+            .flat_map(|(path, obj)| obj.spend_path_statuses(&path, state))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use sapio_base::satisfaction::Missing;
+    use std::str::FromStr;
+    const K: &str = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+    #[test]
+    fn reports_who_can_spend_when() {
+        let k = XOnlyPublicKey::from_str(K).unwrap();
+        let d = Descriptor::<XOnlyPublicKey>::from_str(&format!(
+            "tr({k},{{pk({k}),and_v(v:pk({k}),older(144))}})",
+            k = K
+        ))
+        .unwrap();
+        let o = Context::compiled_from_descriptor(d, None);
+        let state = SpendingState::default().with_confirmation(100, 0);
+        let statuses = o.spend_path_statuses_at(&state);
+        assert_eq!(statuses.len(), 3);
+        for s in statuses.iter() {
+            let status = s.status.as_ref().unwrap();
+            assert!(status.missing.contains(&Missing::Key(k)));
+        }
+        let statuses = o.spend_path_statuses_at(&state.with_key(k).with_height(200));
+        let ready = statuses
+            .iter()
+            .filter(|s| s.status.as_ref().unwrap().is_satisfied())
+            .count();
+        assert_eq!(ready, 2);
+        let waiting = statuses
+            .iter()
+            .find_map(|s| s.status.as_ref().filter(|s| !s.is_satisfied()))
+            .unwrap();
+        assert_eq!(waiting.unlocks_at_height, Some(243));
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks a compiled contract against consensus and standardness limits, so
+//! that a contract which could never be mined (or relayed) is caught before
+//! it is funded.
+use super::object::{Object, SupportedDescriptors};
+use crate::contract::CompilationError;
+use crate::template::Template;
+use crate::util::amountrange::dust_threshold;
+use ::miniscript::descriptor::WshInner;
+use ::miniscript::{Descriptor, Miniscript, MiniscriptKey, ScriptContext};
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use serde::{Deserialize, Serialize};
+
+/// Largest weight of a transaction relayed by default
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+/// Largest number of stack elements permitted by consensus
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Largest segwit v0 witness script permitted by consensus
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+/// Largest segwit v0 witness script relayed by default
+pub const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3600;
+/// The tapscript signature operation budget is this plus the witness size
+pub const TAPSCRIPT_SIGOP_BUDGET_BASE: usize = 50;
+/// The cost of each signature operation against the tapscript budget
+pub const TAPSCRIPT_SIGOP_COST: usize = 50;
+/// Largest transaction version relayed by default
+pub const MAX_STANDARD_TX_VERSION: i32 = 2;
+
+/// Which limit was broken
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StandardnessRule {
+    /// A script is larger than permitted
+    ScriptSize {
+        /// the script's size
+        size: usize,
+        /// the limit
+        limit: usize,
+    },
+    /// Satisfying a script may need more stack elements than permitted
+    StackDepth {
+        /// the elements needed
+        items: usize,
+        /// the limit
+        limit: usize,
+    },
+    /// A tapscript may use more signature operations than its witness pays
+    /// for
+    SigopBudget {
+        /// the signature operations in the script
+        sigops: usize,
+        /// the budget its largest satisfaction allows
+        budget: usize,
+    },
+    /// Satisfying a script may need a larger witness than fits in a standard
+    /// transaction
+    WitnessSize {
+        /// the witness size
+        size: usize,
+        /// the limit
+        limit: usize,
+    },
+    /// A script can never be satisfied
+    Unsatisfiable,
+    /// An output is below the dust threshold of its script type
+    DustOutput {
+        /// the output's index
+        output: u32,
+        /// the output's amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        amount: Amount,
+    },
+    /// A transaction is heavier than a standard transaction
+    TxWeight {
+        /// the transaction's weight (without witnesses)
+        weight: usize,
+        /// the limit
+        limit: usize,
+    },
+    /// A transaction's version is not standard
+    Version(i32),
+    /// A transaction sets a lock time, but every input's sequence is final so
+    /// the lock time is not enforced
+    LockTimeIgnored,
+    /// A transaction has no inputs or no outputs
+    Empty,
+}
+
+/// # Standardness Violation
+/// A broken limit, and where in the contract it was found.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StandardnessViolation {
+    /// the path of the contract at fault
+    pub path: String,
+    /// if the violation is in a template, its CTV hash
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub template: Option<sha256::Hash>,
+    /// the limit broken
+    pub rule: StandardnessRule,
+}
+
+/// Check a single script against the limits of its context
+fn check_script<Pk: MiniscriptKey, Ctx: ScriptContext>(
+    ms: &Miniscript<Pk, Ctx>,
+    tapscript: bool,
+) -> Vec<StandardnessRule> {
+    let mut res = vec![];
+    let size = ms.script_size();
+    if !tapscript && size > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+        res.push(StandardnessRule::ScriptSize {
+            size,
+            limit: if size > MAX_SCRIPT_SIZE {
+                MAX_SCRIPT_SIZE
+            } else {
+                MAX_STANDARD_P2WSH_SCRIPT_SIZE
+            },
+        });
+    }
+    let (items, witness) = match (
+        ms.max_satisfaction_witness_elements(),
+        ms.max_satisfaction_size(),
+    ) {
+        (Ok(items), Ok(witness)) => (items, witness),
+        _ => {
+            res.push(StandardnessRule::Unsatisfiable);
+            return res;
+        }
+    };
+    if items > MAX_STACK_SIZE {
+        res.push(StandardnessRule::StackDepth {
+            items,
+            limit: MAX_STACK_SIZE,
+        });
+    }
+    // witness bytes weigh 1 each, and the script is part of the witness
+    if witness + size > MAX_STANDARD_TX_WEIGHT {
+        res.push(StandardnessRule::WitnessSize {
+            size: witness + size,
+            limit: MAX_STANDARD_TX_WEIGHT,
+        });
+    }
+    if tapscript {
+        let sigops = ms.iter_pk().count();
+        let budget = TAPSCRIPT_SIGOP_BUDGET_BASE + witness + size;
+        if sigops * TAPSCRIPT_SIGOP_COST > budget {
+            res.push(StandardnessRule::SigopBudget { sigops, budget });
+        }
+    }
+    res
+}
+
+/// Check a template's transaction against standardness rules
+pub fn check_template(tmpl: &Template) -> Vec<StandardnessRule> {
+    let tx = &tmpl.tx;
+    let mut res = vec![];
+    if tx.input.is_empty() || tx.output.is_empty() {
+        res.push(StandardnessRule::Empty);
+    }
+    if tx.version < 1 || tx.version > MAX_STANDARD_TX_VERSION {
+        res.push(StandardnessRule::Version(tx.version));
+    }
+    let weight = tx.get_weight();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        res.push(StandardnessRule::TxWeight {
+            weight,
+            limit: MAX_STANDARD_TX_WEIGHT,
+        });
+    }
+    if tx.lock_time != 0 && tx.input.iter().all(|i| i.sequence == 0xFFFF_FFFF) {
+        res.push(StandardnessRule::LockTimeIgnored);
+    }
+    // anchors carry the smallest standard amount for their output type
+    let anchors: Vec<u32> = tmpl.anchor_outputs().iter().map(|a| a.vout).collect();
+    for (i, out) in tx.output.iter().enumerate() {
+        if out.value < dust_threshold(&out.script_pubkey).as_sat() && !anchors.contains(&(i as u32))
+        {
+            res.push(StandardnessRule::DustOutput {
+                output: i as u32,
+                amount: Amount::from_sat(out.value),
+            });
+        }
+    }
+    res
+}
+
+impl SupportedDescriptors {
+    /// Check every script of this descriptor against the limits of its
+    /// context
+    pub fn check_standardness(&self) -> Vec<StandardnessRule> {
+        match self {
+            SupportedDescriptors::XOnly(Descriptor::Tr(t)) => t
+                .iter_scripts()
+                .flat_map(|(_, ms)| check_script(ms, true))
+                .collect(),
+            SupportedDescriptors::Pk(Descriptor::Wsh(w)) => match w.as_inner() {
+                WshInner::Ms(ms) => check_script(ms, false),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+}
+
+impl Object {
+    /// Every consensus or standardness limit broken by a script or template
+    /// reachable from this object.
+    pub fn standardness_violations(&self) -> Vec<StandardnessViolation> {
+        let mut res = vec![];
+        for (path, obj) in self.reachable_objects() {
+            if let Some(d) = &obj.descriptor {
+                res.extend(
+                    d.check_standardness()
+                        .into_iter()
+                        .map(|rule| StandardnessViolation {
+                            path: path.clone(),
+                            template: None,
+                            rule,
+                        }),
+                );
+            }
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                res.extend(
+                    check_template(tmpl)
+                        .into_iter()
+                        .map(|rule| StandardnessViolation {
+                            path: path.clone(),
+                            template: Some(tmpl.hash()),
+                            rule,
+                        }),
+                );
+            }
+        }
+        res
+    }
+
+    /// Check that every script and template reachable from this object is
+    /// within consensus and standardness limits, returning the first
+    /// violation found as a [`CompilationError::StandardnessViolation`].
+    pub fn check_standardness(&self) -> Result<(), CompilationError> {
+        match self.standardness_violations().into_iter().next() {
+            Some(v) => Err(CompilationError::StandardnessViolation(v)),
+            None => Ok(()),
+        }
+    }
+}
@@ -5,7 +5,9 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Formats for Sapio Studio
+use crate::contract::abi::broadcast::BroadcastAfter;
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::witness_template::WitnessTemplate;
 use crate::template::output::OutputMeta;
 use crate::template::TemplateMetadata;
 use ::miniscript::*;
@@ -17,6 +19,8 @@ use sapio_base::serialization_helpers::SArc;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+pub mod psbt_v2;
+pub use psbt_v2::{PsbtVersion, TxModifiable};
 
 /// Container for data from  `Object::bind_psbt`.
 #[derive(Serialize, Deserialize)]
@@ -28,6 +32,12 @@ pub struct LinkedPSBT {
     pub metadata: TemplateMetadata,
     /// output specific metadata
     pub output_metadata: Vec<OutputMeta>,
+    /// when the transaction may be mined, if known
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub broadcast_after: Option<BroadcastAfter>,
+    /// children spending each anchor output, for fee bumping via CPFP
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub cpfp_children: Vec<PartiallySignedTransaction>,
 }
 
 /// Format for a Linked PSBT in Sapio Studio
@@ -36,7 +46,7 @@ pub enum SapioStudioFormat {
     /// Used for PSBT Return Values
     #[serde(rename = "linked_psbt")]
     LinkedPSBT {
-        /// Base 64 Encoded PSBT
+        /// Base 64 Encoded PSBT, version 0 or 2 as requested at binding
         psbt: String,
         /// Hex encoded TXN
         hex: String,
@@ -44,21 +54,49 @@ pub enum SapioStudioFormat {
         metadata: TemplateMetadata,
         /// per-Output Metadata
         output_metadata: Vec<OutputMeta>,
+        /// when the transaction may be mined, if known
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        broadcast_after: Option<BroadcastAfter>,
+        /// Base 64 Encoded PSBTs spending each anchor output, for CPFP
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        cpfp_psbts: Vec<String>,
     },
 }
 
 impl From<LinkedPSBT> for SapioStudioFormat {
     fn from(l: LinkedPSBT) -> SapioStudioFormat {
-        let psbt = {
-            let bytes = serialize(&l.psbt);
-            base64::encode(bytes)
+        l.into_studio_format(PsbtVersion::V0, TxModifiable::default())
+    }
+}
+
+impl LinkedPSBT {
+    /// Encode for Sapio Studio, emitting PSBTs of the given version. For
+    /// version 2, `modifiable` sets what constructors may change in the
+    /// transaction; CPFP children are always left open to extension.
+    pub fn into_studio_format(
+        self,
+        version: PsbtVersion,
+        modifiable: TxModifiable,
+    ) -> SapioStudioFormat {
+        let encode = |p: &PartiallySignedTransaction, m: TxModifiable| match version {
+            PsbtVersion::V0 => base64::encode(serialize(p)),
+            PsbtVersion::V2 => base64::encode(psbt_v2::serialize_v2(p, m)),
+        };
+        let psbt = encode(&self.psbt, modifiable);
+        let open = TxModifiable {
+            inputs: true,
+            outputs: true,
+            sighash_single: false,
         };
-        let hex = bitcoin::consensus::encode::serialize_hex(&l.psbt.extract_tx());
+        let cpfp_psbts = self.cpfp_children.iter().map(|c| encode(c, open)).collect();
+        let hex = bitcoin::consensus::encode::serialize_hex(&self.psbt.extract_tx());
         SapioStudioFormat::LinkedPSBT {
             psbt,
             hex,
-            metadata: l.metadata,
-            output_metadata: l.output_metadata,
+            metadata: self.metadata,
+            output_metadata: self.output_metadata,
+            broadcast_after: self.broadcast_after,
+            cpfp_psbts,
         }
     }
 }
@@ -89,4 +127,7 @@ pub struct SapioStudioObject {
     pub txs: Vec<SapioStudioFormat>,
     /// List of continue APIs from this point.
     pub continue_apis: HashMap<SArc<EffectPath>, ContinuationPoint>,
+    /// What signers must provide to spend this object, per spend path
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub witness_templates: Vec<WitnessTemplate>,
 }
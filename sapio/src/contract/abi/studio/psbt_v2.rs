@@ -0,0 +1,182 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! PSBT version 2 (BIP 370) serialization, so that downstream constructors
+//! can add inputs and outputs (e.g. for fees) without rebuilding the
+//! transaction.
+use bitcoin::consensus::encode::{serialize, Decodable, Encodable, VarInt};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+const PSBT_MAGIC: &[u8; 5] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+/// # PSBT Version
+/// Which PSBT serialization to emit.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PsbtVersion {
+    /// BIP 174, with the unsigned transaction in the global map
+    V0,
+    /// BIP 370, with each input and output's fields in its own map
+    V2,
+}
+
+impl Default for PsbtVersion {
+    fn default() -> Self {
+        PsbtVersion::V0
+    }
+}
+
+/// What a PSBTv2's constructors may still change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct TxModifiable {
+    /// inputs may be added or removed
+    pub inputs: bool,
+    /// outputs may be added or removed
+    pub outputs: bool,
+    /// some input signs with SIGHASH_SINGLE, so inputs and outputs must be
+    /// added in pairs
+    pub sighash_single: bool,
+}
+
+impl TxModifiable {
+    /// The PSBT_GLOBAL_TX_MODIFIABLE flags
+    pub fn flags(&self) -> u8 {
+        (self.inputs as u8) | (self.outputs as u8) << 1 | (self.sighash_single as u8) << 2
+    }
+}
+
+type Map = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn read_map(c: &mut Cursor<&[u8]>) -> Map {
+    let mut map = vec![];
+    loop {
+        let key = Vec::<u8>::consensus_decode(&mut *c).expect("serialized by rust-bitcoin");
+        if key.is_empty() {
+            return map;
+        }
+        let value = Vec::<u8>::consensus_decode(&mut *c).expect("serialized by rust-bitcoin");
+        map.push((key, value));
+    }
+}
+
+fn write_map(out: &mut Vec<u8>, map: &Map) {
+    for (k, v) in map {
+        k.consensus_encode(&mut *out)
+            .expect("in-memory writes succeed");
+        v.consensus_encode(&mut *out)
+            .expect("in-memory writes succeed");
+    }
+    out.push(0x00);
+}
+
+/// Serialize `psbt` as a PSBTv2 with the given modifiable flags. Everything
+/// rust-bitcoin records in the version 0 maps is carried over.
+pub fn serialize_v2(psbt: &PartiallySignedTransaction, modifiable: TxModifiable) -> Vec<u8> {
+    let tx = &psbt.unsigned_tx;
+    let v0 = serialize(psbt);
+    let mut c = Cursor::new(&v0[PSBT_MAGIC.len()..]);
+    let mut global: Map = read_map(&mut c)
+        .into_iter()
+        .filter(|(k, _)| k[0] != PSBT_GLOBAL_UNSIGNED_TX && k[0] != PSBT_GLOBAL_VERSION)
+        .collect();
+    global.push((vec![PSBT_GLOBAL_TX_VERSION], serialize(&tx.version)));
+    if tx.lock_time != 0 {
+        global.push((
+            vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+            serialize(&tx.lock_time),
+        ));
+    }
+    global.push((
+        vec![PSBT_GLOBAL_INPUT_COUNT],
+        serialize(&VarInt(tx.input.len() as u64)),
+    ));
+    global.push((
+        vec![PSBT_GLOBAL_OUTPUT_COUNT],
+        serialize(&VarInt(tx.output.len() as u64)),
+    ));
+    global.push((vec![PSBT_GLOBAL_TX_MODIFIABLE], vec![modifiable.flags()]));
+    global.push((vec![PSBT_GLOBAL_VERSION], serialize(&2u32)));
+
+    let mut out = PSBT_MAGIC.to_vec();
+    write_map(&mut out, &global);
+    for txin in &tx.input {
+        let mut map = read_map(&mut c);
+        map.push((
+            vec![PSBT_IN_PREVIOUS_TXID],
+            serialize(&txin.previous_output.txid),
+        ));
+        map.push((
+            vec![PSBT_IN_OUTPUT_INDEX],
+            serialize(&txin.previous_output.vout),
+        ));
+        map.push((vec![PSBT_IN_SEQUENCE], serialize(&txin.sequence)));
+        write_map(&mut out, &map);
+    }
+    for txout in &tx.output {
+        let mut map = read_map(&mut c);
+        map.push((vec![PSBT_OUT_AMOUNT], serialize(&txout.value)));
+        map.push((vec![PSBT_OUT_SCRIPT], txout.script_pubkey.to_bytes()));
+        write_map(&mut out, &map);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Witness};
+    #[test]
+    fn v2_moves_tx_into_maps() {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFD,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let modifiable = TxModifiable {
+            inputs: true,
+            outputs: true,
+            sighash_single: false,
+        };
+        let v2 = serialize_v2(&psbt, modifiable);
+        assert_eq!(&v2[..5], PSBT_MAGIC);
+        let mut c = Cursor::new(&v2[5..]);
+        let global = read_map(&mut c);
+        assert!(global.iter().all(|(k, _)| k[0] != PSBT_GLOBAL_UNSIGNED_TX));
+        assert!(global.contains(&(vec![PSBT_GLOBAL_TX_MODIFIABLE], vec![0b11])));
+        assert!(global.contains(&(vec![PSBT_GLOBAL_VERSION], vec![2, 0, 0, 0])));
+        let input = read_map(&mut c);
+        assert!(input.contains(&(vec![PSBT_IN_SEQUENCE], vec![0xFD, 0xFF, 0xFF, 0xFF])));
+        let output = read_map(&mut c);
+        assert!(output.contains(&(vec![PSBT_OUT_AMOUNT], serialize(&1000u64))));
+        assert_eq!(c.position() as usize, v2.len() - 5);
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watch-only wallet export for every address reachable in a compiled contract
+use super::object::{Object, SupportedDescriptors};
+use crate::util::extended_address::ExtendedAddress;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+
+/// # Rescan Timestamp
+/// Where a wallet should start scanning for the imported scripts from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportTimestamp {
+    /// Only watch for new transactions (serialized as `"now"`)
+    Now,
+    /// Scan from this unix timestamp onwards
+    Time(u64),
+}
+
+impl Serialize for ImportTimestamp {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ImportTimestamp::Now => s.serialize_str("now"),
+            ImportTimestamp::Time(t) => s.serialize_u64(*t),
+        }
+    }
+}
+
+/// # `importdescriptors` Request
+/// A single entry of the argument to Bitcoin Core's `importdescriptors` RPC.
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportDescriptor {
+    /// the descriptor, including checksum
+    pub desc: String,
+    /// must be false for non-ranged watch-only descriptors
+    pub active: bool,
+    /// when to rescan from
+    pub timestamp: ImportTimestamp,
+    /// the contract path that created this address
+    pub label: String,
+}
+
+/// # `importmulti` Script
+/// The `scriptPubKey` field of an `importmulti` request.
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ImportMultiScript {
+    /// watch an address
+    Address {
+        /// the address to watch
+        address: String,
+    },
+    /// watch a raw script (hex)
+    Script(String),
+}
+
+/// # `importmulti` Request
+/// A single entry of the argument to Bitcoin Core's (legacy wallet)
+/// `importmulti` RPC.
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportMulti {
+    /// the script to watch
+    #[serde(rename = "scriptPubKey")]
+    pub script_pubkey: ImportMultiScript,
+    /// when to rescan from
+    pub timestamp: ImportTimestamp,
+    /// always true, nothing is known to be spendable
+    pub watchonly: bool,
+    /// the contract path that created this address
+    pub label: String,
+}
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn poly_mod(mut c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    c = ((c & 0x7ffffffff) << 5) ^ val;
+    for (bit, gen) in [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bb80d1213,
+        0x3706b1677a,
+        0x644d626ffd,
+    ]
+    .iter()
+    .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= gen;
+        }
+    }
+    c
+}
+
+/// Compute the BIP-380 checksum of a descriptor (without a checksum).
+/// Returns None if the descriptor has characters outside the descriptor
+/// character set.
+pub fn descriptor_checksum(desc: &str) -> Option<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET.find(ch)? as u64;
+        c = poly_mod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = poly_mod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = poly_mod(c, cls);
+    }
+    for _ in 0..8 {
+        c = poly_mod(c, 0);
+    }
+    c ^= 1;
+    Some(
+        (0..8)
+            .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+            .collect(),
+    )
+}
+
+/// attach a (freshly computed) checksum to a descriptor string
+fn with_checksum(desc: &str) -> String {
+    let desc = desc.split('#').next().unwrap_or(desc);
+    match descriptor_checksum(desc) {
+        Some(c) => format!("{}#{}", desc, c),
+        None => desc.into(),
+    }
+}
+
+impl Object {
+    /// Get every Object reachable from this one (including itself) through
+    /// its templates, paired with the path it was compiled at.
+    pub(crate) fn reachable_objects(&self) -> Vec<(String, &Object)> {
+        let mut res = vec![];
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            res.push((obj.root_path.0.to_string(), obj));
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                stack.extend(tmpl.outputs.iter().map(|o| &o.contract));
+            }
+        }
+        res
+    }
+
+    /// The output descriptor (with checksum) for this object's address, or
+    /// None for an OP_RETURN. Objects without a descriptor get an `addr()`
+    /// or `raw()` descriptor.
+    pub fn output_descriptor(&self) -> Option<String> {
+        let desc = match (&self.descriptor, &self.address) {
+            (_, ExtendedAddress::OpReturn(_)) => return None,
+            (Some(SupportedDescriptors::Pk(d)), _) => d.to_string(),
+            (Some(SupportedDescriptors::XOnly(d)), _) => d.to_string(),
+            (None, ExtendedAddress::Address(a)) => format!("addr({})", a),
+            (None, ExtendedAddress::Unknown(s)) => format!("raw({:x})", s),
+        };
+        Some(with_checksum(&desc))
+    }
+
+    /// The output descriptor (with checksum) of every address reachable in
+    /// this compiled contract, keyed by the path it was compiled at.
+    /// OP_RETURN outputs are skipped.
+    pub fn all_descriptors(&self) -> HashMap<SArc<EffectPath>, String> {
+        let mut res = HashMap::new();
+        for (_, obj) in self.reachable_objects() {
+            if let Some(desc) = obj.output_descriptor() {
+                res.entry(obj.root_path.clone()).or_insert(desc);
+            }
+        }
+        res
+    }
+
+    /// Generate the `importdescriptors` payload to watch every address
+    /// reachable in this compiled contract (the root plus all child
+    /// contracts). OP_RETURN outputs are skipped, and each script is only
+    /// imported once.
+    pub fn watch_only_descriptors(&self, timestamp: ImportTimestamp) -> Vec<ImportDescriptor> {
+        let mut seen = HashSet::new();
+        self.reachable_objects()
+            .into_iter()
+            .filter_map(|(label, obj)| {
+                let desc = obj.output_descriptor()?;
+                if seen.insert(desc.clone()) {
+                    Some(ImportDescriptor {
+                        desc,
+                        active: false,
+                        timestamp,
+                        label,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Generate the `importmulti` payload to watch every address reachable in
+    /// this compiled contract, for legacy (non-descriptor) wallets. OP_RETURN
+    /// outputs are skipped, and each script is only imported once.
+    pub fn watch_only_importmulti(&self, timestamp: ImportTimestamp) -> Vec<ImportMulti> {
+        let mut seen = HashSet::new();
+        self.reachable_objects()
+            .into_iter()
+            .filter_map(|(label, obj)| {
+                let script_pubkey = match &obj.address {
+                    ExtendedAddress::OpReturn(_) => return None,
+                    ExtendedAddress::Address(a) => ImportMultiScript::Address {
+                        address: a.to_string(),
+                    },
+                    ExtendedAddress::Unknown(s) => ImportMultiScript::Script(format!("{:x}", s)),
+                };
+                if seen.insert(bitcoin::Script::from(obj.address.clone())) {
+                    Some(ImportMulti {
+                        script_pubkey,
+                        timestamp,
+                        watchonly: true,
+                        label,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_checksum() {
+        assert_eq!(
+            descriptor_checksum("raw(deadbeef)"),
+            Some("vxy5ajq4".into())
+        );
+        assert_eq!(
+            with_checksum("raw(deadbeef)#00000000"),
+            "raw(deadbeef)#vxy5ajq4"
+        );
+        assert_eq!(
+            serde_json::to_string(&ImportTimestamp::Now).unwrap(),
+            "\"now\""
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Witness templates tell signers exactly which items are needed, and in
+//! what order, to spend a contract through each of its spend paths.
+use super::object::{Object, ObjectError, SupportedDescriptors};
+use ::miniscript::descriptor::Tr;
+use ::miniscript::{Descriptor, DescriptorTrait, MiniscriptKey, Satisfier, ToPublicKey};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d};
+use bitcoin::secp256k1::{ecdsa, schnorr, Secp256k1, Verification};
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{EcdsaSig, EcdsaSighashType, SchnorrSig, SchnorrSighashType, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// # Witness Item
+/// A single element of a witness stack.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WitnessItem {
+    /// # Signature
+    /// A signature from `key`
+    Signature {
+        /// hex encoded public key
+        key: String,
+    },
+    /// # Preimage
+    /// The preimage of `hash`
+    Preimage {
+        /// one of sha256, hash256, ripemd160, hash160
+        hash_type: String,
+        /// hex encoded hash
+        hash: String,
+    },
+    /// # Constant Push
+    /// A fixed value (e.g., empty, to take the other side of a branch)
+    Push {
+        /// hex encoded bytes
+        hex: String,
+    },
+    /// # Tapscript
+    /// The leaf script being executed
+    Script {
+        /// hex encoded script
+        hex: String,
+    },
+    /// # Control Block
+    /// The taproot control block for the leaf
+    ControlBlock {
+        /// hex encoded control block
+        hex: String,
+    },
+    /// # Witness Script
+    /// The segwit v0 witness script
+    WitnessScript {
+        /// hex encoded script
+        hex: String,
+    },
+}
+
+/// # Spend Path
+/// Which way a witness template spends the output.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SpendPath {
+    /// # Taproot Key Path
+    KeyPath,
+    /// # Taproot Script Path
+    ScriptPath {
+        /// hex encoded tapleaf hash
+        leaf_hash: String,
+        /// depth of the leaf in the tree
+        depth: u8,
+    },
+    /// # Segwit V0 Script
+    WitnessScript,
+}
+
+/// # Witness Template
+/// The ordered witness stack (bottom first) needed to spend via a path.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct WitnessTemplate {
+    /// # Spend Path
+    pub spend_path: SpendPath,
+    /// # Witness Stack
+    pub stack: Vec<WitnessItem>,
+}
+
+/// A Satisfier which answers every request with a unique placeholder, and
+/// remembers what each placeholder stands for.
+#[derive(Default)]
+struct Placeholders {
+    items: RefCell<Vec<(Vec<u8>, WitnessItem)>>,
+}
+
+impl Placeholders {
+    fn id(&self) -> [u8; 4] {
+        (self.items.borrow().len() as u32 + 1).to_be_bytes()
+    }
+    fn remember(&self, bytes: Vec<u8>, item: WitnessItem) {
+        self.items.borrow_mut().push((bytes, item));
+    }
+    fn preimage(&self, hash_type: &str, hash: String) -> Option<[u8; 32]> {
+        let mut preimage = [0xaa; 32];
+        preimage[..4].copy_from_slice(&self.id());
+        self.remember(
+            preimage.to_vec(),
+            WitnessItem::Preimage {
+                hash_type: hash_type.into(),
+                hash,
+            },
+        );
+        Some(preimage)
+    }
+    /// map a satisfied witness back to what each element stands for
+    fn resolve(&self, witness: Vec<Vec<u8>>, script: &[u8], tap: bool) -> Vec<WitnessItem> {
+        let items = self.items.borrow();
+        witness
+            .into_iter()
+            .map(|w| {
+                if let Some((_, item)) = items.iter().find(|(b, _)| *b == w) {
+                    item.clone()
+                } else if w == script {
+                    let hex = w.to_hex();
+                    if tap {
+                        WitnessItem::Script { hex }
+                    } else {
+                        WitnessItem::WitnessScript { hex }
+                    }
+                } else {
+                    WitnessItem::Push { hex: w.to_hex() }
+                }
+            })
+            .collect()
+    }
+}
+
+impl<Pk: MiniscriptKey + ToPublicKey> Satisfier<Pk> for Placeholders {
+    fn lookup_ecdsa_sig(&self, pk: &Pk) -> Option<EcdsaSig> {
+        let mut compact = [0u8; 64];
+        compact[28..32].copy_from_slice(&self.id());
+        compact[63] = 1;
+        let sig = EcdsaSig {
+            sig: ecdsa::Signature::from_compact(&compact).ok()?,
+            hash_ty: EcdsaSighashType::All,
+        };
+        self.remember(
+            sig.to_vec(),
+            WitnessItem::Signature {
+                key: pk.to_public_key().to_string(),
+            },
+        );
+        Some(sig)
+    }
+    fn lookup_tap_leaf_script_sig(&self, pk: &Pk, _: &TapLeafHash) -> Option<SchnorrSig> {
+        let mut bytes = [0u8; 64];
+        bytes[..4].copy_from_slice(&self.id());
+        bytes[63] = 1;
+        let sig = SchnorrSig {
+            sig: schnorr::Signature::from_slice(&bytes).ok()?,
+            hash_ty: SchnorrSighashType::Default,
+        };
+        self.remember(
+            sig.to_vec(),
+            WitnessItem::Signature {
+                key: pk.to_x_only_pubkey().to_string(),
+            },
+        );
+        Some(sig)
+    }
+    fn lookup_sha256(&self, h: sha256::Hash) -> Option<[u8; 32]> {
+        self.preimage("sha256", h.to_string())
+    }
+    fn lookup_hash256(&self, h: sha256d::Hash) -> Option<[u8; 32]> {
+        self.preimage("hash256", h.to_string())
+    }
+    fn lookup_ripemd160(&self, h: ripemd160::Hash) -> Option<[u8; 32]> {
+        self.preimage("ripemd160", h.to_string())
+    }
+    fn lookup_hash160(&self, h: hash160::Hash) -> Option<[u8; 32]> {
+        self.preimage("hash160", h.to_string())
+    }
+    fn check_older(&self, _: u32) -> bool {
+        true
+    }
+    fn check_after(&self, _: u32) -> bool {
+        true
+    }
+}
+
+/// Compute the spend info for a taproot descriptor
+pub(crate) fn taproot_spend_info<C: Verification>(
+    t: &Tr<XOnlyPublicKey>,
+    secp: &Secp256k1<C>,
+) -> Result<TaprootSpendInfo, ObjectError> {
+    let mut builder = TaprootBuilder::new();
+    let mut added = false;
+    for (depth, ms) in t.iter_scripts() {
+        added = true;
+        builder = builder.add_leaf(depth, ms.encode())?;
+    }
+    Ok(if added {
+        builder.finalize(secp, t.internal_key().clone())?
+    } else {
+        TaprootSpendInfo::new_key_spend(secp, t.internal_key().clone(), None)
+    })
+}
+
+impl SupportedDescriptors {
+    /// Generate a witness template for every spend path of this descriptor.
+    ///
+    /// Within a single script, where several satisfactions are possible the
+    /// one miniscript considers cheapest is chosen.
+    pub fn witness_templates(&self) -> Result<Vec<WitnessTemplate>, ObjectError> {
+        match self {
+            SupportedDescriptors::Pk(d) => {
+                let placeholders = Placeholders::default();
+                let script = d.explicit_script()?;
+                let (witness, _) = d.get_satisfaction(&placeholders)?;
+                Ok(vec![WitnessTemplate {
+                    spend_path: SpendPath::WitnessScript,
+                    stack: placeholders.resolve(witness, script.as_bytes(), false),
+                }])
+            }
+            SupportedDescriptors::XOnly(Descriptor::Tr(t)) => {
+                let secp = Secp256k1::verification_only();
+                let info = taproot_spend_info(t, &secp)?;
+                let mut res = vec![WitnessTemplate {
+                    spend_path: SpendPath::KeyPath,
+                    stack: vec![WitnessItem::Signature {
+                        key: t.internal_key().to_string(),
+                    }],
+                }];
+                for (depth, ms) in t.iter_scripts() {
+                    let placeholders = Placeholders::default();
+                    let script = ms.encode();
+                    let witness = ms.satisfy(&placeholders)?;
+                    let mut stack = placeholders.resolve(witness, script.as_bytes(), true);
+                    stack.push(WitnessItem::Script {
+                        hex: script.to_hex(),
+                    });
+                    let control_block = info
+                        .control_block(&(script.clone(), LeafVersion::TapScript))
+                        .expect("Must be present");
+                    stack.push(WitnessItem::ControlBlock {
+                        hex: control_block.serialize().to_hex(),
+                    });
+                    res.push(WitnessTemplate {
+                        spend_path: SpendPath::ScriptPath {
+                            leaf_hash: TapLeafHash::from_script(&script, LeafVersion::TapScript)
+                                .to_hex(),
+                            depth,
+                        },
+                        stack,
+                    });
+                }
+                Ok(res)
+            }
+            SupportedDescriptors::XOnly(_) => Ok(vec![]),
+        }
+    }
+}
+
+impl Object {
+    /// Generate a witness template for every spend path of this object, if
+    /// its descriptor is known.
+    pub fn witness_templates(&self) -> Result<Vec<WitnessTemplate>, ObjectError> {
+        self.descriptor
+            .as_ref()
+            .map_or(Ok(vec![]), |d| d.witness_templates())
+    }
+}
@@ -12,6 +12,8 @@ use crate::contract::actions::ConditionallyCompileIfList;
 use crate::contract::actions::GuardList;
 use sapio_base::effects::EffectDBError;
 
+pub use bitcoin::SchnorrSighashType;
+
 use core::marker::PhantomData;
 use schemars::schema::RootSchema;
 use serde::Deserialize;
@@ -39,6 +41,13 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     pub schema: Option<Arc<RootSchema>>,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used to spend, used to place
+    /// more likely branches shallower in the taproot tree.
+    pub weight: u64,
+    /// the sighash type signers must use when spending via this branch, if
+    /// not the default (e.g. `SinglePlusAnyoneCanPay` to let others add
+    /// inputs and outputs).
+    pub sighash: Option<SchnorrSighashType>,
     /// Type switch to enable/disable compilation with serialized fields
     /// (if negative trait bounds, could remove!)
     pub f: PhantomData<WebAPIStatus>,
@@ -69,6 +78,10 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     fn get_name(&self) -> &Arc<String>;
     /// Get the RootSchema for calling this with an update
     fn get_schema(&self) -> &Option<Arc<RootSchema>>;
+    /// Get the relative likelihood of this branch being used to spend
+    fn get_weight(&self) -> u64;
+    /// Get the sighash type required to spend via this branch, if any
+    fn get_sighash(&self) -> Option<SchnorrSighashType>;
     /// If the call_json is implemented
     fn has_call_json(&self) -> bool {
         false
@@ -99,6 +112,12 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
+    fn get_weight(&self) -> u64 {
+        self.weight
+    }
+    fn get_sighash(&self) -> Option<SchnorrSighashType> {
+        self.sighash
+    }
 }
 
 impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf, StatefulArguments>
@@ -138,4 +157,10 @@ where
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
+    fn get_weight(&self) -> u64 {
+        self.weight
+    }
+    fn get_sighash(&self) -> Option<SchnorrSighashType> {
+        self.sighash
+    }
 }
@@ -12,11 +12,24 @@ use sapio_base::Clause;
 /// If bool = true, the computation of the guard is cached, which is useful if e.g. Guard
 /// must contact a remote server or it should be the same across calls *for a given contract
 /// instance*.
+///
+/// The `u64` is the relative likelihood of the guard being used to spend
+/// when it is a finish branch, used to place more likely branches shallower
+/// in the taproot tree. It is ignored when the guard is used in `guarded_by`.
 pub enum Guard<ContractSelf> {
     /// Cache Variant should only be called one time per contract and the result saved
-    Cache(fn(&ContractSelf, Context) -> Clause),
+    Cache(fn(&ContractSelf, Context) -> Clause, u64),
     /// Fresh Variant may be called repeatedly
-    Fresh(fn(&ContractSelf, Context) -> Clause),
+    Fresh(fn(&ContractSelf, Context) -> Clause, u64),
+}
+
+impl<ContractSelf> Guard<ContractSelf> {
+    /// the relative likelihood of this guard being used to spend
+    pub fn weight(&self) -> u64 {
+        match self {
+            Guard::Cache(_, w) | Guard::Fresh(_, w) => *w,
+        }
+    }
 }
 
 /// A List of Guards, for convenience
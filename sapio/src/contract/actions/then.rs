@@ -26,4 +26,7 @@ pub struct ThenFunc<'a, ContractSelf> {
     pub func: fn(&ContractSelf, Context) -> TxTmplIt,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used to spend, used to place
+    /// more likely branches shallower in the taproot tree.
+    pub weight: u64,
 }
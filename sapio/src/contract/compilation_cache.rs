@@ -0,0 +1,120 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Caches of compiled subcontracts, so that recompiling a tree after a small
+//! change (e.g., one new effect) only recompiles what changed.
+//!
+//! A cache is attached with [`Context::with_compilation_cache`] and used by
+//! [`Context::compile_cached`] and
+//! [`Builder::add_output_cached`](crate::template::Builder::add_output_cached).
+//! Diagnostics are not replayed for cache hits. A cache should not be shared
+//! between contexts using different CTV emulators.
+use super::{CompilationError, Compiled, Context};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::util::amount::Amount;
+use bitcoin::Network;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Everything which determines the result of compiling a subcontract
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// where the subcontract is compiled
+    pub path: SArc<EffectPath>,
+    /// hash of the subcontract's serialized arguments
+    pub args: sha256::Hash,
+    /// the network compiled for
+    pub network: Network,
+    /// the funds available to the subcontract
+    pub funds: Amount,
+    /// digest of the effects applicable to the subcontract
+    pub effects: sha256::Hash,
+}
+
+impl CacheKey {
+    /// Compute the key for compiling `args` with `ctx`
+    pub fn new<A: Serialize>(ctx: &Context, args: &A) -> Result<Self, CompilationError> {
+        let args = serde_json::to_vec(args).map_err(CompilationError::SerializationError)?;
+        Ok(CacheKey {
+            path: SArc(ctx.path().clone()),
+            args: sha256::Hash::hash(&args),
+            network: ctx.network,
+            funds: ctx.funds(),
+            effects: ctx.effects_digest(),
+        })
+    }
+    /// A single hash committing to the whole key
+    pub fn digest(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(self.path.0.to_string().as_bytes());
+        engine.input(&self.args[..]);
+        engine.input(self.network.magic().to_le_bytes().as_ref());
+        engine.input(&self.funds.as_sat().to_le_bytes());
+        engine.input(&self.effects[..]);
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+/// A store of previously compiled subcontracts
+pub trait CompilationCache: Send + Sync {
+    /// look up a previous compilation
+    fn get(&self, key: &CacheKey) -> Option<Compiled>;
+    /// record a compilation
+    fn put(&self, key: CacheKey, compiled: &Compiled);
+}
+
+/// A CompilationCache held in memory
+#[derive(Default)]
+pub struct MemoryCompilationCache {
+    entries: Mutex<HashMap<CacheKey, Compiled>>,
+}
+
+impl CompilationCache for MemoryCompilationCache {
+    fn get(&self, key: &CacheKey) -> Option<Compiled> {
+        self.entries.lock().ok()?.get(key).cloned()
+    }
+    fn put(&self, key: CacheKey, compiled: &Compiled) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert(key, compiled.clone());
+        }
+    }
+}
+
+/// A CompilationCache persisted as one JSON file per entry in a directory,
+/// so it survives restarts. Unreadable entries are treated as misses.
+pub struct DirCompilationCache {
+    dir: PathBuf,
+}
+
+impl DirCompilationCache {
+    /// Use (creating if needed) `dir` for cache entries
+    pub fn new<P: Into<PathBuf>>(dir: P) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(DirCompilationCache { dir })
+    }
+    fn file(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{}.json", key.digest().to_hex()))
+    }
+}
+
+impl CompilationCache for DirCompilationCache {
+    fn get(&self, key: &CacheKey) -> Option<Compiled> {
+        let data = std::fs::read(self.file(key)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+    fn put(&self, key: CacheKey, compiled: &Compiled) {
+        if let Ok(data) = serde_json::to_vec(compiled) {
+            // a failed write only costs a recompile later
+            let _ = std::fs::write(self.file(&key), data);
+        }
+    }
+}
@@ -30,8 +30,8 @@ impl<T> GuardCache<T> {
     }
     pub(crate) fn create_entry(g: Option<Guard<T>>, t: &T, ctx: Context) -> Option<CacheEntry<T>> {
         Some(match g? {
-            Guard::Cache(f) => CacheEntry::Cached(f(t, ctx)),
-            Guard::Fresh(f) => CacheEntry::Fresh(f),
+            Guard::Cache(f, _) => CacheEntry::Cached(f(t, ctx)),
+            Guard::Fresh(f, _) => CacheEntry::Fresh(f),
         })
     }
     pub(crate) fn get(
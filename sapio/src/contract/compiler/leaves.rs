@@ -0,0 +1,84 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Common subexpression elimination for taproot leaves
+use super::CompilationError;
+use ::miniscript::{Miniscript, Tap};
+use bitcoin::{Script, XOnlyPublicKey};
+use sapio_base::clause_simplify::simplify;
+use sapio_base::Clause;
+use std::collections::HashMap;
+
+/// Compile weighted clauses into leaves, compiling each distinct clause once
+/// and merging leaves with identical scripts (e.g., the same guard reached
+/// via several branches). A merged leaf is weighted by the sum of the
+/// weights merged into it, and keeps the position of its first occurrence.
+///
+/// Clauses are simplified (see [`simplify`]) first, and ones which can never
+/// be satisfied are left out.
+pub(crate) fn merge_leaves<'a, I>(
+    clauses: I,
+) -> Result<Vec<(u64, Miniscript<XOnlyPublicKey, Tap>)>, CompilationError>
+where
+    I: Iterator<Item = &'a (u64, Clause)>,
+{
+    let mut distinct: Vec<(u64, Clause)> = vec![];
+    let mut seen: HashMap<Clause, usize> = HashMap::new();
+    for (w, c) in clauses {
+        let c = simplify(c);
+        if c == Clause::Unsatisfiable {
+            continue;
+        }
+        match seen.get(&c) {
+            Some(i) => distinct[*i].0 = distinct[*i].0.saturating_add(*w),
+            None => {
+                seen.insert(c.clone(), distinct.len());
+                distinct.push((*w, c));
+            }
+        }
+    }
+    let mut leaves: Vec<(u64, Miniscript<XOnlyPublicKey, Tap>)> = vec![];
+    let mut scripts: HashMap<Script, usize> = HashMap::new();
+    for (w, c) in distinct {
+        let ms = c.compile().map_err(Into::<CompilationError>::into)?;
+        match scripts.get(&ms.encode()) {
+            Some(i) => leaves[*i].0 = leaves[*i].0.saturating_add(w),
+            None => {
+                scripts.insert(ms.encode(), leaves.len());
+                leaves.push((w, ms));
+            }
+        }
+    }
+    Ok(leaves)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::internal_key::{hashed_constant_key, BIP341_NUMS_X};
+    #[test]
+    fn identical_clauses_merge() {
+        let a = Clause::Key(hashed_constant_key());
+        let b = Clause::Key(XOnlyPublicKey::from_slice(&BIP341_NUMS_X).unwrap());
+        let clauses = vec![(1, a.clone()), (2, b), (3, a)];
+        let leaves = merge_leaves(clauses.iter()).unwrap();
+        assert_eq!(leaves.len(), 2);
+        assert_eq!(leaves[0].0, 4);
+        assert_eq!(leaves[1].0, 2);
+    }
+    #[test]
+    fn equivalent_clauses_merge() {
+        let a = Clause::Key(hashed_constant_key());
+        let clauses = vec![
+            (1, a.clone()),
+            (2, Clause::And(vec![Clause::Trivial, a.clone()])),
+            (3, Clause::And(vec![Clause::Unsatisfiable, a])),
+        ];
+        let leaves = merge_leaves(clauses.iter()).unwrap();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].0, 3);
+    }
+}
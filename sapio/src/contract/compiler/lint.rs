@@ -0,0 +1,96 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Static checks on a contract's branches which don't need its templates:
+//! guards which can never be satisfied whatever the witness, and branch
+//! names which would be derived at the same path.
+use super::CompilationError;
+use sapio_base::effects::EffectPath;
+use sapio_base::effects::PathFragment;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// true if no witness can satisfy `clause`, because every way to satisfy it
+/// requires a `Clause::Unsatisfiable` (or a threshold larger than the
+/// satisfiable clauses under it).
+pub(crate) fn structurally_unsatisfiable(clause: &Clause) -> bool {
+    match clause {
+        Clause::Unsatisfiable => true,
+        Clause::And(v) => v.iter().any(structurally_unsatisfiable),
+        Clause::Or(v) => v.iter().all(|(_, c)| structurally_unsatisfiable(c)),
+        Clause::Threshold(k, v) => v.iter().filter(|c| !structurally_unsatisfiable(c)).count() < *k,
+        _ => false,
+    }
+}
+
+/// Reject branch `names`, derived under `at`, if any two would be derived at
+/// the same path, as the second would fail to derive its context.
+///
+/// A name's path fragment is its escaped form (see `PathFragment`), which is
+/// distinct for distinct names, so two names collide exactly when they are
+/// equal. Then and finish_or branches are derived under different paths, so
+/// this catches a name used twice among one kind of branch, e.g. by
+/// declaring one action twice or by two continuations given the same name.
+pub(crate) fn check_branch_names<'a>(
+    names: impl Iterator<Item = &'a String>,
+    at: &Arc<EffectPath>,
+) -> Result<(), CompilationError> {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(CompilationError::DuplicateBranchName {
+                name: name.clone(),
+                path: EffectPath::push(
+                    Some(at.clone()),
+                    PathFragment::Named(SArc(Arc::new(name.clone()))),
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    #[test]
+    fn unsatisfiable_structure() {
+        let lock = Clause::After(100);
+        let dead = Clause::And(vec![lock.clone(), Clause::Unsatisfiable]);
+        assert!(structurally_unsatisfiable(&dead));
+        assert!(structurally_unsatisfiable(&Clause::Or(vec![
+            (1, dead.clone()),
+            (1, Clause::Unsatisfiable)
+        ])));
+        assert!(!structurally_unsatisfiable(&Clause::Or(vec![
+            (1, dead.clone()),
+            (1, lock.clone())
+        ])));
+        assert!(structurally_unsatisfiable(&Clause::Threshold(
+            2,
+            vec![lock.clone(), dead.clone(), Clause::Unsatisfiable]
+        )));
+        assert!(!structurally_unsatisfiable(&Clause::Threshold(
+            1,
+            vec![lock, dead]
+        )));
+    }
+
+    #[test]
+    fn branch_name_collisions() {
+        let at = Arc::new(EffectPath::try_from("root").unwrap());
+        let names: Vec<String> = vec!["pay".into(), "pay_".into(), "pay%".into(), "Pay".into()];
+        assert!(check_branch_names(names.iter(), &at).is_ok());
+        let names: Vec<String> = vec!["pay".into(), "refund".into(), "pay".into()];
+        match check_branch_names(names.iter(), &at) {
+            Err(CompilationError::DuplicateBranchName { name, .. }) => assert_eq!(name, "pay"),
+            _ => panic!("duplicate branch name not caught"),
+        }
+    }
+}
@@ -13,15 +13,27 @@ use super::Context;
 use crate::contract::abi::continuation::ContinuationPoint;
 use crate::contract::actions::conditional_compile::CCILWrapper;
 use crate::contract::actions::CallableAsFoF;
+use crate::contract::covenant::{self, apo, CovenantBackend};
+use crate::contract::diagnostics::{Diagnostic, WarningKind};
+use crate::contract::object::{RawTaproot, SupportedDescriptors};
 use crate::contract::TxTmplIt;
 use crate::util::amountrange::AmountRange;
+use crate::util::extended_address::ExtendedAddress;
+use crate::util::internal_key::{
+    hashed_constant_key, nums_key, InternalKeyPolicy, InternalKeySource, HASHED_CONSTANT_PREIMAGE,
+};
+use crate::util::musig::KeyAggregation;
 use ::miniscript::descriptor::TapTree;
 use ::miniscript::*;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::schnorr::TweakedPublicKey;
+use bitcoin::util::amount::Amount;
 use std::collections::BinaryHeap;
 
+use bitcoin::util::taproot::LeafVersion;
+use bitcoin::SchnorrSighashType;
+use bitcoin::Script;
 use bitcoin::XOnlyPublicKey;
 use sapio_base::effects::EffectDB;
 use sapio_base::effects::EffectPath;
@@ -35,6 +47,17 @@ use std::collections::LinkedList;
 use std::sync::Arc;
 mod cache;
 use cache::*;
+mod leaves;
+use leaves::merge_leaves;
+mod lint;
+use lint::{check_branch_names, structurally_unsatisfiable};
+pub(crate) mod schema;
+use schema::check_effect_arg;
+mod timelocks;
+use timelocks::{check_timelock_units, timelocks_satisfiable};
+/// Leaf scripts larger than this (the pre-taproot consensus limit) raise a
+/// [`WarningKind::OversizedScript`]
+pub const MAX_SCRIPT_SIZE_WARNING: usize = 10_000;
 /// Used to prevent unintended callers to internal_clone.
 pub struct InternalCompilerTag {
     _secret: (),
@@ -86,6 +109,20 @@ enum Nullable {
     No,
 }
 
+/// Record the sighash type a branch requires on each template it suggests
+fn with_sighash(txtmpls: TxTmplIt, sighash: Option<SchnorrSighashType>) -> TxTmplIt {
+    let txtmpls = txtmpls?;
+    match sighash {
+        None => Ok(txtmpls),
+        Some(ty) => Ok(Box::new(txtmpls.map(move |r| {
+            r.and_then(|mut t| {
+                t.metadata_map_s2s = t.metadata_map_s2s.set_sighash_type(ty)?;
+                Ok(t)
+            })
+        }))),
+    }
+}
+
 fn compute_all_effects<C, A: Default>(
     mut top_effect_ctx: Context,
     self_ref: &C,
@@ -93,10 +130,30 @@ fn compute_all_effects<C, A: Default>(
 ) -> TxTmplIt {
     let mut applied_effects_ctx = top_effect_ctx.derive(PathFragment::Effects)?;
     let default_applied_effect_ctx = top_effect_ctx.derive(PathFragment::DefaultEffect)?;
-    top_effect_ctx
-        .get_effects(InternalCompilerTag { _secret: () })
+    let effects = top_effect_ctx.get_effects(InternalCompilerTag { _secret: () });
+    effects
         .get_value(top_effect_ctx.path())
         .flat_map(|(k, arg)| {
+            // skip stale or otherwise inapplicable effects, which are not an
+            // error in the contract being compiled
+            if let Err(e) = effects.check_applicable(top_effect_ctx.path(), k) {
+                top_effect_ctx.diagnostics().push(Diagnostic {
+                    path: SArc(top_effect_ctx.path().clone()),
+                    kind: WarningKind::InapplicableEffect,
+                    message: format!("effect {} was not applied: {:?}", k, e),
+                });
+                return None;
+            }
+            // reject arguments not matching the schema before deserializing
+            // them, so the error names the effect
+            if func.has_call_json() {
+                if let Err(e) =
+                    check_effect_arg(func.get_schema().as_deref(), top_effect_ctx.path(), k, arg)
+                {
+                    let r: TxTmplIt = Err(e.into());
+                    return Some(r);
+                }
+            }
             let c = applied_effects_ctx
                 .derive(PathFragment::Named(SArc(k.clone())))
                 .expect("Must be a valid derivation or internal invariant not held");
@@ -124,9 +181,12 @@ where
     /// The main Compilation Logic for a Contract.
     /// TODO: Better Document Semantics
     fn compile(&self, mut ctx: Context) -> Result<Compiled, CompilationError> {
+        let _scope = ctx.diagnostics().enter_compile();
+        self.validate(&ctx)?;
         let self_ref = self.get_inner_ref();
 
         let guard_clauses = std::cell::RefCell::new(GuardCache::new());
+        let contract_type = std::any::type_name::<T>();
 
         // The code for then_fns and finish_or_fns is very similar, differing
         // only in that then_fns have a CTV enforcing the contract and
@@ -134,20 +194,27 @@ where
         // in a row.
         let then_fns: Vec<_> = {
             let mut then_fn_ctx = ctx.derive(PathFragment::ThenFn)?;
+            let funcs: Vec<_> = self.then_fns().iter().filter_map(|func| func()).collect();
+            check_branch_names(funcs.iter().map(|f| f.name.as_ref()), then_fn_ctx.path())?;
             let mut conditional_compile_ctx = then_fn_ctx.derive(PathFragment::CondCompIf)?;
             let mut guards_ctx = then_fn_ctx.derive(PathFragment::Guard)?;
             let mut next_tx_ctx = then_fn_ctx.derive(PathFragment::Next)?;
-            self.then_fns()
-                .iter()
-                .filter_map(|func| func())
+            funcs
+                .into_iter()
                 .flat_map(|func| {
                     let name = PathFragment::Named(SArc(func.name.clone()));
                     conditional_compile_ctx
                         .derive(name.clone())
                         .map(|mut this_ctx| {
-                            match CCILWrapper(func.conditional_compile_if)
-                                .assemble(self_ref, &mut this_ctx)
-                            {
+                            let constraint = CCILWrapper(func.conditional_compile_if)
+                                .assemble(self_ref, &mut this_ctx);
+                            this_ctx.diagnostics().record_reachability(
+                                contract_type,
+                                &func.name,
+                                this_ctx.path(),
+                                !matches!(constraint, ConditionalCompileType::Never),
+                            );
+                            match constraint {
                                 ConditionalCompileType::Fail(errors) => {
                                     Some((func, name, (errors, Nullable::No)))
                                 }
@@ -178,6 +245,8 @@ where
                             nullability,
                             UseCTV::Yes,
                             guards,
+                            ntx_ctx.path().clone(),
+                            func.weight,
                             if errors.is_empty() {
                                 (func.func)(self_ref, ntx_ctx)
                             } else {
@@ -193,15 +262,23 @@ where
         // the default argument.
         let (continue_apis, finish_or_fns): (
             HashMap<SArc<EffectPath>, ContinuationPoint>,
-            Vec<(Nullable, UseCTV, Clause, TxTmplIt)>,
+            Vec<(Nullable, UseCTV, Clause, Arc<EffectPath>, u64, TxTmplIt)>,
         ) = {
             let mut finish_or_fns_ctx = ctx.derive(PathFragment::FinishOrFn)?;
+            let funcs: Vec<_> = self
+                .finish_or_fns()
+                .iter()
+                .filter_map(|func| func())
+                .collect();
+            check_branch_names(
+                funcs.iter().map(|f| f.get_name().as_ref()),
+                finish_or_fns_ctx.path(),
+            )?;
             let mut conditional_compile_ctx = finish_or_fns_ctx.derive(PathFragment::CondCompIf)?;
             let mut guard_ctx = finish_or_fns_ctx.derive(PathFragment::Guard)?;
             let mut suggested_tx_ctx = finish_or_fns_ctx.derive(PathFragment::Suggested)?;
-            self.finish_or_fns()
-                .iter()
-                .filter_map(|func| func())
+            funcs
+                .into_iter()
                 // TODO: De-duplicate this code?
                 .filter_map(|func| {
                     let name = PathFragment::Named(SArc(func.get_name().clone()));
@@ -210,6 +287,12 @@ where
                         .map(|mut this_ctx| {
                             let constraint = CCILWrapper(func.get_conditional_compile_if())
                                 .assemble(self_ref, &mut this_ctx);
+                            this_ctx.diagnostics().record_reachability(
+                                contract_type,
+                                func.get_name(),
+                                this_ctx.path(),
+                                !matches!(constraint, ConditionalCompileType::Never),
+                            );
                             match constraint {
                                 ConditionalCompileType::Fail(errors) => Some((func, name, errors)),
                                 ConditionalCompileType::Required
@@ -238,14 +321,24 @@ where
                                 ContinuationPoint::at(
                                     func.get_schema().clone(),
                                     top_effect_ctx.path().clone(),
-                                ),
+                                )
+                                .with_sighash(func.get_sighash()),
                             ),
                             (
                                 Nullable::Yes,
                                 UseCTV::No,
                                 guard,
+                                top_effect_ctx.path().clone(),
+                                func.get_weight(),
                                 if errors.is_empty() {
-                                    compute_all_effects(top_effect_ctx, self_ref, func.as_ref())
+                                    with_sighash(
+                                        compute_all_effects(
+                                            top_effect_ctx,
+                                            self_ref,
+                                            func.as_ref(),
+                                        ),
+                                        func.get_sighash(),
+                                    )
                                 } else {
                                     Err(CompilationError::ConditionalCompilationFailed(errors))
                                 },
@@ -256,7 +349,7 @@ where
                 .collect::<Result<
                     Vec<(
                         (SArc<EffectPath>, ContinuationPoint),
-                        (Nullable, UseCTV, Clause, TxTmplIt),
+                        (Nullable, UseCTV, Clause, Arc<EffectPath>, u64, TxTmplIt),
                     )>,
                     CompilationError,
                 >>()?
@@ -267,51 +360,124 @@ where
         let mut ctv_to_tx = HashMap::new();
         let mut suggested_txs = HashMap::new();
         let mut amount_range = AmountRange::new();
+        // the largest amount required by any mandatory branch, and where
+        let mut min_funding: Option<(Amount, Arc<EffectPath>)> = None;
 
         // If no guards and not CTV, then nothing gets added (not interpreted as Trivial True)
         // If CTV and no guards, just CTV added.
         // If CTV and guards, CTV & guards added.
-        let clause_accumulator = then_fns
+        let branch_templates = then_fns
             .into_iter()
             .chain(finish_or_fns.into_iter())
-            .map(|(nullability, uses_ctv, guards, r_txtmpls)| {
+            .map(|(nullability, uses_ctv, guards, path, w, r_txtmpls)| {
+                // the cheapest template which satisfies this branch
+                let mut branch_min: Option<Amount> = None;
+                // the CTV templates of this branch and their extra guards,
+                // whose emulator clauses are requested with every other
+                // branch's below
+                let mut ctv_guards: Vec<(Sha256, Vec<Clause>)> = vec![];
                 // it would be an error if any of r_txtmpls is an error instead of just an empty
                 // iterator.
-                let txtmpl_clauses = r_txtmpls?
-                    .map(|r_txtmpl| {
-                        let txtmpl = r_txtmpl?;
-                        let h = txtmpl.hash();
-                        amount_range.update_range(txtmpl.max);
-                        // Add the addition guards to these clauses
+                for r_txtmpl in r_txtmpls? {
+                    let txtmpl = r_txtmpl?;
+                    let h = txtmpl.hash();
+                    // an unsatisfiable guard is reported on its own below
+                    let timelocks_ok = structurally_unsatisfiable(&guards)
+                        || std::iter::once(&guards)
+                            .chain(txtmpl.guards.iter())
+                            .all(|g| timelocks_satisfiable(g, &txtmpl.tx, 0));
+                    if !timelocks_ok {
                         if uses_ctv == UseCTV::Yes {
-                            let txtmpl = ctv_to_tx.entry(h).or_insert(txtmpl);
-                            if txtmpl.guards.len() == 0 {
-                                ctx.ctv_emulator(h).map(Some)
-                            } else {
-                                let mut g = txtmpl.guards.clone();
-                                g.push(ctx.ctv_emulator(h)?);
-                                Ok(Some(Clause::And(g)))
-                            }
+                            return Err(CompilationError::TimelockNotSatisfiable {
+                                template: h,
+                                path: path.clone(),
+                            });
+                        }
+                        ctx.diagnostics().push(Diagnostic {
+                            path: SArc(path.clone()),
+                            kind: WarningKind::TimelockMismatch,
+                            message: format!(
+                                "suggested transaction {} cannot satisfy the guard's timelocks",
+                                h
+                            ),
+                        });
+                    }
+                    amount_range.update_range(txtmpl.max);
+                    // funds the template brings in itself needn't be funded
+                    let required = txtmpl.required_funding();
+                    branch_min = Some(branch_min.map_or(required, |m| m.min(required)));
+                    // Add the addition guards to these clauses
+                    if uses_ctv == UseCTV::Yes {
+                        let txtmpl = ctv_to_tx.entry(h).or_insert(txtmpl);
+                        ctv_guards.push((h, txtmpl.guards.clone()));
+                    } else {
+                        let txtmpl = suggested_txs.entry(h).or_insert(txtmpl);
+                        // Don't return or use the extra guards here because we're within a
+                        // non-CTV context... if we did, then it would destabilize compilation
+                        // with effect arguments.
+                        if txtmpl.guards.len() != 0 {
+                            // todo: In theory, the *default* effect could pass up something here.
+                            return Err(CompilationError::AdditionalGuardsNotAllowedHere);
+                        }
+                    }
+                }
+                if uses_ctv == UseCTV::No {
+                    let warning = if structurally_unsatisfiable(&guards) {
+                        Some((
+                            WarningKind::UnsatisfiableGuard,
+                            "guard can never be satisfied",
+                        ))
+                    } else if branch_min.is_none() {
+                        Some((
+                            WarningKind::UnusedFinishOr,
+                            "no transactions suggested by default",
+                        ))
+                    } else {
+                        None
+                    };
+                    if let Some((kind, message)) = warning {
+                        ctx.diagnostics().push(Diagnostic {
+                            path: SArc(path.clone()),
+                            kind,
+                            message: message.into(),
+                        });
+                    }
+                }
+                // Only CTV branches which can't be pruned must always be fundable
+                if uses_ctv == UseCTV::Yes && nullability == Nullable::No {
+                    if let Some(required) = branch_min {
+                        if min_funding.as_ref().map_or(true, |(m, _)| required > *m) {
+                            min_funding = Some((required, path.clone()));
+                        }
+                    }
+                }
+                Ok((nullability, uses_ctv, guards, path, w, ctv_guards))
+            })
+            .collect::<Result<Vec<_>, CompilationError>>()?;
+        // Every CTV template at this level is looked up with one request to
+        // the emulator, rather than one per branch, and the clauses are then
+        // handed back to their branches in order.
+        let hashes: Vec<Sha256> = branch_templates
+            .iter()
+            .flat_map(|(_, _, _, _, _, ctv_guards)| ctv_guards.iter().map(|(h, _)| *h))
+            .collect();
+        let mut emulated = ctx.ctv_emulators(&hashes)?.into_iter();
+        let mut clause_accumulator = branch_templates
+            .into_iter()
+            .map(|(nullability, uses_ctv, guards, path, w, ctv_guards)| {
+                let txtmpl_clauses: Vec<Clause> = ctv_guards
+                    .into_iter()
+                    .zip(emulated.by_ref())
+                    .map(|((_, mut g), c)| {
+                        if g.is_empty() {
+                            c
                         } else {
-                            let txtmpl = suggested_txs.entry(h).or_insert(txtmpl);
-                            // Don't return or use the extra guards here because we're within a
-                            // non-CTV context... if we did, then it would destabilize compilation
-                            // with effect arguments.
-                            if txtmpl.guards.len() != 0 {
-                                // todo: In theory, the *default* effect could pass up something here.
-                                Err(CompilationError::AdditionalGuardsNotAllowedHere)
-                            } else {
-                                // Don't add anything...
-                                Ok(None)
-                            }
+                            g.push(c);
+                            Clause::And(g)
                         }
                     })
-                    // Drop None values
-                    .filter_map(|s| s.transpose())
-                    // Forces any error to abort the whole thing
-                    .collect::<Result<Vec<Clause>, CompilationError>>()?;
-
-                match (uses_ctv, nullability, txtmpl_clauses.len(), guards) {
+                    .collect();
+                let clauses = match (uses_ctv, nullability, txtmpl_clauses.len(), guards) {
                     // Mark this branch dead.
                     // Nullable branch without anything
                     (UseCTV::Yes, Nullable::Yes, 0, _) => Ok(vec![]),
@@ -327,17 +493,81 @@ where
                     (UseCTV::Yes, Nullable::No, 0, _) => Err(CompilationError::MissingTemplates),
                     // If the guard is trivial, return the hashes standalone
                     (UseCTV::Yes, _, _, Clause::Trivial) => Ok(txtmpl_clauses),
-                    // If the guard is non-trivial, zip it to each hash
-                    // TODO: Arc in miniscript to dedup memory?
-                    //       This could be Clause::Shared(x) or something...
+                    // If the guard is non-trivial, zip it to each hash. Any
+                    // leaves this duplicates are merged by `merge_leaves`.
                     (_, _, _, guards) => Ok(txtmpl_clauses
                         .into_iter()
                         // extra_guards will contain any CTV
                         .map(|extra_guards| Clause::And(vec![guards.clone(), extra_guards]))
                         .collect()),
+                };
+                let clauses = clauses?;
+                for c in clauses.iter() {
+                    check_timelock_units(c, &path)?;
                 }
+                // a literally unsatisfiable guard is an error above, but one
+                // which is only unsatisfiable by its structure, or a template's
+                // extra guards, is only found here
+                if uses_ctv == UseCTV::Yes && clauses.iter().any(structurally_unsatisfiable) {
+                    ctx.diagnostics().push(Diagnostic {
+                        path: SArc(path.clone()),
+                        kind: WarningKind::UnsatisfiableGuard,
+                        message: "a transaction's guard can never be satisfied".into(),
+                    });
+                }
+                // every leaf of a branch is weighted as the branch
+                Ok(clauses.into_iter().map(|c| (w, c)).collect::<Vec<_>>())
             })
-            .collect::<Result<Vec<Vec<Clause>>, CompilationError>>()?;
+            .collect::<Result<Vec<Vec<(u64, Clause)>>, CompilationError>>()?;
+        if let Some((required, path)) = min_funding {
+            if ctx.funds() < required {
+                return Err(CompilationError::InsufficientFunding {
+                    required,
+                    available: ctx.funds(),
+                    path,
+                });
+            }
+        }
+        // Leaves committing to a template with an ANYPREVOUT signature or
+        // with Elements introspection are raw scripts rather than miniscript,
+        // so are kept apart.
+        let mut covenant_leaves: Vec<(u64, Script, Clause)> = vec![];
+        let elements = match ctx.covenant_backend() {
+            CovenantBackend::Elements => Some(ctx.elements().cloned().ok_or_else(|| {
+                CompilationError::TerminateWith(
+                    "The Elements backend must be set with Context::with_elements".into(),
+                )
+            })?),
+            _ => None,
+        };
+        if ctx.covenant_backend() == CovenantBackend::AnyPrevout || elements.is_some() {
+            let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+            for branch in clause_accumulator.iter_mut() {
+                let mut kept = vec![];
+                for (w, c) in branch.drain(..) {
+                    let (guard, h) = match covenant::split_template(&c) {
+                        Some(split) => split,
+                        None => {
+                            kept.push((w, c));
+                            continue;
+                        }
+                    };
+                    let tx = &ctv_to_tx
+                        .get(&h)
+                        .ok_or(CompilationError::MissingTemplates)?
+                        .tx;
+                    let script = match &elements {
+                        Some(params) => covenant::elements::covenant_script(tx, &guard, params)?,
+                        None => apo::covenant_script(tx, &guard, &secp)?,
+                    };
+                    match covenant_leaves.iter_mut().find(|(_, s, _)| *s == script) {
+                        Some(leaf) => leaf.0 = leaf.0.saturating_add(w),
+                        None => covenant_leaves.push((w, script, guard)),
+                    }
+                }
+                *branch = kept;
+            }
+        }
         let finish_fns: Vec<_> = {
             let mut finish_fns_ctx = ctx.derive(PathFragment::FinishFn)?;
             // Compute all finish_functions at this level, caching if requested.
@@ -348,34 +578,124 @@ where
                     (0..)
                         .filter_map(|i| finish_fns_ctx.derive(PathFragment::Branch(i as u64)).ok()),
                 )
-                .filter_map(|(func, c)| guard_clauses.borrow_mut().get(self_ref, *func, c))
-                .collect()
+                .filter_map(|(func, c)| {
+                    let weight = func().map_or(1, |g| g.weight());
+                    let path = c.path().clone();
+                    guard_clauses
+                        .borrow_mut()
+                        .get(self_ref, *func, c)
+                        .map(|clause| {
+                            check_timelock_units(&clause, &path)?;
+                            Ok((weight, clause))
+                        })
+                })
+                .collect::<Result<_, CompilationError>>()?
         };
-        let branches: Vec<Miniscript<XOnlyPublicKey, Tap>> = finish_fns
-            .iter()
-            .chain(clause_accumulator.iter().flatten())
-            .map(|policy| policy.compile().map_err(Into::<CompilationError>::into))
-            .collect::<Result<Vec<_>, _>>()?;
-        // TODO: Pick a better branch that is guaranteed to work!
-        let some_key = branches
-            .iter()
-            .filter_map(|f| {
-                if let Terminal::PkK(k) = f.node {
-                    Some(k)
-                } else {
-                    None
-                }
-            })
-            .next()
-            .map(|x| bitcoin::util::schnorr::UntweakedPublicKey::from(x))
-            .unwrap_or(
-                XOnlyPublicKey::from_slice(&Sha256::hash(&[1u8; 32]).into_inner())
-                    .expect("constant"),
-            );
+        let key_policy = ctx.internal_key_policy().clone();
+        // If every finish guard is keys, the keys may cooperate via a MuSig2
+        // internal key, which makes a leaf requiring all of them redundant.
+        #[cfg(feature = "musig")]
+        let key_aggregation = if key_policy != InternalKeyPolicy::FirstPartyKey {
+            None
+        } else {
+            use crate::contract::object::ObjectError;
+            let clauses: Vec<&Clause> = finish_fns.iter().map(|(_, c)| c).collect();
+            KeyAggregation::keys_for(&clauses)
+                .map(|keys| {
+                    KeyAggregation::new(&bitcoin::secp256k1::Secp256k1::verification_only(), &keys)
+                        .map_err(|e| ObjectError::Custom(Box::new(e)))
+                })
+                .transpose()
+                .map_err(CompilationError::from)?
+        };
+        #[cfg(not(feature = "musig"))]
+        let key_aggregation: Option<KeyAggregation> = None;
+        let mut branches = merge_leaves(
+            finish_fns
+                .iter()
+                .filter(|(_, c)| !key_aggregation.as_ref().map_or(false, |a| a.covers(c)))
+                .chain(clause_accumulator.iter().flatten()),
+        )?;
+        for (_, ms) in branches.iter() {
+            if ms.script_size() > MAX_SCRIPT_SIZE_WARNING {
+                ctx.warn(
+                    WarningKind::OversizedScript,
+                    format!(
+                        "leaf script is {} bytes, larger than many tools support",
+                        ms.script_size()
+                    ),
+                );
+            }
+        }
+        let internal_key = match key_policy {
+            InternalKeyPolicy::Explicit { key } => InternalKeySource::Explicit { key },
+            InternalKeyPolicy::Nums { randomizer } => InternalKeySource::Nums {
+                key: nums_key(
+                    &bitcoin::secp256k1::Secp256k1::verification_only(),
+                    &randomizer,
+                )
+                .map_err(|e| crate::contract::object::ObjectError::Custom(Box::new(e)))
+                .map_err(CompilationError::from)?,
+                randomizer,
+            },
+            // TODO: Pick a better branch that is guaranteed to work!
+            InternalKeyPolicy::FirstPartyKey => key_aggregation
+                .as_ref()
+                .map(|a| InternalKeySource::Aggregate { key: a.aggregate })
+                .or_else(|| {
+                    branches
+                        .iter()
+                        .filter_map(|(_, f)| match &f.node {
+                            Terminal::PkK(k) => Some(*k),
+                            // a leaf which is just a key compiles to c:pk_k,
+                            // but earlier compilers only looked for pk_k, so
+                            // their addresses are kept with the key path leaves
+                            Terminal::Check(inner) if !ctx.keeps_key_path_leaves() => {
+                                match inner.node {
+                                    Terminal::PkK(k) => Some(k),
+                                    _ => None,
+                                }
+                            }
+                            _ => None,
+                        })
+                        .next()
+                        .map(|key| InternalKeySource::LeafKey { key })
+                })
+                .unwrap_or_else(|| InternalKeySource::HashedConstant {
+                    key: hashed_constant_key(),
+                    preimage: Sha256::from_inner(HASHED_CONSTANT_PREIMAGE),
+                }),
+        };
+        let some_key = internal_key.key();
+        // A leaf which only checks the internal key can never be cheaper than
+        // the key path, so it just deepens the tree and reveals structure.
+        if !ctx.keeps_key_path_leaves() {
+            let key_leaf = Clause::Key(some_key)
+                .compile()
+                .map_err(Into::<CompilationError>::into)?;
+            branches.retain(|(_, ms)| *ms != key_leaf);
+        }
+        // Requires a signature by H + salt*G, whose discrete log nobody knows
+        // as nobody knows H's, so this leaf can never be used. It only makes
+        // the address unique to this path.
+        if ctx.salts_addresses() {
+            let salt = Sha256::hash(format!("sapio/address_salt/{}", ctx.path()).as_bytes());
+            let salt_key = nums_key(&bitcoin::secp256k1::Secp256k1::verification_only(), &salt)
+                .map_err(|e| crate::contract::object::ObjectError::Custom(Box::new(e)))
+                .map_err(CompilationError::from)?;
+            branches.push((
+                1,
+                Clause::Key(salt_key)
+                    .compile()
+                    .map_err(Into::<CompilationError>::into)?,
+            ));
+        }
         // Don't remove the key from the scripts in case it was bogus
+        // Huffman encode the tree by weight, so likelier branches are
+        // shallower and have smaller control blocks.
         let mut scripts: BinaryHeap<(Reverse<u64>, TapTree<XOnlyPublicKey>)> = branches
             .iter()
-            .map(|b| (Reverse(1), TapTree::Leaf(Arc::new(b.clone()))))
+            .map(|(w, b)| (Reverse((*w).max(1)), TapTree::Leaf(Arc::new(b.clone()))))
             .collect();
         while scripts.len() > 1 {
             let (w1, v1) = scripts.pop().unwrap();
@@ -388,9 +708,80 @@ where
 
         let tree = scripts.pop().map(|v| v.1);
         let descriptor = Descriptor::Tr(descriptor::Tr::new(some_key, tree)?);
-        let estimated_max_size = descriptor.max_satisfaction_weight()?;
-        let address = descriptor.address(ctx.network)?.into();
-        let descriptor = Some(descriptor.into());
+        let mut estimated_max_size = descriptor.max_satisfaction_weight()?;
+        let mut address = descriptor.address(ctx.network)?.into();
+        let descriptor: SupportedDescriptors = descriptor.into();
+        let key_origins = ctx.key_origins().for_descriptor(&descriptor);
+        let mut descriptor = Some(descriptor);
+        let mut raw_taproot = None;
+        if elements.is_some() {
+            let raw = RawTaproot {
+                internal_key: some_key,
+                leaves: branches
+                    .iter()
+                    .map(|(w, ms)| (*w, ms.encode()))
+                    .chain(covenant_leaves.iter().map(|(w, s, _)| (*w, s.clone())))
+                    .map(|(w, s)| (w.max(1).min(u32::MAX as u64) as u32, s))
+                    .collect(),
+                elements: true,
+            };
+            let tree = covenant::elements::ElementsTaproot::new(
+                &bitcoin::secp256k1::Secp256k1::verification_only(),
+                some_key,
+                &raw.leaves,
+            )?;
+            let n_miniscript = branches.len();
+            for (i, (_, script, guard)) in covenant_leaves.iter().enumerate() {
+                let guard_witness = match guard {
+                    Clause::Trivial => 0,
+                    g => g
+                        .compile()
+                        .map_err(Into::<CompilationError>::into)?
+                        .max_satisfaction_size()?,
+                };
+                let cb = tree
+                    .control_block(n_miniscript + i)
+                    .expect("Must be present");
+                estimated_max_size =
+                    estimated_max_size.max(guard_witness + script.len() + cb.len() + 2 * 3 + 1);
+            }
+            address = ExtendedAddress::Unknown(tree.script_pubkey());
+            descriptor = None;
+            raw_taproot = Some(raw);
+        } else if !covenant_leaves.is_empty() {
+            let raw = RawTaproot {
+                internal_key: some_key,
+                leaves: branches
+                    .iter()
+                    .map(|(w, ms)| (*w, ms.encode()))
+                    .chain(covenant_leaves.iter().map(|(w, s, _)| (*w, s.clone())))
+                    .map(|(w, s)| (w.max(1).min(u32::MAX as u64) as u32, s))
+                    .collect(),
+                elements: false,
+            };
+            let info = raw
+                .spend_info(&bitcoin::secp256k1::Secp256k1::verification_only())
+                .map_err(CompilationError::from)?;
+            for (_, script, guard) in covenant_leaves.iter() {
+                let guard_witness = match guard {
+                    Clause::Trivial => 0,
+                    g => g
+                        .compile()
+                        .map_err(Into::<CompilationError>::into)?
+                        .max_satisfaction_size()?,
+                };
+                let cb = info
+                    .control_block(&(script.clone(), LeafVersion::TapScript))
+                    .expect("Must be present");
+                // the guard's witness, the script, and the control block
+                // (each with a length prefix)
+                estimated_max_size =
+                    estimated_max_size.max(guard_witness + script.len() + cb.size() + 2 * 3 + 1);
+            }
+            address = bitcoin::Address::p2tr_tweaked(info.output_key(), ctx.network).into();
+            descriptor = None;
+            raw_taproot = Some(raw);
+        }
         let root_path = SArc(ctx.path().clone());
 
         let failed_estimate = ctv_to_tx.values().any(|a| {
@@ -412,7 +803,463 @@ where
                 address,
                 descriptor,
                 amount_range,
+                key_aggregation,
+                internal_key: Some(internal_key),
+                key_origins,
+                raw_taproot,
+                abi_version: self.abi_version(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{
+        CallableAsFoF, ConditionallyCompileIf, FinishOrFunc, Guard, ThenFunc, WebAPIDisabled,
+    };
+    use crate::contract::diagnostics::CompilationDiagnostics;
+    use crate::contract::Contract;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use sapio_base::effects::{EffectConditions, EffectEnvironment, MapEffectDB};
+    use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError};
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    const A: &str = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+    const B: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn key(k: &str) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(k).unwrap()
+    }
+
+    fn ctx_with(amount: Amount, emulator: Arc<dyn CTVEmulator>) -> Context {
+        Context::new(
+            bitcoin::Network::Regtest,
+            amount,
+            emulator,
+            EffectPath::try_from("root").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+
+    fn ctx(amount: Amount) -> Context {
+        ctx_with(amount, Arc::new(CTVAvailable))
+    }
+
+    /// `a` may spend at once, or `b` after a delay
+    struct KeyOrLater {
+        a: XOnlyPublicKey,
+        b: XOnlyPublicKey,
+    }
+    impl KeyOrLater {
+        fn guard_now(&self, _ctx: Context) -> Clause {
+            Clause::Key(self.a)
+        }
+        fn now() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(Self::guard_now, 1))
+        }
+        fn guard_later(&self, _ctx: Context) -> Clause {
+            Clause::And(vec![Clause::Key(self.b), Clause::After(10)])
+        }
+        fn later() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(Self::guard_later, 1))
+        }
+    }
+    impl Contract for KeyOrLater {
+        declare! {finish, Self::now, Self::later}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn key_path_leaves_keep_addresses() -> Result<(), CompilationError> {
+        let contract = KeyOrLater {
+            a: key(A),
+            b: key(B),
+        };
+        let later: Miniscript<XOnlyPublicKey, Tap> =
+            contract.guard_later(ctx(Amount::from_sat(0))).compile()?;
+        let now: Miniscript<XOnlyPublicKey, Tap> = Clause::Key(key(A)).compile()?;
+        let address = |internal, tree| {
+            Descriptor::Tr(descriptor::Tr::new(internal, Some(tree)).unwrap())
+                .address(bitcoin::Network::Regtest)
+                .unwrap()
+                .script_pubkey()
+        };
+
+        // the leaf checking `a` is elided, as `a` is the internal key
+        let elided = contract.compile(ctx(Amount::from_sat(10_000)))?;
+        assert_eq!(
+            elided.internal_key,
+            Some(InternalKeySource::LeafKey { key: key(A) })
+        );
+        assert_eq!(
+            Script::from(elided.address),
+            address(key(A), TapTree::Leaf(Arc::new(later.clone())))
+        );
+
+        // the old addresses: no leaf key is found, and both leaves are kept
+        let kept = contract.compile(ctx(Amount::from_sat(10_000)).with_key_path_leaves())?;
+        assert_eq!(
+            kept.internal_key,
+            Some(InternalKeySource::HashedConstant {
+                key: hashed_constant_key(),
+                preimage: Sha256::from_inner(HASHED_CONSTANT_PREIMAGE),
+            })
+        );
+        // leaves of equal weight may be in either order
+        let leaf =
+            |ms: &Miniscript<XOnlyPublicKey, Tap>| Arc::new(TapTree::Leaf(Arc::new(ms.clone())));
+        let either_order = [
+            address(
+                hashed_constant_key(),
+                TapTree::Tree(leaf(&now), leaf(&later)),
+            ),
+            address(
+                hashed_constant_key(),
+                TapTree::Tree(leaf(&later), leaf(&now)),
+            ),
+        ];
+        assert!(either_order.contains(&Script::from(kept.address)));
+        Ok(())
+    }
+    /// Counts the requests made of it
+    #[derive(Default)]
+    struct CountingEmulator(AtomicUsize);
+    impl CTVEmulator for CountingEmulator {
+        fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Clause::TxTemplate(h))
+        }
+        fn sign_batch(&self, hs: &[Sha256]) -> Result<Vec<Clause>, EmulatorError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(hs.iter().cloned().map(Clause::TxTemplate).collect())
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+
+    /// Pays `to` one of two amounts, each by its own branch
+    struct TwoPayouts {
+        to: XOnlyPublicKey,
+    }
+    impl TwoPayouts {
+        fn pay(&self, ctx: Context, sats: u64) -> TxTmplIt {
+            ctx.template()
+                .add_output(Amount::from_sat(sats), &self.to, None)?
+                .into()
+        }
+        fn then_small(&self, ctx: Context) -> TxTmplIt {
+            self.pay(ctx, 1_000)
+        }
+        fn small<'a>() -> Option<ThenFunc<'a, Self>> {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[],
+                func: Self::then_small,
+                name: Arc::new("small".into()),
+                weight: 1,
+            })
+        }
+        fn then_large(&self, ctx: Context) -> TxTmplIt {
+            self.pay(ctx, 2_000)
+        }
+        fn large<'a>() -> Option<ThenFunc<'a, Self>> {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[],
+                func: Self::then_large,
+                name: Arc::new("large".into()),
+                weight: 1,
+            })
+        }
+    }
+    impl Contract for TwoPayouts {
+        declare! {then, Self::small, Self::large}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn one_emulator_request_per_level() -> Result<(), CompilationError> {
+        let emulator = Arc::new(CountingEmulator::default());
+        let compiled = TwoPayouts { to: key(A) }
+            .compile(ctx_with(Amount::from_sat(10_000), emulator.clone()))?;
+        assert_eq!(compiled.ctv_to_tx.len(), 2);
+        assert_eq!(emulator.0.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    /// Pays `to`, unless not `open`, when only `to` may spend
+    struct Gated {
+        to: XOnlyPublicKey,
+        open: bool,
+    }
+    impl Gated {
+        fn compile_if_open(&self, _ctx: Context) -> ConditionalCompileType {
+            if self.open {
+                ConditionalCompileType::NoConstraint
+            } else {
+                ConditionalCompileType::Never
+            }
+        }
+        fn open() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(Self::compile_if_open))
+        }
+        fn then_pay(&self, ctx: Context) -> TxTmplIt {
+            ctx.template()
+                .add_output(Amount::from_sat(500), &self.to, None)?
+                .into()
+        }
+        fn pay<'a>() -> Option<ThenFunc<'a, Self>> {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[Self::open],
+                func: Self::then_pay,
+                name: Arc::new("pay".into()),
+                weight: 1,
+            })
+        }
+        fn guard_signed(&self, _ctx: Context) -> Clause {
+            Clause::Key(self.to)
+        }
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(Self::guard_signed, 1))
+        }
+    }
+    impl Contract for Gated {
+        declare! {then, Self::pay}
+        declare! {finish, Self::signed}
+        declare! {non updatable}
+    }
+
+    /// Creates two `Gated`, the first `open` if `first_open`
+    struct GatedPair {
+        to: XOnlyPublicKey,
+        first_open: bool,
+    }
+    impl GatedPair {
+        fn then_split(&self, ctx: Context) -> TxTmplIt {
+            let gated = |open| Gated { to: self.to, open };
+            ctx.template()
+                .add_output(Amount::from_sat(1_000), &gated(self.first_open), None)?
+                .add_output(Amount::from_sat(1_000), &gated(false), None)?
+                .into()
+        }
+        fn split<'a>() -> Option<ThenFunc<'a, Self>> {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[],
+                func: Self::then_split,
+                name: Arc::new("split".into()),
+                weight: 1,
+            })
+        }
+    }
+    impl Contract for GatedPair {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn unreachable_branches() -> Result<(), CompilationError> {
+        let unreachable = |d: &CompilationDiagnostics| {
+            d.get()
+                .into_iter()
+                .filter(|d| d.kind == WarningKind::UnreachableBranch)
+                .count()
+        };
+        // Never in every instance, reported by a plain compile too
+        let c = ctx(Amount::from_sat(10_000));
+        let d = c.diagnostics().clone();
+        GatedPair {
+            to: key(A),
+            first_open: false,
+        }
+        .compile(c)?;
+        assert_eq!(unreachable(&d), 1);
+        // reachable in one instance
+        let c = ctx(Amount::from_sat(10_000));
+        let d = c.diagnostics().clone();
+        GatedPair {
+            to: key(A),
+            first_open: true,
+        }
+        .compile(c)?;
+        assert_eq!(unreachable(&d), 0);
+        // a single instance may be Never on purpose
+        let (_, diagnostics) = ctx(Amount::from_sat(10_000)).compile_with_diagnostics(Gated {
+            to: key(A),
+            open: false,
+        })?;
+        assert!(diagnostics
+            .iter()
+            .all(|d| d.kind != WarningKind::UnreachableBranch));
+        Ok(())
+    }
+
+    #[test]
+    fn salted_addresses_differ_by_path() -> Result<(), CompilationError> {
+        let contract = KeyOrLater {
+            a: key(A),
+            b: key(B),
+        };
+        let at = |path: &str| {
+            Context::new(
+                bitcoin::Network::Regtest,
+                Amount::from_sat(10_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from(path).unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let address = |c: Context| -> Result<Script, CompilationError> {
+            Ok(Script::from(contract.compile(c)?.address))
+        };
+        let unsalted = address(at("root"))?;
+        assert_eq!(unsalted, address(at("other"))?);
+        let salted = address(at("root").with_address_salting())?;
+        assert_ne!(salted, unsalted);
+        assert_ne!(salted, address(at("other").with_address_salting())?);
+        assert_eq!(salted, address(at("root").with_address_salting())?);
+        Ok(())
+    }
+
+    /// Pays `to` more than it holds, making up the difference with funds
+    /// brought in by the template, or (if not `tops_up`) with funds it only
+    /// claims to have
+    struct Overspends {
+        to: XOnlyPublicKey,
+        tops_up: bool,
+    }
+    impl Overspends {
+        fn then_pay(&self, ctx: Context) -> TxTmplIt {
+            let extra = Amount::from_sat(5_000);
+            let builder = if self.tops_up {
+                ctx.template().add_amount(extra)
+            } else {
+                ctx.add_amount(extra).template()
+            };
+            builder
+                .add_output(Amount::from_sat(6_000), &self.to, None)?
+                .into()
+        }
+        fn pay<'a>() -> Option<ThenFunc<'a, Self>> {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[],
+                func: Self::then_pay,
+                name: Arc::new("pay".into()),
+                weight: 1,
             })
         }
     }
+    impl Contract for Overspends {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn insufficient_funding() -> Result<(), CompilationError> {
+        // the template brings in what the contract lacks
+        let compiled = Overspends {
+            to: key(A),
+            tops_up: true,
+        }
+        .compile(ctx(Amount::from_sat(1_000)))?;
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        // nothing brings it in
+        match (Overspends {
+            to: key(A),
+            tops_up: false,
+        })
+        .compile(ctx(Amount::from_sat(1_000)))
+        {
+            Err(CompilationError::InsufficientFunding {
+                required,
+                available,
+                path,
+            }) => {
+                assert_eq!(required, Amount::from_sat(6_000));
+                assert_eq!(available, Amount::from_sat(1_000));
+                assert!(path.to_string().ends_with("pay"), "{}", path);
+            }
+            r => panic!("expected InsufficientFunding, got {:?}", r.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    /// signed for by `to`, and may be updated with effects, suggesting
+    /// nothing
+    struct Updatable {
+        to: XOnlyPublicKey,
+    }
+    impl Updatable {
+        fn guard_signed(&self, _ctx: Context) -> Clause {
+            Clause::Key(self.to)
+        }
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(Self::guard_signed, 1))
+        }
+        fn continue_update(&self, _ctx: Context, _o: ()) -> TxTmplIt {
+            Ok(Box::new(std::iter::empty()))
+        }
+        fn update<'a>() -> Option<Box<dyn CallableAsFoF<Self, ()>>> {
+            let f: FinishOrFunc<_, _, _, WebAPIDisabled> = FinishOrFunc {
+                coerce_args: Ok,
+                guard: &[Self::signed],
+                conditional_compile_if: &[],
+                func: Self::continue_update,
+                schema: None,
+                name: Arc::new("update".into()),
+                weight: 1,
+                sighash: None,
+                f: Default::default(),
+            };
+            Some(Box::new(f))
+        }
+    }
+    impl Contract for Updatable {
+        declare! {updatable<()>, Self::update}
+    }
+
+    #[test]
+    fn expired_effects_are_skipped() -> Result<(), CompilationError> {
+        let contract = Updatable { to: key(A) };
+        let compiled = contract.compile(ctx(Amount::from_sat(10_000)))?;
+        let at = compiled.continue_apis.keys().next().unwrap().clone();
+        let name = SArc(Arc::new(String::from("bump")));
+        let mut effects = MapEffectDB::default();
+        effects.insert(at.clone(), name.clone(), serde_json::json!(null));
+        effects.set_conditions(
+            at,
+            name,
+            EffectConditions {
+                valid_until_height: Some(100),
+                ..Default::default()
+            },
+        );
+        effects.set_environment(EffectEnvironment {
+            height: Some(100),
+            ..Default::default()
+        });
+        let c = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(10_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("root").unwrap(),
+            Arc::new(effects),
+        );
+        let d = c.diagnostics().clone();
+        contract.compile(c)?;
+        assert!(d
+            .get()
+            .into_iter()
+            .any(|d| d.kind == WarningKind::InapplicableEffect));
+        Ok(())
+    }
 }
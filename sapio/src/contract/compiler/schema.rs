@@ -0,0 +1,84 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that the arguments of an effect match the schema of the
+//! continuation they are applied to, before they are deserialized.
+use sapio_base::effects::{EffectDBError, EffectPath};
+use sapio_base::serialization_helpers::SArc;
+use schemars::schema::RootSchema;
+use std::sync::Arc;
+
+/// Every way `arg` fails to match `schema`, or an empty list if it does.
+fn violations(schema: &RootSchema, arg: &serde_json::Value) -> Vec<String> {
+    let schema = match serde_json::to_value(schema) {
+        Ok(s) => s,
+        Err(e) => return vec![e.to_string()],
+    };
+    let validator = match jsonschema_valid::Config::from_schema(
+        &schema,
+        Some(jsonschema_valid::schemas::Draft::Draft6),
+    ) {
+        Ok(v) => v,
+        Err(e) => return vec![format!("Invalid schema: {}", e)],
+    };
+    match validator.validate(arg) {
+        Ok(()) => vec![],
+        Err(it) => it.map(|e| e.to_string()).collect(),
+    }
+}
+
+/// Check the effect `name` at `at` against the continuation's `schema`, if
+/// it has one.
+pub(crate) fn check_effect_arg(
+    schema: Option<&RootSchema>,
+    at: &Arc<EffectPath>,
+    name: &Arc<String>,
+    arg: &serde_json::Value,
+) -> Result<(), EffectDBError> {
+    let errors = match schema {
+        Some(schema) => violations(schema, arg),
+        None => return Ok(()),
+    };
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(EffectDBError::SchemaViolation(
+            SArc(at.clone()),
+            SArc(name.clone()),
+            errors,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use schemars::JsonSchema;
+    use serde_derive::Deserialize;
+    use std::convert::TryFrom;
+    #[derive(JsonSchema, Deserialize)]
+    struct Args {
+        #[allow(dead_code)]
+        amount: u64,
+    }
+    #[test]
+    fn names_the_effect_and_violation() {
+        let schema = schemars::schema_for!(Args);
+        let schema = Some(&schema);
+        let at = Arc::new(EffectPath::try_from("hello/@finish_fn").unwrap());
+        let name = Arc::new(String::from("update"));
+        assert!(check_effect_arg(schema, &at, &name, &serde_json::json!({"amount": 1})).is_ok());
+        match check_effect_arg(schema, &at, &name, &serde_json::json!({"amount": "1"})) {
+            Err(EffectDBError::SchemaViolation(p, n, errors)) => {
+                assert_eq!(p.0, at);
+                assert_eq!(n.0, name);
+                assert!(!errors.is_empty());
+            }
+            _ => panic!("must be a schema violation"),
+        }
+        assert!(check_effect_arg(None, &at, &name, &serde_json::json!(1)).is_ok());
+    }
+}
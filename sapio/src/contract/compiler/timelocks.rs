@@ -0,0 +1,125 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that a template's nSequence and nLockTime can satisfy the timelocks
+//! in the clause guarding it, and that a clause's timelocks can be satisfied
+//! together at all.
+use super::CompilationError;
+use bitcoin::Transaction;
+use sapio_base::effects::EffectPath;
+use sapio_base::timelocks::LockTimeError;
+use sapio_base::Clause;
+use std::sync::Arc;
+
+const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_MASK: u32 = 0xFFFF;
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+fn older_ok(n: u32, tx: &Transaction, input: usize) -> bool {
+    let seq = match tx.input.get(input) {
+        Some(i) => i.sequence,
+        None => return false,
+    };
+    tx.version >= 2
+        && seq & SEQUENCE_DISABLE_FLAG == 0
+        && seq & SEQUENCE_TYPE_FLAG == n & SEQUENCE_TYPE_FLAG
+        && seq & SEQUENCE_MASK >= n & SEQUENCE_MASK
+}
+
+fn after_ok(n: u32, tx: &Transaction, input: usize) -> bool {
+    let final_input = tx
+        .input
+        .get(input)
+        .map_or(true, |i| i.sequence == 0xFFFF_FFFF);
+    !final_input
+        && (tx.lock_time < LOCKTIME_THRESHOLD) == (n < LOCKTIME_THRESHOLD)
+        && tx.lock_time >= n
+}
+
+/// true if `clause` can be satisfied when spent by `input` of `tx`, as far
+/// as its timelocks are concerned. Everything other than a timelock is
+/// assumed satisfiable.
+pub(crate) fn timelocks_satisfiable(clause: &Clause, tx: &Transaction, input: usize) -> bool {
+    match clause {
+        Clause::Older(n) => older_ok(*n, tx, input),
+        Clause::After(n) => after_ok(*n, tx, input),
+        Clause::Unsatisfiable => false,
+        Clause::And(v) => v.iter().all(|c| timelocks_satisfiable(c, tx, input)),
+        Clause::Or(v) => v.iter().any(|(_, c)| timelocks_satisfiable(c, tx, input)),
+        Clause::Threshold(k, v) => {
+            v.iter()
+                .filter(|c| timelocks_satisfiable(c, tx, input))
+                .count()
+                >= *k
+        }
+        _ => true,
+    }
+}
+
+/// Reject `clause`, guarding the branch at `path`, if it mixes height and
+/// time based locks of one kind anywhere (see
+/// [`sapio_base::timelocks::check_timelock_units`]).
+pub(crate) fn check_timelock_units(
+    clause: &Clause,
+    path: &Arc<EffectPath>,
+) -> Result<(), CompilationError> {
+    sapio_base::timelocks::check_timelock_units(clause).map_err(|e| match e {
+        LockTimeError::MixedUnits(a, b) => CompilationError::MixedTimelockUnits {
+            locks: (a, b),
+            path: path.clone(),
+        },
+        e => e.into(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{TxIn, Witness};
+    fn tx(sequence: u32, lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: Default::default(),
+                script_sig: Default::default(),
+                sequence,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        }
+    }
+    #[test]
+    fn relative_and_absolute() {
+        let older = Clause::Older(10);
+        assert!(timelocks_satisfiable(&older, &tx(10, 0), 0));
+        assert!(!timelocks_satisfiable(&older, &tx(9, 0), 0));
+        assert!(!timelocks_satisfiable(
+            &older,
+            &tx(SEQUENCE_TYPE_FLAG | 10, 0),
+            0
+        ));
+        let after = Clause::After(100);
+        assert!(timelocks_satisfiable(&after, &tx(0, 100), 0));
+        assert!(!timelocks_satisfiable(&after, &tx(0xFFFF_FFFF, 100), 0));
+        let either = Clause::Or(vec![(1, older), (1, after)]);
+        assert!(timelocks_satisfiable(&either, &tx(0, 100), 0));
+        assert!(!timelocks_satisfiable(&either, &tx(0, 0), 0));
+    }
+    #[test]
+    fn mixed_units_name_the_branch() {
+        use std::convert::TryFrom;
+        let path = Arc::new(EffectPath::try_from("@root/guard/#0").unwrap());
+        let mixed = Clause::And(vec![Clause::After(100), Clause::After(1_600_000_000)]);
+        match check_timelock_units(&mixed, &path) {
+            Err(CompilationError::MixedTimelockUnits { path: p, .. }) => assert_eq!(p, path),
+            r => panic!("{:?}", r),
+        }
+        let fine = Clause::And(vec![Clause::After(100), Clause::Older(10)]);
+        assert!(check_timelock_units(&fine, &path).is_ok());
+    }
+}
@@ -6,9 +6,15 @@
 
 //! general non-parameter compilation state required by all contracts
 use super::{Amount, Compilable, CompilationError, Compiled};
+use crate::contract::compilation_cache::{CacheKey, CompilationCache};
 use crate::contract::compiler::InternalCompilerTag;
+use crate::contract::covenant::elements::ElementsParams;
+use crate::contract::covenant::CovenantBackend;
+use crate::contract::diagnostics::{CompilationDiagnostics, Diagnostic, WarningKind};
 use crate::contract::object::SupportedDescriptors;
 use crate::util::amountrange::AmountRange;
+use crate::util::internal_key::InternalKeyPolicy;
+use crate::util::key_origin::{KeyOrigins, SapioKey};
 use bitcoin::Network;
 use miniscript::Descriptor;
 use miniscript::DescriptorTrait;
@@ -16,9 +22,13 @@ use miniscript::MiniscriptKey;
 use miniscript::ToPublicKey;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
+use sapio_base::effects::PathInterner;
 pub use sapio_base::effects::{EffectDB, MapEffectDB};
+use sapio_base::fees::FeeEstimator;
 use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
 use sapio_ctv_emulator_trait::CTVEmulator;
+use serde::Serialize;
 use std::convert::TryInto;
 
 use std::collections::HashMap;
@@ -30,6 +40,7 @@ use std::sync::Arc;
 pub struct Context {
     /* TODO: Add Context Fields! */
     available_funds: Amount,
+    fee_reserve: Amount,
     emulator: Arc<dyn CTVEmulator>,
     /// which network is the contract building for?
     pub network: Network,
@@ -37,6 +48,17 @@ pub struct Context {
     path: Arc<EffectPath>,
     already_derived: HashSet<PathFragment>,
     effects: Arc<MapEffectDB>,
+    salt_addresses: bool,
+    diagnostics: CompilationDiagnostics,
+    cache: Option<Arc<dyn CompilationCache>>,
+    internal_key_policy: InternalKeyPolicy,
+    keep_key_path_leaves: bool,
+    fee_estimator: Option<Arc<dyn FeeEstimator>>,
+    key_origins: KeyOrigins,
+    covenant_backend: CovenantBackend,
+    elements: Option<Arc<ElementsParams>>,
+    paths: Arc<PathInterner<PathFragment>>,
+    strict_determinism: bool,
 }
 
 impl Context {
@@ -49,14 +71,27 @@ impl Context {
         path: EffectPath,
         effects: Arc<MapEffectDB>,
     ) -> Self {
+        let paths: Arc<PathInterner<PathFragment>> = Default::default();
         Context {
             available_funds,
+            fee_reserve: Amount::from_sat(0),
             emulator,
             network,
             // TODO: Should return Option Self if path is not length > 0
-            path: Arc::new(path),
+            path: paths.intern(&path),
             already_derived: Default::default(),
             effects,
+            salt_addresses: false,
+            diagnostics: Default::default(),
+            cache: None,
+            internal_key_policy: Default::default(),
+            keep_key_path_leaves: false,
+            fee_estimator: None,
+            key_origins: Default::default(),
+            covenant_backend: Default::default(),
+            elements: None,
+            paths,
+            strict_determinism: false,
         }
     }
     /// Get this Context's effect database, for clients
@@ -91,14 +126,26 @@ impl Context {
             Err(CompilationError::ContexPathAlreadyDerived)
         } else {
             self.already_derived.insert(path.clone());
-            let new_path = EffectPath::push(Some(self.path.clone()), path);
+            let new_path = self.paths.push(Some(self.path.clone()), path);
             Ok(Context {
                 available_funds: self.available_funds,
+                fee_reserve: self.fee_reserve,
                 emulator: self.emulator.clone(),
                 path: new_path,
                 network: self.network,
                 already_derived: Default::default(),
                 effects: self.effects.clone(),
+                salt_addresses: self.salt_addresses,
+                diagnostics: self.diagnostics.clone(),
+                cache: self.cache.clone(),
+                internal_key_policy: self.internal_key_policy.clone(),
+                keep_key_path_leaves: self.keep_key_path_leaves,
+                fee_estimator: self.fee_estimator.clone(),
+                key_origins: self.key_origins.clone(),
+                covenant_backend: self.covenant_backend,
+                elements: self.elements.clone(),
+                paths: self.paths.clone(),
+                strict_determinism: self.strict_determinism,
             })
         }
     }
@@ -107,25 +154,246 @@ impl Context {
     pub(crate) fn internal_clone(&self, _i: InternalCompilerTag) -> Self {
         Context {
             available_funds: self.available_funds,
+            fee_reserve: self.fee_reserve,
             emulator: self.emulator.clone(),
             path: self.path.clone(),
             network: self.network,
             already_derived: self.already_derived.clone(),
             effects: self.effects.clone(),
+            salt_addresses: self.salt_addresses,
+            diagnostics: self.diagnostics.clone(),
+            cache: self.cache.clone(),
+            internal_key_policy: self.internal_key_policy.clone(),
+            keep_key_path_leaves: self.keep_key_path_leaves,
+            fee_estimator: self.fee_estimator.clone(),
+            key_origins: self.key_origins.clone(),
+            covenant_backend: self.covenant_backend,
+            elements: self.elements.clone(),
+            paths: self.paths.clone(),
+            strict_determinism: self.strict_determinism,
         }
     }
 
+    /// Salt every contract compiled from this context (and its children) with
+    /// its path, so that identical contracts at different paths get distinct
+    /// addresses. The salt is an extra leaf requiring a signature by a
+    /// path-derived NUMS key, which nobody can make, so it never adds a way to
+    /// spend. See [`Object::address_collisions`](crate::contract::object::Object::address_collisions).
+    pub fn with_address_salting(mut self) -> Self {
+        self.salt_addresses = true;
+        self
+    }
+
+    /// true if contracts compiled from this context are salted
+    pub fn salts_addresses(&self) -> bool {
+        self.salt_addresses
+    }
+
+    /// Choose the taproot internal key of contracts compiled from this
+    /// context (and its children) by `policy`
+    pub fn with_internal_key_policy(mut self, policy: InternalKeyPolicy) -> Self {
+        self.internal_key_policy = policy;
+        self
+    }
+
+    /// how contracts compiled from this context pick their internal key
+    pub fn internal_key_policy(&self) -> &InternalKeyPolicy {
+        &self.internal_key_policy
+    }
+
+    /// Keep leaves which only check the internal key, rather than eliding them
+    /// as redundant with the key path, and take the internal key only from
+    /// bare `pk_k` leaves. Reproduces addresses from compilers which did
+    /// neither.
+    pub fn with_key_path_leaves(mut self) -> Self {
+        self.keep_key_path_leaves = true;
+        self
+    }
+
+    /// true if leaves redundant with the key path are kept
+    pub fn keeps_key_path_leaves(&self) -> bool {
+        self.keep_key_path_leaves
+    }
+
+    /// Use `estimator` to budget fees (see
+    /// [`Builder::add_fee_for_rate`](crate::template::Builder::add_fee_for_rate))
+    /// in this context and every context derived from it
+    pub fn with_fee_estimator(mut self, estimator: Arc<dyn FeeEstimator>) -> Self {
+        self.fee_estimator = Some(estimator);
+        self
+    }
+
+    /// the fee estimator, if one is installed
+    pub fn fee_estimator(&self) -> Option<&Arc<dyn FeeEstimator>> {
+        self.fee_estimator.as_ref()
+    }
+
+    /// Forbid nondeterministic inputs (e.g., randomness, the wall clock, or
+    /// live fee estimates) while compiling from this context and its
+    /// children, so that every party compiling the same arguments derives
+    /// the same contract. See [`Context::nondeterministic`] and
+    /// [`Object::fingerprint`](crate::contract::object::Object::fingerprint).
+    pub fn with_strict_determinism(mut self) -> Self {
+        self.strict_determinism = true;
+        self
+    }
+
+    /// true if nondeterministic inputs are forbidden
+    pub fn is_strictly_deterministic(&self) -> bool {
+        self.strict_determinism
+    }
+
+    /// Contract code must call this before using a nondeterministic input,
+    /// named by `source` (e.g., "random"). Fails with
+    /// [`CompilationError::Nondeterministic`] under strict determinism.
+    pub fn nondeterministic(&self, source: &str) -> Result<(), CompilationError> {
+        if self.strict_determinism {
+            Err(CompilationError::Nondeterministic {
+                source: source.into(),
+                path: self.path.clone(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The current wall clock time, unless under strict determinism
+    pub fn wall_clock(&self) -> Result<std::time::SystemTime, CompilationError> {
+        self.nondeterministic("wall clock")?;
+        Ok(std::time::SystemTime::now())
+    }
+
+    /// Enforce templates compiled from this context (and its children) with
+    /// `backend` rather than the emulator
+    pub fn with_covenant_backend(mut self, backend: CovenantBackend) -> Self {
+        self.covenant_backend = backend;
+        self
+    }
+
+    /// Use [`CovenantBackend::NativeCTV`] if this context's network is one
+    /// of `networks`, i.e. where BIP-119 is known to be active
+    pub fn with_native_ctv_on(self, networks: &[Network]) -> Self {
+        if networks.contains(&self.network) {
+            self.with_covenant_backend(CovenantBackend::NativeCTV)
+        } else {
+            self
+        }
+    }
+
+    /// Compile for the Elements chain `params` describes rather than for
+    /// Bitcoin, enforcing templates with introspection opcodes. `network`
+    /// is kept for anything which is not chain specific.
+    pub fn with_elements(mut self, params: ElementsParams) -> Self {
+        self.elements = Some(Arc::new(params));
+        self.with_covenant_backend(CovenantBackend::Elements)
+    }
+
+    /// the Elements chain this context compiles for, if any
+    pub fn elements(&self) -> Option<&ElementsParams> {
+        self.elements.as_deref()
+    }
+
+    /// how templates compiled from this context are enforced
+    pub fn covenant_backend(&self) -> CovenantBackend {
+        self.covenant_backend
+    }
+
+    /// Record a non-fatal warning at this context's path
+    pub fn warn<S: Into<String>>(&self, kind: WarningKind, message: S) {
+        self.diagnostics.push(Diagnostic {
+            path: SArc(self.path.clone()),
+            kind,
+            message: message.into(),
+        })
+    }
+
+    /// The diagnostics collected during this compilation (shared with every
+    /// context derived from the same root)
+    pub fn diagnostics(&self) -> &CompilationDiagnostics {
+        &self.diagnostics
+    }
+
+    /// A clause requiring a signature from `k`, recording its origin (if it
+    /// has one) so that bound PSBTs can carry it
+    pub fn key(&self, k: &SapioKey) -> Clause {
+        self.key_origins.key(k)
+    }
+
+    /// The key origins recorded during this compilation (shared with every
+    /// context derived from the same root)
+    pub fn key_origins(&self) -> &KeyOrigins {
+        &self.key_origins
+    }
+
     /// return the available funds
     pub fn funds(&self) -> Amount {
         self.available_funds
     }
 
+    /// The funds available in this context, including any reserved for fees.
+    /// Same as [`Context::funds`].
+    pub fn total(&self) -> Amount {
+        self.available_funds
+    }
+
+    /// The funds available in this context which are not reserved for fees
+    pub fn spendable(&self) -> Amount {
+        self.available_funds - self.fee_reserve
+    }
+
+    /// The funds reserved for fees in this context
+    pub fn reserved_fees(&self) -> Amount {
+        self.fee_reserve
+    }
+
+    /// Set aside `amount` of the spendable funds for fees. Reserved funds may
+    /// only be spent as fees (e.g., by
+    /// [`Builder::add_fees`](crate::template::Builder::add_fees)), so
+    /// allocating them to outputs fails with [`CompilationError::OutOfFunds`].
+    /// Contexts derived for outputs start with no reserve of their own.
+    pub fn reserve_fees(mut self, amount: Amount) -> Result<Self, CompilationError> {
+        if self.spendable() < amount {
+            Err(CompilationError::OutOfFunds)
+        } else {
+            self.fee_reserve += amount;
+            Ok(self)
+        }
+    }
+
     /// use the context's emulator to get a emulated (or not) clause
     pub fn ctv_emulator(
         &self,
         b: bitcoin::hashes::sha256::Hash,
     ) -> Result<sapio_base::Clause, CompilationError> {
-        Ok(self.emulator.get_signer_for(b)?)
+        match self.covenant_backend {
+            CovenantBackend::Emulated => Ok(self.emulator.get_signer_for(b)?),
+            // the other backends' leaves are lowered from the template hash by
+            // the compiler
+            _ => Ok(Clause::TxTemplate(b)),
+        }
+    }
+
+    /// use the context's emulator to get the clauses for many template
+    /// hashes at once, in order
+    pub fn ctv_emulators(
+        &self,
+        b: &[bitcoin::hashes::sha256::Hash],
+    ) -> Result<Vec<sapio_base::Clause>, CompilationError> {
+        if b.is_empty() {
+            return Ok(vec![]);
+        }
+        let clauses = match self.covenant_backend {
+            CovenantBackend::Emulated => self.emulator.sign_batch(b)?,
+            _ => b.iter().cloned().map(Clause::TxTemplate).collect(),
+        };
+        if clauses.len() != b.len() {
+            return Err(CompilationError::TerminateWith(format!(
+                "Emulator returned {} clauses for {} template hashes",
+                clauses.len(),
+                b.len()
+            )));
+        }
+        Ok(clauses)
     }
 
     /// Compile the compilable item with this context.
@@ -133,6 +401,52 @@ impl Context {
         a.compile(self)
     }
 
+    /// Use `cache` for [`Context::compile_cached`] in this context and every
+    /// context derived from it.
+    pub fn with_compilation_cache(mut self, cache: Arc<dyn CompilationCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Digest of the effects which may apply to contracts compiled at or
+    /// below this context's path
+    pub fn effects_digest(&self) -> bitcoin::hashes::sha256::Hash {
+        self.effects.digest_under(&self.path)
+    }
+
+    /// Compile the compilable item with this context, returning a previous
+    /// result from the compilation cache if nothing it depends on (path,
+    /// arguments, network, funds, applicable effects) has changed.
+    pub fn compile_cached<A: Compilable + Serialize>(
+        self,
+        a: &A,
+    ) -> Result<Compiled, CompilationError> {
+        let cache = match self.cache.clone() {
+            Some(cache) => cache,
+            None => return a.compile(self),
+        };
+        let key = CacheKey::new(&self, a)?;
+        if let Some(compiled) = cache.get(&key) {
+            return Ok(compiled);
+        }
+        let compiled = a.compile(self)?;
+        cache.put(key, &compiled);
+        Ok(compiled)
+    }
+
+    /// Compile the compilable item with this context, also returning any
+    /// warnings raised while compiling it, including branches which no
+    /// instance of their contract could reach.
+    pub fn compile_with_diagnostics<A: Compilable>(
+        self,
+        a: A,
+    ) -> Result<(Compiled, Vec<Diagnostic>), CompilationError> {
+        let diagnostics = self.diagnostics.clone();
+        let before = diagnostics.get().len();
+        let compiled = a.compile(self)?;
+        Ok((compiled, diagnostics.get().split_off(before)))
+    }
+
     // TODO: Fix
     /// return a context with the new amount if amount is smaller or equal to available
     pub fn with_amount(self, amount: Amount) -> Result<Self, CompilationError> {
@@ -141,19 +455,44 @@ impl Context {
         } else {
             Ok(Context {
                 available_funds: amount,
+                // a fresh allocation has its own reserve
+                fee_reserve: Amount::from_sat(0),
                 emulator: self.emulator.clone(),
                 path: self.path.clone(),
                 network: self.network,
                 already_derived: self.already_derived.clone(),
                 effects: self.effects.clone(),
+                salt_addresses: self.salt_addresses,
+                diagnostics: self.diagnostics.clone(),
+                cache: self.cache.clone(),
+                internal_key_policy: self.internal_key_policy.clone(),
+                keep_key_path_leaves: self.keep_key_path_leaves,
+                fee_estimator: self.fee_estimator.clone(),
+                key_origins: self.key_origins.clone(),
+                covenant_backend: self.covenant_backend,
+                elements: self.elements.clone(),
+                paths: self.paths.clone(),
+                strict_determinism: self.strict_determinism,
             })
         }
     }
     /// decrease the amount available in this context object.
     pub fn spend_amount(mut self, amount: Amount) -> Result<Self, CompilationError> {
+        if self.spendable() < amount {
+            Err(CompilationError::OutOfFunds)
+        } else {
+            self.available_funds -= amount;
+            Ok(self)
+        }
+    }
+
+    /// decrease the amount available in this context object to pay fees,
+    /// drawing on the fee reserve first.
+    pub fn spend_fees(mut self, amount: Amount) -> Result<Self, CompilationError> {
         if self.available_funds < amount {
             Err(CompilationError::OutOfFunds)
         } else {
+            self.fee_reserve -= std::cmp::min(self.fee_reserve, amount);
             self.available_funds -= amount;
             Ok(self)
         }
@@ -193,6 +532,57 @@ impl Context {
                 a.update_range(Amount::from_sat(21_000_000 * 100_000_000));
                 a
             }),
+            key_aggregation: None,
+            internal_key: None,
+            key_origins: Default::default(),
+            raw_taproot: None,
+            abi_version: None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::fees::{FeeEstimatorError, StaticFeeEstimator};
+    use sapio_ctv_emulator_trait::dev::InsecureDevEmulator;
+    use std::convert::TryFrom;
+    struct LiveEstimator;
+    impl FeeEstimator for LiveEstimator {
+        fn estimate_feerate(&self, _: u16) -> Result<Amount, FeeEstimatorError> {
+            Ok(Amount::from_sat(7))
+        }
+    }
+    #[test]
+    fn strict_determinism_forbids_live_inputs() {
+        let ctx = |estimator: Arc<dyn FeeEstimator>| {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(InsecureDevEmulator::default()),
+                EffectPath::try_from("root").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .with_fee_estimator(estimator)
+        };
+        let live = ctx(Arc::new(LiveEstimator));
+        assert!(live.wall_clock().is_ok());
+        assert!(live.template().add_fee_for_rate(6).is_ok());
+        let mut strict = ctx(Arc::new(LiveEstimator)).with_strict_determinism();
+        assert!(matches!(
+            strict.wall_clock(),
+            Err(CompilationError::Nondeterministic { .. })
+        ));
+        let child = strict.derive_num(0u64).unwrap();
+        assert!(child.is_strictly_deterministic());
+        assert!(matches!(
+            child.template().add_fee_for_rate(6),
+            Err(CompilationError::Nondeterministic { .. })
+        ));
+        let fixed = ctx(Arc::new(StaticFeeEstimator {
+            feerate: Amount::from_sat(7),
+        }))
+        .with_strict_determinism();
+        assert!(fixed.template().add_fee_for_rate(6).is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! BIP-118 (SIGHASH_ANYPREVOUT) covenants.
+//!
+//! A template is committed to by a signature placed in the leaf script
+//! itself, `<sig> <0x01 || K> OP_CHECKSIG`, made at compile time with a fixed
+//! and publicly known key `K`. Knowing the key does not help to spend another
+//! way, as the signature is fixed by the script. It uses
+//! SIGHASH_ANYPREVOUTANYSCRIPT, so does not commit to the coin spent and may
+//! be made before the contract is funded.
+//!
+//! Unlike a CTV hash, the signature does not commit to the other inputs of
+//! the transaction (neither their number nor their sequences).
+use crate::contract::CompilationError;
+use ::miniscript::{Miniscript, Tap};
+use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_VERIFY};
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{KeyPair, Message, Secp256k1, Signing};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::util::taproot::{LeafVersion, TapSighashHash};
+use bitcoin::{Script, Transaction, Witness, XOnlyPublicKey};
+use sapio_base::Clause;
+
+/// SIGHASH_ANYPREVOUTANYSCRIPT | SIGHASH_ALL
+pub const SIGHASH_ANYPREVOUTANYSCRIPT_ALL: u8 = 0xc1;
+/// The prefix of a BIP-118 public key in a script
+pub const BIP118_KEY_VERSION: u8 = 0x01;
+
+/// The fixed key covenant signatures are made with. Its private key is the
+/// hash of a public tag.
+pub fn covenant_keypair<C: Signing>(secp: &Secp256k1<C>) -> KeyPair {
+    let sk = sha256::Hash::hash(b"sapio/apo/covenant_key");
+    KeyPair::from_seckey_slice(secp, &sk[..]).expect("A sha256 output is a valid key")
+}
+
+/// The BIP-118 signature hash of input 0 of `tx` for
+/// SIGHASH_ANYPREVOUTANYSCRIPT | SIGHASH_ALL, spent via a script path.
+///
+/// It commits to the version, lock time, outputs and input 0's sequence.
+pub fn covenant_sighash(tx: &Transaction) -> TapSighashHash {
+    let mut engine = TapSighashHash::engine();
+    // epoch
+    engine.input(&[0u8, SIGHASH_ANYPREVOUTANYSCRIPT_ALL]);
+    tx.version.consensus_encode(&mut engine).unwrap();
+    tx.lock_time.consensus_encode(&mut engine).unwrap();
+    // no sha_prevouts, sha_amounts, sha_scriptpubkeys nor sha_sequences
+    let mut outputs = sha256::Hash::engine();
+    for o in tx.output.iter() {
+        o.consensus_encode(&mut outputs).unwrap();
+    }
+    engine.input(&sha256::Hash::from_engine(outputs)[..]);
+    // spend_type: script path without an annex
+    engine.input(&[2u8]);
+    // of this input, only the sequence
+    tx.input[0].sequence.consensus_encode(&mut engine).unwrap();
+    // no tapleaf_hash; key_version, then codesep_pos (none)
+    engine.input(&[BIP118_KEY_VERSION]);
+    0xffff_ffffu32.consensus_encode(&mut engine).unwrap();
+    TapSighashHash::from_engine(engine)
+}
+
+/// The leaf script which may only be spent by `tx` (up to its other inputs),
+/// requiring `guard` to be satisfied as well if it is not trivial.
+pub fn covenant_script<C: Signing>(
+    tx: &Transaction,
+    guard: &Clause,
+    secp: &Secp256k1<C>,
+) -> Result<Script, CompilationError> {
+    let keypair = covenant_keypair(secp);
+    let msg = Message::from_slice(&covenant_sighash(tx)[..]).expect("Size must be correct.");
+    let mut sig = secp
+        .sign_schnorr_no_aux_rand(&msg, &keypair)
+        .as_ref()
+        .to_vec();
+    sig.push(SIGHASH_ANYPREVOUTANYSCRIPT_ALL);
+    let mut key = vec![BIP118_KEY_VERSION];
+    key.extend_from_slice(&XOnlyPublicKey::from_keypair(&keypair).serialize());
+    let covenant = Builder::new()
+        .push_slice(&sig)
+        .push_slice(&key)
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+    if *guard == Clause::Trivial {
+        return Ok(covenant);
+    }
+    let guard: Miniscript<XOnlyPublicKey, Tap> =
+        guard.compile().map_err(Into::<CompilationError>::into)?;
+    let mut bytes = guard.encode().into_bytes();
+    bytes.push(OP_VERIFY.into_u8());
+    bytes.extend_from_slice(covenant.as_bytes());
+    Ok(Script::from(bytes))
+}
+
+/// Finalize input 0 of `psbt` if it spends an unguarded covenant leaf for
+/// its own transaction, which needs no signatures. Returns true if it did.
+pub fn finalize_covenant_input<C: Signing>(
+    psbt: &mut PartiallySignedTransaction,
+    secp: &Secp256k1<C>,
+) -> Result<bool, CompilationError> {
+    let leaf = covenant_script(&psbt.unsigned_tx, &Clause::Trivial, secp)?;
+    let inp = &mut psbt.inputs[0];
+    let cb = inp
+        .tap_scripts
+        .iter()
+        .find(|(_, (s, v))| *s == leaf && *v == LeafVersion::TapScript)
+        .map(|(cb, _)| cb.serialize());
+    Ok(match cb {
+        Some(cb) => {
+            inp.final_script_witness = Some(Witness::from_vec(vec![leaf.into_bytes(), cb]));
+            true
+        }
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{OutPoint, TxIn, TxOut};
+    fn tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                sequence: 10,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+    #[test]
+    fn commits_to_outputs_not_prevouts() {
+        let secp = Secp256k1::new();
+        let a = tx();
+        let mut b = tx();
+        b.input[0].previous_output.vout = 7;
+        b.input.push(TxIn::default());
+        assert_eq!(covenant_sighash(&a), covenant_sighash(&b));
+        b.output[0].value = 999;
+        assert_ne!(covenant_sighash(&a), covenant_sighash(&b));
+        let script = covenant_script(&a, &Clause::Trivial, &secp).unwrap();
+        // <65 byte sig> <33 byte key> OP_CHECKSIG
+        assert_eq!(script.len(), 1 + 65 + 1 + 33 + 1);
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Liquid/Elements covenants.
+//!
+//! Elements tapscript can inspect the spending transaction, so a template is
+//! enforced by checking each field CTV would commit to directly: version,
+//! lock time, input count and sequences, the spending input's index, and
+//! every output's script, (explicit) value and asset.
+//!
+//! Elements transactions carry their fee as an explicit output, which is
+//! required to be the one following the template's outputs. Its value is
+//! not checked, as it is fixed by the others.
+//!
+//! Elements taproot uses its own leaf version and tagged hashes, so trees
+//! are built by [`ElementsTaproot`] rather than by rust-bitcoin.
+use crate::contract::CompilationError;
+use ::miniscript::{Miniscript, Tap};
+use bitcoin::blockdata::opcodes::all::{OP_EQUALVERIFY, OP_PUSHNUM_1, OP_VERIFY};
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{Parity, Secp256k1, Verification};
+use bitcoin::{Script, Transaction, XOnlyPublicKey};
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// The Elements tapscript introspection opcodes used here
+pub mod opcodes {
+    /// `<i>` -> `<asset> <prefix>`
+    pub const OP_INSPECTOUTPUTASSET: u8 = 0xce;
+    /// `<i>` -> `<value> <prefix>`
+    pub const OP_INSPECTOUTPUTVALUE: u8 = 0xcf;
+    /// `<i>` -> `<program> <witness version>`
+    pub const OP_INSPECTOUTPUTSCRIPTPUBKEY: u8 = 0xd1;
+    /// `<i>` -> `<sequence>`
+    pub const OP_INSPECTINPUTSEQUENCE: u8 = 0xcb;
+    /// pushes the index of the input being spent
+    pub const OP_PUSHCURRENTINPUTINDEX: u8 = 0xcd;
+    /// pushes the transaction's version
+    pub const OP_INSPECTVERSION: u8 = 0xd2;
+    /// pushes the transaction's lock time
+    pub const OP_INSPECTLOCKTIME: u8 = 0xd3;
+    /// pushes the number of inputs
+    pub const OP_INSPECTNUMINPUTS: u8 = 0xd4;
+    /// pushes the number of outputs
+    pub const OP_INSPECTNUMOUTPUTS: u8 = 0xd5;
+}
+use opcodes::*;
+
+/// The Elements tapscript leaf version
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc4;
+
+/// The L-BTC asset id, as displayed
+const LIQUID_POLICY_ASSET: &str =
+    "6f0279e9ed041c3d710a9f57d0c02928416460c4b722ae3457a11eec381c526d";
+
+/// The Elements chain a context compiles for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ElementsParams {
+    /// The asset template outputs (and the fee) are paid in, in internal
+    /// byte order (the reverse of how asset ids are displayed)
+    pub policy_asset: sha256::Hash,
+    /// The human readable part of the chain's unconfidential segwit
+    /// addresses, e.g. `ex` on Liquid or `ert` on elementsregtest
+    pub hrp: String,
+}
+
+impl ElementsParams {
+    /// Liquid, with L-BTC as the policy asset
+    pub fn liquid() -> Self {
+        let mut asset = <[u8; 32]>::from_hex(LIQUID_POLICY_ASSET).expect("Valid Hex");
+        asset.reverse();
+        ElementsParams {
+            policy_asset: sha256::Hash::from_inner(asset),
+            hrp: "ex".into(),
+        }
+    }
+}
+
+fn push_output_script(b: Builder, spk: &Script) -> Builder {
+    let b = match spk.witness_version() {
+        Some(v) if spk.is_witness_program() => b
+            .push_int(v.into_num() as i64)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&spk.as_bytes()[2..]),
+        // non-segwit scripts are inspected by hash
+        _ => b
+            .push_int(-1)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&sha256::Hash::hash(spk.as_bytes())[..]),
+    };
+    b.push_opcode(OP_EQUALVERIFY)
+}
+
+fn push_explicit(b: Builder, op: u8, i: usize, data: &[u8]) -> Builder {
+    b.push_int(i as i64)
+        .push_opcode(op.into())
+        // prefix of an explicit (not confidential) value or asset
+        .push_int(1)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_slice(data)
+        .push_opcode(OP_EQUALVERIFY)
+}
+
+/// The leaf script which may only be spent by (the Elements equivalent of)
+/// `tx` as input 0, requiring `guard` to be satisfied as well if it is not
+/// trivial.
+pub fn covenant_script(
+    tx: &Transaction,
+    guard: &Clause,
+    params: &ElementsParams,
+) -> Result<Script, CompilationError> {
+    let mut b = Builder::new()
+        .push_opcode(OP_INSPECTVERSION.into())
+        .push_slice(&tx.version.to_le_bytes())
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_INSPECTLOCKTIME.into())
+        .push_slice(&tx.lock_time.to_le_bytes())
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_PUSHCURRENTINPUTINDEX.into())
+        .push_int(0)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_INSPECTNUMINPUTS.into())
+        .push_int(tx.input.len() as i64)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_INSPECTNUMOUTPUTS.into())
+        .push_int(tx.output.len() as i64 + 1)
+        .push_opcode(OP_EQUALVERIFY);
+    for (i, inp) in tx.input.iter().enumerate() {
+        b = b
+            .push_int(i as i64)
+            .push_opcode(OP_INSPECTINPUTSEQUENCE.into())
+            .push_slice(&inp.sequence.to_le_bytes())
+            .push_opcode(OP_EQUALVERIFY);
+    }
+    for (i, out) in tx.output.iter().enumerate() {
+        b = push_explicit(b, OP_INSPECTOUTPUTVALUE, i, &out.value.to_le_bytes());
+        b = push_explicit(b, OP_INSPECTOUTPUTASSET, i, &params.policy_asset[..]);
+        b = push_output_script(
+            b.push_int(i as i64)
+                .push_opcode(OP_INSPECTOUTPUTSCRIPTPUBKEY.into()),
+            &out.script_pubkey,
+        );
+    }
+    // the fee output
+    let fee = tx.output.len();
+    b = push_explicit(b, OP_INSPECTOUTPUTASSET, fee, &params.policy_asset[..]);
+    b = push_output_script(
+        b.push_int(fee as i64)
+            .push_opcode(OP_INSPECTOUTPUTSCRIPTPUBKEY.into()),
+        &Script::new(),
+    );
+    let covenant = b.push_opcode(OP_PUSHNUM_1).into_script();
+    if *guard == Clause::Trivial {
+        return Ok(covenant);
+    }
+    let guard: Miniscript<XOnlyPublicKey, Tap> =
+        guard.compile().map_err(Into::<CompilationError>::into)?;
+    let mut bytes = guard.encode().into_bytes();
+    bytes.push(OP_VERIFY.into_u8());
+    bytes.extend_from_slice(covenant.as_bytes());
+    Ok(Script::from(bytes))
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// The Elements tapleaf hash of `script`
+pub fn leaf_hash(script: &Script) -> [u8; 32] {
+    let mut len = vec![];
+    VarInt(script.len() as u64)
+        .consensus_encode(&mut len)
+        .expect("Vec does not fail");
+    tagged_hash(
+        "TapLeaf/elements",
+        &[&[TAPROOT_LEAF_TAPSCRIPT], &len, script.as_bytes()],
+    )
+}
+
+/// The unconfidential address of a segwit `script_pubkey` (e.g. the
+/// address of a compiled object) on the chain `params` describes
+pub fn unconfidential_address(
+    script_pubkey: &Script,
+    params: &ElementsParams,
+) -> Result<String, CompilationError> {
+    use bitcoin::bech32::{self, ToBase32, Variant};
+    let version = match script_pubkey.witness_version() {
+        Some(v) if script_pubkey.is_witness_program() => v.into_num(),
+        _ => {
+            return Err(CompilationError::TerminateWith(
+                "Only segwit outputs have an address".into(),
+            ))
+        }
+    };
+    let mut data = vec![bech32::u5::try_from_u8(version).expect("Witness versions are < 32")];
+    data.extend(script_pubkey.as_bytes()[2..].to_base32());
+    let variant = if version == 0 {
+        Variant::Bech32
+    } else {
+        Variant::Bech32m
+    };
+    bech32::encode(&params.hrp, data, variant)
+        .map_err(|e| CompilationError::TerminateWith(e.to_string()))
+}
+
+/// An Elements taproot output
+pub struct ElementsTaproot {
+    /// the internal key
+    pub internal_key: XOnlyPublicKey,
+    /// the leaves, in the order given, with their merkle paths
+    pub leaves: Vec<(Script, Vec<[u8; 32]>)>,
+    /// the root of the script tree, if there are leaves
+    pub merkle_root: Option<[u8; 32]>,
+    /// the tweaked output key
+    pub output_key: XOnlyPublicKey,
+    /// the parity of the output key
+    pub parity: u8,
+}
+
+impl ElementsTaproot {
+    /// Build the tree of `leaves`, Huffman encoded by weight
+    pub fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        internal_key: XOnlyPublicKey,
+        leaves: &[(u32, Script)],
+    ) -> Result<Self, CompilationError> {
+        let mut paths: Vec<Vec<[u8; 32]>> = vec![vec![]; leaves.len()];
+        let mut heap: BinaryHeap<(Reverse<u64>, [u8; 32], Vec<usize>)> = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, (w, s))| (Reverse(*w as u64), leaf_hash(s), vec![i]))
+            .collect();
+        while heap.len() > 1 {
+            let (w1, h1, l1) = heap.pop().unwrap();
+            let (w2, h2, l2) = heap.pop().unwrap();
+            for i in l1.iter() {
+                paths[*i].push(h2);
+            }
+            for i in l2.iter() {
+                paths[*i].push(h1);
+            }
+            let (lo, hi) = if h1 <= h2 { (h1, h2) } else { (h2, h1) };
+            let branch = tagged_hash("TapBranch/elements", &[&lo, &hi]);
+            heap.push((
+                Reverse(w1.0.saturating_add(w2.0)),
+                branch,
+                l1.into_iter().chain(l2).collect(),
+            ));
+        }
+        let merkle_root = heap.pop().map(|(_, h, _)| h);
+        let internal = internal_key.serialize();
+        let tweak = match &merkle_root {
+            Some(root) => tagged_hash("TapTweak/elements", &[&internal, root]),
+            None => tagged_hash("TapTweak/elements", &[&internal]),
+        };
+        let mut output_key = internal_key;
+        let parity = output_key
+            .tweak_add_assign(secp, &tweak)
+            .map_err(|e| CompilationError::TerminateWith(e.to_string()))?;
+        Ok(ElementsTaproot {
+            internal_key,
+            leaves: leaves.iter().map(|(_, s)| s.clone()).zip(paths).collect(),
+            merkle_root,
+            output_key,
+            parity: match parity {
+                Parity::Even => 0,
+                Parity::Odd => 1,
+            },
+        })
+    }
+
+    /// The output's scriptPubKey
+    pub fn script_pubkey(&self) -> Script {
+        Builder::new()
+            .push_int(1)
+            .push_slice(&self.output_key.serialize())
+            .into_script()
+    }
+
+    /// The output's unconfidential address on the chain `params` describes
+    pub fn address(&self, params: &ElementsParams) -> Result<String, CompilationError> {
+        unconfidential_address(&self.script_pubkey(), params)
+    }
+
+    /// The control block spending the `i`th leaf
+    pub fn control_block(&self, i: usize) -> Option<Vec<u8>> {
+        let (_, path) = self.leaves.get(i)?;
+        let mut cb = vec![TAPROOT_LEAF_TAPSCRIPT | self.parity];
+        cb.extend_from_slice(&self.internal_key.serialize());
+        for h in path {
+            cb.extend_from_slice(h);
+        }
+        Some(cb)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::internal_key::hashed_constant_key;
+    use bitcoin::{OutPoint, TxIn, TxOut};
+    #[test]
+    fn covenant_checks_each_output() {
+        let params = ElementsParams::liquid();
+        let tx = |n: usize| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: Script::new(),
+                };
+                n
+            ],
+        };
+        let one = covenant_script(&tx(1), &Clause::Trivial, &params).unwrap();
+        let two = covenant_script(&tx(2), &Clause::Trivial, &params).unwrap();
+        assert!(two.len() > one.len());
+        let guarded = covenant_script(&tx(1), &Clause::After(100), &params).unwrap();
+        assert!(guarded.as_bytes().ends_with(one.as_bytes()));
+
+        let secp = Secp256k1::verification_only();
+        let leaves = vec![(1, one), (2, two), (1, guarded)];
+        let tree = ElementsTaproot::new(&secp, hashed_constant_key(), &leaves).unwrap();
+        assert!(tree.merkle_root.is_some());
+        assert_eq!(tree.leaves.len(), 3);
+        // the heaviest leaf is shallowest
+        assert_eq!(tree.leaves[1].1.len(), 1);
+        assert_eq!(tree.control_block(0).unwrap().len(), 33 + 2 * 32);
+        assert!(tree.address(&params).unwrap().starts_with("ex1p"));
+    }
+}
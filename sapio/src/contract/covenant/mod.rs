@@ -0,0 +1,118 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The ways a template hash can be enforced on chain.
+use bitcoin::hashes::sha256;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+pub mod apo;
+pub mod elements;
+
+/// How the compiler turns a template hash into a spending condition. See
+/// [`Context::with_covenant_backend`](crate::contract::Context::with_covenant_backend).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CovenantBackend {
+    /// Ask the context's emulator, e.g. an oracle federation
+    Emulated,
+    /// `<h> OP_CHECKTEMPLATEVERIFY`, for networks where BIP-119 is active
+    /// (e.g. a custom signet). The emulator is not consulted.
+    NativeCTV,
+    /// A BIP-118 signature fixed in the leaf script, for networks where
+    /// SIGHASH_ANYPREVOUT is active (see [`apo`]). Leaves with a template are
+    /// not miniscript, so the compiled object has no descriptor.
+    AnyPrevout,
+    /// Introspection opcodes on Liquid/Elements (see [`elements`]), set by
+    /// [`Context::with_elements`](crate::contract::Context::with_elements).
+    /// Every leaf is lowered to Elements tapscript, so the compiled object
+    /// has no descriptor and its address is only known as a script.
+    Elements,
+}
+
+impl Default for CovenantBackend {
+    fn default() -> Self {
+        CovenantBackend::Emulated
+    }
+}
+
+/// Split a leaf clause produced by the compiler into its template hash and
+/// the rest of its conditions. Leaves with no template return None.
+pub(crate) fn split_template(c: &Clause) -> Option<(Clause, sha256::Hash)> {
+    match c {
+        Clause::TxTemplate(h) => Some((Clause::Trivial, *h)),
+        Clause::And(v) => v.iter().enumerate().find_map(|(i, inner)| {
+            let (guard, h) = split_template(inner)?;
+            let mut rest: Vec<Clause> = v
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, c)| c.clone())
+                .chain(std::iter::once(guard))
+                .filter(|c| *c != Clause::Trivial)
+                .collect();
+            Some((
+                match rest.len() {
+                    0 => Clause::Trivial,
+                    1 => rest.pop().unwrap(),
+                    _ => Clause::And(rest),
+                },
+                h,
+            ))
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::{Amount, Network};
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::dev::InsecureDevEmulator;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    #[test]
+    fn native_ctv_only_on_selected_networks() {
+        let ctx = |network| {
+            Context::new(
+                network,
+                Amount::from_sat(1000),
+                Arc::new(InsecureDevEmulator::default()),
+                "root".try_into().unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .with_native_ctv_on(&[Network::Signet])
+        };
+        let h = sha256::Hash::hash(b"template");
+        let signet = ctx(Network::Signet);
+        assert_eq!(signet.covenant_backend(), CovenantBackend::NativeCTV);
+        assert_eq!(signet.ctv_emulator(h).unwrap(), Clause::TxTemplate(h));
+        assert_eq!(
+            signet.ctv_emulators(&[h, h]).unwrap(),
+            vec![Clause::TxTemplate(h); 2]
+        );
+        let regtest = ctx(Network::Regtest);
+        assert_eq!(regtest.covenant_backend(), CovenantBackend::Emulated);
+        assert!(matches!(regtest.ctv_emulator(h).unwrap(), Clause::Key(_)));
+    }
+    #[test]
+    fn splits_templates_from_guards() {
+        let h = sha256::Hash::hash(b"template");
+        let g = Clause::After(100);
+        assert_eq!(
+            split_template(&Clause::TxTemplate(h)),
+            Some((Clause::Trivial, h))
+        );
+        assert_eq!(
+            split_template(&Clause::And(vec![g.clone(), Clause::TxTemplate(h)])),
+            Some((g.clone(), h))
+        );
+        assert_eq!(split_template(&g), None);
+    }
+}
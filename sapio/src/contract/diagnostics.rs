@@ -0,0 +1,156 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Non-fatal warnings raised during compilation
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// # Warning Kind
+/// What a [`Diagnostic`] is about
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// A branch is guarded by a clause which can never be satisfied, e.g. an
+    /// And with an unsatisfiable part, and should probably be disabled with a
+    /// compile_if instead
+    UnsatisfiableGuard,
+    /// A branch's compile_if was Never for every one of several instances of
+    /// its contract compiled, so it is never part of any contract. A branch
+    /// of a contract compiled once is not reported, as being Never there is
+    /// as likely to be intended as not.
+    UnreachableBranch,
+    /// An output is close to the dust limit
+    NearDustOutput,
+    /// A script is larger than many tools support
+    OversizedScript,
+    /// A finish_or branch suggests no transactions when called with default
+    /// arguments
+    UnusedFinishOr,
+    /// A suggested transaction's nSequence or nLockTime cannot satisfy the
+    /// timelocks in the guard it is suggested under
+    TimelockMismatch,
+    /// An effect was skipped because its conditions are not met, e.g. it
+    /// has expired
+    InapplicableEffect,
+    /// Raised by contract code
+    Custom,
+}
+
+/// # Diagnostic
+/// A non-fatal warning raised during compilation
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// # Path
+    /// Where in the contract the warning was raised
+    pub path: SArc<EffectPath>,
+    /// # Kind
+    pub kind: WarningKind,
+    /// # Message
+    pub message: String,
+}
+
+/// For a branch, by contract type and branch name: the path of the first
+/// instance compiled, how many instances were compiled, and whether it was
+/// reachable in any of them
+type Reachability = BTreeMap<(&'static str, String), (SArc<EffectPath>, usize, bool)>;
+
+/// Collects the [`Diagnostic`]s raised anywhere in a compilation. Cloning
+/// shares the underlying collection.
+#[derive(Clone, Default)]
+pub struct CompilationDiagnostics {
+    diagnostics: Arc<Mutex<Vec<Diagnostic>>>,
+    reachability: Arc<Mutex<Reachability>>,
+    /// how many contract compilations are in progress, so that reachability
+    /// is flushed when the outermost finishes
+    depth: Arc<AtomicUsize>,
+}
+
+/// Marks a contract compilation in progress, see
+/// [`CompilationDiagnostics::enter_compile`]
+pub(crate) struct CompileScope(CompilationDiagnostics);
+
+impl Drop for CompileScope {
+    fn drop(&mut self) {
+        if self.0.depth.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.flush_reachability();
+        }
+    }
+}
+
+impl CompilationDiagnostics {
+    /// record a diagnostic
+    pub fn push(&self, d: Diagnostic) {
+        if let Ok(mut v) = self.diagnostics.lock() {
+            v.push(d);
+        }
+    }
+    /// copy out the diagnostics recorded so far
+    pub fn get(&self) -> Vec<Diagnostic> {
+        self.diagnostics
+            .lock()
+            .map(|v| v.clone())
+            .unwrap_or_default()
+    }
+    /// remove and return the diagnostics recorded so far
+    pub fn take(&self) -> Vec<Diagnostic> {
+        self.diagnostics
+            .lock()
+            .map(|mut v| std::mem::take(&mut *v))
+            .unwrap_or_default()
+    }
+    /// record whether the branch `name` of an instance of `contract`, at
+    /// `path`, was reachable
+    pub(crate) fn record_reachability(
+        &self,
+        contract: &'static str,
+        name: &str,
+        path: &Arc<EffectPath>,
+        reachable: bool,
+    ) {
+        if let Ok(mut r) = self.reachability.lock() {
+            let e = r
+                .entry((contract, name.into()))
+                .or_insert_with(|| (SArc(path.clone()), 0, false));
+            e.1 += 1;
+            e.2 |= reachable;
+        }
+    }
+    /// mark a contract compilation as started until the returned scope is
+    /// dropped. When the outermost compilation in progress ends, its
+    /// reachability is flushed, so each top-level compile reports on its own
+    /// contracts only.
+    pub(crate) fn enter_compile(&self) -> CompileScope {
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        CompileScope(self.clone())
+    }
+    /// raise a [`WarningKind::UnreachableBranch`] for every branch recorded
+    /// as unreachable in every one of more than one instance, and forget what
+    /// was recorded
+    fn flush_reachability(&self) {
+        let recorded = self
+            .reachability
+            .lock()
+            .map(|mut r| std::mem::take(&mut *r))
+            .unwrap_or_default();
+        for ((contract, name), (path, instances, reachable)) in recorded {
+            if instances > 1 && !reachable {
+                self.push(Diagnostic {
+                    path,
+                    kind: WarningKind::UnreachableBranch,
+                    message: format!(
+                        "{} is never compiled, as its compile_if is Never for all {} {} compiled",
+                        name, instances, contract
+                    ),
+                });
+            }
+        }
+    }
+}
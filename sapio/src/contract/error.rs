@@ -7,12 +7,15 @@
 //! error types that can be returned from Sapio.
 //! Where possible, concrete error types are wrapped, but in order to handle
 //! errors created by the user we allow boxing an error trait.
+use crate::contract::abi::standardness::StandardnessViolation;
 use crate::contract::object::ObjectError;
 use sapio_base::effects::EffectDBError;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::ValidFragmentError;
+use sapio_base::fees::FeeEstimatorError;
 use sapio_base::plugin_args::CreateArgs;
 use sapio_base::simp::SIMPError;
+use sapio_base::simp::SIMPOpReturnError;
 use sapio_ctv_emulator_trait::EmulatorError;
 use std::collections::LinkedList;
 use std::error::Error;
@@ -33,6 +36,10 @@ pub enum CompilationError {
     OverwriteMetadata(String),
     /// Fee Specification Error
     MinFeerateError,
+    /// No fee estimator is installed on the Context
+    NoFeeEstimator,
+    /// Fee estimation failed
+    FeeEstimatorError(FeeEstimatorError),
     /// Error when ContextPath has already been used.
     ContexPathAlreadyDerived,
     /// Error when ContextPath attempted
@@ -45,6 +52,57 @@ pub enum CompilationError {
     EmptyPolicy,
     /// Error if a contract does not have sufficient funds available
     OutOfFunds,
+    /// Error if a contract is funded with less than a mandatory branch requires
+    InsufficientFunding {
+        /// the amount the branch requires
+        required: bitcoin::util::amount::Amount,
+        /// the amount available in the context
+        available: bitcoin::util::amount::Amount,
+        /// the path of the branch requiring `required`
+        path: std::sync::Arc<EffectPath>,
+    },
+    /// Error if a CTV template's nSequence or nLockTime cannot satisfy the
+    /// timelocks of the branch it is bound to, so the branch is unspendable
+    TimelockNotSatisfiable {
+        /// the template's CTV hash
+        template: bitcoin::hashes::sha256::Hash,
+        /// the path of the branch
+        path: std::sync::Arc<EffectPath>,
+    },
+    /// Error if a branch requires both height and time based locks of the
+    /// same kind (absolute or relative), so it can never be satisfied
+    MixedTimelockUnits {
+        /// a pair of the conflicting locks
+        locks: (sapio_base::Clause, sapio_base::Clause),
+        /// the path of the branch
+        path: std::sync::Arc<EffectPath>,
+    },
+    /// Error if two branches of one kind have the same name, so both would
+    /// be compiled at the same path
+    DuplicateBranchName {
+        /// the name
+        name: String,
+        /// the path both branches would have
+        path: std::sync::Arc<EffectPath>,
+    },
+    /// Error if contract code used a nondeterministic input while compiling
+    /// under strict determinism
+    Nondeterministic {
+        /// what the input was
+        source: String,
+        /// the path being compiled
+        path: std::sync::Arc<EffectPath>,
+    },
+    /// Error if a value could not be canonically encoded
+    CanonicalEncoding(sapio_base::canonical::CanonicalError),
+    /// Error if an output at a path would be below the dust threshold for its
+    /// script type
+    DustOutput(std::sync::Arc<EffectPath>, bitcoin::util::amount::Amount),
+    /// Error if the change computed for a template would be a dust output
+    ChangeIsDust(bitcoin::util::amount::Amount),
+    /// Error if a compiled script or template breaks a consensus or
+    /// standardness limit
+    StandardnessViolation(StandardnessViolation),
     /// Error if a CheckSequenceVerify clause is incompatible with the sequence already set.
     /// E.g., blocks and time
     IncompatibleSequence,
@@ -69,6 +127,8 @@ pub enum CompilationError {
     EffectDBError(EffectDBError),
     /// Error in a Sapio Interactive Metadata Protocol
     SIMPError(SIMPError),
+    /// Error committing a SIMP on-chain
+    SIMPOpReturnError(SIMPOpReturnError),
     /// Module could not be found.
     /// Used in Plugin interface (TODO: Wrap these types)
     UnknownModule,
@@ -113,11 +173,21 @@ impl From<SIMPError> for CompilationError {
         CompilationError::SIMPError(e)
     }
 }
+impl From<SIMPOpReturnError> for CompilationError {
+    fn from(e: SIMPOpReturnError) -> CompilationError {
+        CompilationError::SIMPOpReturnError(e)
+    }
+}
 impl From<ValidFragmentError> for CompilationError {
     fn from(e: ValidFragmentError) -> CompilationError {
         CompilationError::PathFragmentError(e)
     }
 }
+impl From<FeeEstimatorError> for CompilationError {
+    fn from(e: FeeEstimatorError) -> Self {
+        CompilationError::FeeEstimatorError(e)
+    }
+}
 impl From<EffectDBError> for CompilationError {
     fn from(e: EffectDBError) -> CompilationError {
         CompilationError::EffectDBError(e)
@@ -164,6 +234,12 @@ impl From<ObjectError> for CompilationError {
     }
 }
 
+impl From<sapio_base::canonical::CanonicalError> for CompilationError {
+    fn from(e: sapio_base::canonical::CanonicalError) -> Self {
+        CompilationError::CanonicalEncoding(e)
+    }
+}
+
 impl fmt::Display for CompilationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?}", self)
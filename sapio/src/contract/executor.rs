@@ -0,0 +1,215 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Automatically broadcast timeout branches (e.g., recovery or refund paths)
+//! of bound contracts once their timelocks mature, as reported by a
+//! [`ContractWatcher`](super::watcher::ContractWatcher).
+//!
+//! Only transactions which need nothing but the timelock are executed: those
+//! fully determined by CTV, or signed by the emulator at binding. An
+//! [`ExecutionPolicy`] restricts which of those may be broadcast.
+use super::object::{ObjectError, PsbtVersion, SapioStudioFormat};
+use super::watcher::WatchEvent;
+use super::Compiled;
+use ::miniscript::psbt::PsbtExt;
+use bitcoin::consensus::Decodable;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::sha256;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Transaction};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use sapio_base::CTVHash;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Errors from binding or executing a contract
+#[derive(Debug)]
+pub enum ExecutorError {
+    /// The contract could not be bound
+    Object(ObjectError),
+    /// Broadcasting failed
+    TxIndex(TxIndexError),
+    /// A bound PSBT could not be decoded
+    Malformed(String),
+}
+impl std::error::Error for ExecutorError {}
+impl std::fmt::Display for ExecutorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<ObjectError> for ExecutorError {
+    fn from(e: ObjectError) -> Self {
+        ExecutorError::Object(e)
+    }
+}
+impl From<TxIndexError> for ExecutorError {
+    fn from(e: TxIndexError) -> Self {
+        ExecutorError::TxIndex(e)
+    }
+}
+
+/// Decides which matured branches an [`Executor`] may broadcast
+pub trait ExecutionPolicy {
+    /// may the template with `label` spending the contract at `path` be
+    /// broadcast?
+    fn allows(&self, path: &EffectPath, label: Option<&str>) -> bool;
+}
+
+/// Allows branches of contracts at or below any of `paths`, or whose
+/// template has any of `labels`
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    /// contract paths whose subtrees may be executed
+    pub paths: Vec<SArc<EffectPath>>,
+    /// template labels which may be executed anywhere
+    pub labels: Vec<String>,
+}
+
+impl AllowList {
+    fn under(path: &EffectPath, prefix: &EffectPath) -> bool {
+        // paths iterate from the leaf, so compare the root-most segments
+        let p: Vec<_> = path.iter().collect();
+        let q: Vec<_> = prefix.iter().collect();
+        p.len() >= q.len() && p[p.len() - q.len()..] == q[..]
+    }
+}
+
+impl ExecutionPolicy for AllowList {
+    fn allows(&self, path: &EffectPath, label: Option<&str>) -> bool {
+        self.paths.iter().any(|p| Self::under(path, &p.0))
+            || label.map_or(false, |l| self.labels.iter().any(|x| x == l))
+    }
+}
+
+/// A finalized transaction, ready to broadcast once mature
+struct Ready {
+    path: SArc<EffectPath>,
+    tx: Transaction,
+}
+
+/// Broadcasts finalized timeout branches allowed by a policy as their
+/// timelocks mature
+pub struct Executor<P: ExecutionPolicy> {
+    policy: P,
+    broadcaster: Rc<dyn TxIndex>,
+    ready: HashMap<(OutPoint, sha256::Hash), Ready>,
+}
+
+impl<P: ExecutionPolicy> Executor<P> {
+    /// Execute what `policy` allows, broadcasting through `broadcaster` (an
+    /// index which can add transactions to the network).
+    pub fn new(policy: P, broadcaster: Rc<dyn TxIndex>) -> Self {
+        Executor {
+            policy,
+            broadcaster,
+            ready: HashMap::new(),
+        }
+    }
+
+    /// Bind `compiled` to `outpoint`, whose transaction the broadcaster must
+    /// be able to look up, and keep every transaction which can be finalized
+    /// without further signatures. Returns how many were kept.
+    pub fn bind(
+        &mut self,
+        compiled: &Compiled,
+        outpoint: OutPoint,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<usize, ExecutorError> {
+        let program = compiled.bind_psbt(
+            outpoint,
+            HashMap::new(),
+            self.broadcaster.clone(),
+            emulator,
+            PsbtVersion::V0,
+        )?;
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let mut n = 0;
+        for (path, obj) in program.program {
+            for tx in obj.txs {
+                let SapioStudioFormat::LinkedPSBT { psbt, .. } = tx;
+                let bytes =
+                    base64::decode(&psbt).map_err(|e| ExecutorError::Malformed(e.to_string()))?;
+                let psbt = PartiallySignedTransaction::consensus_decode(&bytes[..])
+                    .map_err(|e| ExecutorError::Malformed(e.to_string()))?;
+                if psbt.unsigned_tx.input.len() != 1 {
+                    continue;
+                }
+                if let Ok(psbt) = psbt.finalize(&secp) {
+                    let tx = psbt.extract_tx();
+                    // the template hash does not commit to the outpoint spent
+                    let key = (tx.input[0].previous_output, tx.get_ctv_hash(0));
+                    self.ready.insert(
+                        key,
+                        Ready {
+                            path: path.clone(),
+                            tx,
+                        },
+                    );
+                    n += 1;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// React to a watcher event, broadcasting the matured branch it reports
+    /// if it is finalized and the policy allows it. Returns the txid
+    /// broadcast, if any.
+    pub fn handle(&mut self, e: &WatchEvent) -> Result<Option<Txid>, ExecutorError> {
+        match e {
+            WatchEvent::TimelockMatured {
+                outpoint,
+                template,
+                label,
+                ..
+            } => {
+                let ready = match self.ready.get(&(*outpoint, *template)) {
+                    Some(r) => r,
+                    None => return Ok(None),
+                };
+                if !self.policy.allows(&ready.path.0, label.as_deref()) {
+                    return Ok(None);
+                }
+                let txid = self.broadcaster.add_tx(Arc::new(ready.tx.clone()))?;
+                Ok(Some(txid))
+            }
+            // once an output is spent, nothing else spending it can be
+            WatchEvent::BranchExecuted { outpoint, .. }
+            | WatchEvent::UnexpectedSpend { outpoint, .. } => {
+                self.ready.retain(|(o, _), _| o != outpoint);
+                Ok(None)
+            }
+            WatchEvent::TimelockNear { .. } => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    #[test]
+    fn allow_list_matches_subtrees() {
+        let path = |s: &str| Arc::new(EffectPath::try_from(s).unwrap());
+        let root = path("@root");
+        let recover = path("@root/recover");
+        let child = path("@root/recover/#0");
+        let other = path("@root/spend");
+        let policy = AllowList {
+            paths: vec![SArc(recover)],
+            labels: vec!["refund".into()],
+        };
+        assert!(policy.allows(&child, None));
+        assert!(!policy.allows(&other, None));
+        assert!(policy.allows(&other, Some("refund")));
+        assert!(!policy.allows(&root, None));
+    }
+}
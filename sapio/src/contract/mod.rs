@@ -12,10 +12,17 @@ pub mod abi;
 // TODO: get rid of this rexport?
 pub use abi::object;
 pub mod actions;
+pub mod compilation_cache;
 pub mod compiler;
 pub mod error;
+pub mod executor;
 pub use error::CompilationError;
 pub mod context;
+pub mod covenant;
+pub mod diagnostics;
+pub mod sim;
+pub mod store;
+pub mod watcher;
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
 pub use context::Context;
@@ -46,6 +53,20 @@ where
     declare! {then}
     declare! { updatable<> }
     declare! {finish}
+
+    /// Called before any branches are expanded during compilation.
+    /// Override to centralize sanity checks on the contract's arguments
+    /// (e.g., key counts vs thresholds) with a descriptive error.
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        Ok(())
+    }
+
+    /// The version of this contract's ABI, recorded in the compiled output.
+    /// Bump it whenever a change to the contract would make effects saved
+    /// against an earlier version mean something different.
+    fn abi_version(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// DynamicContract wraps a struct S with a set of methods (that can be constructed dynamically)
@@ -119,6 +140,14 @@ where
     fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>];
     /// obtain a reference to `Self::Ref` type.
     fn get_inner_ref<'a>(&'a self) -> &'a Self::Ref;
+    /// check the contract's arguments before compiling any branches.
+    fn validate(&self, _ctx: &Context) -> Result<(), CompilationError> {
+        Ok(())
+    }
+    /// the version of the contract's ABI, if it declares one.
+    fn abi_version(&self) -> Option<u64> {
+        None
+    }
 }
 
 impl<C> AnyContract for C
@@ -146,4 +175,10 @@ where
     fn get_inner_ref<'a>(&'a self) -> &Self::Ref {
         self
     }
+    fn validate(&self, ctx: &Context) -> Result<(), CompilationError> {
+        Contract::validate(self, ctx)
+    }
+    fn abi_version(&self) -> Option<u64> {
+        Contract::abi_version(self)
+    }
 }
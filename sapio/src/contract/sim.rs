@@ -0,0 +1,446 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! sapio-sim: step a compiled contract through a simulated chain.
+//!
+//! A [`Simulation`] tracks the unspent outputs of a contract and a simulated
+//! chain tip (height and median time past), along with the keys and
+//! preimages the simulating party can provide. At each step it lists the
+//! templates which could spend each output ([`Move`]s), whether they can be
+//! mined now, and if not what is missing or how long to wait. Applying a
+//! move confirms its transaction in the next block and adds its outputs.
+//!
+//! Only what is visible in the compiled contract is simulated: the
+//! conditions of miniscript leaves (opaque covenant leaves are never
+//! usable), and the template's own nLockTime and nSequence. Inputs of a
+//! template other than the contract's are assumed to be available.
+use super::abi::object::Object;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::{OutPoint, Transaction, Txid};
+use sapio_base::effects::EffectPath;
+use sapio_base::satisfaction::{Satisfiability, SpendingState};
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
+
+/// The default time between simulated blocks, in seconds
+pub const BLOCK_INTERVAL_SECS: u32 = 600;
+
+/// Errors from stepping a [`Simulation`]
+#[derive(Debug)]
+pub enum SimulationError {
+    /// The move does not refer to a live output and one of its templates
+    NoSuchMove,
+    /// The move cannot be made now, for the reasons given
+    NotSpendable(Satisfiability),
+}
+impl std::error::Error for SimulationError {}
+impl std::fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// An unspent output of the simulated contract
+#[derive(Clone, Debug)]
+pub struct LiveOutput<'a> {
+    /// where the output is
+    pub outpoint: OutPoint,
+    /// the contract governing it
+    pub contract: &'a Object,
+    /// its amount, if known (it is not for the funding output)
+    pub amount: Option<Amount>,
+    /// the height of the block it confirmed in
+    pub confirmed_height: u32,
+    /// the median time past of the block before that one
+    pub confirmed_time: u32,
+}
+
+/// A template which could spend a live output
+#[derive(Clone, Debug)]
+pub struct Move {
+    /// the index of the output in [`Simulation::live`]
+    pub output: usize,
+    /// the template's CTV hash
+    pub template: sha256::Hash,
+    /// true if the template is enforced by CTV, false if only suggested
+    pub ctv: bool,
+    /// the template's label, if it has one
+    pub label: Option<String>,
+    /// whether it can be mined in the next block
+    pub status: Satisfiability,
+}
+
+/// A transaction confirmed during a simulation
+#[derive(Clone, Debug)]
+pub struct SimulatedTx {
+    /// the path of the contract it spent
+    pub path: SArc<EffectPath>,
+    /// the template's CTV hash
+    pub template: sha256::Hash,
+    /// the template's label, if it has one
+    pub label: Option<String>,
+    /// the transaction, spending the simulated output
+    pub tx: Transaction,
+    /// the height it confirmed at
+    pub height: u32,
+}
+
+/// A contract being stepped through a simulated chain
+#[derive(Clone, Debug)]
+pub struct Simulation<'a> {
+    /// the chain tip, and the keys and preimages available. Its
+    /// confirmation fields are ignored, each output has its own.
+    pub state: SpendingState,
+    /// seconds of median time past each mined block adds
+    pub block_interval: u32,
+    live: Vec<LiveOutput<'a>>,
+    history: Vec<SimulatedTx>,
+}
+
+/// `c` as it applies to spending with the template `h`: CTV clauses for it
+/// are satisfied, and any others can't be
+fn bind_template(c: &Clause, h: Option<&sha256::Hash>) -> Clause {
+    match c {
+        Clause::TxTemplate(x) if Some(x) == h => Clause::Trivial,
+        Clause::TxTemplate(_) => Clause::Unsatisfiable,
+        Clause::And(cs) => Clause::And(cs.iter().map(|c| bind_template(c, h)).collect()),
+        Clause::Or(cs) => Clause::Or(cs.iter().map(|(w, c)| (*w, bind_template(c, h))).collect()),
+        Clause::Threshold(k, cs) => {
+            Clause::Threshold(*k, cs.iter().map(|c| bind_template(c, h)).collect())
+        }
+        c => c.clone(),
+    }
+}
+
+/// the timelocks `tx` itself imposes on spending the output at input 0
+fn tx_locks(tx: &Transaction) -> Clause {
+    let mut locks = vec![];
+    if tx.lock_time != 0 && tx.input.iter().any(|i| i.sequence != u32::MAX) {
+        locks.push(Clause::After(tx.lock_time));
+    }
+    if let Some(i) = tx.input.first() {
+        if tx.version >= 2 && i.sequence & (1 << 31) == 0 {
+            locks.push(Clause::Older(i.sequence));
+        }
+    }
+    Clause::And(locks)
+}
+
+impl<'a> Simulation<'a> {
+    /// Simulate `contract`, funded by `funding`. The funding output
+    /// confirmed at `state`'s confirmation if set, otherwise at its tip.
+    pub fn new(contract: &'a Object, funding: OutPoint, state: SpendingState) -> Self {
+        let live = vec![LiveOutput {
+            outpoint: funding,
+            contract,
+            amount: None,
+            confirmed_height: state.confirmed_height.unwrap_or(state.height),
+            confirmed_time: state.confirmed_time.unwrap_or(state.time),
+        }];
+        Simulation {
+            state,
+            block_interval: BLOCK_INTERVAL_SECS,
+            live,
+            history: vec![],
+        }
+    }
+    /// Set the seconds of median time past each mined block adds
+    pub fn with_block_interval(mut self, secs: u32) -> Self {
+        self.block_interval = secs;
+        self
+    }
+    /// The unspent outputs
+    pub fn live(&self) -> &[LiveOutput<'a>] {
+        &self.live
+    }
+    /// The transactions confirmed so far, in order
+    pub fn history(&self) -> &[SimulatedTx] {
+        &self.history
+    }
+
+    fn template(&self, output: usize, h: &sha256::Hash) -> Option<(&'a Template, bool)> {
+        let contract = self.live.get(output)?.contract;
+        contract
+            .ctv_to_tx
+            .get(h)
+            .map(|t| (t, true))
+            .or_else(|| contract.suggested_txs.get(h).map(|t| (t, false)))
+    }
+
+    fn status(&self, output: usize, template: &Template, ctv: bool) -> Satisfiability {
+        let out = &self.live[output];
+        let state = self
+            .state
+            .clone()
+            .with_confirmation(out.confirmed_height, out.confirmed_time);
+        let h = if ctv { Some(&template.ctv) } else { None };
+        let spend = Clause::Or(
+            out.contract
+                .spend_conditions()
+                .into_iter()
+                .filter_map(|(_, c)| c)
+                .map(|c| (1, bind_template(&c, h)))
+                .collect(),
+        );
+        state.evaluate(&Clause::And(vec![spend, tx_locks(&template.tx)]))
+    }
+
+    /// Every template which could spend a live output, in a stable order
+    pub fn moves(&self) -> Vec<Move> {
+        let mut moves = vec![];
+        for (i, out) in self.live.iter().enumerate() {
+            let mut templates: Vec<(&Template, bool)> = out
+                .contract
+                .ctv_to_tx
+                .values()
+                .map(|t| (t, true))
+                .chain(
+                    out.contract
+                        .suggested_txs
+                        .iter()
+                        .filter(|(h, _)| !out.contract.ctv_to_tx.contains_key(h))
+                        .map(|(_, t)| (t, false)),
+                )
+                .collect();
+            templates.sort_by_key(|(t, _)| t.ctv);
+            for (t, ctv) in templates {
+                moves.push(Move {
+                    output: i,
+                    template: t.ctv,
+                    ctv,
+                    label: t.metadata_map_s2s.label.clone(),
+                    status: self.status(i, t, ctv),
+                });
+            }
+        }
+        moves
+    }
+
+    /// Mine `n` empty blocks
+    pub fn mine_blocks(&mut self, n: u32) {
+        self.state.height += n;
+        self.state.time += n * self.block_interval;
+    }
+
+    /// The blocks to mine before a move with `status` can be made, if
+    /// nothing but waiting is missing
+    pub fn blocks_until(&self, status: &Satisfiability) -> Option<u32> {
+        if !status.is_possible() || !status.only_waiting() {
+            return None;
+        }
+        let interval = self.block_interval.max(1);
+        let for_height = status
+            .unlocks_at_height
+            .map_or(0, |h| h.saturating_sub(self.state.height));
+        let for_time = status.unlocks_at_time.map_or(0, |t| {
+            (t.saturating_sub(self.state.time) + interval - 1) / interval
+        });
+        Some(for_height.max(for_time))
+    }
+
+    /// Mine blocks until the soonest move which is only waiting on
+    /// timelocks can be made. Returns false if there is none.
+    pub fn fast_forward(&mut self) -> bool {
+        let soonest = self
+            .moves()
+            .iter()
+            .filter_map(|m| self.blocks_until(&m.status))
+            .filter(|n| *n > 0)
+            .min();
+        match soonest {
+            Some(n) => {
+                self.mine_blocks(n);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make `m`, confirming its transaction in the next block
+    pub fn apply(&mut self, m: &Move) -> Result<Txid, SimulationError> {
+        let (template, ctv) = self
+            .template(m.output, &m.template)
+            .ok_or(SimulationError::NoSuchMove)?;
+        let status = self.status(m.output, template, ctv);
+        if !status.is_satisfied() {
+            return Err(SimulationError::NotSpendable(status));
+        }
+        let spent = self.live.remove(m.output);
+        let mut tx = template.tx.clone();
+        tx.input[0].previous_output = spent.outpoint;
+        let txid = tx.txid();
+        let (height, time) = (self.state.height + 1, self.state.time);
+        self.mine_blocks(1);
+        for (vout, o) in template.outputs.iter().enumerate() {
+            self.live.push(LiveOutput {
+                outpoint: OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                contract: &o.contract,
+                amount: Some(o.amount),
+                confirmed_height: height,
+                confirmed_time: time,
+            });
+        }
+        self.history.push(SimulatedTx {
+            path: spent.contract.root_path.clone(),
+            template: m.template,
+            label: template.metadata_map_s2s.label.clone(),
+            tx,
+            height,
+        });
+        Ok(txid)
+    }
+
+    /// Step the simulation until nothing more can be done or `max_steps`
+    /// transactions have confirmed, mining blocks whenever every move is
+    /// waiting on a timelock. Each step, `choose` picks one of the moves
+    /// which can be made, or stops the simulation by returning None.
+    pub fn run<F>(&mut self, mut choose: F, max_steps: usize) -> &[SimulatedTx]
+    where
+        F: FnMut(&[Move]) -> Option<usize>,
+    {
+        while self.history.len() < max_steps {
+            let ready: Vec<Move> = self
+                .moves()
+                .into_iter()
+                .filter(|m| m.status.is_satisfied())
+                .collect();
+            if ready.is_empty() {
+                if self.fast_forward() {
+                    continue;
+                }
+                break;
+            }
+            match choose(&ready).and_then(|i| ready.get(i)) {
+                Some(m) => {
+                    self.apply(m).expect("Checked ready above");
+                }
+                None => break,
+            }
+        }
+        &self.history
+    }
+
+    /// Every sequence of transactions which could confirm (of at most
+    /// `max_steps` each), where any move may be waited for. Outputs are
+    /// spent in the order they were created, so executions differing only
+    /// in the interleaving of independent spends are listed once.
+    pub fn executions(&self, max_steps: usize) -> Vec<Vec<SimulatedTx>> {
+        let mut done = vec![];
+        let mut stack = vec![self.clone()];
+        while let Some(sim) = stack.pop() {
+            let moves: Vec<(Move, u32)> = sim
+                .moves()
+                .into_iter()
+                .filter_map(|m| sim.blocks_until(&m.status).map(|n| (m, n)))
+                .collect();
+            let first = moves.first().map(|(m, _)| m.output);
+            if first.is_none() || sim.history.len() >= max_steps {
+                done.push(sim.history);
+                continue;
+            }
+            for (m, wait) in moves.iter().filter(|(m, _)| Some(m.output) == first) {
+                let mut next = sim.clone();
+                next.mine_blocks(*wait);
+                if next.apply(m).is_ok() {
+                    stack.push(next);
+                }
+            }
+        }
+        done
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use crate::template::{Output, OutputMeta, TemplateMetadata};
+    use bitcoin::{Script, TxIn, TxOut, XOnlyPublicKey};
+    use miniscript::Descriptor;
+    use sapio_base::CTVHash;
+    use std::str::FromStr;
+    const K: &str = "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+
+    /// a suggested transaction spending after `delay` blocks to an OP_RETURN
+    fn suggested(delay: u32, label: &str) -> Template {
+        let end = Object::from_op_return(&label.as_bytes()[..]).unwrap();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                sequence: delay,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut metadata = TemplateMetadata::new();
+        metadata.label = Some(label.into());
+        Template {
+            guards: vec![],
+            ctv: tx.get_ctv_hash(0),
+            ctv_index: 0,
+            max: Amount::from_sat(0),
+            min_feerate_sats_vbyte: None,
+            metadata_map_s2s: metadata,
+            tx,
+            outputs: vec![Output {
+                amount: Amount::from_sat(0),
+                contract: end,
+                metadata: OutputMeta::default(),
+            }],
+            sibling_inputs: vec![],
+            external_amount: Amount::from_sat(0),
+        }
+    }
+
+    #[test]
+    fn steps_through_timelocked_paths() {
+        let k = XOnlyPublicKey::from_str(K).unwrap();
+        let d = Descriptor::<XOnlyPublicKey>::from_str(&format!("tr({})", K)).unwrap();
+        let mut o = Context::compiled_from_descriptor(d, None);
+        for t in [suggested(0, "now"), suggested(10, "later")] {
+            o.suggested_txs.insert(t.ctv, t);
+        }
+        let state = SpendingState::default().with_height(100);
+        // nothing can be done without the key
+        let sim = Simulation::new(&o, OutPoint::default(), state.clone());
+        assert!(sim.moves().iter().all(|m| !m.status.only_waiting()));
+        assert!(sim.executions(5).iter().all(|e| e.is_empty()));
+
+        let mut sim = Simulation::new(&o, OutPoint::default(), state.with_key(k));
+        let executions = sim.executions(5);
+        assert_eq!(executions.len(), 2);
+        let later = executions
+            .iter()
+            .find(|e| e[0].label.as_deref() == Some("later"))
+            .unwrap();
+        // confirmed at 100, so mined in the block 10 later
+        assert_eq!(later[0].height, 110);
+
+        // the later path can't be taken yet
+        let later = |ready: &[Move]| {
+            ready
+                .iter()
+                .position(|m| m.label.as_deref() == Some("later"))
+        };
+        assert!(sim.run(later, 5).is_empty());
+        assert!(sim.fast_forward());
+        assert_eq!(sim.state.height, 109);
+        let history = sim.run(later, 5);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].height, 110);
+        assert_eq!(sim.live().len(), 1);
+        assert!(sim.moves().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Persistent storage for deployed contracts, so that a long-lived process
+//! (e.g., a vault watchtower) can be restarted without losing track of what
+//! it compiled, where it was bound, and what has happened on chain since.
+//!
+//! With the `sled-store` feature, [`SledContractStore`] keeps contracts (and the
+//! transactions they reference, as a [`TxIndex`]) in a sled database.
+use super::Compiled;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::sha256;
+use bitcoin::OutPoint;
+use sapio_base::effects::MapEffectDB;
+#[cfg(feature = "sled-store")]
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Errors from a [`ContractStore`]
+#[derive(Debug)]
+pub enum StoreError {
+    /// A stored contract could not be (de)serialized
+    Serialization(serde_json::Error),
+    /// The backing database failed
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+impl std::error::Error for StoreError {}
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Serialization(e)
+    }
+}
+
+/// # Observed Spend
+/// A contract output seen spent on chain.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ObservedSpend {
+    /// the output spent
+    pub outpoint: OutPoint,
+    /// the spending transaction
+    pub txid: Txid,
+    /// the template the spend matched, if it matched one
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub template: Option<sha256::Hash>,
+    /// the height it confirmed at, if it has
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub height: Option<u32>,
+}
+
+/// # Contract State
+/// Everything needed to resume operating a deployed contract.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ContractState {
+    /// the compiled contract
+    pub compiled: Compiled,
+    /// the effects it was compiled with, for recompiling with more
+    #[serde(default)]
+    pub effects: MapEffectDB,
+    /// the output funding it, once bound
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub funding: Option<OutPoint>,
+    /// spends of the contract's outputs seen so far, in order
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub observed_spends: Vec<ObservedSpend>,
+}
+
+impl ContractState {
+    /// A newly compiled, unbound contract
+    pub fn new(compiled: Compiled, effects: MapEffectDB) -> Self {
+        ContractState {
+            compiled,
+            effects,
+            funding: None,
+            observed_spends: vec![],
+        }
+    }
+    /// The spend of `o` observed so far, if any
+    pub fn spend_of(&self, o: &OutPoint) -> Option<&ObservedSpend> {
+        self.observed_spends.iter().find(|s| s.outpoint == *o)
+    }
+    /// Record a spend, replacing any earlier one of the same output (e.g.,
+    /// once it confirms, or after a reorg)
+    pub fn record_spend(&mut self, spend: ObservedSpend) {
+        match self
+            .observed_spends
+            .iter_mut()
+            .find(|s| s.outpoint == spend.outpoint)
+        {
+            Some(s) => *s = spend,
+            None => self.observed_spends.push(spend),
+        }
+    }
+}
+
+/// A store of deployed contracts, keyed by a caller chosen id
+pub trait ContractStore {
+    /// save (or overwrite) the state of contract `id`
+    fn save(&self, id: &str, state: &ContractState) -> Result<(), StoreError>;
+    /// load the state of contract `id`
+    fn load(&self, id: &str) -> Result<Option<ContractState>, StoreError>;
+    /// forget contract `id`
+    fn remove(&self, id: &str) -> Result<(), StoreError>;
+    /// the ids of every stored contract
+    fn ids(&self) -> Result<Vec<String>, StoreError>;
+    /// record a spend against contract `id`, if it is stored
+    fn record_spend(&self, id: &str, spend: ObservedSpend) -> Result<(), StoreError> {
+        if let Some(mut state) = self.load(id)? {
+            state.record_spend(spend);
+            self.save(id, &state)?;
+        }
+        Ok(())
+    }
+}
+
+/// A ContractStore held in memory, e.g. for tests
+#[derive(Default)]
+pub struct MemoryContractStore {
+    entries: Mutex<HashMap<String, ContractState>>,
+}
+
+impl MemoryContractStore {
+    fn entries(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, HashMap<String, ContractState>>, StoreError> {
+        self.entries
+            .lock()
+            .map_err(|e| StoreError::Backend(e.to_string().into()))
+    }
+}
+
+impl ContractStore for MemoryContractStore {
+    fn save(&self, id: &str, state: &ContractState) -> Result<(), StoreError> {
+        self.entries()?.insert(id.into(), state.clone());
+        Ok(())
+    }
+    fn load(&self, id: &str) -> Result<Option<ContractState>, StoreError> {
+        Ok(self.entries()?.get(id).cloned())
+    }
+    fn remove(&self, id: &str) -> Result<(), StoreError> {
+        self.entries()?.remove(id);
+        Ok(())
+    }
+    fn ids(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self.entries()?.keys().cloned().collect())
+    }
+}
+
+/// A ContractStore in a sled database. Contracts are kept as JSON in the
+/// `contracts` tree, and transactions added as a [`TxIndex`] in `txs`, so
+/// that bound contracts can be rebound after a restart without a node.
+#[cfg(feature = "sled-store")]
+pub struct SledContractStore {
+    contracts: sled::Tree,
+    txs: sled::Tree,
+}
+
+#[cfg(feature = "sled-store")]
+fn backend(e: sled::Error) -> StoreError {
+    StoreError::Backend(Box::new(e))
+}
+
+#[cfg(feature = "sled-store")]
+impl SledContractStore {
+    /// Open (creating if needed) the database at `path`
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self, StoreError> {
+        Self::from_db(&sled::open(path).map_err(backend)?)
+    }
+    /// Use the trees of an already open database
+    pub fn from_db(db: &sled::Db) -> Result<Self, StoreError> {
+        Ok(SledContractStore {
+            contracts: db.open_tree("contracts").map_err(backend)?,
+            txs: db.open_tree("txs").map_err(backend)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled-store")]
+impl ContractStore for SledContractStore {
+    fn save(&self, id: &str, state: &ContractState) -> Result<(), StoreError> {
+        self.contracts
+            .insert(id.as_bytes(), serde_json::to_vec(state)?)
+            .map_err(backend)?;
+        self.contracts.flush().map_err(backend)?;
+        Ok(())
+    }
+    fn load(&self, id: &str) -> Result<Option<ContractState>, StoreError> {
+        match self.contracts.get(id.as_bytes()).map_err(backend)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+    fn remove(&self, id: &str) -> Result<(), StoreError> {
+        self.contracts.remove(id.as_bytes()).map_err(backend)?;
+        Ok(())
+    }
+    fn ids(&self) -> Result<Vec<String>, StoreError> {
+        self.contracts
+            .iter()
+            .keys()
+            .map(|k| {
+                k.map(|k| String::from_utf8_lossy(&k).into_owned())
+                    .map_err(backend)
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "sled-store")]
+impl TxIndex for SledContractStore {
+    fn lookup_tx(&self, b: &Txid) -> Result<std::sync::Arc<bitcoin::Transaction>, TxIndexError> {
+        let v = self
+            .txs
+            .get(&b[..])
+            .map_err(|e| TxIndexError::RpcError(Box::new(e)))?
+            .ok_or(TxIndexError::UnknownTxid(*b))?;
+        bitcoin::consensus::deserialize(&v)
+            .map(std::sync::Arc::new)
+            .map_err(|e| TxIndexError::RpcError(Box::new(e)))
+    }
+    fn add_tx(&self, tx: std::sync::Arc<bitcoin::Transaction>) -> Result<Txid, TxIndexError> {
+        let txid = tx.txid();
+        self.txs
+            .insert(&txid[..], bitcoin::consensus::serialize(&*tx))
+            .map_err(|e| TxIndexError::RpcError(Box::new(e)))?;
+        Ok(txid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn memory_store_roundtrip() -> Result<(), StoreError> {
+        let compiled = Compiled::from_op_return(&b"store"[..]).expect("short enough to fit");
+        let store = MemoryContractStore::default();
+        store.save("a", &ContractState::new(compiled, Default::default()))?;
+        let spend = ObservedSpend {
+            outpoint: OutPoint::default(),
+            txid: Default::default(),
+            template: None,
+            height: None,
+        };
+        store.record_spend("a", spend.clone())?;
+        let state = store.load("a")?.expect("was saved");
+        assert_eq!(state.spend_of(&OutPoint::default()), Some(&spend));
+        assert_eq!(store.ids()?, vec!["a".to_string()]);
+        // round trips through JSON, as a persistent store would
+        let _: ContractState = serde_json::from_str(&serde_json::to_string(&state)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Follow a bound contract on chain, tracking which of its branches were
+//! executed and reporting when timelocked branches become (or are about to
+//! become) spendable.
+//!
+//! A [`ContractWatcher`] can either be fed transactions and tips as they
+//! arrive (e.g., from bitcoind's `rawtx` and `hashblock` zmq notifications)
+//! or [`poll`](ContractWatcher::poll) a [`TxIndex`] (e.g., an Esplora
+//! instance) which can look up spends.
+use super::abi::broadcast::BroadcastAfter;
+use super::store::ObservedSpend;
+use super::Compiled;
+use crate::template::Template;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::sha256;
+use bitcoin::{OutPoint, Transaction};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::txindex::{ChainTip, TxIndex, TxIndexError};
+use sapio_base::CTVHash;
+use std::collections::{BTreeMap, HashSet};
+
+/// Something that happened to a watched contract
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    /// An output was spent by one of its contract's templates
+    BranchExecuted {
+        /// the path of the contract spent
+        path: SArc<EffectPath>,
+        /// the output spent
+        outpoint: OutPoint,
+        /// the spending transaction
+        txid: Txid,
+        /// the template it matched
+        template: sha256::Hash,
+        /// the template's label, if it has one
+        label: Option<String>,
+        /// the height it confirmed at, if it has
+        height: Option<u32>,
+    },
+    /// An output was spent by a transaction matching none of its templates
+    UnexpectedSpend {
+        /// the path of the contract spent
+        path: SArc<EffectPath>,
+        /// the output spent
+        outpoint: OutPoint,
+        /// the spending transaction
+        txid: Txid,
+        /// the height it confirmed at, if it has
+        height: Option<u32>,
+    },
+    /// A template's timelock matures within the warning window
+    TimelockNear {
+        /// the path of the contract the template spends
+        path: SArc<EffectPath>,
+        /// the output the template spends
+        outpoint: OutPoint,
+        /// the template
+        template: sha256::Hash,
+        /// the template's label, if it has one
+        label: Option<String>,
+        /// the height the template may first be mined at
+        height: u32,
+    },
+    /// A template's timelock has matured, so it may be mined in the next block
+    TimelockMatured {
+        /// the path of the contract the template spends
+        path: SArc<EffectPath>,
+        /// the output the template spends
+        outpoint: OutPoint,
+        /// the template
+        template: sha256::Hash,
+        /// the template's label, if it has one
+        label: Option<String>,
+    },
+}
+
+impl WatchEvent {
+    /// The spend this event reports, for recording in a
+    /// [`ContractStore`](super::store::ContractStore)
+    pub fn observed_spend(&self) -> Option<ObservedSpend> {
+        match self {
+            WatchEvent::BranchExecuted {
+                outpoint,
+                txid,
+                template,
+                height,
+                ..
+            } => Some(ObservedSpend {
+                outpoint: *outpoint,
+                txid: *txid,
+                template: Some(*template),
+                height: *height,
+            }),
+            WatchEvent::UnexpectedSpend {
+                outpoint,
+                txid,
+                height,
+                ..
+            } => Some(ObservedSpend {
+                outpoint: *outpoint,
+                txid: *txid,
+                template: None,
+                height: *height,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An unspent output of the contract
+struct Live {
+    contract: Compiled,
+    /// the height the output confirmed at, if it has
+    height: Option<u32>,
+}
+
+/// Tracks a bound contract's unspent outputs, advancing along its
+/// `EffectPath` tree as its templates are seen on chain.
+pub struct ContractWatcher<'a> {
+    live: BTreeMap<OutPoint, Live>,
+    /// how many blocks before a timelock matures to send
+    /// [`WatchEvent::TimelockNear`]
+    pub warn_blocks: u32,
+    warned: HashSet<(OutPoint, sha256::Hash)>,
+    matured: HashSet<(OutPoint, sha256::Hash)>,
+    sink: Box<dyn FnMut(WatchEvent) + 'a>,
+}
+
+impl<'a> ContractWatcher<'a> {
+    /// Watch `compiled`, bound to `outpoint`, sending events to `sink` (e.g.,
+    /// a closure sending on a channel). Warns 6 blocks ahead of timelocks.
+    pub fn new<F>(compiled: Compiled, outpoint: OutPoint, sink: F) -> Self
+    where
+        F: FnMut(WatchEvent) + 'a,
+    {
+        let mut live = BTreeMap::new();
+        live.insert(
+            outpoint,
+            Live {
+                contract: compiled,
+                height: None,
+            },
+        );
+        ContractWatcher {
+            live,
+            warn_blocks: 6,
+            warned: HashSet::new(),
+            matured: HashSet::new(),
+            sink: Box::new(sink),
+        }
+    }
+
+    /// The contract's unspent outputs and the paths of the contracts at them
+    pub fn unspent(&self) -> impl Iterator<Item = (&OutPoint, &SArc<EffectPath>)> {
+        self.live.iter().map(|(o, l)| (o, &l.contract.root_path))
+    }
+
+    /// Whether every output of the contract has been spent
+    pub fn is_done(&self) -> bool {
+        self.live.is_empty()
+    }
+
+    /// Process a transaction, seen in the mempool (`height` None) or in a
+    /// block at `height`. Seeing a transaction again once it confirms
+    /// records the confirmation height of the outputs it created.
+    pub fn observe_tx(&mut self, tx: &Transaction, height: Option<u32>) {
+        let txid = tx.txid();
+        if height.is_some() {
+            for (o, l) in self.live.iter_mut() {
+                if o.txid == txid && l.height.is_none() {
+                    l.height = height;
+                }
+            }
+        }
+        for (idx, input) in tx.input.iter().enumerate() {
+            let spent = match self.live.remove(&input.previous_output) {
+                Some(l) => l,
+                None => continue,
+            };
+            let outpoint = input.previous_output;
+            let path = spent.contract.root_path.clone();
+            match find_template(&spent.contract, tx, idx as u32) {
+                Some((template, vouts)) => {
+                    for (out, vout) in template.outputs.iter().zip(vouts) {
+                        self.live.insert(
+                            OutPoint::new(txid, vout),
+                            Live {
+                                contract: out.contract.clone(),
+                                height,
+                            },
+                        );
+                    }
+                    (self.sink)(WatchEvent::BranchExecuted {
+                        path,
+                        outpoint,
+                        txid,
+                        template: template.hash(),
+                        label: template.metadata_map_s2s.label.clone(),
+                        height,
+                    })
+                }
+                None => (self.sink)(WatchEvent::UnexpectedSpend {
+                    path,
+                    outpoint,
+                    txid,
+                    height,
+                }),
+            }
+        }
+    }
+
+    /// Process a new chain tip, reporting timelocks nearing or reaching
+    /// maturity on confirmed outputs. Each is reported once.
+    pub fn observe_tip(&mut self, tip: ChainTip) {
+        let mut events = vec![];
+        for (o, l) in &self.live {
+            let height = match l.height {
+                Some(h) => h,
+                None => continue,
+            };
+            for t in templates(&l.contract) {
+                let after = BroadcastAfter::compute(&t.tx, Some(height), Some(tip));
+                let unlock = match after.height {
+                    Some(h) if h > height => h,
+                    _ => continue,
+                };
+                let key = (*o, t.hash());
+                if after.ready == Some(true) {
+                    if self.matured.insert(key) {
+                        events.push(WatchEvent::TimelockMatured {
+                            path: l.contract.root_path.clone(),
+                            outpoint: *o,
+                            template: t.hash(),
+                            label: t.metadata_map_s2s.label.clone(),
+                        });
+                    }
+                } else if unlock <= tip.height + 1 + self.warn_blocks && self.warned.insert(key) {
+                    events.push(WatchEvent::TimelockNear {
+                        path: l.contract.root_path.clone(),
+                        outpoint: *o,
+                        template: t.hash(),
+                        label: t.metadata_map_s2s.label.clone(),
+                        height: unlock,
+                    });
+                }
+            }
+        }
+        events.into_iter().for_each(&mut self.sink);
+    }
+
+    /// Query `index` for confirmations and spends of every unspent output,
+    /// then for the chain tip. Requires an index which implements
+    /// [`TxIndex::lookup_spend`] to see spends.
+    pub fn poll(&mut self, index: &dyn TxIndex) -> Result<(), TxIndexError> {
+        let unconfirmed: HashSet<Txid> = self
+            .live
+            .iter()
+            .filter(|(_, l)| l.height.is_none())
+            .map(|(o, _)| o.txid)
+            .collect();
+        for txid in unconfirmed {
+            if let Some(h) = index.lookup_confirmation_height(&txid)? {
+                for (_, l) in self.live.iter_mut().filter(|(o, _)| o.txid == txid) {
+                    l.height = Some(h);
+                }
+            }
+        }
+        // spends may create new outputs which are themselves spent already,
+        // so repeat until nothing changes
+        let mut checked = HashSet::new();
+        loop {
+            let unchecked: Vec<OutPoint> = self
+                .live
+                .keys()
+                .filter(|o| !checked.contains(*o))
+                .cloned()
+                .collect();
+            if unchecked.is_empty() {
+                break;
+            }
+            for o in unchecked {
+                checked.insert(o);
+                if let Some(txid) = index.lookup_spend(&o)? {
+                    let tx = index.lookup_tx(&txid)?;
+                    let height = index.lookup_confirmation_height(&txid)?;
+                    self.observe_tx(&tx, height);
+                }
+            }
+        }
+        if let Some(tip) = index.chain_tip()? {
+            self.observe_tip(tip);
+        }
+        Ok(())
+    }
+}
+
+fn templates(c: &Compiled) -> impl Iterator<Item = &Template> {
+    c.ctv_to_tx.values().chain(c.suggested_txs.values())
+}
+
+/// Find the template of `c` which `tx` (spending `c` at input `idx`)
+/// executes, and the vout of each of the template's outputs. CTV templates
+/// are matched by hash; otherwise every output of the template must appear
+/// in `tx`, which may have more (e.g., change added when paying fees).
+fn find_template<'c>(
+    c: &'c Compiled,
+    tx: &Transaction,
+    idx: u32,
+) -> Option<(&'c Template, Vec<u32>)> {
+    let hash = tx.get_ctv_hash(idx);
+    if let Some(t) = c
+        .ctv_to_tx
+        .get(&hash)
+        .or_else(|| c.suggested_txs.get(&hash))
+    {
+        return Some((t, (0..t.outputs.len() as u32).collect()));
+    }
+    templates(c).find_map(|t| {
+        let mut used = HashSet::new();
+        let vouts =
+            t.tx.output
+                .iter()
+                .map(|want| {
+                    let (vout, _) = tx
+                        .output
+                        .iter()
+                        .enumerate()
+                        .find(|(i, have)| *have == want && !used.contains(i))?;
+                    used.insert(vout);
+                    Some(vout as u32)
+                })
+                .collect::<Option<Vec<u32>>>()?;
+        Some((t, vouts))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{Script, TxIn, TxOut, Witness};
+    #[test]
+    fn reports_unexpected_spend() {
+        let compiled = Compiled::from_op_return(&b"watch"[..]).expect("short enough to fit");
+        let path = compiled.root_path.clone();
+        let funding = OutPoint::default();
+        let mut events = vec![];
+        let mut watcher = ContractWatcher::new(compiled, funding, |e| events.push(e));
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: funding,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new(),
+            }],
+        };
+        watcher.observe_tx(&tx, Some(100));
+        assert!(watcher.is_done());
+        drop(watcher);
+        assert_eq!(
+            events,
+            vec![WatchEvent::UnexpectedSpend {
+                path,
+                outpoint: funding,
+                txid: tx.txid(),
+                height: Some(100),
+            }]
+        );
+    }
+}
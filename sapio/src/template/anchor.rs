@@ -0,0 +1,117 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Anchor outputs, so that templates whose fees are fixed at compile time
+//! can be fee-bumped with a CPFP child.
+use super::{Template, TemplateMetadata};
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut, Txid, Witness, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The key the anchors of a template are listed under in its metadata
+pub const ANCHOR_METADATA_KEY: &str = "anchor_outputs";
+/// The amount of a pay-to-anchor output, its dust limit
+pub const P2A_ANCHOR_SATS: u64 = 240;
+/// The amount of a keyed anchor, the dust limit of a taproot output
+pub const KEY_ANCHOR_SATS: u64 = 330;
+
+/// The witness program of a pay-to-anchor output
+pub const P2A_PROGRAM: [u8; 2] = [0x4e, 0x73];
+
+/// # Anchor Kind
+/// Who may spend an anchor output.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnchorKind {
+    /// # Pay to Anchor
+    /// Anyone may spend it (with an empty witness), so any party can bump
+    Ephemeral,
+    /// # Keyed Anchor
+    /// A key-path only taproot output, so only the key's owner can bump
+    Key {
+        /// the key which may spend the anchor
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+}
+
+impl AnchorKind {
+    /// The amount an anchor of this kind should carry
+    pub fn amount(&self) -> u64 {
+        match self {
+            AnchorKind::Ephemeral => P2A_ANCHOR_SATS,
+            AnchorKind::Key { .. } => KEY_ANCHOR_SATS,
+        }
+    }
+}
+
+/// The script of a pay-to-anchor output
+pub fn p2a_script() -> Script {
+    Builder::new()
+        .push_opcode(opcodes::all::OP_PUSHNUM_1)
+        .push_slice(&P2A_PROGRAM)
+        .into_script()
+}
+
+/// # Anchor Output
+/// An anchor in a template's transaction.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnchorOutput {
+    /// the output's index
+    pub vout: u32,
+    /// who may spend it
+    pub kind: AnchorKind,
+}
+
+impl AnchorOutput {
+    /// A child transaction spending this anchor of `txid`, to which the fee
+    /// payer adds their own inputs and outputs before signing. The anchor's
+    /// value is left to the fee payer's change.
+    pub fn child_psbt(&self, txid: Txid, out: TxOut) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid,
+                    vout: self.vout,
+                },
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFD,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        };
+        let mut psbt =
+            PartiallySignedTransaction::from_unsigned_tx(tx).expect("unsigned by construction");
+        psbt.inputs[0].witness_utxo = Some(out);
+        if let AnchorKind::Key { key } = self.kind {
+            psbt.inputs[0].tap_internal_key = Some(key);
+        }
+        psbt
+    }
+}
+
+impl TemplateMetadata {
+    /// The anchor outputs recorded in this metadata
+    pub fn anchor_outputs(&self) -> Vec<AnchorOutput> {
+        self.extra
+            .get(ANCHOR_METADATA_KEY)
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl Template {
+    /// The anchor outputs added to this template
+    pub fn anchor_outputs(&self) -> Vec<AnchorOutput> {
+        self.metadata_map_s2s.anchor_outputs()
+    }
+}
@@ -5,30 +5,56 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Interactive Transaction Template Builder
-pub use super::{Output, OutputMeta};
+use super::anchor::{p2a_script, AnchorKind, AnchorOutput, ANCHOR_METADATA_KEY};
+use super::output::OP_RETURN_DATA_KEY;
+use super::version::{ContractVersion, CONTRACT_VERSION_METADATA_KEY};
+pub use super::{Output, OutputMeta, SiblingInput};
 use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use crate::contract::diagnostics::{Diagnostic, WarningKind};
+use crate::contract::{Compilable, CompilationError, Compiled, Context};
+use crate::util::amountrange::dust_threshold;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::hex::ToHex;
 use bitcoin::util::amount::Amount;
 use bitcoin::VarInt;
 use bitcoin::Witness;
 use miniscript::DescriptorTrait;
 use sapio_base::effects::PathFragment;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::simp::OnChainSIMP;
 use sapio_base::timelocks::*;
 use sapio_base::CTVHash;
 use sapio_base::Clause;
+use serde::Serialize;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
+/// Outputs below this multiple of the dust threshold of their script type
+/// (see [`dust_threshold`]) raise a [`WarningKind::NearDustOutput`]
+pub const NEAR_DUST_WARNING_MULTIPLE: u64 = 2;
+
+/// The sequence of an input signaling replaceability (BIP-125) without a
+/// relative lock time
+pub const SEQUENCE_RBF: u32 = 0xFFFF_FFFD;
+/// The sequence of an input not signaling replaceability, which still
+/// enables the transaction's lock time
+pub const SEQUENCE_NO_RBF: u32 = 0xFFFF_FFFE;
+
 /// Builder can be used to interactively put together a transaction template before
 /// finalizing into a Template.
 pub struct Builder {
     guards: Vec<Clause>,
     sequences: Vec<Option<AnyRelTimeLock>>,
+    sequence_defaults: Vec<Option<u32>>,
+    rbf: Option<bool>,
     outputs: Vec<Output>,
+    sibling_inputs: Vec<SiblingInput>,
     version: i32,
     lock_time: Option<AnyAbsTimeLock>,
     ctx: Context,
     fees: Amount,
+    /// funds brought in with `add_amount`, including sibling inputs'
+    added: Amount,
     min_feerate: Option<Amount>,
     // Metadata Fields:
     metadata: TemplateMetadata,
@@ -40,11 +66,15 @@ impl Builder {
         Builder {
             guards: Vec::new(),
             sequences: vec![None],
+            sequence_defaults: vec![None],
+            rbf: None,
             outputs: vec![],
+            sibling_inputs: vec![],
             version: 2,
             lock_time: None,
             metadata: TemplateMetadata::new(),
             fees: Amount::from_sat(0),
+            added: Amount::from_sat(0),
             min_feerate: None,
             ctx,
         }
@@ -61,38 +91,150 @@ impl Builder {
         Ok(self)
     }
 
-    /// reduce the amount availble in the builder's context, and add to the fees
-    pub fn add_fees(self, amount: Amount) -> Result<Self, CompilationError> {
-        let mut c = self.spend_amount(amount)?;
-        c.fees += amount;
-        Ok(c)
+    /// reduce the amount availble in the builder's context, and add to the fees.
+    /// Funds reserved for fees in the context are used first.
+    pub fn add_fees(mut self, amount: Amount) -> Result<Self, CompilationError> {
+        self.ctx = self.ctx.spend_fees(amount)?;
+        self.fees += amount;
+        Ok(self)
+    }
+
+    /// Reserve fees for this template at the context's estimated feerate for
+    /// confirmation within `target_blocks`. Call after every output has
+    /// been added: the size is estimated from the template as it stands,
+    /// excluding the witness of the spent input. Under strict determinism
+    /// only a deterministic estimator may be used.
+    pub fn add_fee_for_rate(self, target_blocks: u16) -> Result<Self, CompilationError> {
+        let estimator = self
+            .ctx
+            .fee_estimator()
+            .ok_or(CompilationError::NoFeeEstimator)?;
+        if !estimator.is_deterministic() {
+            self.ctx.nondeterministic("fee estimator")?;
+        }
+        let feerate = estimator.estimate_feerate(target_blocks)?;
+        let fees = feerate
+            .checked_mul(self.estimate_tx_size())
+            .ok_or(CompilationError::OutOfFunds)?;
+        self.add_fees(fees)
     }
 
     /// Creates a new Output, forcing the compilation of the compilable object and defaulting
     /// metadata if not provided to blank.
     pub fn add_output(
-        mut self,
+        self,
         amount: Amount,
-        contract: &dyn crate::contract::Compilable,
+        contract: &dyn Compilable,
         metadata: Option<OutputMeta>,
     ) -> Result<Self, CompilationError> {
+        self.add_output_with(amount, metadata, false, |ctx| contract.compile(ctx))
+    }
+
+    /// Like [`Builder::add_output`], but reuses a previous compilation of
+    /// `contract` from the context's compilation cache when possible.
+    pub fn add_output_cached<C: crate::contract::Compilable + Serialize>(
+        self,
+        amount: Amount,
+        contract: &C,
+        metadata: Option<OutputMeta>,
+    ) -> Result<Self, CompilationError> {
+        self.add_output_with(amount, metadata, false, |ctx| ctx.compile_cached(contract))
+    }
+
+    fn add_output_with<F>(
+        mut self,
+        amount: Amount,
+        metadata: Option<OutputMeta>,
+        near_dust_ok: bool,
+        compile: F,
+    ) -> Result<Self, CompilationError>
+    where
+        F: FnOnce(Context) -> Result<Compiled, CompilationError>,
+    {
         let subctx = self
             .ctx
             .derive(PathFragment::Branch(self.outputs.len() as u64))?
             .with_amount(amount)?;
         let mut ret = self.spend_amount(amount)?;
+        let path = subctx.path().clone();
+        let diagnostics = subctx.diagnostics().clone();
+        let contract = compile(subctx)?;
+        let dust = dust_threshold(&contract.address.clone().into());
+        if amount < dust {
+            return Err(CompilationError::DustOutput(path, amount));
+        }
+        if amount < dust * NEAR_DUST_WARNING_MULTIPLE
+            && !near_dust_ok
+            && !matches!(contract.address, ExtendedAddress::OpReturn(_))
+        {
+            diagnostics.push(Diagnostic {
+                path: SArc(path),
+                kind: WarningKind::NearDustOutput,
+                message: format!("output of {} is close to the dust limit", amount),
+            });
+        }
         ret.outputs.push(Output {
             amount: amount,
-            contract: contract.compile(subctx)?,
+            contract,
             metadata: metadata.unwrap_or_else(Default::default),
         });
         Ok(ret)
     }
 
+    /// Adds an anchor output (see [`AnchorKind`]) which a CPFP child can
+    /// spend to bump this template's fees, and records it in the template
+    /// metadata. The anchor's amount is taken from the context's funds.
+    pub fn add_anchor_output(self, kind: AnchorKind) -> Result<Self, CompilationError> {
+        let vout = self.outputs.len() as u32;
+        let amount = Amount::from_sat(kind.amount());
+        let mut b = match kind {
+            AnchorKind::Ephemeral => {
+                let anchor = Compiled::from_script(p2a_script(), None, self.ctx.network)?;
+                self.add_output_with(amount, None, true, |ctx| anchor.compile(ctx))?
+            }
+            AnchorKind::Key { key } => {
+                self.add_output_with(amount, None, true, |ctx| key.compile(ctx))?
+            }
+        };
+        let mut anchors = b.metadata.anchor_outputs();
+        anchors.push(AnchorOutput { vout, kind });
+        b.metadata.extra.insert(
+            ANCHOR_METADATA_KEY.into(),
+            serde_json::to_value(anchors).map_err(CompilationError::SerializationError)?,
+        );
+        Ok(b)
+    }
+
+    /// Records a hash preimage the contract's input reveals when spent by
+    /// this template (e.g., to claim a `Clause::Hash160` lock). It is added
+    /// to the bound PSBT under each hash lock it opens.
+    pub fn add_preimage(mut self, preimage: &[u8]) -> Result<Self, CompilationError> {
+        self.metadata = self.metadata.add_preimage(preimage)?;
+        Ok(self)
+    }
+
+    /// Sends all funds remaining in the builder's context to `contract` as a
+    /// change output. Should be called after all fixed outputs have been
+    /// added and fees have been reserved (e.g., via `add_fees`).
+    ///
+    /// Returns [`CompilationError::ChangeIsDust`] if the remainder is below
+    /// the [`dust_threshold`] of `contract`'s script.
+    pub fn add_change_to(self, contract: &dyn Compilable) -> Result<Self, CompilationError> {
+        let remaining = self.ctx.funds();
+        self.add_output_with(remaining, None, false, |ctx| {
+            let compiled = contract.compile(ctx)?;
+            if remaining < dust_threshold(&compiled.address.clone().into()) {
+                return Err(CompilationError::ChangeIsDust(remaining));
+            }
+            Ok(compiled)
+        })
+    }
+
     /// adds available funds to the builder's context object.
     /// TODO: Make guarantee there is some external input?
     pub fn add_amount(mut self, a: Amount) -> Self {
         self.ctx = self.ctx.add_amount(a);
+        self.added += a;
         self
     }
 
@@ -100,8 +242,67 @@ impl Builder {
     /// set_sequence(-1, ...) to fill in the back.
     pub fn add_sequence(mut self) -> Self {
         self.sequences.push(None);
+        self.sequence_defaults.push(None);
         self
     }
+
+    /// Adds an input funded by another contract, `contract`, which must
+    /// carry `amount`. Its funds become available in the builder's context.
+    /// The outpoint is supplied when the template is bound (see
+    /// [`Object::bind_psbt`](crate::contract::object::Object::bind_psbt)).
+    pub fn add_sibling_input(
+        self,
+        metadata: OutputMeta,
+        amount: Amount,
+        contract: &Compiled,
+    ) -> Self {
+        let mut b = self.add_sequence().add_amount(amount);
+        b.sibling_inputs.push(SiblingInput {
+            input: (b.sequences.len() - 1) as u32,
+            amount,
+            contract: contract.clone(),
+            metadata,
+        });
+        b
+    }
+
+    /// Signal (or don't) replaceability on every input without a relative
+    /// lock time or a sequence default. Inputs with a relative lock time
+    /// always signal replaceability, per BIP-125. If never called, such
+    /// inputs get a zero relative time lock, which signals replaceability.
+    pub fn set_rbf(mut self, rbf: bool) -> Self {
+        self.rbf = Some(rbf);
+        self
+    }
+
+    /// Set the raw sequence of an input, used if no relative lock time is set
+    /// on it with `set_sequence`.
+    ///
+    /// Negative indexing allows us to work from the back element easily
+    pub fn set_sequence_default(mut self, ii: isize, seq: u32) -> Result<Self, CompilationError> {
+        let i = if ii >= 0 {
+            ii
+        } else {
+            self.sequence_defaults.len() as isize + ii
+        } as usize;
+        match self.sequence_defaults.get_mut(i) {
+            Some(d) => *d = Some(seq),
+            None => return Err(CompilationError::NoSuchSequence),
+        }
+        Ok(self)
+    }
+
+    /// Set the lock time to `tip`, the current height, so the transaction
+    /// can't be mined in a reorg of earlier blocks (anti fee sniping). Only
+    /// meaningful for suggested transactions built close to when they are
+    /// broadcast. A later height lock time already set is kept, as is a
+    /// time based one.
+    pub fn set_anti_fee_sniping(self, tip: AbsHeight) -> Result<Self, CompilationError> {
+        match self.lock_time {
+            Some(AnyAbsTimeLock::AT(_)) => Ok(self),
+            _ => self.set_lock_time(tip.into()),
+        }
+    }
     /// set_sequence adds a height or time based relative lock time to the
     /// template. If a lock time is already set, it will check if it is of the
     /// same kind. Differing kinds will throw an error. Otherwise, it will merge
@@ -115,15 +316,11 @@ impl Builder {
             self.sequences.len() as isize + ii
         } as usize;
         match self.sequences.get_mut(i).as_mut() {
-            Some(Some(seq)) => match (*seq, s) {
-                (a @ AnyRelTimeLock::RH(_), b @ AnyRelTimeLock::RH(_)) => {
-                    *seq = std::cmp::max(a, b);
-                }
-                (a @ AnyRelTimeLock::RT(_), b @ AnyRelTimeLock::RT(_)) => {
-                    *seq = std::cmp::max(a, b);
-                }
-                _ => return Err(CompilationError::IncompatibleSequence),
-            },
+            Some(Some(seq)) => {
+                *seq = seq
+                    .merge(s)
+                    .map_err(|_| CompilationError::IncompatibleSequence)?
+            }
             Some(x @ None) => {
                 x.replace(s);
             }
@@ -137,15 +334,9 @@ impl Builder {
     /// by taking the max of the argument.
     pub fn set_lock_time(mut self, lt_in: AnyAbsTimeLock) -> Result<Self, CompilationError> {
         if let Some(lt) = self.lock_time.as_mut() {
-            match (*lt, lt_in) {
-                (a @ AnyAbsTimeLock::AH(_), b @ AnyAbsTimeLock::AH(_)) => {
-                    *lt = std::cmp::max(a, b);
-                }
-                (a @ AnyAbsTimeLock::AT(_), b @ AnyAbsTimeLock::AT(_)) => {
-                    *lt = std::cmp::max(a, b);
-                }
-                _ => return Err(CompilationError::IncompatibleSequence),
-            }
+            *lt = lt
+                .merge(lt_in)
+                .map_err(|_| CompilationError::IncompatibleSequence)?;
         } else {
             self.lock_time = Some(lt_in);
         }
@@ -165,6 +356,37 @@ impl Builder {
         self.metadata.color = Some(color);
         self
     }
+    /// Commits to the contract logic that created this template with an
+    /// OP_RETURN output and records it in the template metadata, so wallets
+    /// can discover which contract governs the outputs.
+    pub fn commit_contract_version(
+        self,
+        version: ContractVersion,
+    ) -> Result<Self, CompilationError> {
+        let commitment = Compiled::from_op_return(&version.to_bytes()[..])?;
+        self.add_output(Amount::from_sat(0), &commitment, None)?
+            .set_meta(
+                CONTRACT_VERSION_METADATA_KEY,
+                serde_json::to_value(version).map_err(CompilationError::SerializationError)?,
+            )
+    }
+    /// Adds a 0 sat OP_RETURN output carrying `data` (at most 40 bytes), and
+    /// records the data in the output's metadata under
+    /// [`OP_RETURN_DATA_KEY`](super::output::OP_RETURN_DATA_KEY) so tools can
+    /// decode it from the ABI.
+    pub fn add_op_return(self, data: &[u8]) -> Result<Self, CompilationError> {
+        let commitment = Compiled::from_op_return(data)?;
+        let metadata = OutputMeta::from([(OP_RETURN_DATA_KEY, data.to_hex().into())]);
+        self.add_output(Amount::from_sat(0), &commitment, Some(metadata))
+    }
+    /// Commits to a SIMP with a standard OP_RETURN envelope (see
+    /// [`SIMPOpReturn`](sapio_base::simp::SIMPOpReturn)) and records it in the
+    /// template metadata, so indexers can decode it on-chain.
+    pub fn commit_simp<S: OnChainSIMP>(mut self, s: S) -> Result<Self, CompilationError> {
+        let commitment = Compiled::from_op_return(&s.to_op_return()?.to_bytes()[..])?;
+        self.metadata = self.metadata.add_simp(s)?;
+        self.add_output(Amount::from_sat(0), &commitment, None)
+    }
     /// set an extra metadata value
     pub fn set_meta<I, J>(mut self, i: I, j: J) -> Result<Self, CompilationError>
     where
@@ -195,10 +417,17 @@ impl Builder {
             input: self
                 .sequences
                 .iter()
-                .map(|sequence| bitcoin::TxIn {
+                .zip(self.sequence_defaults.iter())
+                .map(|(sequence, seq_default)| bitcoin::TxIn {
                     previous_output: Default::default(),
                     script_sig: Default::default(),
-                    sequence: sequence.unwrap_or(default_seq).get(),
+                    sequence: match (sequence, seq_default, self.rbf) {
+                        (Some(s), _, _) => s.get(),
+                        (None, Some(d), _) => *d,
+                        (None, None, Some(true)) => SEQUENCE_RBF,
+                        (None, None, Some(false)) => SEQUENCE_NO_RBF,
+                        (None, None, None) => default_seq.get(),
+                    },
                     witness: Witness::new(),
                 })
                 .collect(),
@@ -275,12 +504,23 @@ impl Builder {
 impl From<Builder> for Template {
     fn from(t: Builder) -> Template {
         let tx = t.get_tx();
+        let siblings = t
+            .sibling_inputs
+            .iter()
+            .map(|s| s.amount)
+            .fold(Amount::from_sat(0), |b, a| b + a);
         Template {
             guards: t.guards,
             outputs: t.outputs,
+            sibling_inputs: t.sibling_inputs,
             ctv: tx.get_ctv_hash(0),
             ctv_index: 0,
-            max: tx.total_amount() + t.fees,
+            // the contract's own input needn't carry what siblings fund
+            max: (tx.total_amount() + t.fees)
+                .checked_sub(siblings)
+                .unwrap_or(Amount::from_sat(0)),
+            // siblings' funds are already left out of `max`
+            external_amount: t.added.checked_sub(siblings).unwrap_or(Amount::from_sat(0)),
             min_feerate_sats_vbyte: t.min_feerate,
             tx,
             metadata_map_s2s: t.metadata,
@@ -294,3 +534,52 @@ impl From<Builder> for crate::contract::TxTmplIt {
         Ok(Box::new(std::iter::once(Ok(t.into()))))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::address::WitnessVersion;
+    use bitcoin::{Address, Network, Script};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+
+    /// a template sending all of `amount` to `script` as change
+    fn change(amount: Amount, script: &Script) -> Result<Builder, CompilationError> {
+        let ctx = Context::new(
+            Network::Regtest,
+            amount,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("change").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let to = Compiled::from_address(
+            Address::from_script(script, Network::Regtest).unwrap(),
+            None,
+        );
+        ctx.template().add_change_to(&to)
+    }
+
+    #[test]
+    fn change_dust_depends_on_script() {
+        let scripts = [
+            Script::new_p2pkh(&bitcoin::PubkeyHash::from_inner([0; 20])),
+            Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::from_inner([0; 20])),
+            Script::new_v0_p2wsh(&bitcoin::WScriptHash::from_inner([0; 32])),
+            Script::new_witness_program(WitnessVersion::V1, &[1; 32]),
+        ];
+        for script in scripts.iter() {
+            let dust = dust_threshold(script);
+            let under = dust - Amount::from_sat(1);
+            assert!(matches!(
+                change(under, script),
+                Err(CompilationError::ChangeIsDust(a)) if a == under
+            ));
+            let over = dust + Amount::from_sat(1);
+            let b = change(over, script).unwrap();
+            assert_eq!(b.outputs.len(), 1);
+            assert_eq!(b.outputs[0].amount, over);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Template Input container
+use super::*;
+use serde::{Deserialize, Serialize};
+
+/// A SiblingInput declares an input of a template other than the one spending
+/// the contract itself, e.g. another party's funding in a payment pool or
+/// coinjoin. Its outpoint is only known when the template is bound.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SiblingInput {
+    /// the index of the input in the template
+    pub input: u32,
+    /// the amount the input must carry
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "i64")]
+    #[serde(rename = "expected_amount_sats")]
+    pub amount: Amount,
+    /// the contract the input must be locked by
+    #[serde(rename = "expected_contract")]
+    pub contract: crate::contract::Compiled,
+    /// metadata identifying where the input comes from
+    #[serde(
+        rename = "metadata_map_s2s",
+        skip_serializing_if = "OutputMeta::is_empty",
+        default
+    )]
+    pub metadata: OutputMeta,
+}
+
+impl SiblingInput {
+    /// The output this input is expected to spend
+    pub fn expected_txout(&self) -> bitcoin::TxOut {
+        bitcoin::TxOut {
+            value: self.amount.as_sat(),
+            script_pubkey: self.contract.address.clone().into(),
+        }
+    }
+}
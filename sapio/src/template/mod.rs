@@ -10,14 +10,23 @@ use bitcoin::hashes::sha256;
 use bitcoin::util::amount::Amount;
 use sapio_base::simp::SIMPError;
 use sapio_base::simp::SIMP;
+use sapio_base::simp::{OnChainSIMP, SIMPOpReturnError};
 use sapio_base::Clause;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+pub mod anchor;
+pub use anchor::{AnchorKind, AnchorOutput};
+pub mod input;
+pub use input::SiblingInput;
 pub mod output;
 pub use output::{Output, OutputMeta};
 pub mod builder;
 pub use builder::Builder;
+pub mod preimage;
+pub mod sighash;
+pub mod version;
+pub use version::ContractVersion;
 /// Metadata Struct which has some standard defined fields
 /// and can be extended via a hashmap
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
@@ -146,6 +155,23 @@ pub struct Template {
     /// sapio specific information about all the outputs in the `tx`.
     #[serde(rename = "outputs_info")]
     pub outputs: Vec<Output>,
+    /// the inputs of `tx` funded by other contracts
+    #[serde(
+        rename = "sibling_inputs_info",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
+    pub sibling_inputs: Vec<SiblingInput>,
+    /// the part of `max` brought in by the template itself (see
+    /// [`Builder::add_amount`]) rather than by the contract's input. Not
+    /// serialized, as it is only needed while compiling.
+    #[serde(skip, default = "no_amount")]
+    #[schemars(skip)]
+    pub external_amount: Amount,
+}
+
+fn no_amount() -> Amount {
+    Amount::from_sat(0)
 }
 
 impl Template {
@@ -157,10 +183,32 @@ impl Template {
     /// recompute the total amount spent in this template. This is the total
     /// amount required to be sent to this template for this transaction to
     /// succeed.
+    ///
+    /// Amounts funded by sibling inputs are not included.
     pub fn total_amount(&self) -> Amount {
+        let siblings = self
+            .sibling_inputs
+            .iter()
+            .map(|s| s.amount)
+            .fold(Amount::from_sat(0), |b, a| b + a);
         self.outputs
             .iter()
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
+            .checked_sub(siblings)
+            .unwrap_or(Amount::from_sat(0))
+    }
+
+    /// The amount the contract's own input must carry for this template:
+    /// `max` less what the template brings in itself.
+    pub fn required_funding(&self) -> Amount {
+        self.max
+            .checked_sub(self.external_amount)
+            .unwrap_or(Amount::from_sat(0))
+    }
+
+    /// Decode a SIMP committed on-chain by this template, if present.
+    pub fn committed_simp<S: OnChainSIMP>(&self) -> Option<Result<S, SIMPOpReturnError>> {
+        S::from_tx(&self.tx)
     }
 }
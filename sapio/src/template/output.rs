@@ -8,6 +8,11 @@
 use super::*;
 use sapio_base::simp::{SIMPError, SIMP};
 use serde::{Deserialize, Serialize};
+/// The key the data of an OP_RETURN output added with
+/// [`Builder::add_op_return`](super::Builder::add_op_return) is stored under,
+/// hex encoded
+pub const OP_RETURN_DATA_KEY: &str = "op_return_data";
+
 /// Metadata for outputs, arbitrary KV set.
 #[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq, Eq)]
 pub struct OutputMeta {
@@ -24,6 +29,12 @@ impl OutputMeta {
         *self == Default::default()
     }
 
+    /// The data carried by an OP_RETURN output, if recorded
+    pub fn op_return_data(&self) -> Option<Vec<u8>> {
+        use bitcoin::hashes::hex::FromHex;
+        Vec::<u8>::from_hex(self.extra.get(OP_RETURN_DATA_KEY)?.as_str()?).ok()
+    }
+
     /// attempts to add a SIMP to the output meta.
     ///
     /// Returns [`SIMPError::AlreadyDefined`] if one was previously set.
@@ -0,0 +1,136 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Hash preimages a template's spending input reveals, e.g. to claim an HTLC
+//! or complete a cross-chain swap, so they can be put in the bound PSBT.
+use super::{Template, TemplateMetadata};
+use crate::contract::CompilationError;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::util::psbt::Input;
+use bitcoin::Script;
+
+/// The key the preimages of a template are listed under in its metadata
+pub const PREIMAGE_METADATA_KEY: &str = "preimages";
+
+impl TemplateMetadata {
+    /// The preimages recorded in this metadata
+    pub fn preimages(&self) -> Vec<Vec<u8>> {
+        self.extra
+            .get(PREIMAGE_METADATA_KEY)
+            .and_then(|v| serde_json::from_value::<Vec<String>>(v.clone()).ok())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|s| Vec::from_hex(s).ok())
+            .collect()
+    }
+    /// record a preimage the spending input reveals
+    pub fn add_preimage(mut self, preimage: &[u8]) -> Result<Self, CompilationError> {
+        let mut preimages: Vec<String> = self.preimages().iter().map(|p| p.to_hex()).collect();
+        let p = preimage.to_hex();
+        if !preimages.contains(&p) {
+            preimages.push(p);
+        }
+        self.extra.insert(
+            PREIMAGE_METADATA_KEY.into(),
+            serde_json::to_value(preimages).map_err(CompilationError::SerializationError)?,
+        );
+        Ok(self)
+    }
+}
+
+impl Template {
+    /// The preimages the contract's input reveals
+    pub fn preimages(&self) -> Vec<Vec<u8>> {
+        self.metadata_map_s2s.preimages()
+    }
+}
+
+/// true if `script` pushes exactly `data`, as a hashlock does its hash
+fn pushes(script: &Script, data: &[u8]) -> bool {
+    script
+        .instructions()
+        .any(|i| matches!(i, Ok(Instruction::PushBytes(b)) if b == data))
+}
+
+/// Fill in the PSBT preimage fields of `inp` for each of `preimages` whose
+/// hash (of any type) is locked to by one of the input's scripts. Must be
+/// called after the input's scripts are added.
+pub(crate) fn add_preimages(inp: &mut Input, preimages: &[Vec<u8>]) {
+    let scripts: Vec<&Script> = inp
+        .witness_script
+        .iter()
+        .chain(inp.tap_scripts.values().map(|(s, _)| s))
+        .collect();
+    let locked = |h: &[u8]| scripts.iter().any(|s| pushes(s, h));
+    let mut sha256s = vec![];
+    let mut hash256s = vec![];
+    let mut ripemd160s = vec![];
+    let mut hash160s = vec![];
+    for p in preimages {
+        let h = sha256::Hash::hash(p);
+        if locked(&h[..]) {
+            sha256s.push((h, p.clone()));
+        }
+        let h = sha256d::Hash::hash(p);
+        if locked(&h[..]) {
+            hash256s.push((h, p.clone()));
+        }
+        let h = ripemd160::Hash::hash(p);
+        if locked(&h[..]) {
+            ripemd160s.push((h, p.clone()));
+        }
+        let h = hash160::Hash::hash(p);
+        if locked(&h[..]) {
+            hash160s.push((h, p.clone()));
+        }
+    }
+    inp.sha256_preimages.extend(sha256s);
+    inp.hash256_preimages.extend(hash256s);
+    inp.ripemd160_preimages.extend(ripemd160s);
+    inp.hash160_preimages.extend(hash160s);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::internal_key::hashed_constant_key;
+    use ::miniscript::{Miniscript, Tap};
+    use bitcoin::XOnlyPublicKey;
+    use sapio_base::Clause;
+    #[test]
+    fn preimages_fill_matching_fields() {
+        let secret = b"swap secret".to_vec();
+        let m = TemplateMetadata::new()
+            .add_preimage(&secret)
+            .unwrap()
+            .add_preimage(&secret)
+            .unwrap()
+            .add_preimage(b"unused")
+            .unwrap();
+        assert_eq!(m.preimages().len(), 2);
+        let ms: Miniscript<XOnlyPublicKey, Tap> = Clause::And(vec![
+            Clause::Key(hashed_constant_key()),
+            Clause::Or(vec![
+                (1, Clause::Hash160(hash160::Hash::hash(&secret))),
+                (1, Clause::Ripemd160(ripemd160::Hash::hash(&secret))),
+            ]),
+        ])
+        .compile()
+        .unwrap();
+        let mut inp = Input::default();
+        inp.witness_script = Some(ms.encode());
+        add_preimages(&mut inp, &m.preimages());
+        assert_eq!(
+            inp.hash160_preimages.get(&hash160::Hash::hash(&secret)),
+            Some(&secret)
+        );
+        assert_eq!(inp.ripemd160_preimages.len(), 1);
+        assert!(inp.sha256_preimages.is_empty());
+        assert!(inp.hash256_preimages.is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The sighash type a template's spending input must be signed with, for
+//! branches that declare one.
+use super::{Template, TemplateMetadata};
+use crate::contract::CompilationError;
+use bitcoin::SchnorrSighashType;
+use std::str::FromStr;
+
+/// The key the required sighash type is recorded under in template metadata
+pub const SIGHASH_METADATA_KEY: &str = "sighash_type";
+
+impl TemplateMetadata {
+    /// The sighash type recorded in this metadata, if any
+    pub fn sighash_type(&self) -> Option<SchnorrSighashType> {
+        self.extra
+            .get(SIGHASH_METADATA_KEY)
+            .and_then(|v| v.as_str())
+            .and_then(|s| SchnorrSighashType::from_str(s).ok())
+    }
+    /// record the sighash type the spending input must be signed with
+    pub fn set_sighash_type(self, ty: SchnorrSighashType) -> Result<Self, CompilationError> {
+        self.set(SIGHASH_METADATA_KEY, ty.to_string())
+    }
+}
+
+impl Template {
+    /// The sighash type the contract's input must be signed with, if not the
+    /// default
+    pub fn sighash_type(&self) -> Option<SchnorrSighashType> {
+        self.metadata_map_s2s.sighash_type()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn sighash_roundtrip() {
+        let m = TemplateMetadata::new()
+            .set_sighash_type(SchnorrSighashType::SinglePlusAnyoneCanPay)
+            .unwrap();
+        assert_eq!(
+            m.sighash_type(),
+            Some(SchnorrSighashType::SinglePlusAnyoneCanPay)
+        );
+        assert_eq!(TemplateMetadata::new().sighash_type(), None);
+    }
+}
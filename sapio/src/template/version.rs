@@ -0,0 +1,117 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Commitments to the contract logic that created a transaction
+use super::Template;
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{Script, Transaction};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Prefix of the OP_RETURN data committing to a [`ContractVersion`]
+pub const CONTRACT_VERSION_TAG: [u8; 4] = *b"sapv";
+/// Length of the OP_RETURN data committing to a [`ContractVersion`]
+pub const CONTRACT_VERSION_LEN: usize = 40;
+/// The key a [`ContractVersion`] is stored under in template metadata
+pub const CONTRACT_VERSION_METADATA_KEY: &str = "contract_version";
+
+/// Identifies the contract logic governing a transaction's outputs: the plugin
+/// module that compiled it and the version of that module's arguments schema.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(into = "ContractVersionJson", try_from = "ContractVersionJson")]
+pub struct ContractVersion {
+    /// the plugin module's key (the hash of the module)
+    pub module: [u8; 32],
+    /// the version of the module's arguments schema
+    pub schema_version: u32,
+}
+
+/// # Contract Version
+/// JSON format for a [`ContractVersion`]
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct ContractVersionJson {
+    /// # Module Key
+    /// hex encoded hash of the plugin module
+    module: String,
+    /// # Arguments Schema Version
+    schema_version: u32,
+}
+
+impl From<ContractVersion> for ContractVersionJson {
+    fn from(v: ContractVersion) -> Self {
+        ContractVersionJson {
+            module: v.module[..].to_hex(),
+            schema_version: v.schema_version,
+        }
+    }
+}
+
+impl TryFrom<ContractVersionJson> for ContractVersion {
+    type Error = bitcoin::hashes::hex::Error;
+    fn try_from(v: ContractVersionJson) -> Result<Self, Self::Error> {
+        Ok(ContractVersion {
+            module: FromHex::from_hex(&v.module)?,
+            schema_version: v.schema_version,
+        })
+    }
+}
+
+impl ContractVersion {
+    /// The OP_RETURN data committing to this version:
+    /// `"sapv" || module || schema_version (big endian)`
+    pub fn to_bytes(&self) -> [u8; CONTRACT_VERSION_LEN] {
+        let mut b = [0u8; CONTRACT_VERSION_LEN];
+        b[..4].copy_from_slice(&CONTRACT_VERSION_TAG);
+        b[4..36].copy_from_slice(&self.module);
+        b[36..].copy_from_slice(&self.schema_version.to_be_bytes());
+        b
+    }
+
+    /// Parse OP_RETURN data created by [`ContractVersion::to_bytes`]
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() != CONTRACT_VERSION_LEN || b[..4] != CONTRACT_VERSION_TAG {
+            return None;
+        }
+        let mut module = [0u8; 32];
+        module.copy_from_slice(&b[4..36]);
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&b[36..]);
+        Some(ContractVersion {
+            module,
+            schema_version: u32::from_be_bytes(version),
+        })
+    }
+
+    /// Read a version commitment from an OP_RETURN script
+    pub fn from_script(s: &Script) -> Option<Self> {
+        let mut instructions = s.instructions();
+        match instructions.next() {
+            Some(Ok(Instruction::Op(opcodes::all::OP_RETURN))) => (),
+            _ => return None,
+        }
+        match (instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::PushBytes(b))), None) => Self::from_bytes(b),
+            _ => None,
+        }
+    }
+
+    /// Read the version commitment from a transaction's outputs, if any.
+    pub fn from_tx(tx: &Transaction) -> Option<Self> {
+        tx.output
+            .iter()
+            .find_map(|o| Self::from_script(&o.script_pubkey))
+    }
+}
+
+impl Template {
+    /// Read the contract version this template committed to, if any.
+    pub fn contract_version(&self) -> Option<ContractVersion> {
+        ContractVersion::from_tx(&self.tx)
+    }
+}
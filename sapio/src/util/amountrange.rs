@@ -6,6 +6,7 @@
 
 //! Functionality for working with ranges of amounts
 use bitcoin::util::amount::Amount;
+use bitcoin::{Script, VarInt};
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -64,11 +65,36 @@ impl From<AmountU64> for u64 {
         a.0.as_sat()
     }
 }
+/// The feerate, in sats per 1000 vbytes, at which Bitcoin Core's default
+/// relay policy considers an output not worth spending (dust). The policy is
+/// the same on every network.
+pub const DUST_RELAY_FEE_SATS_PER_KVB: u64 = 3000;
+
+/// The smallest amount an output paying `script` may carry and still be
+/// relayed, following Bitcoin Core's `GetDustThreshold`. OP_RETURN outputs
+/// are never dust.
+pub fn dust_threshold(script: &Script) -> Amount {
+    if script.is_op_return() {
+        return Amount::from_sat(0);
+    }
+    let output_size = 8 + VarInt(script.len() as u64).len() + script.len();
+    // outpoint, scriptSig length, sequence, and a typical signature, with
+    // witness data discounted
+    let spend_size = if script.is_witness_program() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    Amount::from_sat((output_size + spend_size) as u64 * DUST_RELAY_FEE_SATS_PER_KVB / 1000)
+}
+
 /// `AmountRange` makes it simple to track and update the range of allowed values
 /// for a contract to receive.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
 pub struct AmountRange {
-    #[serde(rename = "min_btc", skip_serializing_if = "Option::is_none", default)]
+    // earlier versions never set the min, so it is not written out, which
+    // would change every compiled object's JSON
+    #[serde(rename = "min_btc", skip_serializing, default)]
     min: Option<AmountF64>,
     #[serde(rename = "max_btc", skip_serializing_if = "Option::is_none", default)]
     max: Option<AmountF64>,
@@ -83,11 +109,38 @@ impl AmountRange {
     }
     /// Update the min and the max value.
     pub fn update_range(&mut self, amount: Amount) {
-        self.min = std::cmp::min(self.min, Some(amount.into()));
-        self.max = std::cmp::max(self.max, Some(amount.into()));
+        let amount: AmountF64 = amount.into();
+        // n.b. None < Some(_), so std::cmp::min would never set a min
+        self.min = Some(self.min.map_or(amount, |m| std::cmp::min(m, amount)));
+        self.max = std::cmp::max(self.max, Some(amount));
+    }
+    /// Retreive the min value, if set, or return `Amount::min_value`.
+    pub fn min(&self) -> Amount {
+        self.min.unwrap_or(Amount::min_value().into()).0
     }
     /// Retreive the max value, if set, or return `Amount::min_value`.
     pub fn max(&self) -> Amount {
         self.max.unwrap_or(Amount::min_value().into()).0
     }
+    /// true if every amount in the range is at least the dust threshold of
+    /// an output paying `script`
+    pub fn above_dust(&self, script: &Script) -> bool {
+        self.min() >= dust_threshold(script)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    #[test]
+    fn dust_thresholds() {
+        let p2pkh = Script::new_p2pkh(&bitcoin::PubkeyHash::from_inner([0; 20]));
+        let p2wpkh = Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::from_inner([0; 20]));
+        let p2wsh = Script::new_v0_p2wsh(&bitcoin::WScriptHash::from_inner([0; 32]));
+        assert_eq!(dust_threshold(&p2pkh).as_sat(), 546);
+        assert_eq!(dust_threshold(&p2wpkh).as_sat(), 294);
+        assert_eq!(dust_threshold(&p2wsh).as_sat(), 330);
+        assert_eq!(dust_threshold(&Script::new_op_return(&[])).as_sat(), 0);
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Control over, and a record of, how a contract's taproot internal key is
+//! chosen.
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1, Verification};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The x coordinate of BIP-341's NUMS point H, the SHA256 of the standard
+/// uncompressed encoding of the secp256k1 generator.
+pub const BIP341_NUMS_X: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// The preimage hashed to make the internal key of a contract with no keys
+/// under [`InternalKeyPolicy::FirstPartyKey`]
+pub const HASHED_CONSTANT_PREIMAGE: [u8; 32] = [1u8; 32];
+
+/// # Internal Key Policy
+/// How the compiler picks a contract's taproot internal key.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InternalKeyPolicy {
+    /// # First Party Key
+    /// Use the MuSig2 aggregate if there is one, else the first key found in
+    /// the contract's leaves, else a hashed constant (the default).
+    FirstPartyKey,
+    /// # Explicit Key
+    /// Always use `key`, e.g. a key the contract author controls.
+    Explicit {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # Unspendable Key
+    /// Always use H + r*G, where H is the BIP-341 NUMS point. Nobody knows
+    /// its discrete log, so the key path can never be used; revealing `r`
+    /// proves this. `r` keeps the key from being recognizable on chain.
+    Nums {
+        /// # Randomizer
+        /// The 32 byte scalar r
+        randomizer: sha256::Hash,
+    },
+}
+
+impl Default for InternalKeyPolicy {
+    fn default() -> Self {
+        InternalKeyPolicy::FirstPartyKey
+    }
+}
+
+/// # Internal Key Source
+/// Which key a contract was given and why, so that auditors can check who (if
+/// anyone) can spend via the key path.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InternalKeySource {
+    /// # MuSig2 Aggregate
+    /// The aggregate of the keys in `key_aggregation`
+    Aggregate {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # Leaf Key
+    /// A key taken from one of the contract's leaves
+    LeafKey {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # Explicit Key
+    /// The key set by [`InternalKeyPolicy::Explicit`]
+    Explicit {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+    },
+    /// # Hashed Constant
+    /// The SHA256 of a constant, used when the contract has no keys. Nobody
+    /// is expected to know its discrete log, but unlike
+    /// [`InternalKeySource::Nums`] it is the same for every contract.
+    HashedConstant {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+        /// the constant hashed
+        preimage: sha256::Hash,
+    },
+    /// # Unspendable Key
+    /// H + r*G, set by [`InternalKeyPolicy::Nums`]
+    Nums {
+        /// the internal key
+        // TODO: Taproot Fix Encoding
+        #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+        key: XOnlyPublicKey,
+        /// the scalar r
+        randomizer: sha256::Hash,
+    },
+}
+
+/// Compute H + r*G
+pub fn nums_key<C: Verification>(
+    secp: &Secp256k1<C>,
+    randomizer: &sha256::Hash,
+) -> Result<XOnlyPublicKey, secp256k1::Error> {
+    let mut h = [2u8; 33];
+    h[1..].copy_from_slice(&BIP341_NUMS_X);
+    let mut p = PublicKey::from_slice(&h)?;
+    if randomizer.into_inner() != [0u8; 32] {
+        p.add_exp_assign(secp, &randomizer[..])?;
+    }
+    XOnlyPublicKey::from_slice(&p.serialize()[1..])
+}
+
+/// The key used when a contract has no keys and no other policy applies
+pub fn hashed_constant_key() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_slice(&sha256::Hash::hash(&HASHED_CONSTANT_PREIMAGE).into_inner())
+        .expect("constant")
+}
+
+impl InternalKeySource {
+    /// The internal key
+    pub fn key(&self) -> XOnlyPublicKey {
+        match self {
+            InternalKeySource::Aggregate { key }
+            | InternalKeySource::LeafKey { key }
+            | InternalKeySource::Explicit { key }
+            | InternalKeySource::HashedConstant { key, .. }
+            | InternalKeySource::Nums { key, .. } => *key,
+        }
+    }
+
+    /// For the sources that carry a proof (a hashed constant or a NUMS
+    /// key), check that it produces the key. Other sources are only claims
+    /// and are trivially accepted.
+    pub fn verify<C: Verification>(&self, secp: &Secp256k1<C>) -> bool {
+        match self {
+            InternalKeySource::HashedConstant { key, preimage } => {
+                XOnlyPublicKey::from_slice(&sha256::Hash::hash(&preimage[..]).into_inner())
+                    .map_or(false, |k| k == *key)
+            }
+            InternalKeySource::Nums { key, randomizer } => {
+                nums_key(secp, randomizer).map_or(false, |k| k == *key)
+            }
+            _ => true,
+        }
+    }
+
+    /// true if nobody can be expected to spend via the key path
+    pub fn is_unspendable(&self) -> bool {
+        matches!(
+            self,
+            InternalKeySource::HashedConstant { .. } | InternalKeySource::Nums { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn nums_proof_verifies() {
+        let secp = Secp256k1::verification_only();
+        let randomizer = sha256::Hash::hash(b"randomizer");
+        let key = nums_key(&secp, &randomizer).unwrap();
+        assert_eq!(
+            nums_key(&secp, &sha256::Hash::from_inner([0; 32])).unwrap(),
+            XOnlyPublicKey::from_slice(&BIP341_NUMS_X).unwrap()
+        );
+        assert!(InternalKeySource::Nums { key, randomizer }.verify(&secp));
+        let other = sha256::Hash::hash(b"other");
+        assert!(!InternalKeySource::Nums {
+            key,
+            randomizer: other
+        }
+        .verify(&secp));
+    }
+}
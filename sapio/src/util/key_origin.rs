@@ -0,0 +1,153 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Keys which remember where they were derived from, so that bound PSBTs can
+//! tell signers which of their keys to use.
+use crate::contract::object::SupportedDescriptors;
+use ::miniscript::{Descriptor, ForEach, ForEachKey};
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::bip32::{
+    DerivationPath, Error as Bip32Error, ExtendedPubKey, Fingerprint, KeySource,
+};
+use bitcoin::XOnlyPublicKey;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// # Sapio Key
+/// A key and, if known, the BIP-32 origin it was derived from.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct SapioKey {
+    /// # Key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub key: XOnlyPublicKey,
+    /// # Origin
+    /// The master key fingerprint and derivation path of the key
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[schemars(with = "Option<(String, String)>")]
+    pub origin: Option<KeySource>,
+}
+
+impl SapioKey {
+    /// A key with no known origin
+    pub fn new(key: XOnlyPublicKey) -> Self {
+        SapioKey { key, origin: None }
+    }
+    /// A key derived from `fingerprint` along `path`
+    pub fn with_origin(
+        key: XOnlyPublicKey,
+        fingerprint: Fingerprint,
+        path: DerivationPath,
+    ) -> Self {
+        SapioKey {
+            key,
+            origin: Some((fingerprint, path)),
+        }
+    }
+    /// Derive the key at `path` below `xpub`, where `xpub` itself is at
+    /// `xpub_origin` below the master key
+    pub fn derive<C: Verification>(
+        secp: &Secp256k1<C>,
+        xpub: &ExtendedPubKey,
+        xpub_origin: &KeySource,
+        path: &DerivationPath,
+    ) -> Result<Self, Bip32Error> {
+        let child = xpub.derive_pub(secp, path)?;
+        Ok(SapioKey::with_origin(
+            XOnlyPublicKey::from(child.public_key),
+            xpub_origin.0,
+            xpub_origin.1.extend(path),
+        ))
+    }
+}
+
+impl From<XOnlyPublicKey> for SapioKey {
+    fn from(key: XOnlyPublicKey) -> Self {
+        SapioKey::new(key)
+    }
+}
+
+impl From<SapioKey> for XOnlyPublicKey {
+    fn from(k: SapioKey) -> Self {
+        k.key
+    }
+}
+
+/// The origins of the keys used in a compilation. Cloning shares the
+/// underlying collection.
+#[derive(Clone, Default)]
+pub struct KeyOrigins(Arc<Mutex<BTreeMap<XOnlyPublicKey, KeySource>>>);
+
+impl KeyOrigins {
+    /// record the origin of `k`, if it has one, and return a clause
+    /// requiring a signature from it
+    pub fn key(&self, k: &SapioKey) -> Clause {
+        if let (Some(origin), Ok(mut m)) = (&k.origin, self.0.lock()) {
+            m.insert(k.key, origin.clone());
+        }
+        Clause::Key(k.key)
+    }
+    /// The recorded origins of the keys appearing in `d`
+    pub fn for_descriptor(&self, d: &SupportedDescriptors) -> BTreeMap<XOnlyPublicKey, KeySource> {
+        let known = match self.0.lock() {
+            Ok(m) => m,
+            Err(_) => return BTreeMap::new(),
+        };
+        if known.is_empty() {
+            return BTreeMap::new();
+        }
+        let mut res = BTreeMap::new();
+        let mut add = |k: XOnlyPublicKey| {
+            if let Some(o) = known.get(&k) {
+                res.insert(k, o.clone());
+            }
+        };
+        match d {
+            SupportedDescriptors::XOnly(Descriptor::Tr(t)) => {
+                add(t.internal_key().clone());
+                for (_, ms) in t.iter_scripts() {
+                    ms.iter_pk().for_each(&mut add);
+                }
+            }
+            SupportedDescriptors::XOnly(d) => {
+                d.for_each_key(|k: ForEach<XOnlyPublicKey>| {
+                    add(k.as_key().clone());
+                    true
+                });
+            }
+            SupportedDescriptors::Pk(d) => {
+                d.for_each_key(|k: ForEach<bitcoin::PublicKey>| {
+                    add(XOnlyPublicKey::from(k.as_key().inner));
+                    true
+                });
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::internal_key::hashed_constant_key;
+    use std::str::FromStr;
+    #[test]
+    fn records_origins() {
+        let origins = KeyOrigins::default();
+        let k = SapioKey::with_origin(
+            hashed_constant_key(),
+            Fingerprint::from(&[1, 2, 3, 4][..]),
+            DerivationPath::from_str("m/86'/0'/0'/0/1").unwrap(),
+        );
+        assert_eq!(origins.key(&k), Clause::Key(k.key));
+        let d = SupportedDescriptors::XOnly(Descriptor::new_tr(k.key, None).unwrap());
+        let found = origins.for_descriptor(&d);
+        assert_eq!(found.get(&k.key), k.origin.as_ref());
+    }
+}
@@ -7,3 +7,6 @@
 //! Basic functionality / structs for Sapio
 pub mod amountrange;
 pub mod extended_address;
+pub mod internal_key;
+pub mod key_origin;
+pub mod musig;
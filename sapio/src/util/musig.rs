@@ -0,0 +1,157 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! MuSig2 (BIP-327) key aggregation, used to give contracts whose finish
+//! guards are all keys a cooperative key path spend.
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1, Verification};
+use bitcoin::XOnlyPublicKey;
+use sapio_base::Clause;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// # Key Aggregation
+/// Metadata for a MuSig2 aggregated internal key, so that signers can
+/// cooperate off-chain to spend via the key path.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct KeyAggregation {
+    /// # Participant Keys
+    /// In the (sorted) order they were aggregated
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "Vec<bitcoin::hashes::sha256::Hash>")]
+    pub keys: Vec<XOnlyPublicKey>,
+    /// # Aggregate Key
+    /// The taproot internal key
+    // TODO: Taproot Fix Encoding
+    #[schemars(with = "bitcoin::hashes::sha256::Hash")]
+    pub aggregate: XOnlyPublicKey,
+}
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+impl KeyAggregation {
+    /// Aggregate `keys` with MuSig2 KeyAgg, after sorting them (KeySort).
+    /// Each x-only key is taken as the point with even y.
+    pub fn new<C: Verification>(
+        secp: &Secp256k1<C>,
+        keys: &[XOnlyPublicKey],
+    ) -> Result<Self, secp256k1::Error> {
+        let mut plain: Vec<[u8; 33]> = keys
+            .iter()
+            .map(|k| {
+                let mut b = [2u8; 33];
+                b[1..].copy_from_slice(&k.serialize());
+                b
+            })
+            .collect();
+        plain.sort();
+        plain.dedup();
+        let list: Vec<&[u8]> = plain.iter().map(|k| &k[..]).collect();
+        let l = tagged_hash("KeyAgg list", &list);
+        let second = plain.iter().find(|k| **k != plain[0]);
+        let points = plain
+            .iter()
+            .map(|k| {
+                let mut p = PublicKey::from_slice(&k[..])?;
+                if Some(k) != second {
+                    p.mul_assign(secp, &tagged_hash("KeyAgg coefficient", &[&l[..], &k[..]]))?;
+                }
+                Ok(p)
+            })
+            .collect::<Result<Vec<PublicKey>, secp256k1::Error>>()?;
+        let aggregate = PublicKey::combine_keys(&points.iter().collect::<Vec<_>>())?;
+        Ok(KeyAggregation {
+            keys: plain
+                .iter()
+                .map(|k| XOnlyPublicKey::from_slice(&k[1..]))
+                .collect::<Result<_, _>>()?,
+            aggregate: XOnlyPublicKey::from_slice(&aggregate.serialize()[1..])?,
+        })
+    }
+
+    /// If every clause is a key or a conjunction of keys, the keys to
+    /// aggregate. Requiring all of them is no weaker than any one clause, so
+    /// the aggregate is a safe internal key. Returns None if fewer than two
+    /// distinct keys are found.
+    pub(crate) fn keys_for(clauses: &[&Clause]) -> Option<Vec<XOnlyPublicKey>> {
+        let mut keys = vec![];
+        for clause in clauses {
+            match clause {
+                Clause::Key(k) => keys.push(*k),
+                Clause::And(v) => {
+                    for c in v {
+                        match c {
+                            Clause::Key(k) => keys.push(*k),
+                            _ => return None,
+                        }
+                    }
+                }
+                _ => return None,
+            }
+        }
+        keys.sort();
+        keys.dedup();
+        if keys.len() > 1 {
+            Some(keys)
+        } else {
+            None
+        }
+    }
+
+    /// true if `clause` requires exactly the aggregated keys, and so is
+    /// redundant with the key path
+    pub(crate) fn covers(&self, clause: &Clause) -> bool {
+        match clause {
+            Clause::And(v) => {
+                let mut keys = vec![];
+                for c in v {
+                    match c {
+                        Clause::Key(k) => keys.push(*k),
+                        _ => return false,
+                    }
+                }
+                keys.sort();
+                keys.dedup();
+                let mut mine = self.keys.clone();
+                mine.sort();
+                keys == mine
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::KeyPair;
+    #[test]
+    fn aggregation_is_order_independent() {
+        let secp = Secp256k1::new();
+        let keys: Vec<XOnlyPublicKey> = (1..4u8)
+            .map(|i| {
+                let kp = KeyPair::from_seckey_slice(&secp, &[i; 32]).unwrap();
+                XOnlyPublicKey::from_keypair(&kp)
+            })
+            .collect();
+        let a = KeyAggregation::new(&secp, &keys).unwrap();
+        let mut reversed = keys.clone();
+        reversed.reverse();
+        let b = KeyAggregation::new(&secp, &reversed).unwrap();
+        assert_eq!(a, b);
+        assert!(!keys.contains(&a.aggregate));
+    }
+}
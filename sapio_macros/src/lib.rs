@@ -8,7 +8,7 @@ mod tests {
 
 use core::ops::Index;
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, quote_spanned};
 use syn::Lit;
 use syn::{parse_macro_input, AttributeArgs, ItemFn, Meta, NestedMeta};
 /// The compile_if macro is used to define a `ConditionallyCompileIf`.
@@ -44,7 +44,10 @@ pub fn compile_if(args: TokenStream, input: TokenStream) -> TokenStream {
 /// ```ignore
 /// #[guard(
 ///     /// optional, if desired to only be invoked once
-///     cached
+///     cached,
+///     /// optional: relative likelihood of spending via this guard when used
+///     /// as a finish branch (default 1)
+///     weight = 1
 /// )]
 /// fn name(self, ctx) {
 ///     /*Clause*/
@@ -61,6 +64,7 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
     let name = input.sig.ident;
     let guard_name = format_ident!("guard_{}", name);
     let block = input.block;
+    let weight = get_weight(&args);
     let mut ty = format_ident!("Fresh");
     for arg in args {
         match arg {
@@ -74,11 +78,44 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
         fn #guard_name(&self, #context_arg) -> sapio::sapio_base::Clause
         #block
         fn  #name() -> Option<sapio::contract::actions::Guard<Self>> {
-            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name))
+            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name, #weight))
         }
     })
 }
 
+/// Get the `weight = n` argument, defaulting to 1.
+fn get_weight(args: &Vec<NestedMeta>) -> u64 {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("weight") => match &v.lit {
+                Lit::Int(l) => return l.base10_parse().expect("Weight must be a u64"),
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    1
+}
+
+/// Get the `sighash = "Variant"` argument, defaulting to `None`.
+fn get_sighash(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("sighash") => match &v.lit {
+                Lit::Str(l) => {
+                    let variant = format_ident!("{}", l.value());
+                    return quote! {
+                        Some(sapio::contract::actions::SchnorrSighashType::#variant)
+                    };
+                }
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    quote! { None }
+}
+
 fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
     let mut compile_if_array = None;
     let mut guarded_by_array = None;
@@ -123,7 +160,9 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
 ///     /// optional: only compile these branches if these compile_if statements permit
 ///     compile_if= "[compile_if_1, ... compile_if_n]",
 ///     /// optional: protect these branches with the conjunction (and) of these clauses
-///     guarded_by= "[guard_1, ... guard_n]"
+///     guarded_by= "[guard_1, ... guard_n]",
+///     /// optional: relative likelihood of spending via these branches (default 1)
+///     weight = 1
 /// )]
 /// fn name(self, ctx) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -141,6 +180,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
     let then_fn_name = format_ident!("then_{}", name);
     let block = input.block;
     let (cia, gba) = get_arrays(&args);
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
             /// (missing docs fix)
             fn #name<'a>() -> Option<sapio::contract::actions::ThenFunc<'a, Self>>{
@@ -149,6 +189,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
                     conditional_compile_if: &#cia,
                     func: Self::#then_fn_name,
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
                 })
             }
             /// (missing docs fix)
@@ -233,7 +274,11 @@ fn web_api_schema(
 ///     ///  optional: Enables compiling this for a json callable continuation
 ///     web_api,
 ///     /// helper for coercing args for json api, could be arbitrary
-///     coerce_args = "default_coerce"
+///     coerce_args = "default_coerce",
+///     /// optional: relative likelihood of spending via this branch (default 1)
+///     weight = 1,
+///     /// optional: the sighash type spends via this branch must sign with
+///     sighash = "SinglePlusAnyoneCanPay"
 /// )]
 /// fn name(self, ctx:Context, o:UpdateType) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -263,6 +308,8 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
         format_ident!("CONTINUE_SCHEMA_FOR_{}", name.to_string().to_uppercase());
     let web_api_schema_s = web_api_schema(&args, &continue_schema_for_name, &arg_type);
     let coerce_args_f = coerce_args(&args);
+    let weight = get_weight(&args);
+    let sighash = get_sighash(&args);
     proc_macro::TokenStream::from(quote! {
             #web_api_schema_s
             /// (missing docs fix)
@@ -279,9 +326,190 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
                     func: Self::#continue_name,
                     schema: Self::#continue_schema_for_name.map(|f|f()),
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
+                    sighash: #sighash,
                     f: std::default::Default::default()
                 };
                 Some(Box::new(f))
             }
     })
 }
+
+/// The finish macro is used to define a `Guard` which is a finish path of
+/// the contract, rather than only guarding its other actions. It takes the
+/// same arguments as `guard`.
+/// ```ignore
+/// #[finish]
+/// fn name(self, ctx) {
+///     /*Clause*/
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn finish(args: TokenStream, input: TokenStream) -> TokenStream {
+    guard(args, input)
+}
+
+/// The attributes `#[actions]` collects the methods of, by the list of the
+/// `Contract` they go in
+const ACTION_KINDS: [(&str, &str); 3] = [
+    ("then", "__SAPIO_THEN_FNS"),
+    ("finish", "__SAPIO_FINISH_FNS"),
+    ("continuation", "__SAPIO_FINISH_OR_FUNCS"),
+];
+
+fn compile_error<T: quote::ToTokens>(tokens: T, message: &str) -> TokenStream {
+    syn::Error::new_spanned(tokens, message)
+        .to_compile_error()
+        .into()
+}
+
+/// Derives `Contract` from the actions of the impl marked `#[actions]`, in
+/// place of `declare!`.
+/// ```ignore
+/// #[derive(Contract)]
+/// #[contract(
+///     /// optional: the StatefulArguments type, needed by any continuation
+///     /// (default `()`)
+///     args = "UpdateType",
+///     /// optional: a fn(&Self, &Context) -> Result<(), CompilationError>
+///     validate = "Self::check",
+/// )]
+/// struct T { /* ... */ }
+///
+/// #[actions]
+/// impl T {
+///     #[then]
+///     fn then_1(self, ctx) { /* ... */ }
+///     #[finish]
+///     fn finish_1(self, ctx) { /* ... */ }
+///     /* ... */
+/// }
+/// ```
+/// Every `then`, `finish`, and `continuation` in the `#[actions]` impl is
+/// declared, so none can be left out by mistake. Deriving `Contract` without
+/// an `#[actions]` impl is a compile error, as is having more than one.
+#[proc_macro_derive(Contract, attributes(contract))]
+pub fn derive_contract(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as syn::DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut args: Option<syn::Type> = None;
+    let mut validate: Option<syn::ExprPath> = None;
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("contract")) {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(l)) => l,
+            _ => return compile_error(attr, "expected #[contract(...)]"),
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("args") => match &v.lit {
+                    Lit::Str(l) => match l.parse() {
+                        Ok(t) => args = Some(t),
+                        Err(e) => return e.to_compile_error().into(),
+                    },
+                    _ => return compile_error(v, "expected args = \"Type\""),
+                },
+                NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("validate") => {
+                    match &v.lit {
+                        Lit::Str(l) => match l.parse() {
+                            Ok(p) => validate = Some(p),
+                            Err(e) => return e.to_compile_error().into(),
+                        },
+                        _ => return compile_error(v, "expected validate = \"path\""),
+                    }
+                }
+                NestedMeta::Meta(Meta::List(l))
+                    if ACTION_KINDS.iter().any(|(k, _)| l.path.is_ident(k)) =>
+                {
+                    return compile_error(
+                        l,
+                        "actions are declared by marking their impl #[actions], not listed here",
+                    )
+                }
+                _ => return compile_error(nested, "unknown contract argument"),
+            }
+        }
+    }
+    let stateful = args.map_or_else(|| quote! {()}, |t| quote! {#t});
+    let validate_fn = validate.map(|v| {
+        quote! {
+            fn validate(&self, ctx: &sapio::contract::Context) -> Result<(), sapio::contract::CompilationError> {
+                #v(self, ctx)
+            }
+        }
+    });
+    let [then_fns, finish_fns, finish_or_funcs] =
+        ACTION_KINDS.map(|(_, list)| format_ident!("{}", list, span = name.span()));
+    proc_macro::TokenStream::from(quote! {
+        impl #impl_generics sapio::contract::Contract for #name #ty_generics #where_clause {
+            const THEN_FNS: &'static [fn() -> Option<sapio::contract::actions::ThenFunc<'static, Self>>] =
+                Self::#then_fns;
+            const FINISH_FNS: &'static [fn() -> Option<sapio::contract::actions::Guard<Self>>] =
+                Self::#finish_fns;
+            const FINISH_OR_FUNCS: &'static [fn() -> Option<Box<dyn
+                sapio::contract::actions::CallableAsFoF<Self, Self::StatefulArguments>>>] =
+                Self::#finish_or_funcs;
+            type StatefulArguments = #stateful;
+            #validate_fn
+        }
+    })
+}
+
+/// The actions macro marks the impl defining a contract's actions, for
+/// `#[derive(Contract)]`. Every method in it marked `then`, `finish`, or
+/// `continuation` is declared in the contract.
+/// ```ignore
+/// #[actions]
+/// impl T {
+///     /* ... */
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn actions(args: TokenStream, input: TokenStream) -> TokenStream {
+    let _args = parse_macro_input!(args as AttributeArgs);
+    let item = parse_macro_input!(input as syn::ItemImpl);
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    let self_ty = &item.self_ty;
+    let methods: Vec<(&str, &syn::Ident)> = item
+        .items
+        .iter()
+        .filter_map(|i| match i {
+            syn::ImplItem::Method(m) => Some(m),
+            _ => None,
+        })
+        .flat_map(|m| {
+            m.attrs.iter().filter_map(move |a| {
+                let last = a.path.segments.last()?.ident.to_string();
+                ACTION_KINDS
+                    .iter()
+                    .find(|(k, _)| *k == last)
+                    .map(|(k, _)| (*k, &m.sig.ident))
+            })
+        })
+        .collect();
+    let of_kind = |kind: &str| {
+        methods
+            .iter()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, n)| quote_spanned! {n.span()=> Self::#n})
+            .collect::<Vec<_>>()
+    };
+    let [then_fns, finish_fns, finish_or_funcs] = ACTION_KINDS.map(|(k, _)| of_kind(k));
+    let [then_list, finish_list, finish_or_list] =
+        ACTION_KINDS.map(|(_, list)| format_ident!("{}", list));
+    proc_macro::TokenStream::from(quote! {
+        #item
+        impl #impl_generics #self_ty #where_clause {
+            #[doc(hidden)]
+            const #then_list: &'static [fn() -> Option<sapio::contract::actions::ThenFunc<'static, Self>>] =
+                &[#(#then_fns,)*];
+            #[doc(hidden)]
+            const #finish_list: &'static [fn() -> Option<sapio::contract::actions::Guard<Self>>] =
+                &[#(#finish_fns,)*];
+            #[doc(hidden)]
+            const #finish_or_list: &'static [fn() -> Option<Box<dyn
+                sapio::contract::actions::CallableAsFoF<Self, <Self as sapio::contract::Contract>::StatefulArguments>>>] =
+                &[#(#finish_or_funcs,)*];
+        }
+    })
+}
@@ -0,0 +1,279 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fund compiled contracts from a Bitcoin Core wallet, and broadcast their
+//! transactions as their timelocks mature.
+use bitcoin::consensus::Decodable;
+use bitcoin::hash_types::Txid;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{Amount, OutPoint, Transaction};
+use bitcoincore_rpc_async as rpc;
+use miniscript::psbt::PsbtExt;
+use rpc::RpcApi;
+use sapio::contract::abi::broadcast::BroadcastAfter;
+use sapio::contract::object::{ObjectError, PsbtVersion, SapioStudioFormat};
+use sapio::contract::Compiled;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::txindex::{ChainTip, TxIndex, TxIndexLogger};
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Errors from funding or executing a contract
+#[derive(Debug)]
+pub enum CoreWalletError {
+    /// The node returned an error
+    Rpc(rpc::Error),
+    /// The contract could not be bound
+    Object(ObjectError),
+    /// The node returned something unexpected
+    Malformed(String),
+    /// The funding transaction does not pay the contract
+    NoContractOutput(Txid),
+}
+impl std::fmt::Display for CoreWalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for CoreWalletError {}
+impl From<rpc::Error> for CoreWalletError {
+    fn from(e: rpc::Error) -> Self {
+        CoreWalletError::Rpc(e)
+    }
+}
+impl From<ObjectError> for CoreWalletError {
+    fn from(e: ObjectError) -> Self {
+        CoreWalletError::Object(e)
+    }
+}
+
+type Result<T> = std::result::Result<T, CoreWalletError>;
+
+/// A funding transaction and the output paying the contract
+#[derive(Debug, Clone)]
+pub struct Funding {
+    /// the (broadcast) funding transaction
+    pub tx: Transaction,
+    /// the output paying the contract
+    pub outpoint: OutPoint,
+}
+
+/// A fully signed transaction of a bound contract
+#[derive(Debug, Clone)]
+pub struct Executable {
+    /// the path of the contract it spends
+    pub path: SArc<EffectPath>,
+    /// the transaction
+    pub tx: Transaction,
+}
+
+/// A Bitcoin Core wallet funding and executing contracts
+pub struct CoreWallet {
+    /// RPC Client, for a node with a loaded wallet
+    pub client: rpc::Client,
+    /// how long to wait between polls of the node
+    pub poll: Duration,
+}
+
+impl CoreWallet {
+    /// Use the wallet loaded behind `client`, polling every 10 seconds
+    pub fn new(client: rpc::Client) -> Self {
+        CoreWallet {
+            client,
+            poll: Duration::from_secs(10),
+        }
+    }
+
+    /// Fund `compiled` with `amount` from the wallet (via
+    /// `walletcreatefundedpsbt`) and broadcast the funding transaction.
+    pub async fn fund(&self, compiled: &Compiled, amount: Amount) -> Result<Funding> {
+        let script = bitcoin::Script::from(compiled.address.clone());
+        let network = match self.client.get_blockchain_info().await?.chain.as_str() {
+            "main" => bitcoin::Network::Bitcoin,
+            "test" => bitcoin::Network::Testnet,
+            "signet" => bitcoin::Network::Signet,
+            _ => bitcoin::Network::Regtest,
+        };
+        let address = bitcoin::Address::from_script(&script, network)
+            .ok_or_else(|| CoreWalletError::Malformed("contract has no address".into()))?
+            .to_string();
+        let funded: serde_json::Value = self
+            .client
+            .call(
+                "walletcreatefundedpsbt",
+                &[
+                    serde_json::json!([]),
+                    serde_json::json!([{ address: amount.as_btc() }]),
+                ],
+            )
+            .await?;
+        let signed: serde_json::Value = self
+            .client
+            .call("walletprocesspsbt", &[funded["psbt"].clone()])
+            .await?;
+        let finalized: serde_json::Value = self
+            .client
+            .call("finalizepsbt", &[signed["psbt"].clone()])
+            .await?;
+        let hex = finalized["hex"]
+            .as_str()
+            .ok_or_else(|| CoreWalletError::Malformed("wallet could not sign".into()))?;
+        let bytes = bitcoin::hashes::hex::FromHex::from_hex(hex)
+            .map_err(|e| CoreWalletError::Malformed(format!("{:?}", e)))?;
+        let tx: Transaction = bitcoin::consensus::deserialize(&bytes)
+            .map_err(|e| CoreWalletError::Malformed(format!("{:?}", e)))?;
+        let txid = tx.txid();
+        let vout = tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == script)
+            .ok_or(CoreWalletError::NoContractOutput(txid))?;
+        self.client.send_raw_transaction(&tx).await?;
+        Ok(Funding {
+            tx,
+            outpoint: OutPoint::new(txid, vout as u32),
+        })
+    }
+
+    /// The height `txid` confirmed at, if it has
+    pub async fn confirmation_height(&self, txid: &Txid) -> Result<Option<u32>> {
+        let info = self.client.get_raw_transaction_info(txid, None).await?;
+        match info.blockhash {
+            Some(hash) => Ok(Some(
+                self.client.get_block_header_info(&hash).await?.height as u32,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Wait until `txid` confirms, returning the height it confirmed at
+    pub async fn wait_for_confirmation(&self, txid: &Txid) -> Result<u32> {
+        loop {
+            if let Some(h) = self.confirmation_height(txid).await? {
+                return Ok(h);
+            }
+            tokio::time::sleep(self.poll).await;
+        }
+    }
+
+    /// The node's current chain tip
+    pub async fn chain_tip(&self) -> Result<ChainTip> {
+        let info = self.client.get_blockchain_info().await?;
+        Ok(ChainTip {
+            height: info.blocks as u32,
+            median_time_past: info.median_time as u32,
+        })
+    }
+
+    /// Bind `compiled` to `funding` and finalize every transaction which
+    /// needs no further signatures (e.g., those enforced by CTV or signed by
+    /// `emulator`). Transactions with inputs other than the contract's, or
+    /// which cannot be finalized, are left out.
+    pub fn executable_txs(
+        compiled: &Compiled,
+        funding: &Funding,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<Executable>> {
+        let index = Rc::new(TxIndexLogger::new());
+        index
+            .add_tx(Arc::new(funding.tx.clone()))
+            .map_err(|e| CoreWalletError::Malformed(format!("{:?}", e)))?;
+        let program = compiled.bind_psbt(
+            funding.outpoint,
+            HashMap::new(),
+            index,
+            emulator,
+            PsbtVersion::V0,
+        )?;
+        let secp = Secp256k1::verification_only();
+        let mut res = vec![];
+        for (path, obj) in program.program {
+            for tx in obj.txs {
+                let SapioStudioFormat::LinkedPSBT { psbt, .. } = tx;
+                let bytes = base64::decode(&psbt)
+                    .map_err(|e| CoreWalletError::Malformed(format!("{:?}", e)))?;
+                let psbt = PartiallySignedTransaction::consensus_decode(&bytes[..])
+                    .map_err(|e| CoreWalletError::Malformed(format!("{:?}", e)))?;
+                if psbt.unsigned_tx.input.len() != 1 {
+                    continue;
+                }
+                if let Ok(psbt) = psbt.finalize(&secp) {
+                    res.push(Executable {
+                        path: path.clone(),
+                        tx: psbt.extract_tx(),
+                    });
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Broadcast `tx`, which spends an output confirmed at `funding_height`,
+    /// once its timelocks have matured.
+    pub async fn broadcast_when_mature(
+        &self,
+        tx: &Transaction,
+        funding_height: u32,
+    ) -> Result<Txid> {
+        loop {
+            let tip = self.chain_tip().await?;
+            let after = BroadcastAfter::compute(tx, Some(funding_height), Some(tip));
+            if after.ready != Some(false) {
+                match self.client.send_raw_transaction(tx).await {
+                    Ok(txid) => return Ok(txid),
+                    // relative time locks can't be checked ahead of time, so
+                    // keep retrying those
+                    Err(e) if after.ready == Some(true) => return Err(e.into()),
+                    Err(_) => (),
+                }
+            }
+            tokio::time::sleep(self.poll).await;
+        }
+    }
+
+    /// Walk the contract's transactions from `funding`, broadcasting each as
+    /// it matures and waiting for it to confirm before moving on to the
+    /// contracts it creates. Where several transactions spend the same
+    /// output, `choose` picks which (if any) to broadcast. Returns the txids
+    /// broadcast, in order.
+    pub async fn execute<F>(
+        &self,
+        compiled: &Compiled,
+        funding: &Funding,
+        emulator: &dyn CTVEmulator,
+        choose: F,
+    ) -> Result<Vec<Txid>>
+    where
+        F: Fn(&[&Executable]) -> Option<usize>,
+    {
+        let txs = Self::executable_txs(compiled, funding, emulator)?;
+        let height = self.wait_for_confirmation(&funding.outpoint.txid).await?;
+        let mut frontier = vec![(funding.outpoint, height)];
+        let mut broadcast = vec![];
+        while let Some((out, height)) = frontier.pop() {
+            let candidates: Vec<&Executable> = txs
+                .iter()
+                .filter(|e| e.tx.input[0].previous_output == out)
+                .collect();
+            let chosen = match choose(&candidates) {
+                Some(i) if i < candidates.len() => candidates[i],
+                _ => continue,
+            };
+            let txid = self.broadcast_when_mature(&chosen.tx, height).await?;
+            broadcast.push(txid);
+            let height = self.wait_for_confirmation(&txid).await?;
+            for vout in 0..chosen.tx.output.len() {
+                frontier.push((OutPoint::new(txid, vout as u32), height));
+            }
+        }
+        Ok(broadcast)
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A TxIndex backed by an Esplora HTTP API (e.g. blockstream.info or a
+//! mempool.space instance), for looking up real transactions without a node.
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{Amount, OutPoint, Transaction};
+use sapio_base::fees::{FeeEstimator, FeeEstimatorError};
+use sapio_base::txindex::{ChainTip, MempoolSpend, TxIndex, TxIndexError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, TxIndexError>;
+
+fn http_error<E: std::error::Error + 'static>(e: E) -> TxIndexError {
+    TxIndexError::RpcError(Box::new(e))
+}
+
+/// The response of `/tx/:txid/status`
+#[derive(Deserialize, Debug, Clone)]
+pub struct TxStatus {
+    /// whether the transaction is in a block
+    pub confirmed: bool,
+    /// the height of that block
+    pub block_height: Option<u32>,
+}
+
+/// The response of `/tx/:txid/outspend/:vout`
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutSpend {
+    /// whether the output is spent
+    pub spent: bool,
+    /// the spending transaction
+    pub txid: Option<Txid>,
+    /// the spending transaction's status
+    pub status: Option<TxStatus>,
+}
+
+/// The part of the response of `/tx/:txid` needed for feerates
+#[derive(Deserialize, Debug, Clone)]
+struct TxInfo {
+    fee: u64,
+    weight: u64,
+}
+
+/// The part of the response of `/block/:hash` needed for the chain tip
+#[derive(Deserialize, Debug, Clone)]
+struct BlockInfo {
+    height: u32,
+    mediantime: u32,
+}
+
+/// A TxIndex which queries an Esplora instance on every lookup
+pub struct EsploraIndex {
+    /// the API base, e.g. `https://blockstream.info/testnet/api`
+    pub base_url: String,
+    /// http client
+    pub client: reqwest::Client,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+    /// if can_add is true, then allow the Index to broadcast transactions
+    pub can_add: bool,
+}
+
+impl EsploraIndex {
+    fn get(&self, path: &str) -> Result<Option<reqwest::Response>> {
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let resp = self
+                    .client
+                    .get(format!("{}{}", self.base_url, path))
+                    .send()
+                    .await
+                    .map_err(http_error)?;
+                if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                resp.error_for_status().map(Some).map_err(http_error)
+            })
+        })
+    }
+    fn get_text(&self, path: &str) -> Result<Option<String>> {
+        match self.get(path)? {
+            Some(resp) => tokio::task::block_in_place(|| {
+                self.runtime
+                    .block_on(resp.text())
+                    .map(Some)
+                    .map_err(http_error)
+            }),
+            None => Ok(None),
+        }
+    }
+    fn get_json<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<Option<T>> {
+        match self.get(path)? {
+            Some(resp) => tokio::task::block_in_place(|| {
+                self.runtime
+                    .block_on(resp.json::<T>())
+                    .map(Some)
+                    .map_err(http_error)
+            }),
+            None => Ok(None),
+        }
+    }
+    /// The status of a transaction, or None if it is unknown
+    pub fn tx_status(&self, txid: &Txid) -> Result<Option<TxStatus>> {
+        self.get_json(&format!("/tx/{}/status", txid))
+    }
+    /// Whether (and by what) an output is spent
+    pub fn outspend(&self, o: &OutPoint) -> Result<Option<OutSpend>> {
+        self.get_json(&format!("/tx/{}/outspend/{}", o.txid, o.vout))
+    }
+}
+
+impl TxIndex for EsploraIndex {
+    fn lookup_tx(&self, b: &Txid) -> Result<Arc<Transaction>> {
+        let hex = self
+            .get_text(&format!("/tx/{}/hex", b))?
+            .ok_or(TxIndexError::UnknownTxid(*b))?;
+        let bytes = Vec::<u8>::from_hex(hex.trim()).map_err(http_error)?;
+        deserialize(&bytes).map(Arc::new).map_err(http_error)
+    }
+    fn add_tx(&self, tx: Arc<Transaction>) -> Result<Txid> {
+        let txid = tx.txid();
+        if !self.can_add {
+            return Ok(txid);
+        }
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                self.client
+                    .post(format!("{}/tx", self.base_url))
+                    .body(serialize_hex(&*tx))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await
+            })
+        })
+        .map_err(http_error)?;
+        Ok(txid)
+    }
+    fn lookup_confirmation_height(&self, b: &Txid) -> Result<Option<u32>> {
+        Ok(self
+            .tx_status(b)?
+            .filter(|s| s.confirmed)
+            .and_then(|s| s.block_height))
+    }
+    fn chain_tip(&self) -> Result<Option<ChainTip>> {
+        let hash = match self.get_text("/blocks/tip/hash")? {
+            Some(h) => BlockHash::from_str(h.trim()).map_err(http_error)?,
+            None => return Ok(None),
+        };
+        Ok(self
+            .get_json::<BlockInfo>(&format!("/block/{}", hash))?
+            .map(|b| ChainTip {
+                height: b.height,
+                median_time_past: b.mediantime,
+            }))
+    }
+    fn lookup_spend(&self, o: &OutPoint) -> Result<Option<Txid>> {
+        Ok(self.outspend(o)?.filter(|s| s.spent).and_then(|s| s.txid))
+    }
+    fn lookup_mempool_spend(&self, o: &OutPoint) -> Result<Option<MempoolSpend>> {
+        let spend = match self.outspend(o)? {
+            Some(OutSpend {
+                spent: true,
+                txid: Some(txid),
+                status,
+            }) if !status.as_ref().map_or(false, |s| s.confirmed) => txid,
+            _ => return Ok(None),
+        };
+        let info: Option<TxInfo> = self.get_json(&format!("/tx/{}", spend))?;
+        Ok(Some(MempoolSpend {
+            txid: spend,
+            fee: info.as_ref().map(|i| Amount::from_sat(i.fee)),
+            vsize: info.map(|i| (i.weight + 3) / 4),
+        }))
+    }
+}
+
+/// Estimates with `/fee-estimates`, using the estimate for the largest
+/// target not exceeding the one requested
+impl FeeEstimator for EsploraIndex {
+    fn estimate_feerate(
+        &self,
+        target_blocks: u16,
+    ) -> std::result::Result<Amount, FeeEstimatorError> {
+        let estimates: HashMap<String, f64> = self
+            .get_json("/fee-estimates")
+            .map_err(|e| FeeEstimatorError::RpcError(Box::new(e)))?
+            .ok_or(FeeEstimatorError::NoEstimate(target_blocks))?;
+        estimates
+            .iter()
+            .filter_map(|(k, v)| Some((k.parse::<u16>().ok()?, *v)))
+            .filter(|(k, _)| *k <= target_blocks.max(1))
+            .max_by_key(|(k, _)| *k)
+            .map(|(_, v)| Amount::from_sat(v.ceil() as u64))
+            .ok_or(FeeEstimatorError::NoEstimate(target_blocks))
+    }
+}
@@ -8,8 +8,22 @@
 use bitcoin::hash_types::*;
 use bitcoincore_rpc_async as rpc;
 use rpc::RpcApi;
-use sapio_base::txindex::{TxIndex, TxIndexError};
+use sapio_base::fees::{FeeEstimator, FeeEstimatorError};
+use sapio_base::txindex::{ChainTip, MempoolSpend, TxIndex, TxIndexError};
+use std::str::FromStr;
 use std::sync::Arc;
+
+#[cfg(feature = "core-wallet")]
+pub mod core_wallet;
+#[cfg(feature = "esplora")]
+pub mod esplora;
+#[cfg(feature = "fee-api")]
+pub mod mempool_space;
+#[cfg(feature = "nostr")]
+pub mod nostr;
+#[cfg(feature = "alerts")]
+pub mod watchtower;
+
 /// A TxIndex based on a Bitcoin RPC Client
 pub struct BitcoinNodeIndex {
     /// RPC Client
@@ -21,6 +35,10 @@ pub struct BitcoinNodeIndex {
 }
 
 type Result<T> = std::result::Result<T, TxIndexError>;
+fn rpc_error(e: rpc::Error) -> TxIndexError {
+    let b: Box<dyn std::error::Error> = Box::new(e);
+    TxIndexError::RpcError(b)
+}
 impl TxIndex for BitcoinNodeIndex {
     fn lookup_tx(&self, b: &Txid) -> Result<Arc<bitcoin::Transaction>> {
         tokio::task::block_in_place(|| {
@@ -48,4 +66,102 @@ impl TxIndex for BitcoinNodeIndex {
             Ok(txid)
         }
     }
+    fn lookup_confirmation_height(&self, b: &Txid) -> Result<Option<u32>> {
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let info = self
+                    .client
+                    .get_raw_transaction_info(b, None)
+                    .await
+                    .map_err(rpc_error)?;
+                match info.blockhash {
+                    Some(hash) => {
+                        let header = self
+                            .client
+                            .get_block_header_info(&hash)
+                            .await
+                            .map_err(rpc_error)?;
+                        Ok(Some(header.height as u32))
+                    }
+                    None => Ok(None),
+                }
+            })
+        })
+    }
+    fn chain_tip(&self) -> Result<Option<ChainTip>> {
+        tokio::task::block_in_place(|| {
+            self.runtime
+                .block_on(self.client.get_blockchain_info())
+                .map(|info| {
+                    Some(ChainTip {
+                        height: info.blocks as u32,
+                        median_time_past: info.median_time as u32,
+                    })
+                })
+                .map_err(rpc_error)
+        })
+    }
+    /// Uses `gettxspendingprevout`, which requires Bitcoin Core 24 or newer.
+    fn lookup_mempool_spend(&self, o: &bitcoin::OutPoint) -> Result<Option<MempoolSpend>> {
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                let spending: Vec<serde_json::Value> = self
+                    .client
+                    .call(
+                        "gettxspendingprevout",
+                        &[serde_json::json!([{"txid": o.txid, "vout": o.vout}])],
+                    )
+                    .await
+                    .map_err(rpc_error)?;
+                let txid = match spending
+                    .get(0)
+                    .and_then(|s| s.get("spendingtxid"))
+                    .and_then(|t| t.as_str())
+                {
+                    Some(t) => {
+                        Txid::from_str(t).map_err(|e| TxIndexError::RpcError(Box::new(e)))?
+                    }
+                    None => return Ok(None),
+                };
+                // the entry may have been evicted or mined in between calls
+                let entry: Option<serde_json::Value> = self
+                    .client
+                    .call("getmempoolentry", &[serde_json::json!(txid)])
+                    .await
+                    .ok();
+                let fee = entry
+                    .as_ref()
+                    .and_then(|e| e.get("fees")?.get("base")?.as_f64())
+                    .and_then(|btc| bitcoin::Amount::from_btc(btc).ok());
+                let vsize = entry.as_ref().and_then(|e| e.get("vsize")?.as_u64());
+                Ok(Some(MempoolSpend { txid, fee, vsize }))
+            })
+        })
+    }
+}
+
+/// Estimates with `estimatesmartfee`
+impl FeeEstimator for BitcoinNodeIndex {
+    fn estimate_feerate(
+        &self,
+        target_blocks: u16,
+    ) -> std::result::Result<bitcoin::Amount, FeeEstimatorError> {
+        let estimate: serde_json::Value = tokio::task::block_in_place(|| {
+            self.runtime.block_on(
+                self.client
+                    .call("estimatesmartfee", &[serde_json::json!(target_blocks)]),
+            )
+        })
+        .map_err(|e| FeeEstimatorError::RpcError(Box::new(e)))?;
+        // reported in BTC per kvB
+        let btc_per_kvb = estimate
+            .get("feerate")
+            .and_then(|f| f.as_f64())
+            .ok_or(FeeEstimatorError::NoEstimate(target_blocks))?;
+        let sats_per_kvb = bitcoin::Amount::from_btc(btc_per_kvb)
+            .map_err(|e| FeeEstimatorError::RpcError(Box::new(e)))?;
+        Ok(bitcoin::Amount::from_sat(
+            (sats_per_kvb.as_sat() + 999) / 1000,
+        ))
+    }
 }
@@ -0,0 +1,75 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fee estimates from a mempool.space compatible HTTP API
+use bitcoin::Amount;
+use sapio_base::fees::{FeeEstimator, FeeEstimatorError};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// The response of `/api/v1/fees/recommended`, in sats per vbyte
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct RecommendedFees {
+    /// next block
+    pub fastest_fee: u64,
+    /// within 3 blocks
+    pub half_hour_fee: u64,
+    /// within 6 blocks
+    pub hour_fee: u64,
+    /// eventually
+    pub economy_fee: u64,
+    /// the mempool's minimum
+    pub minimum_fee: u64,
+}
+
+impl RecommendedFees {
+    /// The recommendation for confirmation within `target_blocks`
+    pub fn for_target(&self, target_blocks: u16) -> u64 {
+        match target_blocks {
+            0..=1 => self.fastest_fee,
+            2..=3 => self.half_hour_fee,
+            4..=6 => self.hour_fee,
+            _ => self.economy_fee,
+        }
+    }
+}
+
+/// Fetches recommended fees from a mempool.space instance on every estimate
+pub struct MempoolSpaceEstimator {
+    /// the API base, e.g. `https://mempool.space/api`
+    pub base_url: String,
+    /// http client
+    pub client: reqwest::Client,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl MempoolSpaceEstimator {
+    /// Fetch the current recommendations
+    pub fn recommended(&self) -> Result<RecommendedFees, FeeEstimatorError> {
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                self.client
+                    .get(format!("{}/v1/fees/recommended", self.base_url))
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<RecommendedFees>()
+                    .await
+            })
+        })
+        .map_err(|e| FeeEstimatorError::RpcError(Box::new(e)))
+    }
+}
+
+impl FeeEstimator for MempoolSpaceEstimator {
+    fn estimate_feerate(&self, target_blocks: u16) -> Result<Amount, FeeEstimatorError> {
+        Ok(Amount::from_sat(
+            self.recommended()?.for_target(target_blocks),
+        ))
+    }
+}
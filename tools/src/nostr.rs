@@ -0,0 +1,365 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Distribute [`SignedEffect`]s between the participants of a contract over
+//! Nostr relays.
+//!
+//! Proposals are sent as NIP-04 encrypted direct messages (kind 4), one per
+//! participant, tagged with `["t", "sapio-effect"]` and the effect path. On
+//! receipt, the Nostr event signature, the effect envelope signature, and the
+//! proposer's membership in the participant set are all checked before a
+//! proposal is handed to the caller.
+use bitcoin::hashes::{hex::ToHex, sha256, Hash};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{
+    ecdh, schnorr::Signature, All, KeyPair, Message, PublicKey, Secp256k1, SecretKey,
+    XOnlyPublicKey,
+};
+use futures_util::{SinkExt, StreamExt};
+use sapio_base::effects::{EffectDBError, EffectPath, SignedEffect};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// NIP-04 encrypted direct message
+const KIND_ENCRYPTED_DM: u64 = 4;
+/// topic tag identifying sapio effect proposals
+const EFFECT_TOPIC: &str = "sapio-effect";
+
+type Aes256Cbc = block_modes::Cbc<aes::Aes256, block_modes::block_padding::Pkcs7>;
+
+/// Errors from the Nostr effect channel
+#[derive(Debug)]
+pub enum NostrError {
+    /// Could not talk to a relay
+    Relay(tokio_tungstenite::tungstenite::Error),
+    /// A message could not be (de)serialized
+    Serialization(serde_json::Error),
+    /// An event or its contents could not be decrypted
+    Decryption(String),
+    /// A Nostr event's id or signature was invalid
+    BadEvent,
+    /// The effect envelope did not verify
+    Effect(EffectDBError),
+    /// The proposer is not one of the participants
+    UnknownSigner(XOnlyPublicKey),
+}
+impl std::fmt::Display for NostrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for NostrError {}
+impl From<tokio_tungstenite::tungstenite::Error> for NostrError {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        NostrError::Relay(e)
+    }
+}
+impl From<serde_json::Error> for NostrError {
+    fn from(e: serde_json::Error) -> Self {
+        NostrError::Serialization(e)
+    }
+}
+impl From<EffectDBError> for NostrError {
+    fn from(e: EffectDBError) -> Self {
+        NostrError::Effect(e)
+    }
+}
+
+/// A NIP-01 event
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    /// hex sha256 of the serialized event
+    pub id: String,
+    /// hex x-only key of the author
+    pub pubkey: String,
+    /// unix timestamp
+    pub created_at: u64,
+    /// event kind
+    pub kind: u64,
+    /// event tags
+    pub tags: Vec<Vec<String>>,
+    /// event content
+    pub content: String,
+    /// hex BIP-340 signature of the id
+    pub sig: String,
+}
+
+impl Event {
+    fn compute_id(
+        pubkey: &str,
+        created_at: u64,
+        kind: u64,
+        tags: &[Vec<String>],
+        content: &str,
+    ) -> Result<sha256::Hash, NostrError> {
+        let ser = serde_json::to_vec(&(0, pubkey, created_at, kind, tags, content))?;
+        Ok(sha256::Hash::hash(&ser))
+    }
+
+    /// create and sign a new event
+    fn new(
+        secp: &Secp256k1<All>,
+        key: &KeyPair,
+        kind: u64,
+        tags: Vec<Vec<String>>,
+        content: String,
+    ) -> Result<Self, NostrError> {
+        let pubkey = XOnlyPublicKey::from_keypair(key).to_string();
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let id = Self::compute_id(&pubkey, created_at, kind, &tags, &content)?;
+        let msg = Message::from_slice(&id[..]).expect("32 bytes is a valid message");
+        Ok(Event {
+            id: id.to_hex(),
+            sig: secp.sign_schnorr_no_aux_rand(&msg, key).to_string(),
+            pubkey,
+            created_at,
+            kind,
+            tags,
+            content,
+        })
+    }
+
+    /// check the event id and signature, returning the author
+    fn verify(&self, secp: &Secp256k1<All>) -> Result<XOnlyPublicKey, NostrError> {
+        let id = Self::compute_id(
+            &self.pubkey,
+            self.created_at,
+            self.kind,
+            &self.tags,
+            &self.content,
+        )?;
+        if id.to_hex() != self.id {
+            return Err(NostrError::BadEvent);
+        }
+        let author = XOnlyPublicKey::from_str(&self.pubkey).map_err(|_| NostrError::BadEvent)?;
+        let sig = Signature::from_str(&self.sig).map_err(|_| NostrError::BadEvent)?;
+        let msg = Message::from_slice(&id[..]).expect("32 bytes is a valid message");
+        secp.verify_schnorr(&sig, &msg, &author)
+            .map_err(|_| NostrError::BadEvent)?;
+        Ok(author)
+    }
+}
+
+/// NIP-04 shared secret: the x coordinate of the ECDH point
+fn shared_key(ours: &KeyPair, theirs: &XOnlyPublicKey) -> Result<[u8; 32], NostrError> {
+    let mut compressed = [2u8; 33];
+    compressed[1..].copy_from_slice(&theirs.serialize());
+    let theirs =
+        PublicKey::from_slice(&compressed).map_err(|e| NostrError::Decryption(e.to_string()))?;
+    let point = ecdh::shared_secret_point(&theirs, &SecretKey::from_keypair(ours));
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&point[..32]);
+    Ok(key)
+}
+
+fn encrypt(
+    ours: &KeyPair,
+    theirs: &XOnlyPublicKey,
+    plaintext: &[u8],
+) -> Result<String, NostrError> {
+    use block_modes::BlockMode;
+    let key = shared_key(ours, theirs)?;
+    let mut iv = [0u8; 16];
+    thread_rng().fill_bytes(&mut iv);
+    let cipher =
+        Aes256Cbc::new_from_slices(&key, &iv).map_err(|e| NostrError::Decryption(e.to_string()))?;
+    Ok(format!(
+        "{}?iv={}",
+        base64::encode(cipher.encrypt_vec(plaintext)),
+        base64::encode(iv)
+    ))
+}
+
+fn decrypt(ours: &KeyPair, theirs: &XOnlyPublicKey, content: &str) -> Result<Vec<u8>, NostrError> {
+    use block_modes::BlockMode;
+    let bad = |e: &dyn std::fmt::Display| NostrError::Decryption(e.to_string());
+    let mut parts = content.splitn(2, "?iv=");
+    let ciphertext = base64::decode(parts.next().unwrap_or("")).map_err(|e| bad(&e))?;
+    let iv =
+        base64::decode(parts.next().ok_or_else(|| bad(&"missing iv"))?).map_err(|e| bad(&e))?;
+    let key = shared_key(ours, theirs)?;
+    Aes256Cbc::new_from_slices(&key, &iv)
+        .map_err(|e| bad(&e))?
+        .decrypt_vec(&ciphertext)
+        .map_err(|e| bad(&e))
+}
+
+/// A coordination channel for the participants of a contract, publishing and
+/// receiving [`SignedEffect`]s through a set of Nostr relays.
+pub struct NostrEffectChannel {
+    /// relay websocket urls
+    pub relays: Vec<String>,
+    /// our key, used both for Nostr events and decryption
+    pub key: KeyPair,
+    /// everyone proposals are encrypted to and accepted from
+    pub participants: Vec<XOnlyPublicKey>,
+    /// secp context
+    pub secp: Arc<Secp256k1<All>>,
+}
+
+impl NostrEffectChannel {
+    fn our_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_keypair(&self.key)
+    }
+
+    /// Publish a signed effect to every relay, encrypted separately to each
+    /// participant other than ourselves.
+    pub async fn publish(&self, effect: &SignedEffect) -> Result<(), NostrError> {
+        effect.verify(&self.secp)?;
+        let plaintext = serde_json::to_vec(effect)?;
+        let path = effect.proposal.path.0.to_string();
+        let me = self.our_key();
+        let mut events = vec![];
+        for p in self.participants.iter().filter(|p| **p != me) {
+            let tags = vec![
+                vec!["p".into(), p.to_string()],
+                vec!["t".into(), EFFECT_TOPIC.into()],
+                vec!["d".into(), path.clone()],
+            ];
+            let content = encrypt(&self.key, p, &plaintext)?;
+            events.push(Event::new(
+                &self.secp,
+                &self.key,
+                KIND_ENCRYPTED_DM,
+                tags,
+                content,
+            )?);
+        }
+        for relay in &self.relays {
+            let (mut ws, _) = tokio_tungstenite::connect_async(relay.as_str()).await?;
+            for ev in &events {
+                let msg = serde_json::to_string(&("EVENT", ev))?;
+                ws.send(WsMessage::Text(msg)).await?;
+            }
+            ws.close(None).await?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt and verify a received event, returning the effect it carries
+    pub fn open(&self, ev: &Event) -> Result<SignedEffect, NostrError> {
+        let author = ev.verify(&self.secp)?;
+        if !self.participants.contains(&author) {
+            return Err(NostrError::UnknownSigner(author));
+        }
+        let plaintext = decrypt(&self.key, &author, &ev.content)?;
+        let effect: SignedEffect = serde_json::from_slice(&plaintext)?;
+        effect.verify(&self.secp)?;
+        if !self.participants.contains(&effect.signer) {
+            return Err(NostrError::UnknownSigner(effect.signer));
+        }
+        Ok(effect)
+    }
+
+    /// Subscribe to effects proposed to us for `path` on every relay.
+    ///
+    /// Only proposals passing [`Self::open`] are delivered; anything else
+    /// received is dropped. The channel closes once every relay connection
+    /// has ended.
+    pub async fn subscribe(
+        &self,
+        path: &EffectPath,
+    ) -> Result<mpsc::UnboundedReceiver<SignedEffect>, NostrError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let filter = serde_json::json!({
+            "kinds": [KIND_ENCRYPTED_DM],
+            "#p": [self.our_key().to_string()],
+            "#t": [EFFECT_TOPIC],
+            "#d": [path.to_string()],
+        });
+        let sub_id = format!("{}-{}", EFFECT_TOPIC, thread_rng().next_u64());
+        let req = serde_json::to_string(&("REQ", &sub_id, filter))?;
+        let channel = Arc::new(NostrEffectChannel {
+            relays: vec![],
+            key: self.key,
+            participants: self.participants.clone(),
+            secp: self.secp.clone(),
+        });
+        for relay in &self.relays {
+            let (mut ws, _) = tokio_tungstenite::connect_async(relay.as_str()).await?;
+            ws.send(WsMessage::Text(req.clone())).await?;
+            let tx = tx.clone();
+            let channel = channel.clone();
+            let sub_id = sub_id.clone();
+            tokio::spawn(async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let text = match msg {
+                        WsMessage::Text(t) => t,
+                        WsMessage::Close(_) => break,
+                        _ => continue,
+                    };
+                    let ev = match serde_json::from_str::<(String, String, Event)>(&text) {
+                        Ok((typ, id, ev)) if typ == "EVENT" && id == sub_id => ev,
+                        _ => continue,
+                    };
+                    if let Ok(effect) = channel.open(&ev) {
+                        if tx.send(effect).is_err() {
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sapio_base::effects::EffectProposal;
+    use sapio_base::serialization_helpers::SArc;
+    use std::convert::TryFrom;
+    #[test]
+    fn test_open() {
+        let secp = Arc::new(Secp256k1::new());
+        let alice = KeyPair::from_seckey_slice(&secp, &[1u8; 32]).unwrap();
+        let bob = KeyPair::from_seckey_slice(&secp, &[2u8; 32]).unwrap();
+        let participants = vec![
+            XOnlyPublicKey::from_keypair(&alice),
+            XOnlyPublicKey::from_keypair(&bob),
+        ];
+        let effect = EffectProposal {
+            path: SArc(Arc::new(EffectPath::try_from("root").unwrap())),
+            name: SArc(Arc::new("update".into())),
+            value: serde_json::json!({}),
+            conditions: None,
+        }
+        .sign(&secp, &alice)
+        .unwrap();
+        let content = encrypt(
+            &alice,
+            &participants[1],
+            &serde_json::to_vec(&effect).unwrap(),
+        )
+        .unwrap();
+        let ev = Event::new(&secp, &alice, KIND_ENCRYPTED_DM, vec![], content).unwrap();
+        let bobs = NostrEffectChannel {
+            relays: vec![],
+            key: bob,
+            participants: participants.clone(),
+            secp: secp.clone(),
+        };
+        assert_eq!(bobs.open(&ev).unwrap(), effect);
+        let outsider = NostrEffectChannel {
+            relays: vec![],
+            key: bob,
+            participants: vec![participants[1]],
+            secp,
+        };
+        assert!(matches!(
+            outsider.open(&ev),
+            Err(NostrError::UnknownSigner(_))
+        ));
+    }
+}
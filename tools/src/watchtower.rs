@@ -0,0 +1,340 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Monitor contract outputs and raise alerts to configurable sinks.
+//!
+//! A [`Watchtower`] is polled with a [`TxIndex`] and fires an [`Alert`] when:
+//! - a watched output is spent by a transaction the contract did not expect;
+//! - an expected spend opens a clawback window that may need a response; or
+//! - a timelocked transaction is about to become valid.
+//!
+//! Each alert is sent to every configured [`AlertSink`] at most once.
+use bitcoin::hash_types::Txid;
+use bitcoin::OutPoint;
+use sapio_base::txindex::{TxIndex, TxIndexError};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// Errors raised while delivering alerts
+#[derive(Debug)]
+pub enum AlertError {
+    /// The alert could not be serialized
+    Serialization(serde_json::Error),
+    /// A webhook request failed
+    Webhook(reqwest::Error),
+    /// A command hook could not be run or exited unsuccessfully
+    Command(std::io::Error),
+    /// Chain data could not be fetched
+    Index(TxIndexError),
+}
+impl std::fmt::Display for AlertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl std::error::Error for AlertError {}
+impl From<serde_json::Error> for AlertError {
+    fn from(e: serde_json::Error) -> Self {
+        AlertError::Serialization(e)
+    }
+}
+impl From<TxIndexError> for AlertError {
+    fn from(e: TxIndexError) -> Self {
+        AlertError::Index(e)
+    }
+}
+
+/// What happened
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertKind {
+    /// A watched output was spent by a transaction the contract did not create
+    UnexpectedSpend {
+        /// the output spent
+        outpoint: OutPoint,
+        /// the unexpected spend
+        spending_txid: Txid,
+    },
+    /// An expected spend was seen which starts a window in which funds may be
+    /// clawed back
+    ClawbackWindowOpen {
+        /// the output spent
+        outpoint: OutPoint,
+        /// the spend starting the window
+        spending_txid: Txid,
+    },
+    /// A timelocked transaction becomes valid soon
+    TimelockMaturing {
+        /// the timelocked transaction
+        txid: Txid,
+        /// the height it may be mined at
+        height: u32,
+        /// blocks until then
+        blocks_remaining: u32,
+    },
+}
+
+/// An alert, with enough context to act on it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Alert {
+    /// the contract path the alert concerns
+    pub path: String,
+    /// what happened
+    pub kind: AlertKind,
+    /// hex encoded transactions (or base64 PSBTs) suggested in response
+    pub suggested_responses: Vec<String>,
+}
+
+/// Somewhere alerts are delivered to
+pub trait AlertSink {
+    /// deliver an alert
+    fn send(&self, alert: &Alert) -> Result<(), AlertError>;
+}
+
+/// POSTs each alert as JSON to a URL
+pub struct WebhookSink {
+    /// where to POST alerts
+    pub url: String,
+    /// http client
+    pub client: reqwest::Client,
+    /// tokio runtime
+    pub runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl AlertSink for WebhookSink {
+    fn send(&self, alert: &Alert) -> Result<(), AlertError> {
+        tokio::task::block_in_place(|| {
+            self.runtime.block_on(async {
+                self.client
+                    .post(&self.url)
+                    .json(alert)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            })
+        })
+        .map_err(AlertError::Webhook)
+    }
+}
+
+/// Runs a command with each alert as JSON on stdin, e.g. a script which
+/// sends an email.
+pub struct CommandSink {
+    /// program to run
+    pub program: String,
+    /// arguments to pass it
+    pub args: Vec<String>,
+}
+
+impl AlertSink for CommandSink {
+    fn send(&self, alert: &Alert) -> Result<(), AlertError> {
+        let json = serde_json::to_vec(alert)?;
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(AlertError::Command)?;
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(&json).map_err(AlertError::Command)?;
+        }
+        let status = child.wait().map_err(AlertError::Command)?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(AlertError::Command(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("alert command exited with {}", status),
+            )))
+        }
+    }
+}
+
+/// Configuration for a single alert sink
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// see [`WebhookSink`]
+    Webhook {
+        /// where to POST alerts
+        url: String,
+    },
+    /// see [`CommandSink`]
+    Command {
+        /// program to run
+        program: String,
+        /// arguments to pass it
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// Configuration for a watchtower's alerts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlertConfig {
+    /// where alerts go
+    pub sinks: Vec<SinkConfig>,
+    /// how many blocks ahead of a timelock maturing to alert
+    #[serde(default = "AlertConfig::default_maturity_warning")]
+    pub maturity_warning_blocks: u32,
+}
+
+impl AlertConfig {
+    fn default_maturity_warning() -> u32 {
+        6
+    }
+    /// Create the configured sinks
+    pub fn sinks(&self, runtime: Arc<tokio::runtime::Runtime>) -> Vec<Box<dyn AlertSink>> {
+        self.sinks
+            .iter()
+            .map(|s| -> Box<dyn AlertSink> {
+                match s {
+                    SinkConfig::Webhook { url } => Box::new(WebhookSink {
+                        url: url.clone(),
+                        client: reqwest::Client::new(),
+                        runtime: runtime.clone(),
+                    }),
+                    SinkConfig::Command { program, args } => Box::new(CommandSink {
+                        program: program.clone(),
+                        args: args.clone(),
+                    }),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A contract output to watch
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WatchedOutput {
+    /// the contract path that created the output
+    pub path: String,
+    /// the output
+    pub outpoint: OutPoint,
+    /// the spends the contract permits, each with the responses to suggest
+    /// if it is seen. A spend with responses opens a clawback window.
+    pub expected: HashMap<Txid, Vec<String>>,
+    /// responses to suggest if an unexpected spend is seen
+    #[serde(default)]
+    pub on_unexpected: Vec<String>,
+}
+
+/// A timelocked transaction to warn about before it matures
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingTimelock {
+    /// the contract path the transaction belongs to
+    pub path: String,
+    /// the transaction
+    pub txid: Txid,
+    /// the height it may be mined at
+    pub height: u32,
+    /// responses to suggest, e.g. the transaction itself
+    #[serde(default)]
+    pub suggested_responses: Vec<String>,
+}
+
+/// Watches contract outputs and timelocks, sending alerts to sinks.
+pub struct Watchtower {
+    /// outputs being watched
+    pub outputs: Vec<WatchedOutput>,
+    /// timelocks being watched
+    pub timelocks: Vec<PendingTimelock>,
+    /// how many blocks ahead of a timelock maturing to alert
+    pub maturity_warning_blocks: u32,
+    sinks: Vec<Box<dyn AlertSink>>,
+    fired: HashSet<AlertKind>,
+}
+
+impl Watchtower {
+    /// create a watchtower delivering to `sinks`
+    pub fn new(sinks: Vec<Box<dyn AlertSink>>, maturity_warning_blocks: u32) -> Self {
+        Watchtower {
+            outputs: vec![],
+            timelocks: vec![],
+            maturity_warning_blocks,
+            sinks,
+            fired: Default::default(),
+        }
+    }
+
+    /// Find every alert that currently applies
+    pub fn check(&self, index: &dyn TxIndex) -> Result<Vec<Alert>, AlertError> {
+        let mut alerts = vec![];
+        for w in &self.outputs {
+            let spend = match index.lookup_mempool_spend(&w.outpoint)? {
+                Some(spend) => spend,
+                None => continue,
+            };
+            match w.expected.get(&spend.txid) {
+                None => alerts.push(Alert {
+                    path: w.path.clone(),
+                    kind: AlertKind::UnexpectedSpend {
+                        outpoint: w.outpoint,
+                        spending_txid: spend.txid,
+                    },
+                    suggested_responses: w.on_unexpected.clone(),
+                }),
+                Some(responses) if !responses.is_empty() => alerts.push(Alert {
+                    path: w.path.clone(),
+                    kind: AlertKind::ClawbackWindowOpen {
+                        outpoint: w.outpoint,
+                        spending_txid: spend.txid,
+                    },
+                    suggested_responses: responses.clone(),
+                }),
+                Some(_) => (),
+            }
+        }
+        if let Some(tip) = index.chain_tip()? {
+            for t in &self.timelocks {
+                let remaining = t.height.saturating_sub(tip.height + 1);
+                if t.height > tip.height + 1 && remaining <= self.maturity_warning_blocks {
+                    alerts.push(Alert {
+                        path: t.path.clone(),
+                        kind: AlertKind::TimelockMaturing {
+                            txid: t.txid,
+                            height: t.height,
+                            blocks_remaining: remaining,
+                        },
+                        suggested_responses: t.suggested_responses.clone(),
+                    });
+                }
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Check for alerts and send any not already sent to every sink. Returns
+    /// the alerts sent. Sink failures are returned after every sink has been
+    /// tried; an alert which failed to send is retried on the next poll.
+    pub fn poll(&mut self, index: &dyn TxIndex) -> Result<Vec<Alert>, AlertError> {
+        let mut sent = vec![];
+        let mut error = None;
+        for alert in self.check(index)? {
+            if self.fired.contains(&alert.kind) {
+                continue;
+            }
+            let mut ok = true;
+            for sink in &self.sinks {
+                if let Err(e) = sink.send(&alert) {
+                    ok = false;
+                    error = Some(e);
+                }
+            }
+            if ok {
+                self.fired.insert(alert.kind.clone());
+                sent.push(alert);
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(sent),
+        }
+    }
+}